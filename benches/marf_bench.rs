@@ -4,17 +4,22 @@ extern crate blockstack_lib;
 extern crate rand;
 
 use blockstack_lib::chainstate::burn::BlockHeaderHash;
-use blockstack_lib::chainstate::stacks::index::{marf::MARF, storage::TrieFileStorage, MARFValue};
+use blockstack_lib::chainstate::stacks::index::{marf::MARF, storage::TrieFileStorage, storage::TrieHashCalculationMode, storage::TrieStorageBackend, MARFValue};
+use blockstack_lib::chainstate::stacks::index::cache::MARF_NODE_CACHE_DEFAULT_CAPACITY;
 
 use criterion::Criterion;
 use rand::prelude::*;
 use std::fs;
 
-fn benchmark_marf_usage(filename: &str, blocks: u32, writes_per_block: u32, reads_per_block: u32, batch: bool) {
+fn benchmark_marf_usage(filename: &str, blocks: u32, writes_per_block: u32, reads_per_block: u32, batch: bool, backend: TrieStorageBackend) {
     if fs::metadata(filename).is_ok() {
         fs::remove_file(filename).unwrap();
     };
-    let f = TrieFileStorage::new(filename).unwrap();
+    let data_filename = TrieFileStorage::data_file_path(filename, 0);
+    if fs::metadata(&data_filename).is_ok() {
+        fs::remove_file(&data_filename).unwrap();
+    };
+    let f = TrieFileStorage::new_with_backend(filename, Some(backend)).unwrap();
     let mut block_header = BlockHeaderHash::from_bytes(&[0u8; 32]).unwrap();
     let mut marf = MARF::from_storage(f);
     marf.begin(&TrieFileStorage::block_sentinel(), &block_header).unwrap();
@@ -63,8 +68,9 @@ fn benchmark_marf_usage(filename: &str, blocks: u32, writes_per_block: u32, read
     marf.commit().unwrap();
 }
 
-fn benchmark_marf_read(filename: &str, reads: u32, block: u32, writes_per_block: u32) {
-    let f = TrieFileStorage::new(filename).unwrap();
+fn benchmark_marf_read(filename: &str, reads: u32, block: u32, writes_per_block: u32, node_cache_capacity: usize) {
+    let mut f = TrieFileStorage::new(filename).unwrap();
+    f.set_node_cache_capacity(node_cache_capacity);
     let mut block_header = block.to_le_bytes().to_vec();
     block_header.resize(32, 0);
     let block_header = BlockHeaderHash::from_bytes(block_header.as_slice()).unwrap();
@@ -81,16 +87,64 @@ fn benchmark_marf_read(filename: &str, reads: u32, block: u32, writes_per_block:
     }
 }
 
+fn benchmark_marf_commit(filename: &str, blocks: u32, writes_per_block: u32, mode: TrieHashCalculationMode, hash_threads: usize) {
+    if fs::metadata(filename).is_ok() {
+        fs::remove_file(filename).unwrap();
+    };
+    let mut f = TrieFileStorage::new(filename).unwrap();
+    f.set_hash_calculation_mode(mode);
+    f.set_hash_threads(hash_threads);
+
+    let mut block_header = TrieFileStorage::block_sentinel();
+    let mut marf = MARF::from_storage(f);
+
+    let mut rng = rand::thread_rng();
+
+    for i in 0..blocks {
+        let mut next_block_header = (i+1).to_le_bytes().to_vec();
+        next_block_header.resize(32, 0);
+        let next_block_header = BlockHeaderHash::from_bytes(next_block_header.as_slice()).unwrap();
+
+        marf.begin(&block_header, &next_block_header).unwrap();
+        for k in 0..writes_per_block {
+            let key = format!("{}::{}", i, k);
+            let mut value = [0u8; 40];
+            rng.fill_bytes(&mut value);
+            marf.insert(&key, MARFValue(value)).unwrap();
+        }
+        marf.commit().unwrap();
+        block_header = next_block_header;
+    }
+}
+
 pub fn basic_usage_benchmark(c: &mut Criterion) {
-    c.bench_function("marf_setup_1000b_5kW", |b| b.iter(|| benchmark_marf_usage("/tmp/db.1k.sqlite", 1000, 5000, 0, false)));
-    c.bench_function("marf_setup_400b_5kW", |b| b.iter(|| benchmark_marf_usage("/tmp/db.400.sqlite", 1000, 5000, 0, false)));
-    c.bench_function("marf_read_1000b_1kW", |b| b.iter(|| benchmark_marf_read("/tmp/db.1k.sqlite", 1000, 1000, 5000)));
-    c.bench_function("marf_read_400b_1kW", |b| b.iter(|| benchmark_marf_read("/tmp/db.400.sqlite", 1000, 400, 5000)));
-
-    c.bench_function("marf_usage_1b_10kW_0kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 1, 10000, 0, false)));
-    c.bench_function("marf_usage_10b_1kW_2kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 10, 1000, 2000, false)));
-    c.bench_function("marf_usage_100b_5kW_20kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 20, 5000, 20000, false)));
-    c.bench_function("marf_usage_batches_10b_1kW_2kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 10, 1000, 2000, true)));
+    c.bench_function("marf_setup_1000b_5kW", |b| b.iter(|| benchmark_marf_usage("/tmp/db.1k.sqlite", 1000, 5000, 0, false, TrieStorageBackend::SqliteBlobs)));
+    c.bench_function("marf_setup_400b_5kW", |b| b.iter(|| benchmark_marf_usage("/tmp/db.400.sqlite", 1000, 5000, 0, false, TrieStorageBackend::SqliteBlobs)));
+    c.bench_function("marf_read_1000b_1kW", |b| b.iter(|| benchmark_marf_read("/tmp/db.1k.sqlite", 1000, 1000, 5000, MARF_NODE_CACHE_DEFAULT_CAPACITY)));
+    c.bench_function("marf_read_400b_1kW", |b| b.iter(|| benchmark_marf_read("/tmp/db.400.sqlite", 1000, 400, 5000, MARF_NODE_CACHE_DEFAULT_CAPACITY)));
+
+    // same reads with the node cache turned off, to show what the cache buys
+    c.bench_function("marf_read_1000b_1kW_nocache", |b| b.iter(|| benchmark_marf_read("/tmp/db.1k.sqlite", 1000, 1000, 5000, 0)));
+    c.bench_function("marf_read_400b_1kW_nocache", |b| b.iter(|| benchmark_marf_read("/tmp/db.400.sqlite", 1000, 400, 5000, 0)));
+
+    // same setup and reads with the tries in a flat data file instead of SQLite blobs
+    c.bench_function("marf_setup_1000b_5kW_flatfile", |b| b.iter(|| benchmark_marf_usage("/tmp/db.1k.flat.sqlite", 1000, 5000, 0, false, TrieStorageBackend::FlatFile)));
+    c.bench_function("marf_setup_400b_5kW_flatfile", |b| b.iter(|| benchmark_marf_usage("/tmp/db.400.flat.sqlite", 1000, 5000, 0, false, TrieStorageBackend::FlatFile)));
+    c.bench_function("marf_read_1000b_1kW_flatfile", |b| b.iter(|| benchmark_marf_read("/tmp/db.1k.flat.sqlite", 1000, 1000, 5000, MARF_NODE_CACHE_DEFAULT_CAPACITY)));
+    c.bench_function("marf_read_400b_1kW_flatfile", |b| b.iter(|| benchmark_marf_read("/tmp/db.400.flat.sqlite", 1000, 400, 5000, MARF_NODE_CACHE_DEFAULT_CAPACITY)));
+    c.bench_function("marf_read_1000b_1kW_flatfile_nocache", |b| b.iter(|| benchmark_marf_read("/tmp/db.1k.flat.sqlite", 1000, 1000, 5000, 0)));
+    c.bench_function("marf_read_400b_1kW_flatfile_nocache", |b| b.iter(|| benchmark_marf_read("/tmp/db.400.flat.sqlite", 1000, 400, 5000, 0)));
+    c.bench_function("marf_usage_10b_1kW_2kR_flatfile", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.flat.sqlite", 10, 1000, 2000, false, TrieStorageBackend::FlatFile)));
+
+    c.bench_function("marf_usage_1b_10kW_0kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 1, 10000, 0, false, TrieStorageBackend::SqliteBlobs)));
+    c.bench_function("marf_usage_10b_1kW_2kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 10, 1000, 2000, false, TrieStorageBackend::SqliteBlobs)));
+    c.bench_function("marf_usage_100b_5kW_20kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 20, 5000, 20000, false, TrieStorageBackend::SqliteBlobs)));
+    c.bench_function("marf_usage_batches_10b_1kW_2kR", |b| b.iter(|| benchmark_marf_usage("/tmp/foo.bar.z.sqlite", 10, 1000, 2000, true, TrieStorageBackend::SqliteBlobs)));
+
+    // hashing each block's trie as keys are inserted, vs. once per block across a thread pool
+    c.bench_function("marf_commit_10b_10kW_immediate", |b| b.iter(|| benchmark_marf_commit("/tmp/foo.bar.commit.sqlite", 10, 10000, TrieHashCalculationMode::Immediate, 1)));
+    c.bench_function("marf_commit_10b_10kW_deferred_1t", |b| b.iter(|| benchmark_marf_commit("/tmp/foo.bar.commit.sqlite", 10, 10000, TrieHashCalculationMode::Deferred, 1)));
+    c.bench_function("marf_commit_10b_10kW_deferred_4t", |b| b.iter(|| benchmark_marf_commit("/tmp/foo.bar.commit.sqlite", 10, 10000, TrieHashCalculationMode::Deferred, 4)));
 }
 
 pub fn scaling_read_ratio(_c: &mut Criterion) {
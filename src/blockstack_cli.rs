@@ -20,7 +20,8 @@ use blockstack_lib::chainstate::stacks::{
     C32_ADDRESS_VERSION_MAINNET_SINGLESIG, C32_ADDRESS_VERSION_TESTNET_SINGLESIG,
     StacksPrivateKey, TransactionSpendingCondition, TransactionAuth, TransactionVersion,
     StacksPublicKey, TransactionPayload, StacksTransactionSigner,
-    StacksTransaction, TransactionSmartContract, TransactionContractCall, StacksAddress, TokenTransferMemo };
+    StacksTransaction, TransactionSmartContract, TransactionContractCall, StacksAddress, TokenTransferMemo,
+    Error as ChainstateError };
 use blockstack_lib::burnchains::Address;
 use blockstack_lib::address::AddressHashMode;
 use blockstack_lib::net::{Error as NetError, StacksMessageCodec};
@@ -40,11 +41,21 @@ This CLI has these methods:
   generate-sk      used to generate a secret key for transaction signing
   token-transfer   used to generate and sign a transfer transaction
 
+These methods build transactions that are signed in separate steps, e.g. by
+multisig co-signers, offline signers, or a sponsor:
+
+  make-unsigned-tx    used to generate an unsigned single-signature transaction
+  make-multisig-tx    used to generate an unsigned multi-signature transaction
+  sign-origin         used to add an origin signature to a partially-signed transaction
+  sponsor-tx          used to set the fee of a sponsored transaction and sign it as the sponsor
+  verify-tx           used to verify a fully-signed transaction before broadcasting it
+
 For usage information on those methods, call `blockstack-cli [method] -h`
 
 `blockstack-cli` accepts flag options as well:
 
    --testnet       instruct the transaction generator to use a testnet version byte instead of MAINNET (default)
+   --sponsored     instruct make-unsigned-tx and make-multisig-tx to generate a sponsored transaction
 
 ";
 
@@ -82,6 +93,51 @@ The transfer command generates and signs a STX transfer transaction. If successf
 this command outputs the hex string encoding of the transaction to stdout, and exits with
 code 0";
 
+const MAKE_UNSIGNED_USAGE: &str = "blockstack-cli (options) make-unsigned-tx [origin-public-key-hex] [fee-rate] [nonce] [method] [method-args...]
+
+The make-unsigned-tx command generates an unsigned single-signature transaction, so that it can
+be signed elsewhere with sign-origin.  [method] is one of `publish`, `contract-call`, or
+`token-transfer`, and [method-args...] are that method's arguments after its nonce, e.g.
+
+   blockstack-cli --testnet make-unsigned-tx $public_key 10 0 token-transfer ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV 100
+
+With --sponsored, the fee-rate must be 0; the sponsor sets the fee with sponsor-tx.
+If successful, this command outputs the hex string encoding of the unsigned transaction to
+stdout, and exits with code 0";
+
+const MAKE_MULTISIG_USAGE: &str = "blockstack-cli (options) make-multisig-tx [num-signatures] [public-key-hex,public-key-hex,...] [fee-rate] [nonce] [method] [method-args...]
+
+The make-multisig-tx command generates an unsigned transaction from a P2SH multi-signature
+account, which needs [num-signatures] of the comma-separated public keys to sign it.  The
+order of the public keys matters -- it must be the same order in which the account's address
+was generated.  [method] and [method-args...] are as in make-unsigned-tx.
+
+With --sponsored, the fee-rate must be 0; the sponsor sets the fee with sponsor-tx.
+If successful, this command outputs the hex string encoding of the unsigned transaction to
+stdout, and exits with code 0";
+
+const SIGN_ORIGIN_USAGE: &str = "blockstack-cli sign-origin [transaction-hex] [origin-secret-key-hex] [public-key-hex,public-key-hex,...]
+
+The sign-origin command adds one origin signature to a partially-signed transaction.  For a
+multi-signature origin, the full ordered list of the account's public keys must be given, and
+co-signers must sign in the order of that list.  Keys that are skipped are added as public keys,
+and once enough signatures are collected, the remaining keys are added as well.
+
+If successful, this command outputs the hex string encoding of the (partially) signed
+transaction to stdout, and exits with code 0";
+
+const SPONSOR_USAGE: &str = "blockstack-cli sponsor-tx [transaction-hex] [sponsor-secret-key-hex] [fee-rate] [nonce]
+
+The sponsor-tx command takes a sponsored transaction whose origin has finished signing, sets
+the sponsor's fee-rate and nonce, and signs it as the sponsor.  If successful, this command
+outputs the hex string encoding of the signed transaction to stdout, and exits with code 0";
+
+const VERIFY_USAGE: &str = "blockstack-cli verify-tx [transaction-hex]
+
+The verify-tx command checks that all of a transaction's signatures are present and valid.
+If so, this command outputs the hex string encoding of the transaction to stdout, ready to
+broadcast, and exits with code 0";
+
 const GENERATE_USAGE: &str = "blockstack-cli (options) generate-sk
 
 This method generates a secret key, outputting the hex encoding of the
@@ -92,6 +148,7 @@ secret key, the corresponding public key, and the corresponding P2PKH Stacks add
 enum CliError {
     ClarityRuntimeError(RuntimeErrorType),
     ClarityGeneralError(ClarityError),
+    NetError(NetError),
     Message(String),
     Usage,
 }
@@ -101,6 +158,7 @@ impl std::error::Error for CliError {
         match self {
             CliError::ClarityRuntimeError(e) => Some(e),
             CliError::ClarityGeneralError(e) => Some(e),
+            CliError::NetError(e) => Some(e),
             _ => None,
         }
     }
@@ -111,6 +169,7 @@ impl std::fmt::Display for CliError {
         match self {
             CliError::ClarityRuntimeError(e) => write!(f, "Clarity error: {:?}", e), 
             CliError::ClarityGeneralError(e) => write!(f, "Clarity error: {}", e), 
+            CliError::NetError(e) => write!(f, "Stacks NetError: {}", e),
             CliError::Message(e) => write!(f, "{}", e),
             CliError::Usage => write!(f, "{}", USAGE),
         }
@@ -137,7 +196,13 @@ impl From<ClarityError> for CliError {
 
 impl From<NetError> for CliError {
     fn from(value: NetError) -> Self {
-        CliError::Message(format!("Stacks NetError: {}", value))
+        CliError::NetError(value)
+    }
+}
+
+impl From<ChainstateError> for CliError {
+    fn from(value: ChainstateError) -> Self {
+        CliError::Message(format!("Stacks chainstate error: {}", value))
     }
 }
 
@@ -184,6 +249,121 @@ fn make_contract_call(contract_address: String, contract_name: String, function_
 }
 
 
+fn read_contract_publish_payload(contract_name: &str, contract_file: &str) -> Result<TransactionPayload, CliError> {
+    let contract_contents = if contract_file == "-" {
+        let mut buffer = String::new();
+        io::stdin().read_to_string(&mut buffer)?;
+        buffer
+    } else {
+        fs::read_to_string(contract_file)?
+    };
+
+    let payload = make_contract_publish(contract_name.to_string(), contract_contents)?;
+    Ok(payload.into())
+}
+
+/// Parse `[contract-publisher-address] [contract-name] [function-name] [args...]`
+fn parse_contract_call_payload(args: &[String]) -> Result<TransactionPayload, CliError> {
+    let contract_address = &args[0];
+    let contract_name = &args[1];
+    let function_name = &args[2];
+
+    let val_args = &args[3..];
+
+    if val_args.len() % 2 != 0 {
+        return Err("contract-call arguments must be supplied as a list of `-e ...` or `-x 0000...` pairs".into())
+    }
+
+    let mut arg_iterator = 0;
+    let mut values = Vec::new();
+    while arg_iterator < val_args.len() {
+        let eval_method = &val_args[arg_iterator];
+        let input = &val_args[arg_iterator+1];
+        let value = match eval_method.as_str() {
+            "-x" => {
+                Value::try_deserialize_hex_untyped(input)?
+            },
+            "-e" => {
+                vm::execute(input)?
+                    .ok_or("Supplied argument did not evaluate to a Value")?
+            },
+            _ => {
+                return Err("contract-call arguments must be supplied as a list of `-e ...` or `-x 0000...` pairs".into())
+            }
+        };
+
+        values.push(value);
+        arg_iterator += 2;
+    }
+
+    let payload = make_contract_call(contract_address.clone(), contract_name.clone(), function_name.clone(), values)?;
+    Ok(payload.into())
+}
+
+/// Parse `[recipient-address] [amount] [memo]`
+fn parse_token_transfer_payload(args: &[String]) -> Result<TransactionPayload, CliError> {
+    let recipient_address = PrincipalData::parse(&args[0])
+        .map_err(|_e| "Failed to parse recipient")?;
+    let amount = args[1].parse()?;
+    let memo = {
+        let mut memo = [0; 34];
+        let mut bytes = if args.len() == 3 { args[2].as_bytes().to_vec() } else { vec![] };
+        bytes.resize(34, 0);
+        memo.copy_from_slice(&bytes);
+        TokenTransferMemo(memo)
+    };
+
+    Ok(TransactionPayload::TokenTransfer(recipient_address, amount, memo))
+}
+
+/// Parse a payload for an unsigned transaction, given the method that would otherwise build and
+/// sign it, and that method's arguments after its nonce.
+fn parse_payload(method: &str, args: &[String]) -> Result<TransactionPayload, CliError> {
+    match method {
+        "publish" if args.len() == 2 => read_contract_publish_payload(&args[0], &args[1]),
+        "contract-call" if args.len() >= 3 => parse_contract_call_payload(args),
+        "token-transfer" if args.len() == 2 || args.len() == 3 => parse_token_transfer_payload(args),
+        "publish" | "contract-call" | "token-transfer" => Err(CliError::Message(format!("Incorrect argument count supplied for {}", method))),
+        _ => Err(CliError::Message(format!("Unsupported transaction method: {}", method)))
+    }
+}
+
+fn parse_public_keys(public_keys: &str) -> Result<Vec<StacksPublicKey>, CliError> {
+    let mut pubkeys = vec![];
+    for pubkey_hex in public_keys.split(',') {
+        pubkeys.push(StacksPublicKey::from_hex(pubkey_hex)?);
+    }
+    Ok(pubkeys)
+}
+
+fn decode_transaction(transaction: &str) -> Result<StacksTransaction, CliError> {
+    let tx = StacksTransaction::consensus_deserialize(&mut io::Cursor::new(&hex_bytes(transaction)?))?;
+    Ok(tx)
+}
+
+fn encode_transaction(tx: &StacksTransaction) -> String {
+    let mut tx_bytes = vec![];
+    tx.consensus_serialize(&mut tx_bytes).expect("FATAL: invalid transaction");
+    to_hex(&tx_bytes)
+}
+
+fn make_unsigned_tx(version: TransactionVersion, chain_id: u32, payload: TransactionPayload, mut origin_condition: TransactionSpendingCondition,
+                    sponsored: bool, nonce: u64, fee_rate: u64) -> Result<StacksTransaction, CliError> {
+    origin_condition.set_nonce(nonce);
+    let auth = if sponsored {
+        if fee_rate != 0 {
+            return Err("Sponsored transactions must have a fee-rate of 0 -- the sponsor sets the fee".into())
+        }
+        TransactionAuth::Sponsored(origin_condition, TransactionSpendingCondition::new_initial_sighash())
+    } else {
+        origin_condition.set_fee_rate(fee_rate);
+        TransactionAuth::Standard(origin_condition)
+    };
+    let mut tx = StacksTransaction::new(version, auth, payload);
+    tx.chain_id = chain_id;
+    Ok(tx)
+}
+
 fn make_standard_single_sig_tx(version: TransactionVersion, chain_id: u32, payload: TransactionPayload,
                                publicKey: &StacksPublicKey, nonce: u64, fee_rate: u64) -> StacksTransaction {
     let mut spending_condition = TransactionSpendingCondition::new_singlesig_p2pkh(publicKey.clone())
@@ -219,18 +399,11 @@ fn handle_contract_publish(args: &[String], version: TransactionVersion, chain_i
     let contract_name = &args[3];
     let contract_file = &args[4];
 
-    let contract_contents = if contract_file == "-" {
-        let mut buffer = String::new();
-        io::stdin().read_to_string(&mut buffer)?;
-        buffer
-    } else {
-        fs::read_to_string(contract_file)?
-    };
+    let payload = read_contract_publish_payload(contract_name, contract_file)?;
 
     let sk_publisher = StacksPrivateKey::from_hex(sk_publisher)?;
 
-    let payload = make_contract_publish(contract_name.clone(), contract_contents)?;
-    let unsigned_tx = make_standard_single_sig_tx(version, chain_id, payload.into(), &StacksPublicKey::from_private(&sk_publisher),
+    let unsigned_tx = make_standard_single_sig_tx(version, chain_id, payload, &StacksPublicKey::from_private(&sk_publisher),
                                                   nonce, fee_rate);
     let mut unsigned_tx_bytes = vec![];
     unsigned_tx.consensus_serialize(&mut unsigned_tx_bytes).expect("FATAL: invalid transaction");
@@ -252,42 +425,12 @@ fn handle_contract_call(args: &[String], version: TransactionVersion, chain_id:
     let sk_origin = &args[0];
     let fee_rate = args[1].parse()?;
     let nonce = args[2].parse()?;
-    let contract_address = &args[3];
-    let contract_name = &args[4];
-    let function_name = &args[5];
-
-    let val_args = &args[6..];
-
-    if val_args.len() % 2 != 0 {
-        return Err("contract-call arguments must be supplied as a list of `-e ...` or `-x 0000...` pairs".into())
-    }
-
-    let mut arg_iterator = 0;
-    let mut values = Vec::new();
-    while arg_iterator < val_args.len() {
-        let eval_method = &val_args[arg_iterator];
-        let input = &val_args[arg_iterator+1];
-        let value = match eval_method.as_str() {
-            "-x" => {
-                Value::try_deserialize_hex_untyped(input)?
-            },
-            "-e" => {
-                vm::execute(input)?
-                    .ok_or("Supplied argument did not evaluate to a Value")?
-            },
-            _ => {
-                return Err("contract-call arguments must be supplied as a list of `-e ...` or `-x 0000...` pairs".into())
-            }
-        };
 
-        values.push(value);
-        arg_iterator += 2;
-    }
+    let payload = parse_contract_call_payload(&args[3..])?;
 
     let sk_origin = StacksPrivateKey::from_hex(sk_origin)?;
 
-    let payload = make_contract_call(contract_address.clone(), contract_name.clone(), function_name.clone(), values)?;
-    let unsigned_tx = make_standard_single_sig_tx(version, chain_id, payload.into(), &StacksPublicKey::from_private(&sk_origin),
+    let unsigned_tx = make_standard_single_sig_tx(version, chain_id, payload, &StacksPublicKey::from_private(&sk_origin),
                                                   nonce, fee_rate);
     
     let mut unsigned_tx_bytes = vec![];
@@ -310,18 +453,8 @@ fn handle_token_transfer(args: &[String], version: TransactionVersion, chain_id:
     let sk_origin = StacksPrivateKey::from_hex(&args[0])?;
     let fee_rate = args[1].parse()?;
     let nonce = args[2].parse()?;
-    let recipient_address = PrincipalData::parse(&args[3])
-        .map_err(|_e| "Failed to parse recipient")?;
-    let amount = &args[4].parse()?;
-    let memo = {
-        let mut memo = [0; 34];
-        let mut bytes = if args.len() == 6 { args[5].as_bytes().to_vec() } else { vec![] };
-        bytes.resize(34, 0);
-        memo.copy_from_slice(&bytes);
-        TokenTransferMemo(memo)
-    };
 
-    let payload = TransactionPayload::TokenTransfer(recipient_address, *amount, memo);
+    let payload = parse_token_transfer_payload(&args[3..])?;
     let unsigned_tx = make_standard_single_sig_tx(version, chain_id, payload, &StacksPublicKey::from_private(&sk_origin),
                                                   nonce, fee_rate);
     let mut unsigned_tx_bytes = vec![];
@@ -334,6 +467,146 @@ fn handle_token_transfer(args: &[String], version: TransactionVersion, chain_id:
     Ok(to_hex(&signed_tx_bytes))
 }
 
+fn handle_make_unsigned_tx(args: &[String], version: TransactionVersion, chain_id: u32, sponsored: bool) -> Result<String, CliError> {
+    if args.len() >= 1 && args[0] == "-h" {
+        return Err(CliError::Message(format!("USAGE:\n {}", MAKE_UNSIGNED_USAGE)))
+    }
+    if args.len() < 4 {
+        return Err(CliError::Message(format!("Incorrect argument count supplied \n\nUSAGE:\n {}", MAKE_UNSIGNED_USAGE)))
+    }
+    let pk_origin = StacksPublicKey::from_hex(&args[0])?;
+    let fee_rate = args[1].parse()?;
+    let nonce = args[2].parse()?;
+    let payload = parse_payload(&args[3], &args[4..])?;
+
+    let origin_condition = TransactionSpendingCondition::new_singlesig_p2pkh(pk_origin)
+        .ok_or("Failed to create p2pkh spending condition from public key")?;
+
+    let unsigned_tx = make_unsigned_tx(version, chain_id, payload, origin_condition, sponsored, nonce, fee_rate)?;
+    Ok(encode_transaction(&unsigned_tx))
+}
+
+fn handle_make_multisig_tx(args: &[String], version: TransactionVersion, chain_id: u32, sponsored: bool) -> Result<String, CliError> {
+    if args.len() >= 1 && args[0] == "-h" {
+        return Err(CliError::Message(format!("USAGE:\n {}", MAKE_MULTISIG_USAGE)))
+    }
+    if args.len() < 5 {
+        return Err(CliError::Message(format!("Incorrect argument count supplied \n\nUSAGE:\n {}", MAKE_MULTISIG_USAGE)))
+    }
+    let num_sigs : u16 = args[0].parse()?;
+    let pubkeys = parse_public_keys(&args[1])?;
+    let fee_rate = args[2].parse()?;
+    let nonce = args[3].parse()?;
+    let payload = parse_payload(&args[4], &args[5..])?;
+
+    if num_sigs == 0 || (num_sigs as usize) > pubkeys.len() {
+        return Err("Number of signatures must be between 1 and the number of public keys".into())
+    }
+
+    let origin_condition = TransactionSpendingCondition::new_multisig_p2sh(num_sigs, pubkeys)
+        .ok_or("Failed to create p2sh spending condition from public keys")?;
+
+    let unsigned_tx = make_unsigned_tx(version, chain_id, payload, origin_condition, sponsored, nonce, fee_rate)?;
+    Ok(encode_transaction(&unsigned_tx))
+}
+
+fn handle_sign_origin(args: &[String]) -> Result<String, CliError> {
+    if args.len() >= 1 && args[0] == "-h" {
+        return Err(CliError::Message(format!("USAGE:\n {}", SIGN_ORIGIN_USAGE)))
+    }
+    if args.len() != 2 && args.len() != 3 {
+        return Err(CliError::Message(format!("Incorrect argument count supplied \n\nUSAGE:\n {}", SIGN_ORIGIN_USAGE)))
+    }
+    let tx = decode_transaction(&args[0])?;
+    let sk_origin = StacksPrivateKey::from_hex(&args[1])?;
+    let pk_origin = StacksPublicKey::from_private(&sk_origin);
+
+    let mut tx_signer = StacksTransactionSigner::new_partial(&tx)?;
+    match tx.auth().origin() {
+        TransactionSpendingCondition::Singlesig(_) => {
+            if args.len() == 3 {
+                return Err("A public key list is only used with multi-signature origins".into())
+            }
+            tx_signer.sign_origin(&sk_origin)?;
+        },
+        TransactionSpendingCondition::Multisig(ref data) => {
+            if args.len() != 3 {
+                return Err("Multi-signature origins need the ordered list of the account's public keys".into())
+            }
+            let pubkeys = parse_public_keys(&args[2])?;
+            let signer = StacksAddress::from_public_keys(0, &data.hash_mode.to_address_hash_mode(), data.signatures_required as usize, &pubkeys)
+                .ok_or("Failed to generate address from public keys")?;
+            if signer.bytes != data.signer {
+                return Err("Public keys do not match the transaction's origin account".into())
+            }
+
+            let key_index = pubkeys.iter().position(|pubkey| *pubkey == pk_origin)
+                .ok_or("Secret key does not match any of the origin account's public keys")?;
+            let num_fields = data.fields.len();
+            if key_index < num_fields {
+                return Err("This key's turn to sign has passed -- co-signers must sign in the order of the public key list".into())
+            }
+
+            for pubkey in pubkeys[num_fields..key_index].iter() {
+                tx_signer.append_origin(pubkey)?;
+            }
+            tx_signer.sign_origin(&sk_origin)?;
+
+            // once there are enough signatures, the rest of the keys go in as public keys
+            if tx_signer.get_tx_incomplete().auth().origin().num_signatures() >= data.signatures_required {
+                for pubkey in pubkeys[(key_index + 1)..].iter() {
+                    tx_signer.append_origin(pubkey)?;
+                }
+            }
+        }
+    }
+
+    Ok(encode_transaction(&tx_signer.get_tx_incomplete()))
+}
+
+fn handle_sponsor_tx(args: &[String]) -> Result<String, CliError> {
+    if args.len() >= 1 && args[0] == "-h" {
+        return Err(CliError::Message(format!("USAGE:\n {}", SPONSOR_USAGE)))
+    }
+    if args.len() != 4 {
+        return Err(CliError::Message(format!("Incorrect argument count supplied \n\nUSAGE:\n {}", SPONSOR_USAGE)))
+    }
+    let tx = decode_transaction(&args[0])?;
+    let sk_sponsor = StacksPrivateKey::from_hex(&args[1])?;
+    let fee_rate = args[2].parse()?;
+    let nonce = args[3].parse()?;
+
+    if !tx.auth().is_sponsored() {
+        return Err("Not a sponsored transaction".into())
+    }
+
+    let mut sponsor_condition = TransactionSpendingCondition::new_singlesig_p2pkh(StacksPublicKey::from_private(&sk_sponsor))
+        .ok_or("Failed to create p2pkh spending condition from public key")?;
+    sponsor_condition.set_nonce(nonce);
+    sponsor_condition.set_fee_rate(fee_rate);
+
+    // fails if the origin hasn't finished signing
+    let mut tx_signer = StacksTransactionSigner::new_sponsor(&tx, sponsor_condition)?;
+    tx_signer.sign_sponsor(&sk_sponsor)?;
+
+    let signed_tx = tx_signer.get_tx()
+        .ok_or("TX did not finish signing")?;
+    signed_tx.verify()?;
+
+    Ok(encode_transaction(&signed_tx))
+}
+
+fn handle_verify_tx(args: &[String]) -> Result<String, CliError> {
+    if args.len() >= 1 && args[0] == "-h" {
+        return Err(CliError::Message(format!("USAGE:\n {}", VERIFY_USAGE)))
+    }
+    if args.len() != 1 {
+        return Err(CliError::Message(format!("Incorrect argument count supplied \n\nUSAGE:\n {}", VERIFY_USAGE)))
+    }
+    let tx = decode_transaction(&args[0])?;
+    tx.verify()?;
+    Ok(encode_transaction(&tx))
+}
 
 fn generate_secret_key(args: &[String], version: TransactionVersion) -> Result<String, CliError> {
     if args.len() >= 1 && args[0] == "-h" {
@@ -385,6 +658,13 @@ fn main_handler(mut argv: Vec<String>) -> Result<String, CliError> {
         TransactionVersion::Mainnet
     };
 
+    let sponsored = if let Some(ix) = argv.iter().position(|x| x == "--sponsored") {
+        argv.remove(ix);
+        true
+    } else {
+        false
+    };
+
     let chain_id = 
         if tx_version == TransactionVersion::Testnet {
             TESTNET_CHAIN_ID
@@ -399,6 +679,11 @@ fn main_handler(mut argv: Vec<String>) -> Result<String, CliError> {
             "publish" => handle_contract_publish(args, tx_version, chain_id),
             "token-transfer" => handle_token_transfer(args, tx_version, chain_id),
             "generate-sk" => generate_secret_key(args, tx_version),
+            "make-unsigned-tx" => handle_make_unsigned_tx(args, tx_version, chain_id, sponsored),
+            "make-multisig-tx" => handle_make_multisig_tx(args, tx_version, chain_id, sponsored),
+            "sign-origin" => handle_sign_origin(args),
+            "sponsor-tx" => handle_sponsor_tx(args),
+            "verify-tx" => handle_verify_tx(args),
             _ => Err(CliError::Usage)
         }
     } else {
@@ -492,6 +777,109 @@ mod test {
                 .contains("Failed to parse recipient"));
     }
 
+    #[test]
+    fn multisig_sponsored_flow() {
+        let sk_1 = "6d430bb91222408e7706c9001cfaeb91b08c2be6d5ac95779ab52c6b431950e001";
+        let sk_2 = "2a584d899fed1d24e26b524f202763c8ab30260167429f157f1c119f550fa6af01";
+        let sk_3 = "d5200dee706ee53ae98a03fba6cf4fdcc5084c30cfa9e1b3462dcdeaa3e0f1d201";
+        let sk_sponsor = "043ff5004e3d695060fa48ac94c96049b8c14ef441c50a184a6a3875d2a000f301";
+
+        let pubkeys : Vec<String> = [sk_1, sk_2, sk_3].iter()
+            .map(|sk| StacksPublicKey::from_private(&StacksPrivateKey::from_hex(sk).unwrap()).to_hex())
+            .collect();
+        let pubkeys = pubkeys.join(",");
+
+        let make_args = [
+            "--testnet",
+            "--sponsored",
+            "make-multisig-tx",
+            "2",
+            pubkeys.as_str(),
+            "0",
+            "0",
+            "token-transfer",
+            "ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV",
+            "10"];
+
+        let unsigned_tx = main_handler(to_string_vec(&make_args)).unwrap();
+        match main_handler(to_string_vec(&["verify-tx", unsigned_tx.as_str()])) {
+            Err(CliError::NetError(NetError::VerifyingError(_))) => {},
+            res => panic!("Verified an unsigned transaction: {:?}", &res)
+        }
+
+        // sponsors must wait for the origin to finish signing
+        assert!(main_handler(to_string_vec(&["sponsor-tx", unsigned_tx.as_str(), sk_sponsor, "200", "0"])).is_err());
+
+        // co-signers 1 and 3 sign, in list order
+        let partial_tx = main_handler(to_string_vec(&["sign-origin", unsigned_tx.as_str(), sk_1, pubkeys.as_str()])).unwrap();
+        assert!(main_handler(to_string_vec(&["verify-tx", partial_tx.as_str()])).is_err());
+
+        let origin_signed_tx = main_handler(to_string_vec(&["sign-origin", partial_tx.as_str(), sk_3, pubkeys.as_str()])).unwrap();
+
+        // nobody else can sign now
+        assert!(format!("{}", main_handler(to_string_vec(&["sign-origin", origin_signed_tx.as_str(), sk_2, pubkeys.as_str()])).unwrap_err())
+                .contains("turn to sign has passed"));
+
+        let signed_tx = main_handler(to_string_vec(&["sponsor-tx", origin_signed_tx.as_str(), sk_sponsor, "200", "3"])).unwrap();
+        assert_eq!(main_handler(to_string_vec(&["verify-tx", signed_tx.as_str()])).unwrap(), signed_tx);
+
+        let tx = decode_transaction(signed_tx.as_str()).unwrap();
+        assert!(tx.auth().is_sponsored());
+        assert_eq!(tx.get_fee_rate(), 200);
+        assert_eq!(tx.get_sponsor_nonce(), Some(3));
+        assert_eq!(tx.auth().origin().num_signatures(), 2);
+
+        // sponsored transactions leave the fee to the sponsor
+        let make_args = [
+            "--sponsored",
+            "make-multisig-tx",
+            "2",
+            pubkeys.as_str(),
+            "1",
+            "0",
+            "token-transfer",
+            "ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV",
+            "10"];
+
+        assert!(format!("{}", main_handler(to_string_vec(&make_args)).unwrap_err())
+                .contains("fee-rate of 0"));
+    }
+
+    #[test]
+    fn unsigned_single_sig_flow() {
+        let sk = "043ff5004e3d695060fa48ac94c96049b8c14ef441c50a184a6a3875d2a000f3";
+        let pubkey = StacksPublicKey::from_private(&StacksPrivateKey::from_hex(sk).unwrap()).to_hex();
+
+        let make_args = [
+            "make-unsigned-tx",
+            pubkey.as_str(),
+            "1",
+            "0",
+            "token-transfer",
+            "ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV",
+            "10",
+            "Memo"];
+
+        let unsigned_tx = main_handler(to_string_vec(&make_args)).unwrap();
+        let signed_tx = main_handler(to_string_vec(&["sign-origin", unsigned_tx.as_str(), sk])).unwrap();
+
+        // same transaction as one built and signed in one step
+        let tt_args = [
+            "token-transfer",
+            sk,
+            "1",
+            "0",
+            "ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV",
+            "10",
+            "Memo"];
+
+        assert_eq!(main_handler(to_string_vec(&tt_args)).unwrap(), signed_tx);
+        assert_eq!(main_handler(to_string_vec(&["verify-tx", signed_tx.as_str()])).unwrap(), signed_tx);
+
+        assert!(format!("{}", main_handler(to_string_vec(&["make-unsigned-tx", pubkey.as_str(), "1", "0", "mint", "10"])).unwrap_err())
+                .contains("Unsupported transaction method"));
+    }
+
     #[test]
     fn simple_cc() {
         let cc_args = [
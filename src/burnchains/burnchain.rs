@@ -19,6 +19,7 @@
 
 use std::path::PathBuf;
 use std::fs;
+use std::cmp;
 use std::thread;
 use std::sync::mpsc::sync_channel;
 use std::time::Instant;
@@ -47,6 +48,7 @@ use burnchains::Error as burnchain_error;
 use burnchains::indexer::{BurnchainIndexer, BurnchainBlockParser, BurnchainBlockDownloader, BurnBlockIPC};
 use burnchains::BurnchainParameters;
 use burnchains::BurnchainStateTransition;
+use burnchains::BurnchainReplay;
 
 use burnchains::bitcoin::{BitcoinTxInput, BitcoinTxOutput, BitcoinInputType};
 use burnchains::bitcoin::address::to_c32_version_byte;
@@ -803,6 +805,76 @@ impl Burnchain {
         }
     }
 
+    /// Download, parse, and process the burnchain blocks in [start_block, end_block], one at a
+    /// time.  Returns the new snapshots, in order.
+    fn replay_blocks<I: BurnchainIndexer>(&self, indexer: &mut I, burndb: &mut BurnDB, start_block: u64, end_block: u64) -> Result<Vec<BlockSnapshot>, burnchain_error> {
+        let mut downloader = indexer.downloader();
+        let mut parser = indexer.parser();
+
+        let mut replayed = vec![];
+        let input_headers = indexer.read_headers(start_block, end_block + 1)?;
+        for ipc_header in input_headers.iter() {
+            let ipc_block = downloader.download(ipc_header)?;
+            let burnchain_block = parser.parse(&ipc_block)?;
+            if burnchain_block.block_height() == 0 {
+                continue;
+            }
+
+            debug!("Replay burnchain block {} {}", burnchain_block.block_height(), &burnchain_block.block_hash());
+            let (snapshot, _) = Burnchain::process_block(burndb, self, &burnchain_block)?;
+            replayed.push(snapshot);
+        }
+        Ok(replayed)
+    }
+
+    /// Rewind the burn DB to the given height and re-process the burnchain blocks above it.
+    /// See rewind_and_replay_with_indexer().
+    pub fn rewind_and_replay<I: BurnchainIndexer>(&mut self, rewind_height: u64) -> Result<BurnchainReplay, burnchain_error> {
+        let mut indexer: I = self.make_indexer()?;
+        self.rewind_and_replay_with_indexer(&mut indexer, rewind_height)
+    }
+
+    /// Rewind the burn DB to the given height (or lower, if the burnchain reorged below it),
+    /// re-process all burnchain blocks above it, and compare the recomputed consensus hashes
+    /// against the ones we had for the same burnchain blocks.  The caller is responsible for
+    /// rolling back the Stacks chainstate for the rewound snapshots.
+    pub fn rewind_and_replay_with_indexer<I: BurnchainIndexer>(&mut self, indexer: &mut I, rewind_height: u64) -> Result<BurnchainReplay, burnchain_error> {
+        self.setup_chainstate(indexer)?;
+        let mut burndb = self.connect_db(indexer, true)?;
+
+        // if the burnchain reorged below the rewind height, then we have to go back further
+        let orig_header_height = indexer.get_headers_height()?;     // 1-indexed
+        let sync_height = Burnchain::sync_reorg(indexer)?;
+        if sync_height + 1 < orig_header_height {
+            warn!("Dropping headers higher than {} due to burnchain reorg", sync_height);
+            indexer.drop_headers(sync_height)?;
+        }
+        let end_block = indexer.sync_headers(sync_height, None)?;
+
+        let rewind_height = cmp::max(cmp::min(rewind_height, sync_height), burndb.first_block_height);
+        let rewound = burndb.rewind_to_height(rewind_height)?;
+
+        info!("Replay burnchain blocks {}-{}...", rewind_height + 1, end_block);
+        let replayed = self.replay_blocks(indexer, &mut burndb, rewind_height + 1, end_block)?;
+
+        let mut mismatches = vec![];
+        for new_sn in replayed.iter() {
+            if let Some(old_sn) = rewound.iter().find(|sn| sn.burn_header_hash == new_sn.burn_header_hash) {
+                if old_sn.consensus_hash != new_sn.consensus_hash {
+                    error!("Consensus hash mismatch at burn block {} {}: had {}, recomputed {}",
+                           new_sn.block_height, &new_sn.burn_header_hash, &old_sn.consensus_hash, &new_sn.consensus_hash);
+                    mismatches.push((old_sn.clone(), new_sn.clone()));
+                }
+            }
+        }
+
+        Ok(BurnchainReplay {
+            rewound,
+            replayed,
+            mismatches
+        })
+    }
+
     /// Top-level burnchain sync.
     /// Returns new latest block height.
     pub fn sync<I: BurnchainIndexer + 'static>(&mut self) -> Result<u64, burnchain_error> {
@@ -815,16 +887,25 @@ impl Burnchain {
     /// Returns (snapshot of new burnchain tip, last state-transition processed if any)
     /// If this method returns Err(burnchain_error::TrySyncAgain), then call this method again.
     pub fn sync_with_indexer<I: BurnchainIndexer + 'static>(&mut self, indexer: &mut I) -> Result<(BlockSnapshot, Option<BurnchainStateTransition>), burnchain_error> {
+        let mut rewound = vec![];
+        self.sync_with_indexer_and_rewind(indexer, &mut rewound)
+    }
+
+    /// Top-level burnchain sync.  If a burnchain reorg is detected, the burn DB is rewound to the
+    /// highest common ancestor before the new fork's blocks are processed, and the removed
+    /// snapshots are appended to `rewound` so the caller can roll back its Stacks chainstate.
+    /// Returns (snapshot of new burnchain tip, last state-transition processed if any)
+    /// If this method returns Err(burnchain_error::TrySyncAgain), then call this method again
+    /// with the same `rewound` vector.
+    pub fn sync_with_indexer_and_rewind<I: BurnchainIndexer + 'static>(&mut self, indexer: &mut I, rewound: &mut Vec<BlockSnapshot>) -> Result<(BlockSnapshot, Option<BurnchainStateTransition>), burnchain_error> {
         self.setup_chainstate(indexer)?;
         let mut burndb = self.connect_db(indexer, true)?;
-        let burn_chain_tip = BurnDB::get_canonical_burn_chain_tip(burndb.conn())
+        let mut burn_chain_tip = BurnDB::get_canonical_burn_chain_tip(burndb.conn())
             .map_err(|e| {
                 error!("Failed to query burn chain tip from burn DB");
                 burnchain_error::DBError(e)
             })?;
 
-        let db_height = burn_chain_tip.block_height;
-
         // handle reorgs
         let orig_header_height = indexer.get_headers_height()?;     // 1-indexed
         let sync_height = Burnchain::sync_reorg(indexer)?;
@@ -832,8 +913,20 @@ impl Burnchain {
             // a reorg happened
             warn!("Dropping headers higher than {} due to burnchain reorg", sync_height);
             indexer.drop_headers(sync_height)?;
+
+            // forget the snapshots of the old fork as well
+            let rewind_height = cmp::max(sync_height, burndb.first_block_height);
+            if rewind_height < burn_chain_tip.block_height {
+                warn!("Rewinding burn DB from {} to {} due to burnchain reorg", burn_chain_tip.block_height, rewind_height);
+                let mut removed = burndb.rewind_to_height(rewind_height)?;
+                rewound.append(&mut removed);
+
+                burn_chain_tip = BurnDB::get_canonical_burn_chain_tip(burndb.conn())?;
+            }
         }
 
+        let db_height = burn_chain_tip.block_height;
+
         // get latest headers.
         debug!("Sync headers from {}", sync_height);
 
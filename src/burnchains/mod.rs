@@ -56,6 +56,7 @@ use core::*;
 
 use chainstate::burn::operations::Error as op_error;
 use chainstate::burn::ConsensusHash;
+use chainstate::burn::BlockSnapshot;
 
 use chainstate::stacks::StacksAddress;
 use chainstate::stacks::StacksPublicKey; 
@@ -292,6 +293,19 @@ pub struct BurnchainStateTransition {
     pub consumed_leader_keys: Vec<LeaderKeyRegisterOp>
 }
 
+/// The outcome of rewinding the burn DB and re-processing the burnchain blocks above the rewind
+/// height:
+/// -- the snapshots that were removed by the rewind
+/// -- the snapshots produced by re-processing the blocks
+/// -- the (removed, re-processed) snapshot pairs for the same burnchain block whose consensus
+/// hashes disagree
+#[derive(Debug, Clone)]
+pub struct BurnchainReplay {
+    pub rewound: Vec<BlockSnapshot>,
+    pub replayed: Vec<BlockSnapshot>,
+    pub mismatches: Vec<(BlockSnapshot, BlockSnapshot)>
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// Unsupported burn chain
@@ -1171,6 +1171,101 @@ impl BurnDB {
         }
     }
 
+    /// Get all snapshots above a given block height, in all forks, in ascending height order.
+    pub fn get_snapshots_above(conn: &Connection, block_height: u64) -> Result<Vec<BlockSnapshot>, db_error> {
+        let qry = "SELECT * FROM snapshots WHERE block_height > ?1 ORDER BY block_height ASC, burn_header_hash ASC";
+        let args: &[&dyn ToSql] = &[&u64_to_sql(block_height)?];
+        query_rows::<BlockSnapshot, _>(conn, qry, args)
+    }
+
+    /// Rewind the sortition DB to the given burn block height, in all forks.  Every snapshot
+    /// above this height is removed, along with the burnchain operations it contains, the
+    /// Stacks blocks accepted in it, and its index trie.  The removed snapshots are returned
+    /// (in ascending height order) so the caller can roll back the Stacks chainstate and verify
+    /// the re-processed blocks against them.
+    pub fn rewind_to_height(&mut self, block_height: u64) -> Result<Vec<BlockSnapshot>, db_error> {
+        if !self.readwrite {
+            return Err(db_error::ReadOnly);
+        }
+        if block_height < self.first_block_height {
+            return Err(db_error::NotFoundError);
+        }
+
+        let removed = BurnDB::get_snapshots_above(&self.conn, block_height)?;
+        if removed.len() == 0 {
+            return Ok(removed);
+        }
+
+        info!("Rewind burn DB to height {}: remove {} snapshot(s)", block_height, removed.len());
+        self.forget_snapshots(&removed)?;
+        Ok(removed)
+    }
+
+    /// Remove the given snapshots, along with the burnchain operations they contain, the Stacks
+    /// blocks accepted in them, and their index tries.  The caller must remove every descendant
+    /// of a removed snapshot as well.
+    pub fn forget_snapshots(&mut self, removed: &[BlockSnapshot]) -> Result<(), db_error> {
+        if !self.readwrite {
+            return Err(db_error::ReadOnly);
+        }
+
+        let tx = tx_begin_immediate(&mut self.conn)?;
+        for sn in removed.iter() {
+            let args: &[&dyn ToSql] = &[&sn.burn_header_hash];
+            tx.execute("DELETE FROM leader_keys WHERE burn_header_hash = ?1", args).map_err(db_error::SqliteError)?;
+            tx.execute("DELETE FROM block_commits WHERE burn_header_hash = ?1", args).map_err(db_error::SqliteError)?;
+            tx.execute("DELETE FROM user_burn_support WHERE burn_header_hash = ?1", args).map_err(db_error::SqliteError)?;
+            tx.execute("DELETE FROM canonical_accepted_stacks_blocks WHERE burn_block_hash = ?1 OR tip_burn_block_hash = ?1", args).map_err(db_error::SqliteError)?;
+            tx.execute("DELETE FROM snapshots WHERE burn_header_hash = ?1", args).map_err(db_error::SqliteError)?;
+        }
+        tx.commit().map_err(db_error::SqliteError)?;
+
+        // the index tries go last -- a failure here leaves dangling tries, but never dangling snapshots
+        let bhhs : Vec<BlockHeaderHash> = removed.iter().map(|sn| BlockHeaderHash::from(sn.burn_header_hash.clone())).collect();
+        self.marf.drop_blocks(&bhhs).map_err(db_error::IndexError)?;
+
+        Ok(())
+    }
+
+    /// Make the given Stacks fork's tip the memoized canonical Stacks chain tip of the canonical
+    /// burn chain tip.  This is used once the burn DB has been cut back to a given burn block
+    /// (i.e. by rewind_to_height()), which forgets Stacks blocks that were accepted while later
+    /// burn blocks were the chain tip.  Any such block in the fork is remembered again as
+    /// accepted at the burn chain tip, so the next burn block will commit to it.
+    /// The fork is given as (burn header hash, block hash, height), from its tip down; it need
+    /// not include the boot block.
+    pub fn reset_canonical_stacks_chain_tip(&mut self, stacks_fork: &[(BurnchainHeaderHash, BlockHeaderHash, u64)]) -> Result<(), db_error> {
+        let (tip_burn_header_hash, tip_block_hash, tip_height) = match stacks_fork.first() {
+            Some(tip) => tip.clone(),
+            None => {
+                return Ok(());
+            }
+        };
+
+        let mut tx = self.tx_begin()?;
+        let burn_tip = BurnDB::get_canonical_burn_chain_tip(&tx)?;
+
+        for (burn_header_hash, block_hash, height) in stacks_fork.iter() {
+            let stacks_block_key = BurnDB::make_stacks_block_index_key(block_hash);
+            let accepted = BurnDB::get_accepted_stacks_block_pointer(&tx, &burn_tip.burn_header_hash, block_hash)?.is_some()
+                || BurnDB::index_value_get(&tx.as_conn(), &burn_tip.burn_header_hash, &stacks_block_key)?.is_some();
+
+            if accepted {
+                // blocks are accepted in order, so this block's ancestors are remembered too
+                break;
+            }
+            BurnDB::insert_accepted_stacks_block_pointer(&mut tx, &burn_tip.burn_header_hash, burn_header_hash, block_hash, *height)?;
+        }
+
+        let args : &[&dyn ToSql] = &[&tip_burn_header_hash, &tip_block_hash, &u64_to_sql(tip_height)?, &burn_tip.burn_header_hash];
+        tx.execute("UPDATE snapshots SET canonical_stacks_tip_burn_hash = ?1, canonical_stacks_tip_hash = ?2, canonical_stacks_tip_height = ?3
+                    WHERE burn_header_hash = ?4", args)
+            .map_err(db_error::SqliteError)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
     /// Begin a transaction.
     pub fn tx_begin<'a>(&'a mut self) -> Result<BurnDBTx<'a>, db_error> {
         if !self.readwrite {
@@ -3001,6 +3096,124 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rewind_to_height() {
+        // Create a set of forks that looks like this:
+        // 0-1-2-3-4-5-6-7-8-9 (fork 0)
+        //      \
+        //       4-5-6 (fork 1)
+        //
+        // Then, rewind to 2 and confirm that both forks are gone above it, and that the removed
+        // blocks can be processed again.
+        let first_burn_hash = BurnchainHeaderHash([0x00; 32]);
+        let first_block_height = 100;
+        let vtxindex = 456;
+
+        let mut db = BurnDB::connect_test(first_block_height, &first_burn_hash).unwrap();
+
+        let leader_key = LeaderKeyRegisterOp {
+            consensus_hash: ConsensusHash::from_bytes(&hex_bytes("2222222222222222222222222222222222222222").unwrap()).unwrap(),
+            public_key: VRFPublicKey::from_bytes(&hex_bytes("a366b51292bef4edd64063d9145c617fec373bceb0758e98cd72becd84d54c7a").unwrap()).unwrap(),
+            memo: vec![01, 02, 03, 04, 05],
+            address: StacksAddress::from_bitcoin_address(&BitcoinAddress::from_scriptpubkey(BitcoinNetworkType::Testnet, &hex_bytes("76a9140be3e286a15ea85882761618e366586b5574100d88ac").unwrap()).unwrap()),
+
+            txid: Txid::from_bytes_be(&hex_bytes("1bfa831b5fc56c858198acb8e77e5863c1e9d8ac26d49ddb914e24d8d4083562").unwrap()).unwrap(),
+            vtxindex: vtxindex,
+            block_height: first_block_height + 4,
+            burn_header_hash: BurnchainHeaderHash([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,4])
+        };
+
+        let mut fork_0 = vec![];
+        let mut last_snapshot = BurnDB::get_first_block_snapshot(db.conn()).unwrap();
+        for i in 0..10 {
+            let mut next_snapshot = last_snapshot.clone();
+
+            next_snapshot.block_height += 1;
+            next_snapshot.num_sortitions += 1;
+            next_snapshot.parent_burn_header_hash = next_snapshot.burn_header_hash.clone();
+            next_snapshot.burn_header_hash = BurnchainHeaderHash([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,i + 1]);
+            next_snapshot.consensus_hash = ConsensusHash([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,i + 1]);
+
+            let ops = if next_snapshot.block_height == first_block_height + 4 {
+                vec![BlockstackOperationType::LeaderKeyRegister(leader_key.clone())]
+            }
+            else {
+                vec![]
+            };
+
+            let mut tx = db.tx_begin().unwrap();
+            next_snapshot.index_root = BurnDB::append_chain_tip_snapshot(&mut tx, &last_snapshot, &next_snapshot, &ops, &vec![]).unwrap();
+            tx.commit().unwrap();
+
+            fork_0.push((next_snapshot.clone(), ops));
+            last_snapshot = next_snapshot;
+        }
+
+        let mut last_snapshot = fork_0[2].0.clone();
+        for i in 3..6 {
+            let mut next_snapshot = last_snapshot.clone();
+
+            next_snapshot.block_height += 1;
+            next_snapshot.num_sortitions += 1;
+            next_snapshot.parent_burn_header_hash = next_snapshot.burn_header_hash.clone();
+            next_snapshot.burn_header_hash = BurnchainHeaderHash([1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,i + 1]);
+            next_snapshot.consensus_hash = ConsensusHash([1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,i + 1]);
+
+            let mut tx = db.tx_begin().unwrap();
+            next_snapshot.index_root = BurnDB::append_chain_tip_snapshot(&mut tx, &last_snapshot, &next_snapshot, &vec![], &vec![]).unwrap();
+            tx.commit().unwrap();
+
+            last_snapshot = next_snapshot;
+        }
+
+        // rewind both forks
+        let removed = db.rewind_to_height(first_block_height + 3).unwrap();
+        assert_eq!(removed.len(), 7 + 3);
+        for i in 1..removed.len() {
+            assert!(removed[i-1].block_height <= removed[i].block_height);
+        }
+
+        let tip = BurnDB::get_canonical_burn_chain_tip(db.conn()).unwrap();
+        assert_eq!(tip, fork_0[2].0);
+        assert_eq!(BurnDB::get_snapshots_above(db.conn(), first_block_height + 3).unwrap().len(), 0);
+        assert!(BurnDB::get_block_snapshot(db.conn(), &last_snapshot.burn_header_hash).unwrap().is_none());
+
+        // nothing left to remove
+        assert_eq!(db.rewind_to_height(first_block_height + 3).unwrap().len(), 0);
+
+        // can't rewind past the first block
+        match db.rewind_to_height(first_block_height - 1) {
+            Err(db_error::NotFoundError) => {},
+            x => panic!("Unexpected result {:?}", &x)
+        }
+
+        // re-process the removed blocks of fork 0
+        let mut last_snapshot = fork_0[2].0.clone();
+        for (snapshot, ops) in fork_0[3..].iter() {
+            let mut tx = db.tx_begin().unwrap();
+            let index_root = BurnDB::append_chain_tip_snapshot(&mut tx, &last_snapshot, snapshot, ops, &vec![]).unwrap();
+            tx.commit().unwrap();
+
+            // same state, same index root
+            assert_eq!(index_root, snapshot.index_root);
+            last_snapshot = snapshot.clone();
+        }
+
+        let tip = BurnDB::get_canonical_burn_chain_tip(db.conn()).unwrap();
+        assert_eq!(tip, fork_0[9].0);
+
+        {
+            let ic = db.index_conn();
+            let leader_key_opt = BurnDB::get_leader_key_at(&ic, first_block_height + 4, vtxindex, &tip.burn_header_hash).unwrap();
+            assert_eq!(leader_key_opt.unwrap(), leader_key);
+
+            for (snapshot, _) in fork_0.iter() {
+                let ancestor = BurnDB::get_ancestor_snapshot(&ic, snapshot.block_height, &tip.burn_header_hash).unwrap().unwrap();
+                assert_eq!(ancestor, *snapshot);
+            }
+        }
+    }
+
     #[test]
     fn test_get_stacks_header_hashes() {
         let first_burn_hash = BurnchainHeaderHash::from_hex("10000000000000000000000000000000000000000000000000000000000000ff").unwrap();
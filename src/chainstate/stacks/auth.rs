@@ -688,6 +688,31 @@ impl TransactionSpendingCondition {
             TransactionSpendingCondition::Multisig(ref data) => data.verify(initial_sighash, cond_code)
         }
     }
+
+    /// Replay the signatures collected so far against an initial sighash, and return the sighash
+    /// the next signer must sign.  Unlike verify(), the spending condition need not be complete,
+    /// and the recovered public keys are not checked against the signer hash.
+    pub fn replay_signatures(&self, initial_sighash: &Txid, cond_code: &TransactionAuthFlags) -> Result<Txid, net_error> {
+        match *self {
+            TransactionSpendingCondition::Singlesig(ref data) => {
+                if data.signature == MessageSignature::empty() {
+                    return Ok(initial_sighash.clone());
+                }
+                let (_, next_sighash) = TransactionSpendingCondition::next_verification(initial_sighash, cond_code, data.fee_rate, data.nonce, &data.key_encoding, &data.signature)?;
+                Ok(next_sighash)
+            },
+            TransactionSpendingCondition::Multisig(ref data) => {
+                let mut cur_sighash = initial_sighash.clone();
+                for field in data.fields.iter() {
+                    if let TransactionAuthField::Signature(ref pubkey_encoding, ref sigbuf) = field {
+                        let (_, next_sighash) = TransactionSpendingCondition::next_verification(&cur_sighash, cond_code, data.fee_rate, data.nonce, pubkey_encoding, sigbuf)?;
+                        cur_sighash = next_sighash;
+                    }
+                }
+                Ok(cur_sighash)
+            }
+        }
+    }
 }
 
 impl StacksMessageCodec for TransactionAuth {
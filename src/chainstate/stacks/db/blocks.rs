@@ -54,6 +54,7 @@ use util::db::{
     query_count,
     query_int,
     tx_busy_handler,
+    tx_begin_immediate,
 };
 
 use util::strings::StacksString;
@@ -99,6 +100,8 @@ use vm::database::ClarityDatabase;
 
 use vm::contracts::Contract;
 
+use monitoring;
+
 use rand::RngCore;
 use rand::thread_rng;
 
@@ -3148,6 +3151,8 @@ impl StacksChainState {
             match self.process_next_staging_block(&mut tx) {
                 Ok((next_tip_opt, next_microblock_poison_opt)) => match next_tip_opt {
                     Some(next_tip) => {
+                        monitoring::increment_stx_blocks_processed_counter();
+                        monitoring::set_stacks_tip_height(next_tip.0.block_height);
                         ret.push((Some(next_tip), next_microblock_poison_opt));
                     },
                     None => {
@@ -3164,15 +3169,18 @@ impl StacksChainState {
                 },
                 Err(Error::InvalidStacksBlock(msg)) => {
                     warn!("Encountered invalid block: {}", &msg);
+                    monitoring::increment_stx_blocks_rejected_counter();
                     continue;
                 },
                 Err(Error::InvalidStacksMicroblock(msg, hash)) => {
                     warn!("Encountered invalid microblock {}: {}", hash, &msg);
+                    monitoring::increment_stx_blocks_rejected_counter();
                     continue;
                 },
                 Err(Error::NetError(net_error::DeserializeError(msg))) => {
                     // happens if we load a zero-sized block (i.e. an invalid block)
                     warn!("Encountered invalid block: {}", &msg);
+                    monitoring::increment_stx_blocks_rejected_counter();
                     continue;
                 },
                 Err(e) => {
@@ -3197,6 +3205,93 @@ impl StacksChainState {
         Ok(ret)
     }
 
+    /// Roll back every processed Stacks block that was selected in one of the given burnchain
+    /// blocks -- i.e. the blocks removed by BurnDB::rewind_to_height().  The block headers,
+    /// payments, and index/Clarity tries are forgotten, but the blocks themselves stay in the
+    /// chunk store and are re-queued as staging blocks, so they will be re-processed if the
+    /// burnchain blocks that selected them come back.
+    /// Returns the headers of the rolled-back blocks.
+    pub fn rollback_blocks(&mut self, burn_hashes: &[BurnchainHeaderHash]) -> Result<Vec<StacksHeaderInfo>, Error> {
+        let mut rolled_back = vec![];
+        for burn_hash in burn_hashes.iter() {
+            let sql = "SELECT * FROM block_headers WHERE burn_header_hash = ?1".to_string();
+            let args: &[&dyn ToSql] = &[burn_hash];
+            let mut rows = query_rows::<StacksHeaderInfo, _>(&self.headers_db, &sql, args).map_err(Error::DBError)?;
+            rolled_back.append(&mut rows);
+        }
+
+        if rolled_back.len() == 0 {
+            return Ok(rolled_back);
+        }
+
+        info!("Roll back {} Stacks block(s)", rolled_back.len());
+
+        let index_block_hashes : Vec<BlockHeaderHash> = rolled_back.iter().map(|hdr| hdr.index_block_hash()).collect();
+        {
+            let headers_tx = tx_begin_immediate(&mut self.headers_db)?;
+            for (hdr, index_block_hash) in rolled_back.iter().zip(index_block_hashes.iter()) {
+                let block_hash = hdr.anchored_header.block_hash();
+                let args: &[&dyn ToSql] = &[&hdr.burn_header_hash, &block_hash];
+                headers_tx.execute("DELETE FROM microblock_headers WHERE parent_burn_header_hash = ?1 AND parent_block_hash = ?2", args)
+                    .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+                headers_tx.execute("DELETE FROM user_supporters WHERE burn_header_hash = ?1 AND block_hash = ?2", args)
+                    .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+                headers_tx.execute("DELETE FROM block_headers WHERE burn_header_hash = ?1 AND block_hash = ?2", args)
+                    .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+                headers_tx.execute("DELETE FROM payments WHERE index_block_hash = ?1", &[index_block_hash])
+                    .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            }
+            headers_tx.commit().map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+        }
+
+        {
+            let blocks_tx = self.blocks_tx_begin()?;
+            for hdr in rolled_back.iter() {
+                let block_hash = hdr.anchored_header.block_hash();
+                let args: &[&dyn ToSql] = &[&hdr.burn_header_hash, &block_hash];
+                blocks_tx.execute("UPDATE staging_blocks SET processed = 0, attachable = 0 WHERE burn_header_hash = ?1 AND anchored_block_hash = ?2 AND orphaned = 0", args)
+                    .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            }
+            blocks_tx.commit()?;
+        }
+
+        // the tries go last, once nothing refers to them anymore
+        self.headers_state_index.drop_blocks(&index_block_hashes)
+            .map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+        self.clarity_state.drop_blocks(&index_block_hashes)
+            .map_err(Error::ClarityError)?;
+
+        // re-queued blocks whose parents survived can be attached right away
+        {
+            let blocks_tx = self.blocks_tx_begin()?;
+            for hdr in rolled_back.iter() {
+                let block_hash = hdr.anchored_header.block_hash();
+                let parent_hash = hdr.anchored_header.parent_block.clone();
+                let parent_attached =
+                    if parent_hash == FIRST_STACKS_BLOCK_HASH {
+                        true
+                    }
+                    else {
+                        let parent_sql = "SELECT processed FROM staging_blocks WHERE anchored_block_hash = ?1 AND processed = 1 AND orphaned = 0 LIMIT 1".to_string();
+                        let parent_args: &[&dyn ToSql] = &[&parent_hash];
+                        query_row::<i64, _>(&blocks_tx, &parent_sql, parent_args).map_err(Error::DBError)?.is_some()
+                    };
+
+                if parent_attached {
+                    let args: &[&dyn ToSql] = &[&hdr.burn_header_hash, &block_hash];
+                    blocks_tx.execute("UPDATE staging_blocks SET attachable = 1 WHERE burn_header_hash = ?1 AND anchored_block_hash = ?2 AND orphaned = 0", args)
+                        .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+                }
+            }
+            blocks_tx.commit()?;
+        }
+
+        self.cached_header_hashes.clear();
+        self.cached_miner_payments.clear();
+
+        Ok(rolled_back)
+    }
+
     fn is_valid_address_version(mainnet: bool, version: u8) -> bool {
         if mainnet {
             version == C32_ADDRESS_VERSION_MAINNET_SINGLESIG ||
@@ -3394,6 +3489,7 @@ pub mod test {
     use util::db::*;
     use util::hash::*;
     use util::retry::*;
+    use net::test::{TestPeer, TestPeerConfig};
     use std::fs;
 
     pub fn make_empty_coinbase_block(mblock_key: &StacksPrivateKey) -> StacksBlock {
@@ -4994,7 +5090,72 @@ pub mod test {
         }
     }
 
-   
+    #[test]
+    fn stacks_db_rollback_blocks_second_instance() {
+        let peer_config = TestPeerConfig::new("stacks_db_rollback_blocks_second_instance", 32070, 42070);
+        let mut peer = TestPeer::new(peer_config);
+
+        let mut index_block_hashes = vec![];
+        for _i in 0..4 {
+            let (burn_ops, stacks_block, microblocks) = peer.make_default_tenure();
+            peer.next_burnchain_block(burn_ops.clone());
+            peer.process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+            let sn = BurnDB::get_canonical_burn_chain_tip(peer.burndb.as_ref().unwrap().conn()).unwrap();
+            index_block_hashes.push(StacksBlockHeader::make_index_block_hash(&sn.burn_header_hash, &stacks_block.block_hash()));
+        }
+
+        // a second handle on the same chain state, like the one the p2p thread keeps next to the
+        // relayer's.  It looks up the tries that will be rolled back, and caches their identifiers.
+        let mut other = StacksChainState::open(false, peer.config.network_id, &peer.chainstate_path).unwrap();
+        let mut dropped_ids = vec![];
+        for index_block_hash in index_block_hashes[2..].iter() {
+            let storage = other.headers_state_index.borrow_storage_backend();
+            let block_id = storage.get_block_identifier(index_block_hash).unwrap();
+            assert_eq!(storage.get_block_from_local_id(block_id).unwrap(), index_block_hash);
+            dropped_ids.push(block_id);
+        }
+
+        let burn_hashes : Vec<BurnchainHeaderHash> = index_block_hashes[2..].iter()
+            .map(|index_block_hash| {
+                StacksChainState::get_stacks_block_header_info_by_index_block_hash(&peer.chainstate().headers_db, index_block_hash)
+                    .unwrap()
+                    .unwrap()
+                    .burn_header_hash
+            })
+            .collect();
+
+        let rolled_back = peer.chainstate().rollback_blocks(&burn_hashes).unwrap();
+        assert_eq!(rolled_back.len(), 2);
+
+        for index_block_hash in index_block_hashes[2..].iter() {
+            assert!(StacksChainState::get_stacks_block_header_info_by_index_block_hash(&other.headers_db, index_block_hash).unwrap().is_none());
+            assert!(other.headers_state_index.borrow_storage_backend().get_block_identifier(index_block_hash).is_none());
+        }
+        for index_block_hash in index_block_hashes[..2].iter() {
+            assert!(StacksChainState::get_stacks_block_header_info_by_index_block_hash(&other.headers_db, index_block_hash).unwrap().is_some());
+        }
+
+        // the first handle builds new tries on the surviving chain...
+        let new_block_hashes = vec![BlockHeaderHash([0x22; 32]), BlockHeaderHash([0x33; 32])];
+        let mut parent = index_block_hashes[1].clone();
+        for new_block_hash in new_block_hashes.iter() {
+            let index = &mut peer.chainstate().headers_state_index;
+            index.begin(&parent, new_block_hash).unwrap();
+            index.commit().unwrap();
+            parent = new_block_hash.clone();
+        }
+
+        // ...which must not get the rolled-back tries' identifiers, or the second handle would
+        // resolve back-pointers into them to the rolled-back blocks.
+        for new_block_hash in new_block_hashes.iter() {
+            let storage = other.headers_state_index.borrow_storage_backend();
+            let block_id = storage.get_block_identifier(new_block_hash).unwrap();
+            assert!(!dropped_ids.contains(&block_id));
+            assert_eq!(storage.get_block_from_local_id(block_id).unwrap(), new_block_hash);
+        }
+    }
+
     // TODO: test multiple anchored blocks confirming the same microblock stream (in the same
     // place, and different places, with/without orphans)
     // TODO: process_next_staging_block
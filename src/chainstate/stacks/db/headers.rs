@@ -42,9 +42,12 @@ use util::db::{
     query_row,
     query_rows,
     query_row_columns,
-    query_count
+    query_count,
+    get_ancestor_block_hash
 };
 
+use chainstate::stacks::index::marf::MARF;
+
 use core::FIRST_STACKS_BLOCK_HASH;
 use core::FIRST_BURNCHAIN_BLOCK_HASH;
 
@@ -211,6 +214,69 @@ impl StacksChainState {
         }
     }
 
+    /// Get an ancestor block header given an index hash, using a read-only view of the headers
+    /// index (i.e. without opening a headers transaction).
+    pub fn get_ancestor_header_info(&self, tip_index_hash: &BlockHeaderHash, height: u64) -> Result<Option<StacksHeaderInfo>, Error> {
+        match get_ancestor_block_hash(&self.headers_state_index, height, tip_index_hash).map_err(Error::DBError)? {
+            Some(bhh) => {
+                StacksChainState::get_stacks_block_header_info_by_index_block_hash(&self.headers_db, &bhh)
+            },
+            None => {
+                Ok(None)
+            }
+        }
+    }
+
+    /// Get up to `count` anchored block headers, starting at the given tip and walking back
+    /// towards the genesis block.  Headers are returned in descending order of height (i.e. the
+    /// tip is first).  Returns None if the tip is not known, and NoSuchBlockError if its height is
+    /// out of range.
+    pub fn get_ancestor_headers(&self, tip_index_hash: &BlockHeaderHash, count: u64) -> Result<Option<Vec<StacksHeaderInfo>>, Error> {
+        let tip = match StacksChainState::get_stacks_block_header_info_by_index_block_hash(&self.headers_db, tip_index_hash)? {
+            Some(tip) => tip,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        // the headers index can't look up heights past u32::MAX, so such a tip has no usable
+        // ancestors
+        if tip.block_height >= u32::max_value() as u64 {
+            warn!("Chain tip {} has out-of-range height {}", tip_index_hash.to_hex(), tip.block_height);
+            return Err(Error::NoSuchBlockError);
+        }
+
+        let tip_height = tip.block_height;
+        let lowest_height = (tip_height + 1).saturating_sub(count);
+        let mut headers = vec![tip];
+
+        // walk back on a single read-only view of the headers index, instead of opening a new one
+        // for each ancestor
+        let mut index_storage = self.headers_state_index.reopen_storage_readonly()
+            .map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+
+        for height in (lowest_height..tip_height).rev() {
+            let ancestor_hash = MARF::get_block_at_height(&mut index_storage, height as u32, tip_index_hash)
+                .map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+            let ancestor_header_info = match ancestor_hash {
+                Some(bhh) => StacksChainState::get_stacks_block_header_info_by_index_block_hash(&self.headers_db, &bhh)?,
+                None => None
+            };
+            match ancestor_header_info {
+                Some(header_info) => {
+                    headers.push(header_info);
+                },
+                None => {
+                    // ancestor is missing from the index -- stop here
+                    warn!("No ancestor at height {} of {}", height, tip_index_hash.to_hex());
+                    break;
+                }
+            }
+        }
+
+        Ok(Some(headers))
+    }
+
     /// Get the genesis (boot code) block header
     pub fn get_genesis_header_info(conn: &Connection) -> Result<StacksHeaderInfo, Error> {
         // by construction, only one block can have height 0 in this DB
@@ -219,4 +285,10 @@ impl StacksChainState {
         let row_opt = query_row(conn, sql, args)?;
         Ok(row_opt.expect("BUG: no genesis header info"))
     }
+
+    /// Get every processed block's header info, on all forks, in order by height
+    pub fn get_all_stacks_block_header_infos(conn: &Connection) -> Result<Vec<StacksHeaderInfo>, Error> {
+        let sql = "SELECT * FROM block_headers ORDER BY block_height";
+        query_rows::<StacksHeaderInfo, _>(conn, sql, NO_PARAMS).map_err(Error::DBError)
+    }
 }
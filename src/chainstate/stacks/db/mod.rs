@@ -21,6 +21,7 @@ pub mod accounts;
 pub mod blocks;
 pub mod contracts;
 pub mod headers;
+pub mod snapshot;
 pub mod transactions;
 
 use rusqlite::Transaction;
@@ -704,6 +705,16 @@ impl StacksChainState {
         Ok(())
     }
 
+    /// Name of the directory under the chainstate root that holds a given chain's state
+    pub fn chain_dir_name(mainnet: bool, chain_id: u32) -> String {
+        if mainnet {
+            format!("chain-{}-mainnet", &to_hex(&chain_id.to_le_bytes()))
+        }
+        else {
+            format!("chain-{}-testnet", &to_hex(&chain_id.to_le_bytes()))
+        }
+    }
+
     pub fn open(mainnet: bool, chain_id: u32, path_str: &str) -> Result<StacksChainState, Error> {
         StacksChainState::open_and_exec(mainnet, chain_id, path_str, None, |_| {}, ExecutionCost::max_value())
     }
@@ -736,15 +747,7 @@ impl StacksChainState {
     where F: FnOnce(&mut ClarityTx) -> () {
         let mut path = PathBuf::from(path_str);
 
-        let chain_id_str = 
-            if mainnet {
-                format!("chain-{}-mainnet", &to_hex(&chain_id.to_le_bytes()))
-            }
-            else {
-                format!("chain-{}-testnet", &to_hex(&chain_id.to_le_bytes()))
-            };
-
-        path.push(chain_id_str);
+        path.push(StacksChainState::chain_dir_name(mainnet, chain_id));
         StacksChainState::mkdirs(&path)?;
 
         let mut blocks_path = path.clone();
@@ -906,6 +909,21 @@ impl StacksChainState {
         result
     }
 
+    /// Is the Clarity state as of the given block still stored?  It isn't if the block was never
+    /// processed, or if its trie was pruned or quarantined.  Fails if the MARF can't be queried.
+    pub fn has_clarity_state(&mut self, burn_hash: &BurnchainHeaderHash, block_hash: &BlockHeaderHash) -> Result<bool, Error> {
+        let index_block = StacksChainState::get_parent_index_block(burn_hash, block_hash);
+        self.clarity_state.has_block_state(&index_block)
+            .map_err(Error::ClarityError)
+    }
+
+    /// Record the keys of every data map entry written from now on, so the RPC interface can
+    /// list a map's entries.  The setting persists in the Clarity side store.
+    pub fn enable_map_key_index(&mut self) -> Result<(), Error> {
+        self.clarity_state.enable_map_key_index()
+            .map_err(Error::ClarityError)
+    }
+
     fn get_parent_index_block(parent_burn_hash: &BurnchainHeaderHash, parent_block: &BlockHeaderHash) -> BlockHeaderHash {
         if *parent_block == BOOT_BLOCK_HASH {
             // begin boot block
@@ -0,0 +1,1088 @@
+/*
+ copyright: (c) 2013-2019 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Chainstate snapshots.
+//
+// A snapshot is a single archive of the headers DB, the headers and Clarity MARFs, the Clarity
+// side store, the staging DB, and the burn DB, cut back to a chosen Stacks block.  A new node
+// that trusts that block's index block hash can import the snapshot and sync from there,
+// instead of processing the whole chain history.  The archive does not contain the blocks
+// themselves, so the importing node cannot serve blocks from before the snapshot.
+//
+// Archive layout: SNAPSHOT_MAGIC, then the consensus-serialized SnapshotManifest, then the
+// bytes of each file listed in the manifest, in order.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::fs;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::NO_PARAMS;
+
+use sha2::Sha512Trunc256;
+use sha2::Digest;
+
+use core::*;
+
+use burnchains::BurnchainHeaderHash;
+
+use chainstate::burn::BlockHeaderHash;
+use chainstate::burn::BlockSnapshot;
+use chainstate::burn::db::burndb::BurnDB;
+
+use chainstate::stacks::Error;
+use chainstate::stacks::db::*;
+use chainstate::stacks::index::{MARFValue, TrieHash};
+use chainstate::stacks::index::marf::MARF;
+
+use net::StacksMessageCodec;
+use net::Error as net_error;
+use net::codec::{read_next, read_next_at_most, write_next};
+
+use util::db::Error as db_error;
+use util::hash::Sha512Trunc256Sum;
+
+use vm::ast;
+use vm::analysis::type_check;
+use vm::clarity::{ClarityConnection, ClarityReadOnlyConnection};
+use vm::contracts::Contract;
+use vm::database::{ClarityDatabase, ClaritySerializable, DataMapMetadata, DataVariableMetadata,
+                   FungibleTokenMetadata, MarfedKV, NonFungibleTokenMetadata, StoreType};
+use vm::database::marf::ContractCommitment;
+use vm::types::QualifiedContractIdentifier;
+
+pub const SNAPSHOT_MAGIC : &'static [u8; 8] = b"STXSNAP\0";
+pub const SNAPSHOT_VERSION : u32 = 1;
+
+const SNAPSHOT_MAX_FILES : u32 = 65536;
+const SNAPSHOT_MAX_PATH_LEN : u32 = 4096;
+
+/// A file in a snapshot.  The path is relative to the chain's state directory (if it starts with
+/// "chainstate/") or to the burn DB directory (if it starts with "burndb/").
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotFile {
+    pub path: String,
+    pub size: u64,
+    pub hash: Sha512Trunc256Sum
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub mainnet: bool,
+    pub chain_id: u32,
+    pub index_block_hash: BlockHeaderHash,
+    pub burn_header_hash: BurnchainHeaderHash,
+    pub block_hash: BlockHeaderHash,
+    pub block_height: u64,
+    pub burn_block_height: u64,
+    pub state_index_root: TrieHash,
+    pub files: Vec<SnapshotFile>
+}
+
+impl StacksMessageCodec for SnapshotFile {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.path.as_bytes().to_vec())?;
+        write_next(fd, &self.size)?;
+        write_next(fd, &self.hash)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<SnapshotFile, net_error> {
+        let path_bytes : Vec<u8> = read_next_at_most(fd, SNAPSHOT_MAX_PATH_LEN)?;
+        let size : u64 = read_next(fd)?;
+        let hash : Sha512Trunc256Sum = read_next(fd)?;
+
+        let path = String::from_utf8(path_bytes)
+            .map_err(|_e| net_error::DeserializeError("Snapshot file path is not UTF-8".to_string()))?;
+
+        Ok(SnapshotFile {
+            path,
+            size,
+            hash
+        })
+    }
+}
+
+impl StacksMessageCodec for SnapshotManifest {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        let mainnet : u8 = if self.mainnet { 1 } else { 0 };
+        write_next(fd, &self.version)?;
+        write_next(fd, &mainnet)?;
+        write_next(fd, &self.chain_id)?;
+        write_next(fd, &self.index_block_hash)?;
+        write_next(fd, &self.burn_header_hash)?;
+        write_next(fd, &self.block_hash)?;
+        write_next(fd, &self.block_height)?;
+        write_next(fd, &self.burn_block_height)?;
+        write_next(fd, &self.state_index_root)?;
+        write_next(fd, &self.files)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<SnapshotManifest, net_error> {
+        let version : u32 = read_next(fd)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(net_error::DeserializeError(format!("Unsupported snapshot version {}", version)));
+        }
+
+        let mainnet : u8 = read_next(fd)?;
+        if mainnet > 1 {
+            return Err(net_error::DeserializeError(format!("Invalid snapshot network byte {}", mainnet)));
+        }
+
+        let chain_id : u32 = read_next(fd)?;
+        let index_block_hash : BlockHeaderHash = read_next(fd)?;
+        let burn_header_hash : BurnchainHeaderHash = read_next(fd)?;
+        let block_hash : BlockHeaderHash = read_next(fd)?;
+        let block_height : u64 = read_next(fd)?;
+        let burn_block_height : u64 = read_next(fd)?;
+        let state_index_root : TrieHash = read_next(fd)?;
+        let files : Vec<SnapshotFile> = read_next_at_most(fd, SNAPSHOT_MAX_FILES)?;
+
+        Ok(SnapshotManifest {
+            version,
+            mainnet: mainnet == 1,
+            chain_id,
+            index_block_hash,
+            burn_header_hash,
+            block_hash,
+            block_height,
+            burn_block_height,
+            state_index_root,
+            files
+        })
+    }
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::DBError(db_error::IOError(e))
+}
+
+fn path_to_string(path: &Path) -> Result<String, Error> {
+    path.to_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| Error::DBError(db_error::ParseError))
+}
+
+/// Recursive `cp -r`.  The destination must not exist yet.
+fn copy_dir(src: &Path, dest: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dest).map_err(io_error)?;
+    for entry_res in fs::read_dir(src).map_err(io_error)? {
+        let entry = entry_res.map_err(io_error)?;
+        let mut dest_path = dest.to_path_buf();
+        dest_path.push(entry.file_name());
+
+        if entry.file_type().map_err(io_error)?.is_dir() {
+            copy_dir(&entry.path(), &dest_path)?;
+        }
+        else {
+            fs::copy(entry.path(), &dest_path).map_err(io_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Find every file under dir, and name it relative to dir with the given prefix.
+fn list_files(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) -> Result<(), Error> {
+    for entry_res in fs::read_dir(dir).map_err(io_error)? {
+        let entry = entry_res.map_err(io_error)?;
+        let name = entry.file_name().into_string()
+            .map_err(|_e| Error::SnapshotError(format!("Unrepresentable file name in {:?}", dir)))?;
+        let rel_path = format!("{}/{}", prefix, &name);
+
+        if entry.file_type().map_err(io_error)?.is_dir() {
+            list_files(&entry.path(), &rel_path, files)?;
+        }
+        else {
+            files.push((rel_path, entry.path()));
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<(u64, Sha512Trunc256Sum), Error> {
+    let mut fd = fs::File::open(path).map_err(io_error)?;
+    let mut sha2 = Sha512Trunc256::new();
+    let mut buf = [0u8; 65536];
+    let mut size = 0;
+    loop {
+        let nr = fd.read(&mut buf).map_err(io_error)?;
+        if nr == 0 {
+            break;
+        }
+        sha2.input(&buf[0..nr]);
+        size += nr as u64;
+    }
+    Ok((size, Sha512Trunc256Sum::from(sha2.result().as_slice())))
+}
+
+/// Where a snapshot file goes, given the directories the chain state and burn DB are unpacked
+/// into.  Paths that could escape those directories are rejected.
+fn snapshot_file_dest(path: &str, chain_dir: &Path, burndb_dir: &Path) -> Result<PathBuf, Error> {
+    let mut parts = path.split('/');
+    let mut dest = match parts.next() {
+        Some("chainstate") => chain_dir.to_path_buf(),
+        Some("burndb") => burndb_dir.to_path_buf(),
+        _ => {
+            return Err(Error::SnapshotError(format!("Invalid snapshot file path: {}", path)));
+        }
+    };
+
+    let mut depth = 0;
+    for part in parts {
+        if part == "" || part == "." || part == ".." || part.contains('\\') || part.contains('\0') {
+            return Err(Error::SnapshotError(format!("Invalid snapshot file path: {}", path)));
+        }
+        dest.push(part);
+        depth += 1;
+    }
+
+    if depth == 0 {
+        return Err(Error::SnapshotError(format!("Invalid snapshot file path: {}", path)));
+    }
+    Ok(dest)
+}
+
+fn check_marf(marf_path: &Path, expected_roots: &[(BlockHeaderHash, TrieHash)]) -> Result<(), Error> {
+    let marf_path_str = path_to_string(marf_path)?;
+    let mut marf = MARF::from_path(&marf_path_str, None).map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+    let report = marf.check_integrity(expected_roots).map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+    if !report.is_ok() {
+        return Err(Error::SnapshotError(format!("MARF {} failed its integrity check: {} damaged trie(s), {} missing trie(s)",
+                                                &marf_path_str, report.damaged.len(), report.missing.len())));
+    }
+    Ok(())
+}
+
+/// Check that a Stacks block was selected in a burnchain block of the snapshot's burn fork
+fn check_sortition(burndb: &BurnDB, burn_fork: &HashSet<BurnchainHeaderHash>, header: &StacksHeaderInfo) -> Result<(), Error> {
+    let block_hash = header.anchored_header.block_hash();
+    let selected = match BurnDB::get_block_snapshot(burndb.conn(), &header.burn_header_hash)? {
+        Some(sn) => sn.sortition && sn.winning_stacks_block_hash == block_hash && burn_fork.contains(&sn.burn_header_hash),
+        None => false
+    };
+
+    if !selected {
+        return Err(Error::SnapshotError(format!("Block {}/{} was not selected in the snapshot's burnchain fork", &header.burn_header_hash, &block_hash)));
+    }
+    Ok(())
+}
+
+/// Check one contract's metadata from the Clarity side store against the contract's source, as
+/// committed to in the Clarity MARF.  `conn` reads the state as of `block`, the block whose
+/// metadata rows these are.  Everything that can be derived from the source without running it
+/// is recomputed; the values of constants, token supplies, and the data size come from
+/// evaluating the contract, so they can only be checked for consistency.
+fn check_contract_metadata(conn: &mut ClarityReadOnlyConnection, contract_id: &QualifiedContractIdentifier,
+                           block: &BlockHeaderHash, entries: &HashMap<String, String>) -> Result<(), String> {
+    let commitment_key = MarfedKV::make_contract_hash_key(contract_id);
+    let (commitment, deploy_block) = conn.with_clarity_db_readonly(|db| {
+        let commitment = db.store.get::<ContractCommitment>(&commitment_key);
+        let deploy_block = commitment.as_ref().and_then(|c| db.store.get_block_header_hash(c.block_height));
+        (commitment, deploy_block)
+    });
+
+    let commitment = commitment.ok_or_else(|| format!("{} does not exist as of {}", contract_id, block))?;
+    if deploy_block.as_ref() != Some(block) {
+        return Err(format!("{} was not deployed in {}", contract_id, block));
+    }
+
+    let src_key = ClarityDatabase::make_metadata_key(StoreType::Contract, "contract-src");
+    let source = entries.get(&src_key)
+        .ok_or_else(|| format!("{} has no source", contract_id))?;
+    if Sha512Trunc256Sum::from_data(source.as_bytes()) != commitment.hash {
+        return Err(format!("{} source does not match its commitment", contract_id));
+    }
+
+    // redo the analysis the contract was deployed with
+    let mut contract_ast = ast::build_ast(contract_id, source, &mut ())
+        .map_err(|e| format!("{} does not parse: {:?}", contract_id, e))?;
+    let analysis = conn.with_analysis_db_readonly(|analysis_db| type_check(contract_id, &mut contract_ast.expressions, analysis_db, false))
+        .map_err(|e| format!("{} does not type-check: {:?}", contract_id, e))?;
+
+    let contract_key = ClarityDatabase::make_metadata_key(StoreType::Contract, "contract");
+    let contract : Contract = entries.get(&contract_key)
+        .ok_or_else(|| format!("{} has no contract entry", contract_id))
+        .and_then(|json| serde_json::from_str(json).map_err(|e| format!("{} contract entry does not parse: {}", contract_id, e)))?;
+    let context = &contract.contract_context;
+
+    if context.contract_identifier != *contract_id {
+        return Err(format!("{} contract entry is for {}", contract_id, &context.contract_identifier));
+    }
+
+    let functions = Contract::functions_from_ast(&contract_ast)
+        .map_err(|e| format!("{} functions do not parse: {:?}", contract_id, e))?;
+    if functions.len() != context.functions.len() {
+        return Err(format!("{} contract entry has the wrong functions", contract_id));
+    }
+    for (name, function) in functions.iter() {
+        let stored = context.functions.get(name)
+            .ok_or_else(|| format!("{} contract entry is missing function {}", contract_id, name))?;
+        if serde_json::to_string(stored).ok() != serde_json::to_string(function).ok() {
+            return Err(format!("{} contract entry has the wrong function {}", contract_id, name));
+        }
+    }
+
+    let persisted_names : HashSet<_> = analysis.map_types.keys()
+        .chain(analysis.persisted_variable_types.keys())
+        .chain(analysis.fungible_tokens.iter())
+        .chain(analysis.non_fungible_tokens.keys())
+        .cloned()
+        .collect();
+
+    if context.variables.len() != analysis.variable_types.len()
+        || !context.variables.keys().all(|name| analysis.variable_types.contains_key(name))
+        || context.defined_traits.len() != analysis.defined_traits.len()
+        || !context.defined_traits.iter().all(|(name, functions)| analysis.defined_traits.get(name) == Some(functions))
+        || context.implemented_traits.len() != analysis.implemented_traits.len()
+        || !context.implemented_traits.iter().all(|trait_id| analysis.implemented_traits.contains(trait_id))
+        || context.persisted_names != persisted_names {
+        return Err(format!("{} contract entry does not match its source", contract_id));
+    }
+
+    // every other entry is either recomputed or checked above; None marks the ones that are not
+    let mut expected = HashMap::new();
+    expected.insert(src_key, None);
+    expected.insert(contract_key, None);
+    expected.insert(ClarityDatabase::make_metadata_key(StoreType::Contract, "contract-size"), Some(source.len().to_string()));
+    expected.insert(ClarityDatabase::make_metadata_key(StoreType::Contract, "contract-data-size"), Some(context.data_size.to_string()));
+    expected.insert("analysis".to_string(), Some(analysis.serialize()));
+    for (name, value_type) in analysis.persisted_variable_types.iter() {
+        expected.insert(ClarityDatabase::make_metadata_key(StoreType::VariableMeta, name),
+                        Some(DataVariableMetadata { value_type: value_type.clone() }.serialize()));
+    }
+    for (name, (key_type, value_type)) in analysis.map_types.iter() {
+        expected.insert(ClarityDatabase::make_metadata_key(StoreType::DataMapMeta, name),
+                        Some(DataMapMetadata { key_type: key_type.clone(), value_type: value_type.clone() }.serialize()));
+    }
+    for (name, key_type) in analysis.non_fungible_tokens.iter() {
+        expected.insert(ClarityDatabase::make_metadata_key(StoreType::NonFungibleTokenMeta, name),
+                        Some(NonFungibleTokenMetadata { key_type: key_type.clone() }.serialize()));
+    }
+    for name in analysis.fungible_tokens.iter() {
+        let key = ClarityDatabase::make_metadata_key(StoreType::FungibleTokenMeta, name);
+        match entries.get(&key).map(|json| serde_json::from_str::<FungibleTokenMetadata>(json)) {
+            Some(Ok(_)) => {},
+            _ => {
+                return Err(format!("{} has a bad entry for token {}", contract_id, name));
+            }
+        }
+        expected.insert(key, None);
+    }
+
+    if entries.len() != expected.len() {
+        return Err(format!("{} has {} metadata entries, not {}", contract_id, entries.len(), expected.len()));
+    }
+    for (key, value) in entries.iter() {
+        match expected.get(key) {
+            Some(None) => {},
+            Some(Some(expected_value)) if expected_value == value => {},
+            _ => {
+                return Err(format!("{} has a bad metadata entry {}", contract_id, key));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the Clarity side store.  The Clarity MARF only commits to the hashes of the values
+/// stored there, and not to contract metadata at all, so neither is covered by check_marf().
+fn check_clarity_side_store(chainstate: &mut StacksChainState, side_store_path: &Path, headers: &[StacksHeaderInfo]) -> Result<(), Error> {
+    let conn = Connection::open_with_flags(side_store_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+
+    // every value must be stored under the hash the MARF commits to
+    {
+        let mut stmt = conn.prepare("SELECT key, value FROM data_table")
+            .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+        let mut rows = stmt.query(NO_PARAMS)
+            .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+        while let Some(row_res) = rows.next() {
+            let row = row_res.map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            let key : String = row.get(0);
+            let value : Option<String> = row.get(1);
+            if value.map(|v| MARFValue::from_value(&v).to_hex()) != Some(key.clone()) {
+                return Err(Error::SnapshotError(format!("Snapshot Clarity value {} does not match its hash", &key)));
+            }
+        }
+    }
+
+    // group the metadata by the block that wrote it and its contract
+    let mut metadata : BTreeMap<(String, String), HashMap<String, String>> = BTreeMap::new();
+    {
+        let mut stmt = conn.prepare("SELECT key, blockhash, value FROM metadata_table")
+            .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+        let mut rows = stmt.query(NO_PARAMS)
+            .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+        while let Some(row_res) = rows.next() {
+            let row = row_res.map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            let key : String = row.get(0);
+            let block : Option<String> = row.get(1);
+            let value : Option<String> = row.get(2);
+
+            // keys are clr-meta::{contract ID}::{metadata key}, and (key, blockhash) is unique
+            let mut parts = key.splitn(3, "::");
+            match (parts.next(), parts.next(), parts.next(), block, value) {
+                (Some("clr-meta"), Some(contract), Some(meta_key), Some(block), Some(value)) => {
+                    metadata.entry((block, contract.to_string()))
+                        .or_insert_with(HashMap::new)
+                        .insert(meta_key.to_string(), value);
+                },
+                _ => {
+                    return Err(Error::SnapshotError(format!("Snapshot has a malformed Clarity metadata entry {}", &key)));
+                }
+            }
+        }
+    }
+
+    for ((block, contract), entries) in metadata.iter() {
+        // metadata written by a block this node does not know of (such as a block this node
+        // mined, but which was never accepted) can't be read from any state it can open
+        let header = match BlockHeaderHash::from_hex(block).ok()
+            .and_then(|block_id| headers.iter().find(|header| header.index_block_hash() == block_id)) {
+            Some(header) => header,
+            None => {
+                continue;
+            }
+        };
+
+        let contract_id = QualifiedContractIdentifier::parse(contract)
+            .map_err(|_| Error::SnapshotError(format!("Snapshot has Clarity metadata for a malformed contract ID {}", contract)))?;
+        let block_id = header.index_block_hash();
+        chainstate.with_read_only_clarity_tx(&header.burn_header_hash, &header.anchored_header.block_hash(), |conn| {
+            check_contract_metadata(conn, &contract_id, &block_id, entries)
+        })
+        .map_err(|msg| Error::SnapshotError(format!("Snapshot Clarity metadata is invalid: {}", msg)))?;
+    }
+
+    Ok(())
+}
+
+impl StacksChainState {
+    /// Export a snapshot of a chain's state as of the given Stacks block (by index block hash)
+    /// to a new archive at snapshot_path.  The chain state and burn DB are copied and the copies
+    /// are cut back to that block: every burnchain block after the one that selected it is
+    /// forgotten (along with its Stacks blocks), as are unprocessed and orphaned staging blocks,
+    /// and the block becomes the canonical Stacks chain tip.  The node must not be writing to
+    /// the chain state or burn DB while this runs.
+    pub fn export_snapshot(mainnet: bool, chain_id: u32, chainstate_path: &str, burndb_path: &str,
+                           index_block_hash: &BlockHeaderHash, snapshot_path: &str) -> Result<SnapshotManifest, Error> {
+        let staging_path = format!("{}.staging", snapshot_path);
+        for path in [snapshot_path, staging_path.as_str()].iter() {
+            if fs::metadata(path).is_ok() {
+                return Err(Error::SnapshotError(format!("Path already exists: {}", path)));
+            }
+        }
+
+        let mut chain_dir = PathBuf::from(chainstate_path);
+        chain_dir.push(StacksChainState::chain_dir_name(mainnet, chain_id));
+        if fs::metadata(&chain_dir).is_err() {
+            return Err(Error::SnapshotError(format!("No chain state in {}", chainstate_path)));
+        }
+        if fs::metadata(burndb_path).is_err() {
+            return Err(Error::SnapshotError(format!("No burn DB in {}", burndb_path)));
+        }
+
+        let res = StacksChainState::inner_export_snapshot(mainnet, chain_id, &chain_dir, burndb_path, index_block_hash, &staging_path, snapshot_path);
+        if let Err(e) = fs::remove_dir_all(&staging_path) {
+            warn!("Failed to remove snapshot staging directory {}: {:?}", &staging_path, &e);
+        }
+        if res.is_err() {
+            let _ = fs::remove_file(snapshot_path);
+        }
+        res
+    }
+
+    fn inner_export_snapshot(mainnet: bool, chain_id: u32, chain_dir: &Path, burndb_path: &str,
+                             index_block_hash: &BlockHeaderHash, staging_path: &str, snapshot_path: &str) -> Result<SnapshotManifest, Error> {
+        let chain_dir_name = StacksChainState::chain_dir_name(mainnet, chain_id);
+
+        let mut staging_chainstate_root = PathBuf::from(staging_path);
+        staging_chainstate_root.push("chainstate");
+
+        let mut staging_chain_dir = staging_chainstate_root.clone();
+        staging_chain_dir.push(&chain_dir_name);
+
+        let mut staging_burndb = PathBuf::from(staging_path);
+        staging_burndb.push("burndb");
+
+        // the headers, tries, and Clarity side store
+        let mut src_vm_dir = chain_dir.to_path_buf();
+        src_vm_dir.push("vm");
+        let mut dest_vm_dir = staging_chain_dir.clone();
+        dest_vm_dir.push("vm");
+        copy_dir(&src_vm_dir, &dest_vm_dir)?;
+
+        // the staging DB, but not the blocks themselves
+        let mut src_blocks_dir = chain_dir.to_path_buf();
+        src_blocks_dir.push("blocks");
+        let mut dest_blocks_dir = staging_chain_dir.clone();
+        dest_blocks_dir.push("blocks");
+        fs::create_dir_all(&dest_blocks_dir).map_err(io_error)?;
+        for entry_res in fs::read_dir(&src_blocks_dir).map_err(io_error)? {
+            let entry = entry_res.map_err(io_error)?;
+            if entry.file_type().map_err(io_error)?.is_file() && entry.file_name().to_string_lossy().starts_with("staging.db") {
+                let mut dest_path = dest_blocks_dir.clone();
+                dest_path.push(entry.file_name());
+                fs::copy(entry.path(), &dest_path).map_err(io_error)?;
+            }
+        }
+
+        copy_dir(Path::new(burndb_path), &staging_burndb)?;
+
+        let (header, snapshot) = {
+            let mut chainstate = StacksChainState::open(mainnet, chain_id, &path_to_string(&staging_chainstate_root)?)?;
+            let mut burndb = BurnDB::open(&path_to_string(&staging_burndb)?, true)?;
+            StacksChainState::cut_back_to_block(&mut chainstate, &mut burndb, index_block_hash)?
+        };
+
+        // give back the space of the forgotten tries
+        let mut headers_marf_path = staging_chain_dir.clone();
+        headers_marf_path.push("vm");
+        headers_marf_path.push("index");
+
+        let mut clarity_marf_path = staging_chain_dir.clone();
+        clarity_marf_path.push("vm");
+        clarity_marf_path.push("clarity");
+        clarity_marf_path.push("marf");
+
+        let mut burn_marf_path = staging_burndb.clone();
+        burn_marf_path.push("marf");
+
+        for marf_path in [headers_marf_path, clarity_marf_path, burn_marf_path].iter() {
+            let mut marf = MARF::from_path(&path_to_string(marf_path)?, None).map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+            marf.compact().map_err(|e| Error::DBError(db_error::IndexError(e)))?;
+        }
+
+        let mut paths = vec![];
+        list_files(&staging_chain_dir, "chainstate", &mut paths)?;
+        list_files(&staging_burndb, "burndb", &mut paths)?;
+        paths.sort();
+
+        let mut files = vec![];
+        for (rel_path, path) in paths.iter() {
+            let (size, hash) = hash_file(path)?;
+            files.push(SnapshotFile {
+                path: rel_path.clone(),
+                size: size,
+                hash: hash
+            });
+        }
+
+        let manifest = SnapshotManifest {
+            version: SNAPSHOT_VERSION,
+            mainnet: mainnet,
+            chain_id: chain_id,
+            index_block_hash: index_block_hash.clone(),
+            burn_header_hash: header.burn_header_hash.clone(),
+            block_hash: header.anchored_header.block_hash(),
+            block_height: header.block_height,
+            burn_block_height: snapshot.block_height,
+            state_index_root: header.anchored_header.state_index_root.clone(),
+            files: files
+        };
+
+        let snapshot_fd = fs::OpenOptions::new().write(true).create_new(true).open(snapshot_path).map_err(io_error)?;
+        let mut out = BufWriter::new(snapshot_fd);
+        out.write_all(SNAPSHOT_MAGIC).map_err(io_error)?;
+        write_next(&mut out, &manifest).map_err(Error::NetError)?;
+
+        for ((_, path), file) in paths.iter().zip(manifest.files.iter()) {
+            let fd = fs::File::open(path).map_err(io_error)?;
+            let copied = io::copy(&mut fd.take(file.size), &mut out).map_err(io_error)?;
+            if copied != file.size {
+                return Err(Error::SnapshotError(format!("File {:?} changed while being archived", path)));
+            }
+        }
+
+        let snapshot_fd = out.into_inner().map_err(|e| io_error(e.into()))?;
+        snapshot_fd.sync_all().map_err(io_error)?;
+
+        info!("Exported snapshot of {} (height {}) to {}: {} file(s)", index_block_hash, header.block_height, snapshot_path, manifest.files.len());
+        Ok(manifest)
+    }
+
+    /// Cut a (copied) chain state and burn DB back to the given Stacks block, so it becomes the
+    /// canonical Stacks chain tip on top of the burnchain block that selected it.
+    fn cut_back_to_block(chainstate: &mut StacksChainState, burndb: &mut BurnDB, index_block_hash: &BlockHeaderHash) -> Result<(StacksHeaderInfo, BlockSnapshot), Error> {
+        let header = StacksChainState::get_stacks_block_header_info_by_index_block_hash(&chainstate.headers_db, index_block_hash)?
+            .ok_or(Error::NoSuchBlockError)?;
+
+        if header.block_height == 0 {
+            return Err(Error::SnapshotError("Cannot snapshot the boot block".to_string()));
+        }
+
+        let block_hash = header.anchored_header.block_hash();
+        let snapshot = BurnDB::get_block_snapshot(burndb.conn(), &header.burn_header_hash)?
+            .ok_or_else(|| Error::SnapshotError(format!("No burnchain block {} for Stacks block {}", &header.burn_header_hash, &block_hash)))?;
+
+        // forget every later burnchain block, as well as this block's siblings (so it is the
+        // canonical burn chain tip).  A sortition never happens in the first burnchain block, so
+        // snapshot.block_height > 0.
+        let mut removed = burndb.rewind_to_height(snapshot.block_height)?;
+        let siblings : Vec<BlockSnapshot> = BurnDB::get_snapshots_above(burndb.conn(), snapshot.block_height - 1)?
+            .into_iter()
+            .filter(|sn| sn.burn_header_hash != snapshot.burn_header_hash)
+            .collect();
+
+        burndb.forget_snapshots(&siblings)?;
+        removed.extend(siblings);
+
+        let burn_hashes : Vec<BurnchainHeaderHash> = removed.iter().map(|sn| sn.burn_header_hash.clone()).collect();
+        chainstate.rollback_blocks(&burn_hashes)?;
+
+        let stacks_fork : Vec<_> = chainstate.get_ancestor_headers(index_block_hash, header.block_height)?
+            .ok_or(Error::NoSuchBlockError)?
+            .into_iter()
+            .map(|hdr| (hdr.burn_header_hash.clone(), hdr.anchored_header.block_hash(), hdr.block_height))
+            .collect();
+        burndb.reset_canonical_stacks_chain_tip(&stacks_fork)?;
+
+        // the snapshot carries no blocks, so there is nothing left to process
+        {
+            let blocks_tx = chainstate.blocks_tx_begin()?;
+            blocks_tx.execute("DELETE FROM staging_blocks WHERE processed = 0 OR orphaned = 1", NO_PARAMS)
+                .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            blocks_tx.execute("DELETE FROM staging_user_burn_support WHERE NOT EXISTS
+                              (SELECT 1 FROM staging_blocks WHERE staging_blocks.anchored_block_hash = staging_user_burn_support.anchored_block_hash
+                                                              AND staging_blocks.burn_header_hash = staging_user_burn_support.burn_header_hash)", NO_PARAMS)
+                .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            blocks_tx.commit()?;
+        }
+
+        match chainstate.get_stacks_chain_tip(burndb)? {
+            Some(ref tip) if tip.burn_header_hash == header.burn_header_hash && tip.anchored_block_hash == block_hash => {},
+            _ => {
+                return Err(Error::SnapshotError(format!("Failed to make {} the canonical Stacks chain tip", index_block_hash)));
+            }
+        }
+
+        Ok((header, snapshot))
+    }
+
+    fn read_snapshot_manifest<R: Read>(fd: &mut R) -> Result<SnapshotManifest, Error> {
+        let mut magic = [0u8; 8];
+        fd.read_exact(&mut magic).map_err(io_error)?;
+        if magic != *SNAPSHOT_MAGIC {
+            return Err(Error::SnapshotError("Not a chainstate snapshot".to_string()));
+        }
+        read_next(fd).map_err(Error::NetError)
+    }
+
+    /// Read a snapshot's manifest, without unpacking or verifying the snapshot
+    pub fn get_snapshot_manifest(snapshot_path: &str) -> Result<SnapshotManifest, Error> {
+        let mut fd = BufReader::new(fs::File::open(snapshot_path).map_err(io_error)?);
+        StacksChainState::read_snapshot_manifest(&mut fd)
+    }
+
+    /// Import a snapshot made by export_snapshot() as the chain state under chainstate_path and
+    /// the burn DB at burndb_path, neither of which may exist yet.  The snapshot must be of
+    /// trusted_tip.  Before anything is moved into place, the unpacked snapshot is verified:
+    /// every file's hash, the trusted tip's header and state root, the header chain back to the
+    /// boot block, the integrity of all three MARFs (including the tip's Clarity state root),
+    /// and the burnchain fork that selected each block.
+    pub fn import_snapshot(snapshot_path: &str, mainnet: bool, chain_id: u32, chainstate_path: &str, burndb_path: &str,
+                           trusted_tip: &BlockHeaderHash) -> Result<SnapshotManifest, Error> {
+        let mut fd = BufReader::new(fs::File::open(snapshot_path).map_err(io_error)?);
+        let manifest = StacksChainState::read_snapshot_manifest(&mut fd)?;
+
+        if manifest.mainnet != mainnet || manifest.chain_id != chain_id {
+            return Err(Error::SnapshotError(format!("Snapshot is for chain {:08x} (mainnet: {}), not {:08x} (mainnet: {})",
+                                                    manifest.chain_id, manifest.mainnet, chain_id, mainnet)));
+        }
+        if manifest.index_block_hash != *trusted_tip {
+            return Err(Error::SnapshotError(format!("Snapshot is of {}, not {}", &manifest.index_block_hash, trusted_tip)));
+        }
+
+        let chain_dir_name = StacksChainState::chain_dir_name(mainnet, chain_id);
+        let mut chain_dir = PathBuf::from(chainstate_path);
+        chain_dir.push(&chain_dir_name);
+
+        if fs::metadata(&chain_dir).is_ok() {
+            return Err(Error::SnapshotError(format!("Chain state already exists in {}", chainstate_path)));
+        }
+        if fs::metadata(burndb_path).is_ok() {
+            return Err(Error::SnapshotError(format!("Burn DB already exists in {}", burndb_path)));
+        }
+
+        let staging_chainstate_root = PathBuf::from(format!("{}.import", &path_to_string(&chain_dir)?));
+        let mut staging_chain_dir = staging_chainstate_root.clone();
+        staging_chain_dir.push(&chain_dir_name);
+        let staging_burndb = PathBuf::from(format!("{}.import", burndb_path));
+
+        // left over from an interrupted import
+        for path in [&staging_chainstate_root, &staging_burndb].iter() {
+            if fs::metadata(path).is_ok() {
+                fs::remove_dir_all(path).map_err(io_error)?;
+            }
+        }
+
+        let res = StacksChainState::unpack_snapshot(&mut fd, &manifest, &staging_chain_dir, &staging_burndb)
+            .and_then(|_| StacksChainState::verify_snapshot(&manifest, &staging_chainstate_root, &staging_burndb));
+
+        if let Err(e) = res {
+            warn!("Failed to import snapshot {}: {:?}", snapshot_path, &e);
+            let _ = fs::remove_dir_all(&staging_chainstate_root);
+            let _ = fs::remove_dir_all(&staging_burndb);
+            return Err(e);
+        }
+
+        fs::create_dir_all(chainstate_path).map_err(io_error)?;
+        if let Some(parent) = Path::new(burndb_path).parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+        fs::rename(&staging_chain_dir, &chain_dir).map_err(io_error)?;
+        fs::rename(&staging_burndb, burndb_path).map_err(io_error)?;
+        fs::remove_dir_all(&staging_chainstate_root).map_err(io_error)?;
+
+        info!("Imported snapshot of {} (height {}, burn height {}) from {}", trusted_tip, manifest.block_height, manifest.burn_block_height, snapshot_path);
+        Ok(manifest)
+    }
+
+    fn unpack_snapshot<R: Read>(fd: &mut R, manifest: &SnapshotManifest, chain_dir: &Path, burndb_dir: &Path) -> Result<(), Error> {
+        let mut buf = [0u8; 65536];
+        for file in manifest.files.iter() {
+            let dest = snapshot_file_dest(&file.path, chain_dir, burndb_dir)?;
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent).map_err(io_error)?;
+            }
+
+            // create_new() also rejects duplicate paths
+            let mut out = fs::OpenOptions::new().write(true).create_new(true).open(&dest).map_err(io_error)?;
+            let mut sha2 = Sha512Trunc256::new();
+            let mut remaining = file.size;
+            while remaining > 0 {
+                let len = cmp::min(remaining, buf.len() as u64) as usize;
+                fd.read_exact(&mut buf[0..len])
+                    .map_err(|e| {
+                        if e.kind() == io::ErrorKind::UnexpectedEof {
+                            Error::SnapshotError(format!("Snapshot is truncated in {}", &file.path))
+                        }
+                        else {
+                            io_error(e)
+                        }
+                    })?;
+                sha2.input(&buf[0..len]);
+                out.write_all(&buf[0..len]).map_err(io_error)?;
+                remaining -= len as u64;
+            }
+
+            if Sha512Trunc256Sum::from(sha2.result().as_slice()) != file.hash {
+                return Err(Error::SnapshotError(format!("Snapshot file {} is corrupt", &file.path)));
+            }
+            out.sync_all().map_err(io_error)?;
+        }
+
+        if fd.read(&mut buf[0..1]).map_err(io_error)? != 0 {
+            return Err(Error::SnapshotError("Snapshot has trailing data".to_string()));
+        }
+        Ok(())
+    }
+
+    fn verify_snapshot(manifest: &SnapshotManifest, chainstate_root: &Path, burndb_dir: &Path) -> Result<(), Error> {
+        let trusted_tip = &manifest.index_block_hash;
+
+        let mut chain_dir = chainstate_root.to_path_buf();
+        chain_dir.push(StacksChainState::chain_dir_name(manifest.mainnet, manifest.chain_id));
+
+        let mut headers_db_path = chain_dir.clone();
+        headers_db_path.push("vm");
+        headers_db_path.push("headers.db");
+
+        let mut headers_marf_path = chain_dir.clone();
+        headers_marf_path.push("vm");
+        headers_marf_path.push("index");
+
+        let mut clarity_marf_path = chain_dir.clone();
+        clarity_marf_path.push("vm");
+        clarity_marf_path.push("clarity");
+        clarity_marf_path.push("marf");
+
+        let mut burn_marf_path = burndb_dir.to_path_buf();
+        burn_marf_path.push("marf");
+
+        let (tip, headers) = {
+            let conn = Connection::open_with_flags(&headers_db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(|e| Error::DBError(db_error::SqliteError(e)))?;
+            let tip = StacksChainState::get_stacks_block_header_info_by_index_block_hash(&conn, trusted_tip)?
+                .ok_or_else(|| Error::SnapshotError(format!("Snapshot does not contain {}", trusted_tip)))?;
+            let headers = StacksChainState::get_all_stacks_block_header_infos(&conn)?;
+            (tip, headers)
+        };
+
+        // the header's hash commits to its parent and its state root, and the index block hash
+        // commits to the header and the burnchain block that selected it
+        if tip.index_block_hash() != *trusted_tip {
+            return Err(Error::SnapshotError(format!("Snapshot header for {} does not hash to it", trusted_tip)));
+        }
+        if tip.block_height == 0 {
+            return Err(Error::SnapshotError("Snapshot is of the boot block".to_string()));
+        }
+        if tip.anchored_header.state_index_root != manifest.state_index_root
+            || tip.anchored_header.block_hash() != manifest.block_hash
+            || tip.burn_header_hash != manifest.burn_header_hash
+            || tip.block_height != manifest.block_height {
+            return Err(Error::SnapshotError("Snapshot manifest does not match its chain tip".to_string()));
+        }
+
+        // every trie must be intact, and the tries of all known blocks must have the roots their
+        // headers say they do (the boot block's state root is not filled in)
+        let index_roots : Vec<_> = headers.iter()
+            .map(|header| (header.index_block_hash(), header.index_root.clone()))
+            .collect();
+        let clarity_roots : Vec<_> = headers.iter()
+            .filter(|header| header.block_height > 0)
+            .map(|header| (header.index_block_hash(), header.anchored_header.state_index_root.clone()))
+            .collect();
+
+        check_marf(&headers_marf_path, &index_roots)?;
+        check_marf(&clarity_marf_path, &clarity_roots)?;
+
+        let burndb = BurnDB::open(&path_to_string(burndb_dir)?, false)?;
+        let burn_tip = BurnDB::get_canonical_burn_chain_tip(burndb.conn())?;
+        if burn_tip.burn_header_hash != tip.burn_header_hash || burn_tip.block_height != manifest.burn_block_height {
+            return Err(Error::SnapshotError(format!("Snapshot burn chain tip is {}, not {}", &burn_tip.burn_header_hash, &tip.burn_header_hash)));
+        }
+
+        // walk the burn chain tip's fork back to the first burnchain block
+        let mut burn_roots = vec![];
+        let mut burn_fork = HashSet::new();
+        let mut sn = burn_tip;
+        loop {
+            burn_roots.push((BlockHeaderHash::from(sn.burn_header_hash.clone()), sn.index_root.clone()));
+            burn_fork.insert(sn.burn_header_hash.clone());
+            if sn.is_initial() {
+                break;
+            }
+
+            let parent_sn = match BurnDB::get_block_snapshot(burndb.conn(), &sn.parent_burn_header_hash)? {
+                Some(parent_sn) => parent_sn,
+                None => {
+                    return Err(Error::SnapshotError(format!("Snapshot burn chain is missing {}", &sn.parent_burn_header_hash)));
+                }
+            };
+            if parent_sn.block_height + 1 != sn.block_height {
+                return Err(Error::SnapshotError(format!("Snapshot burn chain is broken at {}", &sn.burn_header_hash)));
+            }
+            sn = parent_sn;
+        }
+
+        check_marf(&burn_marf_path, &burn_roots)?;
+
+        // walk the header chain back to the boot block
+        let mut chainstate = StacksChainState::open(manifest.mainnet, manifest.chain_id, &path_to_string(chainstate_root)?)?;
+        check_sortition(&burndb, &burn_fork, &tip)?;
+
+        let mut child = tip.clone();
+        for height in (1..tip.block_height).rev() {
+            let parent = chainstate.get_ancestor_header_info(trusted_tip, height)?
+                .ok_or_else(|| Error::SnapshotError(format!("Snapshot has no ancestor of {} at height {}", trusted_tip, height)))?;
+
+            if parent.block_height != height || parent.anchored_header.block_hash() != child.anchored_header.parent_block {
+                return Err(Error::SnapshotError(format!("Snapshot header chain is broken at height {}", height)));
+            }
+            check_sortition(&burndb, &burn_fork, &parent)?;
+            child = parent;
+        }
+
+        if child.anchored_header.parent_block != FIRST_STACKS_BLOCK_HASH {
+            return Err(Error::SnapshotError("Snapshot header chain does not reach the boot block".to_string()));
+        }
+
+        match chainstate.get_stacks_chain_tip(&burndb)? {
+            Some(ref stacks_tip) if stacks_tip.burn_header_hash == tip.burn_header_hash && stacks_tip.anchored_block_hash == manifest.block_hash => {},
+            _ => {
+                return Err(Error::SnapshotError(format!("Snapshot's canonical Stacks chain tip is not {}", trusted_tip)));
+            }
+        }
+
+        let mut side_store_path = chain_dir.clone();
+        side_store_path.push("vm");
+        side_store_path.push("clarity");
+        side_store_path.push("data.sqlite");
+
+        check_clarity_side_store(&mut chainstate, &side_store_path, &headers)?;
+
+        Ok(())
+    }
+
+    /// Open the chain state, first importing it from a snapshot of trusted_tip if there is no
+    /// state for this chain yet.  See import_snapshot().
+    pub fn open_from_snapshot(mainnet: bool, chain_id: u32, path_str: &str, burndb_path: &str,
+                              snapshot_path: &str, trusted_tip: &BlockHeaderHash) -> Result<StacksChainState, Error> {
+        let mut chain_dir = PathBuf::from(path_str);
+        chain_dir.push(StacksChainState::chain_dir_name(mainnet, chain_id));
+
+        if fs::metadata(&chain_dir).is_err() {
+            StacksChainState::import_snapshot(snapshot_path, mainnet, chain_id, path_str, burndb_path, trusted_tip)?;
+        }
+        StacksChainState::open(mainnet, chain_id, path_str)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use chainstate::burn::db::burndb::BurnDB;
+    use chainstate::stacks::StacksBlockHeader;
+    use net::test::*;
+
+    fn make_peer_with_blocks(test_name: &str, port: u16, num_blocks: usize) -> (TestPeer, Vec<BlockHeaderHash>) {
+        let peer_config = TestPeerConfig::new(test_name, port, port + 10000);
+        let mut peer = TestPeer::new(peer_config);
+
+        let mut index_block_hashes = vec![];
+        for _i in 0..num_blocks {
+            let (burn_ops, stacks_block, microblocks) = peer.make_default_tenure();
+            peer.next_burnchain_block(burn_ops.clone());
+            peer.process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+            let sn = BurnDB::get_canonical_burn_chain_tip(peer.burndb.as_ref().unwrap().conn()).unwrap();
+            index_block_hashes.push(StacksBlockHeader::make_index_block_hash(&sn.burn_header_hash, &stacks_block.block_hash()));
+        }
+
+        (peer, index_block_hashes)
+    }
+
+    fn snapshot_test_paths(test_name: &str) -> (String, String, String) {
+        let test_path = format!("/tmp/blockstack-test-snapshot-{}", test_name);
+        if fs::metadata(&test_path).is_ok() {
+            fs::remove_dir_all(&test_path).unwrap();
+        }
+        fs::create_dir_all(&test_path).unwrap();
+
+        (format!("{}/snapshot.bin", &test_path), format!("{}/chainstate", &test_path), format!("{}/burn", &test_path))
+    }
+
+    #[test]
+    fn test_snapshot_export_import() {
+        let (mut peer, index_block_hashes) = make_peer_with_blocks("test_snapshot_export_import", 32050, 5);
+        let (snapshot_path, chainstate_path, burndb_path) = snapshot_test_paths("test_snapshot_export_import");
+
+        let network_id = peer.config.network_id;
+        let peer_burndb_path = format!("/tmp/blockstack-test-peer-{}-{}/burn", "test_snapshot_export_import", 32050);
+        let trusted_tip = index_block_hashes[2].clone();
+
+        let expected_header = StacksChainState::get_stacks_block_header_info_by_index_block_hash(&peer.chainstate().headers_db, &trusted_tip).unwrap().unwrap();
+
+        let manifest = StacksChainState::export_snapshot(false, network_id, &peer.chainstate_path, &peer_burndb_path, &trusted_tip, &snapshot_path).unwrap();
+        assert_eq!(manifest.index_block_hash, trusted_tip);
+        assert_eq!(manifest.block_height, expected_header.block_height);
+        assert_eq!(manifest.state_index_root, expected_header.anchored_header.state_index_root);
+        assert_eq!(StacksChainState::get_snapshot_manifest(&snapshot_path).unwrap(), manifest);
+
+        // the source chain state is untouched
+        assert!(StacksChainState::get_stacks_block_header_info_by_index_block_hash(&peer.chainstate().headers_db, &index_block_hashes[4]).unwrap().is_some());
+
+        // snapshots are only imported for the tip they were made of
+        assert!(StacksChainState::import_snapshot(&snapshot_path, false, network_id, &chainstate_path, &burndb_path, &index_block_hashes[3]).is_err());
+        assert!(fs::metadata(&burndb_path).is_err());
+
+        let chainstate = StacksChainState::open_from_snapshot(false, network_id, &chainstate_path, &burndb_path, &snapshot_path, &trusted_tip).unwrap();
+        let burndb = BurnDB::open(&burndb_path, false).unwrap();
+
+        let stacks_tip = chainstate.get_stacks_chain_tip(&burndb).unwrap().unwrap();
+        assert_eq!(stacks_tip.burn_header_hash, expected_header.burn_header_hash);
+        assert_eq!(stacks_tip.anchored_block_hash, expected_header.anchored_header.block_hash());
+
+        let burn_tip = BurnDB::get_canonical_burn_chain_tip(burndb.conn()).unwrap();
+        assert_eq!(burn_tip.burn_header_hash, expected_header.burn_header_hash);
+
+        // later blocks are gone; earlier ones are still there
+        assert!(StacksChainState::get_stacks_block_header_info_by_index_block_hash(&chainstate.headers_db, &index_block_hashes[3]).unwrap().is_none());
+        assert!(StacksChainState::get_stacks_block_header_info_by_index_block_hash(&chainstate.headers_db, &index_block_hashes[1]).unwrap().is_some());
+        assert_eq!(chainstate.get_ancestor_headers(&trusted_tip, 10).unwrap().unwrap().len() as u64, expected_header.block_height + 1);
+
+        // can't import over existing state
+        assert!(StacksChainState::import_snapshot(&snapshot_path, false, network_id, &chainstate_path, &burndb_path, &trusted_tip).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_import_corrupt() {
+        let (mut peer, index_block_hashes) = make_peer_with_blocks("test_snapshot_import_corrupt", 32052, 3);
+        let (snapshot_path, chainstate_path, burndb_path) = snapshot_test_paths("test_snapshot_import_corrupt");
+
+        let network_id = peer.config.network_id;
+        let peer_burndb_path = format!("/tmp/blockstack-test-peer-{}-{}/burn", "test_snapshot_import_corrupt", 32052);
+        let trusted_tip = index_block_hashes[1].clone();
+
+        StacksChainState::export_snapshot(false, network_id, &peer.chainstate_path, &peer_burndb_path, &trusted_tip, &snapshot_path).unwrap();
+
+        // flip the last byte of the archive
+        let mut bytes = fs::read(&snapshot_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0x01;
+        fs::write(&snapshot_path, &bytes).unwrap();
+
+        match StacksChainState::import_snapshot(&snapshot_path, false, network_id, &chainstate_path, &burndb_path, &trusted_tip) {
+            Err(Error::SnapshotError(_)) => {},
+            res => panic!("Imported a corrupt snapshot: {:?}", &res)
+        }
+
+        // nothing was left behind
+        let mut chain_dir = PathBuf::from(&chainstate_path);
+        chain_dir.push(StacksChainState::chain_dir_name(false, network_id));
+        assert!(fs::metadata(&chain_dir).is_err());
+        assert!(fs::metadata(&burndb_path).is_err());
+        assert!(fs::metadata(&format!("{}.import", &burndb_path)).is_err());
+        assert!(peer.chainstate().get_ancestor_headers(&trusted_tip, 1).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_snapshot_import_bad_clarity_value() {
+        let (peer, index_block_hashes) = make_peer_with_blocks("test_snapshot_import_bad_clarity_value", 32054, 3);
+        let (snapshot_path, chainstate_path, burndb_path) = snapshot_test_paths("test_snapshot_import_bad_clarity_value");
+
+        let network_id = peer.config.network_id;
+        let peer_burndb_path = format!("/tmp/blockstack-test-peer-{}-{}/burn", "test_snapshot_import_bad_clarity_value", 32054);
+        let trusted_tip = index_block_hashes[1].clone();
+
+        // change a value in the Clarity side store.  The MARF still commits to the old value's
+        // hash, so the MARF checks alone would pass.
+        let mut side_store_path = PathBuf::from(&peer.chainstate_path);
+        side_store_path.push(StacksChainState::chain_dir_name(false, network_id));
+        side_store_path.push("vm");
+        side_store_path.push("clarity");
+        side_store_path.push("data.sqlite");
+
+        let conn = Connection::open(&side_store_path).unwrap();
+        let changed = conn.execute("UPDATE data_table SET value = value || '00' WHERE rowid = (SELECT MIN(rowid) FROM data_table)", NO_PARAMS).unwrap();
+        assert_eq!(changed, 1);
+
+        StacksChainState::export_snapshot(false, network_id, &peer.chainstate_path, &peer_burndb_path, &trusted_tip, &snapshot_path).unwrap();
+
+        match StacksChainState::import_snapshot(&snapshot_path, false, network_id, &chainstate_path, &burndb_path, &trusted_tip) {
+            Err(Error::SnapshotError(_)) => {},
+            res => panic!("Imported a snapshot with a bad Clarity value: {:?}", &res)
+        }
+
+        let mut chain_dir = PathBuf::from(&chainstate_path);
+        chain_dir.push(StacksChainState::chain_dir_name(false, network_id));
+        assert!(fs::metadata(&chain_dir).is_err());
+    }
+}
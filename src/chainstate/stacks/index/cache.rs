@@ -0,0 +1,251 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chainstate::stacks::index::TrieHash;
+use chainstate::stacks::index::node::TrieNodeType;
+use chainstate::stacks::index::node::TriePtr;
+
+/// Default number of decoded trie nodes (and, separately, node hashes) to keep in RAM
+pub const MARF_NODE_CACHE_DEFAULT_CAPACITY : usize = 16384;
+
+/// Capacity of the node cache of each MARF storage opened from now on
+static NODE_CACHE_CAPACITY : AtomicUsize = AtomicUsize::new(MARF_NODE_CACHE_DEFAULT_CAPACITY);
+
+/// Set how many decoded nodes (and node hashes) every MARF storage opened after this call caches.
+/// 0 disables the cache.  Storage that is already open keeps its capacity; see
+/// TrieFileStorage::set_node_cache_capacity().
+pub fn set_node_cache_capacity(capacity: usize) -> () {
+    NODE_CACHE_CAPACITY.store(capacity, Ordering::SeqCst);
+}
+
+pub fn node_cache_capacity() -> usize {
+    NODE_CACHE_CAPACITY.load(Ordering::SeqCst)
+}
+
+/// Number of tries whose offsets in the flat data file to keep in RAM
+pub const MARF_TRIE_LOCATION_CACHE_CAPACITY : usize = 65536;
+
+/// A bounded map that evicts its least-recently-used entry when full.
+/// A capacity of 0 disables it.
+#[derive(Clone)]
+pub struct LruCache<K: Hash + Eq + Clone, V: Clone> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,      // value and the tick it was last used
+    recency: BTreeMap<u64, K>,          // tick to key, oldest first
+    tick: u64
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity: capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.recency.remove(&entry.1);
+                self.recency.insert(tick, key.clone());
+                entry.1 = tick;
+                Some(entry.0.clone())
+            },
+            None => None
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> () {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(tick, key);
+        self.evict();
+    }
+
+    fn evict(&mut self) -> () {
+        while self.entries.len() > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(t) => *t,
+                None => break
+            };
+            if let Some(evicted) = self.recency.remove(&oldest) {
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) -> () {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, evicting the least-recently-used entries if it shrank
+    pub fn set_capacity(&mut self, capacity: usize) -> () {
+        self.capacity = capacity;
+        self.evict();
+    }
+}
+
+/// Cache of nodes read out of flushed tries, keyed by (block identifier, node pointer).
+/// Pointers must not have their back-pointer bit set.
+/// A flushed trie's blob never changes once written, so entries stay valid until tries are
+/// deleted through any handle on the storage (see TrieFileStorage::check_storage_epoch()).  The
+/// trie being extended in RAM is never cached.
+#[derive(Clone)]
+pub struct TrieNodeCache {
+    nodes: LruCache<(u32, u8, u32), (TrieNodeType, TrieHash)>,     // how a node decodes depends on the pointer's node ID
+    hashes: LruCache<(u32, u32), TrieHash>,
+    hits: u64,
+    misses: u64
+}
+
+impl TrieNodeCache {
+    pub fn new(capacity: usize) -> TrieNodeCache {
+        TrieNodeCache {
+            nodes: LruCache::new(capacity),
+            hashes: LruCache::new(capacity),
+            hits: 0,
+            misses: 0
+        }
+    }
+
+    pub fn get_node(&mut self, block_id: u32, ptr: &TriePtr) -> Option<(TrieNodeType, TrieHash)> {
+        let res = self.nodes.get(&(block_id, ptr.id(), ptr.ptr()));
+        self.count(res.is_some());
+        res
+    }
+
+    pub fn get_hash(&mut self, block_id: u32, ptr: &TriePtr) -> Option<TrieHash> {
+        let res = match self.hashes.get(&(block_id, ptr.ptr())) {
+            Some(hash) => Some(hash),
+            None => self.nodes.get(&(block_id, ptr.id(), ptr.ptr())).map(|(_, hash)| hash)
+        };
+        self.count(res.is_some());
+        res
+    }
+
+    pub fn store_node(&mut self, block_id: u32, ptr: &TriePtr, node: TrieNodeType, hash: TrieHash) -> () {
+        self.nodes.insert((block_id, ptr.id(), ptr.ptr()), (node, hash));
+    }
+
+    pub fn store_hash(&mut self, block_id: u32, ptr: &TriePtr, hash: TrieHash) -> () {
+        self.hashes.insert((block_id, ptr.ptr()), hash);
+    }
+
+    fn count(&mut self, hit: bool) -> () {
+        if hit {
+            self.hits += 1;
+        }
+        else {
+            self.misses += 1;
+        }
+    }
+
+    pub fn clear(&mut self) -> () {
+        self.nodes.clear();
+        self.hashes.clear();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.nodes.capacity()
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) -> () {
+        self.nodes.set_capacity(capacity);
+        self.hashes.set_capacity(capacity);
+    }
+
+    /// Get and reset the (hits, misses) counters
+    pub fn stats(&mut self) -> (u64, u64) {
+        let ret = (self.hits, self.misses);
+        self.hits = 0;
+        self.misses = 0;
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        let mut cache = LruCache::new(3);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        cache.insert(3, "c");
+        assert_eq!(cache.len(), 3);
+
+        // touching 1 makes 2 the oldest
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.insert(4, "d");
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.get(&4), Some("d"));
+
+        // overwriting doesn't grow the cache
+        cache.insert(4, "e");
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.get(&4), Some("e"));
+
+        // shrinking evicts the oldest (1, then 3)
+        cache.set_capacity(1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.get(&4), Some("e"));
+
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+
+        // capacity 0 stores nothing
+        let mut cache = LruCache::new(0);
+        cache.insert(1, "a");
+        assert_eq!(cache.get(&1), None);
+    }
+}
@@ -0,0 +1,418 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chainstate::burn::BlockHeaderHash;
+
+use chainstate::stacks::index::{
+    TrieHash,
+    BlockMap,
+    Error
+};
+
+use chainstate::stacks::index::bits::{
+    get_leaf_hash,
+    get_nodetype_hash_bytes
+};
+
+use chainstate::stacks::index::node::{
+    is_backptr,
+    TrieNodeID,
+    TrieNodeType,
+    TriePtr
+};
+
+use chainstate::stacks::index::storage::TrieFileStorage;
+use chainstate::stacks::index::trie::Trie;
+
+/// Something wrong with a trie
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrieDamage {
+    /// The trie's bytes could not be read or decoded
+    Unreadable(String),
+    /// A back-pointer refers to a block with no trie, or to a node that isn't in that block's trie
+    DanglingBackptr(TriePtr),
+    /// A node's stored hash doesn't match the hash of its contents and children (stored, computed)
+    NodeHashMismatch(TriePtr, TrieHash, TrieHash),
+    /// The root's stored hash doesn't match the hash of its contents, children, and ancestor
+    /// tries' roots (stored, computed)
+    RootHashMismatch(TrieHash, TrieHash),
+    /// The root hash isn't the one recorded for this block outside of the MARF (expected, stored)
+    ExpectedRootMismatch(TrieHash, TrieHash)
+}
+
+impl fmt::Display for TrieDamage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrieDamage::Unreadable(ref msg) => write!(f, "unreadable: {}", msg),
+            TrieDamage::DanglingBackptr(ref ptr) => write!(f, "back-pointer to missing node {} in block {}", ptr.ptr(), ptr.back_block()),
+            TrieDamage::NodeHashMismatch(ref ptr, ref stored, ref computed) => write!(f, "node {} has hash {}, expected {}", ptr.ptr(), stored, computed),
+            TrieDamage::RootHashMismatch(ref stored, ref computed) => write!(f, "root has hash {}, expected {}", stored, computed),
+            TrieDamage::ExpectedRootMismatch(ref expected, ref stored) => write!(f, "root hash {} does not match recorded root hash {}", stored, expected)
+        }
+    }
+}
+
+/// The outcome of checking every trie in a MARF
+#[derive(Debug, Clone, PartialEq)]
+pub struct MARFCheckReport {
+    pub num_tries: u64,
+    pub num_nodes: u64,
+    /// blocks that have an expected root hash, but no trie
+    pub missing: Vec<BlockHeaderHash>,
+    /// damaged tries and what's wrong with them, in the order they were written
+    pub damaged: Vec<(BlockHeaderHash, Vec<TrieDamage>)>
+}
+
+impl MARFCheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.len() == 0 && self.damaged.len() == 0
+    }
+}
+
+/// Is the node a back-pointer points to actually there?
+/// s must point to block_hash, and will point to it again afterwards.
+fn check_backptr(storage: &mut TrieFileStorage, block_hash: &BlockHeaderHash, block_id: u32, ptr: &TriePtr) -> Result<bool, Error> {
+    let back_block_hash = match storage.get_block_hash(ptr.back_block()) {
+        Ok(bhh) => bhh,
+        Err(Error::NotFoundError) => {
+            return Ok(false);
+        },
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    storage.open_block_known_id(&back_block_hash, ptr.back_block())?;
+    let res = storage.read_nodetype(&ptr.from_backptr());
+    storage.open_block_known_id(block_hash, block_id)?;
+
+    match res {
+        Ok(_) => Ok(true),
+        Err(Error::NotFoundError) | Err(Error::CorruptionError(_)) => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+/// Walk one trie breadth-first, recording anything wrong with it in damage.  Errs if the trie
+/// can't be walked any further.
+fn inner_check_trie(storage: &mut TrieFileStorage, block_hash: &BlockHeaderHash, expected_root: Option<&TrieHash>,
+                    damage: &mut Vec<TrieDamage>, num_nodes: &mut u64) -> Result<(), Error> {
+    storage.open_block(block_hash)?;
+    let block_id = storage.get_cur_block_identifier()?;
+
+    let root_ptr = storage.root_trieptr();
+    let (root, root_hash) = Trie::read_root(storage)?;
+    if !root.is_node256() {
+        return Err(Error::CorruptionError("Root is not a node256".to_string()));
+    }
+
+    let mut visited = HashSet::new();
+    let mut frontier = VecDeque::new();
+    visited.insert(root_ptr.ptr());
+    frontier.push_back((root_ptr.clone(), root, root_hash.clone()));
+
+    while let Some((ptr, node, stored_hash)) = frontier.pop_front() {
+        *num_nodes += 1;
+
+        // a node's hash covers its back-pointers' block hashes, so it can't be checked if any of
+        // them are dangling
+        let mut hashable = true;
+        if !node.is_leaf() {
+            for child_ptr in node.ptrs().iter() {
+                if child_ptr.id() == TrieNodeID::Empty as u8 {
+                    continue;
+                }
+
+                if is_backptr(child_ptr.id()) {
+                    if !check_backptr(storage, block_hash, block_id, child_ptr)? {
+                        damage.push(TrieDamage::DanglingBackptr(child_ptr.clone()));
+                        hashable = false;
+                    }
+                }
+                else {
+                    if !visited.insert(child_ptr.ptr()) {
+                        return Err(Error::CorruptionError(format!("Node at {} is reachable more than once", child_ptr.ptr())));
+                    }
+                    let (child, child_hash) = storage.read_nodetype(child_ptr)?;
+                    frontier.push_back((child_ptr.clone(), child, child_hash));
+                }
+            }
+        }
+
+        if !hashable {
+            continue;
+        }
+
+        let computed_hash = match node {
+            TrieNodeType::Leaf(ref leaf) => get_leaf_hash(leaf),
+            _ => {
+                let children_hashes = Trie::get_children_hashes(storage, &node)?;
+                get_nodetype_hash_bytes(&node, &children_hashes, storage)
+            }
+        };
+
+        if ptr.ptr() == root_ptr.ptr() {
+            // the first trie's root hash is just its node hash.  Every other root also mixes in
+            // the root hashes of its ancestors.
+            if computed_hash != stored_hash {
+                let computed_root_hash = Trie::get_trie_root_hash(storage, &computed_hash)?;
+                if computed_root_hash != stored_hash {
+                    damage.push(TrieDamage::RootHashMismatch(stored_hash, computed_root_hash));
+                }
+            }
+        }
+        else if computed_hash != stored_hash {
+            damage.push(TrieDamage::NodeHashMismatch(ptr, stored_hash, computed_hash));
+        }
+    }
+
+    if let Some(expected_root_hash) = expected_root {
+        if *expected_root_hash != root_hash {
+            damage.push(TrieDamage::ExpectedRootMismatch(expected_root_hash.clone(), root_hash));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check every flushed trie in storage.  Each node's hash is recomputed from its contents and its
+/// children's hashes (and, for roots, the ancestor tries' root hashes), and each back-pointer must
+/// lead to a node in an existing trie.  expected_roots lists root hashes recorded for blocks
+/// outside of the MARF (i.e. in the Stacks headers DB); those tries' roots must match, and those
+/// blocks must have tries.
+/// The node cache is disabled for the duration of the check, so every node is read from disk.
+pub fn check_tries(storage: &mut TrieFileStorage, expected_roots: &[(BlockHeaderHash, TrieHash)]) -> Result<MARFCheckReport, Error> {
+    let cache_capacity = storage.node_cache_capacity();
+    storage.set_node_cache_capacity(0);
+    let result = inner_check_tries(storage, expected_roots);
+    storage.set_node_cache_capacity(cache_capacity);
+    result
+}
+
+fn inner_check_tries(storage: &mut TrieFileStorage, expected_roots: &[(BlockHeaderHash, TrieHash)]) -> Result<MARFCheckReport, Error> {
+    let expected : HashMap<BlockHeaderHash, TrieHash> = expected_roots.iter().cloned().collect();
+
+    let mut report = MARFCheckReport {
+        num_tries: 0,
+        num_nodes: 0,
+        missing: vec![],
+        damaged: vec![]
+    };
+
+    let mut present = HashSet::new();
+    for (block_hash, _, _) in storage.read_block_parents()?.into_iter() {
+        let mut damage = vec![];
+        if let Err(e) = inner_check_trie(storage, &block_hash, expected.get(&block_hash), &mut damage, &mut report.num_nodes) {
+            damage.push(TrieDamage::Unreadable(format!("{}", &e)));
+        }
+
+        if damage.len() > 0 {
+            warn!("Trie for {} is damaged: {:?}", &block_hash, &damage);
+            report.damaged.push((block_hash.clone(), damage));
+        }
+
+        report.num_tries += 1;
+        present.insert(block_hash);
+    }
+
+    for (block_hash, _) in expected_roots.iter() {
+        if !present.contains(block_hash) {
+            warn!("No trie for {}", block_hash);
+            report.missing.push(block_hash.clone());
+        }
+    }
+
+    Ok(report)
+}
+
+/// Move the damaged tries in report out of the MARF and into its quarantine table, along with
+/// every trie descended from them (which may hold back-pointers into the damaged ones).
+/// Returns the quarantined blocks in the order they were written.
+pub fn quarantine_damaged_tries(storage: &mut TrieFileStorage, report: &MARFCheckReport) -> Result<Vec<BlockHeaderHash>, Error> {
+    if report.damaged.len() == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut reasons = HashMap::new();
+    for (block_hash, damage) in report.damaged.iter() {
+        let reason = damage.iter()
+            .map(|d| format!("{}", d))
+            .collect::<Vec<String>>()
+            .join("; ");
+        reasons.insert(block_hash.clone(), reason);
+    }
+
+    // a child trie is always written after its parent, so one pass in write order finds every
+    // descendant
+    let mut quarantined = HashSet::new();
+    let mut to_quarantine = vec![];
+    for (block_hash, parent_hash, _) in storage.read_block_parents()?.into_iter() {
+        let reason = match reasons.remove(&block_hash) {
+            Some(reason) => reason,
+            None => {
+                if quarantined.contains(&parent_hash) {
+                    format!("descends from quarantined block {}", &parent_hash)
+                }
+                else {
+                    continue;
+                }
+            }
+        };
+
+        quarantined.insert(block_hash.clone());
+        to_quarantine.push((block_hash, reason));
+    }
+
+    storage.quarantine_blocks(&to_quarantine)?;
+    Ok(to_quarantine.into_iter().map(|(block_hash, _)| block_hash).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    use rusqlite::{Connection, NO_PARAMS};
+    use rusqlite::types::ToSql;
+
+    use chainstate::stacks::index::marf::MARF;
+    use chainstate::stacks::index::MARFValue;
+
+    fn block(fork: u8, height: u8) -> BlockHeaderHash {
+        BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0])
+    }
+
+    /// canonical fork a0..a5, and b3 off of a2
+    fn make_test_marf(path: &str) -> Vec<(BlockHeaderHash, TrieHash)> {
+        if fs::metadata(path).is_ok() {
+            fs::remove_file(path).unwrap();
+        }
+
+        let mut marf = MARF::from_path(path, None).unwrap();
+        let mut build = |parent: &BlockHeaderHash, fork: u8, height: u8| {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+            for i in 0..20 {
+                marf.insert(&format!("{}-{}-{}", fork, height, i), MARFValue::from_value(&format!("value-{}-{}-{}", fork, height, i))).unwrap();
+            }
+            marf.commit().unwrap();
+            bhh
+        };
+
+        let mut parent = TrieFileStorage::block_sentinel();
+        for h in 0..6 {
+            parent = build(&parent, 0xa, h);
+        }
+        build(&block(0xa, 2), 0xb, 3);
+
+        let mut marf = MARF::from_path(path, None).unwrap();
+        let mut roots = vec![];
+        for h in 0..6 {
+            roots.push((block(0xa, h), marf.borrow_storage_backend().read_block_root_hash(&block(0xa, h)).unwrap()));
+        }
+        roots.push((block(0xb, 3), marf.borrow_storage_backend().read_block_root_hash(&block(0xb, 3)).unwrap()));
+        roots
+    }
+
+    #[test]
+    fn test_check_tries() {
+        let path = "/tmp/test_check_tries.sqlite";
+        let roots = make_test_marf(path);
+
+        let mut marf = MARF::from_path(path, None).unwrap();
+        let cache_capacity = marf.borrow_storage_backend().node_cache_capacity();
+        marf.borrow_storage_backend().node_cache_stats();
+
+        let report = marf.check_integrity(&roots).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.num_tries, 7);
+        assert!(report.num_nodes > 7);
+
+        // every node was read from disk, and the cache is back on afterwards
+        let (hits, _) = marf.borrow_storage_backend().node_cache_stats();
+        assert_eq!(hits, 0);
+        assert_eq!(marf.borrow_storage_backend().node_cache_capacity(), cache_capacity);
+
+        // roots that disagree with the recorded ones, or that have no trie
+        let mut bad_roots = roots.clone();
+        bad_roots[3].1 = TrieHash([0x11; 32]);
+        bad_roots.push((block(0xc, 0), TrieHash([0x22; 32])));
+
+        let report = marf.check_integrity(&bad_roots).unwrap();
+        assert_eq!(report.missing, vec![block(0xc, 0)]);
+        assert_eq!(report.damaged, vec![(block(0xa, 3), vec![TrieDamage::ExpectedRootMismatch(TrieHash([0x11; 32]), roots[3].1.clone())])]);
+
+        // corrupt a4's root hash.  a5's root hash mixes in a4's, so it no longer checks out either.
+        {
+            let conn = Connection::open(path).unwrap();
+            let mut data : Vec<u8> = conn.query_row("SELECT data FROM marf_data WHERE block_hash = ?1", &[&block(0xa, 4)], |row| row.get(0)).unwrap();
+            let root_ptr = TrieFileStorage::root_ptr_disk() as usize;
+            data[root_ptr] ^= 0xff;
+            let args: &[&dyn ToSql] = &[&data, &block(0xa, 4)];
+            conn.execute("UPDATE marf_data SET data = ?1 WHERE block_hash = ?2", args).unwrap();
+        }
+
+        let mut marf = MARF::from_path(path, None).unwrap();
+        let report = marf.check_integrity(&roots).unwrap();
+        assert!(report.missing.is_empty());
+        assert_eq!(report.damaged.iter().map(|(bhh, _)| bhh.clone()).collect::<Vec<_>>(), vec![block(0xa, 4), block(0xa, 5)]);
+        match report.damaged[0].1[0] {
+            TrieDamage::RootHashMismatch(ref stored, ref computed) => {
+                assert!(stored != computed);
+                assert_eq!(*computed, roots[4].1);
+            },
+            _ => assert!(false)
+        }
+
+        // quarantine takes a4 and its descendants out of the MARF
+        let quarantined = marf.quarantine_damaged(&report).unwrap();
+        assert_eq!(quarantined, vec![block(0xa, 4), block(0xa, 5)]);
+
+        let report = marf.check_integrity(&roots[0..4]).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.num_tries, 5);
+        assert_eq!(marf.get(&block(0xb, 3), "10-2-7").unwrap(), Some(MARFValue::from_value("value-10-2-7")));
+        assert!(marf.get(&block(0xa, 5), "10-2-7").is_err());
+
+        let conn = Connection::open(path).unwrap();
+        let num_quarantined : i64 = conn.query_row("SELECT COUNT(*) FROM marf_quarantine", NO_PARAMS, |row| row.get(0)).unwrap();
+        assert_eq!(num_quarantined, 2);
+    }
+
+    #[test]
+    fn test_check_tries_dangling_backptr() {
+        let path = "/tmp/test_check_tries_dangling_backptr.sqlite";
+        let roots = make_test_marf(path);
+
+        // remove a0 without its descendants
+        {
+            let conn = Connection::open(path).unwrap();
+            conn.execute("DELETE FROM marf_data WHERE block_hash = ?1", &[&block(0xa, 0)]).unwrap();
+        }
+
+        let mut marf = MARF::from_path(path, None).unwrap();
+        let report = marf.check_integrity(&roots).unwrap();
+        assert_eq!(report.missing, vec![block(0xa, 0)]);
+        assert_eq!(report.damaged[0].0, block(0xa, 1));
+        assert!(report.damaged[0].1.iter().any(|d| match d { TrieDamage::DanglingBackptr(_) => true, _ => false }));
+    }
+}
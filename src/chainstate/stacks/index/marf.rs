@@ -34,6 +34,7 @@ use std::path::{
 
 use std::marker::PhantomData;
 use std::fs;
+use std::collections::{HashMap, HashSet};
 
 use sha2::Digest;
 
@@ -65,11 +66,14 @@ use chainstate::stacks::index::node::{
 };
 
 use chainstate::stacks::index::storage::{
-    TrieFileStorage
+    TrieFileStorage,
+    TrieHashCalculationMode,
+    TrieStorageBackend
 };
 
 use chainstate::stacks::index::{
     proofs::TrieMerkleProof,
+    proofs::TrieMerkleBatchProof,
     TrieHash,
     TRIEHASH_ENCODED_SIZE,
     MARFValue
@@ -79,6 +83,12 @@ use chainstate::stacks::index::trie::{
     Trie,
 };
 
+use chainstate::stacks::index::fsck::{
+    check_tries,
+    quarantine_damaged_tries,
+    MARFCheckReport
+};
+
 use chainstate::stacks::index::Error as Error;
 
 use util::log;
@@ -94,6 +104,19 @@ pub struct MARF {
     readonly: bool
 }
 
+/// What MARF::prune_forks() kept and removed
+#[derive(Debug, Clone, PartialEq)]
+pub struct MARFPruneReport {
+    /// fork tips whose tries (and all their ancestors') were kept
+    pub retained_tips: Vec<BlockHeaderHash>,
+    /// number of tries kept
+    pub num_kept: u64,
+    /// tries removed (or that would be removed)
+    pub pruned: Vec<BlockHeaderHash>,
+    /// total size of the removed tries
+    pub pruned_bytes: u64
+}
+
 #[derive(Clone)]
 struct WriteChainTip {
     block_hash: BlockHeaderHash,
@@ -505,7 +528,13 @@ impl MARF {
     /// This will have the side-effect of instantiating a new fork table from the tries encoded on
     /// disk. Performant code should call this method sparingly.
     pub fn from_path(path: &str, miner_tip: Option<&BlockHeaderHash>) -> Result<MARF, Error> {
-        let mut file_storage = TrieFileStorage::new(path)?;
+        MARF::from_path_with_backend(path, miner_tip, None)
+    }
+
+    /// Like from_path(), but choose where the tries are stored.  None means whichever backend the
+    /// MARF already uses (SQLite blobs for a new MARF).  See TrieFileStorage::new_with_backend().
+    pub fn from_path_with_backend(path: &str, miner_tip: Option<&BlockHeaderHash>, backend: Option<TrieStorageBackend>) -> Result<MARF, Error> {
+        let mut file_storage = TrieFileStorage::new_with_backend(path, backend)?;
         match fs::metadata(path) {
             Ok(_) => {},
             Err(e) => {
@@ -530,6 +559,7 @@ impl MARF {
     }
 
     pub fn get_with_proof(&mut self, block_hash: &BlockHeaderHash, key: &str) -> Result<Option<(MARFValue, TrieMerkleProof)>, Error> {
+        self.seal()?;
         let marf_value = match MARF::get_by_key(&mut self.storage, block_hash, key)? {
             None => return Ok(None),
             Some(x) => x
@@ -538,6 +568,23 @@ impl MARF {
         Ok(Some((marf_value, proof)))
     }
 
+    /// Look up several keys at once, and make a single batched proof for the ones that exist.
+    /// The batch has one proof for each Some(..) value, in the order the keys were given.
+    pub fn get_many_with_proof(&mut self, block_hash: &BlockHeaderHash, keys: &[String]) -> Result<(Vec<Option<MARFValue>>, TrieMerkleBatchProof), Error> {
+        self.seal()?;
+        let mut marf_values = Vec::with_capacity(keys.len());
+        let mut proven = vec![];
+        for key in keys.iter() {
+            let marf_value = MARF::get_by_key(&mut self.storage, block_hash, key)?;
+            if let Some(ref marf_value) = marf_value {
+                proven.push((key.clone(), marf_value.clone()));
+            }
+            marf_values.push(marf_value);
+        }
+        let proof = TrieMerkleBatchProof::from_raw_entries(&mut self.storage, &proven, block_hash)?;
+        Ok((marf_values, proof))
+    }
+
     pub fn get_bhh_at_height(&mut self, block_hash: &BlockHeaderHash, height: u32) -> Result<Option<BlockHeaderHash>, Error> {
         MARF::get_block_at_height(&mut self.storage, height, block_hash)
     }
@@ -788,6 +835,35 @@ impl MARF {
         Ok(())
     }
     
+    /// Choose when the nodes of the trie being built get their hashes.  In deferred mode, inserts
+    /// are cheaper, and the trie is hashed in parallel when its root hash is first needed.
+    pub fn set_hash_calculation_mode(&mut self, mode: TrieHashCalculationMode) {
+        self.storage.set_hash_calculation_mode(mode);
+    }
+
+    /// Compute the node hashes the trie being built deferred, if any.  Must be done before the
+    /// trie's root hash is read, a proof is made from it, or it is flushed.
+    fn seal(&mut self) -> Result<(), Error> {
+        if !self.storage.has_pending_hashes() {
+            return Ok(());
+        }
+        let block_hash = match self.open_chain_tip {
+            Some(WriteChainTip{ ref block_hash, .. }) => block_hash.clone(),
+            None => {
+                return Ok(());
+            }
+        };
+
+        let (cur_block_hash, cur_block_id) = self.storage.get_cur_block_and_id();
+        self.storage.open_block(&block_hash)?;
+
+        let result = Trie::update_all_hashes(&mut self.storage);
+
+        // restore
+        self.storage.open_block_maybe_id(&cur_block_hash, cur_block_id)?;
+        result
+    }
+
     /// Drop the current trie from the MARF. This rolls back all
     ///   changes in the block, and closes the current chain tip.
     pub fn drop_current(&mut self) {
@@ -802,6 +878,7 @@ impl MARF {
         if self.readonly {
             return Err(Error::ReadOnlyError);
         }
+        self.seal()?;
         match self.open_chain_tip.take() {
             Some(_tip) => {
                 self.storage.flush()?;
@@ -819,6 +896,7 @@ impl MARF {
         if self.readonly {
             return Err(Error::ReadOnlyError);
         }
+        self.seal()?;
         match self.open_chain_tip.take() {
             Some(_tip) => {
                 self.storage.flush_mined(bhh)?;
@@ -834,6 +912,7 @@ impl MARF {
         if self.readonly {
             return Err(Error::ReadOnlyError);
         }
+        self.seal()?;
         match self.open_chain_tip.take() {
             Some(_tip) => {
                 self.storage.flush_to(real_bhh)?;
@@ -843,6 +922,174 @@ impl MARF {
         Ok(())
     }
 
+    /// Remove the tries for the given blocks from the MARF.  This is used to roll the MARF back
+    /// past a burnchain reorg, so the caller must remove every descendant of a removed block.
+    pub fn drop_blocks(&mut self, bhhs: &[BlockHeaderHash]) -> Result<(), Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+        if self.open_chain_tip.is_some() {
+            error!("MARF at {} is in the process of writing", &self.storage.dir_path);
+            return Err(Error::InProgressError);
+        }
+        self.storage.delete_blocks(bhhs)
+    }
+
+    /// Work out which tries can be pruned, without removing anything.
+    ///
+    /// The tries form a tree by parent block.  A fork tip (a trie with no children) is retained if
+    /// it is at most retention_depth blocks below the canonical tip; the canonical tip itself is
+    /// always retained.  Every ancestor of a retained tip is kept, since its descendants' tries
+    /// may hold back-pointers into it.  Everything else is only reachable from forks that fell too
+    /// far behind, and can be pruned.
+    pub fn find_prunable_blocks(&mut self, canonical_tip: &BlockHeaderHash, retention_depth: u32) -> Result<MARFPruneReport, Error> {
+        let rows = self.storage.read_block_parents()?;
+
+        let mut parents = HashMap::new();
+        let mut has_children = HashSet::new();
+        for (block_hash, parent_hash, _) in rows.iter() {
+            parents.insert(block_hash.clone(), parent_hash.clone());
+            has_children.insert(parent_hash.clone());
+        }
+
+        if !parents.contains_key(canonical_tip) {
+            error!("No trie for canonical tip {}", canonical_tip);
+            return Err(Error::NotFoundError);
+        }
+
+        // height of each trie above the first trie of its fork
+        let mut heights : HashMap<BlockHeaderHash, u64> = HashMap::new();
+        for (block_hash, _, _) in rows.iter() {
+            let mut path = vec![];
+            let mut cur = block_hash.clone();
+            let base_height = loop {
+                if let Some(h) = heights.get(&cur) {
+                    break *h + 1;
+                }
+                match parents.get(&cur) {
+                    Some(parent) => {
+                        path.push(cur.clone());
+                        if path.len() > rows.len() {
+                            return Err(Error::CorruptionError(format!("Cycle in trie parents at {}", &cur)));
+                        }
+                        cur = parent.clone();
+                    },
+                    None => {
+                        // not a stored trie, so the last trie on the path has no stored parent
+                        break 0;
+                    }
+                }
+            };
+            for (i, bhh) in path.iter().rev().enumerate() {
+                heights.insert(bhh.clone(), base_height + (i as u64));
+            }
+        }
+
+        let tip_height = heights[canonical_tip];
+        let min_height = tip_height.saturating_sub(retention_depth as u64);
+
+        let mut retained_tips = vec![canonical_tip.clone()];
+        for (block_hash, _, _) in rows.iter() {
+            if !has_children.contains(block_hash) && heights[block_hash] >= min_height && block_hash != canonical_tip {
+                retained_tips.push(block_hash.clone());
+            }
+        }
+
+        let mut keep = HashSet::new();
+        for tip in retained_tips.iter() {
+            let mut cur = tip.clone();
+            while !keep.contains(&cur) {
+                keep.insert(cur.clone());
+                match parents.get(&cur) {
+                    Some(parent) if parents.contains_key(parent) => {
+                        cur = parent.clone();
+                    },
+                    _ => {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut pruned = vec![];
+        let mut pruned_bytes = 0;
+        for (block_hash, _, size) in rows.iter() {
+            if !keep.contains(block_hash) {
+                pruned.push(block_hash.clone());
+                pruned_bytes += *size;
+            }
+        }
+
+        Ok(MARFPruneReport {
+            retained_tips: retained_tips,
+            num_kept: keep.len() as u64,
+            pruned: pruned,
+            pruned_bytes: pruned_bytes
+        })
+    }
+
+    /// Remove the tries that find_prunable_blocks() says are unreachable from every retained
+    /// fork tip.  This does not shrink the file; call compact() for that.
+    ///
+    /// Other handles on this MARF notice the deletion and drop what they cached, but nothing
+    /// stops a node from extending a trie that is about to be pruned.  Only prune a MARF that no
+    /// running node has open.
+    pub fn prune_forks(&mut self, canonical_tip: &BlockHeaderHash, retention_depth: u32) -> Result<MARFPruneReport, Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+        if self.open_chain_tip.is_some() {
+            error!("MARF at {} is in the process of writing", &self.storage.dir_path);
+            return Err(Error::InProgressError);
+        }
+
+        let report = self.find_prunable_blocks(canonical_tip, retention_depth)?;
+        if report.pruned.len() > 0 {
+            debug!("Prune {} trie(s) ({} bytes) from {}", report.pruned.len(), report.pruned_bytes, &self.storage.dir_path);
+            self.storage.delete_blocks(&report.pruned)?;
+        }
+        Ok(report)
+    }
+
+    /// Check every trie's node hashes and back-pointers, and that the blocks in expected_roots
+    /// have tries with the given root hashes.  Damage is reported, not repaired.
+    pub fn check_integrity(&mut self, expected_roots: &[(BlockHeaderHash, TrieHash)]) -> Result<MARFCheckReport, Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+        if self.open_chain_tip.is_some() {
+            error!("MARF at {} is in the process of writing", &self.storage.dir_path);
+            return Err(Error::InProgressError);
+        }
+        check_tries(&mut self.storage, expected_roots)
+    }
+
+    /// Move the damaged tries found by check_integrity(), and all of their descendants, out of
+    /// the MARF and into its quarantine table.  Returns the quarantined blocks.
+    pub fn quarantine_damaged(&mut self, report: &MARFCheckReport) -> Result<Vec<BlockHeaderHash>, Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+        if self.open_chain_tip.is_some() {
+            error!("MARF at {} is in the process of writing", &self.storage.dir_path);
+            return Err(Error::InProgressError);
+        }
+
+        let quarantined = quarantine_damaged_tries(&mut self.storage, report)?;
+        if quarantined.len() > 0 {
+            warn!("Quarantined {} trie(s) in {}", quarantined.len(), &self.storage.dir_path);
+        }
+        Ok(quarantined)
+    }
+
+    /// Rebuild the MARF's database to reclaim the space of pruned or dropped tries
+    pub fn compact(&mut self) -> Result<(), Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+        self.storage.compact()
+    }
+
     pub fn get_block_height_of(&mut self, bhh: &BlockHeaderHash, current_block_hash: &BlockHeaderHash) -> Result<Option<u32>, Error> {
         if Some(bhh) == self.get_open_chain_tip() {
             return Ok(self.get_open_chain_tip_height())
@@ -922,11 +1169,13 @@ impl MARF {
 
     /// Get the current root trie hash
     pub fn get_root_hash(&mut self) -> Result<TrieHash, Error> {
+        self.seal()?;
         read_root_hash(&mut self.storage)
     }
     
     /// Get the root trie hash at a particular block
     pub fn get_root_hash_at(&mut self, block_hash: &BlockHeaderHash) -> Result<TrieHash, Error> {
+        self.seal()?;
         let cur_block_hash = self.storage.get_cur_block();
 
         self.storage.open_block(block_hash)?;
@@ -948,6 +1197,7 @@ mod test {
         Cursor
     };
     use std::fs;
+    use std::thread;
 
     use chainstate::stacks::index::test::*;
     
@@ -961,6 +1211,8 @@ mod test {
     use util::get_epoch_time_ms;
     use util::hash::to_hex;
 
+    use rusqlite::Connection;
+
     #[test]
     fn marf_insert_different_leaf_same_block_100() {
         let filename = "/tmp/rust_marf_insert_different_leaf_same_block_100";
@@ -2235,5 +2487,568 @@ mod test {
         let read_value_1 = MARF::get_path(marf.borrow_storage_backend(), &block_header_2, &triepath_1).unwrap_err();
         if let Error::NotFoundError = read_value_1 {} else { assert!(false); }
     }
-}
 
+    #[test]
+    fn test_marf_node_cache() {
+        let f = TrieFileStorage::new_memory().unwrap();
+        let mut marf = MARF::from_storage(f);
+
+        let mut last_block = TrieFileStorage::block_sentinel();
+        for i in 0..10u32 {
+            let block = BlockHeaderHash([(i + 1) as u8; 32]);
+            marf.begin(&last_block, &block).unwrap();
+            for j in 0..20u32 {
+                marf.insert(&format!("{}-{}", i, j), MARFValue::from(i * 100 + j)).unwrap();
+            }
+            marf.commit().unwrap();
+            last_block = block;
+        }
+
+        // warm the cache, then read everything again from it
+        for _ in 0..2 {
+            for i in 0..10u32 {
+                for j in 0..20u32 {
+                    assert_eq!(marf.get(&last_block, &format!("{}-{}", i, j)).unwrap(), Some(MARFValue::from(i * 100 + j)));
+                }
+            }
+        }
+        let (hits, misses) = marf.borrow_storage_backend().node_cache_stats();
+        assert!(hits > misses);
+
+        // dropping an in-progress trie leaves nothing stale behind
+        let next_block = BlockHeaderHash([0xf0; 32]);
+        marf.begin(&last_block, &next_block).unwrap();
+        marf.insert("9-0", MARFValue::from(1234u32)).unwrap();
+        marf.drop_current();
+
+        marf.begin(&last_block, &next_block).unwrap();
+        marf.insert("9-0", MARFValue::from(5678u32)).unwrap();
+        marf.commit().unwrap();
+        assert_eq!(marf.get(&next_block, "9-0").unwrap(), Some(MARFValue::from(5678u32)));
+        assert_eq!(marf.get(&last_block, "9-0").unwrap(), Some(MARFValue::from(900u32)));
+
+        // dropping a block drops its cached nodes too, and the next trie written gets a new
+        // block id (ids are never reused), so none of the dropped trie's nodes come back
+        marf.drop_blocks(&[next_block.clone()]).unwrap();
+        let other_block = BlockHeaderHash([0xf1; 32]);
+        marf.begin(&last_block, &other_block).unwrap();
+        marf.insert("9-0", MARFValue::from(4321u32)).unwrap();
+        marf.commit().unwrap();
+        assert_eq!(marf.get(&other_block, "9-0").unwrap(), Some(MARFValue::from(4321u32)));
+        assert_eq!(marf.get(&other_block, "9-1").unwrap(), Some(MARFValue::from(901u32)));
+
+        // a disabled cache reads the same data
+        marf.borrow_storage_backend().set_node_cache_capacity(0);
+        for i in 0..9u32 {
+            for j in 0..20u32 {
+                assert_eq!(marf.get(&other_block, &format!("{}-{}", i, j)).unwrap(), Some(MARFValue::from(i * 100 + j)));
+            }
+        }
+        let (hits, _) = marf.borrow_storage_backend().node_cache_stats();
+        assert_eq!(hits, 0);
+    }
+
+    #[test]
+    fn test_marf_node_cache_shared_storage() {
+        let path = "/tmp/test_marf_node_cache_shared_storage.sqlite".to_string();
+        if fs::metadata(&path).is_ok() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let block = |fork: u8, height: u8| BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]);
+        let build = |marf: &mut MARF, parent: &BlockHeaderHash, fork: u8, height: u8| {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+            marf.insert("tip", MARFValue::from_value(&format!("{}-{}", fork, height))).unwrap();
+            marf.insert(&format!("key-{}", height), MARFValue::from_value(&format!("value-{}-{}", fork, height))).unwrap();
+            marf.commit().unwrap();
+            bhh
+        };
+
+        let mut writer = MARF::from_path(&path, None).unwrap();
+        let mut reader = MARF::from_path(&path, None).unwrap();
+
+        let mut parent = TrieFileStorage::block_sentinel();
+        for h in 0..5 {
+            parent = build(&mut writer, &parent, 0xa, h);
+        }
+
+        // the reader reads from disk, and then from its cache
+        reader.borrow_storage_backend().node_cache_stats();
+        assert_eq!(reader.get(&block(0xa, 2), "key-0").unwrap(), Some(MARFValue::from_value("value-10-0")));
+        let (cold_hits, cold_misses) = reader.borrow_storage_backend().node_cache_stats();
+        assert!(cold_misses > 0);
+
+        assert_eq!(reader.get(&block(0xa, 2), "key-0").unwrap(), Some(MARFValue::from_value("value-10-0")));
+        let (warm_hits, warm_misses) = reader.borrow_storage_backend().node_cache_stats();
+        assert!(warm_hits > cold_hits);
+        assert!(warm_misses < cold_misses);
+
+        // a read-only view of the reader starts out with its cache
+        let mut ro_reader = reader.reopen_readonly().unwrap();
+        assert_eq!(ro_reader.get(&block(0xa, 2), "key-0").unwrap(), Some(MARFValue::from_value("value-10-0")));
+        let (ro_hits, ro_misses) = ro_reader.borrow_storage_backend().node_cache_stats();
+        assert!(ro_hits > 0);
+        assert_eq!(ro_misses, 0);
+
+        // the writer replaces the last two blocks
+        writer.drop_blocks(&[block(0xa, 3), block(0xa, 4)]).unwrap();
+        let mut parent = block(0xa, 2);
+        for h in 3..5 {
+            parent = build(&mut writer, &parent, 0xb, h);
+        }
+
+        // the reader notices, and drops everything it had cached
+        assert_eq!(reader.get(&block(0xa, 2), "key-0").unwrap(), Some(MARFValue::from_value("value-10-0")));
+        assert_eq!(reader.borrow_storage_backend().node_cache_stats(), (cold_hits, cold_misses));
+
+        // so it sees the new tries, and not the old ones
+        assert_eq!(reader.get(&block(0xb, 4), "tip").unwrap(), Some(MARFValue::from_value("11-4")));
+        for h in 0..5 {
+            let fork = if h < 3 { 0xa } else { 0xb };
+            assert_eq!(reader.get(&block(0xb, 4), &format!("key-{}", h)).unwrap(),
+                       Some(MARFValue::from_value(&format!("value-{}-{}", fork, h))));
+        }
+        assert!(reader.get(&block(0xa, 4), "tip").is_err());
+    }
+
+    #[test]
+    fn test_marf_migrate_block_ids_to_autoincrement() {
+        let path = "/tmp/test_marf_migrate_block_ids_to_autoincrement.sqlite".to_string();
+        if fs::metadata(&path).is_ok() {
+            fs::remove_file(&path).unwrap();
+        }
+
+        let block = |fork: u8, height: u8| BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]);
+        let build = |marf: &mut MARF, parent: &BlockHeaderHash, fork: u8, height: u8| {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+            marf.insert("tip", MARFValue::from_value(&format!("{}-{}", fork, height))).unwrap();
+            marf.insert(&format!("key-{}", height), MARFValue::from_value(&format!("value-{}-{}", fork, height))).unwrap();
+            marf.commit().unwrap();
+            bhh
+        };
+
+        {
+            let mut marf = MARF::from_path(&path, None).unwrap();
+            let mut parent = TrieFileStorage::block_sentinel();
+            for h in 0..3 {
+                parent = build(&mut marf, &parent, 0xa, h);
+            }
+        }
+
+        // put marf_data back the way it was before block_id was AUTOINCREMENT
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("
+                CREATE TABLE marf_data_old (block_id INTEGER PRIMARY KEY, block_hash TEXT UNIQUE NOT NULL, data BLOB NOT NULL);
+                INSERT INTO marf_data_old SELECT block_id, block_hash, data FROM marf_data;
+                DROP TABLE marf_data;
+                ALTER TABLE marf_data_old RENAME TO marf_data;
+                CREATE INDEX block_hash_marf_data ON marf_data(block_hash);").unwrap();
+        }
+
+        let mut marf = MARF::from_path(&path, None).unwrap();
+
+        // the tries are all still there, under the same identifiers
+        let dropped_id = marf.borrow_storage_backend().get_block_identifier(&block(0xa, 2)).unwrap();
+        for h in 0..3 {
+            assert_eq!(marf.get(&block(0xa, 2), &format!("key-{}", h)).unwrap(),
+                       Some(MARFValue::from_value(&format!("value-10-{}", h))));
+        }
+
+        // and the identifier of a dropped trie is not handed out again
+        marf.drop_blocks(&[block(0xa, 2)]).unwrap();
+        let bhh = build(&mut marf, &block(0xa, 1), 0xb, 2);
+        let new_id = marf.borrow_storage_backend().get_block_identifier(&bhh).unwrap();
+        assert!(new_id > dropped_id);
+
+        assert_eq!(marf.get(&bhh, "tip").unwrap(), Some(MARFValue::from_value("11-2")));
+        assert_eq!(marf.get(&bhh, "key-0").unwrap(), Some(MARFValue::from_value("value-10-0")));
+    }
+    #[test]
+    fn test_marf_prune_forks() {
+        let f = TrieFileStorage::new_memory().unwrap();
+        let mut marf = MARF::from_storage(f);
+
+        // canonical fork a0..a9, a fork b3..b4 off of a2 that fell behind, and a recent fork
+        // c8 off of a7
+        let block = |fork: u8, height: u8| BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]);
+        let key = |fork: u8, height: u8| format!("{}-{}", fork, height);
+        let value = |fork: u8, height: u8| format!("value-{}-{}", fork, height);
+
+        let build = |marf: &mut MARF, parent: &BlockHeaderHash, fork: u8, height: u8| {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+            marf.insert(&key(fork, height), MARFValue::from_value(&value(fork, height))).unwrap();
+            marf.commit().unwrap();
+            bhh
+        };
+
+        let mut parent = TrieFileStorage::block_sentinel();
+        for h in 0..10 {
+            parent = build(&mut marf, &parent, 0xa, h);
+        }
+        let canonical_tip = parent;
+
+        let mut parent = block(0xa, 2);
+        for h in 3..5 {
+            parent = build(&mut marf, &parent, 0xb, h);
+        }
+        build(&mut marf, &block(0xa, 7), 0xc, 8);
+
+        // nothing to prune if every fork is recent enough
+        let report = marf.find_prunable_blocks(&canonical_tip, 100).unwrap();
+        assert_eq!(report.pruned.len(), 0);
+        assert_eq!(report.num_kept, 13);
+
+        let err = marf.find_prunable_blocks(&block(0xd, 0), 3).unwrap_err();
+        if let Error::NotFoundError = err {} else { assert!(false); }
+
+        // depth 3 keeps forks whose tips are at height 6 or more
+        let report = marf.prune_forks(&canonical_tip, 3).unwrap();
+        assert_eq!(report.retained_tips, vec![canonical_tip.clone(), block(0xc, 8)]);
+        assert_eq!(report.num_kept, 11);
+        assert_eq!(report.pruned, vec![block(0xb, 3), block(0xb, 4)]);
+        assert!(report.pruned_bytes > 0);
+
+        marf.compact().unwrap();
+
+        // kept forks read and prove as before
+        let mut root_table_cache = None;
+        for h in 0..10 {
+            assert_eq!(marf.get(&canonical_tip, &key(0xa, h)).unwrap(), Some(MARFValue::from_value(&value(0xa, h))));
+            root_table_cache = Some(merkle_test_marf_key_value(marf.borrow_storage_backend(), &canonical_tip, &key(0xa, h), &value(0xa, h), root_table_cache));
+        }
+        for h in 0..8 {
+            assert_eq!(marf.get(&block(0xc, 8), &key(0xa, h)).unwrap(), Some(MARFValue::from_value(&value(0xa, h))));
+            root_table_cache = Some(merkle_test_marf_key_value(marf.borrow_storage_backend(), &block(0xc, 8), &key(0xa, h), &value(0xa, h), root_table_cache));
+        }
+        assert_eq!(marf.get(&block(0xc, 8), &key(0xc, 8)).unwrap(), Some(MARFValue::from_value(&value(0xc, 8))));
+        assert_eq!(marf.get(&block(0xc, 8), &key(0xa, 9)).unwrap(), None);
+
+        // pruned forks are gone
+        assert!(marf.get(&block(0xb, 4), &key(0xb, 4)).is_err());
+
+        // and the MARF can keep growing
+        build(&mut marf, &canonical_tip, 0xa, 10);
+        assert_eq!(marf.get(&block(0xa, 10), &key(0xa, 0)).unwrap(), Some(MARFValue::from_value(&value(0xa, 0))));
+
+        // pruning again finds nothing new
+        let report = marf.prune_forks(&block(0xa, 10), 3).unwrap();
+        assert_eq!(report.pruned.len(), 0);
+    }
+
+    #[test]
+    fn test_marf_flat_file_backend() {
+        let path = "/tmp/test_marf_flat_file_backend.sqlite".to_string();
+        for p in [path.clone(), TrieFileStorage::data_file_path(&path, 0), TrieFileStorage::data_file_path(&path, 1)].iter() {
+            if fs::metadata(p).is_ok() {
+                fs::remove_file(p).unwrap();
+            }
+        }
+
+        let block = |fork: u8, height: u8| BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]);
+        let key = |fork: u8, height: u8| format!("{}-{}", fork, height);
+        let value = |fork: u8, height: u8| format!("value-{}-{}", fork, height);
+
+        let build = |marf: &mut MARF, parent: &BlockHeaderHash, fork: u8, height: u8| {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+            marf.insert(&key(fork, height), MARFValue::from_value(&value(fork, height))).unwrap();
+            marf.commit().unwrap();
+            bhh
+        };
+
+        assert!(MARF::from_path_with_backend(":memory:", None, Some(TrieStorageBackend::FlatFile)).is_err());
+
+        // first half of the chain is stored as blobs
+        let mut parent = TrieFileStorage::block_sentinel();
+        {
+            let mut marf = MARF::from_path(&path, None).unwrap();
+            assert_eq!(marf.borrow_storage_backend().backend(), TrieStorageBackend::SqliteBlobs);
+            for h in 0..5 {
+                parent = build(&mut marf, &parent, 0xa, h);
+            }
+        }
+
+        // second half, and a fork off of a blob trie, go to the flat file
+        let mut marf = MARF::from_path_with_backend(&path, None, Some(TrieStorageBackend::FlatFile)).unwrap();
+        assert_eq!(marf.borrow_storage_backend().backend(), TrieStorageBackend::FlatFile);
+        for h in 5..10 {
+            parent = build(&mut marf, &parent, 0xa, h);
+        }
+        let tip = parent;
+
+        let mut parent = block(0xa, 2);
+        for h in 3..5 {
+            parent = build(&mut marf, &parent, 0xb, h);
+        }
+
+        let check_reads = |marf: &mut MARF| {
+            let mut root_table_cache = None;
+            for h in 0..10 {
+                assert_eq!(marf.get(&tip, &key(0xa, h)).unwrap(), Some(MARFValue::from_value(&value(0xa, h))));
+                root_table_cache = Some(merkle_test_marf_key_value(marf.borrow_storage_backend(), &tip, &key(0xa, h), &value(0xa, h), root_table_cache));
+            }
+            assert_eq!(marf.get(&tip, &key(0xb, 4)).unwrap(), None);
+        };
+
+        // reads work across both kinds of tries
+        check_reads(&mut marf);
+        assert_eq!(marf.get(&block(0xb, 4), &key(0xb, 4)).unwrap(), Some(MARFValue::from_value(&value(0xb, 4))));
+        assert_eq!(marf.get(&block(0xb, 4), &key(0xa, 1)).unwrap(), Some(MARFValue::from_value(&value(0xa, 1))));
+
+        // can't go back to blobs
+        assert!(MARF::from_path_with_backend(&path, None, Some(TrieStorageBackend::SqliteBlobs)).is_err());
+
+        // move the remaining blobs over
+        let (num_tries, num_bytes) = marf.borrow_storage_backend().migrate_to_flat_file().unwrap();
+        assert!(num_tries >= 5);
+        assert!(num_bytes > 0);
+        assert_eq!(marf.borrow_storage_backend().migrate_to_flat_file().unwrap(), (0, 0));
+        check_reads(&mut marf);
+
+        // reopening picks the flat file back up, read-only or not
+        let mut marf = MARF::from_path(&path, None).unwrap();
+        assert_eq!(marf.borrow_storage_backend().backend(), TrieStorageBackend::FlatFile);
+        check_reads(&mut marf);
+
+        let ro_storage = marf.borrow_storage_backend().reopen_readonly().unwrap();
+        let mut ro_marf = MARF::from_storage(ro_storage);
+        assert_eq!(ro_marf.get(&tip, &key(0xa, 7)).unwrap(), Some(MARFValue::from_value(&value(0xa, 7))));
+
+        // pruning and compaction rewrite the flat file
+        let report = marf.prune_forks(&tip, 3).unwrap();
+        assert_eq!(report.pruned, vec![block(0xb, 3), block(0xb, 4)]);
+
+        let size_before = fs::metadata(&TrieFileStorage::data_file_path(&path, 0)).unwrap().len();
+        marf.compact().unwrap();
+        assert_eq!(marf.borrow_storage_backend().get_data_file_path(), Some(TrieFileStorage::data_file_path(&path, 1)));
+        assert!(fs::metadata(&TrieFileStorage::data_file_path(&path, 0)).is_err());
+
+        let size_after = fs::metadata(&TrieFileStorage::data_file_path(&path, 1)).unwrap().len();
+        assert_eq!(size_before - size_after, report.pruned_bytes);
+
+        check_reads(&mut marf);
+        assert!(marf.get(&block(0xb, 4), &key(0xb, 4)).is_err());
+
+        // and the MARF can keep growing
+        build(&mut marf, &tip, 0xa, 10);
+        assert_eq!(marf.get(&block(0xa, 10), &key(0xa, 0)).unwrap(), Some(MARFValue::from_value(&value(0xa, 0))));
+    }
+
+    #[test]
+    fn test_marf_flat_file_shared_storage() {
+        let path = "/tmp/test_marf_flat_file_shared_storage.sqlite".to_string();
+        for p in [path.clone(), TrieFileStorage::data_file_path(&path, 0), TrieFileStorage::data_file_path(&path, 1)].iter() {
+            if fs::metadata(p).is_ok() {
+                fs::remove_file(p).unwrap();
+            }
+        }
+
+        let block = |fork: u8, height: u8| BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]);
+        let key = |fork: u8, height: u8| format!("{}-{}", fork, height);
+        let value = |fork: u8, height: u8| format!("value-{}-{}", fork, height);
+
+        let build = |marf: &mut MARF, parent: &BlockHeaderHash, fork: u8, height: u8| {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+            marf.insert(&key(fork, height), MARFValue::from_value(&value(fork, height))).unwrap();
+            marf.commit().unwrap();
+            bhh
+        };
+
+        // one handle writes the chain, and a fork that falls behind
+        let mut writer = MARF::from_path_with_backend(&path, None, Some(TrieStorageBackend::FlatFile)).unwrap();
+        let mut reader = MARF::from_path(&path, None).unwrap();
+
+        let mut parent = TrieFileStorage::block_sentinel();
+        for h in 0..10 {
+            parent = build(&mut writer, &parent, 0xa, h);
+        }
+        let tip = parent;
+
+        let mut parent = block(0xa, 2);
+        for h in 3..5 {
+            parent = build(&mut writer, &parent, 0xb, h);
+        }
+
+        // the other handle reads all of it
+        for h in 0..10 {
+            assert_eq!(reader.get(&tip, &key(0xa, h)).unwrap(), Some(MARFValue::from_value(&value(0xa, h))));
+        }
+        assert_eq!(reader.get(&block(0xb, 4), &key(0xb, 4)).unwrap(), Some(MARFValue::from_value(&value(0xb, 4))));
+        assert_eq!(reader.borrow_storage_backend().get_data_file_path(), Some(TrieFileStorage::data_file_path(&path, 0)));
+
+        // tries can't be deleted out from under a trie that's being built
+        writer.begin(&tip, &block(0xa, 10)).unwrap();
+        match writer.borrow_storage_backend().delete_blocks(&[block(0xb, 4)]) {
+            Err(Error::InProgressError) => {},
+            res => panic!("Deleted tries while building one: {:?}", &res)
+        }
+        writer.drop_current();
+
+        // prune the fork, and compact into the next data file
+        let report = writer.prune_forks(&tip, 3).unwrap();
+        assert_eq!(report.pruned, vec![block(0xb, 3), block(0xb, 4)]);
+        writer.compact().unwrap();
+        assert!(fs::metadata(&TrieFileStorage::data_file_path(&path, 0)).is_err());
+
+        // the other handle switches over to the new data file...
+        for h in 0..10 {
+            assert_eq!(reader.get(&tip, &key(0xa, h)).unwrap(), Some(MARFValue::from_value(&value(0xa, h))));
+        }
+        assert!(reader.get(&block(0xb, 4), &key(0xb, 4)).is_err());
+        assert_eq!(reader.borrow_storage_backend().get_data_file_path(), Some(TrieFileStorage::data_file_path(&path, 1)));
+
+        // ...and appends to it, not to the removed one
+        let new_tip = build(&mut reader, &tip, 0xa, 10);
+        assert_eq!(writer.get(&new_tip, &key(0xa, 10)).unwrap(), Some(MARFValue::from_value(&value(0xa, 10))));
+        assert_eq!(writer.get(&new_tip, &key(0xa, 0)).unwrap(), Some(MARFValue::from_value(&value(0xa, 0))));
+    }
+
+    #[test]
+    fn test_marf_flat_file_concurrent_writers() {
+        let path = "/tmp/test_marf_flat_file_concurrent_writers.sqlite".to_string();
+        for p in [path.clone(), TrieFileStorage::data_file_path(&path, 0)].iter() {
+            if fs::metadata(p).is_ok() {
+                fs::remove_file(p).unwrap();
+            }
+        }
+
+        fn block(fork: u8, height: u8) -> BlockHeaderHash {
+            BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0])
+        }
+        fn key(fork: u8, height: u8) -> String {
+            format!("{}-{}", fork, height)
+        }
+        fn value(fork: u8, height: u8) -> String {
+            format!("value-{}-{}", fork, height)
+        }
+
+        {
+            let mut marf = MARF::from_path_with_backend(&path, None, Some(TrieStorageBackend::FlatFile)).unwrap();
+            marf.begin(&TrieFileStorage::block_sentinel(), &block(0, 0)).unwrap();
+            marf.insert(&key(0, 0), MARFValue::from_value(&value(0, 0))).unwrap();
+            marf.commit().unwrap();
+        }
+
+        // two handles append forks to the same data file at the same time
+        let writers : Vec<_> = [0xa, 0xb].iter().map(|fork| {
+            let path = path.clone();
+            let fork = *fork;
+            thread::spawn(move || {
+                let mut marf = MARF::from_path(&path, None).unwrap();
+                let mut parent = block(0, 0);
+                for h in 1..20 {
+                    marf.begin(&parent, &block(fork, h)).unwrap();
+                    marf.insert(&key(fork, h), MARFValue::from_value(&value(fork, h))).unwrap();
+                    marf.commit().unwrap();
+                    parent = block(fork, h);
+                }
+            })
+        }).collect();
+
+        for writer in writers.into_iter() {
+            writer.join().unwrap();
+        }
+
+        // no trie overwrote another
+        let mut marf = MARF::from_path(&path, None).unwrap();
+        for fork in [0xa, 0xb].iter() {
+            for h in 1..20 {
+                assert_eq!(marf.get(&block(*fork, 19), &key(*fork, h)).unwrap(), Some(MARFValue::from_value(&value(*fork, h))));
+            }
+            assert_eq!(marf.get(&block(*fork, 19), &key(0, 0)).unwrap(), Some(MARFValue::from_value(&value(0, 0))));
+        }
+    }
+
+    #[test]
+    fn test_marf_deferred_hashes_match_immediate() {
+        let block = |fork: u8, height: u8| BlockHeaderHash([fork, height, 0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]);
+
+        // same keys every time; later blocks overwrite some of the earlier ones
+        let key = |height: u8, i: u32| {
+            let seed = format!("{}", i + (height as u32) * 50);
+            to_hex(TrieHash::from_data(seed.as_bytes()).as_bytes())
+        };
+        let value = |fork: u8, height: u8, i: u32| format!("value-{}-{}-{}", fork, height, i);
+
+        let build = |marf: &mut MARF, parent: &BlockHeaderHash, fork: u8, height: u8| -> (BlockHeaderHash, TrieHash, TrieHash) {
+            let bhh = block(fork, height);
+            marf.begin(parent, &bhh).unwrap();
+
+            // insert half the keys one at a time, check the root hash mid-block, and batch the rest
+            for i in 0..100 {
+                marf.insert(&key(height, i), MARFValue::from_value(&value(fork, height, i))).unwrap();
+            }
+            let mid_root_hash = marf.get_root_hash().unwrap();
+
+            let keys : Vec<String> = (100..200).map(|i| key(height, i)).collect();
+            let values = (100..200).map(|i| MARFValue::from_value(&value(fork, height, i))).collect();
+            marf.insert_batch(&keys, values).unwrap();
+
+            let root_hash = marf.get_root_hash().unwrap();
+            marf.commit().unwrap();
+            (bhh, mid_root_hash, root_hash)
+        };
+
+        let build_chain = |mode: TrieHashCalculationMode, num_threads: usize| -> (MARF, Vec<(BlockHeaderHash, TrieHash, TrieHash)>) {
+            let mut marf = MARF::from_storage(TrieFileStorage::new_memory().unwrap());
+            marf.set_hash_calculation_mode(mode);
+            marf.borrow_storage_backend().set_hash_threads(num_threads);
+
+            let mut blocks = vec![];
+            let mut parent = TrieFileStorage::block_sentinel();
+            for h in 0..8 {
+                let b = build(&mut marf, &parent, 0xa, h);
+                parent = b.0.clone();
+                blocks.push(b);
+            }
+
+            // a fork, so some tries have backptrs into a sibling's ancestors
+            let mut parent = block(0xa, 3);
+            for h in 4..6 {
+                let b = build(&mut marf, &parent, 0xb, h);
+                parent = b.0.clone();
+                blocks.push(b);
+            }
+            (marf, blocks)
+        };
+
+        let (_, expected) = build_chain(TrieHashCalculationMode::Immediate, 1);
+        for num_threads in [1, 2, 4, 7].iter() {
+            let (mut marf, blocks) = build_chain(TrieHashCalculationMode::Deferred, *num_threads);
+            assert_eq!(blocks, expected);
+
+            for (bhh, _, root_hash) in expected.iter() {
+                assert_eq!(marf.get_root_hash_at(bhh).unwrap(), *root_hash);
+            }
+
+            // proofs verify against the deferred MARF
+            let tip = block(0xa, 7);
+            let mut root_table_cache = None;
+            for h in 0..8 {
+                let i = (h as u32) * 20 + 150;
+                assert_eq!(marf.get(&tip, &key(h, i)).unwrap(), Some(MARFValue::from_value(&value(0xa, h, i))));
+                root_table_cache = Some(merkle_test_marf_key_value(marf.borrow_storage_backend(), &tip, &key(h, i), &value(0xa, h, i), root_table_cache));
+            }
+        }
+
+        // storage won't write out a trie whose hashes were never computed
+        let mut marf = MARF::from_storage(TrieFileStorage::new_memory().unwrap());
+        marf.set_hash_calculation_mode(TrieHashCalculationMode::Deferred);
+        marf.begin(&TrieFileStorage::block_sentinel(), &block(0xc, 0)).unwrap();
+        marf.insert(&key(0, 0), MARFValue::from_value(&value(0xc, 0, 0))).unwrap();
+        assert!(marf.borrow_storage_backend().has_pending_hashes());
+        match marf.borrow_storage_backend().flush() {
+            Err(Error::CorruptionError(_)) => {},
+            x => panic!("Expected a corruption error, got {:?}", x)
+        }
+
+        // but the MARF computes them first
+        marf.commit().unwrap();
+        assert!(!marf.borrow_storage_backend().has_pending_hashes());
+    }
+}
@@ -18,6 +18,8 @@
 */
 
 pub mod bits;
+pub mod cache;
+pub mod fsck;
 pub mod marf;
 pub mod node;
 pub mod proofs;
@@ -93,7 +93,7 @@ use chainstate::stacks::index::trie::{
 
 use chainstate::stacks::index::Error as Error;
 
-use net::{StacksMessageCodec, codec::read_next};
+use net::{StacksMessageCodec, codec::read_next, codec::read_next_at_most};
 use util::{log, hash::to_hex};
 
 #[derive(Debug, Clone, PartialEq)]
@@ -1345,6 +1345,363 @@ impl TrieMerkleProof {
     }
 }
 
+/// An entry in a batched Merkle proof, shared by every key whose proof uses it.  Unlike a segment
+/// proof node, a batched proof node carries the hashes of _all_ of its non-empty children (in
+/// ptr order), so keys whose paths descend through different children can use the same node.
+/// A child hash that no key needs -- because every key through this node descends into it --
+/// is left zeroed; the verifier always calculates it anyway.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrieMerkleBatchProofEntry {
+    Node((ProofTrieNode, Vec<TrieHash>)),
+    Leaf(TrieLeaf),
+    Shunt((i64, Vec<TrieHash>))
+}
+
+define_u8_enum!( TrieMerkleBatchProofEntryIndicator {
+    Node = 0, Leaf = 1, Shunt = 2
+});
+
+/// A step in one key's proof within a batched Merkle proof.  `entry` is the index of the shared
+/// entry, and `chr` is the chr of the child the key's path passes through (0 for shunt proofs).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrieMerkleBatchProofStep {
+    pub chr: u8,
+    pub entry: u32
+}
+
+/// A Merkle proof of inclusion for a set of keys against a single root.  Each key's proof is the
+/// same sequence of segment and shunt proofs a TrieMerkleProof would have, but trie nodes and
+/// shunt proofs that several keys have in common are only stored once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrieMerkleBatchProof {
+    pub entries: Vec<TrieMerkleBatchProofEntry>,
+    pub proofs: Vec<Vec<TrieMerkleBatchProofStep>>
+}
+
+impl StacksMessageCodec for TrieMerkleBatchProofEntry {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), ::net::Error> {
+        match self {
+            TrieMerkleBatchProofEntry::Node((node, child_hashes)) => {
+                (TrieMerkleBatchProofEntryIndicator::Node as u8).consensus_serialize(fd)?;
+                node.consensus_serialize(fd)?;
+                child_hashes.consensus_serialize(fd)
+            },
+            TrieMerkleBatchProofEntry::Leaf(leaf_node) => {
+                (TrieMerkleBatchProofEntryIndicator::Leaf as u8).consensus_serialize(fd)?;
+                leaf_node.consensus_serialize(fd)
+            },
+            TrieMerkleBatchProofEntry::Shunt((idx, hashes)) => {
+                (TrieMerkleBatchProofEntryIndicator::Shunt as u8).consensus_serialize(fd)?;
+                idx.consensus_serialize(fd)?;
+                hashes.consensus_serialize(fd)
+            }
+        }
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<TrieMerkleBatchProofEntry, ::net::Error> {
+        let type_byte = TrieMerkleBatchProofEntryIndicator::from_u8(read_next(fd)?)
+            .ok_or_else(|| ::net::Error::DeserializeError("Bad type byte in Trie Merkle batch proof".into()))?;
+
+        let entry = match type_byte {
+            TrieMerkleBatchProofEntryIndicator::Node => {
+                let node = read_next(fd)?;
+                let child_hashes = read_next_at_most(fd, 256)?;
+                TrieMerkleBatchProofEntry::Node((node, child_hashes))
+            },
+            TrieMerkleBatchProofEntryIndicator::Leaf => {
+                let leaf_node = read_next(fd)?;
+                TrieMerkleBatchProofEntry::Leaf(leaf_node)
+            },
+            TrieMerkleBatchProofEntryIndicator::Shunt => {
+                let idx = read_next(fd)?;
+                let hashes = read_next(fd)?;
+                TrieMerkleBatchProofEntry::Shunt((idx, hashes))
+            }
+        };
+
+        Ok(entry)
+    }
+}
+
+impl StacksMessageCodec for TrieMerkleBatchProofStep {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), ::net::Error> {
+        self.chr.consensus_serialize(fd)?;
+        self.entry.consensus_serialize(fd)
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<TrieMerkleBatchProofStep, ::net::Error> {
+        let chr = read_next(fd)?;
+        let entry = read_next(fd)?;
+
+        Ok(TrieMerkleBatchProofStep { chr, entry })
+    }
+}
+
+impl StacksMessageCodec for TrieMerkleBatchProof {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), ::net::Error> {
+        self.entries.consensus_serialize(fd)?;
+        self.proofs.consensus_serialize(fd)
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<TrieMerkleBatchProof, ::net::Error> {
+        let entries = read_next(fd)?;
+        let proofs = read_next(fd)?;
+
+        Ok(TrieMerkleBatchProof { entries, proofs })
+    }
+}
+
+impl TrieMerkleBatchProof {
+    pub fn to_hex(&self) -> String {
+        let mut marf_proof = vec![];
+        self.consensus_serialize(&mut marf_proof).expect("Write error on memory buffer");
+        to_hex(&marf_proof)
+    }
+
+    /// Split a segment proof node into the chr of the child the proof passes through, the node,
+    /// and the sibling hashes.  Returns None for leaves and shunt proofs.
+    fn segment_proof_node_parts(proof_node: &TrieMerkleProofType) -> Option<(u8, &ProofTrieNode, &[TrieHash])> {
+        match *proof_node {
+            TrieMerkleProofType::Node4((ref chr, ref node, ref hashes)) => Some((*chr, node, &hashes[..])),
+            TrieMerkleProofType::Node16((ref chr, ref node, ref hashes)) => Some((*chr, node, &hashes[..])),
+            TrieMerkleProofType::Node48((ref chr, ref node, ref hashes)) => Some((*chr, node, &hashes[..])),
+            TrieMerkleProofType::Node256((ref chr, ref node, ref hashes)) => Some((*chr, node, &hashes[..])),
+            _ => None
+        }
+    }
+
+    /// Given a segment proof node's sibling hashes, find out what we know about the hashes of each
+    /// of the node's non-empty children.  The hash of the child the proof passes through is not
+    /// known.  Mirrors TrieMerkleProof::make_proof_hashes().
+    fn known_child_hashes(node: &ProofTrieNode, chr: u8, hashes: &[TrieHash]) -> Option<Vec<Option<TrieHash>>> {
+        let mut child_hashes = vec![];
+        let mut ih = 0;
+        for ptr in node.ptrs() {
+            if ptr.id == TrieNodeID::Empty as u8 {
+                ih += 1;
+            }
+            else if ptr.chr == chr {
+                child_hashes.push(None);
+            }
+            else {
+                child_hashes.push(Some(hashes.get(ih)?.clone()));
+                ih += 1;
+            }
+        }
+        if ih != hashes.len() {
+            return None;
+        }
+        Some(child_hashes)
+    }
+
+    /// Merge what another proof knows about a node's child hashes into what we already know.
+    /// Fails (and changes nothing) if the two disagree, in which case they aren't the same node.
+    fn merge_child_hashes(known: &mut Vec<Option<TrieHash>>, other: &Vec<Option<TrieHash>>) -> bool {
+        if known.len() != other.len() {
+            return false;
+        }
+        for (known_hash, other_hash) in known.iter().zip(other.iter()) {
+            if let (&Some(ref h1), &Some(ref h2)) = (known_hash, other_hash) {
+                if h1 != h2 {
+                    return false;
+                }
+            }
+        }
+        for (known_hash, other_hash) in known.iter_mut().zip(other.iter()) {
+            if known_hash.is_none() {
+                *known_hash = other_hash.clone();
+            }
+        }
+        true
+    }
+
+    /// Combine independent proofs (all against the same root) into a single batched proof.
+    /// Identical leaves and shunt proofs are stored once, and so are trie nodes, whose child
+    /// hashes are pooled from every proof that passes through them.
+    pub fn from_proofs(proofs: &[TrieMerkleProof]) -> Result<TrieMerkleBatchProof, Error> {
+        let mut entries = vec![];
+        let mut node_child_hashes : HashMap<usize, Vec<Option<TrieHash>>> = HashMap::new();
+        let mut entry_index : HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+        let mut batch_proofs = Vec::with_capacity(proofs.len());
+
+        for proof in proofs.iter() {
+            let mut steps = Vec::with_capacity(proof.len());
+            for proof_node in proof.iter() {
+                let (chr, entry_idx) = match TrieMerkleBatchProof::segment_proof_node_parts(proof_node) {
+                    Some((chr, node, hashes)) => {
+                        let child_hashes = TrieMerkleBatchProof::known_child_hashes(node, chr, hashes)
+                            .ok_or_else(|| Error::CorruptionError(format!("Malformed segment proof node {:?}", proof_node)))?;
+
+                        let mut index_key = vec![TrieMerkleBatchProofEntryIndicator::Node as u8];
+                        node.consensus_serialize(&mut index_key).expect("Write error on memory buffer");
+
+                        let candidates = entry_index.entry(index_key).or_insert(vec![]);
+                        let mut found = None;
+                        for candidate in candidates.iter() {
+                            let known = node_child_hashes.get_mut(candidate).expect("BUG: no child hashes for batched proof node");
+                            if TrieMerkleBatchProof::merge_child_hashes(known, &child_hashes) {
+                                found = Some(*candidate);
+                                break;
+                            }
+                        }
+
+                        let entry_idx = match found {
+                            Some(entry_idx) => entry_idx,
+                            None => {
+                                let entry_idx = entries.len();
+                                entries.push(TrieMerkleBatchProofEntry::Node((node.clone(), vec![])));
+                                node_child_hashes.insert(entry_idx, child_hashes);
+                                candidates.push(entry_idx);
+                                entry_idx
+                            }
+                        };
+                        (chr, entry_idx)
+                    },
+                    None => {
+                        let (chr, entry) = match *proof_node {
+                            TrieMerkleProofType::Leaf((ref chr, ref leaf)) => (*chr, TrieMerkleBatchProofEntry::Leaf(leaf.clone())),
+                            TrieMerkleProofType::Shunt((ref idx, ref hashes)) => (0, TrieMerkleBatchProofEntry::Shunt((*idx, hashes.clone()))),
+                            _ => unreachable!()
+                        };
+
+                        let mut index_key = vec![];
+                        entry.consensus_serialize(&mut index_key).expect("Write error on memory buffer");
+
+                        let candidates = entry_index.entry(index_key).or_insert(vec![]);
+                        let entry_idx = match candidates.first() {
+                            Some(entry_idx) => *entry_idx,
+                            None => {
+                                let entry_idx = entries.len();
+                                entries.push(entry);
+                                candidates.push(entry_idx);
+                                entry_idx
+                            }
+                        };
+                        (chr, entry_idx)
+                    }
+                };
+                steps.push(TrieMerkleBatchProofStep { chr, entry: entry_idx as u32 });
+            }
+            batch_proofs.push(steps);
+        }
+
+        for (entry_idx, child_hashes) in node_child_hashes.into_iter() {
+            if let TrieMerkleBatchProofEntry::Node((_, ref mut hashes)) = entries[entry_idx] {
+                *hashes = child_hashes.into_iter()
+                    .map(|hash_opt| hash_opt.unwrap_or(TrieHash([0; 32])))
+                    .collect();
+            }
+        }
+
+        Ok(TrieMerkleBatchProof { entries, proofs: batch_proofs })
+    }
+
+    /// Make a batched merkle proof of inclusion for a list of paths and their values.
+    /// If any path doesn't resolve, return an error (NotFoundError)
+    pub fn from_paths(storage: &mut TrieFileStorage, paths: &[(TriePath, MARFValue)], root_block_header: &BlockHeaderHash) -> Result<TrieMerkleBatchProof, Error> {
+        let mut proofs = Vec::with_capacity(paths.len());
+        for (path, expected_value) in paths.iter() {
+            let proof = TrieMerkleProof::from_path(storage, path, expected_value, root_block_header)?;
+            proofs.push(proof);
+        }
+        TrieMerkleBatchProof::from_proofs(&proofs)
+    }
+
+    /// Make a batched merkle proof of inclusion for a list of keys and their MARF values.
+    /// If any key doesn't resolve, return an error (NotFoundError)
+    pub fn from_raw_entries(storage: &mut TrieFileStorage, entries: &[(String, MARFValue)], root_block_header: &BlockHeaderHash) -> Result<TrieMerkleBatchProof, Error> {
+        let paths : Vec<_> = entries.iter()
+            .map(|(key, value)| (TriePath::from_key(key), value.clone()))
+            .collect();
+        TrieMerkleBatchProof::from_paths(storage, &paths, root_block_header)
+    }
+
+    /// Rebuild a segment proof node, leaf, or shunt proof from a step in a key's proof.
+    fn expand_step(&self, step: &TrieMerkleBatchProofStep) -> Option<TrieMerkleProofType> {
+        match *self.entries.get(step.entry as usize)? {
+            TrieMerkleBatchProofEntry::Leaf(ref leaf) => Some(TrieMerkleProofType::Leaf((step.chr, leaf.clone()))),
+            TrieMerkleBatchProofEntry::Shunt((ref idx, ref hashes)) => Some(TrieMerkleProofType::Shunt((*idx, hashes.clone()))),
+            TrieMerkleBatchProofEntry::Node((ref node, ref child_hashes)) => {
+                let mut hashes = vec![];
+                let mut ic = 0;
+                for ptr in node.ptrs() {
+                    if ptr.id == TrieNodeID::Empty as u8 {
+                        hashes.push(TrieHash::from_data(&[]));
+                    }
+                    else {
+                        let child_hash = child_hashes.get(ic)?;
+                        ic += 1;
+                        if ptr.chr != step.chr {
+                            hashes.push(child_hash.clone());
+                        }
+                    }
+                }
+                if ic != child_hashes.len() || hashes.len() + 1 != node.ptrs().len() {
+                    trace!("Malformed batched proof node {:?} for chr 0x{:02x}", node, step.chr);
+                    return None;
+                }
+
+                match node.ptrs().len() {
+                    4 => {
+                        let mut array = [TrieHash([0; 32]); 3];
+                        array.clone_from_slice(&hashes);
+                        Some(TrieMerkleProofType::Node4((step.chr, node.clone(), array)))
+                    },
+                    16 => {
+                        let mut array = [TrieHash([0; 32]); 15];
+                        array.clone_from_slice(&hashes);
+                        Some(TrieMerkleProofType::Node16((step.chr, node.clone(), array)))
+                    },
+                    48 => {
+                        let mut array = [TrieHash([0; 32]); 47];
+                        array.clone_from_slice(&hashes);
+                        Some(TrieMerkleProofType::Node48((step.chr, node.clone(), array)))
+                    },
+                    256 => {
+                        let mut array = [TrieHash([0; 32]); 255];
+                        array.clone_from_slice(&hashes);
+                        Some(TrieMerkleProofType::Node256((step.chr, node.clone(), array)))
+                    },
+                    _ => None
+                }
+            }
+        }
+    }
+
+    /// Rebuild the independent proof for the i-th key in this batch.
+    /// Returns None if there is no such key, or if the batch is malformed.
+    pub fn get_proof(&self, i: usize) -> Option<TrieMerkleProof> {
+        let steps = self.proofs.get(i)?;
+        let mut proof = Vec::with_capacity(steps.len());
+        for step in steps.iter() {
+            proof.push(self.expand_step(step)?);
+        }
+        Some(TrieMerkleProof(proof))
+    }
+
+    /// Verify this proof.  `entries` must list the proven paths and values in the same order
+    /// the batch was made with.
+    pub fn verify(&self, entries: &[(TriePath, MARFValue)], root_hash: &TrieHash, root_to_block: &HashMap<TrieHash, BlockHeaderHash>) -> bool {
+        if entries.len() != self.proofs.len() {
+            trace!("Batch proof has {} proofs, but {} entries were given", self.proofs.len(), entries.len());
+            return false;
+        }
+        for (i, (path, marf_value)) in entries.iter().enumerate() {
+            let proof = match self.get_proof(i) {
+                Some(proof) => proof,
+                None => {
+                    trace!("Malformed batch proof for {:?}", path);
+                    return false;
+                }
+            };
+            if !TrieMerkleProof::verify_proof(&proof.0, path, marf_value, root_hash, root_to_block) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1413,4 +1770,91 @@ mod test {
         let marf_value_1 = MARFValue::from_value(&old_v);
         assert!(proof_1.verify(&triepath_1, &marf_value_1, &root_hash_1, &root_to_block));
     }
+
+    #[test]
+    fn batch_proof_matches_independent_proofs() {
+        let mut m = MARF::from_path(":memory:", None).unwrap();
+
+        let mut parent = TrieFileStorage::block_sentinel();
+        let mut root_hash = TrieHash([0u8; 32]);
+        for i in 0..4 {
+            let block = BlockHeaderHash([i as u8; 32]);
+            m.begin(&parent, &block).unwrap();
+            for j in 0..8 {
+                m.insert(&format!("K{}-{}", i, j), MARFValue::from_value(&format!("V{}-{}", i, j))).unwrap();
+            }
+            let (_, block_root_hash) = Trie::read_root(m.borrow_storage_backend()).unwrap();
+            root_hash = block_root_hash;
+            m.commit().unwrap();
+            parent = block;
+        }
+
+        let tip = parent;
+        let root_to_block = m.borrow_storage_backend().read_root_to_block_table().unwrap();
+
+        let keys : Vec<String> = vec!["K0-0", "K0-1", "K1-3", "nonexistent", "K2-5", "K3-7", "K3-2"]
+            .into_iter()
+            .map(|k| k.to_string())
+            .collect();
+
+        let (marf_values, batch_proof) = m.get_many_with_proof(&tip, &keys).unwrap();
+        assert_eq!(marf_values.len(), keys.len());
+        assert!(marf_values[3].is_none());
+
+        let mut entries = vec![];
+        let mut independent_size = 0;
+        for (key, marf_value_opt) in keys.iter().zip(marf_values.iter()) {
+            let marf_value = match marf_value_opt {
+                Some(marf_value) => marf_value.clone(),
+                None => continue
+            };
+            let value = format!("V{}", &key[1..]);
+            assert_eq!(marf_value, MARFValue::from_value(&value));
+
+            let proof = TrieMerkleProof::from_entry(m.borrow_storage_backend(), key, &value, &tip).unwrap();
+            independent_size += proof.to_hex().len() / 2;
+
+            // each key's proof is rebuilt exactly
+            assert_eq!(batch_proof.get_proof(entries.len()).unwrap().0, proof.0);
+            entries.push((TriePath::from_key(key), marf_value));
+        }
+        assert_eq!(batch_proof.proofs.len(), entries.len());
+        assert!(batch_proof.get_proof(entries.len()).is_none());
+
+        // shared nodes are only stored once
+        let batch_size = batch_proof.to_hex().len() / 2;
+        test_debug!("batch proof is {} bytes; independent proofs are {} bytes", batch_size, independent_size);
+        assert!(batch_size < independent_size);
+
+        assert!(batch_proof.verify(&entries, &root_hash, &root_to_block));
+
+        // encoding round-trips
+        let mut bytes = vec![];
+        batch_proof.consensus_serialize(&mut bytes).unwrap();
+        let decoded = TrieMerkleBatchProof::consensus_deserialize(&mut &bytes[..]).unwrap();
+        assert_eq!(decoded, batch_proof);
+        assert!(decoded.verify(&entries, &root_hash, &root_to_block));
+
+        // wrong values, wrong order, and missing entries are all rejected
+        let mut bad_entries = entries.clone();
+        bad_entries[2].1 = MARFValue::from_value(&"nope".to_string());
+        assert!(!batch_proof.verify(&bad_entries, &root_hash, &root_to_block));
+
+        let mut bad_entries = entries.clone();
+        bad_entries.swap(0, 4);
+        assert!(!batch_proof.verify(&bad_entries, &root_hash, &root_to_block));
+
+        assert!(!batch_proof.verify(&entries[1..], &root_hash, &root_to_block));
+
+        // tampered-with shared nodes are caught
+        let mut bad_batch_proof = batch_proof.clone();
+        for entry in bad_batch_proof.entries.iter_mut() {
+            if let TrieMerkleBatchProofEntry::Node((_, ref mut child_hashes)) = *entry {
+                for child_hash in child_hashes.iter_mut() {
+                    *child_hash = TrieHash([0xff; 32]);
+                }
+            }
+        }
+        assert!(!bad_batch_proof.verify(&entries, &root_hash, &root_to_block));
+    }
 }
@@ -29,6 +29,7 @@ use std::io::{
     BufWriter,
 };
 
+use std::cmp;
 use std::char::from_digit;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
@@ -42,6 +43,7 @@ use std::path::{
 
 use std::os;
 use std::iter::FromIterator;
+use std::thread;
 
 use regex::Regex;
 
@@ -63,8 +65,16 @@ use chainstate::stacks::index::bits::{
     read_node_hash_bytes,
     read_nodetype,
     get_node_hash,
+    get_leaf_hash,
+    get_nodetype_hash_bytes,
 };
 
+use chainstate::stacks::index::cache::{
+    LruCache,
+    TrieNodeCache,
+    node_cache_capacity,
+    MARF_TRIE_LOCATION_CACHE_CAPACITY
+};
 use chainstate::stacks::index::node::{
     is_backptr,
     clear_backptr,
@@ -82,12 +92,14 @@ use chainstate::stacks::index::node::{
 };
 
 use rusqlite::{
-    Connection, OptionalExtension,
+    Connection, OptionalExtension, Transaction,
     types::{ FromSql,
              ToSql },
     NO_PARAMS,
     Error as SqliteError,
-    OpenFlags
+    OpenFlags,
+    DatabaseName,
+    blob::Blob
 };
 
 use std::convert::{
@@ -147,6 +159,19 @@ impl BlockMap for TrieSqlHashMapCursor<'_> {
     }
 }
 
+/// Block hashes looked up ahead of time, so tries can be hashed without the database
+struct TrieBlockHashTable<'a>(&'a HashMap<u32, BlockHeaderHash>);
+
+impl BlockMap for TrieBlockHashTable<'_> {
+    fn get_block_hash(&self, id: u32) -> Result<BlockHeaderHash, Error> {
+        self.0.get(&id).cloned().ok_or(Error::NotFoundError)
+    }
+
+    fn get_block_hash_caching(&mut self, id: u32) -> Result<&BlockHeaderHash, Error> {
+        self.0.get(&id).ok_or(Error::NotFoundError)
+    }
+}
+
 enum FlushOptions<'a> {
     CurrentHeader,
     NewHeader(&'a BlockHeaderHash),
@@ -181,6 +206,9 @@ pub struct TrieRAM {
 
     total_bytes: usize,
 
+    // set when nodes were written without their hashes (see TrieHashCalculationMode::Deferred)
+    hashes_pending: bool,
+
     parent: BlockHeaderHash
 }
 
@@ -203,6 +231,8 @@ impl TrieRAM {
 
             total_bytes: 0,
 
+            hashes_pending: false,
+
             parent: parent.clone(),
         }
     }
@@ -433,6 +463,131 @@ impl TrieRAM {
     pub fn last_ptr(&mut self) -> Result<u32, Error> {
         Ok(self.data.len() as u32)
     }
+
+    /// Find the identifiers of the blocks that the nodes reachable from the root back-point into
+    fn reachable_back_blocks(&self) -> Result<HashSet<u32>, Error> {
+        let mut back_blocks = HashSet::new();
+        let mut frontier = vec![0u32];
+        while let Some(ptr) = frontier.pop() {
+            let (node, _) = self.data.get(ptr as usize)
+                .ok_or_else(|| Error::CorruptionError(format!("TrieRAM: no node at {}", ptr)))?;
+            for child_ptr in node.ptrs().iter() {
+                if child_ptr.id() == TrieNodeID::Empty as u8 {
+                    continue;
+                }
+                if is_backptr(child_ptr.id()) {
+                    back_blocks.insert(child_ptr.back_block());
+                }
+                else {
+                    frontier.push(child_ptr.ptr());
+                }
+            }
+        }
+        Ok(back_blocks)
+    }
+
+    /// Hash an interior node from the hashes of its children.  A child in this trie gets its hash
+    /// from in_trie_hash; a back-pointed child contributes its block's hash.
+    fn hash_node<F>(node: &TrieNodeType, block_hashes: &HashMap<u32, BlockHeaderHash>, mut in_trie_hash: F) -> Result<TrieHash, Error>
+    where
+        F: FnMut(&TriePtr) -> Result<TrieHash, Error>
+    {
+        let mut children_hashes = Vec::with_capacity(node.ptrs().len());
+        for child_ptr in node.ptrs().iter() {
+            let child_hash =
+                if child_ptr.id() == TrieNodeID::Empty as u8 {
+                    TrieHash::from_data(&[])
+                }
+                else if is_backptr(child_ptr.id()) {
+                    let block_hash = block_hashes.get(&child_ptr.back_block())
+                        .ok_or_else(|| Error::CorruptionError(format!("TrieRAM: unknown back block {}", child_ptr.back_block())))?;
+                    TrieHash(block_hash.0)
+                }
+                else {
+                    in_trie_hash(child_ptr)?
+                };
+            children_hashes.push(child_hash);
+        }
+        Ok(get_nodetype_hash_bytes(node, &children_hashes, &mut TrieBlockHashTable(block_hashes)))
+    }
+
+    /// Hash the subtree at ptr, children first.  The hash of each node in the subtree is appended
+    /// to hashes, and the hash of the node at ptr is returned.
+    fn hash_subtree(data: &[(TrieNodeType, TrieHash)], ptr: u32, block_hashes: &HashMap<u32, BlockHeaderHash>,
+                    hashes: &mut Vec<(u32, TrieHash)>) -> Result<TrieHash, Error> {
+        let (node, _) = data.get(ptr as usize)
+            .ok_or_else(|| Error::CorruptionError(format!("TrieRAM: no node at {}", ptr)))?;
+
+        let hash = match *node {
+            TrieNodeType::Leaf(ref leaf) => get_leaf_hash(leaf),
+            _ => TrieRAM::hash_node(node, block_hashes,
+                                    |child_ptr| TrieRAM::hash_subtree(data, child_ptr.ptr(), block_hashes, hashes))?
+        };
+
+        hashes.push((ptr, hash.clone()));
+        Ok(hash)
+    }
+
+    /// Recompute the hash of every node reachable from the root.  The root's children head
+    /// disjoint subtrees, so they are divided among num_threads threads and hashed in parallel.
+    /// block_hashes must map every block this trie back-points into to its hash.
+    /// Returns the hash of the root node by itself -- the caller must still mix in the ancestor
+    /// tries' root hashes (see Trie::update_all_hashes()).
+    pub fn compute_node_hashes(&mut self, block_hashes: &HashMap<u32, BlockHeaderHash>, num_threads: usize) -> Result<TrieHash, Error> {
+        let root = match self.data.get(0) {
+            Some(&(ref node, _)) => node.clone(),
+            None => {
+                return Err(Error::CorruptionError("TrieRAM: no root node".to_string()));
+            }
+        };
+
+        let subtree_ptrs : Vec<u32> = root.ptrs()
+            .iter()
+            .filter(|child_ptr| child_ptr.id() != TrieNodeID::Empty as u8 && !is_backptr(child_ptr.id()))
+            .map(|child_ptr| child_ptr.ptr())
+            .collect();
+
+        let subtree_hashes =
+            if subtree_ptrs.len() > 0 {
+                let num_threads = cmp::max(1, cmp::min(num_threads, subtree_ptrs.len()));
+                let chunk_size = (subtree_ptrs.len() + num_threads - 1) / num_threads;
+                let data = &self.data;
+
+                thread::scope(|scope| {
+                    let workers : Vec<_> = subtree_ptrs.chunks(chunk_size)
+                        .map(|chunk| scope.spawn(move || -> Result<Vec<(u32, TrieHash)>, Error> {
+                            let mut hashes = vec![];
+                            for ptr in chunk.iter() {
+                                TrieRAM::hash_subtree(data, *ptr, block_hashes, &mut hashes)?;
+                            }
+                            Ok(hashes)
+                        }))
+                        .collect();
+
+                    workers.into_iter()
+                        .map(|worker| worker.join().expect("FATAL: trie hashing thread panicked"))
+                        .collect::<Result<Vec<Vec<(u32, TrieHash)>>, Error>>()
+                })?
+            }
+            else {
+                vec![]
+            };
+
+        for (ptr, hash) in subtree_hashes.into_iter().flatten() {
+            self.data[ptr as usize].1 = hash;
+        }
+
+        let data = &self.data;
+        let root_hash = TrieRAM::hash_node(&root, block_hashes, |child_ptr| {
+            data.get(child_ptr.ptr() as usize)
+                .map(|&(_, ref hash)| hash.clone())
+                .ok_or_else(|| Error::CorruptionError(format!("TrieRAM: no node at {}", child_ptr.ptr())))
+        })?;
+
+        self.data[0].1 = root_hash.clone();
+        self.hashes_pending = false;
+        Ok(root_hash)
+    }
 }
 
 impl NodeHashReader for TrieRAM {
@@ -447,19 +602,140 @@ impl NodeHashReader for TrieRAM {
     }
 }
 
-pub struct TrieSqlCursor <'a> {
-    db: &'a Connection,
-    block_id: u32
-}
-
 pub struct TrieSqlHashMapCursor <'a> {
     db: &'a Connection,
     cache: &'a mut HashMap<u32, BlockHeaderHash>
 }
 
-impl NodeHashReader for TrieSqlCursor<'_> {
+/// Where the bytes of flushed tries are kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrieStorageBackend {
+    /// Each trie is a blob in the marf_data table
+    SqliteBlobs,
+    /// Tries are appended to a data file next to the database, and the database only records
+    /// each trie's offset and length
+    FlatFile
+}
+
+/// When the nodes of the trie being built get their hashes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrieHashCalculationMode {
+    /// Each insert rehashes the nodes along its path, so the trie's hashes are always current
+    Immediate,
+    /// Inserts only link the nodes together.  The whole trie is hashed once, before its root hash
+    /// is read or it is flushed, with the root's subtrees hashed in parallel.
+    Deferred
+}
+
+/// How many bytes of the flat data file to read at a time
+const TRIE_READ_BUFFER_SIZE: u64 = 8192;
+
+/// One trie's bytes within the flat data file.  Offsets are relative to the start of the trie, so
+/// this reads just like a trie blob.  Reads go through a caller-owned buffer, so that reading a
+/// node doesn't allocate.
+struct TrieFileSlice<'a> {
+    file: &'a fs::File,
+    offset: u64,
+    length: u64,
+    pos: u64,
+    // holds the slice's bytes from buf_pos on
+    buf: &'a mut Vec<u8>,
+    buf_pos: u64
+}
+
+impl<'a> TrieFileSlice<'a> {
+    fn new(file: &'a fs::File, offset: u64, length: u64, buf: &'a mut Vec<u8>) -> TrieFileSlice<'a> {
+        buf.clear();
+        TrieFileSlice {
+            file: file,
+            offset: offset,
+            length: length,
+            pos: 0,
+            buf: buf,
+            buf_pos: 0
+        }
+    }
+}
+
+impl Read for TrieFileSlice<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.length {
+            return Ok(0);
+        }
+        if self.pos < self.buf_pos || self.pos >= self.buf_pos + (self.buf.len() as u64) {
+            let len = cmp::min(TRIE_READ_BUFFER_SIZE, self.length - self.pos) as usize;
+            self.buf.resize(len, 0);
+            self.file.seek(SeekFrom::Start(self.offset + self.pos))?;
+            let nr = self.file.read(&mut self.buf[..])?;
+            self.buf.truncate(nr);
+            self.buf_pos = self.pos;
+            if nr == 0 {
+                return Ok(0);
+            }
+        }
+
+        let start = (self.pos - self.buf_pos) as usize;
+        let len = cmp::min(out.len(), self.buf.len() - start);
+        out[0..len].copy_from_slice(&self.buf[start..(start + len)]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for TrieFileSlice<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(p) => (p, 0),
+            SeekFrom::End(d) => (self.length, d),
+            SeekFrom::Current(d) => (self.pos, d)
+        };
+        let new_pos =
+            if delta >= 0 {
+                base.checked_add(delta as u64)
+            }
+            else {
+                base.checked_sub(delta.wrapping_neg() as u64)
+            };
+
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+/// A flushed trie's bytes, wherever they are stored
+enum TrieBlob<'a> {
+    Sqlite(Blob<'a>),
+    FlatFile(TrieFileSlice<'a>)
+}
+
+impl Read for TrieBlob<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            TrieBlob::Sqlite(ref mut blob) => blob.read(buf),
+            TrieBlob::FlatFile(ref mut slice) => slice.read(buf)
+        }
+    }
+}
+
+impl Seek for TrieBlob<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            TrieBlob::Sqlite(ref mut blob) => blob.seek(pos),
+            TrieBlob::FlatFile(ref mut slice) => slice.seek(pos)
+        }
+    }
+}
+
+impl NodeHashReader for TrieBlob<'_> {
     fn read_node_hash_bytes<W: Write>(&mut self, ptr: &TriePtr, w: &mut W) -> Result<(), Error> {
-        trie_sql::read_node_hash_bytes(self.db, w, self.block_id, ptr)
+        let hash_buff = read_node_hash_bytes(self, ptr)?;
+        w.write_all(&hash_buff)?;
+        Ok(())
     }
 }
 
@@ -490,8 +766,32 @@ pub struct TrieFileStorage {
 
     block_hash_cache: HashMap<u32, BlockHeaderHash>,
 
+    // decoded nodes and node hashes from flushed tries.  Only cleared when blocks are deleted,
+    // here or through another handle on the same storage.
+    node_cache: TrieNodeCache,
+
+    // the storage epoch as of when the caches were last known to be good.  Any handle that
+    // deletes tries bumps the epoch in the database.  Re-reading it is only worthwhile once
+    // SQLite's data_version says another connection has committed something.
+    storage_epoch: u64,
+    data_version: Option<i64>,
+
+    // the flat data file, if the tries are stored in one instead of as SQLite blobs
+    data_file: Option<fs::File>,
+    data_file_generation: u64,
+
+    // (offset, length) of recently-read tries in the flat data file, or None if the trie is
+    // still a blob
+    trie_locations: LruCache<u32, Option<(u64, u64)>>,
+    // reused by every read from the flat data file
+    read_buffer: Vec<u8>,
+
     pub readonly: bool,
 
+    hash_calculation_mode: TrieHashCalculationMode,
+    // how many threads hash the trie being built in deferred mode
+    hash_threads: usize,
+
     // used in testing in order to short-circuit block-height lookups
     //   when the trie struct is tested outside of marf.rs usage
     #[cfg(test)]
@@ -499,14 +799,47 @@ pub struct TrieFileStorage {
 }
 
 impl TrieFileStorage {
+    /// Open the trie storage at dir_path, with whichever backend it already uses.  New storage
+    /// keeps its tries as SQLite blobs.
     pub fn new(dir_path: &str) -> Result<TrieFileStorage, Error> {
+        TrieFileStorage::new_with_backend(dir_path, None)
+    }
+
+    /// Open the trie storage at dir_path with the given backend (None means whichever one it
+    /// already uses).  Asking for the flat file on storage that uses SQLite blobs switches it over:
+    /// new tries go to the flat file, and existing ones stay put until migrate_to_flat_file() is
+    /// called.  Storage that uses the flat file cannot be switched back.
+    pub fn new_with_backend(dir_path: &str, backend: Option<TrieStorageBackend>) -> Result<TrieFileStorage, Error> {
         let mut db = Connection::open(dir_path)?;
         db.busy_handler(Some(tx_busy_handler))?;
 
-        let dir_path = dir_path.to_string();
-
         trie_sql::create_tables_if_needed(&mut db)?;
 
+        let data_file_generation = match (trie_sql::get_data_file_generation(&db)?, backend) {
+            (Some(_), Some(TrieStorageBackend::SqliteBlobs)) => {
+                error!("TrieFileStorage {} uses a flat data file, and cannot be switched back to SQLite blobs", dir_path);
+                return Err(Error::CorruptionError(format!("{} uses the flat-file backend", dir_path)));
+            },
+            (None, Some(TrieStorageBackend::FlatFile)) => {
+                let tx = tx_begin_immediate(&mut db)?;
+                TrieFileStorage::enable_data_file(&tx, dir_path)?;
+                trie_sql::bump_storage_epoch(&tx)?;
+                tx.commit()?;
+                Some(0)
+            },
+            (generation, _) => generation
+        };
+
+        let data_file = match data_file_generation {
+            Some(generation) => Some(TrieFileStorage::open_data_file(&TrieFileStorage::data_file_path(dir_path, generation), false)?),
+            None => None
+        };
+
+        let storage_epoch = trie_sql::get_storage_epoch(&db)?;
+        let data_version = trie_sql::get_data_version(&db)?;
+
+        let dir_path = dir_path.to_string();
+
         test_debug!("Opened TrieFileStorage {};", dir_path);
 
         let ret = TrieFileStorage {
@@ -528,10 +861,21 @@ impl TrieFileStorage {
 
             trie_ancestor_hash_bytes_cache: None,
             block_hash_cache: HashMap::new(),
+            node_cache: TrieNodeCache::new(node_cache_capacity()),
+            storage_epoch: storage_epoch,
+            data_version: Some(data_version),
+
+            data_file: data_file,
+            data_file_generation: data_file_generation.unwrap_or(0),
+            trie_locations: LruCache::new(MARF_TRIE_LOCATION_CACHE_CAPACITY),
+            read_buffer: Vec::with_capacity(TRIE_READ_BUFFER_SIZE as usize),
   
             miner_tip: None,
             readonly: false,
 
+            hash_calculation_mode: TrieHashCalculationMode::Immediate,
+            hash_threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+
             // used in testing in order to short-circuit block-height lookups
             //   when the trie struct is tested outside of marf.rs usage
             #[cfg(test)]
@@ -550,6 +894,11 @@ impl TrieFileStorage {
         let db = Connection::open_with_flags(&self.dir_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
         db.busy_handler(Some(tx_busy_handler))?;
 
+        let data_file = match self.data_file {
+            Some(_) => Some(TrieFileStorage::open_data_file(&TrieFileStorage::data_file_path(&self.dir_path, self.data_file_generation), true)?),
+            None => None
+        };
+
         trace!("Make read-only view of TrieFileStorage: {}", &self.dir_path);
         
         let ret = TrieFileStorage {
@@ -571,9 +920,20 @@ impl TrieFileStorage {
 
             trie_ancestor_hash_bytes_cache: self.trie_ancestor_hash_bytes_cache.clone(),
             block_hash_cache: self.block_hash_cache.clone(),
+            node_cache: self.node_cache.clone(),
+            storage_epoch: self.storage_epoch,
+            data_version: None,
+
+            data_file: data_file,
+            data_file_generation: self.data_file_generation,
+            trie_locations: LruCache::new(MARF_TRIE_LOCATION_CACHE_CAPACITY),
+            read_buffer: Vec::with_capacity(TRIE_READ_BUFFER_SIZE as usize),
   
             miner_tip: None,
             readonly: true,
+
+            hash_calculation_mode: self.hash_calculation_mode,
+            hash_threads: self.hash_threads,
             
             // used in testing in order to short-circuit block-height lookups
             //   when the trie struct is tested outside of marf.rs usage
@@ -608,6 +968,179 @@ impl TrieFileStorage {
         None
     }
 
+    /// Choose when the nodes of the trie being built get their hashes
+    pub fn set_hash_calculation_mode(&mut self, mode: TrieHashCalculationMode) {
+        self.hash_calculation_mode = mode;
+    }
+
+    pub fn hash_calculation_mode(&self) -> TrieHashCalculationMode {
+        self.hash_calculation_mode
+    }
+
+    /// Set how many threads hash the trie being built in deferred mode
+    pub fn set_hash_threads(&mut self, num_threads: usize) {
+        self.hash_threads = cmp::max(1, num_threads);
+    }
+
+    /// Record that nodes in the trie being built were written without their hashes
+    pub fn set_hashes_pending(&mut self) {
+        if let Some((_, ref mut trie_ram)) = self.last_extended {
+            trie_ram.hashes_pending = true;
+        }
+    }
+
+    /// Does the trie being built have nodes whose hashes were deferred?
+    pub fn has_pending_hashes(&self) -> bool {
+        match self.last_extended {
+            Some((_, ref trie_ram)) => trie_ram.hashes_pending,
+            None => false
+        }
+    }
+
+    /// Hash every node in the trie being built, in parallel (see TrieRAM::compute_node_hashes()).
+    /// Returns the root node's hash before the ancestor tries' root hashes are mixed in.
+    pub fn compute_extending_trie_hashes(&mut self) -> Result<TrieHash, Error> {
+        let back_blocks = match self.last_extended {
+            Some((_, ref trie_ram)) => trie_ram.reachable_back_blocks()?,
+            None => {
+                return Err(Error::NotFoundError);
+            }
+        };
+
+        // look up the back-pointed blocks now, so the hashing threads don't need the database
+        let mut block_hashes = HashMap::new();
+        for block_id in back_blocks.into_iter() {
+            let block_hash = self.get_block_hash_caching(block_id)?.clone();
+            block_hashes.insert(block_id, block_hash);
+        }
+
+        let num_threads = self.hash_threads;
+        match self.last_extended {
+            Some((_, ref mut trie_ram)) => trie_ram.compute_node_hashes(&block_hashes, num_threads),
+            None => Err(Error::NotFoundError)
+        }
+    }
+
+    /// Set how many decoded nodes (and node hashes) to cache.  0 disables the cache.
+    pub fn set_node_cache_capacity(&mut self, capacity: usize) {
+        self.node_cache.set_capacity(capacity);
+    }
+
+    /// How many decoded nodes (and node hashes) this storage caches
+    pub fn node_cache_capacity(&self) -> usize {
+        self.node_cache.capacity()
+    }
+
+    /// Get and reset the node cache's (hits, misses) counters
+    pub fn node_cache_stats(&mut self) -> (u64, u64) {
+        self.node_cache.stats()
+    }
+
+    /// Drop every cached node and node hash, so they're all read from disk again
+    pub fn clear_node_cache(&mut self) {
+        self.node_cache.clear();
+    }
+
+    /// Which backend holds this storage's tries
+    pub fn backend(&self) -> TrieStorageBackend {
+        match self.data_file {
+            Some(_) => TrieStorageBackend::FlatFile,
+            None => TrieStorageBackend::SqliteBlobs
+        }
+    }
+
+    /// Path to the given generation of the flat data file for the storage at dir_path.  Compaction
+    /// writes the live tries to the next generation, so the database only ever points at a
+    /// complete file.
+    pub fn data_file_path(dir_path: &str, generation: u64) -> String {
+        format!("{}.blobs.{}", dir_path, generation)
+    }
+
+    /// Path to the flat data file currently in use, if any
+    pub fn get_data_file_path(&self) -> Option<String> {
+        match self.data_file {
+            Some(_) => Some(TrieFileStorage::data_file_path(&self.dir_path, self.data_file_generation)),
+            None => None
+        }
+    }
+
+    fn open_data_file(path: &str, readonly: bool) -> Result<fs::File, Error> {
+        let mut options = fs::OpenOptions::new();
+        options.read(true);
+        if !readonly {
+            options.write(true).create(true);
+        }
+        options.open(path)
+            .map_err(|e| {
+                error!("Failed to open MARF data file {}: {:?}", path, &e);
+                Error::IOError(e)
+            })
+    }
+
+    /// Mark the database as storing its tries in the flat data file from now on.  The caller
+    /// bumps the storage epoch and commits.
+    fn enable_data_file(tx: &Transaction, dir_path: &str) -> Result<(), Error> {
+        if dir_path == ":memory:" {
+            error!("In-RAM TrieFileStorage cannot use a flat data file");
+            return Err(Error::NotDirectoryError);
+        }
+
+        debug!("Store tries for {} in {}", dir_path, &TrieFileStorage::data_file_path(dir_path, 0));
+        trie_sql::set_data_file_generation(tx, 0)
+    }
+
+    /// Make sure data_file is the generation of the flat data file that the database points at,
+    /// reopening it if another handle has enabled or compacted it since.  Anything that appends
+    /// to the file calls this with the write lock held, so the generation can't change again
+    /// before its bytes are committed.
+    fn open_current_data_file(conn: &Connection, dir_path: &str, data_file: &mut Option<fs::File>,
+                              data_file_generation: &mut u64, readonly: bool) -> Result<(), Error> {
+        if let Some(generation) = trie_sql::get_data_file_generation(conn)? {
+            if data_file.is_none() || generation != *data_file_generation {
+                let path = TrieFileStorage::data_file_path(dir_path, generation);
+                debug!("TrieFileStorage {} now stores its tries in {}", dir_path, &path);
+                *data_file = Some(TrieFileStorage::open_data_file(&path, readonly)?);
+                *data_file_generation = generation;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a trie to the flat data file and sync it to disk.  Returns its (offset, length).
+    /// The caller must hold the database write lock until the location is committed, so no
+    /// other handle appends at the same offset.
+    fn append_to_data_file(data_file: &mut fs::File, buffer: &[u8]) -> Result<(u64, u64), Error> {
+        let offset = fseek_end(data_file)?;
+        data_file.write_all(buffer)?;
+        data_file.sync_data()?;
+        Ok((offset, buffer.len() as u64))
+    }
+
+    /// Open a flushed trie's bytes for reading, wherever they are stored.
+    /// This borrows individual fields so callers can keep using the others.
+    fn open_trie_blob<'a>(db: &'a Connection, data_file: &'a Option<fs::File>, trie_locations: &mut LruCache<u32, Option<(u64, u64)>>,
+                          read_buffer: &'a mut Vec<u8>, block_id: u32) -> Result<TrieBlob<'a>, Error> {
+        if let Some(ref file) = *data_file {
+            let location = match trie_locations.get(&block_id) {
+                Some(location) => location,
+                None => {
+                    let location = trie_sql::get_external_trie_location(db, block_id)?;
+                    trie_locations.insert(block_id, location.clone());
+                    location
+                }
+            };
+
+            if let Some((offset, length)) = location {
+                return Ok(TrieBlob::FlatFile(TrieFileSlice::new(file, offset, length, read_buffer)));
+            }
+
+            // otherwise, this trie was written before the flat file was enabled
+        }
+
+        let blob = db.blob_open(DatabaseName::Main, "marf_data", "data", block_id.into(), true)?;
+        Ok(TrieBlob::Sqlite(blob))
+    }
+
     #[cfg(test)]
     pub fn new_memory() -> Result<TrieFileStorage, Error> {
         TrieFileStorage::new(":memory:")
@@ -663,17 +1196,28 @@ impl TrieFileStorage {
 
     /// Read the Trie root node's hash from the block table.
     #[cfg(test)]
-    pub fn read_block_root_hash(&self, bhh: &BlockHeaderHash) -> Result<TrieHash, Error> {
+    pub fn read_block_root_hash(&mut self, bhh: &BlockHeaderHash) -> Result<TrieHash, Error> {
         let root_hash_ptr =
             TriePtr::new(TrieNodeID::Node256 as u8, 0, TrieFileStorage::root_ptr_disk());
-        trie_sql::get_node_hash_bytes_by_bhh(&self.db, bhh, &root_hash_ptr)
+        self.check_storage_epoch()?;
+        let block_id = trie_sql::get_block_identifier(&self.db, bhh)?;
+        let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, block_id)?;
+        Ok(TrieHash(read_node_hash_bytes(&mut blob, &root_hash_ptr)?))
     }
 
     /// Generate a mapping between Trie root hashes and the blocks that contain them
     #[cfg(test)]
     pub fn read_root_to_block_table(&mut self) -> Result<HashMap<TrieHash, BlockHeaderHash>, Error> {
-        let mut ret = HashMap::from_iter(trie_sql::read_all_block_hashes_and_roots(&self.db)?
-                                         .into_iter());
+        let root_hash_ptr =
+            TriePtr::new(TrieNodeID::Node256 as u8, 0, TrieFileStorage::root_ptr_disk());
+
+        self.check_storage_epoch()?;
+        let mut ret = HashMap::new();
+        for (block_id, block_hash) in trie_sql::read_all_block_ids(&self.db)?.into_iter() {
+            let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, block_id)?;
+            let root_hash = TrieHash(read_node_hash_bytes(&mut blob, &root_hash_ptr)?);
+            ret.insert(root_hash, block_hash);
+        }
 
         let last_extended = match self.last_extended.take() {
             Some((bhh, trie_ram)) => {
@@ -758,7 +1302,12 @@ impl TrieFileStorage {
     }
 
     pub fn open_block(&mut self, bhh: &BlockHeaderHash) -> Result<(), Error> {
-        if *bhh == self.cur_block && self.cur_block_id.is_some() {
+        // every MARF read and write opens its block first, so this is where we notice tries
+        // deleted through another handle -- not on each node read
+        let storage_epoch = self.storage_epoch;
+        self.check_storage_epoch()?;
+
+        if *bhh == self.cur_block && self.cur_block_id.is_some() && storage_epoch == self.storage_epoch {
             // no-op
             return Ok(())
         }
@@ -794,6 +1343,16 @@ impl TrieFileStorage {
         trie_sql::get_block_identifier(&self.db, bhh).ok()
     }
 
+    /// Is there a trie for the given block?  Unlike get_block_identifier(), a failure to query
+    /// the DB is returned instead of being treated as a missing block.
+    pub fn has_block(&self, bhh: &BlockHeaderHash) -> Result<bool, Error> {
+        match trie_sql::get_block_identifier(&self.db, bhh) {
+            Ok(_) => Ok(true),
+            Err(Error::NotFoundError) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
     pub fn get_cur_block_identifier(&mut self) -> Result<u32, Error> {
         if let Some((ref last_extended, _)) = self.last_extended {
             if &self.cur_block == last_extended {
@@ -847,6 +1406,7 @@ impl TrieFileStorage {
 
         // blow away db
         trie_sql::clear_tables(&mut self.db)?;
+        self.storage_epoch = trie_sql::get_storage_epoch(&self.db)?;
 
         match self.last_extended {
             Some((_, ref mut trie_storage)) => trie_storage.format()?,
@@ -856,10 +1416,279 @@ impl TrieFileStorage {
         self.cur_block = TrieFileStorage::block_sentinel();
         self.cur_block_id = None;
         self.last_extended = None;
+        self.node_cache.clear();
+        self.trie_locations.clear();
+
+        if let Some(ref data_file) = self.data_file {
+            data_file.set_len(0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the tries for the given blocks.  The caller must make sure that no surviving
+    /// trie has a back-pointer into any of them -- i.e. all descendants must be removed too.
+    pub fn delete_blocks(&mut self, bhhs: &[BlockHeaderHash]) -> Result<(), Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+
+        debug!("Delete {} trie(s) from TrieFileStorage {}", bhhs.len(), &self.dir_path);
+
+        if let Some((ref bhh, _)) = self.last_extended {
+            error!("TrieFileStorage {} is in the process of writing {}", &self.dir_path, bhh);
+            return Err(Error::InProgressError);
+        }
+
+        let tx = tx_begin_immediate(&mut self.db)?;
+        for bhh in bhhs.iter() {
+            trie_sql::delete_block(&tx, bhh)?;
+        }
+        let storage_epoch = trie_sql::bump_storage_epoch(&tx)?;
+        tx.commit()?;
+
+        self.forget_deleted_blocks(storage_epoch);
+        Ok(())
+    }
+
+    /// Move the tries for the given blocks into the quarantine table, along with why each was
+    /// quarantined.  As with delete_blocks(), all descendants must go too.
+    /// A trie whose bytes can't be read is quarantined with no data.
+    pub fn quarantine_blocks(&mut self, bhhs_and_reasons: &[(BlockHeaderHash, String)]) -> Result<(), Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+
+        debug!("Quarantine {} trie(s) in TrieFileStorage {}", bhhs_and_reasons.len(), &self.dir_path);
+
+        if let Some((ref bhh, _)) = self.last_extended {
+            error!("TrieFileStorage {} is in the process of writing {}", &self.dir_path, bhh);
+            return Err(Error::InProgressError);
+        }
+
+        let mut trie_data = Vec::with_capacity(bhhs_and_reasons.len());
+        for (bhh, _) in bhhs_and_reasons.iter() {
+            let data = match self.read_trie_bytes(bhh) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Failed to read trie for {} to quarantine it: {:?}", bhh, &e);
+                    vec![]
+                }
+            };
+            trie_data.push(data);
+        }
+
+        let tx = tx_begin_immediate(&mut self.db)?;
+        for ((bhh, reason), data) in bhhs_and_reasons.iter().zip(trie_data.iter()) {
+            trie_sql::write_quarantined_trie(&tx, bhh, data, reason)?;
+            trie_sql::delete_block(&tx, bhh)?;
+        }
+        let storage_epoch = trie_sql::bump_storage_epoch(&tx)?;
+        tx.commit()?;
+
+        self.forget_deleted_blocks(storage_epoch);
+        Ok(())
+    }
 
+    /// Read all of a flushed trie's bytes
+    fn read_trie_bytes(&mut self, bhh: &BlockHeaderHash) -> Result<Vec<u8>, Error> {
+        self.check_storage_epoch()?;
+        let block_id = trie_sql::get_block_identifier(&self.db, bhh)?;
+        let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, block_id)?;
+        fseek(&mut blob, 0)?;
+
+        let mut data = vec![];
+        blob.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Drop all state that may refer to tries that were just deleted
+    fn forget_deleted_blocks(&mut self, storage_epoch: u64) {
+        self.cur_block = TrieFileStorage::block_sentinel();
+        self.cur_block_id = None;
+        self.drop_caches();
+        self.storage_epoch = storage_epoch;
+    }
+
+    fn drop_caches(&mut self) {
+        self.trie_ancestor_hash_bytes_cache = None;
+        self.block_hash_cache.clear();
+        self.node_cache.clear();
+        self.trie_locations.clear();
+    }
+
+    /// Drop the caches, and switch to the current flat data file, if another handle on this
+    /// storage has deleted or moved tries since they were filled.  This is called whenever a block
+    /// is opened and before whole-trie reads and writes, and only costs a PRAGMA unless another
+    /// connection has committed in the meantime.
+    fn check_storage_epoch(&mut self) -> Result<(), Error> {
+        let data_version = trie_sql::get_data_version(&self.db)?;
+        if self.data_version == Some(data_version) {
+            return Ok(());
+        }
+        self.data_version = Some(data_version);
+
+        let storage_epoch = trie_sql::get_storage_epoch(&self.db)?;
+        if storage_epoch != self.storage_epoch {
+            debug!("TrieFileStorage {} went from epoch {} to {}; dropping cached tries", &self.dir_path, self.storage_epoch, storage_epoch);
+            self.drop_caches();
+            self.storage_epoch = storage_epoch;
+
+            // the tries may have been moved to a new flat data file
+            TrieFileStorage::open_current_data_file(&self.db, &self.dir_path, &mut self.data_file, &mut self.data_file_generation, self.readonly)?;
+        }
+        Ok(())
+    }
+
+    /// Get the block hash, parent block hash, and size in bytes of every flushed trie.
+    /// The parent block hash is stored in the first bytes of each trie.
+    pub fn read_block_parents(&mut self) -> Result<Vec<(BlockHeaderHash, BlockHeaderHash, u64)>, Error> {
+        self.check_storage_epoch()?;
+        let mut ret = vec![];
+        for (block_id, block_hash) in trie_sql::read_all_block_ids(&self.db)?.into_iter() {
+            let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, block_id)?;
+            let size = fseek_end(&mut blob)?;
+            fseek(&mut blob, 0)?;
+
+            let mut parent_bytes = [0u8; BLOCK_HEADER_HASH_ENCODED_SIZE as usize];
+            blob.read_exact(&mut parent_bytes)
+                .map_err(|e| {
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        Error::CorruptionError(format!("Trie for {} has no parent block hash", &block_hash))
+                    }
+                    else {
+                        Error::IOError(e)
+                    }
+                })?;
+
+            ret.push((block_hash, BlockHeaderHash(parent_bytes), size));
+        }
+        Ok(ret)
+    }
+
+    /// Rebuild the underlying database (and flat data file, if used) to give back the space freed
+    /// by delete_blocks()
+    pub fn compact(&mut self) -> Result<(), Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+
+        debug!("Compact TrieFileStorage {}", &self.dir_path);
+        self.check_storage_epoch()?;
+        if self.data_file.is_some() {
+            self.compact_data_file()?;
+        }
+        trie_sql::vacuum(&self.db)
+    }
+
+    /// Copy the live tries into the next generation of the flat data file, and remove the old
+    /// one.  The database switches over to the new file in a single transaction, so a crash
+    /// leaves at worst an unused file behind.
+    fn compact_data_file(&mut self) -> Result<(), Error> {
+        // hold the write lock throughout, so no other handle appends to the old file after its
+        // tries have been copied
+        let tx = tx_begin_immediate(&mut self.db)?;
+        TrieFileStorage::open_current_data_file(&tx, &self.dir_path, &mut self.data_file, &mut self.data_file_generation, false)?;
+
+        let old_path = TrieFileStorage::data_file_path(&self.dir_path, self.data_file_generation);
+        let new_generation = self.data_file_generation + 1;
+        let new_path = TrieFileStorage::data_file_path(&self.dir_path, new_generation);
+
+        let mut new_file = TrieFileStorage::open_data_file(&new_path, false)?;
+
+        // left over from an interrupted compaction
+        new_file.set_len(0)?;
+
+        let mut new_locations = vec![];
+        if let Some(ref old_file) = self.data_file {
+            for (block_id, offset, length) in trie_sql::read_all_external_trie_locations(&tx)?.into_iter() {
+                let mut slice = TrieFileSlice::new(old_file, offset, length, &mut self.read_buffer);
+                let new_offset = fseek_end(&mut new_file)?;
+                let copied = io::copy(&mut slice, &mut new_file)?;
+                if copied != length {
+                    return Err(Error::CorruptionError(format!("Trie {} in {} is truncated", block_id, &old_path)));
+                }
+                new_locations.push((block_id, new_offset, length));
+            }
+        }
+        new_file.sync_all()?;
+
+        for (block_id, offset, length) in new_locations.iter() {
+            trie_sql::set_external_trie_location(&tx, *block_id, *offset, *length)?;
+        }
+        trie_sql::set_data_file_generation(&tx, new_generation)?;
+        let storage_epoch = trie_sql::bump_storage_epoch(&tx)?;
+        tx.commit()?;
+
+        debug!("Compacted {} trie(s) from {} into {}", new_locations.len(), &old_path, &new_path);
+
+        self.data_file = Some(new_file);
+        self.data_file_generation = new_generation;
+        self.storage_epoch = storage_epoch;
+        self.trie_locations.clear();
+
+        if let Err(e) = fs::remove_file(&old_path) {
+            warn!("Failed to remove old MARF data file {}: {:?}", &old_path, &e);
+        }
         Ok(())
     }
 
+    /// Move every trie that is still a SQLite blob into the flat data file, switching this storage
+    /// over to the flat-file backend first if need be.  Returns the number of tries and bytes
+    /// moved.
+    pub fn migrate_to_flat_file(&mut self) -> Result<(u64, u64), Error> {
+        if self.readonly {
+            return Err(Error::ReadOnlyError);
+        }
+
+        self.check_storage_epoch()?;
+
+        // hold the write lock throughout, so no other handle appends to the data file between
+        // the blobs being copied and their locations being committed
+        let tx = tx_begin_immediate(&mut self.db)?;
+        TrieFileStorage::open_current_data_file(&tx, &self.dir_path, &mut self.data_file, &mut self.data_file_generation, false)?;
+        if self.data_file.is_none() {
+            TrieFileStorage::enable_data_file(&tx, &self.dir_path)?;
+            self.data_file = Some(TrieFileStorage::open_data_file(&TrieFileStorage::data_file_path(&self.dir_path, 0), false)?);
+            self.data_file_generation = 0;
+        }
+
+        let block_ids = trie_sql::read_blob_block_ids(&tx)?;
+        let mut locations = Vec::with_capacity(block_ids.len());
+        let mut num_bytes = 0;
+
+        // copy the blobs first, so the database never points at bytes that aren't on disk
+        if let Some(ref mut data_file) = self.data_file {
+            let offset = fseek_end(data_file)?;
+            {
+                let mut writer = BufWriter::new(&*data_file);
+                for block_id in block_ids.iter() {
+                    let data = trie_sql::read_trie_blob(&tx, *block_id)?;
+                    writer.write_all(&data)?;
+                    locations.push((*block_id, offset + num_bytes, data.len() as u64));
+                    num_bytes += data.len() as u64;
+                }
+                writer.flush()?;
+            }
+            data_file.sync_data()?;
+        }
+
+        for (block_id, offset, length) in locations.iter() {
+            trie_sql::set_external_trie_location(&tx, *block_id, *offset, *length)?;
+            trie_sql::clear_trie_blob(&tx, *block_id)?;
+        }
+        self.storage_epoch = trie_sql::bump_storage_epoch(&tx)?;
+        tx.commit()?;
+
+        debug!("Migrated {} trie(s) ({} bytes) from {} to its flat data file", block_ids.len(), num_bytes, &self.dir_path);
+
+        self.trie_locations.clear();
+
+        // give back the space the blobs took up
+        trie_sql::vacuum(&self.db)?;
+        Ok((block_ids.len() as u64, num_bytes))
+    }
+
     /// Read a node's children's hashes into the provided <Write> implementation.
     /// This only works for intermediate nodes and leafs (the latter of which have no children).
     ///
@@ -897,13 +1726,13 @@ impl TrieFileStorage {
         }
 
         // otherwise, the current block is open as an FD
-        let mut cursor = TrieSqlCursor { db: &self.db,
-                                         block_id: self.cur_block_id.ok_or_else(|| {
-                                             error!("Failed to get cur block as hash reader");
-                                             Error::NotFoundError
-                                         })? };
+        let block_id = self.cur_block_id.ok_or_else(|| {
+            error!("Failed to get cur block as hash reader");
+            Error::NotFoundError
+        })?;
+        let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, block_id)?;
 
-        TrieFileStorage::inner_write_children_hashes(&mut cursor, &mut map, node, w)
+        TrieFileStorage::inner_write_children_hashes(&mut blob, &mut map, node, w)
     }
 
     fn inner_write_children_hashes<W: Write, H: NodeHashReader, M: BlockMap>(
@@ -941,7 +1770,16 @@ impl TrieFileStorage {
         // some other block or ptr, or cache miss
         match self.cur_block_id {
             Some(block_id) => {
-                trie_sql::get_node_hash_bytes(&self.db, block_id, ptr)
+                let clear_ptr = ptr.from_backptr();
+                if let Some(hash) = self.node_cache.get_hash(block_id, &clear_ptr) {
+                    return Ok(hash);
+                }
+                let hash = {
+                    let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, block_id)?;
+                    TrieHash(read_node_hash_bytes(&mut blob, ptr)?)
+                };
+                self.node_cache.store_hash(block_id, &clear_ptr, hash.clone());
+                Ok(hash)
             },
             None => {
                 error!("Not found (no file is open)");
@@ -976,7 +1814,17 @@ impl TrieFileStorage {
 
         // some other block
         match self.cur_block_id {
-            Some(id) => trie_sql::read_node_type(&self.db, id, &clear_ptr),
+            Some(id) => {
+                if let Some(node_and_hash) = self.node_cache.get_node(id, &clear_ptr) {
+                    return Ok(node_and_hash);
+                }
+                let (node, hash) = {
+                    let mut blob = TrieFileStorage::open_trie_blob(&self.db, &self.data_file, &mut self.trie_locations, &mut self.read_buffer, id)?;
+                    read_nodetype(&mut blob, &clear_ptr)?
+                };
+                self.node_cache.store_node(id, &clear_ptr, node.clone(), hash.clone());
+                Ok((node, hash))
+            },
             None => {
                 error!("Not found (no file is open)");
                 Err(Error::NotFoundError)
@@ -1032,6 +1880,15 @@ impl TrieFileStorage {
         if self.readonly {
             return Err(Error::ReadOnlyError);
         }
+        if self.has_pending_hashes() {
+            // the MARF computes deferred hashes before it flushes (see Trie::update_all_hashes())
+            error!("Refusing to flush a trie whose node hashes were deferred but never computed");
+            return Err(Error::CorruptionError("Trie has uncomputed node hashes".to_string()));
+        }
+
+        // append to the data file that's in use now
+        self.check_storage_epoch()?;
+
         if let Some((ref bhh, ref mut trie_ram)) = self.last_extended.take() {
             trace!("Buffering block flush started.");
             let mut buffer = Cursor::new(Vec::new());
@@ -1042,10 +1899,25 @@ impl TrieFileStorage {
 
             debug!("Flush: {} to {}", bhh, flush_options);
 
+            // a trie bound for the flat data file must be on disk before the database points at
+            // it.  The write lock is taken first, so no other handle can append to (or compact)
+            // the file until this trie's location is committed.  If the transaction fails, the
+            // appended bytes are just never referenced.
             let tx = tx_begin_immediate(&mut self.db)?;
+            let location = match flush_options {
+                FlushOptions::MinedTable(_) => None,
+                _ => {
+                    TrieFileStorage::open_current_data_file(&tx, &self.dir_path, &mut self.data_file, &mut self.data_file_generation, false)?;
+                    match self.data_file {
+                        Some(ref mut data_file) => Some(TrieFileStorage::append_to_data_file(data_file, &buffer)?),
+                        None => None
+                    }
+                }
+            };
+
             let block_id = match flush_options {
                 FlushOptions::CurrentHeader => {
-                    trie_sql::write_trie_blob(&tx, bhh, &buffer)?
+                    TrieFileStorage::write_flushed_trie(&tx, bhh, &buffer, location)?
                 },
                 FlushOptions::NewHeader(real_bhh) => {
                     // If we opened a block with a given hash, but want to store it as a block with a *different*
@@ -1062,7 +1934,7 @@ impl TrieFileStorage {
                         self.trie_ancestor_hash_bytes_cache = None;
                         self.cur_block = real_bhh.clone();
                     }
-                    trie_sql::write_trie_blob(&tx, real_bhh, &buffer)?
+                    TrieFileStorage::write_flushed_trie(&tx, real_bhh, &buffer, location)?
                 },
                 FlushOptions::MinedTable(real_bhh) => {
                     trie_sql::write_trie_blob_to_mined(&tx, real_bhh, &buffer)?
@@ -1078,6 +1950,13 @@ impl TrieFileStorage {
         Ok(())
     }
 
+    fn write_flushed_trie(conn: &Connection, bhh: &BlockHeaderHash, buffer: &[u8], location: Option<(u64, u64)>) -> Result<u32, Error> {
+        match location {
+            Some((offset, length)) => trie_sql::write_external_trie(conn, bhh, offset, length),
+            None => trie_sql::write_trie_blob(conn, bhh, buffer)
+        }
+    }
+
     pub fn flush(&mut self) -> Result<(), Error> {
         self.inner_flush(FlushOptions::CurrentHeader)
     }
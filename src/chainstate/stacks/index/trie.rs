@@ -60,6 +60,7 @@ use chainstate::stacks::index::node::{
 
 use chainstate::stacks::index::storage::{
     TrieFileStorage,
+    TrieHashCalculationMode,
 };
 
 use chainstate::stacks::index::marf::{
@@ -84,6 +85,11 @@ pub struct Trie {}
 
 /// Fetch children hashes and compute the node's hash
 fn get_nodetype_hash(storage: &mut TrieFileStorage, node: &TrieNodeType) -> Result<TrieHash, Error> {
+    if storage.hash_calculation_mode() == TrieHashCalculationMode::Deferred {
+        // filled in by Trie::update_all_hashes()
+        return Ok(TrieHash([0u8; 32]));
+    }
+
     let mut hasher = TrieHasher::new();
 
     node.write_consensus_bytes(storage, &mut hasher)
@@ -647,6 +653,10 @@ impl Trie {
     fn recalculate_root_hash(storage: &mut TrieFileStorage, cursor: &TrieCursor, update_skiplist: bool) -> Result<(), Error> {
         assert!(cursor.node_ptrs.len() > 0);
 
+        if storage.hash_calculation_mode() == TrieHashCalculationMode::Deferred {
+            return Trie::update_root_ptrs(storage, cursor);
+        }
+
         let mut ptrs = cursor.node_ptrs.clone();
         trace!("update_root_hash: ptrs = {:?}", &ptrs);
         let mut child_ptr = ptrs.pop().unwrap();
@@ -762,6 +772,54 @@ impl Trie {
         Ok(())
     }
     
+    /// Unwind a TrieCursor to point each node on its path at the (possibly new) child the cursor
+    /// walked to, without hashing anything.  The hashes are computed later, all at once, by
+    /// update_all_hashes().
+    fn update_root_ptrs(storage: &mut TrieFileStorage, cursor: &TrieCursor) -> Result<(), Error> {
+        let mut ptrs = cursor.node_ptrs.clone();
+        let mut child_ptr = ptrs.pop().unwrap();
+
+        while let Some(ptr) = ptrs.pop() {
+            if is_backptr(ptr.id()) {
+                // not altered -- only queued to the cursor while walking a backptr skiplist
+                continue;
+            }
+
+            let (mut node, _) = storage.read_nodetype(&ptr)?;
+            assert!(!node.is_leaf());
+
+            let updated = node.replace(&child_ptr);
+            if !updated {
+                trace!("FAILED TO UPDATE {:?} WITH {:?}: {:?}", &node, &child_ptr, cursor);
+                assert!(updated);
+            }
+
+            storage.write_nodetype(ptr.ptr(), &node, TrieHash([0; 32]))?;
+
+            child_ptr = ptr;
+            child_ptr.id = clear_backptr(child_ptr.id);
+        }
+
+        // must be at the root
+        assert_eq!(child_ptr, storage.root_trieptr());
+        storage.set_hashes_pending();
+        Ok(())
+    }
+
+    /// Hash every node of the trie being built whose hashes were deferred, then mix the ancestor
+    /// tries' root hashes into the root's hash.  Produces the same hashes as computing them on
+    /// each insert.  storage must point to the trie being built.
+    pub fn update_all_hashes(storage: &mut TrieFileStorage) -> Result<(), Error> {
+        let content_hash = storage.compute_extending_trie_hashes()?;
+        let root_hash = Trie::get_trie_root_hash(storage, &content_hash)?;
+
+        test_debug!("Next root hash is {} (all hashes updated)", root_hash);
+
+        let root_ptr = storage.root_trieptr();
+        let (root, _) = storage.read_nodetype(&root_ptr)?;
+        storage.write_nodetype(root_ptr.ptr(), &root, root_hash)
+    }
+
     pub fn update_root_hash(storage: &mut TrieFileStorage, cursor: &TrieCursor) -> Result<(), Error> {
         Trie::recalculate_root_hash(storage, cursor, true)
     }
@@ -103,15 +103,31 @@ use chainstate::stacks::index::Error as Error;
 use util::log;
 use util::db::tx_begin_immediate;
 
+// block_id is AUTOINCREMENT so that the identifiers of deleted tries are never handed out again.
+// Back-pointers store block identifiers, and every open handle on the MARF caches which block hash
+// each identifier belongs to.
 static SQL_MARF_DATA_TABLE: &str = "
 CREATE TABLE IF NOT EXISTS marf_data (
-   block_id INTEGER PRIMARY KEY, 
+   block_id INTEGER PRIMARY KEY AUTOINCREMENT,
    block_hash TEXT UNIQUE NOT NULL,
    data BLOB NOT NULL
 );
 
 CREATE INDEX IF NOT EXISTS block_hash_marf_data ON marf_data(block_hash);
 ";
+// Databases created before block_id was AUTOINCREMENT get their marf_data table rebuilt.  The
+// identifiers are copied over as-is, since back-pointers refer to them.
+static SQL_MARF_DATA_TABLE_MIGRATION: &str = "
+CREATE TABLE marf_data_autoincrement (
+   block_id INTEGER PRIMARY KEY AUTOINCREMENT,
+   block_hash TEXT UNIQUE NOT NULL,
+   data BLOB NOT NULL
+);
+
+INSERT INTO marf_data_autoincrement (block_id, block_hash, data) SELECT block_id, block_hash, data FROM marf_data;
+DROP TABLE marf_data;
+ALTER TABLE marf_data_autoincrement RENAME TO marf_data;
+";
 static SQL_MARF_MINED_TABLE: &str = "
 CREATE TABLE IF NOT EXISTS mined_blocks (
    block_id INTEGER PRIMARY KEY, 
@@ -124,17 +140,67 @@ CREATE INDEX IF NOT EXISTS block_hash_mined_blocks ON mined_blocks(block_hash);
 static SQL_EXTENSION_LOCKS_TABLE: &str = "
 CREATE TABLE IF NOT EXISTS block_extension_locks (block_hash TEXT PRIMARY KEY);
 ";
+// Tries in the flat data file still get a (data-less) row in marf_data, so block identifiers are
+// allocated the same way for both backends.  marf_data_file has a row iff the flat file is in use.
+static SQL_MARF_DATA_EXTERNAL_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS marf_data_external (
+   block_id INTEGER PRIMARY KEY,
+   data_offset INTEGER NOT NULL,
+   data_length INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS marf_data_file (
+   generation INTEGER NOT NULL
+);
+";
+// Damaged tries taken out of marf_data, kept around for post-mortems
+static SQL_MARF_QUARANTINE_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS marf_quarantine (
+   block_hash TEXT PRIMARY KEY,
+   data BLOB NOT NULL,
+   reason TEXT NOT NULL
+);
+";
+
+// Bumped whenever tries are deleted, so that other handles on the same storage know that what
+// they have cached may be stale
+static SQL_MARF_STORAGE_EPOCH_TABLE: &str = "
+CREATE TABLE IF NOT EXISTS marf_storage_epoch (
+   epoch INTEGER NOT NULL
+);
+";
 
 pub fn create_tables_if_needed(conn: &mut Connection) -> Result<(), Error> {
     let tx = tx_begin_immediate(conn)?;
 
+    migrate_marf_data_if_needed(&tx)?;
     tx.execute_batch(SQL_MARF_DATA_TABLE)?;
     tx.execute_batch(SQL_MARF_MINED_TABLE)?;
     tx.execute_batch(SQL_EXTENSION_LOCKS_TABLE)?;
+    tx.execute_batch(SQL_MARF_DATA_EXTERNAL_TABLE)?;
+    tx.execute_batch(SQL_MARF_QUARANTINE_TABLE)?;
+    tx.execute_batch(SQL_MARF_STORAGE_EPOCH_TABLE)?;
 
     tx.commit().map_err(|e| e.into())
 }
 
+/// Rebuild a marf_data table whose block_id isn't AUTOINCREMENT, so identifiers of deleted tries
+/// stop being handed out again.
+fn migrate_marf_data_if_needed(conn: &Connection) -> Result<(), Error> {
+    let schema : Option<String> = conn.query_row("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'marf_data'", NO_PARAMS,
+                                                 |row| row.get(0))
+        .optional()?;
+
+    match schema {
+        Some(ref sql) if !sql.to_uppercase().contains("AUTOINCREMENT") => {
+            info!("Rebuilding marf_data so that MARF block identifiers are never reused");
+            conn.execute_batch(SQL_MARF_DATA_TABLE_MIGRATION)?;
+            Ok(())
+        },
+        _ => Ok(())
+    }
+}
+
 pub fn get_block_identifier(conn: &Connection, bhh: &BlockHeaderHash) -> Result<u32, Error> {
     conn.query_row("SELECT block_id FROM marf_data WHERE block_hash = ?", &[bhh],
                    |row| row.get("block_id"))
@@ -169,20 +235,6 @@ pub fn write_trie_blob_to_mined(conn: &Connection, block_hash: &BlockHeaderHash,
     Ok(block_id)
 }
 
-#[cfg(test)]
-pub fn read_all_block_hashes_and_roots(conn: &Connection) -> Result<Vec<(TrieHash, BlockHeaderHash)>, Error> {
-    let mut s = conn.prepare("SELECT block_hash, data FROM marf_data")?;
-    let rows = s.query_and_then(NO_PARAMS, |row| {
-        let block_hash: BlockHeaderHash = row.get("block_hash");
-        let data = row.get_raw("data")
-            .as_blob().expect("DB Corruption: MARF data is non-blob");
-        let start = TrieFileStorage::root_ptr_disk() as usize;
-        let trie_hash = TrieHash(read_hash_bytes(&mut &data[start..])?);
-        Ok((trie_hash, block_hash))
-    })?;
-    rows.collect()
-}
-
 pub fn read_node_hash_bytes<W: Write>(conn: &Connection, w: &mut W, block_id: u32, ptr: &TriePtr) -> Result<(), Error> {
     let mut blob = conn.blob_open(rusqlite::DatabaseName::Main, "marf_data", "data", block_id.into(), true)?;
     let hash_buff = bits_read_node_hash_bytes(&mut blob, ptr)?;
@@ -257,6 +309,135 @@ pub fn clear_tables(conn: &mut Connection) -> Result<(), Error> {
     let tx = tx_begin_immediate(conn)?;
     tx.execute("DELETE FROM block_extension_locks", NO_PARAMS)?;
     tx.execute("DELETE FROM marf_data", NO_PARAMS)?;
+    tx.execute("DELETE FROM marf_data_external", NO_PARAMS)?;
     tx.execute("DELETE FROM mined_blocks", NO_PARAMS)?;
+    bump_storage_epoch(&tx)?;
     tx.commit().map_err(|e| e.into())
 }
+
+pub fn get_storage_epoch(conn: &Connection) -> Result<u64, Error> {
+    let epoch: Option<i64> = conn.query_row("SELECT epoch FROM marf_storage_epoch LIMIT 1", NO_PARAMS,
+                                            |row| row.get("epoch"))
+        .optional()?;
+    Ok(epoch.map(|e| e as u64).unwrap_or(0))
+}
+
+/// Record that tries were deleted, so every other handle on this storage drops its caches.
+/// Returns the new epoch.
+pub fn bump_storage_epoch(conn: &Connection) -> Result<u64, Error> {
+    let epoch = get_storage_epoch(conn)? + 1;
+    conn.execute("DELETE FROM marf_storage_epoch", NO_PARAMS)?;
+    conn.execute("INSERT INTO marf_storage_epoch (epoch) VALUES (?)", &[epoch as i64])?;
+    Ok(epoch)
+}
+
+/// SQLite's data_version for this connection, which changes whenever another connection commits
+pub fn get_data_version(conn: &Connection) -> Result<i64, Error> {
+    let data_version = conn.query_row("PRAGMA data_version", NO_PARAMS, |row| row.get(0))?;
+    Ok(data_version)
+}
+
+/// Get the identifier and block hash of every trie in marf_data, in the order they were written
+pub fn read_all_block_ids(conn: &Connection) -> Result<Vec<(u32, BlockHeaderHash)>, Error> {
+    let mut s = conn.prepare("SELECT block_id, block_hash FROM marf_data ORDER BY block_id")?;
+    let rows = s.query_and_then(NO_PARAMS, |row| {
+        let block_id: u32 = row.get("block_id");
+        let block_hash: BlockHeaderHash = row.get("block_hash");
+        Ok((block_id, block_hash))
+    })?;
+    rows.collect()
+}
+
+/// Get the generation of the flat data file, or None if this MARF keeps its tries as blobs
+pub fn get_data_file_generation(conn: &Connection) -> Result<Option<u64>, Error> {
+    let generation: Option<i64> = conn.query_row("SELECT generation FROM marf_data_file LIMIT 1", NO_PARAMS,
+                                                 |row| row.get("generation"))
+        .optional()?;
+    Ok(generation.map(|g| g as u64))
+}
+
+pub fn set_data_file_generation(conn: &Connection, generation: u64) -> Result<(), Error> {
+    conn.execute("DELETE FROM marf_data_file", NO_PARAMS)?;
+    conn.execute("INSERT INTO marf_data_file (generation) VALUES (?)", &[generation as i64])?;
+    Ok(())
+}
+
+/// Get the (offset, length) of a trie in the flat data file, or None if its data is in marf_data
+pub fn get_external_trie_location(conn: &Connection, block_id: u32) -> Result<Option<(u64, u64)>, Error> {
+    let location = conn.query_row("SELECT data_offset, data_length FROM marf_data_external WHERE block_id = ?", &[block_id],
+                                  |row| {
+                                      let offset: i64 = row.get("data_offset");
+                                      let length: i64 = row.get("data_length");
+                                      (offset as u64, length as u64)
+                                  })
+        .optional()?;
+    Ok(location)
+}
+
+pub fn set_external_trie_location(conn: &Connection, block_id: u32, offset: u64, length: u64) -> Result<(), Error> {
+    let args: &[&dyn ToSql] = &[&block_id, &(offset as i64), &(length as i64)];
+    conn.execute("INSERT OR REPLACE INTO marf_data_external (block_id, data_offset, data_length) VALUES (?, ?, ?)", args)?;
+    Ok(())
+}
+
+/// Get the identifier, offset, and length of every trie in the flat data file, in file order
+pub fn read_all_external_trie_locations(conn: &Connection) -> Result<Vec<(u32, u64, u64)>, Error> {
+    let mut s = conn.prepare("SELECT block_id, data_offset, data_length FROM marf_data_external ORDER BY data_offset")?;
+    let rows = s.query_and_then(NO_PARAMS, |row| {
+        let block_id: u32 = row.get("block_id");
+        let offset: i64 = row.get("data_offset");
+        let length: i64 = row.get("data_length");
+        Ok((block_id, offset as u64, length as u64))
+    })?;
+    rows.collect()
+}
+
+/// Record a trie that was appended to the flat data file at the given offset
+pub fn write_external_trie(conn: &Connection, block_hash: &BlockHeaderHash, offset: u64, length: u64) -> Result<u32, Error> {
+    let block_id = write_trie_blob(conn, block_hash, &[])?;
+    set_external_trie_location(conn, block_id, offset, length)?;
+    Ok(block_id)
+}
+
+/// Get the identifiers of the tries whose data is still a blob in marf_data
+pub fn read_blob_block_ids(conn: &Connection) -> Result<Vec<u32>, Error> {
+    let mut s = conn.prepare("SELECT block_id FROM marf_data WHERE length(data) > 0 ORDER BY block_id")?;
+    let rows = s.query_and_then(NO_PARAMS, |row| {
+        let block_id: u32 = row.get("block_id");
+        Ok(block_id)
+    })?;
+    rows.collect()
+}
+
+pub fn read_trie_blob(conn: &Connection, block_id: u32) -> Result<Vec<u8>, Error> {
+    conn.query_row("SELECT data FROM marf_data WHERE block_id = ?", &[block_id],
+                   |row| row.get("data"))
+        .map_err(|e| e.into())
+}
+
+/// Drop a trie's blob from marf_data once its data lives in the flat data file
+pub fn clear_trie_blob(conn: &Connection, block_id: u32) -> Result<(), Error> {
+    conn.execute("UPDATE marf_data SET data = x'' WHERE block_id = ?", &[block_id])?;
+    Ok(())
+}
+
+/// Rebuild the database file, giving back the space left by deleted tries
+pub fn vacuum(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+/// Keep a copy of a damaged trie's bytes, and why it was taken out of the MARF
+pub fn write_quarantined_trie(conn: &Connection, bhh: &BlockHeaderHash, data: &[u8], reason: &str) -> Result<(), Error> {
+    let args: &[&dyn ToSql] = &[bhh, &data, &reason];
+    conn.execute("INSERT OR REPLACE INTO marf_quarantine (block_hash, data, reason) VALUES (?, ?, ?)", args)?;
+    Ok(())
+}
+
+pub fn delete_block(conn: &Connection, bhh: &BlockHeaderHash) -> Result<(), Error> {
+    conn.execute("DELETE FROM marf_data_external WHERE block_id IN (SELECT block_id FROM marf_data WHERE block_hash = ?)", &[bhh])?;
+    conn.execute("DELETE FROM marf_data WHERE block_hash = ?", &[bhh])?;
+    conn.execute("DELETE FROM mined_blocks WHERE block_hash = ?", &[bhh])?;
+    conn.execute("DELETE FROM block_extension_locks WHERE block_hash = ?", &[bhh])?;
+    Ok(())
+}
@@ -18,7 +18,9 @@
 */
 
 use std::fs;
+use std::fmt;
 use std::mem;
+use std::cmp;
 use std::collections::HashSet;
 use std::collections::HashMap;
 use chainstate::stacks::Error;
@@ -37,6 +39,7 @@ use net::StacksMessageCodec;
 use net::Error as net_error;
 use net::codec::{read_next, write_next};
 use vm::clarity::ClarityConnection;
+use vm::types::{PrincipalData, QualifiedContractIdentifier, StandardPrincipalData};
 
 use util::hash::MerkleTree;
 use util::hash::Sha512Trunc256Sum;
@@ -48,14 +51,175 @@ use chainstate::burn::*;
 use chainstate::burn::operations::*;
 
 use burnchains::BurnchainHeaderHash;
+use burnchains::Txid;
 use burnchains::PrivateKey;
 use burnchains::PublicKey;
 
 use util::vrf::*;
+use util::get_epoch_time_ms;
 
 use core::*;
 use core::mempool::*;
 
+/// Why the miner left a mempool transaction out of an anchored block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionSkipReason {
+    /// the selector's allow/deny lists rejected it
+    Filtered,
+    /// the origin or sponsor already has the maximum number of transactions in this block
+    SenderLimit,
+    /// it would have exceeded the block's size or execution cost limit
+    CostLimit,
+    /// its origin or sponsor nonce was already used (e.g. it was mined in an ancestor block)
+    StaleNonce,
+    /// its origin or sponsor nonce did not match the account's next nonce
+    BadNonce,
+    /// it failed to apply to the chainstate
+    RuntimeError(String),
+    /// the tenure's assembly time budget ran out before it could be tried
+    Timeout
+}
+
+impl fmt::Display for TransactionSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransactionSkipReason::Filtered => write!(f, "filtered by selection policy"),
+            TransactionSkipReason::SenderLimit => write!(f, "too many transactions from sender"),
+            TransactionSkipReason::CostLimit => write!(f, "block cost limit reached"),
+            TransactionSkipReason::StaleNonce => write!(f, "nonce already used"),
+            TransactionSkipReason::BadNonce => write!(f, "bad nonce"),
+            TransactionSkipReason::RuntimeError(ref msg) => write!(f, "runtime error: {}", msg),
+            TransactionSkipReason::Timeout => write!(f, "tenure time budget exhausted")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionSkip {
+    pub txid: Txid,
+    pub reason: TransactionSkipReason
+}
+
+/// Mining policy for choosing which mempool transactions to try to include in an anchored block,
+/// and in what order.
+pub trait TransactionSelector {
+    /// Put the candidate transactions into the order in which the miner should try them.
+    fn order_candidates(&self, candidates: &mut [MemPoolTxInfo]);
+
+    /// Decide whether or not the miner may include this transaction at all.
+    fn admit(&self, _tx: &StacksTransaction) -> bool {
+        true
+    }
+}
+
+/// Try the transactions paying the highest fee rate first.
+pub struct FeeRateSelector {}
+
+impl TransactionSelector for FeeRateSelector {
+    fn order_candidates(&self, candidates: &mut [MemPoolTxInfo]) {
+        candidates.sort_by(|a, b| b.metadata.fee_rate.cmp(&a.metadata.fee_rate)
+                                  .then_with(|| a.metadata.accept_time.cmp(&b.metadata.accept_time)));
+    }
+}
+
+/// Try transactions in the order in which the mempool accepted them.
+pub struct FifoSelector {}
+
+impl TransactionSelector for FifoSelector {
+    fn order_candidates(&self, candidates: &mut [MemPoolTxInfo]) {
+        candidates.sort_by(|a, b| a.metadata.accept_time.cmp(&b.metadata.accept_time)
+                                  .then_with(|| a.metadata.origin_nonce.cmp(&b.metadata.origin_nonce)));
+    }
+}
+
+/// Wraps another selector, and only admits transactions that pass its allow and deny lists.
+/// * A transaction is rejected if its origin or sponsor is a denied principal, or if it calls or
+/// publishes a denied contract.
+/// * If there are allowed principals, the origin must be one of them.
+/// * If there are allowed contracts, contract-calls and contract-publishes must name one of them.
+/// Other transactions (like token transfers) are not subject to the contract allow list.
+pub struct AllowDenySelector {
+    pub inner: Box<dyn TransactionSelector>,
+    pub allowed_contracts: HashSet<QualifiedContractIdentifier>,
+    pub denied_contracts: HashSet<QualifiedContractIdentifier>,
+    pub allowed_principals: HashSet<PrincipalData>,
+    pub denied_principals: HashSet<PrincipalData>
+}
+
+impl AllowDenySelector {
+    fn tx_contract_id(tx: &StacksTransaction) -> Option<QualifiedContractIdentifier> {
+        match tx.payload {
+            TransactionPayload::ContractCall(ref cc) => Some(cc.to_clarity_contract_id()),
+            TransactionPayload::SmartContract(ref sc) => Some(QualifiedContractIdentifier::new(StandardPrincipalData::from(tx.origin_address()), sc.name.clone())),
+            _ => None
+        }
+    }
+}
+
+impl TransactionSelector for AllowDenySelector {
+    fn order_candidates(&self, candidates: &mut [MemPoolTxInfo]) {
+        self.inner.order_candidates(candidates)
+    }
+
+    fn admit(&self, tx: &StacksTransaction) -> bool {
+        if !self.inner.admit(tx) {
+            return false;
+        }
+
+        let origin = PrincipalData::from(tx.origin_address());
+        if self.denied_principals.contains(&origin) {
+            return false;
+        }
+        if self.allowed_principals.len() > 0 && !self.allowed_principals.contains(&origin) {
+            return false;
+        }
+        if let Some(sponsor_addr) = tx.sponsor_address() {
+            if self.denied_principals.contains(&PrincipalData::from(sponsor_addr)) {
+                return false;
+            }
+        }
+
+        if let Some(contract_id) = AllowDenySelector::tx_contract_id(tx) {
+            if self.denied_contracts.contains(&contract_id) {
+                return false;
+            }
+            if self.allowed_contracts.len() > 0 && !self.allowed_contracts.contains(&contract_id) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// How many mempool transactions to read at a time when gathering candidates for a block
+pub const MEMPOOL_CANDIDATES_PAGE_SIZE : u64 = 1024;
+
+/// Default upper bound on how many mempool transactions are considered for a single block
+pub const MAX_MEMPOOL_CANDIDATES : u64 = 16384;
+
+/// Knobs for assembling an anchored block out of the mempool.
+pub struct BlockBuilderSettings {
+    /// chooses and orders the transactions to try
+    pub selector: Box<dyn TransactionSelector>,
+    /// how many transactions a single origin (or sponsor) account may have in the block
+    pub max_txs_per_sender: u64,
+    /// wall-clock budget for assembling the block, if any
+    pub max_assembly_time_ms: Option<u64>,
+    /// how many mempool transactions to consider, at most
+    pub max_mempool_candidates: u64
+}
+
+impl Default for BlockBuilderSettings {
+    fn default() -> BlockBuilderSettings {
+        BlockBuilderSettings {
+            selector: Box::new(FeeRateSelector {}),
+            max_txs_per_sender: 1,
+            max_assembly_time_ms: None,
+            max_mempool_candidates: MAX_MEMPOOL_CANDIDATES
+        }
+    }
+}
+
 impl StacksBlockBuilder {
     fn from_parent_pubkey_hash(miner_id: usize, parent_chain_tip: &StacksHeaderInfo, total_work: &StacksWorkScore, proof: &VRFProof, pubkh: Hash160) -> StacksBlockBuilder {
         let header = StacksBlockHeader::from_parent_empty(&parent_chain_tip.anchored_header, parent_chain_tip.microblock_tail.as_ref(), total_work, proof, &pubkh);
@@ -502,36 +666,28 @@ impl StacksBlockBuilder {
                                 pubkey_hash: Hash160,
                                 coinbase_tx: &StacksTransaction,
                                 execution_budget: ExecutionCost) -> Result<StacksBlock, Error> {
+        let (block, _skipped) = StacksBlockBuilder::build_anchored_block_with_settings(chainstate_handle, mempool, parent_stacks_header, total_burn, proof, pubkey_hash, coinbase_tx, execution_budget, &BlockBuilderSettings::default())?;
+        Ok(block)
+    }
 
-        if let TransactionPayload::Coinbase(..) = coinbase_tx.payload {} else {
-            return Err(Error::MemPoolError("Not a coinbase transaction".to_string()));
-        }
-
-        let (mut tip_burn_header_hash, mut tip_block_hash, mut tip_height) = (parent_stacks_header.burn_header_hash.clone(), parent_stacks_header.anchored_header.block_hash(), parent_stacks_header.block_height);
-
-        debug!("Build anchored block off of {}/{} height {}", &tip_burn_header_hash, &tip_block_hash, tip_height); 
-        
-        let mut header_reader_chainstate = chainstate_handle.reopen()?;            // uesd for reading block headers during an epoch
-        let mut chainstate = chainstate_handle.reopen_limited(execution_budget)?;  // used for processing a block up to the given limit
-
-        let mut builder = StacksBlockBuilder::make_block_builder(parent_stacks_header, proof, total_burn, pubkey_hash)?;
-
-        let cost_overflow_recovery_builder = builder.clone();     // used to construct the real anchored block if we run out of budget
-        
-        let mut epoch_tx = builder.epoch_begin(&mut chainstate)?;
-        builder.try_mine_tx(&mut epoch_tx, coinbase_tx)?;
-
-        let mut considered = HashSet::new();        // txids of all transactions we looked at
-        let mut mined_origin_nonces = HashMap::new();     // map addrs of mined transaction origins to the nonces we used
-        let mut mined_sponsor_nonces = HashMap::new();    // map addrs of mined transaction sponsors to the nonces we used
-
-        debug!("Begin scanning transaction mempool at {}/{} height={}", &tip_burn_header_hash, &tip_block_hash, tip_height);
+    /// Find the mempool transactions that could be mined on top of the given chain tip, walking
+    /// back through its ancestors and reading them a page at a time.  Stops early once
+    /// max_candidates transactions have been found, or if the deadline passes.
+    fn get_mempool_candidates(mempool: &MemPoolDB,
+                              header_reader_chainstate: &mut StacksChainState,
+                              tip_burn_header_hash: &BurnchainHeaderHash,
+                              tip_block_hash: &BlockHeaderHash,
+                              tip_height: u64,
+                              max_candidates: u64,
+                              deadline: Option<u128>) -> Result<Vec<MemPoolTxInfo>, Error> {
+        let mut candidates = vec![];
+        let (mut tip_burn_header_hash, mut tip_block_hash, mut tip_height) = (tip_burn_header_hash.clone(), tip_block_hash.clone(), tip_height);
 
         let mut next_timestamp = match MemPoolDB::get_next_timestamp(mempool.conn(), &tip_burn_header_hash, &tip_block_hash, 0)? {
             Some(ts) => ts,
             None => {
                 // walk back to where the first transaction we can mine can be found
-                match StacksBlockBuilder::walk_mempool(mempool, &mut header_reader_chainstate, &tip_burn_header_hash, &tip_block_hash, tip_height)? {
+                match StacksBlockBuilder::walk_mempool(mempool, header_reader_chainstate, &tip_burn_header_hash, &tip_block_hash, tip_height)? {
                     Some((next_burn_bhh, next_block_bhh, next_height, next_timestamp)) => {
                         tip_burn_header_hash = next_burn_bhh;
                         tip_block_hash = next_block_bhh;
@@ -539,73 +695,50 @@ impl StacksBlockBuilder {
                         next_timestamp
                     },
                     None => {
-                        // no transactions anywhere, so mine an empty block
-                        let block = builder.mine_anchored_block(&mut epoch_tx);
-                        builder.epoch_finish(epoch_tx);
-                        return Ok(block);
+                        // no transactions anywhere
+                        return Ok(candidates);
                     }
                 }
             }
         };
 
-        // set to true if we exceed budget, and need to rebuild with known-good transactions.
-        let mut do_rebuild = false;
-
+        let mut offset = 0;
         loop {
-            debug!("Scan mempool transactions at {}/{} height={} starting at {}", &tip_burn_header_hash, &tip_block_hash, tip_height, next_timestamp);
-            let mut available_txs = match MemPoolDB::get_txs_at(mempool.conn(), &tip_burn_header_hash, &tip_block_hash, next_timestamp) {
-                Ok(txs) => txs,
-                Err(e) => {
-                    epoch_tx.rollback_block();
-                    return Err(e.into());
+            if let Some(deadline) = deadline {
+                if get_epoch_time_ms() >= deadline {
+                    debug!("Out of time scanning mempool at {}/{} height={}", &tip_burn_header_hash, &tip_block_hash, tip_height);
+                    break;
                 }
-            };
-            
-            debug!("Mempool has {} transactions at {}/{} height={} ts={}", available_txs.len(), &tip_burn_header_hash, &tip_block_hash, tip_height, next_timestamp);
+            }
 
-            for txinfo in available_txs.drain(..) {
-                // skip transactions early if we can
-                if considered.contains(&txinfo.tx.txid()) {
-                    continue;
-                }
-                if mined_origin_nonces.get(&txinfo.tx.origin_address()).is_some() {
-                    continue;
-                }
-                if let Some(sponsor_addr) = txinfo.tx.sponsor_address() {
-                    if mined_sponsor_nonces.get(&sponsor_addr).is_some() {
-                        continue;
-                    }
-                }
+            let remaining = max_candidates.saturating_sub(candidates.len() as u64);
+            if remaining == 0 {
+                debug!("Found {} mempool transactions; not scanning past {}/{} height={}", candidates.len(), &tip_burn_header_hash, &tip_block_hash, tip_height);
+                break;
+            }
 
-                considered.insert(txinfo.tx.txid());
+            let page_size = cmp::min(remaining, MEMPOOL_CANDIDATES_PAGE_SIZE);
 
-                match builder.try_mine_tx(&mut epoch_tx, &txinfo.tx) {
-                    Ok(_) => {},
-                    Err(Error::BlockTooBigError) => {
-                        // done mining -- our execution budget is exceeded.
-                        // Make the block from the transactions we did manage to get
-                        debug!("Block budget exceeded on tx {}", &txinfo.tx.txid());
-                        do_rebuild = true;
-                        continue;
-                    },
-                    Err(e) => {
-                        warn!("Failed to apply tx {}: {:?}", &txinfo.tx.txid(), &e);
-                        continue;
-                    }
-                }
+            debug!("Scan mempool transactions at {}/{} height={} starting at {} (offset {})", &tip_burn_header_hash, &tip_block_hash, tip_height, next_timestamp, offset);
+            let mut available_txs = MemPoolDB::get_txs_at(mempool.conn(), &tip_burn_header_hash, &tip_block_hash, next_timestamp, offset, page_size)?;
+            let num_txs = available_txs.len() as u64;
 
-                mined_origin_nonces.insert(txinfo.tx.origin_address(), txinfo.tx.get_origin_nonce());
-                if let (Some(sponsor_addr), Some(sponsor_nonce)) = (txinfo.tx.sponsor_address(), txinfo.tx.get_sponsor_nonce()) {
-                    mined_sponsor_nonces.insert(sponsor_addr, sponsor_nonce);
-                }
+            debug!("Mempool has {} transactions at {}/{} height={} ts={} offset={}", num_txs, &tip_burn_header_hash, &tip_block_hash, tip_height, next_timestamp, offset);
+            candidates.append(&mut available_txs);
+
+            if num_txs == page_size {
+                // there may be more at this timestamp
+                offset += num_txs;
+                continue;
             }
+            offset = 0;
 
             // page back
             next_timestamp = match MemPoolDB::get_next_timestamp(mempool.conn(), &tip_burn_header_hash, &tip_block_hash, next_timestamp)? {
                 Some(ts) => ts,
                 None => {
                     // walk back
-                    match StacksBlockBuilder::walk_mempool(mempool, &mut header_reader_chainstate, &tip_burn_header_hash, &tip_block_hash, tip_height)? {
+                    match StacksBlockBuilder::walk_mempool(mempool, header_reader_chainstate, &tip_burn_header_hash, &tip_block_hash, tip_height)? {
                         Some((next_burn_bhh, next_block_bhh, next_height, next_timestamp)) => {
                             tip_burn_header_hash = next_burn_bhh;
                             tip_block_hash = next_block_bhh;
@@ -621,6 +754,163 @@ impl StacksBlockBuilder {
             };
         }
 
+        Ok(candidates)
+    }
+
+    /// Re-arrange the candidates so that each origin account's transactions are tried in nonce
+    /// order, while keeping the positions the selector chose for that account.
+    fn sort_candidates_by_origin_nonce(candidates: &mut Vec<MemPoolTxInfo>) {
+        let mut positions : HashMap<StacksAddress, Vec<usize>> = HashMap::new();
+        for (i, txinfo) in candidates.iter().enumerate() {
+            positions.entry(txinfo.metadata.origin_address.clone()).or_insert_with(Vec::new).push(i);
+        }
+
+        let mut slots : Vec<Option<MemPoolTxInfo>> = candidates.drain(..).map(Some).collect();
+        for idxs in positions.values() {
+            let mut sender_txs : Vec<MemPoolTxInfo> = idxs.iter().map(|i| slots[*i].take().expect("BUG: candidate slot already taken")).collect();
+            sender_txs.sort_by_key(|txinfo| txinfo.metadata.origin_nonce);
+            for (i, txinfo) in idxs.iter().zip(sender_txs.into_iter()) {
+                slots[*i] = Some(txinfo);
+            }
+        }
+
+        candidates.extend(slots.into_iter().map(|txinfo_opt| txinfo_opt.expect("BUG: candidate slot left empty")));
+    }
+
+    /// Has this transaction's origin or sponsor nonce already been used in this fork?
+    /// The mempool keeps transactions that were mined in ancestor blocks, so the miner sees
+    /// plenty of these; they are not worth reporting as nonce mismatches.
+    fn has_stale_nonce<T: ClarityConnection>(clarity_tx: &mut T, tx: &StacksTransaction) -> bool {
+        let origin_account = StacksChainState::get_account(clarity_tx, &tx.origin_address().into());
+        if tx.get_origin_nonce() < origin_account.nonce {
+            return true;
+        }
+
+        if let Some(sponsor_address) = tx.sponsor_address() {
+            let payer_account = StacksChainState::get_account(clarity_tx, &sponsor_address.into());
+            if tx.get_payer().nonce() < payer_account.nonce {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Given access to the mempool, mine an anchored block with no more than the given execution
+    /// cost, choosing transactions according to the given settings.  Returns the block, and the
+    /// list of mempool transactions that were skipped and why.
+    pub fn build_anchored_block_with_settings(chainstate_handle: &StacksChainState,       // not directly used; used as a handle to open other chainstates
+                                              mempool: &MemPoolDB,
+                                              parent_stacks_header: &StacksHeaderInfo,    // Stacks header we're building off of
+                                              total_burn: u64,                            // the burn so far on the burnchain (i.e. from the last burnchain block)
+                                              proof: VRFProof,                            // proof over the burnchain's last seed
+                                              pubkey_hash: Hash160,
+                                              coinbase_tx: &StacksTransaction,
+                                              execution_budget: ExecutionCost,
+                                              settings: &BlockBuilderSettings) -> Result<(StacksBlock, Vec<TransactionSkip>), Error> {
+
+        if let TransactionPayload::Coinbase(..) = coinbase_tx.payload {} else {
+            return Err(Error::MemPoolError("Not a coinbase transaction".to_string()));
+        }
+
+        let deadline = settings.max_assembly_time_ms.map(|ms| get_epoch_time_ms() + (ms as u128));
+        let (tip_burn_header_hash, tip_block_hash, tip_height) = (parent_stacks_header.burn_header_hash.clone(), parent_stacks_header.anchored_header.block_hash(), parent_stacks_header.block_height);
+
+        debug!("Build anchored block off of {}/{} height {}", &tip_burn_header_hash, &tip_block_hash, tip_height); 
+        
+        let mut header_reader_chainstate = chainstate_handle.reopen()?;            // uesd for reading block headers during an epoch
+        let mut chainstate = chainstate_handle.reopen_limited(execution_budget)?;  // used for processing a block up to the given limit
+
+        debug!("Begin scanning transaction mempool at {}/{} height={}", &tip_burn_header_hash, &tip_block_hash, tip_height);
+        let mut candidates = StacksBlockBuilder::get_mempool_candidates(mempool, &mut header_reader_chainstate, &tip_burn_header_hash, &tip_block_hash, tip_height, settings.max_mempool_candidates, deadline)?;
+
+        settings.selector.order_candidates(&mut candidates);
+        StacksBlockBuilder::sort_candidates_by_origin_nonce(&mut candidates);
+
+        let mut builder = StacksBlockBuilder::make_block_builder(parent_stacks_header, proof, total_burn, pubkey_hash)?;
+
+        let cost_overflow_recovery_builder = builder.clone();     // used to construct the real anchored block if we run out of budget
+        
+        let mut epoch_tx = builder.epoch_begin(&mut chainstate)?;
+        builder.try_mine_tx(&mut epoch_tx, coinbase_tx)?;
+
+        let mut considered = HashSet::new();        // txids of all transactions we looked at
+        let mut mined_origin_counts : HashMap<StacksAddress, u64> = HashMap::new();     // map addrs of mined transaction origins to how many of their txs we mined
+        let mut mined_sponsor_counts : HashMap<StacksAddress, u64> = HashMap::new();    // map addrs of mined transaction sponsors to how many of their txs we mined
+        let mut skipped = vec![];
+
+        // set to true if we exceed budget, and need to rebuild with known-good transactions.
+        let mut do_rebuild = false;
+
+        for txinfo in candidates.drain(..) {
+            let txid = txinfo.tx.txid();
+            if considered.contains(&txid) {
+                continue;
+            }
+            considered.insert(txid.clone());
+
+            if let Some(deadline) = deadline {
+                if get_epoch_time_ms() >= deadline {
+                    skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::Timeout });
+                    continue;
+                }
+            }
+
+            if !settings.selector.admit(&txinfo.tx) {
+                skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::Filtered });
+                continue;
+            }
+
+            // skip transactions early if we can
+            let origin_addr = txinfo.tx.origin_address();
+            let sponsor_addr_opt = txinfo.tx.sponsor_address();
+            let sender_at_limit = mined_origin_counts.get(&origin_addr).cloned().unwrap_or(0) >= settings.max_txs_per_sender ||
+                match sponsor_addr_opt {
+                    Some(ref sponsor_addr) => mined_sponsor_counts.get(sponsor_addr).cloned().unwrap_or(0) >= settings.max_txs_per_sender,
+                    None => false
+                };
+
+            if sender_at_limit {
+                skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::SenderLimit });
+                continue;
+            }
+
+            if StacksBlockBuilder::has_stale_nonce(&mut epoch_tx, &txinfo.tx) {
+                skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::StaleNonce });
+                continue;
+            }
+
+            if let Err(e) = StacksChainState::check_transaction_nonces(&mut epoch_tx, &txinfo.tx) {
+                debug!("Skip tx {}: {}", &txid, &e);
+                skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::BadNonce });
+                continue;
+            }
+
+            match builder.try_mine_tx(&mut epoch_tx, &txinfo.tx) {
+                Ok(_) => {},
+                Err(Error::BlockTooBigError) => {
+                    // done mining -- our execution budget is exceeded.
+                    // Make the block from the transactions we did manage to get
+                    debug!("Block budget exceeded on tx {}", &txid);
+                    skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::CostLimit });
+                    do_rebuild = true;
+                    continue;
+                },
+                Err(e) => {
+                    warn!("Failed to apply tx {}: {:?}", &txid, &e);
+                    skipped.push(TransactionSkip { txid, reason: TransactionSkipReason::RuntimeError(format!("{:?}", &e)) });
+                    continue;
+                }
+            }
+
+            *mined_origin_counts.entry(origin_addr).or_insert(0) += 1;
+            if let Some(sponsor_addr) = sponsor_addr_opt {
+                *mined_sponsor_counts.entry(sponsor_addr).or_insert(0) += 1;
+            }
+        }
+
+        debug!("Skipped {} mempool transactions building off of {}/{}", skipped.len(), &tip_burn_header_hash, &tip_block_hash);
+
         if do_rebuild {
             // re-build a block using the txs that we could mine.
             // TODO: this is only necessary because there's no way to retroatively abort a
@@ -631,13 +921,14 @@ impl StacksBlockBuilder {
             let txs = mem::replace(&mut builder.txs, vec![]);
             epoch_tx.rollback_block();
 
-            return StacksBlockBuilder::make_anchored_block_from_txs(cost_overflow_recovery_builder, &mut chainstate, txs);
+            let block = StacksBlockBuilder::make_anchored_block_from_txs(cost_overflow_recovery_builder, &mut chainstate, txs)?;
+            Ok((block, skipped))
         }
         else {
             // save the block so we can build microblocks off of it
             let block = builder.mine_anchored_block(&mut epoch_tx);
             builder.epoch_finish(epoch_tx);
-            Ok(block)
+            Ok((block, skipped))
         }
     }
 }
@@ -3684,6 +3975,126 @@ pub mod test {
         }
     }
     
+    #[test]
+    fn test_build_anchored_blocks_with_settings() {
+        let mut privks = vec![];
+        let mut balances = vec![];
+        let mut addrs = vec![];
+        let num_blocks = 3;
+
+        for _ in 0..3 {
+            let privk = StacksPrivateKey::new();
+            let addr = StacksAddress::from_public_keys(C32_ADDRESS_VERSION_TESTNET_SINGLESIG, &AddressHashMode::SerializeP2PKH, 1, &vec![StacksPublicKey::from_private(&privk)]).unwrap();
+
+            privks.push(privk);
+            balances.push((addr.to_account_principal(), 100000000));
+            addrs.push(addr);
+        }
+
+        let mut peer_config = TestPeerConfig::new("test_build_anchored_blocks_with_settings", 2012, 2013);
+        peer_config.initial_balances = balances;
+
+        let mut peer = TestPeer::new(peer_config);
+
+        let chainstate_path = peer.chainstate_path.clone();
+
+        let recipient_addr_str = "ST1RFD5Q2QPK3E0F08HG9XDX7SSC7CNRS0QR0SGEV";
+        let recipient = StacksAddress::from_string(recipient_addr_str).unwrap();
+
+        for tenure_id in 0..num_blocks {
+            // send transactions to the mempool
+            let tip = BurnDB::get_canonical_burn_chain_tip(&peer.burndb.as_ref().unwrap().conn()).unwrap();
+            let mut skipped = vec![];
+
+            let (burn_ops, stacks_block, microblocks) = peer.make_tenure(|ref mut miner, ref mut burndb, ref mut chainstate, vrf_proof, ref parent_opt, ref parent_microblock_header_opt| {
+                let parent_tip = match parent_opt {
+                    None => {
+                        StacksChainState::get_genesis_header_info(&chainstate.headers_db).unwrap()
+                    }
+                    Some(block) => {
+                        let ic = burndb.index_conn();
+                        let snapshot = BurnDB::get_block_snapshot_for_winning_stacks_block(&ic, &tip.burn_header_hash, &block.block_hash()).unwrap().unwrap();      // succeeds because we don't fork
+                        StacksChainState::get_anchored_block_header_info(&chainstate.headers_db, &snapshot.burn_header_hash, &snapshot.winning_stacks_block_hash).unwrap().unwrap()
+                    }
+                };
+                
+                let parent_header_hash = parent_tip.anchored_header.block_hash();
+                let parent_tip_bhh = parent_tip.burn_header_hash.clone();
+
+                let mut mempool = MemPoolDB::open(false, 0x80000000, &chainstate_path).unwrap();
+
+                let coinbase_tx = make_coinbase(miner, tenure_id);
+
+                if tenure_id == 1 {
+                    // three from the first sender, but only two of them fit the per-sender limit.
+                    // (the mempool would reject all but the first as having a future nonce)
+                    for nonce in 0..3 {
+                        let stx_transfer = make_user_stacks_transfer(&privks[0], nonce, 400, &recipient.to_account_principal(), 1);
+                        let mut tx_bytes = vec![];
+                        stx_transfer.consensus_serialize(&mut tx_bytes).unwrap();
+                        mempool.submit_raw(&parent_tip_bhh, &parent_header_hash, tx_bytes).unwrap();
+                    }
+
+                    // the second sender is denied
+                    let stx_transfer = make_user_stacks_transfer(&privks[1], 0, 500, &recipient.to_account_principal(), 1);
+                    mempool.submit(&parent_tip_bhh, &parent_header_hash, stx_transfer).unwrap();
+
+                    let stx_transfer = make_user_stacks_transfer(&privks[2], 0, 250, &recipient.to_account_principal(), 1);
+                    mempool.submit(&parent_tip_bhh, &parent_header_hash, stx_transfer).unwrap();
+                }
+
+                let mut denied_principals = HashSet::new();
+                denied_principals.insert(addrs[1].to_account_principal());
+
+                let settings = BlockBuilderSettings {
+                    selector: Box::new(AllowDenySelector {
+                        inner: Box::new(FeeRateSelector {}),
+                        allowed_contracts: HashSet::new(),
+                        denied_contracts: HashSet::new(),
+                        allowed_principals: HashSet::new(),
+                        denied_principals
+                    }),
+                    max_txs_per_sender: 2,
+                    max_assembly_time_ms: Some(60_000),
+                    max_mempool_candidates: MAX_MEMPOOL_CANDIDATES
+                };
+
+                let (anchored_block, skipped_txs) = StacksBlockBuilder::build_anchored_block_with_settings(chainstate, &mempool, &parent_tip, tip.total_burn, vrf_proof, Hash160([tenure_id as u8; 20]), &coinbase_tx, ExecutionCost::max_value(), &settings).unwrap();
+                skipped = skipped_txs;
+                (anchored_block, vec![])
+            });
+
+            peer.next_burnchain_block(burn_ops.clone());
+            peer.process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+            let mut reasons : Vec<TransactionSkipReason> = skipped.iter().map(|skip| skip.reason.clone()).collect();
+            reasons.sort_by_key(|reason| format!("{:?}", reason));
+
+            if tenure_id == 1 {
+                // coinbase, the first sender's first two transactions, and the third sender's
+                assert_eq!(stacks_block.txs.len(), 4);
+                assert_eq!(stacks_block.txs[1].origin_address(), addrs[0]);
+                assert_eq!(stacks_block.txs[1].get_origin_nonce(), 0);
+                assert_eq!(stacks_block.txs[2].origin_address(), addrs[0]);
+                assert_eq!(stacks_block.txs[2].get_origin_nonce(), 1);
+                assert_eq!(stacks_block.txs[3].origin_address(), addrs[2]);
+                assert_eq!(reasons, vec![TransactionSkipReason::Filtered, TransactionSkipReason::SenderLimit]);
+            }
+            else if tenure_id == 2 {
+                // the first sender's last transaction gets picked up from the ancestor's mempool,
+                // and the ones mined in the ancestor are reported as stale
+                assert_eq!(stacks_block.txs.len(), 2);
+                assert_eq!(stacks_block.txs[1].origin_address(), addrs[0]);
+                assert_eq!(stacks_block.txs[1].get_origin_nonce(), 2);
+                assert_eq!(reasons, vec![TransactionSkipReason::Filtered, TransactionSkipReason::StaleNonce, TransactionSkipReason::StaleNonce, TransactionSkipReason::StaleNonce]);
+            }
+            else {
+                assert_eq!(stacks_block.txs.len(), 1);
+                assert_eq!(skipped.len(), 0);
+            }
+        }
+    }
+
     #[test]
     fn test_build_anchored_blocks_multiple_chaintips() {
         let mut privks = vec![];
@@ -142,6 +142,7 @@ pub enum Error {
     ReadError(io::Error),
     WriteError(io::Error),
     MemPoolError(String),
+    SnapshotError(String),
 }
 
 impl fmt::Display for Error {
@@ -166,6 +167,7 @@ impl fmt::Display for Error {
             Error::ReadError(ref e) => fmt::Display::fmt(e, f),
             Error::WriteError(ref e) => fmt::Display::fmt(e, f),
             Error::MemPoolError(ref s) => fmt::Display::fmt(s, f),
+            Error::SnapshotError(ref s) => fmt::Display::fmt(s, f),
         }
     }
 }
@@ -192,6 +194,7 @@ impl error::Error for Error {
             Error::ReadError(ref e) => Some(e),
             Error::WriteError(ref e) => Some(e),
             Error::MemPoolError(ref _s) => None,
+            Error::SnapshotError(ref _s) => None,
         }
     }
 }
@@ -756,6 +756,28 @@ impl StacksTransactionSigner {
         })
     }
 
+    /// Pick up signing a partially-signed transaction where its last signer left off, by
+    /// replaying the signatures it already carries.  If the sponsor has begun signing, only
+    /// sponsor signatures may be added.
+    pub fn new_partial(tx: &StacksTransaction) -> Result<StacksTransactionSigner, net_error> {
+        let origin_sighash = tx.auth.origin().replay_signatures(&tx.sign_begin(), &TransactionAuthFlags::AuthStandard)?;
+        let (sighash, origin_done) = match tx.auth.sponsor() {
+            Some(sponsor_condition) if sponsor_condition.num_signatures() > 0 => {
+                let sponsor_sighash = sponsor_condition.replay_signatures(&origin_sighash, &TransactionAuthFlags::AuthSponsored)?;
+                (sponsor_sighash, true)
+            },
+            _ => (origin_sighash, false)
+        };
+
+        Ok(StacksTransactionSigner {
+            tx: tx.clone(),
+            sighash,
+            origin_done,
+            check_oversign: true,
+            check_overlap: true
+        })
+    }
+
     pub fn resume(&mut self, tx: &StacksTransaction) -> () {
         self.tx = tx.clone()
     }
@@ -2470,6 +2492,48 @@ mod test {
         }
     } 
     
+    #[test]
+    fn tx_stacks_transaction_sign_verify_standard_p2sh_partial() {
+        let privk_1 = StacksPrivateKey::from_hex("6d430bb91222408e7706c9001cfaeb91b08c2be6d5ac95779ab52c6b431950e001").unwrap();
+        let privk_2 = StacksPrivateKey::from_hex("2a584d899fed1d24e26b524f202763c8ab30260167429f157f1c119f550fa6af01").unwrap();
+        let privk_3 = StacksPrivateKey::from_hex("d5200dee706ee53ae98a03fba6cf4fdcc5084c30cfa9e1b3462dcdeaa3e0f1d201").unwrap();
+
+        let pubk_1 = StacksPublicKey::from_private(&privk_1);
+        let pubk_2 = StacksPublicKey::from_private(&privk_2);
+        let pubk_3 = StacksPublicKey::from_private(&privk_3);
+
+        let origin_auth = TransactionAuth::Standard(TransactionSpendingCondition::new_multisig_p2sh(2, vec![pubk_1.clone(), pubk_2.clone(), pubk_3.clone()]).unwrap());
+
+        let txs = tx_stacks_transaction_test_txs(&origin_auth);
+
+        for tx in txs {
+            // first co-signer skips key 1 and signs with key 2
+            let mut tx_signer = StacksTransactionSigner::new_partial(&tx).unwrap();
+            tx_signer.append_origin(&pubk_1).unwrap();
+            tx_signer.sign_origin(&privk_2).unwrap();
+            assert!(tx_signer.get_tx().is_none());
+            let partial_tx = tx_signer.get_tx_incomplete();
+
+            // partially-signed transactions don't verify
+            assert!(partial_tx.verify().is_err());
+
+            // second co-signer picks up from the partially-signed transaction
+            let mut tx_signer = StacksTransactionSigner::new_partial(&partial_tx).unwrap();
+            tx_signer.sign_origin(&privk_3).unwrap();
+            let signed_tx = tx_signer.get_tx().unwrap();
+
+            assert_eq!(signed_tx.auth().origin().num_signatures(), 2);
+            signed_tx.verify().unwrap();
+
+            // same as signing it all at once
+            let mut tx_signer = StacksTransactionSigner::new(&tx);
+            tx_signer.append_origin(&pubk_1).unwrap();
+            tx_signer.sign_origin(&privk_2).unwrap();
+            tx_signer.sign_origin(&privk_3).unwrap();
+            assert_eq!(tx_signer.get_tx().unwrap(), signed_tx);
+        }
+    }
+
     #[test]
     fn tx_stacks_transaction_sign_verify_sponsored_p2sh() {
         let origin_privk = StacksPrivateKey::from_hex("807bbe9e471ac976592cc35e3056592ecc0f778ee653fced3b491a122dd8d59701").unwrap();
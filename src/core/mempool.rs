@@ -49,11 +49,14 @@ use util::db::{DBConn, DBTx, FromRow};
 use util::db::FromColumn;
 use util::db::query_rows;
 use util::db::query_row;
+use util::db::query_row_columns;
 use util::db::Error as db_error;
 use util::get_epoch_time_secs;
 use util::db::tx_begin_immediate;
 use util::db::tx_busy_handler;
 
+use monitoring;
+
 use core::FIRST_STACKS_BLOCK_HASH;
 use core::FIRST_BURNCHAIN_BLOCK_HASH;
 
@@ -194,7 +197,9 @@ pub struct MemPoolDB {
 
 pub struct MemPoolTx<'a> {
     tx: DBTx<'a>,
-    admitter: &'a mut MemPoolAdmitter
+    admitter: &'a mut MemPoolAdmitter,
+    num_txs_added: u64,
+    num_txs_removed: u64
 }
 
 impl<'a> Deref for MemPoolTx<'a> {
@@ -214,12 +219,18 @@ impl<'a> MemPoolTx<'a> {
     pub fn new(tx: DBTx<'a>, admitter: &'a mut MemPoolAdmitter) -> MemPoolTx<'a> {
         MemPoolTx {
             tx,
-            admitter
+            admitter,
+            num_txs_added: 0,
+            num_txs_removed: 0
         }
     }
     
+    /// Commit the transaction, and move the mempool size gauge by however many rows it added
+    /// and removed (the gauge is seeded once, when the mempool is opened).
     pub fn commit(self) -> Result<(), db_error> {
-        self.tx.commit().map_err(db_error::SqliteError)
+        self.tx.commit().map_err(db_error::SqliteError)?;
+        monitoring::update_mempool_size(self.num_txs_added, self.num_txs_removed);
+        Ok(())
     }
 }
 
@@ -278,6 +289,8 @@ impl MemPoolDB {
             // instantiate!
             MemPoolDB::instantiate_mempool_db(&mut conn)?;
         }
+
+        monitoring::set_mempool_size(MemPoolDB::get_num_txs(&conn)?);
         
         Ok(MemPoolDB {
             db: conn,
@@ -300,6 +313,13 @@ impl MemPoolDB {
             .and_then(|row_opt: Option<i64>| Ok(row_opt.is_some()))
     }
 
+    fn db_has_tx_by_addresses(conn: &DBConn, origin_address: &StacksAddress, origin_nonce: u64, sponsor_address: &StacksAddress, sponsor_nonce: u64) -> Result<bool, db_error> {
+        let sql = "SELECT 1 FROM mempool WHERE origin_address = ?1 AND origin_nonce = ?2 AND sponsor_address = ?3 AND sponsor_nonce = ?4";
+        let args : &[&dyn ToSql] = &[&origin_address.to_string(), &u64_to_sql(origin_nonce)?, &sponsor_address.to_string(), &u64_to_sql(sponsor_nonce)?];
+        query_row(conn, sql, args)
+            .and_then(|row_opt: Option<i64>| Ok(row_opt.is_some()))
+    }
+
     pub fn get_tx(conn: &DBConn, txid: &Txid) -> Result<Option<MemPoolTxInfo>, db_error> {
         query_row(conn, "SELECT * FROM mempool WHERE txid = ?1", &[txid as &dyn ToSql])
     }
@@ -308,6 +328,13 @@ impl MemPoolDB {
         query_row(conn, "SELECT estimated_fee FROM mempool WHERE txid = ?1", &[txid as &dyn ToSql])
     }
 
+    /// Count the transactions across all tips
+    pub fn get_num_txs(conn: &DBConn) -> Result<u64, db_error> {
+        let num_txs = query_row::<i64, _>(conn, "SELECT COUNT(*) FROM mempool", NO_PARAMS)?
+            .unwrap_or(0);
+        Ok(num_txs as u64)
+    }
+
     /// Get all transactions across all tips
     #[cfg(test)]
     pub fn get_all_txs(conn: &DBConn) -> Result<Vec<MemPoolTxInfo>, db_error> {
@@ -323,10 +350,11 @@ impl MemPoolDB {
         query_row(conn, sql, args)
     }
     
-    /// Get all transactions at a particular timestamp and chain tip
-    pub fn get_txs_at(conn: &DBConn, burn_header_hash: &BurnchainHeaderHash, block_header_hash: &BlockHeaderHash, timestamp: u64) -> Result<Vec<MemPoolTxInfo>, db_error> {
-        let sql = "SELECT * FROM mempool WHERE accept_time = ?1 AND burn_header_hash = ?2 AND block_header_hash = ?3 ORDER BY estimated_fee DESC";
-        let args : &[&dyn ToSql] = &[&u64_to_sql(timestamp)?, burn_header_hash, block_header_hash];
+    /// Get up to `limit` transactions at a particular timestamp and chain tip, skipping the first
+    /// `offset` of them
+    pub fn get_txs_at(conn: &DBConn, burn_header_hash: &BurnchainHeaderHash, block_header_hash: &BlockHeaderHash, timestamp: u64, offset: u64, limit: u64) -> Result<Vec<MemPoolTxInfo>, db_error> {
+        let sql = "SELECT * FROM mempool WHERE accept_time = ?1 AND burn_header_hash = ?2 AND block_header_hash = ?3 ORDER BY estimated_fee DESC, txid LIMIT ?4 OFFSET ?5";
+        let args : &[&dyn ToSql] = &[&u64_to_sql(timestamp)?, burn_header_hash, block_header_hash, &u64_to_sql(limit)?, &u64_to_sql(offset)?];
         let rows = query_rows::<MemPoolTxInfo, _>(conn, &sql, args)?;
         Ok(rows)
     }
@@ -375,6 +403,67 @@ impl MemPoolDB {
         Ok(rows)
     }
 
+    /// Get the txids of the most recently-accepted transactions across all chain tips, newest
+    /// first.  Used to summarize our mempool when asking a peer for the transactions we lack.
+    pub fn get_recent_txids(conn: &DBConn, count: u64) -> Result<Vec<Txid>, db_error> {
+        let sql = "SELECT txid FROM mempool ORDER BY accept_time DESC LIMIT ?1".to_string();
+        let args : &[&dyn ToSql] = &[&u64_to_sql(count)?];
+        query_row_columns::<Txid, _>(conn, &sql, args, "txid")
+    }
+
+    /// Get the most recently-accepted transactions across all chain tips, newest first.
+    pub fn get_recent_txs(conn: &DBConn, count: u64) -> Result<Vec<MemPoolTxInfo>, db_error> {
+        let sql = "SELECT * FROM mempool ORDER BY accept_time DESC LIMIT ?1";
+        let args : &[&dyn ToSql] = &[&u64_to_sql(count)?];
+        query_rows::<MemPoolTxInfo, _>(conn, sql, args)
+    }
+
+    /// Get a page of pending transactions' metadata across all chain tips, in the order in which
+    /// they were accepted (ties broken by txid).  If `after` is given, only transactions that come
+    /// strictly after that (accept time, txid) position are considered -- if it has no txid, all
+    /// transactions accepted at that time are skipped.  If `address` is given, only transactions
+    /// that it originates or sponsors are considered.
+    /// At most `count` rows are returned.  Pass the last row's (accept time, txid) back as `after`
+    /// to fetch the next page.
+    pub fn get_tx_metadata_page(conn: &DBConn, address: Option<&StacksAddress>, after: Option<(u64, Option<&Txid>)>, count: u64) -> Result<Vec<MemPoolTxMetadata>, db_error> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        let sql = "SELECT 
+            txid,
+            origin_address,
+            origin_nonce,
+            sponsor_address,
+            sponsor_nonce,
+            estimated_fee,
+            fee_rate,
+            length,
+            burn_header_hash,
+            block_header_hash,
+            height,
+            accept_time
+            FROM mempool WHERE (accept_time > ?1 OR (accept_time = ?1 AND txid > ?2)) AND (?3 IS NULL OR origin_address = ?3 OR sponsor_address = ?3) ORDER BY accept_time ASC, txid ASC LIMIT ?4";
+
+        let (after_time, after_txid) = match after {
+            Some((ts, txid_opt)) => (u64_to_sql(ts)?, txid_opt.cloned()),
+            None => (-1, None)
+        };
+        let address_str = address.map(|addr| addr.to_string());
+        let args : &[&dyn ToSql] = &[&after_time, &after_txid, &address_str, &u64_to_sql(count)?];
+        query_rows::<MemPoolTxMetadata, _>(conn, sql, args)
+    }
+
+    /// Get the distinct nonces, in ascending order, of all pending transactions across all chain
+    /// tips that the given address either originates or sponsors.
+    pub fn get_pending_nonces(conn: &DBConn, address: &StacksAddress) -> Result<Vec<u64>, db_error> {
+        let sql = "SELECT origin_nonce AS nonce FROM mempool WHERE origin_address = ?1 
+                   UNION SELECT sponsor_nonce AS nonce FROM mempool WHERE sponsor_address = ?1
+                   ORDER BY nonce ASC";
+        let args : &[&dyn ToSql] = &[&address.to_string()];
+        query_rows::<u64, _>(conn, sql, args)
+    }
+
     /// Get a transaction's metadata in a chain tip, given its origin and sponsor metadata.
     /// Faster than getting the MemPoolTxInfo, since no deserialization will be needed.
     /// Used to see if there exists a transaction with this info, so as to implement replace-by-fee
@@ -431,6 +520,9 @@ impl MemPoolDB {
             }
         }
 
+        // a replaced row (from any chain tip) doesn't change the mempool size
+        let replaces = MemPoolDB::db_has_tx_by_addresses(tx, origin_address, origin_nonce, sponsor_address, sponsor_nonce).map_err(MemPoolRejection::DBError)?;
+
         let sql = "INSERT OR REPLACE INTO mempool (
             txid,
             origin_address,
@@ -463,6 +555,9 @@ impl MemPoolDB {
             &tx_bytes];
 
         tx.execute(sql, args).map_err(|e| MemPoolRejection::DBError(db_error::SqliteError(e)))?;
+        if !replaces {
+            tx.num_txs_added += 1;
+        }
         Ok(())
     }
 
@@ -472,7 +567,8 @@ impl MemPoolDB {
         let sql = "DELETE FROM mempool WHERE height < ?1";
         let args : &[&dyn ToSql] = &[&u64_to_sql(min_height)?];
 
-        tx.execute(sql, args).map_err(db_error::SqliteError)?;
+        let num_removed = tx.execute(sql, args).map_err(db_error::SqliteError)?;
+        tx.num_txs_removed += num_removed as u64;
         Ok(())
     }
 
@@ -596,13 +692,22 @@ mod tests {
         StacksTransaction, TransactionSmartContract, TransactionContractCall, StacksAddress };
 
     use util::db::{DBConn, FromRow};
+    use util::db::u64_to_sql;
+    use rusqlite::types::ToSql;
     use super::MemPoolDB;
 
     use burnchains::BurnchainHeaderHash;
+    use burnchains::Txid;
     use chainstate::stacks::test::codec_all_transactions;
     use chainstate::stacks::db::test::chainstate_path;
     use chainstate::stacks::db::test::instantiate_chainstate;
 
+    use core::FIRST_BURNCHAIN_BLOCK_HASH;
+    use core::FIRST_STACKS_BLOCK_HASH;
+    use net::mempool::test::{make_mempool_txs, submit_mempool_txs};
+
+    use std::collections::HashSet;
+
     const FOO_CONTRACT: &'static str = "(define-public (foo) (ok 1))
                                         (define-public (bar (x uint)) (ok x))";
     const SK_1: &'static str = "a1289f6438855da7decf9b61b852c882c398cff1446b2a0f823538aa2ebef92e01";
@@ -736,6 +841,7 @@ mod tests {
             assert!(MemPoolDB::db_has_tx(&mempool_tx, &old_txid).unwrap());
             assert!(!MemPoolDB::db_has_tx(&mempool_tx, &txid).unwrap());
         }
+        assert_eq!(mempool_tx.num_txs_added, num_txs);
         mempool_tx.commit().unwrap();
 
         eprintln!("get all txs");
@@ -753,9 +859,128 @@ mod tests {
         eprintln!("garbage-collect");
         let mut mempool_tx = mempool.tx_begin().unwrap();
         MemPoolDB::garbage_collect(&mut mempool_tx, 101).unwrap();
+        assert_eq!(mempool_tx.num_txs_removed, num_txs);
         mempool_tx.commit().unwrap();
         
         let txs = MemPoolDB::get_txs_after(&mempool.db, &BurnchainHeaderHash([0x1; 32]), &BlockHeaderHash([0x2; 32]), 0, num_txs).unwrap();
         assert_eq!(txs.len(), 0);
     }
+
+    #[test]
+    fn mempool_db_tx_metadata_page_and_pending_nonces() {
+        let chainstate = instantiate_chainstate(false, 0x80000000, "mempool_db_tx_metadata_page_and_pending_nonces");
+        let chainstate_path = chainstate_path("mempool_db_tx_metadata_page_and_pending_nonces");
+        let mut mempool = MemPoolDB::open(false, 0x80000000, &chainstate_path).unwrap();
+
+        let mut txs = codec_all_transactions(&TransactionVersion::Testnet, 0x80000000, &TransactionAnchorMode::Any, &TransactionPostConditionMode::Allow);
+        assert!(txs.len() >= 10);
+
+        let addr_1 = StacksAddress { version: 22, bytes: Hash160([0x11; 20]) };
+        let addr_2 = StacksAddress { version: 22, bytes: Hash160([0x22; 20]) };
+        let addr_3 = StacksAddress { version: 22, bytes: Hash160([0x33; 20]) };
+
+        let mut mempool_tx = mempool.tx_begin().unwrap();
+        for (i, mut tx) in txs.drain(..).take(10).enumerate() {
+            // even txs come from addr_1, odd txs from addr_2.  Each tx's nonce is its index,
+            // and every two txs share an accept time.
+            let address = if i % 2 == 0 { addr_1.clone() } else { addr_2.clone() };
+            let nonce = i as u64;
+
+            tx.set_fee_rate(100 + nonce);
+
+            let txid = tx.txid();
+            let mut tx_bytes = vec![];
+            tx.consensus_serialize(&mut tx_bytes).unwrap();
+            let estimated_fee = tx.get_fee_rate() * (tx_bytes.len() as u64);
+
+            MemPoolDB::try_add_tx(&mut mempool_tx, &BurnchainHeaderHash([0x1; 32]), &BlockHeaderHash([0x2; 32]), txid, tx_bytes, estimated_fee, tx.get_fee_rate(), 100, &address, nonce, &address, nonce).unwrap();
+
+            let args : &[&dyn ToSql] = &[&u64_to_sql(1000 + nonce / 2).unwrap(), &txid];
+            mempool_tx.execute("UPDATE mempool SET accept_time = ?1 WHERE txid = ?2", args).unwrap();
+        }
+        mempool_tx.commit().unwrap();
+
+        // pages hold exactly `count` rows, even when that splits a timestamp
+        let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), None, None, 3).unwrap();
+        assert_eq!(page.len(), 3);
+        assert_eq!(page.iter().map(|md| md.accept_time).collect::<Vec<u64>>(), vec![1000, 1000, 1001]);
+
+        // paging on the (accept time, txid) cursor visits every transaction exactly once
+        let mut all_txids = vec![];
+        let mut cursor : Option<(u64, Txid)> = None;
+        loop {
+            let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), None, cursor.as_ref().map(|(ts, txid)| (*ts, Some(txid))), 3).unwrap();
+            assert!(page.len() <= 3);
+            all_txids.extend(page.iter().map(|md| md.txid.clone()));
+            if page.len() < 3 {
+                break;
+            }
+            let last = page.last().unwrap();
+            cursor = Some((last.accept_time, last.txid.clone()));
+        }
+        assert_eq!(all_txids.len(), 10);
+        let mut sorted_txids = all_txids.clone();
+        sorted_txids.sort();
+        sorted_txids.dedup();
+        assert_eq!(sorted_txids.len(), 10);
+
+        // a cursor without a txid skips its whole timestamp
+        let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), None, Some((1001, None)), 100).unwrap();
+        assert_eq!(page.len(), 6);
+        assert!(page.iter().all(|md| md.accept_time > 1001));
+
+        let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), None, Some((1004, None)), 100).unwrap();
+        assert_eq!(page.len(), 0);
+
+        let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), None, None, 0).unwrap();
+        assert_eq!(page.len(), 0);
+
+        // filter by address
+        let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), Some(&addr_1), None, 100).unwrap();
+        assert_eq!(page.len(), 5);
+        assert!(page.iter().all(|md| md.origin_address == addr_1));
+
+        let page = MemPoolDB::get_tx_metadata_page(mempool.conn(), Some(&addr_3), None, 100).unwrap();
+        assert_eq!(page.len(), 0);
+
+        // pending nonces
+        assert_eq!(MemPoolDB::get_pending_nonces(mempool.conn(), &addr_1).unwrap(), vec![0, 2, 4, 6, 8]);
+        assert_eq!(MemPoolDB::get_pending_nonces(mempool.conn(), &addr_2).unwrap(), vec![1, 3, 5, 7, 9]);
+        assert_eq!(MemPoolDB::get_pending_nonces(mempool.conn(), &addr_3).unwrap(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn mempool_db_get_txs_at_pages() {
+        let chainstate = instantiate_chainstate(false, 0x80000000, "mempool_db_get_txs_at_pages");
+        let chainstate_path = chainstate_path("mempool_db_get_txs_at_pages");
+        let mut mempool = MemPoolDB::open(false, 0x80000000, &chainstate_path).unwrap();
+
+        let txs = make_mempool_txs(10);
+        submit_mempool_txs(&mut mempool, &txs);
+
+        // read everything back, three at a time
+        let mut txids = HashSet::new();
+        let mut num_read = 0;
+        let mut next_timestamp = MemPoolDB::get_next_timestamp(mempool.conn(), &FIRST_BURNCHAIN_BLOCK_HASH, &FIRST_STACKS_BLOCK_HASH, 0).unwrap();
+        while let Some(timestamp) = next_timestamp {
+            let mut offset = 0;
+            loop {
+                let page = MemPoolDB::get_txs_at(mempool.conn(), &FIRST_BURNCHAIN_BLOCK_HASH, &FIRST_STACKS_BLOCK_HASH, timestamp, offset, 3).unwrap();
+                assert!(page.len() <= 3);
+                num_read += page.len();
+                for txinfo in page.iter() {
+                    txids.insert(txinfo.tx.txid());
+                }
+                if page.len() < 3 {
+                    break;
+                }
+                offset += 3;
+            }
+            next_timestamp = MemPoolDB::get_next_timestamp(mempool.conn(), &FIRST_BURNCHAIN_BLOCK_HASH, &FIRST_STACKS_BLOCK_HASH, timestamp).unwrap();
+        }
+
+        // each transaction is read exactly once
+        assert_eq!(num_read, txs.len());
+        assert_eq!(txids, txs.iter().map(|tx| tx.txid()).collect::<HashSet<Txid>>());
+    }
 }
@@ -18,7 +18,7 @@
 */
 
 // This module contains the "main loop" that drives everything
-use burnchains::{Burnchain, BurnchainHeaderHash};
+use burnchains::{Burnchain, BurnchainHeaderHash, BurnchainReplay};
 use burnchains::Error as burnchain_error;
 use chainstate::burn::BlockHeaderHash;
 use util::log;
@@ -73,3 +73,21 @@ pub fn sync_burnchain_bitcoin(working_dir: &String, network_name: &String) -> Re
 
     Ok(new_height)
 }
+
+/// Rewind the burn DB to the given height and re-process the Bitcoin blocks above it,
+/// verifying that the recomputed consensus hashes match the ones we had.
+pub fn replay_burnchain_bitcoin(working_dir: &String, network_name: &String, rewind_height: u64) -> Result<BurnchainReplay, burnchain_error> {
+    use burnchains::bitcoin::indexer::BitcoinIndexer;
+
+    let mut burnchain = Burnchain::new(working_dir, &"bitcoin".to_string(), network_name)
+        .map_err(|e| {
+            error!("Failed to instantiate burn chain driver for {}: {:?}", network_name, e);
+            e
+        })?;
+
+    burnchain.rewind_and_replay::<BitcoinIndexer>(rewind_height)
+        .map_err(|e| {
+            error!("Failed to replay Bitcoin chain state for {} in {} from height {}", network_name, working_dir, rewind_height);
+            e
+        })
+}
@@ -33,6 +33,9 @@ extern crate ed25519_dalek;
 #[macro_use] extern crate lazy_static;
 extern crate sha2;
 extern crate sha3;
+extern crate sha1;
+extern crate base64;
+extern crate chacha20poly1305;
 extern crate ripemd160;
 extern crate regex;
 extern crate time;
@@ -60,6 +63,7 @@ pub mod address;
 pub mod burnchains;
 pub mod core;
 pub mod deps;
+pub mod monitoring;
 pub mod vm;
 
 pub mod clarity;
@@ -42,9 +42,13 @@ use blockstack_lib::chainstate::stacks::*;
 use blockstack_lib::util::hash::{hex_bytes, to_hex};
 use blockstack_lib::util::retry::LogReader;
 use blockstack_lib::chainstate::stacks::index::marf::MARF;
+use blockstack_lib::chainstate::stacks::index::storage::TrieStorageBackend;
 use blockstack_lib::chainstate::stacks::StacksBlockHeader;
+use blockstack_lib::chainstate::stacks::db::StacksChainState;
 use blockstack_lib::chainstate::burn::BlockHeaderHash;
 use blockstack_lib::burnchains::BurnchainHeaderHash;
+use blockstack_lib::net::db::PeerDB;
+use blockstack_lib::net::reputation::{PeerAdminAction, PeerSelector, get_decayed_reputations, apply_peer_admin_action, REPUTATION_DECAY_INTERVAL};
 
 use blockstack_lib::burnchains::bitcoin::spv;
 use blockstack_lib::burnchains::bitcoin::BitcoinNetworkType;
@@ -290,6 +294,251 @@ fn main() {
         return
     }
 
+    if argv[1] == "peerdb-reputation" {
+        if argv.len() < 3 {
+            eprintln!("Usage: {} peerdb-reputation PEER_DB", argv[0]);
+            process::exit(1);
+        }
+
+        let peerdb = PeerDB::open(&argv[2], false).expect("Failed to open peer DB");
+        let local_peer = PeerDB::get_local_peer(peerdb.conn()).expect("Failed to load local peer");
+        let reputations = get_decayed_reputations(peerdb.conn(), local_peer.network_id, REPUTATION_DECAY_INTERVAL)
+            .expect("Failed to load peer reputations");
+
+        for reputation in reputations.iter() {
+            println!("{} {} score={} whitelisted={} blacklisted={}",
+                     &reputation.addr, to_hex(&reputation.public_key.to_bytes_compressed()), reputation.score, reputation.whitelisted, reputation.blacklisted);
+        }
+        return
+    }
+
+    if argv[1] == "peerdb-admin" {
+        if argv.len() < 5 {
+            eprintln!("Usage: {} peerdb-admin PEER_DB ban|unban|allow|disallow IP:PORT|PUBLIC_KEY [DURATION_SECS]", argv[0]);
+            process::exit(1);
+        }
+
+        let duration = if argv.len() >= 6 {
+            Some(argv[5].parse::<u64>().expect("Bad duration"))
+        }
+        else {
+            None
+        };
+
+        let action = PeerAdminAction::from_name(&argv[3], duration).unwrap_or_else(|| {
+            eprintln!("Unrecognized action '{}'", &argv[3]);
+            process::exit(1);
+        });
+
+        let selector = PeerSelector::parse(&argv[4]).unwrap_or_else(|e| {
+            eprintln!("Bad peer '{}': {:?}", &argv[4], &e);
+            process::exit(1);
+        });
+
+        let mut peerdb = PeerDB::open(&argv[2], true).expect("Failed to open peer DB");
+        let local_peer = PeerDB::get_local_peer(peerdb.conn()).expect("Failed to load local peer");
+
+        let mut tx = peerdb.tx_begin().expect("Failed to begin peer DB transaction");
+        let changed = apply_peer_admin_action(&mut tx, local_peer.network_id, &selector, &action)
+            .expect("Failed to update peer DB");
+        tx.commit().expect("Failed to commit peer DB transaction");
+
+        if changed.len() == 0 {
+            println!("No known peer matches; will {} it when it shows up", action.name());
+        }
+
+        for nk in changed.iter() {
+            println!("{} {}", action.name(), nk);
+        }
+        return
+    }
+
+    if argv[1] == "marf-prune" {
+        if argv.len() < 5 {
+            eprintln!("Usage: {} marf-prune MARF_PATH CANONICAL_TIP RETENTION_DEPTH [--dry-run]", argv[0]);
+            eprintln!("Stop any node that uses MARF_PATH first.");
+            process::exit(1);
+        }
+
+        let marf_path = &argv[2];
+        let canonical_tip = BlockHeaderHash::from_hex(&argv[3]).expect("Bad MARF block hash");
+        let retention_depth = argv[4].parse::<u32>().expect("Bad retention depth");
+        let dry_run = argv.len() >= 6 && argv[5] == "--dry-run";
+
+        if fs::metadata(marf_path).is_err() {
+            eprintln!("No such file or directory: {}", marf_path);
+            process::exit(1);
+        }
+
+        // the tries may be in a flat data file next to the database
+        let marf_size = |marf: &mut MARF| {
+            let mut size = fs::metadata(marf_path).expect("Failed to stat MARF").len();
+            if let Some(data_file_path) = marf.borrow_storage_backend().get_data_file_path() {
+                size += fs::metadata(&data_file_path).expect("Failed to stat MARF data file").len();
+            }
+            size
+        };
+
+        let mut marf = MARF::from_path(marf_path, None).expect("Failed to open MARF");
+        let size_before = marf_size(&mut marf);
+
+        let report =
+            if dry_run {
+                marf.find_prunable_blocks(&canonical_tip, retention_depth).expect("Failed to find prunable tries")
+            }
+            else {
+                let report = marf.prune_forks(&canonical_tip, retention_depth).expect("Failed to prune MARF");
+                marf.compact().expect("Failed to compact MARF");
+                report
+            };
+
+        for tip in report.retained_tips.iter() {
+            println!("retain fork tip {}", tip);
+        }
+        for bhh in report.pruned.iter() {
+            println!("{} {}", if dry_run { "would prune" } else { "pruned" }, bhh);
+        }
+        println!("kept {} trie(s), pruned {} trie(s) totaling {} bytes", report.num_kept, report.pruned.len(), report.pruned_bytes);
+
+        if !dry_run {
+            let size_after = marf_size(&mut marf);
+            println!("reclaimed {} bytes ({} -> {})", size_before.saturating_sub(size_after), size_before, size_after);
+        }
+        return
+    }
+
+    if argv[1] == "marf-migrate-flat-file" {
+        if argv.len() < 3 {
+            eprintln!("Usage: {} marf-migrate-flat-file MARF_PATH", argv[0]);
+            process::exit(1);
+        }
+
+        let marf_path = &argv[2];
+        if fs::metadata(marf_path).is_err() {
+            eprintln!("No such file or directory: {}", marf_path);
+            process::exit(1);
+        }
+
+        let mut marf = MARF::from_path_with_backend(marf_path, None, Some(TrieStorageBackend::FlatFile)).expect("Failed to open MARF");
+        let (num_tries, num_bytes) = marf.borrow_storage_backend().migrate_to_flat_file().expect("Failed to migrate MARF");
+        let data_file_path = marf.borrow_storage_backend().get_data_file_path().expect("BUG: no data file after migration");
+
+        println!("moved {} trie(s) totaling {} bytes to {}", num_tries, num_bytes, &data_file_path);
+        return
+    }
+
+    if argv[1] == "marf-fsck" {
+        if argv.len() < 3 {
+            eprintln!("Usage: {} marf-fsck CHAINSTATE_DIR [--quarantine]", argv[0]);
+            process::exit(1);
+        }
+
+        let chainstate_dir = &argv[2];
+        let quarantine = argv.len() >= 4 && argv[3] == "--quarantine";
+
+        let db_path = format!("{}/vm/headers.db", chainstate_dir);
+        let index_marf_path = format!("{}/vm/index", chainstate_dir);
+        let clarity_marf_path = format!("{}/vm/clarity/marf", chainstate_dir);
+
+        for path in [&db_path, &index_marf_path, &clarity_marf_path].iter() {
+            if fs::metadata(path).is_err() {
+                eprintln!("No such file or directory: {}", path);
+                process::exit(1);
+            }
+        }
+
+        let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY).expect("Failed to open DB");
+        let headers = StacksChainState::get_all_stacks_block_header_infos(&conn).expect("Failed to load block headers");
+
+        // the headers MARF's roots are recorded as index_root, and the Clarity MARF's as each
+        // block's state_index_root (except for the boot block's, which is not filled in)
+        let index_roots : Vec<_> = headers.iter()
+            .map(|header| (header.index_block_hash(), header.index_root.clone()))
+            .collect();
+        let clarity_roots : Vec<_> = headers.iter()
+            .filter(|header| header.block_height > 0)
+            .map(|header| (header.index_block_hash(), header.anchored_header.state_index_root.clone()))
+            .collect();
+
+        let mut all_ok = true;
+        for (marf_path, expected_roots) in [(&index_marf_path, index_roots), (&clarity_marf_path, clarity_roots)].iter() {
+            let mut marf = MARF::from_path(marf_path, None).expect("Failed to open MARF");
+            let report = marf.check_integrity(expected_roots).expect("Failed to check MARF");
+
+            println!("{}: checked {} trie(s), {} node(s)", marf_path, report.num_tries, report.num_nodes);
+            for (bhh, damage) in report.damaged.iter() {
+                for d in damage.iter() {
+                    println!("damaged {}: {}", bhh, d);
+                }
+            }
+            for bhh in report.missing.iter() {
+                println!("missing {}", bhh);
+            }
+
+            if !report.is_ok() {
+                all_ok = false;
+            }
+
+            if quarantine {
+                let quarantined = marf.quarantine_damaged(&report).expect("Failed to quarantine damaged tries");
+                for bhh in quarantined.iter() {
+                    println!("quarantined {}", bhh);
+                }
+            }
+        }
+
+        if !all_ok {
+            process::exit(1);
+        }
+        return
+    }
+
+    if argv[1] == "export-snapshot" {
+        if argv.len() < 8 {
+            eprintln!("Usage: {} export-snapshot NETWORK CHAIN_ID STACKS_CHAINSTATE_DIR BURN_DB_DIR INDEX_BLOCK_HASH SNAPSHOT_PATH", argv[0]);
+            process::exit(1);
+        }
+
+        let mainnet = argv[2] == "mainnet";
+        let chain_id = u32::from_str_radix(&argv[3], 16).expect("Bad chain ID");
+        let index_block_hash = BlockHeaderHash::from_hex(&argv[6]).expect("Bad index block hash");
+
+        let manifest = match StacksChainState::export_snapshot(mainnet, chain_id, &argv[4], &argv[5], &index_block_hash, &argv[7]) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                eprintln!("Failed to export snapshot of {}: {:?}", &index_block_hash, &e);
+                process::exit(1);
+            }
+        };
+
+        println!("Exported {} (height {}, burn block {} at height {}): {} file(s)",
+                 &manifest.index_block_hash, manifest.block_height, &manifest.burn_header_hash, manifest.burn_block_height, manifest.files.len());
+        return
+    }
+
+    if argv[1] == "import-snapshot" {
+        if argv.len() < 8 {
+            eprintln!("Usage: {} import-snapshot NETWORK CHAIN_ID STACKS_CHAINSTATE_DIR BURN_DB_DIR TRUSTED_INDEX_BLOCK_HASH SNAPSHOT_PATH", argv[0]);
+            process::exit(1);
+        }
+
+        let mainnet = argv[2] == "mainnet";
+        let chain_id = u32::from_str_radix(&argv[3], 16).expect("Bad chain ID");
+        let trusted_tip = BlockHeaderHash::from_hex(&argv[6]).expect("Bad index block hash");
+
+        let manifest = match StacksChainState::import_snapshot(&argv[7], mainnet, chain_id, &argv[4], &argv[5], &trusted_tip) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                eprintln!("Failed to import snapshot {}: {:?}", &argv[7], &e);
+                process::exit(1);
+            }
+        };
+
+        println!("Imported {} (height {}, burn block {} at height {})",
+                 &manifest.index_block_hash, manifest.block_height, &manifest.burn_header_hash, manifest.burn_block_height);
+        return
+    }
+
     if argv[1] == "docgen" {
         println!("{}", vm::docs::make_json_api_reference());
         return
@@ -300,6 +549,51 @@ fn main() {
         return
     }
 
+    if argv[1] == "replay-burnchain" {
+        if argv.len() < 5 || argv.len() == 6 {
+            eprintln!("Usage: {} replay-burnchain NETWORK WORKING_DIR BURN_HEIGHT [STACKS_CHAINSTATE_DIR CHAIN_ID]", argv[0]);
+            process::exit(1);
+        }
+
+        let network = &argv[2];
+        let working_dir = &argv[3];
+        let burn_height : u64 = argv[4].parse().expect("Bad burn block height");
+
+        let mut chainstate_opt = if argv.len() >= 7 {
+            let chain_id = u32::from_str_radix(&argv[6], 16).expect("Bad chain ID");
+            let chainstate = StacksChainState::open(network == "mainnet", chain_id, &argv[5])
+                .expect("Failed to open Stacks chainstate");
+            Some(chainstate)
+        }
+        else {
+            None
+        };
+
+        let replay = match core::replay_burnchain_bitcoin(working_dir, network, burn_height) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("Failed to replay bitcoin {} from {}: {:?}", network, burn_height, e);
+                process::exit(1);
+            }
+        };
+
+        if let Some(ref mut chainstate) = chainstate_opt {
+            let burn_hashes : Vec<BurnchainHeaderHash> = replay.rewound.iter().map(|sn| sn.burn_header_hash.clone()).collect();
+            let rolled_back = chainstate.rollback_blocks(&burn_hashes).expect("Failed to roll back Stacks chainstate");
+            println!("Rolled back {} Stacks block(s)", rolled_back.len());
+        }
+
+        println!("Rewound {} burnchain block(s), replayed {}", replay.rewound.len(), replay.replayed.len());
+        for (old_sn, new_sn) in replay.mismatches.iter() {
+            println!("Consensus hash mismatch at {} {}: {} != {}", new_sn.block_height, &new_sn.burn_header_hash, &old_sn.consensus_hash, &new_sn.consensus_hash);
+        }
+
+        if replay.mismatches.len() > 0 {
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
     if argv.len() < 4 {
         eprintln!("Usage: {} blockchain network working_dir", argv[0]);
         process::exit(1);
@@ -0,0 +1,213 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Process-wide metrics registry.  Subsystems update these counters and gauges as they run,
+// and the node exports them in the Prometheus text exposition format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge"
+        }
+    }
+}
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    value: AtomicU64
+}
+
+impl Metric {
+    const fn new(name: &'static str, help: &'static str, kind: MetricKind) -> Metric {
+        Metric {
+            name,
+            help,
+            kind,
+            value: AtomicU64::new(0)
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    fn set(&self, value: u64) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+
+    fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::SeqCst);
+    }
+
+    fn sub(&self, value: u64) {
+        // saturate at zero, so a gauge that was seeded late can't wrap around
+        let mut cur = self.value.load(Ordering::SeqCst);
+        loop {
+            match self.value.compare_exchange(cur, cur.saturating_sub(value), Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => break,
+                Err(actual) => cur = actual
+            }
+        }
+    }
+}
+
+pub static BURN_BLOCK_HEIGHT: Metric = Metric::new(
+    "stacks_node_burn_block_height", "Height of the canonical burnchain tip", MetricKind::Gauge);
+pub static STACKS_TIP_HEIGHT: Metric = Metric::new(
+    "stacks_node_stacks_tip_height", "Height of the last Stacks block processed", MetricKind::Gauge);
+pub static SORTITION_WINS: Metric = Metric::new(
+    "stacks_node_sortition_wins_total", "Number of sortitions won by this node's miner", MetricKind::Counter);
+pub static STX_BLOCKS_PROCESSED: Metric = Metric::new(
+    "stacks_node_stx_blocks_processed_total", "Number of Stacks blocks appended to the chainstate", MetricKind::Counter);
+pub static STX_BLOCKS_REJECTED: Metric = Metric::new(
+    "stacks_node_stx_blocks_rejected_total", "Number of Stacks blocks rejected as invalid", MetricKind::Counter);
+pub static MEMPOOL_SIZE: Metric = Metric::new(
+    "stacks_node_mempool_size", "Number of transactions in the mempool", MetricKind::Gauge);
+pub static INBOUND_NEIGHBORS: Metric = Metric::new(
+    "stacks_node_inbound_neighbors", "Number of inbound p2p conversations", MetricKind::Gauge);
+pub static OUTBOUND_NEIGHBORS: Metric = Metric::new(
+    "stacks_node_outbound_neighbors", "Number of outbound p2p conversations", MetricKind::Gauge);
+pub static P2P_BYTES_RX: Metric = Metric::new(
+    "stacks_node_p2p_bytes_received_total", "Bytes received on p2p conversations", MetricKind::Counter);
+pub static P2P_BYTES_TX: Metric = Metric::new(
+    "stacks_node_p2p_bytes_sent_total", "Bytes sent on p2p conversations", MetricKind::Counter);
+pub static DOWNLOADER_INFLIGHT_REQUESTS: Metric = Metric::new(
+    "stacks_node_block_downloader_inflight_requests", "Number of in-flight block and microblock requests", MetricKind::Gauge);
+pub static EVENT_DISPATCHER_PENDING: Metric = Metric::new(
+    "stacks_node_event_dispatcher_pending_payloads", "Number of event observer payloads not yet delivered", MetricKind::Gauge);
+pub static EVENT_DISPATCHER_LAG_MS: Metric = Metric::new(
+    "stacks_node_event_dispatcher_lag_ms", "Milliseconds it took to deliver the last block's events to all observers", MetricKind::Gauge);
+
+pub static ALL_METRICS: &[&'static Metric] = &[
+    &BURN_BLOCK_HEIGHT,
+    &STACKS_TIP_HEIGHT,
+    &SORTITION_WINS,
+    &STX_BLOCKS_PROCESSED,
+    &STX_BLOCKS_REJECTED,
+    &MEMPOOL_SIZE,
+    &INBOUND_NEIGHBORS,
+    &OUTBOUND_NEIGHBORS,
+    &P2P_BYTES_RX,
+    &P2P_BYTES_TX,
+    &DOWNLOADER_INFLIGHT_REQUESTS,
+    &EVENT_DISPATCHER_PENDING,
+    &EVENT_DISPATCHER_LAG_MS,
+];
+
+pub fn set_burn_block_height(height: u64) {
+    BURN_BLOCK_HEIGHT.set(height);
+}
+
+pub fn set_stacks_tip_height(height: u64) {
+    STACKS_TIP_HEIGHT.set(height);
+}
+
+pub fn increment_sortition_wins_counter() {
+    SORTITION_WINS.add(1);
+}
+
+pub fn increment_stx_blocks_processed_counter() {
+    STX_BLOCKS_PROCESSED.add(1);
+}
+
+pub fn increment_stx_blocks_rejected_counter() {
+    STX_BLOCKS_REJECTED.add(1);
+}
+
+pub fn set_mempool_size(num_txs: u64) {
+    MEMPOOL_SIZE.set(num_txs);
+}
+
+pub fn update_mempool_size(num_added: u64, num_removed: u64) {
+    MEMPOOL_SIZE.add(num_added);
+    MEMPOOL_SIZE.sub(num_removed);
+}
+
+pub fn set_neighbor_counts(num_inbound: u64, num_outbound: u64) {
+    INBOUND_NEIGHBORS.set(num_inbound);
+    OUTBOUND_NEIGHBORS.set(num_outbound);
+}
+
+pub fn update_p2p_bytes_rx(num_bytes: u64) {
+    P2P_BYTES_RX.add(num_bytes);
+}
+
+pub fn update_p2p_bytes_tx(num_bytes: u64) {
+    P2P_BYTES_TX.add(num_bytes);
+}
+
+pub fn set_downloader_inflight_requests(num_requests: u64) {
+    DOWNLOADER_INFLIGHT_REQUESTS.set(num_requests);
+}
+
+pub fn set_event_dispatcher_pending(num_payloads: u64) {
+    EVENT_DISPATCHER_PENDING.set(num_payloads);
+}
+
+pub fn set_event_dispatcher_lag_ms(lag_ms: u64) {
+    EVENT_DISPATCHER_LAG_MS.set(lag_ms);
+}
+
+/// Render every registered metric in the Prometheus text exposition format.
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+    for metric in ALL_METRICS.iter() {
+        let _ = write!(out, "# HELP {} {}\n", metric.name, metric.help);
+        let _ = write!(out, "# TYPE {} {}\n", metric.name, metric.kind.as_str());
+        let _ = write!(out, "{} {}\n", metric.name, metric.get());
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        set_burn_block_height(123);
+        update_p2p_bytes_rx(10);
+        update_p2p_bytes_rx(20);
+
+        let rendered = render_prometheus();
+        let lines : Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 3 * ALL_METRICS.len());
+
+        assert!(lines.contains(&"# TYPE stacks_node_burn_block_height gauge"));
+        assert!(lines.contains(&"stacks_node_burn_block_height 123"));
+        assert!(lines.contains(&"# TYPE stacks_node_p2p_bytes_received_total counter"));
+        assert!(P2P_BYTES_RX.get() >= 30);
+
+        for metric in ALL_METRICS.iter() {
+            assert!(lines.contains(&format!("# HELP {} {}", metric.name, metric.help).as_str()));
+        }
+    }
+}
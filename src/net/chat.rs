@@ -37,11 +37,14 @@ use net::BLOCKS_INV_DATA_MAX_BITLEN;
 use net::connection::ConnectionP2P;
 use net::connection::ReplyHandleP2P;
 use net::connection::ConnectionOptions;
+use net::connection::FrameCipher;
+use net::reputation::PeerOffense;
 
 use net::neighbors::MAX_NEIGHBOR_BLOCK_DELAY;
 
 use net::db::*;
 
+use util;
 use util::db::Error as db_error;
 use util::db::DBConn;
 use util::secp256k1::Secp256k1PublicKey;
@@ -53,6 +56,7 @@ use chainstate::burn::db::burndb;
 use chainstate::burn::db::burndb::BurnDB;
 
 use chainstate::stacks::db::StacksChainState;
+use chainstate::stacks::StacksBlock;
 use chainstate::stacks::StacksBlockHeader;
 use chainstate::stacks::StacksPublicKey;
 use burnchains::Burnchain;
@@ -73,9 +77,10 @@ use util::log;
 use util::get_epoch_time_secs;
 use util::hash::to_hex;
 
+use monitoring;
+
 use rand;
 use rand::Rng;
-use rand::thread_rng;
 
 // did we or did we not successfully send a message?
 #[derive(Debug, Clone)]
@@ -147,7 +152,10 @@ pub struct NeighborStats {
     pub block_push_rx_counts: VecDeque<(u64, u64)>,         // (count, num bytes)
     pub microblocks_push_rx_counts: VecDeque<(u64, u64)>,   // (count, num bytes)
     pub transaction_push_rx_counts: VecDeque<(u64, u64)>,   // (count, num bytes)
-    pub relayed_messages: HashMap<NeighborAddress, RelayStats>
+    pub mempool_sync_tx_counts: VecDeque<(u64, u64)>,       // (count, num bytes) -- bytes we sent in reply to this peer's mempool sync requests
+    pub block_txs_read_counts: VecDeque<(u64, u64)>,        // (count, num bytes) -- block bytes we read to answer this peer's GetBlockTxs requests
+    pub relayed_messages: HashMap<NeighborAddress, RelayStats>,
+    pub offenses: Vec<PeerOffense>,                         // misbehavior not yet counted against the peer's reputation
 }
 
 impl NeighborStats {
@@ -170,7 +178,10 @@ impl NeighborStats {
             block_push_rx_counts: VecDeque::new(),
             microblocks_push_rx_counts: VecDeque::new(),
             transaction_push_rx_counts: VecDeque::new(),
+            mempool_sync_tx_counts: VecDeque::new(),
+            block_txs_read_counts: VecDeque::new(),
             relayed_messages: HashMap::new(),
+            offenses: vec![],
         }
     }
     
@@ -206,6 +217,20 @@ impl NeighborStats {
         }
     }
 
+    pub fn add_mempool_sync(&mut self, message_size: u64) -> () {
+        self.mempool_sync_tx_counts.push_back((get_epoch_time_secs(), message_size));
+        while self.mempool_sync_tx_counts.len() > NUM_BLOCK_POINTS {
+            self.mempool_sync_tx_counts.pop_front();
+        }
+    }
+
+    pub fn add_block_txs_read(&mut self, block_size: u64) -> () {
+        self.block_txs_read_counts.push_back((get_epoch_time_secs(), block_size));
+        while self.block_txs_read_counts.len() > NUM_BLOCK_POINTS {
+            self.block_txs_read_counts.pop_front();
+        }
+    }
+
     pub fn add_relayer(&mut self, addr: NeighborAddress, num_bytes: u64) -> () {
         if let Some(stats) = self.relayed_messages.get_mut(&addr) {
             stats.num_messages += 1;
@@ -227,6 +252,11 @@ impl NeighborStats {
         ret
     }
 
+    /// Take the offenses this peer committed since we last checked
+    pub fn take_offenses(&mut self) -> Vec<PeerOffense> {
+        mem::replace(&mut self.offenses, vec![])
+    }
+
     /// Get a peer's perceived health -- the last $NUM_HEALTH_POINTS successful messages divided by
     /// the total.
     pub fn get_health_score(&self) -> f64 {
@@ -287,6 +317,16 @@ impl NeighborStats {
         NeighborStats::get_bandwidth(&self.transaction_push_rx_counts, BLOCK_POINT_LIFETIME)
     }
 
+    /// Get the bandwidth we have spent answering a peer's mempool sync requests
+    pub fn get_mempool_sync_bandwidth(&self) -> f64 {
+        NeighborStats::get_bandwidth(&self.mempool_sync_tx_counts, BLOCK_POINT_LIFETIME)
+    }
+
+    /// Get the bandwidth we have spent reading blocks to answer a peer's GetBlockTxs requests
+    pub fn get_block_txs_read_bandwidth(&self) -> f64 {
+        NeighborStats::get_bandwidth(&self.block_txs_read_counts, BLOCK_POINT_LIFETIME)
+    }
+
     /// Determine how many of a particular message this peer has received
     pub fn get_message_recv_count(&self, msg_id: StacksMessageID) -> u64 {
         *(self.msg_rx_counts.get(&msg_id).unwrap_or(&0))
@@ -630,8 +670,7 @@ impl ConversationP2P {
 
     /// Get next message sequence number
     fn next_seq(&mut self) -> u32 {
-        let mut rng = thread_rng();
-        rng.gen::<u32>()
+        util::with_rng(|rng| rng.gen::<u32>())
     }
 
     /// Generate a signed message for this conversation 
@@ -818,6 +857,7 @@ impl ConversationP2P {
             Err(net_error::InvalidHandshake) => {
                 let reject = StacksMessage::from_chain_view(self.version, self.network_id, chain_view, StacksMessageType::HandshakeReject);
                 debug!("{:?}: invalid handshake", &self);
+                self.stats.offenses.push(PeerOffense::BadHandshake);
                 return Ok((Some(reject), true));
             },
             Err(e) => {
@@ -830,6 +870,17 @@ impl ConversationP2P {
             _ => panic!("Message is not a handshake")
         };
 
+        // an operator may have banned this key before we ever met its owner
+        if let Ok(pubk) = handshake_data.node_public_key.to_public_key() {
+            if let Some(rule) = PeerDB::get_public_key_rule(peerdb.conn(), self.network_id, &pubk).map_err(net_error::DBError)? {
+                if rule.is_blacklisted() {
+                    let reject = StacksMessage::from_chain_view(self.version, self.network_id, chain_view, StacksMessageType::HandshakeReject);
+                    debug!("{:?}: handshake from banned public key {}", &self, &to_hex(&pubk.to_bytes_compressed()));
+                    return Ok((Some(reject), true));
+                }
+            }
+        }
+
         let old_pubkey_opt = self.connection.get_public_key();
         let updated = self.update_from_handshake_data(&message.preamble, &handshake_data)?;
         let _authentic_msg = if !updated { "same" } else if old_pubkey_opt.is_none() { "new" } else { "upgraded" };
@@ -859,11 +910,55 @@ impl ConversationP2P {
         Ok((Some(accept), false))
     }
 
+    /// Do we and the remote peer both support encrypted channels?
+    fn can_encrypt(&self, local_peer: &LocalPeer) -> bool {
+        let flag = ServiceFlags::ENCRYPTION as u16;
+        (local_peer.services & flag) != 0 && (self.peer_services & flag) != 0
+    }
+
+    /// Do we and the remote peer both support compact blocks?
+    fn can_relay_compact_blocks(&self, local_peer: &LocalPeer) -> bool {
+        let flag = ServiceFlags::COMPACT_BLOCKS as u16;
+        (local_peer.services & flag) != 0 && (self.peer_services & flag) != 0
+    }
+
+    /// Do we and the remote peer both support mempool sync?
+    pub fn can_sync_mempool(&self, local_peer: &LocalPeer) -> bool {
+        let flag = ServiceFlags::MEMPOOL_SYNC as u16;
+        (local_peer.services & flag) != 0 && (self.peer_services & flag) != 0
+    }
+
+    /// Switch to an encrypted channel once a handshake completes, if both we and the remote peer
+    /// support it.  The channel keys come from the ECDH secret between our session key and the
+    /// remote peer's, salted with the HandshakeAccept's signature.  The side that sent the
+    /// HandshakeAccept encrypts everything it sends after it; the side that receives it encrypts
+    /// everything it sends after processing it.  A channel that is already encrypted stays
+    /// as-is on subsequent handshakes.
+    fn begin_encryption(&mut self, local_peer: &LocalPeer, accept_preamble: &Preamble) -> Result<(), net_error> {
+        if self.connection.is_encrypted() || !self.can_encrypt(local_peer) {
+            return Ok(());
+        }
+
+        let remote_public_key = match self.connection.get_public_key() {
+            Some(pubk) => pubk,
+            None => {
+                return Ok(());
+            }
+        };
+
+        let (send_cipher, recv_cipher) = FrameCipher::derive_pair(&local_peer.private_key, &remote_public_key, accept_preamble.signature.as_bytes());
+        self.connection.set_ciphers(send_cipher, recv_cipher)?;
+
+        debug!("{:?}: channel is now encrypted", &self);
+        Ok(())
+    }
+
     /// Handle an inbound handshake-accept
     /// Update conversation state based on a HandshakeAccept
     /// Called from the p2p network thread.
-    fn handle_handshake_accept(&mut self, preamble: &Preamble, handshake_accept: &HandshakeAcceptData) -> Result<(), net_error> {
+    fn handle_handshake_accept(&mut self, local_peer: &LocalPeer, preamble: &Preamble, handshake_accept: &HandshakeAcceptData) -> Result<(), net_error> {
         self.update_from_handshake_data(preamble, &handshake_accept.handshake)?;
+        self.begin_encryption(local_peer, preamble)?;
         self.peer_heartbeat = 
             if handshake_accept.heartbeat_interval > (MAX_PEER_HEARTBEAT_INTERVAL as u32) {
                 debug!("{:?}: heartbeat interval is too long; forcing default maximum", self);
@@ -958,6 +1053,61 @@ impl ConversationP2P {
         self.sign_and_reply(local_peer, burnchain_view, preamble, blocks_inv_payload)
     }
 
+    /// Handle an inbound GetBlockTxs request.
+    /// Reply with the requested transactions from the block, or NACK if we don't have the block or
+    /// the request refers to transactions it doesn't have.
+    /// Each request makes us read a whole block from disk, so the bytes we read count against
+    /// the peer's GetBlockTxs budget, and we NACK peers that exceed it.
+    fn handle_getblocktxs(&mut self, local_peer: &LocalPeer, chainstate: &mut StacksChainState, burnchain_view: &BurnchainView, preamble: &Preamble, get_block_txs: &GetBlockTxsData) -> Result<ReplyHandleP2P, net_error> {
+        if !self.can_relay_compact_blocks(local_peer) {
+            debug!("{:?}: Handle GetBlockTxs from {:?}: compact blocks not supported", &local_peer, &self);
+            return self.reply_nack(local_peer, burnchain_view, preamble, NackErrorCodes::UnsupportedService);
+        }
+
+        if self.connection.options.max_block_txs_read_bandwidth > 0 && self.stats.get_block_txs_read_bandwidth() > (self.connection.options.max_block_txs_read_bandwidth as f64) {
+            debug!("Neighbor {:?} exceeded max block-txs read bandwidth of {} bytes/sec (currently at {})", &self.to_neighbor_key(), self.connection.options.max_block_txs_read_bandwidth, self.stats.get_block_txs_read_bandwidth());
+            return self.reply_nack(local_peer, burnchain_view, preamble, NackErrorCodes::Throttled);
+        }
+
+        let block_bytes = match StacksChainState::load_block_bytes(&chainstate.blocks_path, &get_block_txs.burn_header_hash, &get_block_txs.block_hash) {
+            Ok(Some(block_bytes)) => block_bytes,
+            Ok(None) | Err(_) => {
+                debug!("{:?}: Handle GetBlockTxs from {:?}: no such block {}/{}", &local_peer, &self, &get_block_txs.burn_header_hash, &get_block_txs.block_hash);
+                return self.reply_nack(local_peer, burnchain_view, preamble, NackErrorCodes::NoSuchBlock);
+            }
+        };
+
+        self.stats.add_block_txs_read(block_bytes.len() as u64);
+
+        let block = match StacksBlock::consensus_deserialize(&mut &block_bytes[..]) {
+            Ok(block) => block,
+            Err(_e) => {
+                debug!("{:?}: Handle GetBlockTxs from {:?}: failed to decode block {}/{}: {:?}", &local_peer, &self, &get_block_txs.burn_header_hash, &get_block_txs.block_hash, &_e);
+                return self.reply_nack(local_peer, burnchain_view, preamble, NackErrorCodes::NoSuchBlock);
+            }
+        };
+
+        let mut txs = Vec::with_capacity(get_block_txs.indexes.len());
+        for index in get_block_txs.indexes.iter() {
+            match block.txs.get(*index as usize) {
+                Some(tx) => txs.push(tx.clone()),
+                None => {
+                    debug!("{:?}: Handle GetBlockTxs from {:?}: block {} has no transaction {}", &local_peer, &self, &get_block_txs.block_hash, index);
+                    return self.reply_nack(local_peer, burnchain_view, preamble, NackErrorCodes::NoSuchBlock);
+                }
+            }
+        }
+
+        debug!("{:?}: Handle GetBlockTxs from {:?}. Reply {} transaction(s) from block {}/{}", &local_peer, &self, txs.len(), &get_block_txs.burn_header_hash, &get_block_txs.block_hash);
+
+        let block_txs_payload = StacksMessageType::BlockTxs(BlockTxsData {
+            burn_header_hash: get_block_txs.burn_header_hash.clone(),
+            block_hash: get_block_txs.block_hash.clone(),
+            txs
+        });
+        self.sign_and_reply(local_peer, burnchain_view, preamble, block_txs_payload)
+    }
+
     /// Verify that there are no cycles in our relayers list.
     /// Identify relayers by public key hash
     fn check_relayer_cycles(relayers: &Vec<RelayData>) -> bool {
@@ -1065,12 +1215,31 @@ impl ConversationP2P {
         Ok(None)
     }
     
+    /// Validate a mempool sync request.
+    /// The reply is built upstream from the mempool, but don't answer a peer that didn't
+    /// advertise mempool sync, or that has already consumed too much of our bandwidth with its
+    /// sync requests.
+    fn validate_mempool_sync(&mut self, local_peer: &LocalPeer, chain_view: &BurnchainView, preamble: &Preamble) -> Result<Option<ReplyHandleP2P>, net_error> {
+        if !self.can_sync_mempool(local_peer) {
+            debug!("{:?}: Refusing MemPoolSync from {:?}: mempool sync not supported", &local_peer, &self);
+            return self.reply_nack(local_peer, chain_view, preamble, NackErrorCodes::UnsupportedService)
+                .and_then(|handle| Ok(Some(handle)));
+        }
+        if self.connection.options.max_mempool_sync_bandwidth > 0 && self.stats.get_mempool_sync_bandwidth() > (self.connection.options.max_mempool_sync_bandwidth as f64) {
+            debug!("Neighbor {:?} exceeded max mempool-sync bandwidth of {} bytes/sec (currently at {})", &self.to_neighbor_key(), self.connection.options.max_mempool_sync_bandwidth, self.stats.get_mempool_sync_bandwidth());
+            return self.reply_nack(local_peer, chain_view, preamble, NackErrorCodes::Throttled)
+                .and_then(|handle| Ok(Some(handle)));
+        }
+        Ok(None)
+    }
+
     /// Handle an inbound authenticated p2p data-plane message.
     /// Return the message if not handled
     fn handle_data_message(&mut self, local_peer: &LocalPeer, peerdb: &mut PeerDB, burndb: &BurnDB, chainstate: &mut StacksChainState, chain_view: &BurnchainView, msg: StacksMessage) -> Result<Option<StacksMessage>, net_error> {
         let res = match msg.payload {
             StacksMessageType::GetNeighbors => self.handle_getneighbors(peerdb.conn(), local_peer, chain_view, &msg.preamble),
             StacksMessageType::GetBlocksInv(ref get_blocks_inv) => self.handle_getblocksinv(local_peer, burndb, chainstate, chain_view, &msg.preamble, get_blocks_inv),
+            StacksMessageType::GetBlockTxs(ref get_block_txs) => self.handle_getblocktxs(local_peer, chainstate, chain_view, &msg.preamble, get_block_txs),
             StacksMessageType::Blocks(_) => {
                 // not handled here, but do some accounting -- we can't receive blocks too often,
                 // so close this conversation if we do.
@@ -1082,6 +1251,23 @@ impl ConversationP2P {
                     }
                 }
             },
+            StacksMessageType::CompactBlock(_) => {
+                // not handled here, but do some accounting -- a compact block counts against the
+                // same budget as a full block push.
+                if !self.can_relay_compact_blocks(local_peer) {
+                    debug!("{:?}: Refusing CompactBlock from {:?}: compact blocks not supported", &local_peer, &self);
+                    let handle = self.reply_nack(local_peer, chain_view, &msg.preamble, NackErrorCodes::UnsupportedService)?;
+                    self.reply_handles.push_back(handle);
+                    return Ok(None);
+                }
+                match self.validate_blocks_push(local_peer, chain_view, &msg.preamble, msg.relayers.clone())? {
+                    Some(handle) => Ok(handle),
+                    None => {
+                        // will forward upstream
+                        return Ok(Some(msg))
+                    }
+                }
+            },
             StacksMessageType::Microblocks(_) => {
                 // not handled here, but do some accounting -- we can't receive too many
                 // unconfirmed microblocks per second
@@ -1104,6 +1290,17 @@ impl ConversationP2P {
                     }
                 }
             },
+            StacksMessageType::MemPoolSync(_) => {
+                // not handled here, since we need the mempool to answer it -- but make sure the
+                // peer isn't asking too often
+                match self.validate_mempool_sync(local_peer, chain_view, &msg.preamble)? {
+                    Some(handle) => Ok(handle),
+                    None => {
+                        // will forward upstream
+                        return Ok(Some(msg))
+                    }
+                }
+            },
             _ => {
                 // all else will forward upstream
                 return Ok(Some(msg));
@@ -1133,6 +1330,7 @@ impl ConversationP2P {
                     if num_recved > 0 {
                         self.stats.last_recv_time = get_epoch_time_secs();
                         self.stats.bytes_rx += num_recved as u64;
+                        monitoring::update_p2p_bytes_rx(num_recved as u64);
                     }
                     else {
                         break;
@@ -1162,6 +1360,7 @@ impl ConversationP2P {
                     if num_sent > 0 {
                         self.stats.last_send_time = get_epoch_time_secs();
                         self.stats.bytes_tx += num_sent as u64;
+                        monitoring::update_p2p_bytes_tx(num_sent as u64);
                     }
                     else {
                         break;
@@ -1264,7 +1463,7 @@ impl ConversationP2P {
             },
             StacksMessageType::HandshakeAccept(ref data) => {
                 test_debug!("{:?}: Got HandshakeAccept", &self);
-                self.handle_handshake_accept(&msg.preamble, data).and_then(|_| Ok(None))
+                self.handle_handshake_accept(local_peer, &msg.preamble, data).and_then(|_| Ok(None))
             },
             StacksMessageType::Ping(_) => {
                 test_debug!("{:?}: Got Ping", &self);
@@ -1305,7 +1504,7 @@ impl ConversationP2P {
             StacksMessageType::HandshakeAccept(ref data) => {
                 if solicited {
                     test_debug!("{:?}: Got unauthenticated HandshakeAccept", &self);
-                    self.handle_handshake_accept(&msg.preamble, data).and_then(|_| Ok(None))
+                    self.handle_handshake_accept(local_peer, &msg.preamble, data).and_then(|_| Ok(None))
                 }
                 else {
                     test_debug!("{:?}: Unsolicited unauthenticated HandshakeAccept", &self);
@@ -1387,8 +1586,18 @@ impl ConversationP2P {
                     // send back this message to the remote peer
                     test_debug!("{:?}: Send control-plane reply type {}", &self, reply.payload.get_message_name());
                     reply.sign(msg.preamble.seq, &local_peer.private_key)?;
+                    let accept_preamble_opt = match reply.payload {
+                        StacksMessageType::HandshakeAccept(_) => Some(reply.preamble.clone()),
+                        _ => None
+                    };
+
                     let reply_handle = self.relay_signed_message(reply)?;
                     self.reply_handles.push_back(reply_handle);
+
+                    if let Some(accept_preamble) = accept_preamble_opt {
+                        // the HandshakeAccept goes out in plaintext, but nothing after it does
+                        self.begin_encryption(local_peer, &accept_preamble)?;
+                    }
                 }
             }
             
@@ -1471,6 +1680,7 @@ impl ConversationP2P {
        let num_drained = self.connection.drain_timeouts();
        for _ in 0..num_drained {
            self.stats.add_healthpoint(false);
+           self.stats.offenses.push(PeerOffense::Timeout);
        }
     }
 
@@ -1513,6 +1723,7 @@ mod test {
     use std::fs;
 
     use net::test::*;
+    use chainstate::stacks::test::make_codec_test_block;
 
     use core::{PEER_VERSION, NETWORK_P2P_PORT};
 
@@ -1720,6 +1931,103 @@ mod test {
         assert_eq!(convo_1.data_url, "http://peer2.com".into());
     }
     
+    #[test]
+    fn convo_handshake_encrypted() {
+        let conn_opts = ConnectionOptions::default();
+
+        let socketaddr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let socketaddr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 8081);
+        
+        let first_burn_hash = BurnchainHeaderHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+        
+        let burnchain = Burnchain {
+            peer_version: PEER_VERSION,
+            network_id: 0,
+            chain_name: "bitcoin".to_string(),
+            network_name: "testnet".to_string(),
+            working_dir: "/nope".to_string(),
+            consensus_hash_lifetime: 24,
+            stable_confirmations: 7,
+            first_block_height: 12300,
+            first_block_hash: first_burn_hash.clone(),
+        };
+
+        let mut chain_view = BurnchainView {
+            burn_block_height: 12348,
+            burn_consensus_hash: ConsensusHash::from_hex("1111111111111111111111111111111111111111").unwrap(),
+            burn_stable_block_height: 12341,
+            burn_stable_consensus_hash: ConsensusHash::from_hex("2222222222222222222222222222222222222222").unwrap(),
+            last_consensus_hashes: HashMap::new()
+        };
+        chain_view.make_test_data();
+
+        let (mut peerdb_1, mut burndb_1, mut chainstate_1) = make_test_chain_dbs("convo_handshake_encrypted_1", &burnchain, 0x9abcdef0, 12350, "http://peer1.com".into(), &vec![], &vec![]);
+        let (mut peerdb_2, mut burndb_2, mut chainstate_2) = make_test_chain_dbs("convo_handshake_encrypted_2", &burnchain, 0x9abcdef0, 12351, "http://peer2.com".into(), &vec![], &vec![]);
+
+        db_setup(&mut peerdb_1, &mut burndb_1, &socketaddr_1, &chain_view);
+        db_setup(&mut peerdb_2, &mut burndb_2, &socketaddr_2, &chain_view);
+
+        // both peers support encryption
+        for peerdb in [&mut peerdb_1, &mut peerdb_2].iter_mut() {
+            let mut tx = peerdb.tx_begin().unwrap();
+            PeerDB::set_local_services(&mut tx, (ServiceFlags::RELAY as u16) | (ServiceFlags::ENCRYPTION as u16)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let local_peer_1 = PeerDB::get_local_peer(&peerdb_1.conn()).unwrap();
+        let local_peer_2 = PeerDB::get_local_peer(&peerdb_2.conn()).unwrap();
+
+        let mut convo_1 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_2, &conn_opts, true, 0);
+        let mut convo_2 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_1, &conn_opts, true, 0);
+
+        // convo_1 sends a handshake to convo_2
+        let handshake_data_1 = HandshakeData::from_local_peer(&local_peer_1);
+        let handshake_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::Handshake(handshake_data_1.clone())).unwrap();
+        let mut rh_1 = convo_1.send_signed_request(handshake_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_1], &mut convo_2);
+        convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        // convo_2 switched to encryption once it queued its HandshakeAccept
+        assert!(convo_2.connection.is_encrypted());
+        assert!(!convo_1.connection.is_encrypted());
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_1], &mut convo_1);
+        convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        match rh_1.recv(0).unwrap().payload {
+            StacksMessageType::HandshakeAccept(ref data) => {
+                assert_eq!(data.handshake.services, local_peer_2.services);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // convo_1 switched to encryption once it processed the HandshakeAccept
+        assert!(convo_1.connection.is_encrypted());
+
+        // the two can still talk
+        let ping_data_1 = PingData::new();
+        let ping_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::Ping(ping_data_1.clone())).unwrap();
+        let mut rh_ping_1 = convo_1.send_signed_request(ping_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_ping_1], &mut convo_2);
+        convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_ping_1], &mut convo_1);
+        convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        match rh_ping_1.recv(0).unwrap().payload {
+            StacksMessageType::Pong(ref data) => {
+                assert_eq!(data.nonce, ping_data_1.nonce);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+    }
+    
     #[test]
     fn convo_handshake_reject() {
         let conn_opts = ConnectionOptions::default();
@@ -1798,6 +2106,86 @@ mod test {
         assert!(convo_2.connection.get_public_key().is_none());
     }
 
+    #[test]
+    fn convo_handshake_banned_public_key() {
+        let conn_opts = ConnectionOptions::default();
+        let socketaddr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let socketaddr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 8081);
+        
+        let first_burn_hash = BurnchainHeaderHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+
+        let burnchain = Burnchain {
+            peer_version: PEER_VERSION,
+            network_id: 0,
+            chain_name: "bitcoin".to_string(),
+            network_name: "testnet".to_string(),
+            working_dir: "/nope".to_string(),
+            consensus_hash_lifetime: 24,
+            stable_confirmations: 7,
+            first_block_height: 12300,
+            first_block_hash: first_burn_hash.clone(),
+        };
+
+        let mut chain_view = BurnchainView {
+            burn_block_height: 12348,
+            burn_consensus_hash: ConsensusHash::from_hex("1111111111111111111111111111111111111111").unwrap(),
+            burn_stable_block_height: 12341,
+            burn_stable_consensus_hash: ConsensusHash::from_hex("2222222222222222222222222222222222222222").unwrap(),
+            last_consensus_hashes: HashMap::new()
+        };
+        chain_view.make_test_data();
+        
+        let (mut peerdb_1, mut burndb_1, mut chainstate_1) = make_test_chain_dbs("convo_handshake_banned_public_key_1", &burnchain, 0x9abcdef0, 12350, "http://peer1.com".into(), &vec![], &vec![]);
+        let (mut peerdb_2, mut burndb_2, mut chainstate_2) = make_test_chain_dbs("convo_handshake_banned_public_key_2", &burnchain, 0x9abcdef0, 12351, "http://peer2.com".into(), &vec![], &vec![]);
+
+        db_setup(&mut peerdb_1, &mut burndb_1, &socketaddr_1, &chain_view);
+        db_setup(&mut peerdb_2, &mut burndb_2, &socketaddr_2, &chain_view);
+
+        let local_peer_1 = PeerDB::get_local_peer(&peerdb_1.conn()).unwrap();
+        let local_peer_2 = PeerDB::get_local_peer(&peerdb_2.conn()).unwrap();
+
+        // peer 2's operator banned peer 1's public key before the two ever met
+        {
+            let mut tx = peerdb_2.tx_begin().unwrap();
+            let rule = PeerAdminRule { whitelisted: Some(0), blacklisted: Some(i64::max_value()) };
+            PeerDB::set_public_key_rule(&mut tx, 123, &Secp256k1PublicKey::from_private(&local_peer_1.private_key), &rule).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let mut convo_1 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_2, &conn_opts, true, 0);
+        let mut convo_2 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_1, &conn_opts, true, 0);
+       
+        // convo_1 sends an otherwise-valid handshake to convo_2
+        let handshake_data_1 = HandshakeData::from_local_peer(&local_peer_1);
+        let handshake_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::Handshake(handshake_data_1.clone())).unwrap();
+
+        let mut rh_1 = convo_1.send_signed_request(handshake_1, 1000000).unwrap();
+
+        // convo_2 receives it and automatically rejects it.
+        convo_send_recv(&mut convo_1, vec![&mut rh_1], &mut convo_2);
+        let unhandled_2 = convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        // convo_1 has a handshakereject
+        convo_send_recv(&mut convo_2, vec![&mut rh_1], &mut convo_1);
+        let unhandled_1 = convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        let reply_1 = rh_1.recv(0).unwrap();
+
+        assert_eq!(unhandled_1.len(), 0);
+        assert_eq!(unhandled_2.len(), 0);
+
+        match reply_1.payload {
+            StacksMessageType::HandshakeReject => {},
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // peer 2 did not authenticate peer 1
+        assert!(convo_1.connection.get_public_key().is_none());
+        assert!(convo_2.connection.get_public_key().is_none());
+    }
+
     #[test]
     fn convo_handshake_badsignature() {
         let conn_opts = ConnectionOptions::default();
@@ -2626,6 +3014,321 @@ mod test {
             assert_eq!(stats.num_bytes, (msg.preamble.payload_len - 1) as u64);
         }
     }
+
+    #[test]
+    fn convo_mempool_sync_throttled() {
+        let mut conn_opts = ConnectionOptions::default();
+        conn_opts.max_mempool_sync_bandwidth = 1000;
+
+        let socketaddr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let socketaddr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 8081);
+        
+        let first_burn_hash = BurnchainHeaderHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+
+        let burnchain = Burnchain {
+            peer_version: PEER_VERSION,
+            network_id: 0,
+            chain_name: "bitcoin".to_string(),
+            network_name: "testnet".to_string(),
+            working_dir: "/nope".to_string(),
+            consensus_hash_lifetime: 24,
+            stable_confirmations: 7,
+            first_block_height: 12300,
+            first_block_hash: first_burn_hash.clone(),
+        };
+
+        let mut chain_view = BurnchainView {
+            burn_block_height: 12348,
+            burn_consensus_hash: ConsensusHash::from_hex("1111111111111111111111111111111111111111").unwrap(),
+            burn_stable_block_height: 12341,
+            burn_stable_consensus_hash: ConsensusHash::from_hex("2222222222222222222222222222222222222222").unwrap(),
+            last_consensus_hashes: HashMap::new()
+        };
+        chain_view.make_test_data();
+        
+        let (mut peerdb_1, mut burndb_1, mut chainstate_1) = make_test_chain_dbs("convo_mempool_sync_throttled_1", &burnchain, 0x9abcdef0, 12350, "http://peer1.com".into(), &vec![], &vec![]);
+        let (mut peerdb_2, mut burndb_2, mut chainstate_2) = make_test_chain_dbs("convo_mempool_sync_throttled_2", &burnchain, 0x9abcdef0, 12351, "http://peer2.com".into(), &vec![], &vec![]);
+
+        db_setup(&mut peerdb_1, &mut burndb_1, &socketaddr_1, &chain_view);
+        db_setup(&mut peerdb_2, &mut burndb_2, &socketaddr_2, &chain_view);
+
+        for peerdb in [&mut peerdb_1, &mut peerdb_2].iter_mut() {
+            let mut tx = peerdb.tx_begin().unwrap();
+            PeerDB::set_local_services(&mut tx, (ServiceFlags::RELAY as u16) | (ServiceFlags::MEMPOOL_SYNC as u16)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let local_peer_1 = PeerDB::get_local_peer(&peerdb_1.conn()).unwrap();
+        let local_peer_2 = PeerDB::get_local_peer(&peerdb_2.conn()).unwrap();
+
+        let mut convo_1 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_2, &conn_opts, true, 0);
+        let mut convo_2 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_1, &conn_opts, true, 0);
+
+        // convo_1 handshakes with convo_2 and asks for its mempool
+        let handshake_data_1 = HandshakeData::from_local_peer(&local_peer_1);
+        let handshake_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::Handshake(handshake_data_1.clone())).unwrap();
+        let mut rh_handshake_1 = convo_1.send_signed_request(handshake_1, 1000000).unwrap();
+
+        let sync_data = MemPoolSyncData::from_txids(1, &[]);
+        let sync_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::MemPoolSync(sync_data.clone())).unwrap();
+        let mut rh_sync_1 = convo_1.send_signed_request(sync_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_handshake_1, &mut rh_sync_1], &mut convo_2);
+        let unhandled_2 = convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_handshake_1], &mut convo_1);
+        let unhandled_1 = convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        let reply_handshake_1 = rh_handshake_1.recv(0).unwrap();
+        match reply_handshake_1.payload {
+            StacksMessageType::HandshakeAccept(_) => {},
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // the mempool sync request is within convo_1's bandwidth, so it gets passed along to be
+        // answered from the mempool
+        assert_eq!(unhandled_1.len(), 0);
+        assert_eq!(unhandled_2.len(), 2);
+        match unhandled_2[1].payload {
+            StacksMessageType::MemPoolSync(ref data) => {
+                assert_eq!(*data, sync_data);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // pretend convo_2 has since sent convo_1 a lot of mempool transactions
+        convo_2.stats.add_mempool_sync(1000000);
+        convo_2.stats.add_mempool_sync(1000000);
+        assert!(convo_2.stats.get_mempool_sync_bandwidth() > 1000.0);
+
+        // so, convo_1's next request gets throttled
+        let sync_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::MemPoolSync(sync_data.clone())).unwrap();
+        let mut rh_sync_1 = convo_1.send_signed_request(sync_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_sync_1], &mut convo_2);
+        let unhandled_2 = convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_sync_1], &mut convo_1);
+        let unhandled_1 = convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        assert_eq!(unhandled_1.len(), 0);
+        assert_eq!(unhandled_2.len(), 0);
+
+        let reply_sync_1 = rh_sync_1.recv(0).unwrap();
+        match reply_sync_1.payload {
+            StacksMessageType::Nack(ref data) => {
+                assert_eq!(data.error_code, NackErrorCodes::Throttled);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // a peer that doesn't advertise mempool sync can't ask for our mempool
+        convo_2.peer_services = ServiceFlags::RELAY as u16;
+
+        let sync_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::MemPoolSync(sync_data.clone())).unwrap();
+        let mut rh_sync_1 = convo_1.send_signed_request(sync_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_sync_1], &mut convo_2);
+        let unhandled_2 = convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_sync_1], &mut convo_1);
+        convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        assert_eq!(unhandled_2.len(), 0);
+
+        let reply_sync_1 = rh_sync_1.recv(0).unwrap();
+        match reply_sync_1.payload {
+            StacksMessageType::Nack(ref data) => {
+                assert_eq!(data.error_code, NackErrorCodes::UnsupportedService);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+    }
+
+    #[test]
+    fn convo_getblocktxs() {
+        let mut conn_opts = ConnectionOptions::default();
+        conn_opts.max_block_txs_read_bandwidth = 1000;
+
+        let socketaddr_1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let socketaddr_2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 8081);
+        
+        let first_burn_hash = BurnchainHeaderHash::from_hex("0000000000000000000000000000000000000000000000000000000000000000").unwrap();
+
+        let burnchain = Burnchain {
+            peer_version: PEER_VERSION,
+            network_id: 0,
+            chain_name: "bitcoin".to_string(),
+            network_name: "testnet".to_string(),
+            working_dir: "/nope".to_string(),
+            consensus_hash_lifetime: 24,
+            stable_confirmations: 7,
+            first_block_height: 12300,
+            first_block_hash: first_burn_hash.clone(),
+        };
+
+        let mut chain_view = BurnchainView {
+            burn_block_height: 12348,
+            burn_consensus_hash: ConsensusHash::from_hex("1111111111111111111111111111111111111111").unwrap(),
+            burn_stable_block_height: 12341,
+            burn_stable_consensus_hash: ConsensusHash::from_hex("2222222222222222222222222222222222222222").unwrap(),
+            last_consensus_hashes: HashMap::new()
+        };
+        chain_view.make_test_data();
+        
+        let (mut peerdb_1, mut burndb_1, mut chainstate_1) = make_test_chain_dbs("convo_getblocktxs_1", &burnchain, 0x9abcdef0, 12350, "http://peer1.com".into(), &vec![], &vec![]);
+        let (mut peerdb_2, mut burndb_2, mut chainstate_2) = make_test_chain_dbs("convo_getblocktxs_2", &burnchain, 0x9abcdef0, 12351, "http://peer2.com".into(), &vec![], &vec![]);
+
+        db_setup(&mut peerdb_1, &mut burndb_1, &socketaddr_1, &chain_view);
+        db_setup(&mut peerdb_2, &mut burndb_2, &socketaddr_2, &chain_view);
+
+        for peerdb in [&mut peerdb_1, &mut peerdb_2].iter_mut() {
+            let mut tx = peerdb.tx_begin().unwrap();
+            PeerDB::set_local_services(&mut tx, (ServiceFlags::RELAY as u16) | (ServiceFlags::COMPACT_BLOCKS as u16)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let local_peer_1 = PeerDB::get_local_peer(&peerdb_1.conn()).unwrap();
+        let local_peer_2 = PeerDB::get_local_peer(&peerdb_2.conn()).unwrap();
+
+        // convo_2 has a block that convo_1 wants transactions from
+        let block = make_codec_test_block(3);
+        let burn_header_hash = BurnchainHeaderHash([0x11; 32]);
+        StacksChainState::store_block(&chainstate_2.blocks_path, &burn_header_hash, &block).unwrap();
+
+        let get_block_txs = GetBlockTxsData {
+            burn_header_hash: burn_header_hash.clone(),
+            block_hash: block.block_hash(),
+            indexes: vec![1, 2]
+        };
+
+        let mut convo_1 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_2, &conn_opts, true, 0);
+        let mut convo_2 = ConversationP2P::new(123, 456, &burnchain, &socketaddr_1, &conn_opts, true, 0);
+
+        // convo_1 handshakes with convo_2 and asks for the block's transactions
+        let handshake_data_1 = HandshakeData::from_local_peer(&local_peer_1);
+        let handshake_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::Handshake(handshake_data_1.clone())).unwrap();
+        let mut rh_handshake_1 = convo_1.send_signed_request(handshake_1, 1000000).unwrap();
+
+        let getblocktxs_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::GetBlockTxs(get_block_txs.clone())).unwrap();
+        let mut rh_getblocktxs_1 = convo_1.send_signed_request(getblocktxs_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_handshake_1, &mut rh_getblocktxs_1], &mut convo_2);
+        let unhandled_2 = convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_handshake_1, &mut rh_getblocktxs_1], &mut convo_1);
+        let unhandled_1 = convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        assert_eq!(unhandled_1.len(), 0);
+        assert_eq!(unhandled_2.len(), 1);
+
+        let reply_handshake_1 = rh_handshake_1.recv(0).unwrap();
+        match reply_handshake_1.payload {
+            StacksMessageType::HandshakeAccept(_) => {},
+            _ => {
+                assert!(false);
+            }
+        };
+
+        let reply_getblocktxs_1 = rh_getblocktxs_1.recv(0).unwrap();
+        match reply_getblocktxs_1.payload {
+            StacksMessageType::BlockTxs(ref data) => {
+                assert_eq!(data.burn_header_hash, burn_header_hash);
+                assert_eq!(data.block_hash, block.block_hash());
+                assert_eq!(data.txs, vec![block.txs[1].clone(), block.txs[2].clone()]);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // reading the block counted against convo_1's budget
+        assert_eq!(convo_2.stats.block_txs_read_counts.len(), 1);
+
+        // convo_2 doesn't have this block
+        let mut no_such_block = get_block_txs.clone();
+        no_such_block.block_hash = BlockHeaderHash([0x22; 32]);
+
+        // convo_2's block doesn't have this transaction
+        let mut no_such_tx = get_block_txs.clone();
+        no_such_tx.indexes = vec![1, 3];
+
+        for bad_get_block_txs in [no_such_block, no_such_tx].iter() {
+            let getblocktxs_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::GetBlockTxs(bad_get_block_txs.clone())).unwrap();
+            let mut rh_getblocktxs_1 = convo_1.send_signed_request(getblocktxs_1, 1000000).unwrap();
+
+            convo_send_recv(&mut convo_1, vec![&mut rh_getblocktxs_1], &mut convo_2);
+            convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+            convo_send_recv(&mut convo_2, vec![&mut rh_getblocktxs_1], &mut convo_1);
+            convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+            let reply_getblocktxs_1 = rh_getblocktxs_1.recv(0).unwrap();
+            match reply_getblocktxs_1.payload {
+                StacksMessageType::Nack(ref data) => {
+                    assert_eq!(data.error_code, NackErrorCodes::NoSuchBlock);
+                },
+                _ => {
+                    assert!(false);
+                }
+            };
+        }
+
+        // pretend convo_1 has since made convo_2 read a lot of blocks
+        convo_2.stats.add_block_txs_read(1000000);
+        convo_2.stats.add_block_txs_read(1000000);
+        assert!(convo_2.stats.get_block_txs_read_bandwidth() > 1000.0);
+
+        // so, convo_1's next request gets throttled
+        let getblocktxs_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::GetBlockTxs(get_block_txs.clone())).unwrap();
+        let mut rh_getblocktxs_1 = convo_1.send_signed_request(getblocktxs_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_getblocktxs_1], &mut convo_2);
+        convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_getblocktxs_1], &mut convo_1);
+        convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        let reply_getblocktxs_1 = rh_getblocktxs_1.recv(0).unwrap();
+        match reply_getblocktxs_1.payload {
+            StacksMessageType::Nack(ref data) => {
+                assert_eq!(data.error_code, NackErrorCodes::Throttled);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+
+        // a peer that doesn't advertise compact blocks can't ask for block transactions
+        convo_2.peer_services = ServiceFlags::RELAY as u16;
+
+        let getblocktxs_1 = convo_1.sign_message(&chain_view, &local_peer_1.private_key, StacksMessageType::GetBlockTxs(get_block_txs.clone())).unwrap();
+        let mut rh_getblocktxs_1 = convo_1.send_signed_request(getblocktxs_1, 1000000).unwrap();
+
+        convo_send_recv(&mut convo_1, vec![&mut rh_getblocktxs_1], &mut convo_2);
+        convo_2.chat(&local_peer_2, &mut peerdb_2, &burndb_2, &mut chainstate_2, &chain_view).unwrap();
+
+        convo_send_recv(&mut convo_2, vec![&mut rh_getblocktxs_1], &mut convo_1);
+        convo_1.chat(&local_peer_1, &mut peerdb_1, &burndb_1, &mut chainstate_1, &chain_view).unwrap();
+
+        let reply_getblocktxs_1 = rh_getblocktxs_1.recv(0).unwrap();
+        match reply_getblocktxs_1.payload {
+            StacksMessageType::Nack(ref data) => {
+                assert_eq!(data.error_code, NackErrorCodes::UnsupportedService);
+            },
+            _ => {
+                assert!(false);
+            }
+        };
+    }
 }
 
 // TODO: test bandwidth limits
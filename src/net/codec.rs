@@ -535,6 +535,165 @@ impl StacksMessageCodec for MicroblocksData {
     }
 }
 
+impl StacksMessageCodec for MemPoolSyncData {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.seed)?;
+        write_next(fd, &self.short_txids)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<MemPoolSyncData, net_error> {
+        let seed : u64 = read_next(fd)?;
+        let short_txids : Vec<u64> = read_next_at_most::<_, u64>(fd, MEMPOOL_SYNC_TXIDS_MAX)?;
+
+        // only valid if sorted and free of dups
+        for i in 1..short_txids.len() {
+            if short_txids[i - 1] >= short_txids[i] {
+                return Err(net_error::DeserializeError("Invalid MemPoolSyncData: short txids are not sorted and unique".to_string()));
+            }
+        }
+
+        Ok(MemPoolSyncData {
+            seed,
+            short_txids
+        })
+    }
+}
+
+impl StacksMessageCodec for MemPoolTxsData {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.txs)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<MemPoolTxsData, net_error> {
+        let txs : Vec<StacksTransaction> = {
+            // loose upper-bound
+            let mut bound_read = BoundReader::from_reader(fd, MAX_MESSAGE_LEN as u64);
+            read_next_at_most::<_, StacksTransaction>(&mut bound_read, MEMPOOL_SYNC_TXS_MAX)
+        }?;
+
+        Ok(MemPoolTxsData {
+            txs
+        })
+    }
+}
+
+impl StacksMessageCodec for PrefilledTransaction {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.index)?;
+        write_next(fd, &self.tx)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<PrefilledTransaction, net_error> {
+        let index : u32 = read_next(fd)?;
+        let tx : StacksTransaction = read_next(fd)?;
+        Ok(PrefilledTransaction {
+            index,
+            tx
+        })
+    }
+}
+
+impl StacksMessageCodec for CompactBlockData {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.burn_header_hash)?;
+        write_next(fd, &self.header)?;
+        write_next(fd, &self.seed)?;
+        write_next(fd, &self.short_txids)?;
+        write_next(fd, &self.prefilled_txs)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<CompactBlockData, net_error> {
+        let burn_header_hash : BurnchainHeaderHash = read_next(fd)?;
+        let header : StacksBlockHeader = read_next(fd)?;
+        let seed : u64 = read_next(fd)?;
+        let short_txids : Vec<u64> = read_next_at_most::<_, u64>(fd, COMPACT_BLOCK_TXS_MAX)?;
+        let prefilled_txs : Vec<PrefilledTransaction> = {
+            // loose upper-bound
+            let mut bound_read = BoundReader::from_reader(fd, MAX_MESSAGE_LEN as u64);
+            read_next_at_most::<_, PrefilledTransaction>(&mut bound_read, COMPACT_BLOCK_TXS_MAX)
+        }?;
+
+        // prefilled transactions must be in block order, and must fit into the block
+        let num_txs = (short_txids.len() as u64) + (prefilled_txs.len() as u64);
+        if num_txs > COMPACT_BLOCK_TXS_MAX as u64 {
+            return Err(net_error::DeserializeError("Invalid CompactBlockData: too many transactions".to_string()));
+        }
+        for i in 0..prefilled_txs.len() {
+            if (prefilled_txs[i].index as u64) >= num_txs {
+                return Err(net_error::DeserializeError("Invalid CompactBlockData: prefilled transaction index out of range".to_string()));
+            }
+            if i > 0 && prefilled_txs[i - 1].index >= prefilled_txs[i].index {
+                return Err(net_error::DeserializeError("Invalid CompactBlockData: prefilled transactions are not sorted and unique".to_string()));
+            }
+        }
+
+        Ok(CompactBlockData {
+            burn_header_hash,
+            header,
+            seed,
+            short_txids,
+            prefilled_txs
+        })
+    }
+}
+
+impl StacksMessageCodec for GetBlockTxsData {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.burn_header_hash)?;
+        write_next(fd, &self.block_hash)?;
+        write_next(fd, &self.indexes)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<GetBlockTxsData, net_error> {
+        let burn_header_hash : BurnchainHeaderHash = read_next(fd)?;
+        let block_hash : BlockHeaderHash = read_next(fd)?;
+        let indexes : Vec<u32> = read_next_at_most::<_, u32>(fd, COMPACT_BLOCK_TXS_MAX)?;
+
+        // only valid if sorted and free of dups
+        for i in 1..indexes.len() {
+            if indexes[i - 1] >= indexes[i] {
+                return Err(net_error::DeserializeError("Invalid GetBlockTxsData: indexes are not sorted and unique".to_string()));
+            }
+        }
+
+        Ok(GetBlockTxsData {
+            burn_header_hash,
+            block_hash,
+            indexes
+        })
+    }
+}
+
+impl StacksMessageCodec for BlockTxsData {
+    fn consensus_serialize<W: Write>(&self, fd: &mut W) -> Result<(), net_error> {
+        write_next(fd, &self.burn_header_hash)?;
+        write_next(fd, &self.block_hash)?;
+        write_next(fd, &self.txs)?;
+        Ok(())
+    }
+
+    fn consensus_deserialize<R: Read>(fd: &mut R) -> Result<BlockTxsData, net_error> {
+        let burn_header_hash : BurnchainHeaderHash = read_next(fd)?;
+        let block_hash : BlockHeaderHash = read_next(fd)?;
+        let txs : Vec<StacksTransaction> = {
+            // loose upper-bound
+            let mut bound_read = BoundReader::from_reader(fd, MAX_MESSAGE_LEN as u64);
+            read_next_at_most::<_, StacksTransaction>(&mut bound_read, COMPACT_BLOCK_TXS_MAX)
+        }?;
+
+        Ok(BlockTxsData {
+            burn_header_hash,
+            block_hash,
+            txs
+        })
+    }
+}
+
 impl NeighborAddress {
     pub fn from_neighbor(n: &Neighbor) -> NeighborAddress {
         NeighborAddress {
@@ -751,7 +910,12 @@ impl StacksMessageType {
             StacksMessageType::Transaction(ref _m) => StacksMessageID::Transaction,
             StacksMessageType::Nack(ref _m) => StacksMessageID::Nack,
             StacksMessageType::Ping(ref _m) => StacksMessageID::Ping,
-            StacksMessageType::Pong(ref _m) => StacksMessageID::Pong
+            StacksMessageType::Pong(ref _m) => StacksMessageID::Pong,
+            StacksMessageType::MemPoolSync(ref _m) => StacksMessageID::MemPoolSync,
+            StacksMessageType::MemPoolTxs(ref _m) => StacksMessageID::MemPoolTxs,
+            StacksMessageType::CompactBlock(ref _m) => StacksMessageID::CompactBlock,
+            StacksMessageType::GetBlockTxs(ref _m) => StacksMessageID::GetBlockTxs,
+            StacksMessageType::BlockTxs(ref _m) => StacksMessageID::BlockTxs
         }
     }
 
@@ -771,7 +935,12 @@ impl StacksMessageType {
             StacksMessageType::Transaction(ref _m) => "Transaction",
             StacksMessageType::Nack(ref _m) => "Nack",
             StacksMessageType::Ping(ref _m) => "Ping",
-            StacksMessageType::Pong(ref _m) => "Pong"
+            StacksMessageType::Pong(ref _m) => "Pong",
+            StacksMessageType::MemPoolSync(ref _m) => "MemPoolSync",
+            StacksMessageType::MemPoolTxs(ref _m) => "MemPoolTxs",
+            StacksMessageType::CompactBlock(ref _m) => "CompactBlock",
+            StacksMessageType::GetBlockTxs(ref _m) => "GetBlockTxs",
+            StacksMessageType::BlockTxs(ref _m) => "BlockTxs"
         }
     }
 }
@@ -799,6 +968,11 @@ impl StacksMessageCodec for StacksMessageID {
             x if x == StacksMessageID::Nack as u8 => StacksMessageID::Nack,
             x if x == StacksMessageID::Ping as u8 => StacksMessageID::Ping,
             x if x == StacksMessageID::Pong as u8 => StacksMessageID::Pong,
+            x if x == StacksMessageID::MemPoolSync as u8 => StacksMessageID::MemPoolSync,
+            x if x == StacksMessageID::MemPoolTxs as u8 => StacksMessageID::MemPoolTxs,
+            x if x == StacksMessageID::CompactBlock as u8 => StacksMessageID::CompactBlock,
+            x if x == StacksMessageID::GetBlockTxs as u8 => StacksMessageID::GetBlockTxs,
+            x if x == StacksMessageID::BlockTxs as u8 => StacksMessageID::BlockTxs,
             _ => { return Err(net_error::DeserializeError("Unknown message ID".to_string())); }
         };
         Ok(id)
@@ -823,7 +997,12 @@ impl StacksMessageCodec for StacksMessageType {
             StacksMessageType::Transaction(ref m) => write_next(fd, m)?,
             StacksMessageType::Nack(ref m) => write_next(fd, m)?,
             StacksMessageType::Ping(ref m) => write_next(fd, m)?,
-            StacksMessageType::Pong(ref m) => write_next(fd, m)?
+            StacksMessageType::Pong(ref m) => write_next(fd, m)?,
+            StacksMessageType::MemPoolSync(ref m) => write_next(fd, m)?,
+            StacksMessageType::MemPoolTxs(ref m) => write_next(fd, m)?,
+            StacksMessageType::CompactBlock(ref m) => write_next(fd, m)?,
+            StacksMessageType::GetBlockTxs(ref m) => write_next(fd, m)?,
+            StacksMessageType::BlockTxs(ref m) => write_next(fd, m)?
         }
         Ok(())
     }
@@ -846,6 +1025,11 @@ impl StacksMessageCodec for StacksMessageType {
             StacksMessageID::Nack => { let m : NackData = read_next(fd)?; StacksMessageType::Nack(m) },
             StacksMessageID::Ping => { let m : PingData = read_next(fd)?; StacksMessageType::Ping(m) },
             StacksMessageID::Pong => { let m : PongData = read_next(fd)?; StacksMessageType::Pong(m) },
+            StacksMessageID::MemPoolSync => { let m : MemPoolSyncData = read_next(fd)?; StacksMessageType::MemPoolSync(m) },
+            StacksMessageID::MemPoolTxs => { let m : MemPoolTxsData = read_next(fd)?; StacksMessageType::MemPoolTxs(m) },
+            StacksMessageID::CompactBlock => { let m : CompactBlockData = read_next(fd)?; StacksMessageType::CompactBlock(m) },
+            StacksMessageID::GetBlockTxs => { let m : GetBlockTxsData = read_next(fd)?; StacksMessageType::GetBlockTxs(m) },
+            StacksMessageID::BlockTxs => { let m : BlockTxsData = read_next(fd)?; StacksMessageType::BlockTxs(m) },
             StacksMessageID::Reserved => { return Err(net_error::DeserializeError("Unsupported message ID 'reserved'".to_string())); }
         };
         Ok(message)
@@ -1048,6 +1232,8 @@ pub mod test {
 
     use util::hash::hex_bytes;
     use util::secp256k1::*;
+
+    use chainstate::stacks::test::make_codec_test_block;
     
     fn check_overflow<T>(r: Result<T, net_error>) -> bool {
         match r {
@@ -1527,6 +1713,113 @@ pub mod test {
         check_codec_and_corruption::<BlocksAvailableData>(&data, &bytes);
     }
 
+    #[test]
+    fn codec_MemPoolSyncData() {
+        let data = MemPoolSyncData {
+            seed: 0x0102030405060708,
+            short_txids: vec![0x1111111111111111, 0x2222222222222222]
+        };
+        let bytes = vec![
+            // seed
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            // length
+            0x00, 0x00, 0x00, 0x02,
+            // short txids
+            0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+            0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22, 0x22,
+        ];
+
+        check_codec_and_corruption::<MemPoolSyncData>(&data, &bytes);
+
+        // short txids must be sorted and unique
+        let unsorted = MemPoolSyncData {
+            seed: 0x0102030405060708,
+            short_txids: vec![0x2222222222222222, 0x1111111111111111]
+        };
+        assert!(check_deserialize_failure::<MemPoolSyncData>(&unsorted));
+
+        let duplicated = MemPoolSyncData {
+            seed: 0x0102030405060708,
+            short_txids: vec![0x1111111111111111, 0x1111111111111111]
+        };
+        assert!(check_deserialize_failure::<MemPoolSyncData>(&duplicated));
+
+        // can't send too many short txids
+        let too_many = MemPoolSyncData {
+            seed: 0x0102030405060708,
+            short_txids: (0..(MEMPOOL_SYNC_TXIDS_MAX as u64) + 1).collect()
+        };
+        assert!(check_deserialize_failure::<MemPoolSyncData>(&too_many));
+    }
+
+    #[test]
+    fn codec_CompactBlockData() {
+        let block = make_codec_test_block(10);
+        let data = CompactBlockData::from_block(&BurnchainHeaderHash([0x11; 32]), &block, 0x0102030405060708);
+
+        let mut bytes = vec![];
+        bytes.append(&mut vec![0x11; 32]);
+        block.header.consensus_serialize(&mut bytes).unwrap();
+        bytes.append(&mut vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+        data.short_txids.consensus_serialize(&mut bytes).unwrap();
+        // one prefilled transaction (the coinbase) at index 0
+        bytes.append(&mut vec![0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+        block.txs[0].consensus_serialize(&mut bytes).unwrap();
+
+        check_codec_and_corruption::<CompactBlockData>(&data, &bytes);
+
+        // prefilled transactions must be in range
+        let mut out_of_range = data.clone();
+        out_of_range.prefilled_txs[0].index = block.txs.len() as u32;
+        assert!(check_deserialize_failure::<CompactBlockData>(&out_of_range));
+
+        // prefilled transactions must be sorted and unique
+        let mut duplicated = data.clone();
+        duplicated.short_txids.pop();
+        duplicated.prefilled_txs.push(duplicated.prefilled_txs[0].clone());
+        assert!(check_deserialize_failure::<CompactBlockData>(&duplicated));
+
+        let mut unsorted = data.clone();
+        unsorted.short_txids.pop();
+        unsorted.prefilled_txs.insert(0, PrefilledTransaction { index: 1, tx: block.txs[1].clone() });
+        assert!(check_deserialize_failure::<CompactBlockData>(&unsorted));
+
+        // can't have too many transactions
+        let mut too_many = data.clone();
+        too_many.short_txids = (0..(COMPACT_BLOCK_TXS_MAX as u64)).collect();
+        assert!(check_deserialize_failure::<CompactBlockData>(&too_many));
+    }
+
+    #[test]
+    fn codec_GetBlockTxsData() {
+        let data = GetBlockTxsData {
+            burn_header_hash: BurnchainHeaderHash([0x11; 32]),
+            block_hash: BlockHeaderHash([0x22; 32]),
+            indexes: vec![1, 2, 3]
+        };
+        let mut bytes = vec![];
+        bytes.append(&mut vec![0x11; 32]);
+        bytes.append(&mut vec![0x22; 32]);
+        bytes.append(&mut vec![0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03]);
+
+        check_codec_and_corruption::<GetBlockTxsData>(&data, &bytes);
+
+        // indexes must be sorted and unique
+        let unsorted = GetBlockTxsData {
+            burn_header_hash: BurnchainHeaderHash([0x11; 32]),
+            block_hash: BlockHeaderHash([0x22; 32]),
+            indexes: vec![2, 1]
+        };
+        assert!(check_deserialize_failure::<GetBlockTxsData>(&unsorted));
+
+        let duplicated = GetBlockTxsData {
+            burn_header_hash: BurnchainHeaderHash([0x11; 32]),
+            block_hash: BlockHeaderHash([0x22; 32]),
+            indexes: vec![1, 1]
+        };
+        assert!(check_deserialize_failure::<GetBlockTxsData>(&duplicated));
+    }
+
     #[test]
     fn codec_StacksMessage() {
         let payloads: Vec<StacksMessageType> = vec![
@@ -1592,6 +1885,24 @@ pub mod test {
             StacksMessageType::Pong(PongData {
                 nonce: 0x01020304
             }),
+            StacksMessageType::MemPoolSync(MemPoolSyncData {
+                seed: 0x0102030405060708,
+                short_txids: vec![0x1111111111111111, 0x2222222222222222]
+            }),
+            StacksMessageType::MemPoolTxs(MemPoolTxsData {
+                txs: vec![]
+            }),
+            StacksMessageType::CompactBlock(CompactBlockData::from_block(&BurnchainHeaderHash([0x11; 32]), &make_codec_test_block(3), 0x0102030405060708)),
+            StacksMessageType::GetBlockTxs(GetBlockTxsData {
+                burn_header_hash: BurnchainHeaderHash([0x11; 32]),
+                block_hash: BlockHeaderHash([0x22; 32]),
+                indexes: vec![1, 2]
+            }),
+            StacksMessageType::BlockTxs(BlockTxsData {
+                burn_header_hash: BurnchainHeaderHash([0x11; 32]),
+                block_hash: BlockHeaderHash([0x22; 32]),
+                txs: vec![]
+            }),
         ];
 
         let mut maximal_relayers : Vec<RelayData> = vec![];
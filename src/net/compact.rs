@@ -0,0 +1,557 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/// This module implements compact block relay.  Instead of pushing a whole anchored block, a node
+/// can push the block header along with a short txid for each transaction (see
+/// `MemPoolSyncData::short_txid`).  The recipient rebuilds the block from its mempool, and asks
+/// the sender for whichever transactions it could not find.  If the block still can't be rebuilt,
+/// the recipient falls back to fetching it with the block downloader.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use net::*;
+use net::Error as net_error;
+use net::connection::ReplyHandleP2P;
+use net::p2p::PeerNetwork;
+
+use core::mempool::MemPoolDB;
+
+use burnchains::Txid;
+use burnchains::BurnchainHeaderHash;
+
+use chainstate::burn::BlockHeaderHash;
+use chainstate::stacks::StacksBlock;
+use chainstate::stacks::StacksBlockHeader;
+use chainstate::stacks::StacksTransaction;
+
+use util::log;
+
+// maximum number of recent mempool transactions to consider when rebuilding a compact block
+pub const COMPACT_BLOCK_MEMPOOL_SCAN_MAX : u64 = 65536;
+
+impl CompactBlockData {
+    /// Make a compact block out of an anchored block.
+    /// The coinbase is always sent in full, since no one else will have it in their mempool.
+    pub fn from_block(burn_header_hash: &BurnchainHeaderHash, block: &StacksBlock, seed: u64) -> CompactBlockData {
+        let mut short_txids = vec![];
+        let mut prefilled_txs = vec![];
+        for (i, tx) in block.txs.iter().enumerate() {
+            if i == 0 {
+                prefilled_txs.push(PrefilledTransaction {
+                    index: 0,
+                    tx: tx.clone()
+                });
+            }
+            else {
+                short_txids.push(MemPoolSyncData::short_txid(seed, &tx.txid()));
+            }
+        }
+
+        CompactBlockData {
+            burn_header_hash: burn_header_hash.clone(),
+            header: block.header.clone(),
+            seed,
+            short_txids,
+            prefilled_txs
+        }
+    }
+
+    pub fn block_hash(&self) -> BlockHeaderHash {
+        self.header.block_hash()
+    }
+
+    pub fn index_block_hash(&self) -> BlockHeaderHash {
+        StacksBlockHeader::make_index_block_hash(&self.burn_header_hash, &self.block_hash())
+    }
+
+    pub fn num_txs(&self) -> usize {
+        self.short_txids.len() + self.prefilled_txs.len()
+    }
+}
+
+/// Short txids of our recent mempool transactions.
+/// Short txids are salted with a per-block seed, so they can't be stored in the mempool itself.
+/// Instead, we read the recent txids once per batch of compact blocks, and hash them once per
+/// distinct seed.
+pub struct MemPoolShortTxidIndex {
+    txids: Vec<Txid>,
+    short_txids: HashMap<u64, HashMap<u64, Option<Txid>>>,     // seed --> short txid --> txid (None if ambiguous)
+}
+
+impl MemPoolShortTxidIndex {
+    pub fn new(mempool: &MemPoolDB) -> Result<MemPoolShortTxidIndex, net_error> {
+        let txids = MemPoolDB::get_recent_txids(mempool.conn(), COMPACT_BLOCK_MEMPOOL_SCAN_MAX)
+            .map_err(net_error::DBError)?;
+
+        Ok(MemPoolShortTxidIndex {
+            txids,
+            short_txids: HashMap::new()
+        })
+    }
+
+    /// Find the mempool transaction with the given short txid.
+    /// Returns None if there is no such transaction, or if more than one transaction matches.
+    pub fn lookup(&mut self, seed: u64, short_txid: u64) -> Option<Txid> {
+        let txids = &self.txids;
+        let seed_index = self.short_txids.entry(seed).or_insert_with(|| {
+            let mut candidates : HashMap<u64, Option<Txid>> = HashMap::new();
+            for txid in txids.iter() {
+                match candidates.entry(MemPoolSyncData::short_txid(seed, txid)) {
+                    Entry::Occupied(mut e) => {
+                        // collision -- ambiguous
+                        e.insert(None);
+                    },
+                    Entry::Vacant(e) => {
+                        e.insert(Some(txid.clone()));
+                    }
+                }
+            }
+            candidates
+        });
+
+        match seed_index.get(&short_txid) {
+            Some(Some(txid)) => Some(txid.clone()),
+            _ => None
+        }
+    }
+}
+
+/// A compact block that we're in the process of rebuilding
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartialBlock {
+    pub neighbor: NeighborKey,                  // who sent us the compact block
+    pub relayers: Vec<RelayData>,               // relay hints from the compact block message
+    pub data: CompactBlockData,
+    pub txs: Vec<Option<StacksTransaction>>,    // the block's transactions we have so far
+    short_txid_indexes: Vec<u32>,               // block position of each short txid
+}
+
+impl PartialBlock {
+    pub fn new(neighbor: NeighborKey, relayers: Vec<RelayData>, data: CompactBlockData) -> PartialBlock {
+        let num_txs = data.num_txs();
+        let mut txs : Vec<Option<StacksTransaction>> = vec![None; num_txs];
+        for prefilled in data.prefilled_txs.iter() {
+            // index is in range, since the compact block was checked when it was decoded
+            txs[prefilled.index as usize] = Some(prefilled.tx.clone());
+        }
+
+        let short_txid_indexes = (0..num_txs)
+            .filter(|i| txs[*i].is_none())
+            .map(|i| i as u32)
+            .collect();
+
+        PartialBlock {
+            neighbor,
+            relayers,
+            data,
+            txs,
+            short_txid_indexes
+        }
+    }
+
+    /// Fill in whatever transactions we can find in our mempool.
+    /// A short txid that matches more than one of our transactions is left unfilled.
+    pub fn fill_from_mempool(&mut self, mempool: &MemPoolDB, index: &mut MemPoolShortTxidIndex) -> Result<(), net_error> {
+        for (short_txid, pos) in self.data.short_txids.iter().zip(self.short_txid_indexes.iter()) {
+            if self.txs[*pos as usize].is_some() {
+                continue;
+            }
+            if let Some(txid) = index.lookup(self.data.seed, *short_txid) {
+                if let Some(tx_info) = MemPoolDB::get_tx(mempool.conn(), &txid).map_err(net_error::DBError)? {
+                    self.txs[*pos as usize] = Some(tx_info.tx);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Positions of the transactions we still need
+    pub fn missing_indexes(&self) -> Vec<u32> {
+        self.txs.iter()
+            .enumerate()
+            .filter(|(_, tx_opt)| tx_opt.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Fill in the missing transactions from a BlockTxs reply to the GetBlockTxs request
+    /// for `indexes`.
+    pub fn fill_from_block_txs(&mut self, indexes: &Vec<u32>, block_txs: BlockTxsData) -> Result<(), net_error> {
+        if block_txs.burn_header_hash != self.data.burn_header_hash || block_txs.block_hash != self.data.block_hash() {
+            return Err(net_error::InvalidMessage);
+        }
+        if block_txs.txs.len() != indexes.len() {
+            return Err(net_error::InvalidMessage);
+        }
+
+        for (index, tx) in indexes.iter().zip(block_txs.txs.into_iter()) {
+            match self.txs.get_mut(*index as usize) {
+                Some(tx_slot) => {
+                    *tx_slot = Some(tx);
+                },
+                None => {
+                    return Err(net_error::InvalidMessage);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Assemble the block, if we have all of its transactions.
+    /// Returns Ok(None) if we're still missing transactions.
+    /// The block is passed through the block codec, so a rebuilt block gets the same checks as one
+    /// that was pushed to us in full (including the transaction Merkle root).  An error here
+    /// means that either the compact block was bad, or a short txid matched the wrong transaction.
+    pub fn try_assemble(&self) -> Result<Option<StacksBlock>, net_error> {
+        let mut txs = Vec::with_capacity(self.txs.len());
+        for tx_opt in self.txs.iter() {
+            match tx_opt {
+                Some(tx) => txs.push(tx.clone()),
+                None => {
+                    return Ok(None);
+                }
+            }
+        }
+
+        let block = StacksBlock {
+            header: self.data.header.clone(),
+            txs
+        };
+
+        let mut block_bytes = vec![];
+        block.consensus_serialize(&mut block_bytes)?;
+        let block = StacksBlock::consensus_deserialize(&mut &block_bytes[..])?;
+        Ok(Some(block))
+    }
+}
+
+/// State of our in-flight requests for the missing transactions of compact blocks
+pub struct CompactBlockState {
+    pub requests: HashMap<BlockHeaderHash, (PartialBlock, Vec<u32>, ReplyHandleP2P)>,    // index block hash --> (block, requested indexes, request)
+    pub num_rebuilt: u64,                       // number of blocks rebuilt from fetched transactions
+    pub num_failed: u64,                        // number of blocks we had to give up on
+}
+
+impl CompactBlockState {
+    pub fn new() -> CompactBlockState {
+        CompactBlockState {
+            requests: HashMap::new(),
+            num_rebuilt: 0,
+            num_failed: 0,
+        }
+    }
+}
+
+impl PeerNetwork {
+    /// Ask the peer that sent us a compact block for the transactions we couldn't find in our
+    /// mempool.
+    pub fn compact_block_fetch_begin(&mut self, partial_block: PartialBlock) -> Result<(), net_error> {
+        let index_block_hash = partial_block.data.index_block_hash();
+        if self.compact_blocks.requests.contains_key(&index_block_hash) {
+            debug!("{:?}: already fetching transactions for compact block {}", &self.local_peer, &index_block_hash);
+            return Ok(());
+        }
+
+        let indexes = partial_block.missing_indexes();
+        let get_block_txs = GetBlockTxsData {
+            burn_header_hash: partial_block.data.burn_header_hash.clone(),
+            block_hash: partial_block.data.block_hash(),
+            indexes: indexes.clone()
+        };
+
+        debug!("{:?}: ask {:?} for {} transaction(s) in compact block {}", &self.local_peer, &partial_block.neighbor, indexes.len(), &index_block_hash);
+
+        let nk = partial_block.neighbor.clone();
+        let message = self.sign_for_peer(&nk, StacksMessageType::GetBlockTxs(get_block_txs))?;
+        let request = self.send_message(&nk, message, self.connection_opts.timeout)?;
+        self.compact_blocks.requests.insert(index_block_hash, (partial_block, indexes, request));
+        Ok(())
+    }
+
+    /// Finish rebuilding compact blocks whose missing transactions have arrived.
+    /// Rebuilt blocks are added to the network result as if they had been pushed to us by the
+    /// peer that sent the compact block.  If a block can't be rebuilt, wake up the block
+    /// downloader so it fetches the block instead.
+    pub fn compact_block_fetch_try_finish(&mut self, network_result: &mut NetworkResult) -> () {
+        let mut pending = HashMap::new();
+        let mut num_failed = 0;
+        let requests = std::mem::replace(&mut self.compact_blocks.requests, HashMap::new());
+
+        for (index_block_hash, (mut partial_block, indexes, mut request)) in requests.into_iter() {
+            if let Err(_e) = self.saturate_p2p_socket(request.get_event_id(), &mut request) {
+                debug!("{:?}: failed to send GetBlockTxs to {:?}: {:?}", &self.local_peer, &partial_block.neighbor, &_e);
+                num_failed += 1;
+                continue;
+            }
+
+            let block_txs = match request.try_send_recv() {
+                Ok(message) => {
+                    let _msgtype = message.get_message_name();
+                    match message.payload {
+                        StacksMessageType::BlockTxs(block_txs) => block_txs,
+                        StacksMessageType::Nack(nack_data) => {
+                            debug!("{:?}: remote neighbor {:?} nack'ed our GetBlockTxs for {}: NACK code {}", &self.local_peer, &partial_block.neighbor, &index_block_hash, nack_data.error_code);
+                            num_failed += 1;
+                            continue;
+                        },
+                        _ => {
+                            debug!("{:?}: remote neighbor {:?} sent an unexpected reply of '{}'", &self.local_peer, &partial_block.neighbor, _msgtype);
+                            num_failed += 1;
+                            continue;
+                        }
+                    }
+                },
+                Err(req_res) => {
+                    match req_res {
+                        Ok(same_req) => {
+                            // try again
+                            pending.insert(index_block_hash, (partial_block, indexes, same_req));
+                        },
+                        Err(_e) => {
+                            debug!("{:?}: failed to get transactions for compact block {} from {:?}: {:?}", &self.local_peer, &index_block_hash, &partial_block.neighbor, &_e);
+                            num_failed += 1;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let block_res = partial_block.fill_from_block_txs(&indexes, block_txs)
+                .and_then(|_| partial_block.try_assemble());
+
+            match block_res {
+                Ok(Some(block)) => {
+                    debug!("{:?}: rebuilt compact block {} from {:?}", &self.local_peer, &index_block_hash, &partial_block.neighbor);
+                    self.compact_blocks.num_rebuilt += 1;
+
+                    let blocks_data = BlocksData { blocks: vec![(partial_block.data.burn_header_hash.clone(), block)] };
+                    if let Some(blocks_msgs) = network_result.pushed_blocks.get_mut(&partial_block.neighbor) {
+                        blocks_msgs.push(blocks_data);
+                    }
+                    else {
+                        network_result.pushed_blocks.insert(partial_block.neighbor.clone(), vec![blocks_data]);
+                    }
+                },
+                Ok(None) => {
+                    debug!("{:?}: still missing transactions for compact block {}", &self.local_peer, &index_block_hash);
+                    num_failed += 1;
+                },
+                Err(e) => {
+                    info!("{:?}: failed to rebuild compact block {} from {:?}: {:?}", &self.local_peer, &index_block_hash, &partial_block.neighbor, &e);
+                    num_failed += 1;
+                }
+            }
+        }
+
+        self.compact_blocks.requests = pending;
+
+        if num_failed > 0 {
+            // get the block(s) the old-fashioned way
+            self.compact_blocks.num_failed += num_failed;
+            self.hint_download_rescan();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use chainstate::stacks::test::make_codec_test_block;
+    use chainstate::stacks::db::test::{instantiate_chainstate, chainstate_path};
+    use net::mempool::test::{make_mempool_txs, submit_mempool_txs};
+    use net::test::*;
+
+    use util::hash::MerkleTree;
+    use util::hash::Sha512Trunc256Sum;
+
+    /// Make a well-formed block out of the codec test block's header and the given transactions
+    pub fn make_block_with_txs(txs: Vec<StacksTransaction>) -> StacksBlock {
+        let mut block = make_codec_test_block(1);
+        let txid_vecs = txs.iter().map(|tx| tx.txid().as_bytes().to_vec()).collect();
+        let merkle_tree = MerkleTree::<Sha512Trunc256Sum>::new(&txid_vecs);
+        block.header.tx_merkle_root = merkle_tree.root();
+        block.txs = txs;
+        block
+    }
+
+    #[test]
+    fn test_compact_block_rebuild() {
+        let block = make_codec_test_block(10);
+        let burn_header_hash = BurnchainHeaderHash([0x11; 32]);
+        let nk = NeighborKey {
+            peer_version: 0x12345678,
+            network_id: 0x9abcdef0,
+            addrbytes: PeerAddress([0x01; 16]),
+            port: 12345,
+        };
+
+        let compact = CompactBlockData::from_block(&burn_header_hash, &block, 0x0102030405060708);
+        assert_eq!(compact.num_txs(), block.txs.len());
+        assert_eq!(compact.prefilled_txs.len(), 1);
+        assert_eq!(compact.prefilled_txs[0].tx, block.txs[0]);
+        assert_eq!(compact.block_hash(), block.block_hash());
+
+        // only the coinbase is available at first
+        let mut partial = PartialBlock::new(nk.clone(), vec![], compact.clone());
+        let missing = partial.missing_indexes();
+        assert_eq!(missing, (1..(block.txs.len() as u32)).collect::<Vec<u32>>());
+        assert_eq!(partial.try_assemble().unwrap(), None);
+
+        // wrong block
+        let bad_block_txs = BlockTxsData {
+            burn_header_hash: BurnchainHeaderHash([0x22; 32]),
+            block_hash: block.block_hash(),
+            txs: missing.iter().map(|i| block.txs[*i as usize].clone()).collect()
+        };
+        assert!(partial.clone().fill_from_block_txs(&missing, bad_block_txs).is_err());
+
+        // wrong number of transactions
+        let short_block_txs = BlockTxsData {
+            burn_header_hash: burn_header_hash.clone(),
+            block_hash: block.block_hash(),
+            txs: vec![]
+        };
+        assert!(partial.clone().fill_from_block_txs(&missing, short_block_txs).is_err());
+
+        // transactions in the wrong places
+        let mut swapped_partial = partial.clone();
+        let mut swapped_txs : Vec<StacksTransaction> = missing.iter().map(|i| block.txs[*i as usize].clone()).collect();
+        swapped_txs.swap(0, 1);
+        swapped_partial.fill_from_block_txs(&missing, BlockTxsData {
+            burn_header_hash: burn_header_hash.clone(),
+            block_hash: block.block_hash(),
+            txs: swapped_txs
+        }).unwrap();
+        assert!(swapped_partial.try_assemble().is_err());
+
+        // all transactions given
+        partial.fill_from_block_txs(&missing, BlockTxsData {
+            burn_header_hash: burn_header_hash.clone(),
+            block_hash: block.block_hash(),
+            txs: missing.iter().map(|i| block.txs[*i as usize].clone()).collect()
+        }).unwrap();
+        assert_eq!(partial.missing_indexes().len(), 0);
+        assert_eq!(partial.try_assemble().unwrap(), Some(block));
+    }
+
+    #[test]
+    fn test_compact_block_fill_from_mempool() {
+        let _chainstate = instantiate_chainstate(false, 0x80000000, "test_compact_block_fill_from_mempool");
+        let mut mempool = MemPoolDB::open(false, 0x80000000, &chainstate_path("test_compact_block_fill_from_mempool")).unwrap();
+
+        // the block's last transaction never makes it to our mempool
+        let mempool_txs = make_mempool_txs(5);
+        submit_mempool_txs(&mut mempool, &mempool_txs[0..4].to_vec());
+
+        let mut block_txs = vec![make_codec_test_block(1).txs[0].clone()];
+        block_txs.append(&mut mempool_txs.clone());
+        let block = make_block_with_txs(block_txs);
+
+        let burn_header_hash = BurnchainHeaderHash([0x11; 32]);
+        let nk = NeighborKey {
+            peer_version: 0x12345678,
+            network_id: 0x9abcdef0,
+            addrbytes: PeerAddress([0x01; 16]),
+            port: 12345,
+        };
+
+        // one index serves compact blocks with different seeds
+        let mut index = MemPoolShortTxidIndex::new(&mempool).unwrap();
+        for seed in [0x0102030405060708, 0x1112131415161718].iter() {
+            let compact = CompactBlockData::from_block(&burn_header_hash, &block, *seed);
+            let mut partial = PartialBlock::new(nk.clone(), vec![], compact);
+            assert_eq!(partial.missing_indexes(), vec![1, 2, 3, 4, 5]);
+
+            partial.fill_from_mempool(&mempool, &mut index).unwrap();
+            assert_eq!(partial.missing_indexes(), vec![5]);
+            for i in 1..5 {
+                assert_eq!(partial.txs[i], Some(block.txs[i].clone()));
+            }
+            assert_eq!(partial.try_assemble().unwrap(), None);
+
+            partial.fill_from_block_txs(&vec![5], BlockTxsData {
+                burn_header_hash: burn_header_hash.clone(),
+                block_hash: block.block_hash(),
+                txs: vec![block.txs[5].clone()]
+            }).unwrap();
+            assert_eq!(partial.try_assemble().unwrap(), Some(block.clone()));
+        }
+        assert_eq!(index.short_txids.len(), 2);
+
+        // short txids for another seed don't match
+        let other_short_txid = MemPoolSyncData::short_txid(0x2122232425262728, &mempool_txs[0].txid());
+        assert_eq!(index.lookup(0x0102030405060708, other_short_txid), None);
+        assert_eq!(index.lookup(0x2122232425262728, other_short_txid), Some(mempool_txs[0].txid()));
+    }
+
+    #[test]
+    fn test_compact_block_fetch_falls_back_to_downloader() {
+        let mut peer_1_config = TestPeerConfig::new("test_compact_block_fetch_falls_back_to_downloader", 32110, 42110);
+        let mut peer_2_config = TestPeerConfig::new("test_compact_block_fetch_falls_back_to_downloader", 32111, 42111);
+
+        peer_1_config.add_neighbor(&peer_2_config.to_neighbor());
+        peer_2_config.add_neighbor(&peer_1_config.to_neighbor());
+
+        let nk_1 = peer_1_config.to_neighbor().addr;
+
+        let mut peer_1 = TestPeer::new(peer_1_config);
+        let mut peer_2 = TestPeer::new(peer_2_config);
+
+        // wait for peer 2 to finish its handshake with peer 1
+        let mut round = 0;
+        loop {
+            let _ = peer_1.step();
+            let _ = peer_2.step();
+
+            let authenticated = match peer_2.network.get_event_id(&nk_1) {
+                Some(event_id) => peer_2.network.peers.get(&event_id).map(|convo| convo.is_authenticated()).unwrap_or(false),
+                None => false
+            };
+            if authenticated {
+                break;
+            }
+
+            round += 1;
+            assert!(round < 1000, "peer 2 never connected to peer 1");
+        }
+
+        // peer 1 sent peer 2 a compact block, but doesn't have the block itself
+        let block = make_codec_test_block(3);
+        let compact = CompactBlockData::from_block(&BurnchainHeaderHash([0x11; 32]), &block, 0x0102030405060708);
+        let partial = PartialBlock::new(nk_1.clone(), vec![], compact);
+
+        peer_2.network.compact_block_fetch_begin(partial).unwrap();
+        assert_eq!(peer_2.network.compact_blocks.requests.len(), 1);
+
+        // peer 1 NACKs the GetBlockTxs, so peer 2 gives up and leaves the block to its downloader
+        let mut round = 0;
+        while peer_2.network.compact_blocks.num_failed == 0 {
+            let _ = peer_1.step();
+            let _ = peer_2.step();
+
+            round += 1;
+            assert!(round < 1000, "peer 2 never gave up on the compact block");
+        }
+
+        assert_eq!(peer_2.network.compact_blocks.num_failed, 1);
+        assert_eq!(peer_2.network.compact_blocks.num_rebuilt, 0);
+        assert_eq!(peer_2.network.compact_blocks.requests.len(), 0);
+    }
+}
@@ -17,8 +17,10 @@
  along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
 */
 
+use std::mem;
 use std::net;
 use std::io;
+use std::fmt;
 use std::io::{Read, Write};
 use std::ops::Deref;
 use std::ops::DerefMut;
@@ -37,6 +39,13 @@ use std::sync::mpsc::RecvTimeoutError;
 use mio;
 use mio::net as mio_net;
 
+use rand::thread_rng;
+use rand::RngCore;
+
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{ Aead, NewAead, Payload };
+use chacha20poly1305::aead::generic_array::GenericArray;
+
 use net::Error as net_error;
 use net::StacksMessageCodec;
 use net::Preamble;
@@ -50,11 +59,16 @@ use net::StacksHttp;
 use net::MessageSequence;
 use net::codec::*;
 use net::MAX_MESSAGE_LEN;
+use net::MAX_HANDSHAKE_MESSAGE_LEN;
 
 use net::inv::INV_SYNC_INTERVAL;
 use net::download::BLOCK_DOWNLOAD_INTERVAL;
+use net::mempool::{ MEMPOOL_SYNC_INTERVAL, MEMPOOL_SYNC_MAX_BYTES };
 use net::neighbors::{ NEIGHBOR_WALK_INTERVAL, NEIGHBOR_REQUEST_TIMEOUT };
+use net::ratelimit::RPCRateLimits;
+use net::reputation::{ REPUTATION_BAN_THRESHOLD, REPUTATION_DECAY_INTERVAL };
 
+use util;
 use util::strings::UrlString;
 
 use vm::{
@@ -66,6 +80,8 @@ use chainstate::burn::ConsensusHash;
 
 use util::log;
 use util::secp256k1::Secp256k1PublicKey;
+use util::secp256k1::Secp256k1PrivateKey;
+use util::hash::Sha512Trunc256Sum;
 use util::get_epoch_time_secs;
 use util::sleep_ms;
 use util::hash::to_hex;
@@ -282,7 +298,121 @@ impl<P: ProtocolFamily> Write for NetworkReplyHandle<P> {
 #[derive(Debug)]
 struct InflightMessage<P: ProtocolFamily> {
     pipe_read: Option<PipeRead>,
-    notify: Option<ReceiverNotify<P>>
+    notify: Option<ReceiverNotify<P>>,
+    encrypt: bool                       // was the channel encrypted when this message was queued?
+}
+
+/// Encrypted message frames begin with these bytes.  A plaintext p2p message begins with its
+/// preamble's peer version, which never takes this value.
+pub const ENCRYPTED_FRAME_MAGIC : [u8; 4] = [0xff, 0xff, 0xff, 0xfe];
+pub const ENCRYPTED_FRAME_HEADER_LEN : usize = 8;       // magic, and u32 body length
+pub const ENCRYPTED_FRAME_NONCE_LEN : usize = 12;
+pub const ENCRYPTED_FRAME_TAG_LEN : usize = 16;
+pub const MAX_ENCRYPTED_FRAME_LEN : usize = (MAX_MESSAGE_LEN as usize) + ENCRYPTED_FRAME_NONCE_LEN + ENCRYPTED_FRAME_TAG_LEN;
+
+// Encrypted bytes held from a peer that hasn't finished its handshake yet: one frame, no bigger
+// than the biggest handshake message
+pub const MAX_PRE_CIPHER_BUFFER_LEN : usize = ENCRYPTED_FRAME_HEADER_LEN + ENCRYPTED_FRAME_NONCE_LEN + (MAX_HANDSHAKE_MESSAGE_LEN as usize) + ENCRYPTED_FRAME_TAG_LEN;
+
+/// One direction of an encrypted channel.  Each message is sealed into its own frame with
+/// ChaCha20-Poly1305 under a random nonce.  Frames are numbered, and the frame number is
+/// authenticated along with the frame header, so a frame can't be dropped, replayed, or
+/// reordered without the receiver noticing.
+pub struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+    num_frames: u64
+}
+
+impl fmt::Debug for FrameCipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // don't leak the key
+        write!(f, "FrameCipher(num_frames={})", self.num_frames)
+    }
+}
+
+impl FrameCipher {
+    pub fn new(key: &[u8; 32]) -> FrameCipher {
+        FrameCipher {
+            cipher: ChaCha20Poly1305::new(GenericArray::from_slice(&key[..])),
+            num_frames: 0
+        }
+    }
+
+    /// Derive the sending and receiving ciphers for a p2p connection from the ECDH secret between
+    /// our session key and the remote peer's session key.  Each direction gets its own key.  The
+    /// salt binds the keys to this particular handshake.
+    /// Returns (send cipher, receive cipher).
+    pub fn derive_pair(local_private_key: &Secp256k1PrivateKey, remote_public_key: &Secp256k1PublicKey, salt: &[u8]) -> (FrameCipher, FrameCipher) {
+        let secret = local_private_key.shared_secret(remote_public_key);
+        let local_public_key = Secp256k1PublicKey::from_private(local_private_key);
+
+        let derive = |sender: &Secp256k1PublicKey| {
+            let mut material = vec![];
+            material.extend_from_slice(&secret);
+            material.extend_from_slice(salt);
+            material.extend_from_slice(&sender.to_bytes_compressed());
+            FrameCipher::new(&Sha512Trunc256Sum::from_data(&material).0)
+        };
+
+        (derive(&local_public_key), derive(remote_public_key))
+    }
+
+    fn associated_data(frame_num: u64, body_len: u32) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(16);
+        aad.extend_from_slice(&ENCRYPTED_FRAME_MAGIC);
+        aad.extend_from_slice(&body_len.to_be_bytes());
+        aad.extend_from_slice(&frame_num.to_be_bytes());
+        aad
+    }
+
+    /// Encrypt a message into a frame
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, net_error> {
+        let body_len = ENCRYPTED_FRAME_NONCE_LEN + plaintext.len() + ENCRYPTED_FRAME_TAG_LEN;
+        if body_len > MAX_ENCRYPTED_FRAME_LEN {
+            return Err(net_error::OverflowError(format!("Message of {} bytes is too big to encrypt", plaintext.len())));
+        }
+
+        let mut nonce = [0u8; ENCRYPTED_FRAME_NONCE_LEN];
+        util::with_rng(|rng| rng.fill_bytes(&mut nonce));
+
+        let aad = FrameCipher::associated_data(self.num_frames, body_len as u32);
+        let ciphertext = self.cipher.encrypt(GenericArray::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+            .map_err(|_e| net_error::SerializeError("Failed to encrypt message".to_string()))?;
+
+        let mut frame = Vec::with_capacity(ENCRYPTED_FRAME_HEADER_LEN + body_len);
+        frame.extend_from_slice(&ENCRYPTED_FRAME_MAGIC);
+        frame.extend_from_slice(&(body_len as u32).to_be_bytes());
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&ciphertext);
+
+        self.num_frames += 1;
+        Ok(frame)
+    }
+
+    /// Decrypt a whole frame (including its header) into a message
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, net_error> {
+        if frame.len() < ENCRYPTED_FRAME_HEADER_LEN + ENCRYPTED_FRAME_NONCE_LEN + ENCRYPTED_FRAME_TAG_LEN || frame[0..4] != ENCRYPTED_FRAME_MAGIC {
+            return Err(net_error::InvalidMessage);
+        }
+
+        let body_len = ((frame[4] as u32) << 24) | ((frame[5] as u32) << 16) | ((frame[6] as u32) << 8) | (frame[7] as u32);
+        if (body_len as usize) != frame.len() - ENCRYPTED_FRAME_HEADER_LEN {
+            return Err(net_error::InvalidMessage);
+        }
+
+        let nonce = &frame[ENCRYPTED_FRAME_HEADER_LEN..(ENCRYPTED_FRAME_HEADER_LEN + ENCRYPTED_FRAME_NONCE_LEN)];
+        let ciphertext = &frame[(ENCRYPTED_FRAME_HEADER_LEN + ENCRYPTED_FRAME_NONCE_LEN)..];
+
+        let aad = FrameCipher::associated_data(self.num_frames, body_len);
+        let plaintext = self.cipher.decrypt(GenericArray::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_e| {
+                debug!("Failed to decrypt frame {}", self.num_frames);
+                net_error::InvalidMessage
+            })?;
+
+        self.num_frames += 1;
+        Ok(plaintext)
+    }
 }
 
 #[derive(Debug)]
@@ -298,6 +428,14 @@ struct ConnectionInbox<P: ProtocolFamily> {
     buf: Vec<u8>,
     message_ptr: usize,     // index into buf where the message begins
     payload_ptr: usize,     // for payloads of unknown length, this points to where to read next
+
+    // bytes read from the socket that have not been decrypted (if need be) and parsed yet
+    socket_in_buf: Vec<u8>,
+    cipher: Option<FrameCipher>,
+
+    // set once we receive a message that switches the connection to another protocol.  Nothing
+    // after it is parsed, and the bytes after it stay in socket_in_buf for the new protocol.
+    upgraded: bool,
 }
 
 #[derive(Debug)]
@@ -309,6 +447,8 @@ struct ConnectionOutbox<P: ProtocolFamily> {
     pending_message_fd: Option<PipeRead>,
     socket_out_buf: Vec<u8>,
     socket_out_ptr: usize,
+    socket_out_sealed: bool,                // has socket_out_buf been encrypted?
+    cipher: Option<FrameCipher>,
 
     // in-flight messages 
     inflight: VecDeque<ReceiverNotify<P>>
@@ -338,6 +478,8 @@ pub struct ConnectionOptions {
     pub walk_inbound_ratio: u64,
     pub inv_sync_interval: u64,
     pub download_interval: u64,
+    pub mempool_sync_interval: u64,
+    pub mempool_sync_max_bytes: u64,
     pub pingback_timeout: u64,
     pub dns_timeout: u128,
     pub max_inflight_blocks: u64,
@@ -346,7 +488,19 @@ pub struct ConnectionOptions {
     pub max_block_push_bandwidth: u64,
     pub max_microblocks_push_bandwidth: u64,
     pub max_transaction_push_bandwidth: u64,
+    pub max_mempool_sync_bandwidth: u64,
+    pub max_block_txs_read_bandwidth: u64,
     pub max_sockets: usize,
+    pub disable_p2p_encryption: bool,
+    pub reputation_ban_threshold: u64,
+    pub reputation_decay_interval: u64,
+
+    // RPC access control
+    pub rpc_api_keys: Vec<String>,
+    pub rpc_admin_api_keys: Vec<String>,
+    pub rpc_disable_post_transaction: bool,
+    pub rpc_ip_rate_limits: RPCRateLimits,
+    pub rpc_api_key_rate_limits: RPCRateLimits,
     
     // fault injection
     pub disable_neighbor_walk: bool,
@@ -358,6 +512,7 @@ pub struct ConnectionOptions {
     pub disable_block_advertisement: bool,
     pub disable_pingbacks: bool,
     pub disable_inbound_walks: bool,
+    pub disable_mempool_sync: bool,
 }
 
 impl std::default::Default for ConnectionOptions {
@@ -385,6 +540,8 @@ impl std::default::Default for ConnectionOptions {
             walk_inbound_ratio: 2,                              // walk inbound neighbors twice as often as outbound by default
             inv_sync_interval: INV_SYNC_INTERVAL,               // how often to synchronize block inventories
             download_interval: BLOCK_DOWNLOAD_INTERVAL,         // how often to scan for blocks to download
+            mempool_sync_interval: MEMPOOL_SYNC_INTERVAL,       // how often to ask a neighbor for the mempool transactions we're missing
+            mempool_sync_max_bytes: MEMPOOL_SYNC_MAX_BYTES,     // how many bytes of transactions to send back in reply to a mempool sync request
             pingback_timeout: 60,
             dns_timeout: 15_000,            // DNS timeout, in millis
             max_inflight_blocks: 6,         // number of parallel block downloads
@@ -395,7 +552,16 @@ impl std::default::Default for ConnectionOptions {
             max_block_push_bandwidth: 0,    // infinite upload bandwidth allowed
             max_microblocks_push_bandwidth: 0,     // infinite upload bandwidth allowed
             max_transaction_push_bandwidth: 0,      // infinite upload bandwidth allowed
+            max_mempool_sync_bandwidth: 0,          // infinite mempool sync bandwidth allowed
+            max_block_txs_read_bandwidth: 0,        // infinite block reads for GetBlockTxs allowed
             max_sockets: 800,               // maximum number of client sockets we'll ever register
+            reputation_ban_threshold: REPUTATION_BAN_THRESHOLD,     // misbehavior score at which a peer gets banned
+            reputation_decay_interval: REPUTATION_DECAY_INTERVAL,   // seconds for a peer's misbehavior score to drop by one point
+            rpc_api_keys: vec![],           // if non-empty, RPC clients must send one of these in X-Api-Key
+            rpc_admin_api_keys: vec![],     // if non-empty, peer admin clients must send one of these in X-Api-Key (otherwise, only localhost may)
+            rpc_disable_post_transaction: false,        // if true, only clients with an API key may POST transactions
+            rpc_ip_rate_limits: RPCRateLimits::unlimited(),         // per-client-IP requests per minute
+            rpc_api_key_rate_limits: RPCRateLimits::unlimited(),    // per-API-key requests per minute
 
             // no faults on by default
             disable_neighbor_walk: false,
@@ -407,6 +573,8 @@ impl std::default::Default for ConnectionOptions {
             disable_block_advertisement: false,
             disable_pingbacks: false,
             disable_inbound_walks: false,
+            disable_mempool_sync: false,
+            disable_p2p_encryption: false,          // advertise and negotiate encrypted p2p channels
         }
     }
 }
@@ -429,6 +597,9 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
             buf: vec![],
             message_ptr: 0,
             payload_ptr: 0,
+            socket_in_buf: vec![],
+            cipher: None,
+            upgraded: false,
         }
     }
 
@@ -672,6 +843,7 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
             }
 
             let mut consumed_message = false;
+            let mut upgraded = false;
             let bytes_consumed_message = {
                 let mut preamble_opt = self.preamble.take();
                 let bytes_consumed =
@@ -681,6 +853,7 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
                             Some(message) => {
                                 // queue up
                                 test_debug!("Consumed message '{}' (request {}) in {} bytes", message.get_message_name(), message.request_id(), bytes_consumed);
+                                upgraded = protocol.is_upgrade(&message);
                                 self.inbox.push_back(message);
                                 consumed_message = true;
                             },
@@ -702,6 +875,10 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
             }
 
             offset += bytes_consumed_message;
+            if upgraded {
+                self.stash_upgrade_bytes(&buf[offset..]);
+                return Ok(());
+            }
             if offset == buf.len() {
                 break;
             }
@@ -712,6 +889,7 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
         if self.buf.len() > 0 {
             loop {
                 let mut consumed_message = false;
+                let mut upgraded = false;
 
                 if self.preamble.is_none() {
                     let (preamble_opt, _bytes_consumed) = self.consume_preamble(protocol, &[])?;
@@ -729,6 +907,7 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
                             Some(message) => {
                                 // queue up
                                 test_debug!("Consumed bufferred message '{}' (request {}) from {} input buffer bytes", message.get_message_name(), message.request_id(), _bytes_consumed);
+                                upgraded = protocol.is_upgrade(&message);
                                 self.inbox.push_back(message);
                                 consumed_message = true;
                             },
@@ -741,6 +920,10 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
                         // next message
                         self.preamble = None;
                     }
+                    if upgraded {
+                        self.stash_upgrade_bytes(&[]);
+                        return Ok(());
+                    }
                 }
 
                 if !consumed_message {
@@ -753,6 +936,106 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
         Ok(())
     }
 
+    /// Stop parsing once we've received a message that switches the connection to another
+    /// protocol.  The bytes after it -- both the ones we've buffered and the `unconsumed` ones --
+    /// are put back into socket_in_buf, in the order in which they arrived.
+    fn stash_upgrade_bytes(&mut self, unconsumed: &[u8]) -> () {
+        let mut rest = mem::replace(&mut self.buf, vec![]);
+        rest.extend_from_slice(unconsumed);
+        rest.extend_from_slice(&self.socket_in_buf);
+
+        self.socket_in_buf = rest;
+        self.preamble = None;
+        self.message_ptr = 0;
+        self.payload_ptr = 0;
+        self.upgraded = true;
+    }
+
+    /// Are we between messages?
+    fn at_message_boundary(&self) -> bool {
+        self.preamble.is_none() && self.buf.len() == 0
+    }
+
+    /// How many more plaintext bytes can we consume without going past the end of the message
+    /// we're currently parsing?
+    fn plaintext_bytes_wanted(&self, protocol: &mut P) -> usize {
+        let wanted = match self.preamble {
+            None => protocol.preamble_size_hint().saturating_sub(self.buf.len()),
+            Some(ref preamble) => match protocol.payload_len(preamble) {
+                Some(payload_len) => (self.message_ptr + payload_len).saturating_sub(self.buf.len()),
+                None => usize::max_value()
+            }
+        };
+        if wanted == 0 { 1 } else { wanted }
+    }
+
+    /// Consume the bytes we've read from the socket.
+    /// Plaintext messages are parsed as-is, and encrypted frames are decrypted and then parsed.
+    /// Plaintext is consumed one message at a time, since the remote peer may start sending
+    /// encrypted frames as soon as the handshake completes.  Frames that arrive before we've
+    /// installed a cipher are held until we do.  Once we have a cipher, plaintext messages are no
+    /// longer accepted.
+    fn consume_socket_bytes(&mut self, protocol: &mut P) -> Result<(), net_error> {
+        while !self.upgraded && self.socket_in_buf.len() > 0 {
+            if self.at_message_boundary() {
+                let prefix_len = if self.socket_in_buf.len() < ENCRYPTED_FRAME_MAGIC.len() { self.socket_in_buf.len() } else { ENCRYPTED_FRAME_MAGIC.len() };
+                if self.socket_in_buf[0..prefix_len] == ENCRYPTED_FRAME_MAGIC[0..prefix_len] {
+                    // (start of) an encrypted frame
+                    if self.socket_in_buf.len() < ENCRYPTED_FRAME_HEADER_LEN {
+                        break;
+                    }
+
+                    let body_len = ((self.socket_in_buf[4] as usize) << 24) | ((self.socket_in_buf[5] as usize) << 16) | ((self.socket_in_buf[6] as usize) << 8) | (self.socket_in_buf[7] as usize);
+                    if body_len > MAX_ENCRYPTED_FRAME_LEN {
+                        debug!("Encrypted frame of {} bytes is too big", body_len);
+                        return Err(net_error::InvalidMessage);
+                    }
+
+                    if self.cipher.is_none() {
+                        // wait until the handshake gives us a cipher, but the peer isn't
+                        // authenticated yet, so don't hold much for it
+                        if self.socket_in_buf.len() > MAX_PRE_CIPHER_BUFFER_LEN {
+                            debug!("Too many bytes of encrypted frames received before encryption was negotiated");
+                            return Err(net_error::InvalidMessage);
+                        }
+                        break;
+                    }
+
+                    if self.socket_in_buf.len() < ENCRYPTED_FRAME_HEADER_LEN + body_len {
+                        break;
+                    }
+
+                    let frame : Vec<u8> = self.socket_in_buf.drain(0..(ENCRYPTED_FRAME_HEADER_LEN + body_len)).collect();
+                    let plaintext = match self.cipher {
+                        Some(ref mut cipher) => cipher.open(&frame)?,
+                        None => unreachable!()
+                    };
+
+                    self.consume_messages(protocol, &plaintext)?;
+                    if !self.at_message_boundary() {
+                        // frames hold exactly one message
+                        debug!("Encrypted frame did not contain a whole message");
+                        return Err(net_error::InvalidMessage);
+                    }
+                    continue;
+                }
+                else if self.cipher.is_some() {
+                    // no plaintext allowed once the channel is encrypted
+                    debug!("Received a plaintext message on an encrypted channel");
+                    return Err(net_error::InvalidMessage);
+                }
+            }
+
+            let to_consume = {
+                let wanted = self.plaintext_bytes_wanted(protocol);
+                if wanted < self.socket_in_buf.len() { wanted } else { self.socket_in_buf.len() }
+            };
+            let bytes : Vec<u8> = self.socket_in_buf.drain(0..to_consume).collect();
+            self.consume_messages(protocol, &bytes)?;
+        }
+        Ok(())
+    }
+
     /// Read bytes from an input stream, buffer them up, try to parse the buffer
     /// into messages, and enqueue the messages into the inbox.
     /// Returns net_error::RecvError if we couldn't read from the fd 
@@ -764,7 +1047,7 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
         let mut blocked = false;
         let mut total_read = 0;
         let mut socket_closed = false;
-        while !blocked {
+        while !blocked && !self.upgraded {
             // get the next bytes
             // NOTE: it's important that buf not be too big, since up to buf.len()-1 bytes may need
             // to be copied if a message boundary isn't aligned with buf (which is usually the
@@ -805,7 +1088,8 @@ impl<P: ProtocolFamily> ConnectionInbox<P> {
             
             if num_read > 0 {
                 // decode into message stream
-                self.consume_messages(protocol, &buf[0..num_read])?;
+                self.socket_in_buf.extend_from_slice(&buf[0..num_read]);
+                self.consume_socket_bytes(protocol)?;
             }
         }
         
@@ -835,6 +1119,8 @@ impl<P: ProtocolFamily> ConnectionOutbox<P> {
             pending_message_fd: None,
             socket_out_buf: vec![],
             socket_out_ptr: 0,
+            socket_out_sealed: false,
+            cipher: None,
             inflight: VecDeque::new()
         }
     }
@@ -881,7 +1167,8 @@ impl<P: ProtocolFamily> ConnectionOutbox<P> {
 
         let inflight = InflightMessage {
             pipe_read: Some(pipe_read),
-            notify: recv_notify
+            notify: recv_notify,
+            encrypt: self.cipher.is_some()
         };
         self.outbox.push_back(inflight);
         Ok(())
@@ -954,6 +1241,24 @@ impl<P: ProtocolFamily> ConnectionOutbox<P> {
                 }
             };
 
+            let encrypt = self.outbox.front().map(|m| m.encrypt).unwrap_or(false);
+            if encrypt && !self.socket_out_sealed {
+                if !message_eof {
+                    // can't send anything until we have the whole message to encrypt
+                    continue;
+                }
+
+                let frame = match self.cipher {
+                    Some(ref mut cipher) => cipher.seal(&self.socket_out_buf)?,
+                    None => {
+                        panic!("BUG: message was queued for encryption, but there is no cipher");
+                    }
+                };
+                self.socket_out_buf = frame;
+                self.socket_out_ptr = 0;
+                self.socket_out_sealed = true;
+            }
+
             if self.socket_out_ptr < self.socket_out_buf.len() {
                 // have pending bytes.
                 // send as many bytes as we can
@@ -993,6 +1298,7 @@ impl<P: ProtocolFamily> ConnectionOutbox<P> {
                 self.pending_message_fd = None;
                 self.socket_out_buf.clear();
                 self.socket_out_ptr = 0;
+                self.socket_out_sealed = false;
             }
         }
 
@@ -1195,6 +1501,34 @@ impl<P: ProtocolFamily + Clone> NetworkConnection<P> {
         self.inbox.public_key.is_some()
     }
 
+    /// Did the remote peer send a message that switches this connection to another protocol?
+    /// If so, nothing it sent after that message has been parsed.
+    pub fn is_upgraded(&self) -> bool {
+        self.inbox.upgraded
+    }
+
+    /// Take the bytes the remote peer sent after the message that switched this connection to
+    /// another protocol.
+    pub fn take_upgraded_bytes(&mut self) -> Vec<u8> {
+        if !self.inbox.upgraded {
+            return vec![];
+        }
+        mem::replace(&mut self.inbox.socket_in_buf, vec![])
+    }
+
+    /// Encrypt all messages queued from now on, and decrypt all frames received from now on.
+    /// Any encrypted frames that arrived before now are decrypted and parsed.
+    pub fn set_ciphers(&mut self, send_cipher: FrameCipher, recv_cipher: FrameCipher) -> Result<(), net_error> {
+        self.outbox.cipher = Some(send_cipher);
+        self.inbox.cipher = Some(recv_cipher);
+        self.inbox.consume_socket_bytes(&mut self.protocol)
+    }
+
+    /// Is this connection encrypted?
+    pub fn is_encrypted(&self) -> bool {
+        self.outbox.cipher.is_some()
+    }
+
     /// send a protocol message
     pub fn send_message<W: Write>(&mut self, fd: &mut W, msg: &P::Message) -> Result<(), net_error> {
         self.protocol.write_message(fd, msg)
@@ -1892,6 +2226,187 @@ mod test {
         pinger.join().unwrap();
     }
 
+    fn make_test_ping(privkey: &Secp256k1PrivateKey, i: u32) -> StacksMessage {
+        let mut ping = StacksMessage::new(0x12345678, 0x9abcdef0,
+                                          12345 + (i as u64),
+                                          &ConsensusHash::from_hex("1111111111111111111111111111111111111111").unwrap(),
+                                          12339 + (i as u64),
+                                          &ConsensusHash::from_hex("2222222222222222222222222222222222222222").unwrap(),
+                                          StacksMessageType::Ping(PingData { nonce: 0x01020304 + i }));
+        ping.sign(i, privkey).unwrap();
+        ping
+    }
+
+    #[test]
+    fn connection_frame_cipher() {
+        let privkey_1 = Secp256k1PrivateKey::new();
+        let privkey_2 = Secp256k1PrivateKey::new();
+        let pubkey_1 = Secp256k1PublicKey::from_private(&privkey_1);
+        let pubkey_2 = Secp256k1PublicKey::from_private(&privkey_2);
+
+        let (mut send_1, mut recv_1) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+        let (mut send_2, mut recv_2) = FrameCipher::derive_pair(&privkey_2, &pubkey_1, &[0x01; 65]);
+
+        // each direction works
+        let frame_1 = send_1.seal(&[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(&frame_1[0..4], &ENCRYPTED_FRAME_MAGIC);
+        assert_eq!(frame_1.len(), ENCRYPTED_FRAME_HEADER_LEN + ENCRYPTED_FRAME_NONCE_LEN + 5 + ENCRYPTED_FRAME_TAG_LEN);
+        assert_eq!(recv_2.open(&frame_1).unwrap(), vec![1, 2, 3, 4, 5]);
+
+        let frame_2 = send_2.seal(&[6, 7, 8]).unwrap();
+        assert_eq!(recv_1.open(&frame_2).unwrap(), vec![6, 7, 8]);
+
+        // the two directions use different keys
+        let (_, mut recv_1_copy) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+        assert!(recv_1_copy.open(&send_1.seal(&[1, 2, 3]).unwrap()).is_err());
+
+        // a different salt gives different keys
+        let (mut send_1_salted, _) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x02; 65]);
+        assert!(recv_2.open(&send_1_salted.seal(&[1, 2, 3]).unwrap()).is_err());
+
+        // can't tamper with frames
+        let (mut send_1, _) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+        let (_, mut recv_2) = FrameCipher::derive_pair(&privkey_2, &pubkey_1, &[0x01; 65]);
+        let mut frame = send_1.seal(&[1, 2, 3, 4, 5]).unwrap();
+        let last = frame.len() - 1;
+        frame[last] ^= 0x01;
+        assert!(recv_2.open(&frame).is_err());
+
+        // can't replay, drop, or reorder frames
+        let (mut send_1, _) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+        let (_, mut recv_2) = FrameCipher::derive_pair(&privkey_2, &pubkey_1, &[0x01; 65]);
+        let frame_a = send_1.seal(&[1]).unwrap();
+        let frame_b = send_1.seal(&[2]).unwrap();
+        let frame_c = send_1.seal(&[3]).unwrap();
+        assert_eq!(recv_2.open(&frame_a).unwrap(), vec![1]);
+        assert!(recv_2.open(&frame_a).is_err());
+        assert!(recv_2.open(&frame_c).is_err());
+        assert_eq!(recv_2.open(&frame_b).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn connection_encrypted_recv() {
+        let privkey_1 = Secp256k1PrivateKey::new();
+        let privkey_2 = Secp256k1PrivateKey::new();
+        let pubkey_1 = Secp256k1PublicKey::from_private(&privkey_1);
+        let pubkey_2 = Secp256k1PublicKey::from_private(&privkey_2);
+
+        let conn_opts = ConnectionOptions::default();
+
+        // peer 1 sends a plaintext message, and then immediately sends encrypted frames
+        let (mut send_1, _) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+        let pings : Vec<StacksMessage> = (0..3).map(|i| make_test_ping(&privkey_1, i)).collect();
+        let mut wire = vec![];
+        pings[0].consensus_serialize(&mut wire).unwrap();
+        for ping in pings[1..].iter() {
+            let mut ping_bytes = vec![];
+            ping.consensus_serialize(&mut ping_bytes).unwrap();
+            wire.append(&mut send_1.seal(&ping_bytes).unwrap());
+        }
+
+        // peer 2 reads all of it at once, but can't decrypt the frames until it has a cipher
+        let mut conn = ConnectionP2P::new(StacksP2P::new(), &conn_opts, Some(pubkey_1.clone()));
+        {
+            let mut fd = NetCursor::new(wire.as_mut_slice());
+            conn.recv_data(&mut fd).unwrap();
+        }
+        assert_eq!(conn.drain_inbox(), vec![pings[0].clone()]);
+        assert!(!conn.is_encrypted());
+
+        let (send_2, recv_2) = FrameCipher::derive_pair(&privkey_2, &pubkey_1, &[0x01; 65]);
+        conn.set_ciphers(send_2, recv_2).unwrap();
+        assert!(conn.is_encrypted());
+        assert_eq!(conn.drain_inbox(), pings[1..].to_vec());
+
+        // no more plaintext
+        let mut plaintext = vec![];
+        make_test_ping(&privkey_1, 3).consensus_serialize(&mut plaintext).unwrap();
+        {
+            let mut fd = NetCursor::new(plaintext.as_mut_slice());
+            assert!(conn.recv_data(&mut fd).is_err());
+        }
+    }
+
+    #[test]
+    fn connection_encrypted_recv_before_cipher_limit() {
+        let privkey_1 = Secp256k1PrivateKey::new();
+        let privkey_2 = Secp256k1PrivateKey::new();
+        let pubkey_2 = Secp256k1PublicKey::from_private(&privkey_2);
+
+        let conn_opts = ConnectionOptions::default();
+        let (mut send_1, _) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+
+        // a frame as big as a handshake is held until there's a cipher...
+        let frame = send_1.seal(&vec![0u8; MAX_HANDSHAKE_MESSAGE_LEN as usize]).unwrap();
+        assert_eq!(frame.len(), MAX_PRE_CIPHER_BUFFER_LEN);
+
+        let mut conn = ConnectionP2P::new(StacksP2P::new(), &conn_opts, None);
+        let mut wire = frame.clone();
+        {
+            let mut fd = NetCursor::new(wire.as_mut_slice());
+            conn.recv_data(&mut fd).unwrap();
+        }
+
+        // ...but not a byte more
+        let mut wire = send_1.seal(&[0u8]).unwrap();
+        {
+            let mut fd = NetCursor::new(wire.as_mut_slice());
+            assert!(conn.recv_data(&mut fd).is_err());
+        }
+    }
+
+    #[test]
+    fn connection_encrypted_send() {
+        let privkey_1 = Secp256k1PrivateKey::new();
+        let privkey_2 = Secp256k1PrivateKey::new();
+        let pubkey_1 = Secp256k1PublicKey::from_private(&privkey_1);
+        let pubkey_2 = Secp256k1PublicKey::from_private(&privkey_2);
+
+        let conn_opts = ConnectionOptions::default();
+        let mut conn = ConnectionP2P::new(StacksP2P::new(), &conn_opts, Some(pubkey_2.clone()));
+
+        // the first message is queued before the channel is encrypted; the second after
+        let ping_plaintext = make_test_ping(&privkey_1, 0);
+        let ping_encrypted = make_test_ping(&privkey_1, 1);
+
+        let mut handle_plaintext = conn.make_relay_handle(0).unwrap();
+        ping_plaintext.consensus_serialize(&mut handle_plaintext).unwrap();
+
+        let (send_1, recv_1) = FrameCipher::derive_pair(&privkey_1, &pubkey_2, &[0x01; 65]);
+        conn.set_ciphers(send_1, recv_1).unwrap();
+
+        let mut handle_encrypted = conn.make_relay_handle(0).unwrap();
+        ping_encrypted.consensus_serialize(&mut handle_encrypted).unwrap();
+
+        let flusher = thread::spawn(move || {
+            handle_plaintext.flush().unwrap();
+            handle_encrypted.flush().unwrap();
+            (handle_plaintext, handle_encrypted)
+        });
+
+        let mut plaintext_bytes = vec![];
+        ping_plaintext.consensus_serialize(&mut plaintext_bytes).unwrap();
+        let mut encrypted_bytes = vec![];
+        ping_encrypted.consensus_serialize(&mut encrypted_bytes).unwrap();
+
+        let len = plaintext_bytes.len() + ENCRYPTED_FRAME_HEADER_LEN + ENCRYPTED_FRAME_NONCE_LEN + encrypted_bytes.len() + ENCRYPTED_FRAME_TAG_LEN;
+        let mut wire = vec![0u8; len];
+        {
+            let mut fd = NetCursor::new(wire.as_mut_slice());
+            let mut nw = 0;
+            while nw < len {
+                nw += conn.send_data(&mut fd).unwrap();
+            }
+        }
+
+        let _handles = flusher.join().unwrap();
+
+        assert_eq!(&wire[0..plaintext_bytes.len()], &plaintext_bytes[..]);
+
+        let (_, mut recv_2) = FrameCipher::derive_pair(&privkey_2, &pubkey_1, &[0x01; 65]);
+        assert_eq!(recv_2.open(&wire[plaintext_bytes.len()..]).unwrap(), encrypted_bytes);
+    }
+
     #[test]
     fn connection_send_recv_timeout() {
         let privkey = Secp256k1PrivateKey::new();
@@ -44,7 +44,6 @@ use chainstate::stacks::StacksPublicKey;
 
 use rand::RngCore;
 use rand::Rng;
-use rand::thread_rng;
 use rand::seq::SliceRandom;
 
 use net::asn::ASEntry4;
@@ -115,10 +114,9 @@ impl fmt::Debug for LocalPeer {
 impl LocalPeer {
     pub fn new(network_id: u32, parent_network_id: u32, addrbytes: PeerAddress, port: u16, privkey: Option<Secp256k1PrivateKey>, key_expire: u64, data_url: UrlString) -> LocalPeer {
         let pkey = privkey.unwrap_or(Secp256k1PrivateKey::new());
-        let mut rng = thread_rng();
         let mut my_nonce = [0u8; 32];
 
-        rng.fill_bytes(&mut my_nonce);
+        util::with_rng(|rng| rng.fill_bytes(&mut my_nonce));
 
         let addr = addrbytes;
         let port = port;
@@ -242,6 +240,100 @@ impl FromRow<Neighbor> for Neighbor {
     }
 }
 
+/// A peer's misbehavior score, as stored in the frontier table.
+/// Higher scores are worse; a peer whose score reaches the ban threshold gets banned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerReputation {
+    pub addr: NeighborKey,
+    pub public_key: Secp256k1PublicKey,
+    pub score: u64,
+    pub score_updated: u64,     // time when the score was last changed
+    pub whitelisted: i64,
+    pub blacklisted: i64
+}
+
+impl FromRow<PeerReputation> for PeerReputation {
+    fn from_row<'a>(row: &'a Row) -> Result<PeerReputation, db_error> {
+        let peer_version : u32 = row.get("peer_version");
+        let network_id : u32 = row.get("network_id");
+        let addrbytes : PeerAddress = PeerAddress::from_column(row, "addrbytes")?;
+        let port : u16 = row.get("port");
+        let public_key : Secp256k1PublicKey = Secp256k1PublicKey::from_column(row, "public_key")?;
+        let score = u64::from_column(row, "reputation")?;
+        let score_updated = u64::from_column(row, "reputation_updated")?;
+        let whitelisted : i64 = row.get("whitelisted");
+        let blacklisted : i64 = row.get("blacklisted");
+
+        Ok(PeerReputation {
+            addr: NeighborKey {
+                peer_version: peer_version,
+                network_id: network_id,
+                addrbytes: addrbytes,
+                port: port,
+            },
+            public_key: public_key,
+            score: score,
+            score_updated: score_updated,
+            whitelisted: whitelisted,
+            blacklisted: blacklisted
+        })
+    }
+}
+
+/// An operator's standing ban or allow-list rule for a peer address or public key.  Rules are
+/// kept apart from the frontier so they can name peers we haven't met yet; they're applied when
+/// such a peer connects or gets added to the frontier.  A None deadline leaves that flag alone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerAdminRule {
+    pub whitelisted: Option<i64>,
+    pub blacklisted: Option<i64>
+}
+
+impl FromRow<PeerAdminRule> for PeerAdminRule {
+    fn from_row<'a>(row: &'a Row) -> Result<PeerAdminRule, db_error> {
+        let whitelisted : Option<i64> = row.get("whitelisted");
+        let blacklisted : Option<i64> = row.get("blacklisted");
+        Ok(PeerAdminRule {
+            whitelisted: whitelisted,
+            blacklisted: blacklisted
+        })
+    }
+}
+
+impl PeerAdminRule {
+    pub fn is_empty(&self) -> bool {
+        self.whitelisted.is_none() && self.blacklisted.is_none()
+    }
+
+    pub fn is_blacklisted(&self) -> bool {
+        match self.blacklisted {
+            Some(deadline) => deadline < 0 || (deadline as u64) > util::get_epoch_time_secs(),
+            None => false
+        }
+    }
+
+    /// Overwrite the neighbor's flags with the ones this rule sets
+    pub fn apply_to(&self, neighbor: &mut Neighbor) -> () {
+        if let Some(whitelisted) = self.whitelisted {
+            neighbor.whitelisted = whitelisted;
+        }
+        if let Some(blacklisted) = self.blacklisted {
+            neighbor.blacklisted = blacklisted;
+        }
+    }
+}
+
+// Rules are keyed by a selector string -- either "addr:{addrbytes}:{port}" or "key:{public key}".
+const PEERDB_ADMIN_RULES_SQL : &'static str = r#"
+    CREATE TABLE IF NOT EXISTS peer_admin_rules(
+        network_id INTEGER NOT NULL,
+        selector TEXT NOT NULL,
+        whitelisted INTEGER,
+        blacklisted INTEGER,
+
+        PRIMARY KEY(network_id, selector)
+    );"#;
+
 // In what is likely an abuse of Sqlite, the peer database is structured such that the `frontier`
 // table stores peers keyed by a deterministically-chosen random "slot," instead of their IP/port.
 // (i.e. the slot is determined by a cryptographic the hash of the IP/port).  The reason for this
@@ -267,6 +359,10 @@ const PEERDB_SETUP : &'static [&'static str]= &[
         in_degree INTEGER NOT NULL,
         out_degree INTEGER NOT NULL,
 
+        -- misbehavior score, and when it was last updated (so it can be decayed)
+        reputation INTEGER NOT NULL DEFAULT 0,
+        reputation_updated INTEGER NOT NULL DEFAULT 0,
+
         -- used to deterministically insert and evict
         slot INTEGER UNIQUE NOT NULL,
 
@@ -296,7 +392,8 @@ const PEERDB_SETUP : &'static [&'static str]= &[
         port INTEGER NOT NULL,
         services INTEGER NOT NULL,
         data_url TEXT NOT NULL
-    );"#
+    );"#,
+    PEERDB_ADMIN_RULES_SQL
 ];
 
 pub struct PeerDB {
@@ -420,11 +517,88 @@ impl PeerDB {
                 }
             }
         } else {
+            if readwrite {
+                db.add_reputation_columns()?;
+                db.add_admin_rules_table()?;
+            }
             db.update_local_peer(network_id, parent_network_id, data_url, p2p_port)?;
         }
         Ok(db)
     }
 
+    /// Open an existing peer database, without touching the local peer record.
+    /// Used by tools that inspect or administer a node's peers.
+    pub fn open(path: &String, readwrite: bool) -> Result<PeerDB, db_error> {
+        if fs::metadata(path).is_err() {
+            return Err(db_error::NoDBError);
+        }
+
+        let open_flags = 
+            if readwrite {
+                OpenFlags::SQLITE_OPEN_READ_WRITE
+            }
+            else {
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+            };
+
+        let conn = Connection::open_with_flags(path, open_flags)
+            .map_err(|e| db_error::SqliteError(e))?;
+
+        let mut db = PeerDB {
+            conn: conn,
+            readwrite: readwrite,
+        };
+
+        if readwrite {
+            db.add_reputation_columns()?;
+            db.add_admin_rules_table()?;
+        }
+        Ok(db)
+    }
+
+    /// Peer databases created before peer reputations were tracked won't have the columns for
+    /// them.  Add them if they're missing.
+    fn add_reputation_columns(&mut self) -> Result<(), db_error> {
+        let column_names = {
+            let mut stmt = self.conn.prepare("PRAGMA table_info(frontier)")
+                .map_err(db_error::SqliteError)?;
+
+            let rows = stmt.query_map(NO_PARAMS, |row| {
+                let name : String = row.get(1);
+                name
+            })
+            .map_err(db_error::SqliteError)?;
+
+            let mut column_names = vec![];
+            for row in rows {
+                column_names.push(row.map_err(db_error::SqliteError)?);
+            }
+            column_names
+        };
+
+        if column_names.contains(&"reputation".to_string()) {
+            return Ok(());
+        }
+
+        debug!("Add peer reputation columns to the frontier table");
+        let tx = self.tx_begin()?;
+        tx.execute("ALTER TABLE frontier ADD COLUMN reputation INTEGER NOT NULL DEFAULT 0", NO_PARAMS)
+            .map_err(db_error::SqliteError)?;
+        tx.execute("ALTER TABLE frontier ADD COLUMN reputation_updated INTEGER NOT NULL DEFAULT 0", NO_PARAMS)
+            .map_err(db_error::SqliteError)?;
+        tx.commit()
+            .map_err(db_error::SqliteError)?;
+        Ok(())
+    }
+
+    /// Peer databases created before operators could ban or allow-list peers we haven't met won't
+    /// have a table for those rules.  Add it if it's missing.
+    fn add_admin_rules_table(&mut self) -> Result<(), db_error> {
+        self.conn.execute(PEERDB_ADMIN_RULES_SQL, NO_PARAMS)
+            .map_err(db_error::SqliteError)?;
+        Ok(())
+    }
+
     /// Open a burn database in memory (used for testing)
     #[cfg(test)]
     pub fn connect_memory(network_id: u32, parent_network_id: u32, key_expires: u64, data_url: UrlString, asn4_entries: &Vec<ASEntry4>, initial_neighbors: &Vec<Neighbor>) -> Result<PeerDB, db_error> {
@@ -579,8 +753,12 @@ impl PeerDB {
         }
     }
 
-    /// Insert or replace a neighbor into a given slot 
+    /// Insert or replace a neighbor into a given slot.  Any operator rules for its address or
+    /// public key override its white- and blacklist flags.
     pub fn insert_or_replace_peer<'a>(tx: &mut Transaction<'a>, neighbor: &Neighbor, slot: u32) -> Result<(), db_error> {
+        let mut neighbor = neighbor.clone();
+        PeerDB::apply_admin_rules(tx, &mut neighbor)?;
+
         let neighbor_args : &[&dyn ToSql] = &[
             &neighbor.addr.peer_version,
             &neighbor.addr.network_id,
@@ -633,6 +811,97 @@ impl PeerDB {
         Ok(())
     }
 
+    fn address_rule_selector(peer_addr: &PeerAddress, peer_port: u16) -> String {
+        format!("addr:{}:{}", peer_addr.to_hex(), peer_port)
+    }
+
+    fn public_key_rule_selector(public_key: &Secp256k1PublicKey) -> String {
+        format!("key:{}", to_hex(&public_key.to_bytes_compressed()))
+    }
+
+    fn get_admin_rule(conn: &DBConn, network_id: u32, selector: &String) -> Result<Option<PeerAdminRule>, db_error> {
+        let qry = "SELECT * FROM peer_admin_rules WHERE network_id = ?1 AND selector = ?2".to_string();
+        let args = [&network_id as &dyn ToSql, selector as &dyn ToSql];
+        let rows = query_rows::<PeerAdminRule, _>(conn, &qry, &args)?;
+        Ok(rows.into_iter().next())
+    }
+
+    fn set_admin_rule<'a>(tx: &mut Transaction<'a>, network_id: u32, selector: &String, rule: &PeerAdminRule) -> Result<(), db_error> {
+        if rule.is_empty() {
+            tx.execute("DELETE FROM peer_admin_rules WHERE network_id = ?1 AND selector = ?2", &[&network_id as &dyn ToSql, selector as &dyn ToSql])
+                .map_err(db_error::SqliteError)?;
+        }
+        else {
+            let args : &[&dyn ToSql] = &[&network_id, selector, &rule.whitelisted, &rule.blacklisted];
+            tx.execute("INSERT OR REPLACE INTO peer_admin_rules (network_id, selector, whitelisted, blacklisted) VALUES (?1, ?2, ?3, ?4)", args)
+                .map_err(db_error::SqliteError)?;
+        }
+        Ok(())
+    }
+
+    /// Get the operator's rule for a peer address, if there is one
+    pub fn get_address_rule(conn: &DBConn, network_id: u32, peer_addr: &PeerAddress, peer_port: u16) -> Result<Option<PeerAdminRule>, db_error> {
+        PeerDB::get_admin_rule(conn, network_id, &PeerDB::address_rule_selector(peer_addr, peer_port))
+    }
+
+    /// Get the operator's rule for a peer public key, if there is one
+    pub fn get_public_key_rule(conn: &DBConn, network_id: u32, public_key: &Secp256k1PublicKey) -> Result<Option<PeerAdminRule>, db_error> {
+        PeerDB::get_admin_rule(conn, network_id, &PeerDB::public_key_rule_selector(public_key))
+    }
+
+    /// Set (or, if it sets nothing, remove) the operator's rule for a peer address
+    pub fn set_address_rule<'a>(tx: &mut Transaction<'a>, network_id: u32, peer_addr: &PeerAddress, peer_port: u16, rule: &PeerAdminRule) -> Result<(), db_error> {
+        PeerDB::set_admin_rule(tx, network_id, &PeerDB::address_rule_selector(peer_addr, peer_port), rule)
+    }
+
+    /// Set (or, if it sets nothing, remove) the operator's rule for a peer public key
+    pub fn set_public_key_rule<'a>(tx: &mut Transaction<'a>, network_id: u32, public_key: &Secp256k1PublicKey, rule: &PeerAdminRule) -> Result<(), db_error> {
+        PeerDB::set_admin_rule(tx, network_id, &PeerDB::public_key_rule_selector(public_key), rule)
+    }
+
+    /// Apply the operator's rules for a neighbor's public key and address to it.  The address
+    /// rule is more specific, so it wins.
+    pub fn apply_admin_rules(conn: &DBConn, neighbor: &mut Neighbor) -> Result<(), db_error> {
+        if let Some(rule) = PeerDB::get_public_key_rule(conn, neighbor.addr.network_id, &neighbor.public_key)? {
+            rule.apply_to(neighbor);
+        }
+        if let Some(rule) = PeerDB::get_address_rule(conn, neighbor.addr.network_id, &neighbor.addr.addrbytes, neighbor.addr.port)? {
+            rule.apply_to(neighbor);
+        }
+        Ok(())
+    }
+
+    /// Look up a peer's reputation.  Returns None if the peer is not in the frontier.
+    pub fn get_peer_reputation(conn: &DBConn, network_id: u32, peer_addr: &PeerAddress, peer_port: u16) -> Result<Option<PeerReputation>, db_error> {
+        let qry = "SELECT * FROM frontier WHERE network_id = ?1 AND addrbytes = ?2 AND port = ?3".to_string();
+        let args = [&network_id as &dyn ToSql, &peer_addr.to_hex() as &dyn ToSql, &peer_port as &dyn ToSql];
+        let rows = query_rows::<PeerReputation, _>(conn, &qry, &args)?;
+        Ok(rows.into_iter().next())
+    }
+
+    /// Set a peer's (undecayed) reputation score, and when it was computed.
+    pub fn set_peer_reputation<'a>(tx: &mut Transaction<'a>, network_id: u32, peer_addr: &PeerAddress, peer_port: u16, score: u64, score_updated: u64) -> Result<(), db_error> {
+        let args : &[&dyn ToSql] = &[&u64_to_sql(score)?, &u64_to_sql(score_updated)?, &network_id, &peer_addr.to_hex(), &peer_port];
+        tx.execute("UPDATE frontier SET reputation = ?1, reputation_updated = ?2 WHERE network_id = ?3 AND addrbytes = ?4 AND port = ?5", args)
+            .map_err(db_error::SqliteError)?;
+
+        Ok(())
+    }
+
+    /// Get the reputations of all peers in a network, worst-behaved first.
+    pub fn get_peer_reputations(conn: &DBConn, network_id: u32) -> Result<Vec<PeerReputation>, db_error> {
+        let qry = "SELECT * FROM frontier WHERE network_id = ?1 ORDER BY reputation DESC, addrbytes ASC, port ASC".to_string();
+        let args = [&network_id as &dyn ToSql];
+        query_rows::<PeerReputation, _>(conn, &qry, &args)
+    }
+
+    /// Find all peers in a network that use the given public key.
+    pub fn find_peers_by_public_key(conn: &DBConn, network_id: u32, public_key: &Secp256k1PublicKey) -> Result<Vec<Neighbor>, db_error> {
+        let qry = "SELECT * FROM frontier WHERE network_id = ?1 AND public_key = ?2".to_string();
+        let args = [&network_id as &dyn ToSql, &to_hex(&public_key.to_bytes_compressed()) as &dyn ToSql];
+        query_rows::<Neighbor, _>(conn, &qry, &args)
+    }
+
     /// Update an existing peer's entries.  Does nothing if the peer is not present.
     pub fn update_peer<'a>(tx: &mut Transaction<'a>, neighbor: &Neighbor) -> Result<(), db_error> {
         let args : &[&dyn ToSql] = &[
@@ -693,14 +962,14 @@ impl PeerDB {
 
         if always_include_whitelisted {
             // always include whitelisted neighbors, freshness be damned
-            let whitelist_qry = "SELECT * FROM frontier WHERE network_id = ?1 AND blacklisted < ?2 AND (whitelisted < 0 OR ?3 < whitelisted)".to_string();
+            let whitelist_qry = "SELECT * FROM frontier WHERE network_id = ?1 AND blacklisted < ?2 AND (whitelisted < 0 OR ?3 < whitelisted) ORDER BY slot".to_string();
             let whitelist_args : &[&dyn ToSql] = &[&network_id, &u64_to_sql(now_secs)?, &u64_to_sql(now_secs)?];
             let mut whitelist_rows = query_rows::<Neighbor, _>(conn, &whitelist_qry, whitelist_args)?;
 
             if whitelist_rows.len() >= (count as usize) {
                 // return a random subset 
                 let whitelist_slice = whitelist_rows.as_mut_slice();
-                whitelist_slice.shuffle(&mut thread_rng());
+                util::with_rng(|rng| whitelist_slice.shuffle(rng));
                 return Ok(whitelist_slice[0..(count as usize)].to_vec());
             }
 
@@ -711,20 +980,39 @@ impl PeerDB {
         let random_peers_qry = 
             if always_include_whitelisted {
                 "SELECT * FROM frontier WHERE network_id = ?1 AND last_contact_time >= 0 AND ?2 < expire_block_height AND blacklisted < ?3 AND \
-                 (whitelisted >= 0 AND whitelisted <= ?4) ORDER BY RANDOM() LIMIT ?5".to_string()
+                 (whitelisted >= 0 AND whitelisted <= ?4)".to_string()
             }
             else {
                 "SELECT * FROM frontier WHERE network_id = ?1 AND last_contact_time >= 0 AND ?2 < expire_block_height AND blacklisted < ?3 AND \
-                 (whitelisted < 0 OR (whitelisted >= 0 AND whitelisted <= ?4)) ORDER BY RANDOM() LIMIT ?5".to_string()
+                 (whitelisted < 0 OR (whitelisted >= 0 AND whitelisted <= ?4))".to_string()
             };
 
-        let random_peers_args : &[&dyn ToSql] = &[&network_id, &u64_to_sql(block_height)?, &u64_to_sql(now_secs)?, &u64_to_sql(now_secs)?, &(count - (ret.len() as u32))];
-        let mut random_peers = query_rows::<Neighbor, _>(conn, &random_peers_qry, random_peers_args)?;
+        let random_peers_args : &[&dyn ToSql] = &[&network_id, &u64_to_sql(block_height)?, &u64_to_sql(now_secs)?, &u64_to_sql(now_secs)?];
+        let mut random_peers = PeerDB::sample_neighbors(conn, &random_peers_qry, random_peers_args, count - (ret.len() as u32))?;
     
         ret.append(&mut random_peers);
         Ok(ret)
     }
 
+    /// Sample up to count rows of a frontier query
+    #[cfg(not(test))]
+    fn sample_neighbors(conn: &DBConn, qry: &str, args: &[&dyn ToSql], count: u32) -> Result<Vec<Neighbor>, db_error> {
+        let sample_qry = format!("{} ORDER BY RANDOM() LIMIT {}", qry, count);
+        query_rows::<Neighbor, _>(conn, &sample_qry, args)
+    }
+
+    /// Sample up to count rows of a frontier query.  In tests, the sample comes from
+    /// util::with_rng() instead of SQLite, so the network simulator can seed it.
+    #[cfg(test)]
+    fn sample_neighbors(conn: &DBConn, qry: &str, args: &[&dyn ToSql], count: u32) -> Result<Vec<Neighbor>, db_error> {
+        let sample_qry = format!("{} ORDER BY slot", qry);
+        let mut rows = query_rows::<Neighbor, _>(conn, &sample_qry, args)?;
+
+        util::with_rng(|rng| rows.as_mut_slice().shuffle(rng));
+        rows.truncate(count as usize);
+        Ok(rows)
+    }
+
     /// Get an randomized initial set of peers.
     /// -- always include all whitelisted neighbors
     /// -- never include blacklisted neighbors
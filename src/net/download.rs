@@ -54,6 +54,7 @@ use net::db::*;
 
 use net::p2p::PeerNetwork;
 
+use util;
 use util::db::Error as db_error;
 use util::db::DBConn;
 use util::secp256k1::Secp256k1PublicKey;
@@ -90,7 +91,6 @@ use util::get_epoch_time_ms;
 use util::hash::to_hex;
 
 use rand::RngCore;
-use rand::thread_rng;
 use rand::seq::SliceRandom;
 
 use core::EMPTY_MICROBLOCK_PARENT_HASH;
@@ -664,6 +664,11 @@ impl BlockDownloader {
     pub fn is_download_idle(&self) -> bool {
         self.empty_block_download_passes > 0 && self.empty_microblock_download_passes > 0
     }
+
+    // how many block and confirmed-microblock requests are outstanding?
+    pub fn num_inflight_requests(&self) -> u64 {
+        (self.getblock_requests.len() + self.getmicroblocks_requests.len()) as u64
+    }
 }
 
 impl PeerNetwork {
@@ -842,7 +847,9 @@ impl PeerNetwork {
             // don't request the same data from the same data url, in case multiple peers report the
             // same data url (e.g. two peers sharing a Gaia hub).
             let block_urls : HashSet<UrlString> = HashSet::new();
-            (&mut neighbors[..]).shuffle(&mut thread_rng());
+            // (sorted first, so the shuffle depends only on the RNG and not on hash map order)
+            neighbors.sort();
+            util::with_rng(|rng| (&mut neighbors[..]).shuffle(rng));
 
             let mut requests = VecDeque::new();
             for nk in neighbors.drain(..) {
@@ -1085,7 +1092,7 @@ impl PeerNetwork {
     }
 
     fn connect_or_send_http_request(&mut self, data_url: UrlString, addr: SocketAddr, request: HttpRequestType, chainstate: &mut StacksChainState) -> Result<usize, net_error> {
-        PeerNetwork::with_network_state(self, |ref mut network, ref mut network_state| {
+        PeerNetwork::with_network_state(self, |ref mut network, network_state| {
             match network.http.connect_http(network_state, data_url.clone(), addr.clone(), Some(request.clone())) {
                 Ok(event_id) => Ok(event_id),
                 Err(net_error::AlreadyConnected(event_id)) => {
@@ -1479,7 +1486,10 @@ pub mod test {
     use net::inv::*;
     use net::test::*;
     use net::relay::*;
+    use net::dns::DNSResolver;
+    use net::sim::*;
     use chainstate::stacks::*;
+    use rand::rngs::StdRng;
     use std::collections::HashMap;
 
     fn get_peer_availability(peer: &mut TestPeer, start_height: u64, end_height: u64) -> Vec<(BurnchainHeaderHash, Option<BlockHeaderHash>, Vec<NeighborKey>)> {
@@ -1640,7 +1650,21 @@ pub mod test {
         inv
     }
     
-    pub fn run_get_blocks_and_microblocks<T, F, P, C, D>(test_name: &str, port_base: u16, num_peers: usize, make_topology: T, block_generator: F, mut peer_func: P, mut check_breakage: C, mut done_func: D) -> Vec<TestPeer>
+    pub fn run_get_blocks_and_microblocks<T, F, P, C, D>(test_name: &str, port_base: u16, num_peers: usize, make_topology: T, block_generator: F, peer_func: P, check_breakage: C, done_func: D) -> Vec<TestPeer>
+    where 
+        T: FnOnce(&mut Vec<TestPeerConfig>) -> (),
+        F: FnOnce(usize, &mut Vec<TestPeer>) -> Vec<(BurnchainHeaderHash, Option<StacksBlock>, Option<Vec<StacksMicroblock>>)>,
+        P: FnMut(&mut Vec<TestPeer>) -> (),
+        C: FnMut(&mut TestPeer) -> bool,
+        D: FnMut(&mut Vec<TestPeer>) -> bool
+    {
+        run_get_blocks_and_microblocks_ex(test_name, port_base, num_peers, None, make_topology, block_generator, peer_func, check_breakage, done_func)
+    }
+
+    /// Same as run_get_blocks_and_microblocks, but optionally over a simulated network.
+    /// If sim_opt is given, each round advances the simulated clock by one tick, and each peer
+    /// draws its randomness from an RNG seeded by the simulated network.
+    pub fn run_get_blocks_and_microblocks_ex<T, F, P, C, D>(test_name: &str, port_base: u16, num_peers: usize, sim_opt: Option<&SimNetwork>, make_topology: T, block_generator: F, mut peer_func: P, mut check_breakage: C, mut done_func: D) -> Vec<TestPeer>
     where 
         T: FnOnce(&mut Vec<TestPeerConfig>) -> (),
         F: FnOnce(usize, &mut Vec<TestPeer>) -> Vec<(BurnchainHeaderHash, Option<StacksBlock>, Option<Vec<StacksMicroblock>>)>,
@@ -1662,8 +1686,22 @@ pub mod test {
         make_topology(&mut peer_configs);
 
         let mut peers = vec![];
-        for conf in peer_configs.drain(..) {
-            let peer = TestPeer::new(conf);
+        let mut rngs : Vec<Option<StdRng>> = vec![];
+        for mut conf in peer_configs.drain(..) {
+            let peer = match sim_opt {
+                Some(sim) => {
+                    SimHarness::configure_peer(&mut conf, num_peers);
+                    let transport = sim.transport(conf.connection_opts.max_sockets);
+                    let mut rng = Some(sim.make_rng());
+                    let peer = with_seeded_rng(&mut rng, || TestPeer::new_with_transport(conf, Some(Box::new(transport))));
+                    rngs.push(rng);
+                    peer
+                },
+                None => {
+                    rngs.push(None);
+                    TestPeer::new(conf)
+                }
+            };
             peers.push(peer);
         }
 
@@ -1682,11 +1720,20 @@ pub mod test {
 
         let mut dns_clients = vec![];
         let mut dns_threads = vec![];
+        let mut dns_resolvers = vec![];
 
         for _ in 0..peers.len() {
-            let (dns_client, dns_thread_handle) = dns_thread_start(100);
-            dns_clients.push(dns_client);
-            dns_threads.push(dns_thread_handle);
+            if sim_opt.is_some() {
+                // simulated peers' data URLs are IP addresses, so nothing gets resolved
+                let (dns_resolver, dns_client) = DNSResolver::new(100);
+                dns_clients.push(dns_client);
+                dns_resolvers.push(dns_resolver);
+            }
+            else {
+                let (dns_client, dns_thread_handle) = dns_thread_start(100);
+                dns_clients.push(dns_client);
+                dns_threads.push(dns_thread_handle);
+            }
         }
         
         let mut round = 0;
@@ -1695,17 +1742,24 @@ pub mod test {
         let mut done = false;
 
         loop {
+            if let Some(sim) = sim_opt {
+                sim.advance(SIM_DEFAULT_TICK_MS);
+            }
+
             peer_func(&mut peers);
 
             for i in 0..peers.len() {
                 let peer = &mut peers[i];
                 
                 test_debug!("======= peer {} step begin =========", i);
-                let mut result = peer.step_dns(&mut dns_clients[i]).unwrap();
+                let dns_client = &mut dns_clients[i];
+                let mut result = with_seeded_rng(&mut rngs[i], || peer.step_dns(dns_client)).unwrap();
 
                 let lp = peer.network.local_peer.clone();
-                peer.with_db_state(|burndb, chainstate, relayer, mempool| {
-                    relayer.process_network_result(&lp, &mut result, burndb, chainstate, mempool)
+                with_seeded_rng(&mut rngs[i], || {
+                    peer.with_db_state(|burndb, chainstate, relayer, mempool| {
+                        relayer.process_network_result(&lp, &mut result, burndb, chainstate, mempool)
+                    })
                 }).unwrap();
 
                 test_debug!("Peer {} processes {} blocks and {} microblock streams", i, result.blocks.len(), result.confirmed_microblocks.len());
@@ -1776,6 +1830,7 @@ pub mod test {
         }
 
         drop(dns_clients);
+        drop(dns_resolvers);
         for handle in dns_threads.drain(..) {
             handle.join().unwrap();
         }
@@ -1951,6 +2006,125 @@ pub mod test {
                                        },
                                        |_| true);
     }
+
+    #[test]
+    pub fn test_get_blocks_and_microblocks_3_peers_line_sim() {
+        // a line of 3 peers over a simulated network, with peers[0] holding all the blocks.
+        // There are no neighbor walks, so peers[2] can only learn about them by inventory sync
+        // with peers[1].
+        let sim = SimNetwork::new(3);
+
+        let peers = run_get_blocks_and_microblocks_ex("test_get_blocks_and_microblocks_3_peers_line_sim", 3250, 3, Some(&sim),
+                                       |ref mut peer_configs| {
+                                           assert_eq!(peer_configs.len(), 3);
+                                           let mut neighbors = vec![];
+                                           
+                                           for p in peer_configs.iter_mut() {
+                                               p.connection_opts.disable_block_advertisement = true;
+                                               p.connection_opts.disable_neighbor_walk = true;
+                                           }
+
+                                           for i in 0..peer_configs.len() {
+                                               neighbors.push(peer_configs[i].to_neighbor());
+                                           }
+
+                                           for i in 0..peer_configs.len()-1 {
+                                               peer_configs[i].add_neighbor(&neighbors[i+1]);
+                                               peer_configs[i+1].add_neighbor(&neighbors[i]);
+                                           }
+                                       },
+                                       |num_blocks, ref mut peers| {
+                                           let mut block_data = vec![];
+                                           for _ in 0..num_blocks {
+                                               let (burn_ops, stacks_block, microblocks) = peers[0].make_default_tenure();
+                                               peers[0].next_burnchain_block(burn_ops.clone());
+                                               peers[0].process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+                                               for i in 1..peers.len() {
+                                                   peers[i].next_burnchain_block(burn_ops.clone());
+                                               }
+
+                                               let sn = BurnDB::get_canonical_burn_chain_tip(&peers[0].burndb.as_ref().unwrap().conn()).unwrap();
+                                               block_data.push((sn.burn_header_hash.clone(), Some(stacks_block), Some(microblocks)));
+                                           }
+                                           block_data
+                                       },
+                                       |_| {},
+                                       |peer| {
+                                           // nothing should break
+                                           match peer.network.block_downloader {
+                                               Some(ref dl) => {
+                                                   assert_eq!(dl.broken_peers.len(), 0);
+                                                   assert_eq!(dl.dead_peers.len(), 0);
+                                               },
+                                               None => {}
+                                           }
+                                           true
+                                       },
+                                       |_| true);
+
+        // everyone ends up on peers[0]'s Stacks tip
+        let tip_0 = BurnDB::get_canonical_burn_chain_tip(&peers[0].burndb.as_ref().unwrap().conn()).unwrap();
+        for i in 1..peers.len() {
+            let tip = BurnDB::get_canonical_burn_chain_tip(&peers[i].burndb.as_ref().unwrap().conn()).unwrap();
+            assert_eq!(tip.canonical_stacks_tip_hash, tip_0.canonical_stacks_tip_hash);
+            assert_eq!(tip.canonical_stacks_tip_height, tip_0.canonical_stacks_tip_height);
+        }
+
+        // peers[2] got the last block by way of peers[1]'s inventory, and never heard of peers[0]
+        let inv_state = peers[2].network.inv_state.as_ref().unwrap();
+        let nk_0 = peers[0].config.to_neighbor().addr;
+        let nk_1 = peers[1].config.to_neighbor().addr;
+        let stats = inv_state.get_stats(&nk_1).expect("peers[2] has no inventory from peers[1]");
+        assert!(stats.inv.has_ith_block(tip_0.block_height));
+        assert!(inv_state.get_stats(&nk_0).is_none());
+    }
+
+    #[test]
+    pub fn test_get_blocks_and_microblocks_5_peers_line_sim_lossy() {
+        // same as the 5-peer line, but over a slow, lossy simulated network
+        let sim = SimNetwork::new(5);
+        sim.set_latency(50, 400);
+        sim.set_loss_rate(0.05);
+
+        run_get_blocks_and_microblocks_ex("test_get_blocks_and_microblocks_5_peers_line_sim_lossy", 3240, 5, Some(&sim),
+                                       |ref mut peer_configs| {
+                                           assert_eq!(peer_configs.len(), 5);
+                                           let mut neighbors = vec![];
+                                           
+                                           for p in peer_configs.iter_mut() {
+                                               p.connection_opts.disable_block_advertisement = true;
+                                           }
+
+                                           for i in 0..peer_configs.len() {
+                                               neighbors.push(peer_configs[i].to_neighbor());
+                                           }
+
+                                           for i in 0..peer_configs.len()-1 {
+                                               peer_configs[i].add_neighbor(&neighbors[i+1]);
+                                               peer_configs[i+1].add_neighbor(&neighbors[i]);
+                                           }
+                                       },
+                                       |num_blocks, ref mut peers| {
+                                           let mut block_data = vec![];
+                                           for _ in 0..num_blocks {
+                                               let (burn_ops, stacks_block, microblocks) = peers[0].make_default_tenure();
+                                               peers[0].next_burnchain_block(burn_ops.clone());
+                                               peers[0].process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+                                               for i in 1..peers.len() {
+                                                   peers[i].next_burnchain_block(burn_ops.clone());
+                                               }
+
+                                               let sn = BurnDB::get_canonical_burn_chain_tip(&peers[0].burndb.as_ref().unwrap().conn()).unwrap();
+                                               block_data.push((sn.burn_header_hash.clone(), Some(stacks_block), Some(microblocks)));
+                                           }
+                                           block_data
+                                       },
+                                       |_| {},
+                                       |_| true,
+                                       |_| true);
+    }
     
     #[test]
     #[ignore]
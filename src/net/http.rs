@@ -39,6 +39,7 @@ use net::HttpRequestPreamble;
 use net::HttpResponsePreamble;
 use net::HttpContentType;
 use net::HttpRequestType;
+use net::TipRequest;
 use net::HttpResponseType;
 use net::HttpVersion;
 use net::StacksHttpPreamble;
@@ -53,7 +54,20 @@ use net::HTTP_PREAMBLE_MAX_ENCODED_SIZE;
 use net::HTTP_PREAMBLE_MAX_NUM_HEADERS;
 use net::MAX_MESSAGE_LEN;
 use net::MAX_MICROBLOCKS_UNCONFIRMED;
+use net::MAX_HEADERS_PER_REQUEST;
+use net::MAX_MEMPOOL_TXS_PER_REQUEST;
+use net::MAX_MAP_ENTRIES_PER_REQUEST;
+use net::MAX_BATCH_PROOF_ITEMS;
+use net::RPCStacksHeaderInfo;
+use net::RPCMempoolTxsData;
+use net::RPCMempoolAccountData;
+use net::{ RPCPeerReputation, RPCPeerAdminRequest };
+use net::reputation::{ PeerAdminAction, PeerSelector };
+use net::{ FungibleTokenBalanceResponse, NonFungibleTokenOwnerResponse, DataVarResponse };
+use net::MapEntriesResponse;
+use net::{ BatchProofRequestBody, BatchProofMapEntryRequest, BatchProofResponse };
 use net::HTTP_REQUEST_ID_RESERVED;
+use net::websocket::is_valid_websocket_key;
 
 use chainstate::burn::BlockHeaderHash;
 use burnchains::{ Txid, Address };
@@ -63,6 +77,7 @@ use chainstate::stacks::{
 
 use util::log;
 use util::hash::hex_bytes;
+use util::hash::to_hex;
 use util::retry::RetryReader;
 use util::retry::BoundReader;
 
@@ -71,6 +86,7 @@ use vm::{
         STANDARD_PRINCIPAL_REGEX, PRINCIPAL_DATA_REGEX, CLARITY_NAME_REGEX, CONTRACT_NAME_REGEX
     },
     types::{ PrincipalData, BOUND_VALUE_SERIALIZATION_HEX },
+    database::ClaritySerializable,
     ClarityName, ContractName, Value
 };
 
@@ -90,16 +106,36 @@ use std::time::SystemTime;
 lazy_static! {
     static ref PATH_GETINFO : Regex = Regex::new(r#"^/v2/info$"#).unwrap();
     static ref PATH_GETNEIGHBORS : Regex = Regex::new(r#"^/v2/neighbors$"#).unwrap();
+    static ref PATH_GET_PEER_REPUTATIONS : Regex = Regex::new(r#"^/v2/neighbors/reputation$"#).unwrap();
+    static ref PATH_POST_PEER_ADMIN : Regex = Regex::new(r#"^/v2/neighbors/(ban|unban|allow|disallow)$"#).unwrap();
     static ref PATH_GETBLOCK : Regex = Regex::new(r#"^/v2/blocks/([0-9a-f]{64})$"#).unwrap();
     static ref PATH_GETMICROBLOCKS_INDEXED : Regex = Regex::new(r#"^/v2/microblocks/([0-9a-f]{64})$"#).unwrap();
     static ref PATH_GETMICROBLOCKS_CONFIRMED : Regex = Regex::new(r#"^/v2/microblocks/confirmed/([0-9a-f]{64})$"#).unwrap();
     static ref PATH_GETMICROBLOCKS_UNCONFIRMED : Regex = Regex::new(r#"^/v2/microblocks/unconfirmed/([0-9a-f]{64})/([0-9]{1,5})$"#).unwrap();
+    static ref PATH_GETHEADERS : Regex = Regex::new(r#"^/v2/headers$"#).unwrap();
+    static ref PATH_GETBLOCK_BY_HEIGHT : Regex = Regex::new(r#"^/v2/blocks/height/([0-9]{1,20})$"#).unwrap();
     static ref PATH_POSTTRANSACTION : Regex = Regex::new(r#"^/v2/transactions$"#).unwrap();
+    static ref PATH_GET_MEMPOOL_TXS : Regex = Regex::new(r#"^/v2/mempool/transactions$"#).unwrap();
+    static ref PATH_GET_MEMPOOL_ACCOUNT: Regex = Regex::new(&format!(
+        "^/v2/mempool/accounts/(?P<address>{})$", *STANDARD_PRINCIPAL_REGEX)).unwrap();
     static ref PATH_GET_ACCOUNT: Regex = Regex::new(&format!(
         "^/v2/accounts/(?P<principal>{})$", *PRINCIPAL_DATA_REGEX)).unwrap();
     static ref PATH_GET_MAP_ENTRY: Regex = Regex::new(&format!(
         "^/v2/map_entry/(?P<address>{})/(?P<contract>{})/(?P<map>{})$",
         *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX, *CLARITY_NAME_REGEX)).unwrap();
+    static ref PATH_GET_MAP_ENTRIES: Regex = Regex::new(&format!(
+        "^/v2/map_entries/(?P<address>{})/(?P<contract>{})/(?P<map>{})$",
+        *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX, *CLARITY_NAME_REGEX)).unwrap();
+    static ref PATH_POST_BATCH_PROOF : Regex = Regex::new(r#"^/v2/batch_proof$"#).unwrap();
+    static ref PATH_GET_FT_BALANCE: Regex = Regex::new(&format!(
+        "^/v2/ft_balance/(?P<address>{})/(?P<contract>{})/(?P<token>{})/(?P<principal>{})$",
+        *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX, *CLARITY_NAME_REGEX, *PRINCIPAL_DATA_REGEX)).unwrap();
+    static ref PATH_GET_NFT_OWNER: Regex = Regex::new(&format!(
+        "^/v2/nft_owner/(?P<address>{})/(?P<contract>{})/(?P<token>{})$",
+        *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX, *CLARITY_NAME_REGEX)).unwrap();
+    static ref PATH_GET_DATA_VAR: Regex = Regex::new(&format!(
+        "^/v2/data_var/(?P<address>{})/(?P<contract>{})/(?P<var>{})$",
+        *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX, *CLARITY_NAME_REGEX)).unwrap();
     static ref PATH_POST_CALL_READ_ONLY: Regex = Regex::new(&format!(
         "^/v2/contracts/call-read/(?P<address>{})/(?P<contract>{})/(?P<function>{})$",
         *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX, *CLARITY_NAME_REGEX)).unwrap();
@@ -110,6 +146,7 @@ lazy_static! {
         "^/v2/contracts/interface/(?P<address>{})/(?P<contract>{})$",
         *STANDARD_PRINCIPAL_REGEX, *CONTRACT_NAME_REGEX)).unwrap();
     static ref PATH_GET_TRANSFER_COST: Regex = Regex::new("^/v2/fees/transfer$").unwrap();
+    static ref PATH_SUBSCRIBE: Regex = Regex::new("^/v2/subscribe$").unwrap();
     static ref PATH_OPTIONS_WILDCARD: Regex = Regex::new("^/v2/.{0,4096}$").unwrap();
 }
 
@@ -728,7 +765,9 @@ impl HttpRequestPreamble {
     }
 }
 
-fn empty_headers<W: Write>(_fd: &mut W) -> Result<(), net_error> {
+fn websocket_request_headers<W: Write>(fd: &mut W, key: &str) -> Result<(), net_error> {
+    fd.write_all("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n".as_bytes()).map_err(net_error::WriteError)?;
+    fd.write_all(format!("Sec-WebSocket-Key: {}\r\n", key).as_bytes()).map_err(net_error::WriteError)?;
     Ok(())
 }
 
@@ -753,6 +792,19 @@ fn keep_alive_headers<W: Write>(fd: &mut W, md: &HttpResponseMetadata) -> Result
     Ok(())
 }
 
+fn retry_after_headers<W: Write>(fd: &mut W, md: &HttpResponseMetadata, retry_after: u64) -> Result<(), net_error> {
+    keep_alive_headers(fd, md)?;
+    fd.write_all(format!("Retry-After: {}\r\n", retry_after).as_bytes()).map_err(net_error::WriteError)?;
+    Ok(())
+}
+
+fn api_key_headers<W: Write>(fd: &mut W, md: &HttpRequestMetadata) -> Result<(), net_error> {
+    if let Some(ref api_key) = md.api_key {
+        fd.write_all(format!("X-Api-Key: {}\r\n", api_key).as_bytes()).map_err(net_error::WriteError)?;
+    }
+    Ok(())
+}
+
 fn write_headers<W: Write>(fd: &mut W, headers: &HashMap<String, String>) -> Result<(), net_error> {
     for (ref key, ref value) in headers.iter() {
         fd.write_all(key.as_str().as_bytes()).map_err(net_error::WriteError)?;
@@ -870,7 +922,13 @@ impl StacksMessageCodec for HttpRequestPreamble {
                     }
                     else if key == "connection" {
                         // parse
-                        if value.to_lowercase() == "close" {
+                        if value.split(',').any(|token| token.trim().to_lowercase() == "upgrade") {
+                            // protocol upgrade (i.e. to a websocket).  Keep the header so the
+                            // request parser can check it.
+                            keep_alive = true;
+                            headers.insert(key, value);
+                        }
+                        else if value.to_lowercase() == "close" {
                             keep_alive = false;
                         }
                         else if value.to_lowercase() == "keep-alive" {
@@ -931,7 +989,7 @@ impl HttpResponsePreamble {
         fd.write_all("Server: stacks/2.0\r\nDate: ".as_bytes()).map_err(net_error::WriteError)?;
         fd.write_all(rfc7231_now().as_bytes()).map_err(net_error::WriteError)?;
         fd.write_all("\r\nAccess-Control-Allow-Origin: *".as_bytes()).map_err(net_error::WriteError)?;
-        fd.write_all("\r\nAccess-Control-Allow-Headers: origin, content-type".as_bytes()).map_err(net_error::WriteError)?;
+        fd.write_all("\r\nAccess-Control-Allow-Headers: origin, content-type, x-api-key".as_bytes()).map_err(net_error::WriteError)?;
         fd.write_all("\r\nAccess-Control-Allow-Methods: POST, GET, OPTIONS".as_bytes()).map_err(net_error::WriteError)?;
         fd.write_all("\r\nContent-Type: ".as_bytes()).map_err(net_error::WriteError)?;
         fd.write_all(content_type.as_str().as_bytes()).map_err(net_error::WriteError)?;
@@ -1160,17 +1218,29 @@ impl HttpRequestType {
         let REQUEST_METHODS: &[(&str, &Regex, &dyn Fn(&mut StacksHttp, &HttpRequestPreamble, &Captures, Option<&str>, &mut R) -> Result<HttpRequestType, net_error>)] = &[
             ("GET", &PATH_GETINFO, &HttpRequestType::parse_getinfo),
             ("GET", &PATH_GETNEIGHBORS, &HttpRequestType::parse_getneighbors),
+            ("GET", &PATH_GET_PEER_REPUTATIONS, &HttpRequestType::parse_get_peer_reputations),
+            ("POST", &PATH_POST_PEER_ADMIN, &HttpRequestType::parse_post_peer_admin),
             ("GET", &PATH_GETBLOCK, &HttpRequestType::parse_getblock),
             ("GET", &PATH_GETMICROBLOCKS_INDEXED, &HttpRequestType::parse_getmicroblocks_indexed),
             ("GET", &PATH_GETMICROBLOCKS_CONFIRMED, &HttpRequestType::parse_getmicroblocks_confirmed),
             ("GET", &PATH_GETMICROBLOCKS_UNCONFIRMED, &HttpRequestType::parse_getmicroblocks_unconfirmed),
+            ("GET", &PATH_GETHEADERS, &HttpRequestType::parse_getheaders),
+            ("GET", &PATH_GETBLOCK_BY_HEIGHT, &HttpRequestType::parse_getblock_by_height),
             ("POST", &PATH_POSTTRANSACTION, &HttpRequestType::parse_posttransaction),
+            ("GET", &PATH_GET_MEMPOOL_TXS, &HttpRequestType::parse_get_mempool_transactions),
+            ("GET", &PATH_GET_MEMPOOL_ACCOUNT, &HttpRequestType::parse_get_mempool_account),
             ("GET", &PATH_GET_ACCOUNT, &HttpRequestType::parse_get_account),
             ("POST", &PATH_GET_MAP_ENTRY, &HttpRequestType::parse_get_map_entry),
+            ("GET", &PATH_GET_MAP_ENTRIES, &HttpRequestType::parse_get_map_entries),
+            ("POST", &PATH_POST_BATCH_PROOF, &HttpRequestType::parse_batch_proof),
+            ("GET", &PATH_GET_FT_BALANCE, &HttpRequestType::parse_get_ft_balance),
+            ("POST", &PATH_GET_NFT_OWNER, &HttpRequestType::parse_get_nft_owner),
+            ("GET", &PATH_GET_DATA_VAR, &HttpRequestType::parse_get_data_var),
             ("GET", &PATH_GET_TRANSFER_COST, &HttpRequestType::parse_get_transfer_cost),
             ("GET", &PATH_GET_CONTRACT_SRC, &HttpRequestType::parse_get_contract_source),
             ("GET", &PATH_GET_CONTRACT_ABI, &HttpRequestType::parse_get_contract_abi),
             ("POST", &PATH_POST_CALL_READ_ONLY, &HttpRequestType::parse_call_read_only),
+            ("GET", &PATH_SUBSCRIBE, &HttpRequestType::parse_subscribe),
             ("OPTIONS", &PATH_OPTIONS_WILDCARD, &HttpRequestType::parse_options_preflight),
         ];
 
@@ -1235,6 +1305,222 @@ impl HttpRequestType {
         !no_proof
     }
 
+    /// get the optional `tip=` query argument, which names the index block hash of the
+    /// Stacks chain tip to read from.  Returns None if not given.
+    fn get_tip_query(query: Option<&str>) -> Result<Option<BlockHeaderHash>, net_error> {
+        let tip_str = match query {
+            Some(query_string) => form_urlencoded::parse(query_string.as_bytes())
+                .find(|(key, _v)| key == "tip")
+                .map(|(_k, value)| value.to_string()),
+            None => None
+        };
+
+        match tip_str {
+            Some(tip_str) => {
+                let tip = BlockHeaderHash::from_hex(&tip_str)
+                    .map_err(|_e| net_error::DeserializeError("Failed to parse tip index block hash".to_string()))?;
+                Ok(Some(tip))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// get the optional `tip=` or `height=` query argument, which names the block a state-reading
+    /// request reads from -- by index block hash, or by height on the canonical fork.  At most one
+    /// may be given.  Defaults to the canonical Stacks chain tip.
+    fn get_tip_request_query(query: Option<&str>) -> Result<TipRequest, net_error> {
+        let tip_opt = HttpRequestType::get_tip_query(query)?;
+        let height_opt = HttpRequestType::get_u64_query(query, "height")?;
+        match (tip_opt, height_opt) {
+            (Some(_), Some(_)) => Err(net_error::DeserializeError("Cannot give both tip and height".to_string())),
+            (Some(tip), None) => Ok(TipRequest::SpecificTip(tip)),
+            (None, Some(height)) => Ok(TipRequest::AtHeight(height)),
+            (None, None) => Ok(TipRequest::UseLatestAnchoredTip)
+        }
+    }
+
+    /// get the `count=` query argument for /v2/headers.  Defaults to 1.
+    fn get_count_query(query: Option<&str>) -> Result<u64, net_error> {
+        let count_str = match query {
+            Some(query_string) => form_urlencoded::parse(query_string.as_bytes())
+                .find(|(key, _v)| key == "count")
+                .map(|(_k, value)| value.to_string()),
+            None => None
+        };
+
+        match count_str {
+            Some(count_str) => {
+                let count = count_str.parse::<u64>()
+                    .map_err(|_e| net_error::DeserializeError("Failed to parse header count".to_string()))?;
+                if count == 0 || count > MAX_HEADERS_PER_REQUEST {
+                    return Err(net_error::DeserializeError(format!("Invalid header count: must be between 1 and {}", MAX_HEADERS_PER_REQUEST)));
+                }
+                Ok(count)
+            },
+            None => Ok(1)
+        }
+    }
+
+    fn parse_getheaders<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, _captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        if preamble.get_content_length() != 0 {
+            return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for GetHeaders".to_string()));
+        }
+
+        let count = HttpRequestType::get_count_query(query)?;
+        let tip = HttpRequestType::get_tip_query(query)?;
+        Ok(HttpRequestType::GetHeaders(HttpRequestMetadata::from_preamble(preamble), count, tip))
+    }
+
+    fn parse_getblock_by_height<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        if preamble.get_content_length() != 0 {
+            return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for GetBlockByHeight".to_string()));
+        }
+
+        let height = captures
+            .get(1)
+            .ok_or(net_error::DeserializeError("Failed to match path to block height group".to_string()))?
+            .as_str()
+            .parse::<u64>()
+            .map_err(|_e| net_error::DeserializeError("Failed to parse block height".to_string()))?;
+
+        let tip = HttpRequestType::get_tip_query(query)?;
+        Ok(HttpRequestType::GetBlockByHeight(HttpRequestMetadata::from_preamble(preamble), height, tip))
+    }
+
+    /// get an optional unsigned integer query argument with the given key.  Returns None if not
+    /// given.
+    fn get_u64_query(query: Option<&str>, key: &str) -> Result<Option<u64>, net_error> {
+        let value_str = match query {
+            Some(query_string) => form_urlencoded::parse(query_string.as_bytes())
+                .find(|(k, _v)| k == key)
+                .map(|(_k, value)| value.to_string()),
+            None => None
+        };
+
+        match value_str {
+            Some(value_str) => {
+                let value = value_str.parse::<u64>()
+                    .map_err(|_e| net_error::DeserializeError(format!("Failed to parse query argument '{}'", key)))?;
+                Ok(Some(value))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// get the optional `after=` query argument for /v2/mempool/transactions.  This is either a
+    /// timestamp, or a `timestamp:txid` cursor as given back in a previous page's `next_cursor`.
+    fn get_mempool_cursor_query(query: Option<&str>) -> Result<Option<(u64, Option<Txid>)>, net_error> {
+        let cursor_str = match query {
+            Some(query_string) => form_urlencoded::parse(query_string.as_bytes())
+                .find(|(key, _v)| key == "after")
+                .map(|(_k, value)| value.to_string()),
+            None => None
+        };
+
+        let cursor_str = match cursor_str {
+            Some(cursor_str) => cursor_str,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        let parse_error = || net_error::DeserializeError("Failed to parse query argument 'after'".to_string());
+        let mut parts = cursor_str.splitn(2, ':');
+        let accept_time = parts.next().unwrap_or("").parse::<u64>()
+            .map_err(|_e| parse_error())?;
+        let txid = match parts.next() {
+            Some(txid_str) => Some(Txid::from_hex(txid_str).map_err(|_e| parse_error())?),
+            None => None
+        };
+        Ok(Some((accept_time, txid)))
+    }
+
+    fn parse_get_mempool_transactions<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, _captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        if preamble.get_content_length() != 0 {
+            return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for GetMempoolTransactions".to_string()));
+        }
+
+        let count = HttpRequestType::get_u64_query(query, "count")?.unwrap_or(MAX_MEMPOOL_TXS_PER_REQUEST);
+        if count == 0 || count > MAX_MEMPOOL_TXS_PER_REQUEST {
+            return Err(net_error::DeserializeError(format!("Invalid mempool transaction count: must be between 1 and {}", MAX_MEMPOOL_TXS_PER_REQUEST)));
+        }
+
+        let after = HttpRequestType::get_mempool_cursor_query(query)?;
+
+        let address_str = match query {
+            Some(query_string) => form_urlencoded::parse(query_string.as_bytes())
+                .find(|(key, _v)| key == "principal")
+                .map(|(_k, value)| value.to_string()),
+            None => None
+        };
+        let address = match address_str {
+            Some(address_str) => {
+                let address = StacksAddress::from_string(&address_str)
+                    .ok_or_else(|| net_error::DeserializeError("Failed to parse principal address".to_string()))?;
+                Some(address)
+            },
+            None => None
+        };
+
+        Ok(HttpRequestType::GetMempoolTransactions(HttpRequestMetadata::from_preamble(preamble), address, after, count))
+    }
+
+    fn parse_get_mempool_account<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, _query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        if preamble.get_content_length() != 0 {
+            return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for GetMempoolAccount".to_string()));
+        }
+
+        let address = StacksAddress::from_string(&captures["address"])
+            .ok_or_else(|| net_error::DeserializeError("Failed to parse account address".to_string()))?;
+
+        Ok(HttpRequestType::GetMempoolAccount(HttpRequestMetadata::from_preamble(preamble), address))
+    }
+
+    fn parse_get_peer_reputations<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, _captures: &Captures, _query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        if preamble.get_content_length() != 0 {
+            return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for GetPeerReputations".to_string()));
+        }
+
+        Ok(HttpRequestType::GetPeerReputations(HttpRequestMetadata::from_preamble(preamble)))
+    }
+
+    fn parse_post_peer_admin<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, _query: Option<&str>, fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let content_len = preamble.get_content_length();
+        if !(content_len > 0 && content_len < 4096) {
+            return Err(net_error::DeserializeError("Invalid Http request: invalid body length for PostPeerAdmin".to_string()));
+        }
+
+        if preamble.content_type != Some(HttpContentType::JSON) {
+            return Err(net_error::DeserializeError("Invalid content-type: expected application/json".to_string()));
+        }
+
+        let body : RPCPeerAdminRequest = serde_json::from_reader(fd)
+            .map_err(|_e| net_error::DeserializeError("Failed to parse JSON body".into()))?;
+
+        let selector = match (body.addr, body.public_key) {
+            (Some(addr), None) => match PeerSelector::parse(&addr)? {
+                PeerSelector::Address(addrbytes, port) => PeerSelector::Address(addrbytes, port),
+                _ => {
+                    return Err(net_error::DeserializeError("Failed to parse peer address".into()));
+                }
+            },
+            (None, Some(public_key)) => match PeerSelector::parse(&public_key)? {
+                PeerSelector::PublicKey(public_key) => PeerSelector::PublicKey(public_key),
+                _ => {
+                    return Err(net_error::DeserializeError("Failed to parse peer public key".into()));
+                }
+            },
+            (_, _) => {
+                return Err(net_error::DeserializeError("Expected exactly one of addr or public_key".into()));
+            }
+        };
+
+        let action = PeerAdminAction::from_name(&captures[1], body.duration)
+            .ok_or_else(|| net_error::DeserializeError("Unrecognized peer admin action".into()))?;
+
+        Ok(HttpRequestType::PostPeerAdmin(HttpRequestMetadata::from_preamble(preamble), action, selector))
+    }
+
     fn parse_get_account<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
         if preamble.get_content_length() != 0 {
             return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for GetAccount".to_string()));
@@ -1244,8 +1530,9 @@ impl HttpRequestType {
             .map_err(|_e| net_error::DeserializeError("Failed to parse account principal".into()))?;
 
         let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
 
-        Ok(HttpRequestType::GetAccount(HttpRequestMetadata::from_preamble(preamble), principal, with_proof))
+        Ok(HttpRequestType::GetAccount(HttpRequestMetadata::from_preamble(preamble), principal, with_proof, tip_req))
     }
 
     fn parse_get_map_entry<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, fd: &mut R) -> Result<HttpRequestType, net_error> {
@@ -1273,11 +1560,137 @@ impl HttpRequestType {
             .map_err(|_e| net_error::DeserializeError("Failed to deserialize key value".into()))?;
 
         let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
+
+        Ok(HttpRequestType::GetMapEntry(HttpRequestMetadata::from_preamble(preamble), contract_addr, contract_name, map_name, value, with_proof, tip_req))
+    }
+
+    fn parse_get_map_entries<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let (md, contract_addr, contract_name) = HttpRequestType::parse_get_contract_arguments(preamble, captures)?;
+        let map_name = ClarityName::try_from(captures["map"].to_string())
+            .map_err(|_e| net_error::DeserializeError("Failed to parse map name".into()))?;
+
+        let count = HttpRequestType::get_u64_query(query, "count")?.unwrap_or(MAX_MAP_ENTRIES_PER_REQUEST);
+        if count == 0 || count > MAX_MAP_ENTRIES_PER_REQUEST {
+            return Err(net_error::DeserializeError(format!("Invalid map entry count: must be between 1 and {}", MAX_MAP_ENTRIES_PER_REQUEST)));
+        }
+
+        let after_str = match query {
+            Some(query_string) => form_urlencoded::parse(query_string.as_bytes())
+                .find(|(key, _v)| key == "after")
+                .map(|(_k, value)| value.to_string()),
+            None => None
+        };
+        let after = match after_str {
+            Some(after_str) => {
+                let after = Value::try_deserialize_hex_untyped(&after_str)
+                    .map_err(|_e| net_error::DeserializeError("Failed to deserialize key value".into()))?;
+                Some(after)
+            },
+            None => None
+        };
+
+        let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
+
+        Ok(HttpRequestType::GetMapEntries(md, contract_addr, contract_name, map_name, after, count, with_proof, tip_req))
+    }
+
+    fn parse_batch_proof<R: Read>(protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, _captures: &Captures, query: Option<&str>, fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let content_len = preamble.get_content_length();
+        if !(content_len > 0 && content_len < protocol.maximum_call_argument_size) {
+            return Err(net_error::DeserializeError("Invalid Http request: invalid body length for GetBatchProof".to_string()));
+        }
+
+        if preamble.content_type != Some(HttpContentType::JSON) {
+            return Err(net_error::DeserializeError("Invalid content-type: expected application/json".to_string()));
+        }
+
+        let body: BatchProofRequestBody = serde_json::from_reader(fd)
+            .map_err(|_e| net_error::DeserializeError("Failed to parse JSON body".into()))?;
+
+        let num_items = (body.accounts.len() + body.map_entries.len()) as u64;
+        if num_items == 0 || num_items > MAX_BATCH_PROOF_ITEMS {
+            return Err(net_error::DeserializeError(format!("Invalid batch proof size: must prove between 1 and {} items", MAX_BATCH_PROOF_ITEMS)));
+        }
+
+        let accounts = body.accounts.iter()
+            .map(|principal| PrincipalData::parse(principal).ok())
+            .collect::<Option<Vec<PrincipalData>>>()
+            .ok_or_else(|| net_error::DeserializeError("Failed to parse account principal".into()))?;
+
+        let mut map_entries = Vec::with_capacity(body.map_entries.len());
+        for entry in body.map_entries.into_iter() {
+            let contract_addr = StacksAddress::from_string(&entry.contract_address)
+                .ok_or_else(|| net_error::DeserializeError("Failed to parse contract address".into()))?;
+            let contract_name = ContractName::try_from(entry.contract_name)
+                .map_err(|_e| net_error::DeserializeError("Failed to parse contract name".into()))?;
+            let map_name = ClarityName::try_from(entry.map_name)
+                .map_err(|_e| net_error::DeserializeError("Failed to parse map name".into()))?;
+            let key = Value::try_deserialize_hex_untyped(&entry.key)
+                .map_err(|_e| net_error::DeserializeError("Failed to deserialize key value".into()))?;
+            map_entries.push((contract_addr, contract_name, map_name, key));
+        }
+
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
+
+        Ok(HttpRequestType::GetBatchProof(HttpRequestMetadata::from_preamble(preamble), accounts, map_entries, tip_req))
+    }
+
+    fn parse_get_ft_balance<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let (md, contract_addr, contract_name) = HttpRequestType::parse_get_contract_arguments(preamble, captures)?;
+        let token_name = ClarityName::try_from(captures["token"].to_string())
+            .map_err(|_e| net_error::DeserializeError("Failed to parse token name".into()))?;
+        let principal = PrincipalData::parse(&captures["principal"])
+            .map_err(|_e| net_error::DeserializeError("Failed to parse account principal".into()))?;
+
+        let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
+
+        Ok(HttpRequestType::GetFungibleTokenBalance(md, contract_addr, contract_name, token_name, principal, with_proof, tip_req))
+    }
+
+    fn parse_get_nft_owner<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let content_len = preamble.get_content_length();
+        if !(content_len > 0 && content_len < (BOUND_VALUE_SERIALIZATION_HEX)) {
+            return Err(net_error::DeserializeError("Invalid Http request: invalid body length for GetNonFungibleTokenOwner".to_string()));
+        }
+
+        if preamble.content_type != Some(HttpContentType::JSON) {
+            return Err(net_error::DeserializeError("Invalid content-type: expected application/json".into()));
+        }
+
+        let contract_addr =  StacksAddress::from_string(&captures["address"])
+            .ok_or_else(|| net_error::DeserializeError("Failed to parse contract address".into()))?;
+        let contract_name = ContractName::try_from(captures["contract"].to_string())
+            .map_err(|_e| net_error::DeserializeError("Failed to parse contract name".into()))?;
+        let token_name = ClarityName::try_from(captures["token"].to_string())
+            .map_err(|_e| net_error::DeserializeError("Failed to parse token name".into()))?;
+
+        let value_hex: String = serde_json::from_reader(fd)
+            .map_err(|_e| net_error::DeserializeError("Failed to parse JSON body".into()))?;
+
+        let value = Value::try_deserialize_hex_untyped(&value_hex)
+            .map_err(|_e| net_error::DeserializeError("Failed to deserialize asset value".into()))?;
+
+        let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
+
+        Ok(HttpRequestType::GetNonFungibleTokenOwner(HttpRequestMetadata::from_preamble(preamble), contract_addr, contract_name, token_name, value, with_proof, tip_req))
+    }
+
+    fn parse_get_data_var<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let (md, contract_addr, contract_name) = HttpRequestType::parse_get_contract_arguments(preamble, captures)?;
+        let var_name = ClarityName::try_from(captures["var"].to_string())
+            .map_err(|_e| net_error::DeserializeError("Failed to parse data var name".into()))?;
+
+        let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
 
-        Ok(HttpRequestType::GetMapEntry(HttpRequestMetadata::from_preamble(preamble), contract_addr, contract_name, map_name, value, with_proof))
+        Ok(HttpRequestType::GetDataVar(md, contract_addr, contract_name, var_name, with_proof, tip_req))
     }
 
-    fn parse_call_read_only<R: Read>(protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, _query: Option<&str>, fd: &mut R) -> Result<HttpRequestType, net_error> {
+    fn parse_call_read_only<R: Read>(protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, fd: &mut R) -> Result<HttpRequestType, net_error> {
         let content_len = preamble.get_content_length();
         if !(content_len > 0 && content_len < protocol.maximum_call_argument_size) {
             return Err(net_error::DeserializeError("Invalid Http request: invalid body length for GetMapEntry".to_string()));
@@ -1305,9 +1718,11 @@ impl HttpRequestType {
             .collect::<Option<Vec<Value>>>()
             .ok_or_else(|| net_error::DeserializeError("Failed to deserialize argument value".into()))?;
 
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
+
         Ok(HttpRequestType::CallReadOnlyFunction(
             HttpRequestMetadata::from_preamble(preamble),
-            contract_addr, contract_name, sender, func_name, arguments))
+            contract_addr, contract_name, sender, func_name, arguments, tip_req))
     }
 
     fn parse_get_contract_arguments(preamble: &HttpRequestPreamble, captures: &Captures) -> Result<(HttpRequestMetadata, StacksAddress, ContractName), net_error> {
@@ -1323,15 +1738,17 @@ impl HttpRequestType {
         Ok((HttpRequestMetadata::from_preamble(preamble), contract_addr, contract_name))
     }
 
-    fn parse_get_contract_abi<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, _query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+    fn parse_get_contract_abi<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
         HttpRequestType::parse_get_contract_arguments(preamble, captures)
-            .map(|(preamble, addr, name)| HttpRequestType::GetContractABI(preamble, addr, name))
+            .map(|(preamble, addr, name)| HttpRequestType::GetContractABI(preamble, addr, name, tip_req))
     }
 
     fn parse_get_contract_source<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
         let with_proof = HttpRequestType::get_proof_query(query);
+        let tip_req = HttpRequestType::get_tip_request_query(query)?;
         HttpRequestType::parse_get_contract_arguments(preamble, captures)
-            .map(|(preamble, addr, name)| HttpRequestType::GetContractSrc(preamble, addr, name, with_proof))
+            .map(|(preamble, addr, name)| HttpRequestType::GetContractSrc(preamble, addr, name, with_proof, tip_req))
     }
 
     fn parse_getblock<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, captures: &Captures, _query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
@@ -1426,6 +1843,31 @@ impl HttpRequestType {
         Ok(HttpRequestType::PostTransaction(HttpRequestMetadata::from_preamble(preamble), tx))
     }
 
+    fn parse_subscribe<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, _regex: &Captures, _query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
+        if preamble.get_content_length() != 0 {
+            return Err(net_error::DeserializeError("Invalid Http request: expected 0-length body for Subscribe".to_string()));
+        }
+
+        let is_upgrade = preamble.headers.get("upgrade").map(|v| v.trim().to_lowercase() == "websocket").unwrap_or(false)
+            && preamble.headers.get("connection").is_some();
+        if !is_upgrade {
+            return Err(net_error::DeserializeError("Invalid Http request: expected a websocket upgrade for Subscribe".to_string()));
+        }
+
+        if preamble.headers.get("sec-websocket-version").map(|v| v.trim() != "13").unwrap_or(true) {
+            return Err(net_error::DeserializeError("Invalid Http request: unsupported websocket version".to_string()));
+        }
+
+        let key = match preamble.headers.get("sec-websocket-key") {
+            Some(key) if is_valid_websocket_key(key) => key.trim().to_string(),
+            _ => {
+                return Err(net_error::DeserializeError("Invalid Http request: missing or invalid Sec-WebSocket-Key".to_string()));
+            }
+        };
+
+        Ok(HttpRequestType::Subscribe(HttpRequestMetadata::from_preamble(preamble), key))
+    }
+
     fn parse_options_preflight<R: Read>(_protocol: &mut StacksHttp, preamble: &HttpRequestPreamble, _regex: &Captures, _query: Option<&str>, _fd: &mut R) -> Result<HttpRequestType, net_error> {
         Ok(HttpRequestType::OptionsPreflight(HttpRequestMetadata::from_preamble(preamble), preamble.path.to_string()))
     }
@@ -1438,13 +1880,25 @@ impl HttpRequestType {
             HttpRequestType::GetMicroblocksIndexed(ref md, _) => md,
             HttpRequestType::GetMicroblocksConfirmed(ref md, _) => md,
             HttpRequestType::GetMicroblocksUnconfirmed(ref md, _, _) => md,
+            HttpRequestType::GetHeaders(ref md, ..) => md,
+            HttpRequestType::GetBlockByHeight(ref md, ..) => md,
+            HttpRequestType::GetMempoolTransactions(ref md, ..) => md,
+            HttpRequestType::GetMempoolAccount(ref md, _) => md,
+            HttpRequestType::GetPeerReputations(ref md) => md,
+            HttpRequestType::PostPeerAdmin(ref md, ..) => md,
             HttpRequestType::PostTransaction(ref md, _) => md,
             HttpRequestType::GetAccount(ref md, ..) => md,
             HttpRequestType::GetMapEntry(ref md, ..) => md,
+            HttpRequestType::GetMapEntries(ref md, ..) => md,
+            HttpRequestType::GetBatchProof(ref md, ..) => md,
+            HttpRequestType::GetFungibleTokenBalance(ref md, ..) => md,
+            HttpRequestType::GetNonFungibleTokenOwner(ref md, ..) => md,
+            HttpRequestType::GetDataVar(ref md, ..) => md,
             HttpRequestType::GetTransferCost(ref md) => md,
             HttpRequestType::GetContractABI(ref md, ..) => md,
             HttpRequestType::GetContractSrc(ref md, ..) => md,
             HttpRequestType::CallReadOnlyFunction(ref md, ..) => md,
+            HttpRequestType::Subscribe(ref md, _) => md,
             HttpRequestType::OptionsPreflight(ref md, ..) => md,
             HttpRequestType::Unmatched(ref md, ..) => md,
         }
@@ -1458,13 +1912,25 @@ impl HttpRequestType {
             HttpRequestType::GetMicroblocksIndexed(ref mut md, _) => md,
             HttpRequestType::GetMicroblocksConfirmed(ref mut md, _) => md,
             HttpRequestType::GetMicroblocksUnconfirmed(ref mut md, _, _) => md,
+            HttpRequestType::GetHeaders(ref mut md, ..) => md,
+            HttpRequestType::GetBlockByHeight(ref mut md, ..) => md,
+            HttpRequestType::GetMempoolTransactions(ref mut md, ..) => md,
+            HttpRequestType::GetMempoolAccount(ref mut md, _) => md,
+            HttpRequestType::GetPeerReputations(ref mut md) => md,
+            HttpRequestType::PostPeerAdmin(ref mut md, ..) => md,
             HttpRequestType::PostTransaction(ref mut md, _) => md,
             HttpRequestType::GetAccount(ref mut md, ..) => md,
             HttpRequestType::GetMapEntry(ref mut md, ..) => md,
+            HttpRequestType::GetMapEntries(ref mut md, ..) => md,
+            HttpRequestType::GetBatchProof(ref mut md, ..) => md,
+            HttpRequestType::GetFungibleTokenBalance(ref mut md, ..) => md,
+            HttpRequestType::GetNonFungibleTokenOwner(ref mut md, ..) => md,
+            HttpRequestType::GetDataVar(ref mut md, ..) => md,
             HttpRequestType::GetTransferCost(ref mut md) => md,
             HttpRequestType::GetContractABI(ref mut md, ..) => md,
             HttpRequestType::GetContractSrc(ref mut md, ..) => md,
             HttpRequestType::CallReadOnlyFunction(ref mut md, ..) => md,
+            HttpRequestType::Subscribe(ref mut md, _) => md,
             HttpRequestType::OptionsPreflight(ref mut md, ..) => md,
             HttpRequestType::Unmatched(ref mut md, ..) => md,
         }
@@ -1478,38 +1944,165 @@ impl HttpRequestType {
             HttpRequestType::GetMicroblocksIndexed(_md, block_hash) => format!("/v2/microblocks/{}", block_hash.to_hex()),
             HttpRequestType::GetMicroblocksConfirmed(_md, block_hash) => format!("/v2/microblocks/confirmed/{}", block_hash.to_hex()),
             HttpRequestType::GetMicroblocksUnconfirmed(_md, block_hash, min_seq) => format!("/v2/microblocks/unconfirmed/{}/{}", block_hash.to_hex(), min_seq),
+            HttpRequestType::GetHeaders(_md, count, tip_opt) => match tip_opt {
+                Some(tip) => format!("/v2/headers?count={}&tip={}", count, tip.to_hex()),
+                None => format!("/v2/headers?count={}", count)
+            },
+            HttpRequestType::GetBlockByHeight(_md, height, tip_opt) => match tip_opt {
+                Some(tip) => format!("/v2/blocks/height/{}?tip={}", height, tip.to_hex()),
+                None => format!("/v2/blocks/height/{}", height)
+            },
             HttpRequestType::PostTransaction(_md, _tx) => "/v2/transactions".to_string(),
-            HttpRequestType::GetAccount(_md, principal, _with_proof) => 
-                format!("/v2/accounts/{}", &principal.to_string()[1..]),
-            HttpRequestType::GetMapEntry(_md, contract_addr, contract_name, map_name, _key, _with_proof) =>
-                format!("/v2/map_entry/{}/{}/{}",
-                        contract_addr, contract_name.as_str(), map_name.as_str()),
+            HttpRequestType::GetMempoolTransactions(_md, address_opt, after_opt, count) => {
+                let mut path = format!("/v2/mempool/transactions?count={}", count);
+                match after_opt {
+                    Some((accept_time, Some(txid))) => path.push_str(&format!("&after={}:{}", accept_time, txid.to_hex())),
+                    Some((accept_time, None)) => path.push_str(&format!("&after={}", accept_time)),
+                    None => {}
+                }
+                if let Some(address) = address_opt {
+                    path.push_str(&format!("&principal={}", address));
+                }
+                path
+            },
+            HttpRequestType::GetMempoolAccount(_md, address) => format!("/v2/mempool/accounts/{}", address),
+            HttpRequestType::GetPeerReputations(_md) => "/v2/neighbors/reputation".to_string(),
+            HttpRequestType::PostPeerAdmin(_md, action, _selector) => format!("/v2/neighbors/{}", action.name()),
+            HttpRequestType::GetAccount(_md, principal, with_proof, tip_req) =>
+                format!("/v2/accounts/{}{}", &principal.to_string()[1..], HttpRequestType::make_query_string(*with_proof, tip_req)),
+            HttpRequestType::GetMapEntry(_md, contract_addr, contract_name, map_name, _key, with_proof, tip_req) =>
+                format!("/v2/map_entry/{}/{}/{}{}",
+                        contract_addr, contract_name.as_str(), map_name.as_str(), HttpRequestType::make_query_string(*with_proof, tip_req)),
+            HttpRequestType::GetMapEntries(_md, contract_addr, contract_name, map_name, after_opt, count, with_proof, tip_req) => {
+                let mut path = format!("/v2/map_entries/{}/{}/{}?count={}",
+                                       contract_addr, contract_name.as_str(), map_name.as_str(), count);
+                if let Some(after) = after_opt {
+                    path.push_str(&format!("&after=0x{}", after.serialize()));
+                }
+                if !*with_proof {
+                    path.push_str("&proof=0");
+                }
+                if let Some(tip_query) = HttpRequestType::make_tip_query(tip_req) {
+                    path.push_str(&format!("&{}", tip_query));
+                }
+                path
+            },
+            HttpRequestType::GetBatchProof(_md, _accounts, _map_entries, tip_req) =>
+                format!("/v2/batch_proof{}", HttpRequestType::make_query_string(true, tip_req)),
+            HttpRequestType::GetFungibleTokenBalance(_md, contract_addr, contract_name, token_name, principal, with_proof, tip_req) =>
+                format!("/v2/ft_balance/{}/{}/{}/{}{}",
+                        contract_addr, contract_name.as_str(), token_name.as_str(), principal, HttpRequestType::make_query_string(*with_proof, tip_req)),
+            HttpRequestType::GetNonFungibleTokenOwner(_md, contract_addr, contract_name, token_name, _value, with_proof, tip_req) =>
+                format!("/v2/nft_owner/{}/{}/{}{}",
+                        contract_addr, contract_name.as_str(), token_name.as_str(), HttpRequestType::make_query_string(*with_proof, tip_req)),
+            HttpRequestType::GetDataVar(_md, contract_addr, contract_name, var_name, with_proof, tip_req) =>
+                format!("/v2/data_var/{}/{}/{}{}",
+                        contract_addr, contract_name.as_str(), var_name.as_str(), HttpRequestType::make_query_string(*with_proof, tip_req)),
             HttpRequestType::GetTransferCost(_md) => "/v2/fees/transfer".into(),
-            HttpRequestType::GetContractABI(_, contract_addr, contract_name) =>
-                format!("/v2/contracts/interface/{}/{}", contract_addr, contract_name.as_str()),
-            HttpRequestType::GetContractSrc(_, contract_addr, contract_name, _with_proof) => 
-                format!("/v2/contracts/source/{}/{}", contract_addr, contract_name.as_str()),
-            HttpRequestType::CallReadOnlyFunction(_, contract_addr, contract_name, _, func_name, ..) => {
-                format!("/v2/contracts/call-read/{}/{}/{}", contract_addr, contract_name.as_str(), func_name.as_str())
+            HttpRequestType::GetContractABI(_, contract_addr, contract_name, tip_req) =>
+                format!("/v2/contracts/interface/{}/{}{}", contract_addr, contract_name.as_str(), HttpRequestType::make_query_string(true, tip_req)),
+            HttpRequestType::GetContractSrc(_, contract_addr, contract_name, with_proof, tip_req) =>
+                format!("/v2/contracts/source/{}/{}{}", contract_addr, contract_name.as_str(), HttpRequestType::make_query_string(*with_proof, tip_req)),
+            HttpRequestType::CallReadOnlyFunction(_, contract_addr, contract_name, _, func_name, _, tip_req) => {
+                format!("/v2/contracts/call-read/{}/{}/{}{}", contract_addr, contract_name.as_str(), func_name.as_str(), HttpRequestType::make_query_string(true, tip_req))
             },
+            HttpRequestType::Subscribe(_md, _key) => "/v2/subscribe".to_string(),
             HttpRequestType::OptionsPreflight(_md, path) => path.to_string(),
             HttpRequestType::Unmatched(_md, path) => path.to_string(),
         }
     }
 
+    /// the query argument that names the block a state-reading request reads from, if it isn't
+    /// the canonical Stacks chain tip.
+    fn make_tip_query(tip_req: &TipRequest) -> Option<String> {
+        match *tip_req {
+            TipRequest::UseLatestAnchoredTip => None,
+            TipRequest::SpecificTip(ref tip) => Some(format!("tip={}", tip.to_hex())),
+            TipRequest::AtHeight(height) => Some(format!("height={}", height))
+        }
+    }
+
+    /// the query string to append to a state-reading request's path to request (or not) a MARF
+    /// proof, and to name the block to read from.
+    fn make_query_string(with_proof: bool, tip_req: &TipRequest) -> String {
+        let mut args = vec![];
+        if !with_proof {
+            args.push("proof=0".to_string());
+        }
+        if let Some(tip_query) = HttpRequestType::make_tip_query(tip_req) {
+            args.push(tip_query);
+        }
+
+        if args.len() > 0 {
+            format!("?{}", args.join("&"))
+        }
+        else {
+            "".to_string()
+        }
+    }
+
     pub fn send<W: Write>(&self, _protocol: &mut StacksHttp, fd: &mut W) -> Result<(), net_error> {
         match self {
+            HttpRequestType::GetNonFungibleTokenOwner(md, _, _, _, value, _, _) => {
+                let value_json = serde_json::to_string(&value.serialize())
+                    .map_err(|e| net_error::SerializeError(format!("Failed to serialize asset value: {:?}", &e)))?;
+
+                HttpRequestPreamble::new_serialized(fd, &md.version, "POST", &self.request_path(), &md.peer, md.keep_alive, Some(value_json.len() as u32), Some(&HttpContentType::JSON), |ref mut fd| api_key_headers(fd, md))?;
+                fd.write_all(value_json.as_bytes()).map_err(net_error::WriteError)?;
+            },
             HttpRequestType::PostTransaction(md, tx) => {
                 let mut tx_bytes = vec![];
                 write_next(&mut tx_bytes, tx)?;
 
-                HttpRequestPreamble::new_serialized(fd, &md.version, "POST", &self.request_path(), &md.peer, md.keep_alive, Some(tx_bytes.len() as u32), Some(&HttpContentType::Bytes), empty_headers)?;
+                HttpRequestPreamble::new_serialized(fd, &md.version, "POST", &self.request_path(), &md.peer, md.keep_alive, Some(tx_bytes.len() as u32), Some(&HttpContentType::Bytes), |ref mut fd| api_key_headers(fd, md))?;
                 fd.write_all(&tx_bytes).map_err(net_error::WriteError)?;
             },
+            HttpRequestType::GetBatchProof(md, accounts, map_entries, _tip_req) => {
+                let body = BatchProofRequestBody {
+                    accounts: accounts.iter()
+                        .map(|principal| principal.to_string())
+                        .collect(),
+                    map_entries: map_entries.iter()
+                        .map(|(contract_addr, contract_name, map_name, key)| BatchProofMapEntryRequest {
+                            contract_address: contract_addr.to_string(),
+                            contract_name: contract_name.as_str().to_string(),
+                            map_name: map_name.as_str().to_string(),
+                            key: format!("0x{}", key.serialize())
+                        })
+                        .collect()
+                };
+                let body_json = serde_json::to_string(&body)
+                    .map_err(|e| net_error::SerializeError(format!("Failed to serialize batch proof request: {:?}", &e)))?;
+
+                HttpRequestPreamble::new_serialized(fd, &md.version, "POST", &self.request_path(), &md.peer, md.keep_alive, Some(body_json.len() as u32), Some(&HttpContentType::JSON), |ref mut fd| api_key_headers(fd, md))?;
+                fd.write_all(body_json.as_bytes()).map_err(net_error::WriteError)?;
+            },
+            HttpRequestType::PostPeerAdmin(md, action, selector) => {
+                let body = match selector {
+                    PeerSelector::Address(addrbytes, port) => RPCPeerAdminRequest {
+                        addr: Some(format!("{}", addrbytes.to_socketaddr(*port))),
+                        public_key: None,
+                        duration: action.duration()
+                    },
+                    PeerSelector::PublicKey(public_key) => RPCPeerAdminRequest {
+                        addr: None,
+                        public_key: Some(to_hex(&public_key.to_bytes_compressed())),
+                        duration: action.duration()
+                    }
+                };
+                let body_json = serde_json::to_string(&body)
+                    .map_err(|e| net_error::SerializeError(format!("Failed to serialize peer admin request: {:?}", &e)))?;
+
+                HttpRequestPreamble::new_serialized(fd, &md.version, "POST", &self.request_path(), &md.peer, md.keep_alive, Some(body_json.len() as u32), Some(&HttpContentType::JSON), |ref mut fd| api_key_headers(fd, md))?;
+                fd.write_all(body_json.as_bytes()).map_err(net_error::WriteError)?;
+            },
+            HttpRequestType::Subscribe(md, key) => {
+                HttpRequestPreamble::new_serialized(fd, &md.version, "GET", &self.request_path(), &md.peer, md.keep_alive, None, None, |ref mut fd| { api_key_headers(fd, md)?; websocket_request_headers(fd, key) })?;
+            },
             other_type => {
                 let md = other_type.metadata();
                 let request_path = other_type.request_path();
-                HttpRequestPreamble::new_serialized(fd, &md.version, "GET", &request_path, &md.peer, md.keep_alive, None, None, empty_headers)?;
+                HttpRequestPreamble::new_serialized(fd, &md.version, "GET", &request_path, &md.peer, md.keep_alive, None, None, |ref mut fd| api_key_headers(fd, md))?;
             }
         }
         Ok(())
@@ -1549,6 +2142,10 @@ impl HttpResponseType {
             402 => HttpResponseType::PaymentRequired(md, error_text),
             403 => HttpResponseType::Forbidden(md, error_text),
             404 => HttpResponseType::NotFound(md, error_text),
+            429 => {
+                let retry_after = preamble.headers.get("retry-after").and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+                HttpResponseType::TooManyRequests(md, retry_after, error_text)
+            },
             500 => HttpResponseType::ServerError(md, error_text),
             503 => HttpResponseType::ServiceUnavailable(md, error_text),
             _ => HttpResponseType::Error(md, preamble.status_code, error_text)
@@ -1672,16 +2269,33 @@ impl HttpResponseType {
         }
 
         // TODO: make this static somehow
-        let RESPONSE_METHODS : [(&Regex, &dyn Fn(&mut StacksHttp, HttpVersion, &HttpResponsePreamble, &mut R, Option<usize>) -> Result<HttpResponseType, net_error>); 7] = [
+        let RESPONSE_METHODS : [(&Regex, &dyn Fn(&mut StacksHttp, HttpVersion, &HttpResponsePreamble, &mut R, Option<usize>) -> Result<HttpResponseType, net_error>); 18] = [
             (&PATH_GETINFO, &HttpResponseType::parse_peerinfo),
             (&PATH_GETNEIGHBORS, &HttpResponseType::parse_neighbors),
+            (&PATH_GET_PEER_REPUTATIONS, &HttpResponseType::parse_peer_reputations),
+            (&PATH_POST_PEER_ADMIN, &HttpResponseType::parse_peer_reputations),
             (&PATH_GETBLOCK, &HttpResponseType::parse_block),
             (&PATH_GETMICROBLOCKS_INDEXED, &HttpResponseType::parse_microblocks),
             (&PATH_GETMICROBLOCKS_CONFIRMED, &HttpResponseType::parse_microblocks),
             (&PATH_GETMICROBLOCKS_UNCONFIRMED, &HttpResponseType::parse_microblocks_unconfirmed),
+            (&PATH_GETHEADERS, &HttpResponseType::parse_headers),
+            (&PATH_GETBLOCK_BY_HEIGHT, &HttpResponseType::parse_header_info),
+            (&PATH_GET_MEMPOOL_TXS, &HttpResponseType::parse_mempool_transactions),
+            (&PATH_GET_MEMPOOL_ACCOUNT, &HttpResponseType::parse_mempool_account),
+            (&PATH_GET_MAP_ENTRIES, &HttpResponseType::parse_map_entries),
+            (&PATH_POST_BATCH_PROOF, &HttpResponseType::parse_batch_proof),
+            (&PATH_GET_FT_BALANCE, &HttpResponseType::parse_ft_balance),
+            (&PATH_GET_NFT_OWNER, &HttpResponseType::parse_nft_owner),
+            (&PATH_GET_DATA_VAR, &HttpResponseType::parse_data_var),
             (&PATH_POSTTRANSACTION, &HttpResponseType::parse_txid)
         ];
 
+        // the request path may carry a query string, which the path regexes do not match
+        let request_path = match request_path.find('?') {
+            Some(idx) => request_path[0..idx].to_string(),
+            None => request_path
+        };
+
         for (regex, parser) in RESPONSE_METHODS.iter() {
             match HttpResponseType::try_parse(protocol, regex, request_version, preamble, &request_path, fd, len_hint, parser) {
                 Ok(Some(request)) => {
@@ -1711,11 +2325,61 @@ impl HttpResponseType {
         Ok(HttpResponseType::Neighbors(HttpResponseMetadata::from_preamble(request_version, preamble), neighbors_data))
     }
 
+    fn parse_peer_reputations<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let reputations : Vec<RPCPeerReputation> = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::PeerReputations(HttpResponseMetadata::from_preamble(request_version, preamble), reputations))
+    }
+
     fn parse_block<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
         let block : StacksBlock = HttpResponseType::parse_bytestream(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
         Ok(HttpResponseType::Block(HttpResponseMetadata::from_preamble(request_version, preamble), block))
     }
 
+    fn parse_headers<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let headers : Vec<RPCStacksHeaderInfo> = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::Headers(HttpResponseMetadata::from_preamble(request_version, preamble), headers))
+    }
+
+    fn parse_header_info<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let header_info : RPCStacksHeaderInfo = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::HeaderInfo(HttpResponseMetadata::from_preamble(request_version, preamble), header_info))
+    }
+
+    fn parse_mempool_transactions<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let txs_data : RPCMempoolTxsData = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::MempoolTransactions(HttpResponseMetadata::from_preamble(request_version, preamble), txs_data))
+    }
+
+    fn parse_map_entries<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let entries_data : MapEntriesResponse = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::GetMapEntries(HttpResponseMetadata::from_preamble(request_version, preamble), entries_data))
+    }
+
+    fn parse_batch_proof<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let batch_proof_data : BatchProofResponse = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::GetBatchProof(HttpResponseMetadata::from_preamble(request_version, preamble), batch_proof_data))
+    }
+
+    fn parse_mempool_account<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let account_data : RPCMempoolAccountData = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::MempoolAccount(HttpResponseMetadata::from_preamble(request_version, preamble), account_data))
+    }
+
+    fn parse_ft_balance<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let balance : FungibleTokenBalanceResponse = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::GetFungibleTokenBalance(HttpResponseMetadata::from_preamble(request_version, preamble), balance))
+    }
+
+    fn parse_nft_owner<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let owner : NonFungibleTokenOwnerResponse = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::GetNonFungibleTokenOwner(HttpResponseMetadata::from_preamble(request_version, preamble), owner))
+    }
+
+    fn parse_data_var<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
+        let data_var : DataVarResponse = HttpResponseType::parse_json(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
+        Ok(HttpResponseType::GetDataVar(HttpResponseMetadata::from_preamble(request_version, preamble), data_var))
+    }
+
     fn parse_microblocks<R: Read>(_protocol: &mut StacksHttp, request_version: HttpVersion, preamble: &HttpResponsePreamble, fd: &mut R, len_hint: Option<usize>) -> Result<HttpResponseType, net_error> {
         let microblocks : Vec<StacksMicroblock> = HttpResponseType::parse_bytestream(preamble, fd, len_hint, MAX_MESSAGE_LEN as u64)?;
         Ok(HttpResponseType::Microblocks(HttpResponseMetadata::from_preamble(request_version, preamble), microblocks))
@@ -1768,6 +2432,7 @@ impl HttpResponseType {
             402 => "Payment Required",
             403 => "Forbidden",
             404 => "Not Found",
+            429 => "Too Many Requests",
             500 => "Internal Server Error",
             503 => "Service Temporarily Unavailable",
             _ => "Error"
@@ -1790,13 +2455,24 @@ impl HttpResponseType {
             HttpResponseType::Microblocks(ref md, _) => md,
             HttpResponseType::MicroblockStream(ref md) => md,
             HttpResponseType::TransactionID(ref md, _) => md,
+            HttpResponseType::Headers(ref md, _) => md,
+            HttpResponseType::HeaderInfo(ref md, _) => md,
+            HttpResponseType::MempoolTransactions(ref md, _) => md,
+            HttpResponseType::MempoolAccount(ref md, _) => md,
+            HttpResponseType::PeerReputations(ref md, _) => md,
             HttpResponseType::TokenTransferCost(ref md, _) => md,
             HttpResponseType::GetMapEntry(ref md, _) => md,
+            HttpResponseType::GetMapEntries(ref md, _) => md,
+            HttpResponseType::GetBatchProof(ref md, _) => md,
+            HttpResponseType::GetFungibleTokenBalance(ref md, _) => md,
+            HttpResponseType::GetNonFungibleTokenOwner(ref md, _) => md,
+            HttpResponseType::GetDataVar(ref md, _) => md,
             HttpResponseType::GetAccount(ref md, _) => md,
             HttpResponseType::GetContractABI(ref md, _) => md,
             HttpResponseType::GetContractSrc(ref md, _) => md,
             HttpResponseType::CallReadOnlyFunction(ref md, _) => md,
             HttpResponseType::OptionsPreflight(ref md) => md,
+            HttpResponseType::WebSocketUpgrade(ref md, _) => md,
             // errors
             HttpResponseType::BadRequestJSON(ref md, _) => md,
             HttpResponseType::BadRequest(ref md, _) => md,
@@ -1804,6 +2480,7 @@ impl HttpResponseType {
             HttpResponseType::PaymentRequired(ref md, _) => md,
             HttpResponseType::Forbidden(ref md, _) => md,
             HttpResponseType::NotFound(ref md, _) => md,
+            HttpResponseType::TooManyRequests(ref md, _, _) => md,
             HttpResponseType::ServerError(ref md, _) => md,
             HttpResponseType::ServiceUnavailable(ref md, _) => md,
             HttpResponseType::Error(ref md, _, _) => md,
@@ -1881,6 +2558,26 @@ impl HttpResponseType {
                 HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
                 HttpResponseType::send_json(protocol, md, fd, map_data)?;
             },
+            HttpResponseType::GetMapEntries(ref md, ref entries_data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, entries_data)?;
+            },
+            HttpResponseType::GetBatchProof(ref md, ref batch_proof_data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, batch_proof_data)?;
+            },
+            HttpResponseType::GetFungibleTokenBalance(ref md, ref data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, data)?;
+            },
+            HttpResponseType::GetNonFungibleTokenOwner(ref md, ref data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, data)?;
+            },
+            HttpResponseType::GetDataVar(ref md, ref data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, data)?;
+            },
             HttpResponseType::PeerInfo(ref md, ref peer_info) => {
                 HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
                 HttpResponseType::send_json(protocol, md, fd, peer_info)?;
@@ -1889,6 +2586,26 @@ impl HttpResponseType {
                 HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
                 HttpResponseType::send_json(protocol, md, fd, neighbor_data)?;
             },
+            HttpResponseType::Headers(ref md, ref headers) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, headers)?;
+            },
+            HttpResponseType::HeaderInfo(ref md, ref header_info) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, header_info)?;
+            },
+            HttpResponseType::MempoolTransactions(ref md, ref txs_data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, txs_data)?;
+            },
+            HttpResponseType::MempoolAccount(ref md, ref account_data) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, account_data)?;
+            },
+            HttpResponseType::PeerReputations(ref md, ref reputations) => {
+                HttpResponsePreamble::ok_JSON_from_md(fd, md)?;
+                HttpResponseType::send_json(protocol, md, fd, reputations)?;
+            },
             HttpResponseType::Block(ref md, ref block) => {
                 HttpResponsePreamble::new_serialized(fd, 200, "OK", md.content_length.clone(), &HttpContentType::Bytes, md.request_id, |ref mut fd| keep_alive_headers(fd, md))?;
                 HttpResponseType::send_bytestream(protocol, md, fd, block)?;
@@ -1916,6 +2633,12 @@ impl HttpResponseType {
                 HttpResponsePreamble::new_serialized(fd, 200, "OK", None, &HttpContentType::Text, md.request_id, |ref mut fd| keep_alive_headers(fd, md))?;
                 HttpResponseType::send_text(protocol, md, fd, "".as_bytes())?;
             },
+            HttpResponseType::WebSocketUpgrade(ref md, ref accept_key) => {
+                // no body, and no Content-Length -- the connection stops being HTTP after this
+                fd.write_all("HTTP/1.1 101 Switching Protocols\r\nServer: stacks/2.0\r\n".as_bytes()).map_err(net_error::WriteError)?;
+                fd.write_all("Upgrade: websocket\r\nConnection: Upgrade\r\n".as_bytes()).map_err(net_error::WriteError)?;
+                fd.write_all(format!("Sec-WebSocket-Accept: {}\r\nX-Request-Id: {}\r\n\r\n", accept_key, md.request_id).as_bytes()).map_err(net_error::WriteError)?;
+            },
             HttpResponseType::BadRequestJSON(ref md, ref data) => {
                 HttpResponsePreamble::new_serialized(fd, 400, HttpResponseType::error_reason(400), md.content_length.clone(), &HttpContentType::JSON, md.request_id, |ref mut fd| keep_alive_headers(fd, md))?;
                 HttpResponseType::send_json(protocol, md, fd, data)?;
@@ -1925,6 +2648,11 @@ impl HttpResponseType {
             HttpResponseType::PaymentRequired(_, ref msg) => self.error_response(fd, 402, msg)?,
             HttpResponseType::Forbidden(_, ref msg) => self.error_response(fd, 403, msg)?,
             HttpResponseType::NotFound(_, ref msg) => self.error_response(fd, 404, msg)?,
+            HttpResponseType::TooManyRequests(ref md, ref retry_after, ref msg) => {
+                HttpResponsePreamble::new_serialized(fd, 429, HttpResponseType::error_reason(429), Some(msg.len() as u32), &HttpContentType::Text, md.request_id,
+                                                     |ref mut fd| retry_after_headers(fd, md, *retry_after))?;
+                fd.write_all(msg.as_bytes()).map_err(net_error::WriteError)?;
+            },
             HttpResponseType::ServerError(_, ref msg) => self.error_response(fd, 500, msg)?,
             HttpResponseType::ServiceUnavailable(_, ref msg) => self.error_response(fd, 503, msg)?,
             HttpResponseType::Error(_, ref error_code, ref msg) => self.error_response(fd, *error_code, msg)?
@@ -1988,19 +2716,36 @@ impl MessageSequence for StacksHttpMessage {
                 HttpRequestType::GetMicroblocksIndexed(_, _) => "HTTP(GetMicroblocksIndexed)",
                 HttpRequestType::GetMicroblocksConfirmed(_, _) => "HTTP(GetMicroblocksConfirmed)",
                 HttpRequestType::GetMicroblocksUnconfirmed(_, _, _) => "HTTP(GetMicroblocksUnconfirmed)",
+                HttpRequestType::GetHeaders(..) => "HTTP(GetHeaders)",
+                HttpRequestType::GetBlockByHeight(..) => "HTTP(GetBlockByHeight)",
+                HttpRequestType::GetMempoolTransactions(..) => "HTTP(GetMempoolTransactions)",
+                HttpRequestType::GetMempoolAccount(..) => "HTTP(GetMempoolAccount)",
+                HttpRequestType::GetPeerReputations(_) => "HTTP(GetPeerReputations)",
+                HttpRequestType::PostPeerAdmin(..) => "HTTP(PostPeerAdmin)",
                 HttpRequestType::PostTransaction(_, _) => "HTTP(PostTransaction)",
                 HttpRequestType::GetAccount(..) => "HTTP(GetAccount)",
                 HttpRequestType::GetMapEntry(..) => "HTTP(GetMapEntry)",
+                HttpRequestType::GetMapEntries(..) => "HTTP(GetMapEntries)",
+                HttpRequestType::GetBatchProof(..) => "HTTP(GetBatchProof)",
+                HttpRequestType::GetFungibleTokenBalance(..) => "HTTP(GetFungibleTokenBalance)",
+                HttpRequestType::GetNonFungibleTokenOwner(..) => "HTTP(GetNonFungibleTokenOwner)",
+                HttpRequestType::GetDataVar(..) => "HTTP(GetDataVar)",
                 HttpRequestType::GetTransferCost(_) => "HTTP(GetTransferCost)",
                 HttpRequestType::GetContractABI(..) => "HTTP(GetContractABI)",
                 HttpRequestType::GetContractSrc(..) => "HTTP(GetContractSrc)",
                 HttpRequestType::CallReadOnlyFunction(..) => "HTTP(CallReadOnlyFunction)",
+                HttpRequestType::Subscribe(..) => "HTTP(Subscribe)",
                 HttpRequestType::OptionsPreflight(..) => "HTTP(OptionsPreflight)",
                 HttpRequestType::Unmatched(..) => "HTTP(Unmatched)",
             },
             StacksHttpMessage::Response(ref res) => match res {
                 HttpResponseType::TokenTransferCost(_, _) => "HTTP(TokenTransferCost)",
                 HttpResponseType::GetMapEntry(_, _) => "HTTP(GetMapEntry)",
+                HttpResponseType::GetMapEntries(_, _) => "HTTP(GetMapEntries)",
+                HttpResponseType::GetBatchProof(_, _) => "HTTP(GetBatchProof)",
+                HttpResponseType::GetFungibleTokenBalance(_, _) => "HTTP(GetFungibleTokenBalance)",
+                HttpResponseType::GetNonFungibleTokenOwner(_, _) => "HTTP(GetNonFungibleTokenOwner)",
+                HttpResponseType::GetDataVar(_, _) => "HTTP(GetDataVar)",
                 HttpResponseType::GetAccount(_, _) => "HTTP(GetAccount)",
                 HttpResponseType::GetContractABI(..) => "HTTP(GetContractABI)",
                 HttpResponseType::GetContractSrc(..) => "HTTP(GetContractSrc)",
@@ -2012,12 +2757,19 @@ impl MessageSequence for StacksHttpMessage {
                 HttpResponseType::Microblocks(_, _) => "HTTP(Microblocks)",
                 HttpResponseType::MicroblockStream(_) => "HTTP(MicroblockStream)",
                 HttpResponseType::TransactionID(_, _) => "HTTP(Transaction)",
+                HttpResponseType::Headers(_, _) => "HTTP(Headers)",
+                HttpResponseType::HeaderInfo(_, _) => "HTTP(HeaderInfo)",
+                HttpResponseType::MempoolTransactions(_, _) => "HTTP(MempoolTransactions)",
+                HttpResponseType::MempoolAccount(_, _) => "HTTP(MempoolAccount)",
+                HttpResponseType::PeerReputations(_, _) => "HTTP(PeerReputations)",
                 HttpResponseType::OptionsPreflight(_) => "HTTP(OptionsPreflight)",
+                HttpResponseType::WebSocketUpgrade(_, _) => "HTTP(WebSocketUpgrade)",
                 HttpResponseType::BadRequestJSON(..) | HttpResponseType::BadRequest(..) => "HTTP(400)",
                 HttpResponseType::Unauthorized(_, _) => "HTTP(401)",
                 HttpResponseType::PaymentRequired(_, _) => "HTTP(402)",
                 HttpResponseType::Forbidden(_, _) => "HTTP(403)",
                 HttpResponseType::NotFound(_, _) => "HTTP(404)",
+                HttpResponseType::TooManyRequests(_, _, _) => "HTTP(429)",
                 HttpResponseType::ServerError(_, _) => "HTTP(500)",
                 HttpResponseType::ServiceUnavailable(_, _) => "HTTP(503)",
                 HttpResponseType::Error(_, _, _) => "HTTP(other)"
@@ -2425,6 +3177,14 @@ impl ProtocolFamily for StacksHttp {
             StacksHttpMessage::Response(ref resp) => resp.send(self, fd)
         }
     }
+
+    fn is_upgrade(&mut self, message: &StacksHttpMessage) -> bool {
+        // everything after a websocket upgrade request is websocket frames
+        match *message {
+            StacksHttpMessage::Request(HttpRequestType::Subscribe(..)) => true,
+            _ => false
+        }
+    }
 }
 
 
@@ -2436,6 +3196,10 @@ mod test {
     use net::codec::test::check_codec_and_corruption;
     use net::RPCNeighbor;
     use net::RPCNeighborsInfo;
+    use net::RPCMempoolTxInfo;
+    use net::MapEntriesItem;
+    use net::{ AccountEntryResponse, MapEntryResponse };
+    use net::websocket::websocket_accept_key;
 
     use chainstate::burn::BlockHeaderHash;
     use burnchains::Txid;
@@ -2964,7 +3728,7 @@ mod test {
         assert!(txt.find("foo: bar\r\n").is_some(), "foo header is missing");
         assert!(txt.find("X-Request-Id: 456\r\n").is_some(), "X-Request-Id is missing");
         assert!(txt.find("Access-Control-Allow-Origin: *\r\n").is_some(), "CORS header is missing");
-        assert!(txt.find("Access-Control-Allow-Headers: origin, content-type\r\n").is_some(), "CORS header is missing");
+        assert!(txt.find("Access-Control-Allow-Headers: origin, content-type, x-api-key\r\n").is_some(), "CORS header is missing");
         assert!(txt.find("Access-Control-Allow-Methods: POST, GET, OPTIONS\r\n").is_some(), "CORS header is missing");
         assert!(txt.find("Connection: ").is_none());    // not sent if keep_alive is true
     }
@@ -3114,11 +3878,13 @@ mod test {
             version: HttpVersion::Http11,
             peer: PeerHost::IP(PeerAddress([0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15]), 12345),
             keep_alive: true,
+            api_key: None,
         };
         let http_request_metadata_dns = HttpRequestMetadata {
             version: HttpVersion::Http11,
             peer: PeerHost::DNS("www.foo.com".to_string(), 80),
             keep_alive: true,
+            api_key: None,
         };
 
         let tests = vec![
@@ -3438,6 +4204,687 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_http_getheaders_codec() {
+        let tip = BlockHeaderHash([0x11; 32]);
+        let dummy_md = HttpRequestMetadata::new("localhost".to_string(), 1234);
+
+        let requests = vec![
+            ("GET /v2/headers HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(),
+             HttpRequestType::GetHeaders(dummy_md.clone(), 1, None)),
+            (format!("GET /v2/headers?count=10&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", tip.to_hex()),
+             HttpRequestType::GetHeaders(dummy_md.clone(), 10, Some(tip.clone()))),
+            ("GET /v2/blocks/height/123 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(),
+             HttpRequestType::GetBlockByHeight(dummy_md.clone(), 123, None)),
+            (format!("GET /v2/blocks/height/0?tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", tip.to_hex()),
+             HttpRequestType::GetBlockByHeight(dummy_md.clone(), 0, Some(tip.clone()))),
+        ];
+
+        for (request_str, expected) in requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let (message, _) = http.read_payload(&preamble, &request_str.as_bytes()[offset..]).unwrap();
+            match message {
+                StacksHttpMessage::Request(req) => {
+                    let mut expected = expected.clone();
+                    *expected.metadata_mut() = req.metadata().clone();
+                    assert_eq!(req, expected);
+
+                    // request path round-trips
+                    let path = request_str.split(' ').nth(1).unwrap();
+                    if path != "/v2/headers" {
+                        assert_eq!(req.request_path(), path);
+                    }
+                },
+                _ => {
+                    panic!("Did not get a request: {:?}", &message);
+                }
+            }
+        }
+
+        let bad_requests = vec![
+            ("GET /v2/headers?count=0 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Invalid header count"),
+            (format!("GET /v2/headers?count={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", MAX_HEADERS_PER_REQUEST + 1), "Invalid header count"),
+            ("GET /v2/headers?count=abc HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Failed to parse header count"),
+            ("GET /v2/blocks/height/1?tip=abc HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Failed to parse tip"),
+        ];
+
+        for (request_str, expected_error) in bad_requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find(expected_error).is_some(), errstr);
+        }
+
+        let header_info = RPCStacksHeaderInfo {
+            header: "00".to_string(),
+            block_hash: BlockHeaderHash([0x22; 32]).to_hex(),
+            index_block_hash: tip.to_hex(),
+            burn_header_hash: BlockHeaderHash([0x33; 32]).to_hex(),
+            burn_header_timestamp: 1234,
+            block_height: 5,
+            index_root: BlockHeaderHash([0x44; 32]).to_hex()
+        };
+
+        let headers_json = serde_json::to_string(&vec![header_info.clone()]).unwrap();
+        let header_info_json = serde_json::to_string(&header_info).unwrap();
+
+        let responses = vec![
+            (HttpResponseType::Headers(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(headers_json.len() as u32), true), vec![header_info.clone()]),
+             format!("/v2/headers?count=1&tip={}", tip.to_hex())),
+            (HttpResponseType::HeaderInfo(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(header_info_json.len() as u32), true), header_info.clone()),
+             "/v2/blocks/height/5".to_string()),
+        ];
+
+        for (response, request_path) in responses.iter() {
+            let mut http = StacksHttp::new();
+            let mut bytes = vec![];
+            http.begin_request(HttpVersion::Http11, request_path.to_string());
+            http.write_message(&mut bytes, &StacksHttpMessage::Response(response.clone())).unwrap();
+
+            match StacksHttp::parse_response(request_path, &bytes).unwrap() {
+                StacksHttpMessage::Response(HttpResponseType::Headers(_, headers)) => {
+                    assert_eq!(headers, vec![header_info.clone()]);
+                },
+                StacksHttpMessage::Response(HttpResponseType::HeaderInfo(_, hdr)) => {
+                    assert_eq!(hdr, header_info);
+                },
+                message => {
+                    panic!("Did not get a header response: {:?}", &message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_asset_requests_codec() {
+        let md = HttpRequestMetadata::new("localhost".to_string(), 1234);
+        let contract_addr = StacksAddress::from_string("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+        let contract_name = ContractName::try_from("hello-world".to_string()).unwrap();
+        let token_name = ClarityName::try_from("tokens".to_string()).unwrap();
+        let principal = PrincipalData::parse("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV.other-contract").unwrap();
+        let tip = BlockHeaderHash([0x33; 32]);
+
+        let requests = vec![
+            HttpRequestType::GetFungibleTokenBalance(md.clone(), contract_addr.clone(), contract_name.clone(), token_name.clone(), principal.clone(), true, TipRequest::UseLatestAnchoredTip),
+            HttpRequestType::GetFungibleTokenBalance(md.clone(), contract_addr.clone(), contract_name.clone(), token_name.clone(), PrincipalData::from(contract_addr.clone()), false, TipRequest::SpecificTip(tip.clone())),
+            HttpRequestType::GetNonFungibleTokenOwner(md.clone(), contract_addr.clone(), contract_name.clone(), token_name.clone(), Value::UInt(123), true, TipRequest::AtHeight(12)),
+            HttpRequestType::GetNonFungibleTokenOwner(md.clone(), contract_addr.clone(), contract_name.clone(), token_name.clone(), Value::UInt(456), false, TipRequest::UseLatestAnchoredTip),
+            HttpRequestType::GetDataVar(md.clone(), contract_addr.clone(), contract_name.clone(), token_name.clone(), true, TipRequest::SpecificTip(tip.clone())),
+            HttpRequestType::GetDataVar(md.clone(), contract_addr.clone(), contract_name.clone(), token_name.clone(), false, TipRequest::AtHeight(0)),
+            HttpRequestType::GetAccount(md.clone(), principal.clone(), true, TipRequest::AtHeight(34)),
+            HttpRequestType::GetAccount(md.clone(), PrincipalData::from(contract_addr.clone()), false, TipRequest::SpecificTip(tip.clone())),
+            HttpRequestType::GetContractSrc(md.clone(), contract_addr.clone(), contract_name.clone(), false, TipRequest::AtHeight(5)),
+            HttpRequestType::GetContractABI(md.clone(), contract_addr.clone(), contract_name.clone(), TipRequest::SpecificTip(tip.clone())),
+        ];
+
+        for request in requests.iter() {
+            let mut bytes = vec![];
+            let mut http = StacksHttp::new();
+            http.write_message(&mut bytes, &StacksHttpMessage::Request(request.clone())).unwrap();
+
+            test_debug!("request:\n{}", str::from_utf8(&bytes).unwrap());
+
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+            let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+            match message {
+                StacksHttpMessage::Request(parsed) => {
+                    let mut expected = request.clone();
+                    *expected.metadata_mut() = parsed.metadata().clone();
+                    assert_eq!(parsed, expected);
+                },
+                _ => {
+                    panic!("Did not get a request: {:?}", &message);
+                }
+            }
+        }
+
+        // a block may be named by tip or by height, but not both
+        let bad_requests = vec![
+            format!("GET /v2/accounts/{}?height=1&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &principal, tip.to_hex()),
+            format!("GET /v2/data_var/{}/hello-world/tokens?tip={}&height=1 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, tip.to_hex()),
+            format!("GET /v2/ft_balance/{}/hello-world/tokens/{}?height=1&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, &principal, tip.to_hex()),
+            format!("GET /v2/contracts/source/{}/hello-world?height=1&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, tip.to_hex()),
+            format!("GET /v2/contracts/interface/{}/hello-world?height=1&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, tip.to_hex()),
+        ];
+
+        for request_str in bad_requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find("Cannot give both tip and height").is_some(), errstr);
+        }
+    }
+
+    #[test]
+    fn test_http_map_entries_codec() {
+        let dummy_md = HttpRequestMetadata::new("localhost".to_string(), 1234);
+        let contract_addr = StacksAddress::from_string("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+        let contract_name = ContractName::try_from("hello-world".to_string()).unwrap();
+        let map_name = ClarityName::try_from("balances".to_string()).unwrap();
+        let tip = BlockHeaderHash([0x22; 32]);
+
+        let requests = vec![
+            (format!("GET /v2/map_entries/{}/hello-world/balances HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr),
+             HttpRequestType::GetMapEntries(dummy_md.clone(), contract_addr.clone(), contract_name.clone(), map_name.clone(), None, MAX_MAP_ENTRIES_PER_REQUEST, true, TipRequest::UseLatestAnchoredTip)),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?count=10&after=0x{}&proof=0&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, Value::UInt(123).serialize(), tip.to_hex()),
+             HttpRequestType::GetMapEntries(dummy_md.clone(), contract_addr.clone(), contract_name.clone(), map_name.clone(), Some(Value::UInt(123)), 10, false, TipRequest::SpecificTip(tip.clone()))),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?height=15 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr),
+             HttpRequestType::GetMapEntries(dummy_md.clone(), contract_addr.clone(), contract_name.clone(), map_name.clone(), None, MAX_MAP_ENTRIES_PER_REQUEST, true, TipRequest::AtHeight(15))),
+        ];
+
+        for (request_str, expected) in requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let (message, _) = http.read_payload(&preamble, &request_str.as_bytes()[offset..]).unwrap();
+            match message {
+                StacksHttpMessage::Request(req) => {
+                    let mut expected = expected.clone();
+                    *expected.metadata_mut() = req.metadata().clone();
+                    assert_eq!(req, expected);
+
+                    // re-encoded request parses back to the same request
+                    let mut bytes = vec![];
+                    let mut http = StacksHttp::new();
+                    http.write_message(&mut bytes, &StacksHttpMessage::Request(req.clone())).unwrap();
+
+                    let mut http = StacksHttp::new();
+                    let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+                    let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+                    match message {
+                        StacksHttpMessage::Request(reparsed) => {
+                            let mut expected = req.clone();
+                            *expected.metadata_mut() = reparsed.metadata().clone();
+                            assert_eq!(reparsed, expected);
+                        },
+                        _ => {
+                            panic!("Did not get a request: {:?}", &message);
+                        }
+                    }
+                },
+                _ => {
+                    panic!("Did not get a request: {:?}", &message);
+                }
+            }
+        }
+
+        let bad_requests = vec![
+            (format!("GET /v2/map_entries/{}/hello-world/balances?count=0 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr), "Invalid map entry count"),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?count={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, MAX_MAP_ENTRIES_PER_REQUEST + 1), "Invalid map entry count"),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?after=0xzz HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr), "Failed to deserialize key value"),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?tip=abc HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr), "Failed to parse tip index block hash"),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?height=-1 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr), "Failed to parse query argument 'height'"),
+            (format!("GET /v2/map_entries/{}/hello-world/balances?height=1&tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &contract_addr, tip.to_hex()), "Cannot give both tip and height"),
+        ];
+
+        for (request_str, expected_error) in bad_requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find(expected_error).is_some(), errstr);
+        }
+
+        let entries_data = MapEntriesResponse {
+            entries: vec![
+                MapEntriesItem {
+                    key: format!("0x{}", Value::UInt(1).serialize()),
+                    data: format!("0x{}", Value::UInt(2).serialize()),
+                    marf_proof: Some("0x1234".to_string())
+                },
+                MapEntriesItem {
+                    key: format!("0x{}", Value::UInt(3).serialize()),
+                    data: format!("0x{}", Value::UInt(4).serialize()),
+                    marf_proof: None
+                }
+            ],
+            next_cursor: Some(format!("0x{}", Value::UInt(3).serialize()))
+        };
+        let entries_json = serde_json::to_string(&entries_data).unwrap();
+
+        let response = HttpResponseType::GetMapEntries(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(entries_json.len() as u32), true), entries_data.clone());
+        let request_path = format!("/v2/map_entries/{}/hello-world/balances?count=2", &contract_addr);
+
+        let mut http = StacksHttp::new();
+        let mut bytes = vec![];
+        http.begin_request(HttpVersion::Http11, request_path.clone());
+        http.write_message(&mut bytes, &StacksHttpMessage::Response(response.clone())).unwrap();
+
+        match StacksHttp::parse_response(&request_path, &bytes).unwrap() {
+            StacksHttpMessage::Response(HttpResponseType::GetMapEntries(_, data)) => {
+                assert_eq!(data, entries_data);
+            },
+            message => {
+                panic!("Did not get a map entries response: {:?}", &message);
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_batch_proof_codec() {
+        let dummy_md = HttpRequestMetadata::new("localhost".to_string(), 1234);
+        let contract_addr = StacksAddress::from_string("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+        let contract_name = ContractName::try_from("hello-world".to_string()).unwrap();
+        let map_name = ClarityName::try_from("balances".to_string()).unwrap();
+        let principal = PrincipalData::parse("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV.other-contract").unwrap();
+        let tip = BlockHeaderHash([0x22; 32]);
+
+        let body = format!("{{\"accounts\":[\"{}\",\"ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV.other-contract\"],\"map_entries\":[{{\"contract_address\":\"{}\",\"contract_name\":\"hello-world\",\"map_name\":\"balances\",\"key\":\"0x{}\"}}]}}",
+                           &contract_addr, &contract_addr, Value::UInt(123).serialize());
+        let accounts_body = format!("{{\"accounts\":[\"{}\"]}}", &contract_addr);
+
+        let requests = vec![
+            (format!("POST /v2/batch_proof HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), &body),
+             HttpRequestType::GetBatchProof(dummy_md.clone(), vec![PrincipalData::from(contract_addr.clone()), principal.clone()],
+                                            vec![(contract_addr.clone(), contract_name.clone(), map_name.clone(), Value::UInt(123))], TipRequest::UseLatestAnchoredTip)),
+            (format!("POST /v2/batch_proof?tip={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", tip.to_hex(), accounts_body.len(), &accounts_body),
+             HttpRequestType::GetBatchProof(dummy_md.clone(), vec![PrincipalData::from(contract_addr.clone())], vec![], TipRequest::SpecificTip(tip.clone()))),
+        ];
+
+        for (request_str, expected) in requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let (message, _) = http.read_payload(&preamble, &request_str.as_bytes()[offset..]).unwrap();
+            match message {
+                StacksHttpMessage::Request(req) => {
+                    let mut expected = expected.clone();
+                    *expected.metadata_mut() = req.metadata().clone();
+                    assert_eq!(req, expected);
+
+                    // re-encoded request parses back to the same request
+                    let mut bytes = vec![];
+                    let mut http = StacksHttp::new();
+                    http.write_message(&mut bytes, &StacksHttpMessage::Request(req.clone())).unwrap();
+
+                    let mut http = StacksHttp::new();
+                    let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+                    let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+                    match message {
+                        StacksHttpMessage::Request(reparsed) => {
+                            let mut expected = req.clone();
+                            *expected.metadata_mut() = reparsed.metadata().clone();
+                            assert_eq!(reparsed, expected);
+                        },
+                        _ => {
+                            panic!("Did not get a request: {:?}", &message);
+                        }
+                    }
+                },
+                _ => {
+                    panic!("Did not get a request: {:?}", &message);
+                }
+            }
+        }
+
+        let too_many_accounts = vec![format!("\"{}\"", &contract_addr); (MAX_BATCH_PROOF_ITEMS + 1) as usize].join(",");
+        let bad_bodies = vec![
+            ("{}".to_string(), "Invalid batch proof size"),
+            (format!("{{\"accounts\":[{}]}}", &too_many_accounts), "Invalid batch proof size"),
+            ("{\"accounts\":[\"nonsense\"]}".to_string(), "Failed to parse account principal"),
+            (format!("{{\"map_entries\":[{{\"contract_address\":\"{}\",\"contract_name\":\"hello-world\",\"map_name\":\"balances\",\"key\":\"0xzz\"}}]}}", &contract_addr), "Failed to deserialize key value"),
+        ];
+
+        for (bad_body, expected_error) in bad_bodies.iter() {
+            let request_str = format!("POST /v2/batch_proof HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", bad_body.len(), bad_body);
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find(expected_error).is_some(), errstr);
+        }
+
+        let batch_proof_data = BatchProofResponse {
+            accounts: vec![
+                AccountEntryResponse {
+                    balance: "0x00000000000000000000000000000064".to_string(),
+                    nonce: 1,
+                    balance_proof: None,
+                    nonce_proof: None
+                }
+            ],
+            map_entries: vec![
+                MapEntryResponse {
+                    data: format!("0x{}", Value::none().serialize()),
+                    marf_proof: None
+                }
+            ],
+            proven_keys: vec!["vm-account::ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV::19".to_string()],
+            proof: "0x1234".to_string()
+        };
+        let batch_proof_json = serde_json::to_string(&batch_proof_data).unwrap();
+
+        let response = HttpResponseType::GetBatchProof(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(batch_proof_json.len() as u32), true), batch_proof_data.clone());
+        let request_path = "/v2/batch_proof".to_string();
+
+        let mut http = StacksHttp::new();
+        let mut bytes = vec![];
+        http.begin_request(HttpVersion::Http11, request_path.clone());
+        http.write_message(&mut bytes, &StacksHttpMessage::Response(response.clone())).unwrap();
+
+        match StacksHttp::parse_response(&request_path, &bytes).unwrap() {
+            StacksHttpMessage::Response(HttpResponseType::GetBatchProof(_, data)) => {
+                assert_eq!(data, batch_proof_data);
+            },
+            message => {
+                panic!("Did not get a batch proof response: {:?}", &message);
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_mempool_codec() {
+        let dummy_md = HttpRequestMetadata::new("localhost".to_string(), 1234);
+        let address = StacksAddress::from_string("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+
+        let requests = vec![
+            ("GET /v2/mempool/transactions HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(),
+             HttpRequestType::GetMempoolTransactions(dummy_md.clone(), None, None, MAX_MEMPOOL_TXS_PER_REQUEST)),
+            (format!("GET /v2/mempool/transactions?count=10&after=1234&principal={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &address),
+             HttpRequestType::GetMempoolTransactions(dummy_md.clone(), Some(address.clone()), Some((1234, None)), 10)),
+            (format!("GET /v2/mempool/transactions?count=10&after=1234:{} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", Txid([0x11; 32]).to_hex()),
+             HttpRequestType::GetMempoolTransactions(dummy_md.clone(), None, Some((1234, Some(Txid([0x11; 32])))), 10)),
+            (format!("GET /v2/mempool/accounts/{} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", &address),
+             HttpRequestType::GetMempoolAccount(dummy_md.clone(), address.clone())),
+        ];
+
+        for (request_str, expected) in requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let (message, _) = http.read_payload(&preamble, &request_str.as_bytes()[offset..]).unwrap();
+            match message {
+                StacksHttpMessage::Request(req) => {
+                    let mut expected = expected.clone();
+                    *expected.metadata_mut() = req.metadata().clone();
+                    assert_eq!(req, expected);
+
+                    // request path round-trips
+                    let path = request_str.split(' ').nth(1).unwrap();
+                    if path != "/v2/mempool/transactions" {
+                        assert_eq!(req.request_path(), path);
+                    }
+                },
+                _ => {
+                    panic!("Did not get a request: {:?}", &message);
+                }
+            }
+        }
+
+        let bad_requests = vec![
+            ("GET /v2/mempool/transactions?count=0 HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Invalid mempool transaction count"),
+            (format!("GET /v2/mempool/transactions?count={} HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n", MAX_MEMPOOL_TXS_PER_REQUEST + 1), "Invalid mempool transaction count"),
+            ("GET /v2/mempool/transactions?after=abc HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Failed to parse query argument 'after'"),
+            ("GET /v2/mempool/transactions?after=1234:abc HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Failed to parse query argument 'after'"),
+            ("GET /v2/mempool/transactions?principal=abc HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "Failed to parse principal address"),
+        ];
+
+        for (request_str, expected_error) in bad_requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find(expected_error).is_some(), errstr);
+        }
+
+        let txs_data = RPCMempoolTxsData {
+            transactions: vec![
+                RPCMempoolTxInfo {
+                    txid: Txid([0x11; 32]).to_hex(),
+                    fee_rate: 1,
+                    estimated_fee: 200,
+                    origin_address: address.to_string(),
+                    origin_nonce: 2,
+                    sponsor_address: address.to_string(),
+                    sponsor_nonce: 2,
+                    accept_time: 1234
+                }
+            ],
+            next_cursor: Some(format!("1234:{}", Txid([0x11; 32]).to_hex()))
+        };
+        let account_data = RPCMempoolAccountData {
+            address: address.to_string(),
+            nonce: 1,
+            pending_count: 2,
+            min_pending_nonce: Some(1),
+            max_pending_nonce: Some(3),
+            next_nonce: 2
+        };
+
+        let txs_json = serde_json::to_string(&txs_data).unwrap();
+        let account_json = serde_json::to_string(&account_data).unwrap();
+
+        let responses = vec![
+            (HttpResponseType::MempoolTransactions(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(txs_json.len() as u32), true), txs_data.clone()),
+             format!("/v2/mempool/transactions?count=1&principal={}", &address)),
+            (HttpResponseType::MempoolAccount(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(account_json.len() as u32), true), account_data.clone()),
+             format!("/v2/mempool/accounts/{}", &address)),
+        ];
+
+        for (response, request_path) in responses.iter() {
+            let mut http = StacksHttp::new();
+            let mut bytes = vec![];
+            http.begin_request(HttpVersion::Http11, request_path.to_string());
+            http.write_message(&mut bytes, &StacksHttpMessage::Response(response.clone())).unwrap();
+
+            match StacksHttp::parse_response(request_path, &bytes).unwrap() {
+                StacksHttpMessage::Response(HttpResponseType::MempoolTransactions(_, data)) => {
+                    assert_eq!(data, txs_data);
+                },
+                StacksHttpMessage::Response(HttpResponseType::MempoolAccount(_, data)) => {
+                    assert_eq!(data, account_data);
+                },
+                message => {
+                    panic!("Did not get a mempool response: {:?}", &message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_peer_admin_codec() {
+        use util::secp256k1::{Secp256k1PublicKey, Secp256k1PrivateKey};
+
+        let md = HttpRequestMetadata::new("localhost".to_string(), 1234);
+        let public_key = Secp256k1PublicKey::from_private(&Secp256k1PrivateKey::new());
+        let addrbytes = PeerAddress::from_socketaddr(&"127.0.0.1:20444".parse::<SocketAddr>().unwrap());
+
+        let requests = vec![
+            HttpRequestType::GetPeerReputations(md.clone()),
+            HttpRequestType::PostPeerAdmin(md.clone(), PeerAdminAction::Ban(None), PeerSelector::Address(addrbytes.clone(), 20444)),
+            HttpRequestType::PostPeerAdmin(md.clone(), PeerAdminAction::Ban(Some(3600)), PeerSelector::PublicKey(public_key.clone())),
+            HttpRequestType::PostPeerAdmin(md.clone(), PeerAdminAction::Unban, PeerSelector::Address(addrbytes.clone(), 20444)),
+            HttpRequestType::PostPeerAdmin(md.clone(), PeerAdminAction::Allow(Some(60)), PeerSelector::PublicKey(public_key.clone())),
+            HttpRequestType::PostPeerAdmin(md.clone(), PeerAdminAction::Disallow, PeerSelector::Address(addrbytes.clone(), 20444)),
+        ];
+
+        for request in requests.iter() {
+            let mut bytes = vec![];
+            let mut http = StacksHttp::new();
+            http.write_message(&mut bytes, &StacksHttpMessage::Request(request.clone())).unwrap();
+
+            test_debug!("request:\n{}", str::from_utf8(&bytes).unwrap());
+
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+            let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+            match message {
+                StacksHttpMessage::Request(parsed) => {
+                    let mut expected = request.clone();
+                    *expected.metadata_mut() = parsed.metadata().clone();
+                    assert_eq!(parsed, expected);
+                },
+                _ => {
+                    panic!("Did not get a request: {:?}", &message);
+                }
+            }
+        }
+
+        let bad_requests = vec![
+            ("POST /v2/neighbors/ban HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}".to_string(), "Expected exactly one of addr or public_key"),
+            (format!("POST /v2/neighbors/ban HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nContent-Type: application/json\r\nContent-Length: 19\r\n\r\n{{\"addr\":\"nonsense\"}}"), "Not an IP:port or a public key"),
+        ];
+
+        for (request_str, expected_error) in bad_requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find(expected_error).is_some(), errstr);
+        }
+
+        let reputations = vec![
+            RPCPeerReputation {
+                network_id: 0x80000000,
+                peer_version: 0x18000000,
+                addrbytes: addrbytes.clone(),
+                port: 20444,
+                public_key: to_hex(&public_key.to_bytes_compressed()),
+                score: 25,
+                whitelisted: 0,
+                blacklisted: -1
+            }
+        ];
+        let reputations_json = serde_json::to_string(&reputations).unwrap();
+
+        for request_path in ["/v2/neighbors/reputation", "/v2/neighbors/ban"].iter() {
+            let response = HttpResponseType::PeerReputations(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(reputations_json.len() as u32), true), reputations.clone());
+            let mut http = StacksHttp::new();
+            let mut bytes = vec![];
+            http.begin_request(HttpVersion::Http11, request_path.to_string());
+            http.write_message(&mut bytes, &StacksHttpMessage::Response(response)).unwrap();
+
+            match StacksHttp::parse_response(request_path, &bytes).unwrap() {
+                StacksHttpMessage::Response(HttpResponseType::PeerReputations(_, data)) => {
+                    assert_eq!(data, reputations);
+                },
+                message => {
+                    panic!("Did not get a peer reputation response: {:?}", &message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_subscribe_codec() {
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let upgrade_headers = format!("Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n", key);
+        let request_str = format!("GET /v2/subscribe HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n{}\r\n", &upgrade_headers);
+
+        let mut http = StacksHttp::new();
+        let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+        let (message, _) = http.read_payload(&preamble, &request_str.as_bytes()[offset..]).unwrap();
+        let request = match message {
+            StacksHttpMessage::Request(HttpRequestType::Subscribe(md, parsed_key)) => {
+                assert_eq!(parsed_key, key);
+                assert!(md.keep_alive);
+                HttpRequestType::Subscribe(md, parsed_key)
+            },
+            _ => {
+                panic!("Did not get a subscribe request: {:?}", &message);
+            }
+        };
+
+        // request serializes to something we can parse again
+        let mut bytes = vec![];
+        request.send(&mut http, &mut bytes).unwrap();
+        let mut http = StacksHttp::new();
+        let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+        let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+        match message {
+            StacksHttpMessage::Request(req) => {
+                assert_eq!(req, request);
+            },
+            _ => {
+                panic!("Did not get a request: {:?}", &message);
+            }
+        }
+
+        let bad_requests = vec![
+            ("GET /v2/subscribe HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\n\r\n".to_string(), "expected a websocket upgrade"),
+            (format!("GET /v2/subscribe HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 8\r\nSec-WebSocket-Key: {}\r\n\r\n", key), "unsupported websocket version"),
+            ("GET /v2/subscribe HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: abc\r\n\r\n".to_string(), "missing or invalid Sec-WebSocket-Key"),
+        ];
+
+        for (request_str, expected_error) in bad_requests.iter() {
+            let mut http = StacksHttp::new();
+            let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+            let e = http.read_payload(&preamble, &request_str.as_bytes()[offset..]);
+            let errstr = format!("{:?}", &e);
+            assert!(e.is_err(), errstr);
+            assert!(e.unwrap_err().to_string().find(expected_error).is_some(), errstr);
+        }
+
+        // 101 response carries the accept key and no body
+        let response = HttpResponseType::WebSocketUpgrade(HttpResponseMetadata::new(HttpVersion::Http11, 123, None, true), websocket_accept_key(key));
+        let mut http = StacksHttp::new();
+        let mut bytes = vec![];
+        response.send(&mut http, &mut bytes).unwrap();
+        let response_str = String::from_utf8(bytes).unwrap();
+        assert!(response_str.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response_str.find("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n").is_some());
+        assert!(response_str.find("Content-Length").is_none());
+        assert!(response_str.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_http_api_key_and_rate_limit_codec() {
+        // api key header is parsed into request metadata, and survives a round-trip
+        let request_str = "GET /v2/info HTTP/1.1\r\nUser-Agent: stacks/2.0\r\nHost: localhost:1234\r\nX-Api-Key: hunter2\r\n\r\n";
+        let mut http = StacksHttp::new();
+        let (preamble, offset) = http.read_preamble(request_str.as_bytes()).unwrap();
+        let (message, _) = http.read_payload(&preamble, &request_str.as_bytes()[offset..]).unwrap();
+        let request = match message {
+            StacksHttpMessage::Request(req) => req,
+            _ => {
+                panic!("Did not get a request: {:?}", &message);
+            }
+        };
+        assert_eq!(request.metadata().api_key, Some("hunter2".to_string()));
+
+        let mut bytes = vec![];
+        request.send(&mut http, &mut bytes).unwrap();
+        assert!(String::from_utf8(bytes.clone()).unwrap().find("X-Api-Key: hunter2\r\n").is_some());
+
+        let mut http = StacksHttp::new();
+        let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+        let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+        match message {
+            StacksHttpMessage::Request(req) => {
+                assert_eq!(req, request);
+            },
+            _ => {
+                panic!("Did not get a request: {:?}", &message);
+            }
+        }
+
+        // 429 carries a Retry-After header
+        let response = HttpResponseType::TooManyRequests(HttpResponseMetadata::new(HttpVersion::Http11, 123, Some(10), true), 30, "slow down!".to_string());
+        let mut http = StacksHttp::new();
+        let mut bytes = vec![];
+        http.begin_request(HttpVersion::Http11, "/v2/info".to_string());
+        http.write_message(&mut bytes, &StacksHttpMessage::Response(response.clone())).unwrap();
+        assert!(String::from_utf8(bytes.clone()).unwrap().find("Retry-After: 30\r\n").is_some());
+
+        let (preamble, offset) = http.read_preamble(&bytes).unwrap();
+        let (message, _) = http.read_payload(&preamble, &bytes[offset..]).unwrap();
+        match message {
+            StacksHttpMessage::Response(resp) => {
+                assert_eq!(resp, response);
+            },
+            _ => {
+                panic!("Did not get a response: {:?}", &message);
+            }
+        }
+    }
+
     #[test]
     fn test_http_headers_too_big() {
         let bad_header_value = std::iter::repeat("A").take(HTTP_PREAMBLE_MAX_ENCODED_SIZE as usize).collect::<String>();
@@ -0,0 +1,692 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+use burnchains::BurnchainHeaderHash;
+
+use chainstate::burn::BlockHeaderHash;
+use chainstate::burn::BlockSnapshot;
+use chainstate::stacks::StacksAddress;
+use chainstate::stacks::StacksBlockHeader;
+use chainstate::stacks::index::{MARFValue, TrieHash};
+use chainstate::stacks::index::node::TriePath;
+use chainstate::stacks::index::proofs::{TrieMerkleProof, TrieMerkleProofType, TrieMerkleBatchProof};
+
+use core::{FIRST_BURNCHAIN_BLOCK_HASH, FIRST_STACKS_BLOCK_HASH};
+
+use net::StacksMessageCodec;
+use net::codec::read_next;
+use net::{ AccountEntryResponse, MapEntryResponse, ContractSrcResponse, BatchProofResponse, RPCStacksHeaderInfo };
+
+use util::hash::{hex_bytes, to_hex, Sha512Trunc256Sum};
+
+use vm::{
+    ClarityName,
+    ContractName,
+    Value,
+    types::{ PrincipalData,
+             QualifiedContractIdentifier },
+    database::{ ClarityDatabase,
+                MarfedKV,
+                ClaritySerializable,
+                marf::ContractCommitment },
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The header could not be decoded, or disagrees with the hashes sent alongside it
+    MalformedHeader(String),
+    /// No trusted sortition happened in this burnchain block
+    NoSuchSortition(BurnchainHeaderHash),
+    /// The sortition in this burnchain block picked a different Stacks block
+    SortitionMismatch(BurnchainHeaderHash, BlockHeaderHash),
+    /// The header's parent has not been accepted
+    UnknownParent(BlockHeaderHash),
+    /// The chain tip (an index block hash) has not been accepted
+    NoSuchTip(BlockHeaderHash),
+    /// The node did not send a proof (e.g. because the value does not exist)
+    MissingProof,
+    /// The proof or the value it proves could not be decoded
+    MalformedProof(String),
+    /// The proof does not prove this value for this key at the chain tip
+    InvalidProof(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::MalformedHeader(ref s) => write!(f, "Malformed header: {}", s),
+            Error::NoSuchSortition(ref burn_hash) => write!(f, "No sortition in burnchain block {}", burn_hash.to_hex()),
+            Error::SortitionMismatch(ref burn_hash, ref block_hash) => write!(f, "Sortition in burnchain block {} did not pick {}", burn_hash.to_hex(), block_hash.to_hex()),
+            Error::UnknownParent(ref block_hash) => write!(f, "Unknown parent block {}", block_hash.to_hex()),
+            Error::NoSuchTip(ref index_block_hash) => write!(f, "Unknown chain tip {}", index_block_hash.to_hex()),
+            Error::MissingProof => write!(f, "No MARF proof given"),
+            Error::MalformedProof(ref s) => write!(f, "Malformed proof: {}", s),
+            Error::InvalidProof(ref key) => write!(f, "Invalid MARF proof for {}", key),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        None
+    }
+}
+
+/// A Stacks block header the light client has accepted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightClientHeader {
+    pub header: StacksBlockHeader,
+    pub burn_header_hash: BurnchainHeaderHash,
+    pub index_block_hash: BlockHeaderHash,
+    pub block_height: u64,
+    /// height of the burnchain block whose sortition picked this block
+    pub burn_block_height: u64,
+    /// root hash of the headers MARF as of this block, as reported by the node
+    pub index_root: TrieHash,
+}
+
+/// A light client for checking the MARF proofs a Stacks node returns over RPC, without a copy of
+/// the chainstate.  The client is given the burnchain sortitions it trusts, and the Stacks headers
+/// it downloads from `/v2/headers` or `/v2/blocks/height/{n}`.  Each header is only accepted if
+/// a trusted sortition picked it and it builds on an accepted parent.  The `state_index_root`s
+/// of the accepted headers are then all a MARF proof needs to be checked against.
+///
+/// The client also tracks each header's `index_root`, so proofs against the node's headers MARF
+/// can be checked.  Unlike the `state_index_root`, the `index_root` is not committed to by the
+/// header itself, so it is only as trustworthy as the node that reported it.
+pub struct LightClient {
+    /// trusted sortitions, by burnchain header hash
+    sortitions: HashMap<BurnchainHeaderHash, BlockSnapshot>,
+    /// accepted headers, by index block hash
+    headers: HashMap<BlockHeaderHash, LightClientHeader>,
+    /// index block hashes of the accepted headers, by Stacks block hash
+    index_hashes: HashMap<BlockHeaderHash, Vec<BlockHeaderHash>>,
+    /// Clarity MARF root hash of each accepted block, mapped to its index block hash
+    root_to_block: HashMap<TrieHash, BlockHeaderHash>,
+    /// headers MARF root hash of each accepted block, mapped to its index block hash
+    index_root_to_block: HashMap<TrieHash, BlockHeaderHash>,
+}
+
+/// Decode a hex string that may or may not start with "0x"
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = if s.starts_with("0x") { &s[2..] } else { s };
+    hex_bytes(s).ok()
+}
+
+impl LightClient {
+    /// Make a light client that knows about the genesis block only.  The genesis header does not
+    /// commit to the boot code's state, so the caller must supply (and trust) the Clarity MARF
+    /// root hash of the boot block.  Values written by the boot code can't be verified otherwise.
+    /// Likewise, `boot_index_root` is the headers MARF root hash of the boot block.
+    pub fn new(boot_state_root: TrieHash, boot_index_root: TrieHash) -> LightClient {
+        let genesis_header = StacksBlockHeader::genesis_block_header();
+        let genesis_index_hash = StacksBlockHeader::make_index_block_hash(&FIRST_BURNCHAIN_BLOCK_HASH, &FIRST_STACKS_BLOCK_HASH);
+
+        let mut headers = HashMap::new();
+        headers.insert(genesis_index_hash.clone(), LightClientHeader {
+            header: genesis_header,
+            burn_header_hash: FIRST_BURNCHAIN_BLOCK_HASH.clone(),
+            index_block_hash: genesis_index_hash.clone(),
+            block_height: 0,
+            burn_block_height: 0,
+            index_root: boot_index_root.clone()
+        });
+
+        let mut index_hashes = HashMap::new();
+        index_hashes.insert(FIRST_STACKS_BLOCK_HASH.clone(), vec![genesis_index_hash.clone()]);
+
+        let mut root_to_block = HashMap::new();
+        root_to_block.insert(boot_state_root, genesis_index_hash.clone());
+
+        let mut index_root_to_block = HashMap::new();
+        index_root_to_block.insert(boot_index_root, genesis_index_hash);
+
+        LightClient {
+            sortitions: HashMap::new(),
+            headers,
+            index_hashes,
+            root_to_block,
+            index_root_to_block
+        }
+    }
+
+    /// Trust a burnchain block's sortition.  Where snapshots come from is up to the caller --
+    /// e.g. a burnchain SPV client, or a burn DB the caller maintains.
+    pub fn add_sortition(&mut self, snapshot: BlockSnapshot) -> () {
+        self.sortitions.insert(snapshot.burn_header_hash.clone(), snapshot);
+    }
+
+    /// Accept a header downloaded over RPC.  The header must have been picked by a trusted
+    /// sortition, and must build on an accepted header.  Accepting a header twice is a no-op.
+    pub fn add_header(&mut self, header_info: &RPCStacksHeaderInfo) -> Result<(), Error> {
+        let header_bytes = decode_hex(&header_info.header)
+            .ok_or(Error::MalformedHeader("header is not hex".to_string()))?;
+        let header : StacksBlockHeader = read_next(&mut &header_bytes[..])
+            .map_err(|e| Error::MalformedHeader(format!("{:?}", &e)))?;
+        let burn_header_hash = BurnchainHeaderHash::from_hex(&header_info.burn_header_hash)
+            .map_err(|_e| Error::MalformedHeader("burn_header_hash is not a hash".to_string()))?;
+        let index_root = TrieHash::from_hex(&header_info.index_root)
+            .map_err(|_e| Error::MalformedHeader("index_root is not a hash".to_string()))?;
+
+        let block_hash = header.block_hash();
+        let index_block_hash = StacksBlockHeader::make_index_block_hash(&burn_header_hash, &block_hash);
+        if block_hash.to_hex() != header_info.block_hash {
+            return Err(Error::MalformedHeader(format!("block_hash is not {}", block_hash.to_hex())));
+        }
+        if index_block_hash.to_hex() != header_info.index_block_hash {
+            return Err(Error::MalformedHeader(format!("index_block_hash is not {}", index_block_hash.to_hex())));
+        }
+        if header.total_work.work != header_info.block_height {
+            return Err(Error::MalformedHeader(format!("block_height is not {}", header.total_work.work)));
+        }
+
+        if let Some(known_header) = self.headers.get(&index_block_hash) {
+            if known_header.header == header && known_header.index_root == index_root {
+                return Ok(());
+            }
+            return Err(Error::MalformedHeader(format!("conflicts with accepted header {}", index_block_hash.to_hex())));
+        }
+
+        let burn_block_height = match self.sortitions.get(&burn_header_hash) {
+            Some(ref snapshot) if snapshot.sortition => {
+                if snapshot.winning_stacks_block_hash != block_hash {
+                    return Err(Error::SortitionMismatch(burn_header_hash, block_hash));
+                }
+                snapshot.block_height
+            },
+            _ => {
+                return Err(Error::NoSuchSortition(burn_header_hash));
+            }
+        };
+
+        // the parent must be an accepted header at the previous height, picked earlier on the
+        // burnchain.
+        let has_parent = match self.index_hashes.get(&header.parent_block) {
+            Some(parent_index_hashes) => parent_index_hashes
+                .iter()
+                .filter_map(|parent_index_hash| self.headers.get(parent_index_hash))
+                .any(|parent| parent.block_height + 1 == header.total_work.work && parent.burn_block_height < burn_block_height),
+            None => false
+        };
+        if !has_parent {
+            return Err(Error::UnknownParent(header.parent_block.clone()));
+        }
+
+        test_debug!("Light client accepts {} (height {}) at {}", index_block_hash.to_hex(), header.total_work.work, header.state_index_root.to_hex());
+
+        self.root_to_block.insert(header.state_index_root.clone(), index_block_hash.clone());
+        self.index_root_to_block.insert(index_root.clone(), index_block_hash.clone());
+        self.index_hashes.entry(block_hash)
+            .or_insert(vec![])
+            .push(index_block_hash.clone());
+        self.headers.insert(index_block_hash.clone(), LightClientHeader {
+            block_height: header.total_work.work,
+            header,
+            burn_header_hash,
+            index_block_hash,
+            burn_block_height,
+            index_root
+        });
+        Ok(())
+    }
+
+    /// Accept a list of headers, such as from `/v2/headers` (which lists them from the tip
+    /// backwards).  Headers are added lowest first, so each one's parent is accepted before it.
+    pub fn add_headers(&mut self, headers: &[RPCStacksHeaderInfo]) -> Result<(), Error> {
+        let mut sorted_headers : Vec<&RPCStacksHeaderInfo> = headers.iter().collect();
+        sorted_headers.sort_by_key(|header_info| header_info.block_height);
+        for header_info in sorted_headers.into_iter() {
+            if header_info.block_height == 0 {
+                // genesis is always accepted
+                continue;
+            }
+            self.add_header(header_info)?;
+        }
+        Ok(())
+    }
+
+    /// Get an accepted header by its index block hash
+    pub fn get_header(&self, index_block_hash: &BlockHeaderHash) -> Option<&LightClientHeader> {
+        self.headers.get(index_block_hash)
+    }
+
+    /// Map of each accepted block's Clarity MARF root hash to its index block hash, as
+    /// TrieMerkleProof::verify() needs it.
+    pub fn root_to_block(&self) -> &HashMap<TrieHash, BlockHeaderHash> {
+        &self.root_to_block
+    }
+
+    /// Map of each accepted block's headers MARF root hash to its index block hash
+    pub fn index_root_to_block(&self) -> &HashMap<TrieHash, BlockHeaderHash> {
+        &self.index_root_to_block
+    }
+
+    /// Decode a hex-encoded proof, or fail with MissingProof if it's empty
+    fn decode_proof_bytes(proof_hex: &str) -> Result<Vec<u8>, Error> {
+        if proof_hex.len() == 0 {
+            return Err(Error::MissingProof);
+        }
+        decode_hex(proof_hex)
+            .ok_or(Error::MalformedProof("proof is not hex".to_string()))
+    }
+
+    /// Verify a hex-encoded MARF proof that `key` maps to `value`, given the MARF root hash at the
+    /// tip and the root-to-block map of the MARF the proof was made from.
+    fn verify_proof_at(key: &str, value: &str, proof_hex: &str, root_hash: &TrieHash, root_to_block: &HashMap<TrieHash, BlockHeaderHash>) -> Result<(), Error> {
+        let proof_bytes = LightClient::decode_proof_bytes(proof_hex)?;
+        let proof_nodes : Vec<TrieMerkleProofType> = read_next(&mut &proof_bytes[..])
+            .map_err(|e| Error::MalformedProof(format!("{:?}", &e)))?;
+        let proof = TrieMerkleProof(proof_nodes);
+
+        if !proof.verify(&TriePath::from_key(key), &MARFValue::from_value(value), root_hash, root_to_block) {
+            return Err(Error::InvalidProof(key.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Verify a hex-encoded MARF proof that `key` maps to the Clarity-serialized `value` as of the
+    /// chain tip with the given index block hash.
+    pub fn verify_marf_proof(&self, tip: &BlockHeaderHash, key: &str, value: &str, proof_hex: &str) -> Result<(), Error> {
+        let tip_header = self.headers.get(tip)
+            .ok_or(Error::NoSuchTip(tip.clone()))?;
+        LightClient::verify_proof_at(key, value, proof_hex, &tip_header.header.state_index_root, &self.root_to_block)
+    }
+
+    /// Verify a hex-encoded proof from the node's headers MARF that `key` maps to `value` as of
+    /// the chain tip with the given index block hash.  The proof is checked against the tip's
+    /// `index_root`.
+    pub fn verify_headers_marf_proof(&self, tip: &BlockHeaderHash, key: &str, value: &str, proof_hex: &str) -> Result<(), Error> {
+        let tip_header = self.headers.get(tip)
+            .ok_or(Error::NoSuchTip(tip.clone()))?;
+        LightClient::verify_proof_at(key, value, proof_hex, &tip_header.index_root, &self.index_root_to_block)
+    }
+
+    /// Verify the balance and nonce in a response from `/v2/accounts/{principal}?proof=1`
+    pub fn verify_account(&self, tip: &BlockHeaderHash, principal: &PrincipalData, account: &AccountEntryResponse) -> Result<(), Error> {
+        let balance_bytes = decode_hex(&account.balance)
+            .ok_or(Error::MalformedProof("balance is not hex".to_string()))?;
+        if balance_bytes.len() != 16 {
+            return Err(Error::MalformedProof("balance is not a 128-bit integer".to_string()));
+        }
+        let mut balance_be = [0u8; 16];
+        balance_be.copy_from_slice(&balance_bytes[..]);
+        let balance = u128::from_be_bytes(balance_be);
+
+        let balance_proof = account.balance_proof.as_ref().ok_or(Error::MissingProof)?;
+        let nonce_proof = account.nonce_proof.as_ref().ok_or(Error::MissingProof)?;
+
+        self.verify_marf_proof(tip, &ClarityDatabase::make_key_for_account_balance(principal), &balance.serialize(), balance_proof)?;
+        self.verify_marf_proof(tip, &ClarityDatabase::make_key_for_account_nonce(principal), &account.nonce.serialize(), nonce_proof)
+    }
+
+    /// Verify the value in a response from `/v2/map_entry/{address}/{contract}/{map}?proof=1`,
+    /// given the key that was asked for.
+    pub fn verify_map_entry(&self, tip: &BlockHeaderHash, contract_addr: &StacksAddress, contract_name: &ContractName,
+                            map_name: &ClarityName, key: &Value, entry: &MapEntryResponse) -> Result<(), Error> {
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+        let value_bytes = decode_hex(&entry.data)
+            .ok_or(Error::MalformedProof("data is not hex".to_string()))?;
+        let marf_proof = entry.marf_proof.as_ref().ok_or(Error::MissingProof)?;
+
+        let key = ClarityDatabase::make_key_for_data_map_entry(&contract_identifier, map_name, key);
+        self.verify_marf_proof(tip, &key, &to_hex(&value_bytes), marf_proof)
+    }
+
+    /// Verify the source code in a response from `/v2/contracts/source/{address}/{contract}?proof=1`.
+    /// The MARF commits to the hash of the source and the height it was published at.
+    pub fn verify_contract_source(&self, tip: &BlockHeaderHash, contract_addr: &StacksAddress, contract_name: &ContractName,
+                                  contract_src: &ContractSrcResponse) -> Result<(), Error> {
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+        let marf_proof = contract_src.marf_proof.as_ref().ok_or(Error::MissingProof)?;
+
+        let commitment = ContractCommitment {
+            hash: Sha512Trunc256Sum::from_data(contract_src.source.as_bytes()),
+            block_height: contract_src.publish_height
+        };
+        self.verify_marf_proof(tip, &MarfedKV::make_contract_hash_key(&contract_identifier), &commitment.serialize(), marf_proof)
+    }
+
+    /// Verify a response from `/v2/batch_proof`, given the accounts and data map entries that
+    /// were asked for (in the order they were asked for).  Every requested value must be covered
+    /// by the batched proof, as with the single-value proofs.
+    pub fn verify_batch_proof(&self, tip: &BlockHeaderHash, accounts: &[PrincipalData],
+                              map_entries: &[(StacksAddress, ContractName, ClarityName, Value)],
+                              batch: &BatchProofResponse) -> Result<(), Error> {
+        let tip_header = self.headers.get(tip)
+            .ok_or(Error::NoSuchTip(tip.clone()))?;
+
+        if batch.accounts.len() != accounts.len() || batch.map_entries.len() != map_entries.len() {
+            return Err(Error::MalformedProof("batch does not answer every request".to_string()));
+        }
+
+        let mut entries = vec![];
+        for (principal, account) in accounts.iter().zip(batch.accounts.iter()) {
+            let balance_bytes = decode_hex(&account.balance)
+                .ok_or(Error::MalformedProof("balance is not hex".to_string()))?;
+            if balance_bytes.len() != 16 {
+                return Err(Error::MalformedProof("balance is not a 128-bit integer".to_string()));
+            }
+            let mut balance_be = [0u8; 16];
+            balance_be.copy_from_slice(&balance_bytes[..]);
+            let balance = u128::from_be_bytes(balance_be);
+
+            entries.push((ClarityDatabase::make_key_for_account_balance(principal), balance.serialize()));
+            entries.push((ClarityDatabase::make_key_for_account_nonce(principal), account.nonce.serialize()));
+        }
+        for ((contract_addr, contract_name, map_name, key), entry) in map_entries.iter().zip(batch.map_entries.iter()) {
+            let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+            let value_bytes = decode_hex(&entry.data)
+                .ok_or(Error::MalformedProof("data is not hex".to_string()))?;
+            entries.push((ClarityDatabase::make_key_for_data_map_entry(&contract_identifier, map_name, key), to_hex(&value_bytes)));
+        }
+
+        // the node only proves the values it has, in the order they were asked for
+        if entries.len() != batch.proven_keys.len() {
+            return Err(Error::MissingProof);
+        }
+        for ((key, _), proven_key) in entries.iter().zip(batch.proven_keys.iter()) {
+            if key != proven_key {
+                return Err(Error::InvalidProof(key.clone()));
+            }
+        }
+
+        let proof_bytes = LightClient::decode_proof_bytes(&batch.proof)?;
+        let proof : TrieMerkleBatchProof = read_next(&mut &proof_bytes[..])
+            .map_err(|e| Error::MalformedProof(format!("{:?}", &e)))?;
+
+        if proof.proofs.len() != entries.len() {
+            return Err(Error::MalformedProof(format!("batch has {} proofs, not {}", proof.proofs.len(), entries.len())));
+        }
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let key_proof = proof.get_proof(i)
+                .ok_or(Error::MalformedProof(format!("no proof for {}", key)))?;
+            if !key_proof.verify(&TriePath::from_key(key), &MARFValue::from_value(value), &tip_header.header.state_index_root, &self.root_to_block) {
+                return Err(Error::InvalidProof(key.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    use burnchains::Txid;
+    use chainstate::burn::{ConsensusHash, OpsHash, SortitionHash};
+    use chainstate::stacks::{StacksWorkScore, STACKS_BLOCK_VERSION, MINER_BLOCK_HEADER_HASH};
+    use chainstate::stacks::index::marf::MARF;
+    use chainstate::stacks::index::storage::TrieFileStorage;
+    use core::EMPTY_MICROBLOCK_PARENT_HASH;
+    use util::hash::Hash160;
+    use util::vrf::VRFProof;
+
+    fn make_snapshot(burn_block_height: u64, burn_header_hash: &BurnchainHeaderHash, winner: &BlockHeaderHash) -> BlockSnapshot {
+        BlockSnapshot {
+            block_height: burn_block_height,
+            burn_header_timestamp: 0,
+            burn_header_hash: burn_header_hash.clone(),
+            parent_burn_header_hash: BurnchainHeaderHash([0u8; 32]),
+            consensus_hash: ConsensusHash([0u8; 20]),
+            ops_hash: OpsHash([0u8; 32]),
+            total_burn: 0,
+            sortition: true,
+            sortition_hash: SortitionHash([0u8; 32]),
+            winning_block_txid: Txid([0u8; 32]),
+            winning_stacks_block_hash: winner.clone(),
+            index_root: TrieHash([0u8; 32]),
+            num_sortitions: 0,
+            stacks_block_accepted: true,
+            stacks_block_height: 0,
+            arrival_index: 0,
+            canonical_stacks_tip_height: 0,
+            canonical_stacks_tip_hash: BlockHeaderHash([0u8; 32]),
+            canonical_stacks_tip_burn_hash: BurnchainHeaderHash([0u8; 32]),
+        }
+    }
+
+    fn make_header(parent: &StacksBlockHeader, state_index_root: &TrieHash) -> StacksBlockHeader {
+        StacksBlockHeader {
+            version: STACKS_BLOCK_VERSION,
+            total_work: StacksWorkScore { burn: 0, work: parent.total_work.work + 1 },
+            proof: VRFProof::empty(),
+            parent_block: parent.block_hash(),
+            parent_microblock: EMPTY_MICROBLOCK_PARENT_HASH.clone(),
+            parent_microblock_sequence: 0,
+            tx_merkle_root: Sha512Trunc256Sum([0u8; 32]),
+            state_index_root: state_index_root.clone(),
+            microblock_pubkey_hash: Hash160([0u8; 20]),
+        }
+    }
+
+    fn make_header_info(header: &StacksBlockHeader, burn_header_hash: &BurnchainHeaderHash, index_root: &TrieHash) -> RPCStacksHeaderInfo {
+        let mut header_bytes = vec![];
+        header.consensus_serialize(&mut header_bytes).unwrap();
+        RPCStacksHeaderInfo {
+            header: to_hex(&header_bytes),
+            block_hash: header.block_hash().to_hex(),
+            index_block_hash: header.index_block_hash(burn_header_hash).to_hex(),
+            burn_header_hash: burn_header_hash.to_hex(),
+            burn_header_timestamp: 0,
+            block_height: header.total_work.work,
+            index_root: index_root.to_hex()
+        }
+    }
+
+    /// Mine a block into the MARF the way the chainstate does: write the trie under a placeholder
+    /// hash, put its root hash into the header, then rename the trie to the index block hash.
+    fn mine_block(marf: &mut MARF, parent: &StacksBlockHeader, parent_index_hash: &BlockHeaderHash,
+                  burn_header_hash: &BurnchainHeaderHash, kvs: &[(String, String)]) -> StacksBlockHeader {
+        marf.begin(parent_index_hash, &MINER_BLOCK_HEADER_HASH).unwrap();
+        for (key, value) in kvs.iter() {
+            marf.insert(key, MARFValue::from_value(value)).unwrap();
+        }
+        let root_hash = marf.get_root_hash().unwrap();
+        let header = make_header(parent, &root_hash);
+        marf.commit_to(&header.index_block_hash(burn_header_hash)).unwrap();
+        header
+    }
+
+    /// Write a block's trie into a MARF keyed by index block hash from the start, the way the
+    /// headers MARF is.  Returns the root hash.
+    fn index_block(marf: &mut MARF, parent_index_hash: &BlockHeaderHash, index_hash: &BlockHeaderHash, kvs: &[(String, String)]) -> TrieHash {
+        marf.begin(parent_index_hash, index_hash).unwrap();
+        for (key, value) in kvs.iter() {
+            marf.insert(key, MARFValue::from_value(value)).unwrap();
+        }
+        let root_hash = marf.get_root_hash().unwrap();
+        marf.commit().unwrap();
+        root_hash
+    }
+
+    #[test]
+    fn test_light_client_verify_proofs() {
+        let mut marf = MARF::from_path(":memory:", None).unwrap();
+
+        let principal = PrincipalData::parse("SP3X6QWWETNBZWGBK6DRGTR1KX50S74D3433WDGJY").unwrap();
+        let contract_addr = StacksAddress { version: 22, bytes: Hash160([0x11; 20]) };
+        let contract_name = ContractName::try_from("hello-world".to_string()).unwrap();
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+        let map_name = ClarityName::try_from("unit-map".to_string()).unwrap();
+        let map_key = Value::UInt(1);
+        let source = "(define-map unit-map ((key uint)) ((value uint)))".to_string();
+
+        let balance_key = ClarityDatabase::make_key_for_account_balance(&principal);
+        let nonce_key = ClarityDatabase::make_key_for_account_nonce(&principal);
+        let contract_key = MarfedKV::make_contract_hash_key(&contract_identifier);
+        let map_entry_key = ClarityDatabase::make_key_for_data_map_entry(&contract_identifier, &map_name, &map_key);
+        let map_value = Value::some(Value::UInt(2)).unwrap();
+        let commitment = ContractCommitment { hash: Sha512Trunc256Sum::from_data(source.as_bytes()), block_height: 1 };
+
+        // boot block
+        let genesis = StacksBlockHeader::genesis_block_header();
+        let genesis_index_hash = genesis.index_block_hash(&FIRST_BURNCHAIN_BLOCK_HASH);
+        marf.begin(&TrieFileStorage::block_sentinel(), &genesis_index_hash).unwrap();
+        marf.insert(&balance_key, MARFValue::from_value(&(1000 as u128).serialize())).unwrap();
+        let boot_state_root = marf.get_root_hash().unwrap();
+        marf.commit().unwrap();
+
+        // block 1 publishes the contract and spends from the account
+        let burn_1 = BurnchainHeaderHash([0x01; 32]);
+        let header_1 = mine_block(&mut marf, &genesis, &genesis_index_hash, &burn_1,
+                                  &[(contract_key.clone(), commitment.serialize()),
+                                    (balance_key.clone(), (900 as u128).serialize()),
+                                    (nonce_key.clone(), (1 as u64).serialize())]);
+        let index_1 = header_1.index_block_hash(&burn_1);
+
+        // block 2 writes the map entry
+        let burn_2 = BurnchainHeaderHash([0x02; 32]);
+        let header_2 = mine_block(&mut marf, &header_1, &index_1, &burn_2,
+                                  &[(map_entry_key.clone(), map_value.serialize())]);
+        let index_2 = header_2.index_block_hash(&burn_2);
+
+        // the node's headers MARF over the same blocks
+        let mut headers_marf = MARF::from_path(":memory:", None).unwrap();
+        let height_key = "light-client::height".to_string();
+        let block_1_key = "light-client::block-1".to_string();
+        let index_root_0 = index_block(&mut headers_marf, &TrieFileStorage::block_sentinel(), &genesis_index_hash,
+                                       &[(height_key.clone(), "00".to_string())]);
+        let index_root_1 = index_block(&mut headers_marf, &genesis_index_hash, &index_1,
+                                       &[(height_key.clone(), "01".to_string()), (block_1_key.clone(), index_1.to_hex())]);
+        let index_root_2 = index_block(&mut headers_marf, &index_1, &index_2,
+                                       &[(height_key.clone(), "02".to_string())]);
+
+        let mut client = LightClient::new(boot_state_root, index_root_0);
+
+        // headers are only accepted with a sortition that picked them
+        let headers = vec![make_header_info(&header_2, &burn_2, &index_root_2), make_header_info(&header_1, &burn_1, &index_root_1)];
+        assert_eq!(client.add_headers(&headers), Err(Error::NoSuchSortition(burn_1.clone())));
+
+        client.add_sortition(make_snapshot(1, &burn_1, &BlockHeaderHash([0x22; 32])));
+        assert_eq!(client.add_headers(&headers), Err(Error::SortitionMismatch(burn_1.clone(), header_1.block_hash())));
+
+        client.add_sortition(make_snapshot(1, &burn_1, &header_1.block_hash()));
+        client.add_sortition(make_snapshot(2, &burn_2, &header_2.block_hash()));
+
+        // and only on top of an accepted parent
+        assert_eq!(client.add_header(&headers[0]), Err(Error::UnknownParent(header_1.block_hash())));
+        client.add_headers(&headers).unwrap();
+        assert_eq!(client.get_header(&index_2).unwrap().header, header_2);
+        assert_eq!(client.root_to_block().get(&header_1.state_index_root), Some(&index_1));
+        assert_eq!(client.get_header(&index_2).unwrap().index_root, index_root_2);
+        assert_eq!(client.index_root_to_block().get(&index_root_1), Some(&index_1));
+
+        // a header whose hashes don't match its contents is rejected
+        let mut bad_header_info = make_header_info(&header_2, &burn_2, &index_root_2);
+        bad_header_info.block_hash = header_1.block_hash().to_hex();
+        match client.add_header(&bad_header_info) {
+            Err(Error::MalformedHeader(_)) => {},
+            res => panic!("Accepted a malformed header: {:?}", &res)
+        }
+
+        // so is an accepted header with a different index root
+        match client.add_header(&make_header_info(&header_2, &burn_2, &TrieHash([0x55; 32]))) {
+            Err(Error::MalformedHeader(_)) => {},
+            res => panic!("Accepted a conflicting index root: {:?}", &res)
+        }
+
+        // proofs from the headers MARF are checked against the tip's index root, including
+        // values written in earlier blocks
+        let (_, height_proof) = headers_marf.get_with_proof(&index_2, &height_key).unwrap().unwrap();
+        let (_, block_1_proof) = headers_marf.get_with_proof(&index_2, &block_1_key).unwrap().unwrap();
+        let height_proof_hex = format!("0x{}", height_proof.to_hex());
+        client.verify_headers_marf_proof(&index_2, &height_key, "02", &height_proof_hex).unwrap();
+        client.verify_headers_marf_proof(&index_2, &block_1_key, &index_1.to_hex(), &format!("0x{}", block_1_proof.to_hex())).unwrap();
+        assert_eq!(client.verify_headers_marf_proof(&index_2, &height_key, "01", &height_proof_hex),
+                   Err(Error::InvalidProof(height_key.clone())));
+        assert_eq!(client.verify_marf_proof(&index_2, &height_key, "02", &height_proof_hex),
+                   Err(Error::InvalidProof(height_key.clone())));
+        assert_eq!(client.verify_headers_marf_proof(&index_2, &height_key, "02", ""), Err(Error::MissingProof));
+
+        // the proofs a node would send as of block 2
+        let (_, balance_proof) = marf.get_with_proof(&index_2, &balance_key).unwrap().unwrap();
+        let (_, nonce_proof) = marf.get_with_proof(&index_2, &nonce_key).unwrap().unwrap();
+        let (_, map_entry_proof) = marf.get_with_proof(&index_2, &map_entry_key).unwrap().unwrap();
+        let (_, contract_proof) = marf.get_with_proof(&index_2, &contract_key).unwrap().unwrap();
+
+        let account = AccountEntryResponse {
+            balance: format!("0x{}", to_hex(&(900 as u128).to_be_bytes())),
+            nonce: 1,
+            balance_proof: Some(format!("0x{}", balance_proof.to_hex())),
+            nonce_proof: Some(format!("0x{}", nonce_proof.to_hex()))
+        };
+        client.verify_account(&index_2, &principal, &account).unwrap();
+
+        let mut bad_account = account.clone();
+        bad_account.balance = format!("0x{}", to_hex(&(1000 as u128).to_be_bytes()));
+        assert_eq!(client.verify_account(&index_2, &principal, &bad_account), Err(Error::InvalidProof(balance_key.clone())));
+
+        let mut no_proof_account = account.clone();
+        no_proof_account.nonce_proof = None;
+        assert_eq!(client.verify_account(&index_2, &principal, &no_proof_account), Err(Error::MissingProof));
+
+        // proofs are checked against the tip's own state root
+        assert_eq!(client.verify_account(&BlockHeaderHash([0x33; 32]), &principal, &account), Err(Error::NoSuchTip(BlockHeaderHash([0x33; 32]))));
+
+        let entry = MapEntryResponse {
+            data: format!("0x{}", map_value.serialize()),
+            marf_proof: Some(format!("0x{}", map_entry_proof.to_hex()))
+        };
+        client.verify_map_entry(&index_2, &contract_addr, &contract_name, &map_name, &map_key, &entry).unwrap();
+
+        let mut bad_entry = entry.clone();
+        bad_entry.data = format!("0x{}", Value::some(Value::UInt(3)).unwrap().serialize());
+        assert_eq!(client.verify_map_entry(&index_2, &contract_addr, &contract_name, &map_name, &map_key, &bad_entry),
+                   Err(Error::InvalidProof(map_entry_key.clone())));
+
+        let contract_src = ContractSrcResponse {
+            source: source.clone(),
+            publish_height: 1,
+            marf_proof: Some(format!("0x{}", contract_proof.to_hex()))
+        };
+        client.verify_contract_source(&index_2, &contract_addr, &contract_name, &contract_src).unwrap();
+
+        let mut bad_contract_src = contract_src.clone();
+        bad_contract_src.source = "(define-map unit-map ((key uint)) ((value int)))".to_string();
+        assert_eq!(client.verify_contract_source(&index_2, &contract_addr, &contract_name, &bad_contract_src),
+                   Err(Error::InvalidProof(contract_key.clone())));
+
+        // a batch proof covers the account and the map entry at once
+        let (_, batch_proof) = marf.get_many_with_proof(&index_2, &[balance_key.clone(), nonce_key.clone(), map_entry_key.clone()]).unwrap();
+        let requested_map_entries = vec![(contract_addr.clone(), contract_name.clone(), map_name.clone(), map_key.clone())];
+        let batch = BatchProofResponse {
+            accounts: vec![AccountEntryResponse { balance_proof: None, nonce_proof: None, ..account.clone() }],
+            map_entries: vec![MapEntryResponse { marf_proof: None, ..entry.clone() }],
+            proven_keys: vec![balance_key.clone(), nonce_key.clone(), map_entry_key.clone()],
+            proof: format!("0x{}", batch_proof.to_hex())
+        };
+        client.verify_batch_proof(&index_2, &[principal.clone()], &requested_map_entries, &batch).unwrap();
+
+        let mut bad_batch = batch.clone();
+        bad_batch.map_entries[0].data = bad_entry.data.clone();
+        assert_eq!(client.verify_batch_proof(&index_2, &[principal.clone()], &requested_map_entries, &bad_batch),
+                   Err(Error::InvalidProof(map_entry_key.clone())));
+
+        let mut unproven_batch = batch.clone();
+        unproven_batch.proven_keys.pop();
+        assert_eq!(client.verify_batch_proof(&index_2, &[principal.clone()], &requested_map_entries, &unproven_batch),
+                   Err(Error::MissingProof));
+    }
+}
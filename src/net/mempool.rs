@@ -0,0 +1,462 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/// This module implements mempool synchronization between peers.  A node periodically sends one
+/// of its outbound neighbors a compact summary of its mempool (a sorted list of salted short
+/// txids), and the neighbor replies with the transactions the node does not have.  This lets
+/// nodes that are new or that were offline obtain transactions that were only pushed once.
+
+use net::*;
+use net::Error as net_error;
+use net::connection::ReplyHandleP2P;
+use net::p2p::PeerNetwork;
+
+use core::mempool::MemPoolDB;
+
+use burnchains::Txid;
+
+use chainstate::stacks::StacksTransaction;
+
+use util;
+use util::hash::Sha512Trunc256Sum;
+use util::get_epoch_time_secs;
+use util::log;
+
+use rand::Rng;
+
+// how often to ask a neighbor for the transactions we're missing
+#[cfg(not(test))] pub const MEMPOOL_SYNC_INTERVAL : u64 = 300;
+#[cfg(test)] pub const MEMPOOL_SYNC_INTERVAL : u64 = 5;
+
+// default maximum number of bytes of transactions to send in a single MemPoolTxs reply
+pub const MEMPOOL_SYNC_MAX_BYTES : u64 = 1024 * 1024;
+
+// maximum number of transactions to load from the mempool while answering a single MemPoolSync
+// request, whether or not they make it into the reply
+#[cfg(not(test))] pub const MEMPOOL_SYNC_MAX_TX_READS : u64 = MEMPOOL_SYNC_TXS_MAX as u64;
+#[cfg(test)] pub const MEMPOOL_SYNC_MAX_TX_READS : u64 = 16;
+
+impl MemPoolSyncData {
+    /// Calculate the short txid for a transaction, given the requester's seed.
+    /// This is the first 8 bytes of SHA512/256(seed || txid), as a big-endian integer.
+    pub fn short_txid(seed: u64, txid: &Txid) -> u64 {
+        let mut buf = seed.to_be_bytes().to_vec();
+        buf.extend_from_slice(txid.as_bytes());
+        let h = Sha512Trunc256Sum::from_data(&buf[..]);
+
+        let mut short_bytes = [0u8; 8];
+        short_bytes.copy_from_slice(&h.as_bytes()[0..8]);
+        u64::from_be_bytes(short_bytes)
+    }
+
+    /// Build a mempool summary from a list of txids.
+    /// Only the first MEMPOOL_SYNC_TXIDS_MAX distinct short txids are kept.
+    pub fn from_txids(seed: u64, txids: &[Txid]) -> MemPoolSyncData {
+        let mut short_txids : Vec<u64> = txids.iter().map(|txid| MemPoolSyncData::short_txid(seed, txid)).collect();
+        short_txids.sort();
+        short_txids.dedup();
+        short_txids.truncate(MEMPOOL_SYNC_TXIDS_MAX as usize);
+
+        MemPoolSyncData {
+            seed,
+            short_txids
+        }
+    }
+
+    /// Summarize our mempool's most recently-accepted transactions.
+    pub fn from_mempool(mempool: &MemPoolDB, seed: u64) -> Result<MemPoolSyncData, net_error> {
+        let txids = MemPoolDB::get_recent_txids(mempool.conn(), MEMPOOL_SYNC_TXIDS_MAX as u64)
+            .map_err(net_error::DBError)?;
+        Ok(MemPoolSyncData::from_txids(seed, &txids))
+    }
+
+    /// Does the requester (probably) have this transaction?
+    /// A short txid collision can make this return true for a transaction the requester lacks;
+    /// the requester will pick a different seed next time.
+    pub fn has_txid(&self, txid: &Txid) -> bool {
+        self.short_txids.binary_search(&MemPoolSyncData::short_txid(self.seed, txid)).is_ok()
+    }
+}
+
+impl MemPoolTxsData {
+    /// Find the transactions in our mempool that the requester lacks, newest first.
+    /// Stops once the next transaction would exceed `max_bytes` of encoded transactions, once
+    /// MEMPOOL_SYNC_TXS_MAX transactions have been found, or once MEMPOOL_SYNC_MAX_TX_READS
+    /// transactions have been loaded -- a requester with an empty (or bogus) summary can't make
+    /// us read our whole mempool.
+    /// Returns the reply, as well as the number of bytes of transactions in it.
+    pub fn from_mempool(mempool: &MemPoolDB, sync_data: &MemPoolSyncData, max_bytes: u64) -> Result<(MemPoolTxsData, u64), net_error> {
+        // the reply must fit into a single message
+        let max_bytes = if max_bytes > (MAX_PAYLOAD_LEN as u64) - 5 { (MAX_PAYLOAD_LEN as u64) - 5 } else { max_bytes };
+
+        let txids = MemPoolDB::get_recent_txids(mempool.conn(), MEMPOOL_SYNC_TXIDS_MAX as u64)
+            .map_err(net_error::DBError)?;
+
+        let mut txs = vec![];
+        let mut num_bytes = 0;
+        let mut num_reads = 0;
+        for txid in txids.iter() {
+            if txs.len() >= MEMPOOL_SYNC_TXS_MAX as usize || num_reads >= MEMPOOL_SYNC_MAX_TX_READS {
+                break;
+            }
+            if sync_data.has_txid(txid) {
+                continue;
+            }
+
+            num_reads += 1;
+            let tx_info = match MemPoolDB::get_tx(mempool.conn(), txid).map_err(net_error::DBError)? {
+                Some(tx_info) => tx_info,
+                None => {
+                    // garbage-collected in the meantime
+                    continue;
+                }
+            };
+
+            if num_bytes + tx_info.metadata.len > max_bytes {
+                break;
+            }
+
+            num_bytes += tx_info.metadata.len;
+            txs.push(tx_info.tx);
+        }
+
+        Ok((MemPoolTxsData { txs }, num_bytes))
+    }
+}
+
+/// State of our ongoing mempool synchronization with our neighbors
+pub struct MemPoolSyncState {
+    pub last_sync_time: u64,
+    pub request: Option<(NeighborKey, ReplyHandleP2P)>,     // in-flight MemPoolSync request, and who it's to
+    pub num_syncs: u64,                                     // number of completed syncs
+    pub num_txs_received: u64,                              // total number of transactions received from syncs
+}
+
+impl MemPoolSyncState {
+    pub fn new() -> MemPoolSyncState {
+        MemPoolSyncState {
+            last_sync_time: 0,
+            request: None,
+            num_syncs: 0,
+            num_txs_received: 0,
+        }
+    }
+}
+
+impl PeerNetwork {
+    /// Pick a random outbound, authenticated neighbor that supports mempool sync to sync our
+    /// mempool against
+    fn choose_mempool_sync_peer(&self) -> Option<NeighborKey> {
+        let mut candidates = vec![];
+        for (nk, event_id) in self.events.iter() {
+            match self.peers.get(event_id) {
+                Some(convo) => {
+                    if convo.is_outbound() && convo.is_authenticated() && convo.can_sync_mempool(&self.local_peer) {
+                        candidates.push(nk.clone());
+                    }
+                },
+                None => {}
+            }
+        }
+
+        if candidates.len() == 0 {
+            return None;
+        }
+
+        candidates.sort();
+        let idx = util::with_rng(|rng| rng.gen::<usize>()) % candidates.len();
+        Some(candidates.swap_remove(idx))
+    }
+
+    /// Send a MemPoolSync request to one of our neighbors
+    fn mempool_sync_begin(&mut self, mempool: &MemPoolDB) -> Result<(), net_error> {
+        let nk = match self.choose_mempool_sync_peer() {
+            Some(nk) => nk,
+            None => {
+                debug!("{:?}: no neighbors to synchronize mempools with", &self.local_peer);
+                return Ok(());
+            }
+        };
+
+        let sync_data = MemPoolSyncData::from_mempool(mempool, util::with_rng(|rng| rng.gen::<u64>()))?;
+        debug!("{:?}: ask {:?} for mempool transactions, given {} short txids", &self.local_peer, &nk, sync_data.short_txids.len());
+
+        let message = self.sign_for_peer(&nk, StacksMessageType::MemPoolSync(sync_data))?;
+        let request = self.send_message(&nk, message, self.connection_opts.timeout)?;
+        self.mempool_sync.request = Some((nk, request));
+        Ok(())
+    }
+
+    /// Try to get back the reply to our in-flight MemPoolSync request.
+    /// Returns the transactions we received, if the reply arrived.
+    fn mempool_sync_try_finish(&mut self) -> Vec<StacksTransaction> {
+        let (nk, mut request) = match self.mempool_sync.request.take() {
+            Some(x) => x,
+            None => {
+                return vec![];
+            }
+        };
+
+        if let Err(_e) = self.saturate_p2p_socket(request.get_event_id(), &mut request) {
+            debug!("{:?}: failed to send MemPoolSync to {:?}: {:?}", &self.local_peer, &nk, &_e);
+            return vec![];
+        }
+
+        match request.try_send_recv() {
+            Ok(message) => {
+                match message.payload {
+                    StacksMessageType::MemPoolTxs(txs_data) => {
+                        debug!("{:?}: got {} mempool transactions from {:?}", &self.local_peer, txs_data.txs.len(), &nk);
+                        self.mempool_sync.num_syncs += 1;
+                        self.mempool_sync.num_txs_received += txs_data.txs.len() as u64;
+                        txs_data.txs
+                    },
+                    StacksMessageType::Nack(nack_data) => {
+                        debug!("{:?}: remote neighbor {:?} nack'ed our MemPoolSync: NACK code {}", &self.local_peer, &nk, nack_data.error_code);
+                        vec![]
+                    },
+                    _ => {
+                        debug!("{:?}: remote neighbor {:?} sent an unexpected reply of '{}'", &self.local_peer, &nk, message.get_message_name());
+                        vec![]
+                    }
+                }
+            },
+            Err(req_res) => {
+                match req_res {
+                    Ok(same_req) => {
+                        // try again
+                        self.mempool_sync.request = Some((nk, same_req));
+                    },
+                    Err(_e) => {
+                        debug!("{:?}: failed to get mempool transactions from {:?}: {:?}", &self.local_peer, &nk, &_e);
+                    }
+                }
+                vec![]
+            }
+        }
+    }
+
+    /// Periodically ask a neighbor for the mempool transactions we're missing.
+    /// Returns the transactions we got back, if any.
+    pub fn do_mempool_sync(&mut self, mempool: &MemPoolDB) -> Vec<StacksTransaction> {
+        if cfg!(test) && self.connection_opts.disable_mempool_sync {
+            test_debug!("{:?}: mempool sync is disabled for testing", &self.local_peer);
+            return vec![];
+        }
+
+        if self.mempool_sync.request.is_some() {
+            return self.mempool_sync_try_finish();
+        }
+
+        let now = get_epoch_time_secs();
+        if self.mempool_sync.last_sync_time + self.connection_opts.mempool_sync_interval > now {
+            return vec![];
+        }
+
+        self.mempool_sync.last_sync_time = now;
+        if let Err(e) = self.mempool_sync_begin(mempool) {
+            info!("{:?}: failed to begin mempool sync: {:?}", &self.local_peer, &e);
+        }
+        vec![]
+    }
+
+    /// Answer a neighbor's MemPoolSync request with the transactions it lacks, and charge the
+    /// bytes sent against its mempool-sync bandwidth.
+    pub fn handle_unsolicited_MemPoolSync(&mut self, mempool: &MemPoolDB, event_id: usize, preamble: &Preamble, sync_data: &MemPoolSyncData) -> Result<(), net_error> {
+        let (txs_data, num_bytes) = MemPoolTxsData::from_mempool(mempool, sync_data, self.connection_opts.mempool_sync_max_bytes)?;
+        debug!("{:?}: send {} mempool transactions ({} bytes) to event {}", &self.local_peer, txs_data.txs.len(), num_bytes, event_id);
+
+        let (nk, reply) = match self.peers.get_mut(&event_id) {
+            Some(convo) => {
+                convo.stats.add_mempool_sync(num_bytes);
+                let reply = convo.sign_reply(&self.chain_view, &self.local_peer.private_key, StacksMessageType::MemPoolTxs(txs_data), preamble.seq)?;
+                (convo.to_neighbor_key(), reply)
+            },
+            None => {
+                return Err(net_error::PeerNotConnected);
+            }
+        };
+
+        self.relay_signed_message(&nk, reply)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use net::test::*;
+
+    use chainstate::stacks::*;
+    use chainstate::stacks::db::test::{instantiate_chainstate, chainstate_path};
+
+    use core::FIRST_BURNCHAIN_BLOCK_HASH;
+    use core::FIRST_STACKS_BLOCK_HASH;
+
+    use util::hash::Hash160;
+
+    use std::collections::HashSet;
+
+    /// Make `num_txs` distinct (unsigned) token transfers, all of the same length
+    pub fn make_mempool_txs(num_txs: u64) -> Vec<StacksTransaction> {
+        let privk = StacksPrivateKey::from_hex("6d430bb91222408e7706c9001cfaeb91b08c2be6d5ac95779ab52c6b431950e001").unwrap();
+        let recipient = StacksAddress { version: C32_ADDRESS_VERSION_TESTNET_SINGLESIG, bytes: Hash160([0x11; 20]) };
+        (0..num_txs).map(|nonce| {
+            let mut tx = StacksTransaction::new(TransactionVersion::Testnet,
+                                                TransactionAuth::from_p2pkh(&privk).unwrap(),
+                                                TransactionPayload::TokenTransfer(recipient.clone().into(), 123, TokenTransferMemo([0u8; 34])));
+            tx.chain_id = 0x80000000;
+            tx.set_origin_nonce(nonce);
+            tx.set_fee_rate(1);
+            tx
+        }).collect()
+    }
+
+    pub fn submit_mempool_txs(mempool: &mut MemPoolDB, txs: &Vec<StacksTransaction>) -> () {
+        for tx in txs.iter() {
+            let mut tx_bytes = vec![];
+            tx.consensus_serialize(&mut tx_bytes).unwrap();
+            mempool.submit_raw(&FIRST_BURNCHAIN_BLOCK_HASH, &FIRST_STACKS_BLOCK_HASH, tx_bytes).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_mempool_sync_short_txids() {
+        let txids : Vec<Txid> = (0..100u8).map(|i| Txid([i; 32])).collect();
+
+        // short txids depend on the seed
+        assert_eq!(MemPoolSyncData::short_txid(1, &txids[0]), MemPoolSyncData::short_txid(1, &txids[0]));
+        assert!(MemPoolSyncData::short_txid(1, &txids[0]) != MemPoolSyncData::short_txid(2, &txids[0]));
+        assert!(MemPoolSyncData::short_txid(1, &txids[0]) != MemPoolSyncData::short_txid(1, &txids[1]));
+
+        // summary is sorted and free of duplicates
+        let mut dup_txids = txids.clone();
+        dup_txids.append(&mut txids.clone());
+        let sync_data = MemPoolSyncData::from_txids(1, &dup_txids);
+        assert_eq!(sync_data.short_txids.len(), txids.len());
+        for i in 1..sync_data.short_txids.len() {
+            assert!(sync_data.short_txids[i - 1] < sync_data.short_txids[i]);
+        }
+
+        // summarized txids are found; others are not
+        for txid in txids.iter() {
+            assert!(sync_data.has_txid(txid));
+        }
+        for i in 100..200u8 {
+            assert!(!sync_data.has_txid(&Txid([i; 32])));
+        }
+
+        // summary is capped
+        let many_txids : Vec<Txid> = (0..(MEMPOOL_SYNC_TXIDS_MAX + 1)).map(|i| {
+            let mut bytes = [0u8; 32];
+            bytes[0..4].copy_from_slice(&i.to_be_bytes());
+            Txid(bytes)
+        }).collect();
+        let sync_data = MemPoolSyncData::from_txids(1, &many_txids);
+        assert_eq!(sync_data.short_txids.len(), MEMPOOL_SYNC_TXIDS_MAX as usize);
+    }
+
+    #[test]
+    fn test_mempool_sync_txs_from_mempool() {
+        let _chainstate = instantiate_chainstate(false, 0x80000000, "test_mempool_sync_txs_from_mempool");
+        let mut mempool = MemPoolDB::open(false, 0x80000000, &chainstate_path("test_mempool_sync_txs_from_mempool")).unwrap();
+
+        let txs = make_mempool_txs(MEMPOOL_SYNC_MAX_TX_READS + 4);
+        submit_mempool_txs(&mut mempool, &txs);
+
+        let txids : Vec<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let tx_len = {
+            let mut tx_bytes = vec![];
+            txs[0].consensus_serialize(&mut tx_bytes).unwrap();
+            tx_bytes.len() as u64
+        };
+
+        // a requester with an empty mempool gets no more than MEMPOOL_SYNC_MAX_TX_READS
+        // transactions, no matter how much room is left in the reply
+        let empty = MemPoolSyncData::from_txids(1, &[]);
+        let (txs_data, num_bytes) = MemPoolTxsData::from_mempool(&mempool, &empty, MEMPOOL_SYNC_MAX_BYTES).unwrap();
+        assert_eq!(txs_data.txs.len() as u64, MEMPOOL_SYNC_MAX_TX_READS);
+        assert_eq!(num_bytes, tx_len * MEMPOOL_SYNC_MAX_TX_READS);
+
+        // reply stops at max_bytes
+        let (txs_data, num_bytes) = MemPoolTxsData::from_mempool(&mempool, &empty, 3 * tx_len + tx_len / 2).unwrap();
+        assert_eq!(txs_data.txs.len(), 3);
+        assert_eq!(num_bytes, 3 * tx_len);
+
+        let (txs_data, num_bytes) = MemPoolTxsData::from_mempool(&mempool, &empty, tx_len - 1).unwrap();
+        assert_eq!(txs_data.txs.len(), 0);
+        assert_eq!(num_bytes, 0);
+
+        // transactions the requester already has are neither read nor sent
+        let half = MemPoolSyncData::from_txids(2, &txids[0..txids.len() / 2]);
+        let (txs_data, _) = MemPoolTxsData::from_mempool(&mempool, &half, MEMPOOL_SYNC_MAX_BYTES).unwrap();
+        assert_eq!(txs_data.txs.len(), txids.len() - txids.len() / 2);
+        for tx in txs_data.txs.iter() {
+            assert!(!half.has_txid(&tx.txid()));
+        }
+
+        let all = MemPoolSyncData::from_txids(3, &txids);
+        let (txs_data, num_bytes) = MemPoolTxsData::from_mempool(&mempool, &all, MEMPOOL_SYNC_MAX_BYTES).unwrap();
+        assert_eq!(txs_data.txs.len(), 0);
+        assert_eq!(num_bytes, 0);
+    }
+
+    #[test]
+    fn test_mempool_sync_2_peers() {
+        let mut peer_1_config = TestPeerConfig::new("test_mempool_sync_2_peers", 32080, 42080);
+        let mut peer_2_config = TestPeerConfig::new("test_mempool_sync_2_peers", 32081, 42081);
+
+        // only peer 2 asks for transactions, and it asks as often as it can
+        peer_1_config.connection_opts.disable_mempool_sync = true;
+        peer_2_config.connection_opts.mempool_sync_interval = 0;
+
+        peer_1_config.add_neighbor(&peer_2_config.to_neighbor());
+        peer_2_config.add_neighbor(&peer_1_config.to_neighbor());
+
+        let mut peer_1 = TestPeer::new(peer_1_config);
+        let mut peer_2 = TestPeer::new(peer_2_config);
+
+        let txs = make_mempool_txs(10);
+        submit_mempool_txs(peer_1.mempool.as_mut().unwrap(), &txs);
+
+        let expected_txids : HashSet<Txid> = txs.iter().map(|tx| tx.txid()).collect();
+        let mut synced_txids = HashSet::new();
+
+        let mut round = 0;
+        while synced_txids != expected_txids {
+            let _ = peer_1.step();
+            if let Ok(result) = peer_2.step() {
+                for tx in result.synced_transactions.iter() {
+                    synced_txids.insert(tx.txid());
+                }
+            }
+
+            round += 1;
+            assert!(round < 1000, "peer 2 only synced {} of {} transactions", synced_txids.len(), expected_txids.len());
+        }
+
+        assert!(peer_2.network.mempool_sync.num_syncs > 0);
+        assert!(peer_2.network.mempool_sync.num_txs_received >= txs.len() as u64);
+
+        // peer 1 charged the bytes it sent against peer 2's mempool-sync bandwidth
+        let sent_bytes : u64 = peer_1.network.peers.values()
+            .map(|convo| convo.stats.mempool_sync_tx_counts.iter().map(|(_, bytes)| *bytes).sum::<u64>())
+            .sum();
+        assert!(sent_bytes > 0);
+    }
+}
@@ -26,13 +26,22 @@ pub mod dns;
 pub mod download;
 pub mod http;
 pub mod inv;
+pub mod lightclient;
+pub mod mempool;
+pub mod compact;
 pub mod neighbors;
 pub mod p2p;
 pub mod poll;
 pub mod prune;
+pub mod ratelimit;
+pub mod reputation;
 pub mod rpc;
 pub mod relay;
 pub mod server;
+pub mod websocket;
+
+#[cfg(test)]
+pub mod sim;
 
 use std::fmt;
 use std::hash::Hash;
@@ -57,7 +66,6 @@ use url;
 use rusqlite;
 
 use rand::RngCore;
-use rand::thread_rng;
 
 use serde_json;
 use serde::{Serialize, Deserialize};
@@ -76,6 +84,7 @@ use chainstate::burn::BlockHeaderHash;
 use chainstate::stacks::{
     StacksAddress,
     StacksBlock,
+    StacksBlockHeader,
     StacksMicroblock,
     StacksTransaction,
     StacksPublicKey,
@@ -93,6 +102,7 @@ use vm::{
     analysis::contract_interface_builder::ContractInterface,
 };
 
+use util;
 use util::hash::Hash160;
 use util::hash::DOUBLE_SHA256_ENCODED_SIZE;
 use util::hash::HASH160_ENCODED_SIZE;
@@ -116,6 +126,7 @@ use vm::clarity::Error as clarity_error;
 use chainstate::stacks::index::Error as marf_error;
 
 use self::dns::*;
+use self::reputation::{PeerAdminAction, PeerSelector};
 
 #[derive(Debug)]
 pub enum Error {
@@ -616,6 +627,58 @@ pub struct MicroblocksData {
     pub microblocks: Vec<StacksMicroblock>
 }
 
+/// Request for the transactions in a peer's mempool that the sender does not have.
+/// The sender's mempool is summarized as a sorted list of short transaction IDs, each of which is
+/// the first 8 bytes of the SHA512/256 hash of the seed concatenated with the txid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemPoolSyncData {
+    pub seed: u64,                              // salt for the short txids, chosen by the requester
+    pub short_txids: Vec<u64>,                  // sorted short txids of the requester's mempool transactions (not to exceed MEMPOOL_SYNC_TXIDS_MAX)
+}
+
+/// Mempool transactions sent in reply to a MemPoolSync
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemPoolTxsData {
+    pub txs: Vec<StacksTransaction>
+}
+
+/// A transaction sent in full as part of a compact block, since the recipient is unlikely to have
+/// it in its mempool (e.g. the coinbase).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefilledTransaction {
+    pub index: u32,                             // position of this transaction in the block
+    pub tx: StacksTransaction
+}
+
+/// Compact anchored block pushed.  Carries the block header, and identifies each transaction that
+/// was not prefilled by its short txid (see MemPoolSyncData), so the recipient can rebuild the
+/// block from its mempool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactBlockData {
+    pub burn_header_hash: BurnchainHeaderHash,
+    pub header: StacksBlockHeader,
+    pub seed: u64,                              // salt for the short txids
+    pub short_txids: Vec<u64>,                  // short txids of the transactions that were not prefilled, in block order
+    pub prefilled_txs: Vec<PrefilledTransaction>,   // transactions sent in full, in block order
+}
+
+/// Request for the transactions at the given positions in an anchored block, sent when a compact
+/// block could not be rebuilt from the mempool.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetBlockTxsData {
+    pub burn_header_hash: BurnchainHeaderHash,
+    pub block_hash: BlockHeaderHash,
+    pub indexes: Vec<u32>                       // sorted, unique positions of the transactions to send
+}
+
+/// Transactions sent in reply to a GetBlockTxs, in the order requested
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockTxsData {
+    pub burn_header_hash: BurnchainHeaderHash,
+    pub block_hash: BlockHeaderHash,
+    pub txs: Vec<StacksTransaction>
+}
+
 /// Block available hint
 #[derive(Debug, Clone, PartialEq)]
 pub struct BlocksAvailableData {
@@ -689,6 +752,9 @@ pub struct HandshakeData {
 pub enum ServiceFlags {
     RELAY = 0x01,
     RPC = 0x02,
+    ENCRYPTION = 0x04,      // can switch to an encrypted channel after the handshake
+    COMPACT_BLOCKS = 0x08,  // can send and rebuild compact blocks (CompactBlock, GetBlockTxs, BlockTxs)
+    MEMPOOL_SYNC = 0x10,    // can answer mempool sync requests (MemPoolSync, MemPoolTxs)
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -705,6 +771,8 @@ pub mod NackErrorCodes {
     pub const HandshakeRequired : u32 = 1;
     pub const NoSuchBurnchainBlock : u32 = 2;
     pub const Throttled : u32 = 3;
+    pub const NoSuchBlock : u32 = 4;
+    pub const UnsupportedService : u32 = 5;
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -743,6 +811,11 @@ pub enum StacksMessageType {
     Nack(NackData),
     Ping(PingData),
     Pong(PongData),
+    MemPoolSync(MemPoolSyncData),
+    MemPoolTxs(MemPoolTxsData),
+    CompactBlock(CompactBlockData),
+    GetBlockTxs(GetBlockTxsData),
+    BlockTxs(BlockTxsData),
 }
 
 /// Peer address variants
@@ -862,7 +935,8 @@ pub enum HttpVersion {
 pub struct HttpRequestMetadata {
     pub version: HttpVersion,
     pub peer: PeerHost,
-    pub keep_alive: bool
+    pub keep_alive: bool,
+    pub api_key: Option<String>,        // from the X-Api-Key header
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -874,6 +948,27 @@ pub struct MapEntryResponse {
     pub marf_proof: Option<String>
 }
 
+/// Item in the list given back from a call to `/v2/map_entries/{address}/{contract}/{map}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapEntriesItem {
+    /// hex-encoded, consensus-serialized key
+    pub key: String,
+    pub data: String,
+    #[serde(rename = "proof")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")] 
+    pub marf_proof: Option<String>
+}
+
+/// Struct given back from a call to `/v2/map_entries/{address}/{contract}/{map}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapEntriesResponse {
+    pub entries: Vec<MapEntriesItem>,
+    /// pass this as `after=` to get the next page.  None if there are no more entries.
+    #[serde(default)]
+    pub next_cursor: Option<String>
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ContractSrcResponse {
     pub source: String,
@@ -907,6 +1002,102 @@ pub struct AccountEntryResponse {
     pub nonce_proof: Option<String>
 }
 
+/// Struct given back from a call to `/v2/batch_proof`.  The accounts and data map entries are in
+/// the order they were requested, without proofs of their own.  Instead, `proof` is a single
+/// batched MARF proof for each of the values that exist, and `proven_keys` lists their MARF keys in
+/// the order the proof covers them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchProofResponse {
+    pub accounts: Vec<AccountEntryResponse>,
+    pub map_entries: Vec<MapEntryResponse>,
+    pub proven_keys: Vec<String>,
+    pub proof: String
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FungibleTokenBalanceResponse {
+    pub balance: String,
+    #[serde(rename = "proof")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")] 
+    pub marf_proof: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NonFungibleTokenOwnerResponse {
+    pub owner: Option<String>,
+    #[serde(rename = "proof")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")] 
+    pub marf_proof: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DataVarResponse {
+    pub data: String,
+    #[serde(rename = "proof")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")] 
+    pub marf_proof: Option<String>
+}
+
+/// Struct given back from a call to `/v2/headers` (as a list) or `/v2/blocks/height/{n}`.
+/// Describes a processed anchored block header, along with the burnchain block it was
+/// selected in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCStacksHeaderInfo {
+    /// hex-encoded, consensus-serialized StacksBlockHeader
+    pub header: String,
+    pub block_hash: String,
+    pub index_block_hash: String,
+    pub burn_header_hash: String,
+    pub burn_header_timestamp: u64,
+    pub block_height: u64,
+    /// root hash of the headers MARF as of this block
+    pub index_root: String
+}
+
+/// Item in the list given back from a call to `/v2/mempool/transactions`.
+/// Describes a pending transaction, without its body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCMempoolTxInfo {
+    pub txid: String,
+    pub fee_rate: u64,
+    pub estimated_fee: u64,
+    pub origin_address: String,
+    pub origin_nonce: u64,
+    pub sponsor_address: String,
+    pub sponsor_nonce: u64,
+    pub accept_time: u64
+}
+
+/// Struct given back from a call to `/v2/mempool/transactions`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCMempoolTxsData {
+    pub transactions: Vec<RPCMempoolTxInfo>,
+    /// `accept_time:txid` of the last transaction listed.  Pass this as `after=` to get the next
+    /// page.  None if there are no more transactions.
+    #[serde(default)]
+    pub next_cursor: Option<String>
+}
+
+/// Struct given back from a call to `/v2/mempool/accounts/{address}`.
+/// Describes the range of nonces an account has pending in the mempool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCMempoolAccountData {
+    pub address: String,
+    /// account nonce as of the canonical chain tip
+    pub nonce: u64,
+    /// number of distinct nonces at or above `nonce` used by pending transactions
+    pub pending_count: u64,
+    #[serde(default)]
+    pub min_pending_nonce: Option<u64>,
+    #[serde(default)]
+    pub max_pending_nonce: Option<u64>,
+    /// lowest nonce at or above `nonce` that no pending transaction uses
+    pub next_nonce: u64
+}
+
 /// Request ID to use or expect from non-Stacks HTTP clients.
 /// In particular, if a HTTP response does not contain the x-request-id header, then it's assumed
 /// to be this value.  This is needed to support fetching immutables like block and microblock data
@@ -919,6 +1110,7 @@ impl HttpRequestMetadata {
             version: HttpVersion::Http11,
             peer: PeerHost::from_host_port(host, port),
             keep_alive: true,
+            api_key: None,
         }
     }
 
@@ -927,6 +1119,7 @@ impl HttpRequestMetadata {
             version: HttpVersion::Http11,
             peer: peer_host,
             keep_alive: true,
+            api_key: None,
         }
     }
 
@@ -935,6 +1128,7 @@ impl HttpRequestMetadata {
             version: preamble.version,
             peer: preamble.host.clone(),
             keep_alive: preamble.keep_alive,
+            api_key: preamble.headers.get("x-api-key").cloned(),
         }
     }
 }
@@ -945,6 +1139,25 @@ pub struct CallReadOnlyRequestBody {
     pub arguments: Vec<String>,
 }
 
+/// A data map entry to prove in a call to `/v2/batch_proof`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchProofMapEntryRequest {
+    pub contract_address: String,
+    pub contract_name: String,
+    pub map_name: String,
+    /// hex-encoded, consensus-serialized key
+    pub key: String
+}
+
+/// Body of a call to `/v2/batch_proof`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchProofRequestBody {
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    #[serde(default)]
+    pub map_entries: Vec<BatchProofMapEntryRequest>
+}
+
 /// Items in the NeighborsInfo -- combines NeighborKey and NeighborAddress
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RPCNeighbor {
@@ -970,6 +1183,37 @@ impl RPCNeighbor {
     }
 }
 
+/// Items given back from a call to `/v2/neighbors/reputation`, or to one of the peer admin
+/// endpoints (`/v2/neighbors/{ban,unban,allow,disallow}`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCPeerReputation {
+    pub network_id: u32,
+    pub peer_version: u32,
+    #[serde(rename = "ip")]
+    pub addrbytes: PeerAddress,
+    pub port: u16,
+    pub public_key: String,
+    /// misbehavior score -- the peer gets banned once this reaches the ban threshold
+    pub score: u64,
+    /// allow-list deadline (negative == "forever")
+    pub whitelisted: i64,
+    /// ban deadline (negative == "forever")
+    pub blacklisted: i64
+}
+
+/// Request body for the peer admin endpoints.  Exactly one of `addr` (as IP:port) or `public_key`
+/// must be given.  `duration` (in seconds) only applies to bans and allow-listings; if omitted,
+/// they last forever.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCPeerAdminRequest {
+    #[serde(default)]
+    pub addr: Option<String>,
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub duration: Option<u64>
+}
+
 /// Struct given back from a call to `/v2/neighbors`.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RPCNeighborsInfo {
@@ -978,6 +1222,18 @@ pub struct RPCNeighborsInfo {
     pub outbound: Vec<RPCNeighbor>,
 }
 
+/// The block a state-reading HTTP request reads from, given by its `tip=` or `height=` query
+/// argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum TipRequest {
+    /// the canonical Stacks chain tip
+    UseLatestAnchoredTip,
+    /// the block with this index block hash
+    SpecificTip(BlockHeaderHash),
+    /// the block at this height on the canonical Stacks fork
+    AtHeight(u64),
+}
+
 /// All HTTP request paths we support, and the arguments they carry in their paths
 #[derive(Debug, Clone, PartialEq)]
 pub enum HttpRequestType {
@@ -987,14 +1243,26 @@ pub enum HttpRequestType {
     GetMicroblocksIndexed(HttpRequestMetadata, BlockHeaderHash),
     GetMicroblocksConfirmed(HttpRequestMetadata, BlockHeaderHash),
     GetMicroblocksUnconfirmed(HttpRequestMetadata, BlockHeaderHash, u16),
+    GetHeaders(HttpRequestMetadata, u64, Option<BlockHeaderHash>),
+    GetBlockByHeight(HttpRequestMetadata, u64, Option<BlockHeaderHash>),
+    GetMempoolTransactions(HttpRequestMetadata, Option<StacksAddress>, Option<(u64, Option<Txid>)>, u64),
+    GetMempoolAccount(HttpRequestMetadata, StacksAddress),
+    GetPeerReputations(HttpRequestMetadata),
+    PostPeerAdmin(HttpRequestMetadata, PeerAdminAction, PeerSelector),
     PostTransaction(HttpRequestMetadata, StacksTransaction),
-    GetAccount(HttpRequestMetadata, PrincipalData, bool),
-    GetMapEntry(HttpRequestMetadata, StacksAddress, ContractName, ClarityName, Value, bool),
+    GetAccount(HttpRequestMetadata, PrincipalData, bool, TipRequest),
+    GetMapEntry(HttpRequestMetadata, StacksAddress, ContractName, ClarityName, Value, bool, TipRequest),
+    GetMapEntries(HttpRequestMetadata, StacksAddress, ContractName, ClarityName, Option<Value>, u64, bool, TipRequest),
+    GetBatchProof(HttpRequestMetadata, Vec<PrincipalData>, Vec<(StacksAddress, ContractName, ClarityName, Value)>, TipRequest),
+    GetFungibleTokenBalance(HttpRequestMetadata, StacksAddress, ContractName, ClarityName, PrincipalData, bool, TipRequest),
+    GetNonFungibleTokenOwner(HttpRequestMetadata, StacksAddress, ContractName, ClarityName, Value, bool, TipRequest),
+    GetDataVar(HttpRequestMetadata, StacksAddress, ContractName, ClarityName, bool, TipRequest),
     CallReadOnlyFunction(HttpRequestMetadata, StacksAddress, ContractName,
-                         PrincipalData, ClarityName, Vec<Value>),
+                         PrincipalData, ClarityName, Vec<Value>, TipRequest),
     GetTransferCost(HttpRequestMetadata),
-    GetContractSrc(HttpRequestMetadata, StacksAddress, ContractName, bool),
-    GetContractABI(HttpRequestMetadata, StacksAddress, ContractName),
+    GetContractSrc(HttpRequestMetadata, StacksAddress, ContractName, bool, TipRequest),
+    GetContractABI(HttpRequestMetadata, StacksAddress, ContractName, TipRequest),
+    Subscribe(HttpRequestMetadata, String),     // websocket upgrade, with the client's Sec-WebSocket-Key
     OptionsPreflight(HttpRequestMetadata, String),
     Unmatched(HttpRequestMetadata, String),     // catch-all if we can't parse the request
 }
@@ -1010,12 +1278,13 @@ pub struct HttpResponseMetadata {
 
 impl HttpResponseMetadata {
     pub fn make_request_id() -> u32 {
-        let mut rng = thread_rng();
-        let mut request_id = HTTP_REQUEST_ID_RESERVED;
-        while request_id == HTTP_REQUEST_ID_RESERVED {
-            request_id = rng.next_u32();
-        }
-        request_id
+        util::with_rng(|rng| {
+            let mut request_id = HTTP_REQUEST_ID_RESERVED;
+            while request_id == HTTP_REQUEST_ID_RESERVED {
+                request_id = rng.next_u32();
+            }
+            request_id
+        })
     }
 
     pub fn new(client_version: HttpVersion, request_id: u32, content_length: Option<u32>, client_keep_alive: bool) -> HttpResponseMetadata {
@@ -1063,13 +1332,24 @@ pub enum HttpResponseType {
     Microblocks(HttpResponseMetadata, Vec<StacksMicroblock>),
     MicroblockStream(HttpResponseMetadata),
     TransactionID(HttpResponseMetadata, Txid),
+    Headers(HttpResponseMetadata, Vec<RPCStacksHeaderInfo>),
+    HeaderInfo(HttpResponseMetadata, RPCStacksHeaderInfo),
+    MempoolTransactions(HttpResponseMetadata, RPCMempoolTxsData),
+    MempoolAccount(HttpResponseMetadata, RPCMempoolAccountData),
+    PeerReputations(HttpResponseMetadata, Vec<RPCPeerReputation>),
     TokenTransferCost(HttpResponseMetadata, u64),
     GetMapEntry(HttpResponseMetadata, MapEntryResponse),
+    GetMapEntries(HttpResponseMetadata, MapEntriesResponse),
+    GetBatchProof(HttpResponseMetadata, BatchProofResponse),
+    GetFungibleTokenBalance(HttpResponseMetadata, FungibleTokenBalanceResponse),
+    GetNonFungibleTokenOwner(HttpResponseMetadata, NonFungibleTokenOwnerResponse),
+    GetDataVar(HttpResponseMetadata, DataVarResponse),
     CallReadOnlyFunction(HttpResponseMetadata, CallReadOnlyResponse),
     GetAccount(HttpResponseMetadata, AccountEntryResponse),
     GetContractABI(HttpResponseMetadata, ContractInterface),
     GetContractSrc(HttpResponseMetadata, ContractSrcResponse),
     OptionsPreflight(HttpResponseMetadata),
+    WebSocketUpgrade(HttpResponseMetadata, String),     // 101 Switching Protocols, with the Sec-WebSocket-Accept value
     // peer-given error responses
     BadRequest(HttpResponseMetadata, String),
     BadRequestJSON(HttpResponseMetadata, serde_json::Value),
@@ -1077,6 +1357,7 @@ pub enum HttpResponseType {
     PaymentRequired(HttpResponseMetadata, String),
     Forbidden(HttpResponseMetadata, String),
     NotFound(HttpResponseMetadata, String),
+    TooManyRequests(HttpResponseMetadata, u64, String),     // 429, with the Retry-After value in seconds
     ServerError(HttpResponseMetadata, String),
     ServiceUnavailable(HttpResponseMetadata, String),
     Error(HttpResponseMetadata, u16, String)
@@ -1106,6 +1387,11 @@ pub enum StacksMessageID {
     Nack = 12,
     Ping = 13,
     Pong = 14,
+    MemPoolSync = 15,
+    MemPoolTxs = 16,
+    CompactBlock = 17,
+    GetBlockTxs = 18,
+    BlockTxs = 19,
     Reserved = 255
 }
 
@@ -1171,6 +1457,13 @@ pub trait ProtocolFamily {
     /// Given a Write and a Message, write it out.  This method is also responsible for generating
     /// and writing out a Preamble for its Message.
     fn write_message<W: Write>(&mut self, fd: &mut W, message: &Self::Message) -> Result<(), Error>;
+
+    /// Does this message switch the connection over to a different protocol (such as an HTTP
+    /// upgrade to a websocket)?  If so, none of the bytes after it will be parsed as this
+    /// protocol's messages.
+    fn is_upgrade(&mut self, _message: &Self::Message) -> bool {
+        false
+    }
 }
 
 // these implement the ProtocolFamily trait 
@@ -1196,6 +1489,11 @@ pub const MAX_BROADCAST_INBOUND_RECEIVERS : usize = 16;
 pub const MAX_PAYLOAD_LEN : u32 = 1 + 16 * 1024 * 1024;
 pub const MAX_MESSAGE_LEN : u32 = MAX_PAYLOAD_LEN + (PREAMBLE_ENCODED_SIZE + MAX_RELAYERS_LEN * RELAY_DATA_ENCODED_SIZE);
 
+// biggest handshake message (a HandshakeAccept with the longest data URL): the preamble, the
+// relayers, the type ID, the heartbeat interval, and the handshake data
+pub const MAX_HANDSHAKE_MESSAGE_LEN : u32 = PREAMBLE_ENCODED_SIZE + (4 + MAX_RELAYERS_LEN * RELAY_DATA_ENCODED_SIZE) + 1 + 4 +
+    (PEER_ADDRESS_ENCODED_SIZE + 2 + 2 + STACKS_PUBLIC_KEY_ENCODED_SIZE + 8 + 1 + (::vm::representations::MAX_STRING_LEN as u32));
+
 // maximum value of a blocks's inv data bitlen.
 // NOTE: This needs to be a multiple of 8
 #[cfg(test)] pub const BLOCKS_INV_DATA_MAX_BITLEN : u32 = 32;
@@ -1209,6 +1507,17 @@ pub const BLOCKS_AVAILABLE_MAX_LEN : u32 = 32;
 // message.
 pub const BLOCKS_PUSHED_MAX : u32 = 32;
 
+// maximum number of short txids that can be sent in a MemPoolSync request
+pub const MEMPOOL_SYNC_TXIDS_MAX : u32 = 65536;
+
+// maximum number of transactions that can be sent in a single MemPoolTxs reply
+pub const MEMPOOL_SYNC_TXS_MAX : u32 = 4096;
+
+// maximum number of transactions that a compact block (or a request for its transactions) can
+// refer to.  Anchored blocks are at most MAX_EPOCH_SIZE bytes, and no transaction is smaller than
+// 64 bytes.
+pub const COMPACT_BLOCK_TXS_MAX : u32 = 32768;
+
 macro_rules! impl_byte_array_message_codec {
     ($thing:ident, $len:expr) => {
         impl ::net::StacksMessageCodec for $thing {
@@ -1330,6 +1639,22 @@ pub const NUM_NEIGHBORS : usize = 32;
 // maximum number of unconfirmed microblocks can get streamed to us
 pub const MAX_MICROBLOCKS_UNCONFIRMED : usize = 1024;
 
+// maximum number of anchored block headers that can be requested from /v2/headers at once
+pub const MAX_HEADERS_PER_REQUEST : u64 = 2100;
+
+// maximum number of pending transactions that can be listed from /v2/mempool/transactions at once
+pub const MAX_MEMPOOL_TXS_PER_REQUEST : u64 = 1000;
+
+// maximum number of data map entries that can be listed from /v2/map_entries at once
+pub const MAX_MAP_ENTRIES_PER_REQUEST : u64 = 100;
+
+// maximum number of indexed data map keys that a single /v2/map_entries request can examine,
+// including keys that were deleted or only written on other forks
+pub const MAX_MAP_KEYS_EXAMINED_PER_REQUEST : u32 = 1000;
+
+// maximum number of accounts and data map entries that can be proven by /v2/batch_proof at once
+pub const MAX_BATCH_PROOF_ITEMS : u64 = 100;
+
 // how long a peer will be blacklisted for if it misbehaves
 #[cfg(test)] pub const BLACKLIST_BAN_DURATION : u64 = 30;           // seconds
 #[cfg(not(test))] pub const BLACKLIST_BAN_DURATION : u64 = 86400;   // seconds (1 day)
@@ -1344,7 +1669,9 @@ pub struct NetworkResult {
     pub pushed_transactions: HashMap<NeighborKey, Vec<(Vec<RelayData>, StacksTransaction)>>,                   // all transactions pushed to us and their message relay hints
     pub pushed_blocks: HashMap<NeighborKey, Vec<BlocksData>>,                                                  // all blocks pushed to us
     pub pushed_microblocks: HashMap<NeighborKey, Vec<(Vec<RelayData>, MicroblocksData)>>,                      // all microblocks pushed to us, and the relay hints from the message
+    pub pushed_compact_blocks: HashMap<NeighborKey, Vec<(Vec<RelayData>, CompactBlockData)>>,                  // all compact blocks pushed to us, and the relay hints from the message
     pub uploaded_transactions: Vec<StacksTransaction>,                                                         // transactions sent to us by the http server
+    pub synced_transactions: Vec<StacksTransaction>,                                                           // transactions we fetched from a peer's mempool
 }
 
 impl NetworkResult {
@@ -1356,12 +1683,14 @@ impl NetworkResult {
             pushed_transactions: HashMap::new(),
             pushed_blocks: HashMap::new(),
             pushed_microblocks: HashMap::new(),
+            pushed_compact_blocks: HashMap::new(),
             uploaded_transactions: vec![],
+            synced_transactions: vec![],
         }
     }
 
     pub fn has_blocks(&self) -> bool {
-        self.blocks.len() > 0 || self.pushed_blocks.len() > 0
+        self.blocks.len() > 0 || self.pushed_blocks.len() > 0 || self.pushed_compact_blocks.len() > 0
     }
 
     pub fn has_microblocks(&self) -> bool {
@@ -1369,7 +1698,7 @@ impl NetworkResult {
     }
 
     pub fn has_transactions(&self) -> bool {
-        self.pushed_transactions.len() > 0 || self.uploaded_transactions.len() > 0
+        self.pushed_transactions.len() > 0 || self.uploaded_transactions.len() > 0 || self.synced_transactions.len() > 0
     }
 
     pub fn has_data_to_store(&self) -> bool {
@@ -1396,6 +1725,14 @@ impl NetworkResult {
                             self.pushed_microblocks.insert(neighbor_key.clone(), vec![(message.relayers, mblock_data)]);
                         }
                     },
+                    StacksMessageType::CompactBlock(compact_block_data) => {
+                        if let Some(compact_block_msgs) = self.pushed_compact_blocks.get_mut(&neighbor_key) {
+                            compact_block_msgs.push((message.relayers, compact_block_data));
+                        }
+                        else {
+                            self.pushed_compact_blocks.insert(neighbor_key.clone(), vec![(message.relayers, compact_block_data)]);
+                        }
+                    },
                     StacksMessageType::Transaction(tx_data) => {
                         if let Some(tx_msgs) = self.pushed_transactions.get_mut(&neighbor_key) {
                             tx_msgs.push((message.relayers, tx_data));
@@ -1794,6 +2131,11 @@ pub mod test {
 
     impl TestPeer {
         pub fn new(config: TestPeerConfig) -> TestPeer {
+            TestPeer::new_with_transport(config, None)
+        }
+
+        /// Make a test peer that talks over the given transport instead of real sockets
+        pub fn new_with_transport(config: TestPeerConfig, transport_opt: Option<Box<dyn NetworkTransport>>) -> TestPeer {
             let test_path = format!("/tmp/blockstack-test-peer-{}-{}", &config.test_name, config.server_port);
             match fs::metadata(&test_path) {
                 Ok(_) => {
@@ -1846,7 +2188,7 @@ pub mod test {
             {
                 let mut tx = peerdb.tx_begin().unwrap();
                 PeerDB::set_local_ipaddr(&mut tx, &PeerAddress::from_socketaddr(&local_addr), config.server_port).unwrap();
-                PeerDB::set_local_services(&mut tx, ServiceFlags::RELAY as u16).unwrap();
+                PeerDB::set_local_services(&mut tx, (ServiceFlags::RELAY as u16) | (ServiceFlags::COMPACT_BLOCKS as u16) | (ServiceFlags::MEMPOOL_SYNC as u16)).unwrap();
                 PeerDB::set_local_private_key(&mut tx, &config.private_key, config.private_key_expire).unwrap();
                 
                 tx.commit().unwrap();
@@ -1859,7 +2201,10 @@ pub mod test {
             };
             let mut peer_network = PeerNetwork::new(peerdb, local_peer, config.peer_version, config.burnchain.clone(), burnchain_view, config.connection_opts.clone());
 
-            peer_network.bind(&local_addr, &http_local_addr).unwrap();
+            match transport_opt {
+                Some(transport) => peer_network.bind_transport(transport, &local_addr, &http_local_addr).unwrap(),
+                None => peer_network.bind(&local_addr, &http_local_addr).unwrap()
+            };
             let relayer = Relayer::from_p2p(&mut peer_network);
             let mempool = MemPoolDB::open(false, config.network_id, &chainstate_path).unwrap();
             
@@ -2187,6 +2532,57 @@ pub mod test {
             (vec![BlockstackOperationType::LeaderKeyRegister(leader_key_op), BlockstackOperationType::LeaderBlockCommit(block_commit_op)], stacks_block, microblocks)
         }
 
+        // have this peer produce an anchored block with a coinbase, followed by one transaction
+        // from its internal miner for each of the given payloads.
+        pub fn make_tenure_with_payloads(&mut self, payloads: Vec<TransactionPayload>) -> (Vec<BlockstackOperationType>, StacksBlock, Vec<StacksMicroblock>) {
+            let mut burndb = self.burndb.take().unwrap();
+            let mut burn_block = {
+                let sn = BurnDB::get_canonical_burn_chain_tip(burndb.conn()).unwrap();
+                TestBurnchainBlock::new(&sn, 0)
+            };
+
+            let mut stacks_node = self.stacks_node.take().unwrap();
+
+            let parent_block_opt = stacks_node.get_last_anchored_block(&self.miner);
+            let last_key = stacks_node.get_last_key(&self.miner);
+
+            let network_id = self.config.network_id;
+            let chainstate_path = self.chainstate_path.clone();
+            let burn_block_height = burn_block.block_height;
+
+            let (stacks_block, microblocks, block_commit_op) = stacks_node.mine_stacks_block(&mut burndb, &mut self.miner, &mut burn_block, &last_key, parent_block_opt.as_ref(), 1000, |mut builder, ref mut miner| {
+                let mut miner_chainstate = StacksChainState::open(false, network_id, &chainstate_path).unwrap();
+                let mut epoch = builder.epoch_begin(&mut miner_chainstate).unwrap();
+
+                let miner_account = StacksChainState::get_account(&mut epoch, &miner.origin_address().unwrap().to_account_principal());
+                miner.set_nonce(miner_account.nonce);
+
+                let tx_coinbase_signed = make_coinbase(miner, burn_block_height as usize);
+                builder.try_mine_tx(&mut epoch, &tx_coinbase_signed).unwrap();
+
+                for payload in payloads.into_iter() {
+                    let mut tx = StacksTransaction::new(TransactionVersion::Testnet, miner.as_transaction_auth().unwrap(), payload);
+                    tx.chain_id = network_id;
+                    tx.auth.set_origin_nonce(miner.get_nonce());
+                    tx.set_fee_rate(0);
+
+                    let mut tx_signer = StacksTransactionSigner::new(&tx);
+                    miner.sign_as_origin(&mut tx_signer);
+                    builder.try_mine_tx(&mut epoch, &tx_signer.get_tx().unwrap()).unwrap();
+                }
+
+                let stacks_block = builder.mine_anchored_block(&mut epoch);
+                builder.epoch_finish(epoch);
+                (stacks_block, vec![])
+            });
+
+            let leader_key_op = stacks_node.add_key_register(&mut burn_block, &mut self.miner);
+
+            self.stacks_node = Some(stacks_node);
+            self.burndb = Some(burndb);
+            (vec![BlockstackOperationType::LeaderKeyRegister(leader_key_op), BlockstackOperationType::LeaderBlockCommit(block_commit_op)], stacks_block, microblocks)
+        }
+
         pub fn to_neighbor(&self) -> Neighbor {
             self.config.to_neighbor()
         }
@@ -37,6 +37,7 @@ use net::db::LocalPeer;
 
 use net::p2p::*;
 
+use util;
 use util::db::Error as db_error;
 use util::db::DBConn;
 use util::db::DBTx;
@@ -59,7 +60,6 @@ use util::log;
 use util::get_epoch_time_secs;
 use util::hash::*;
 use rand::prelude::*;
-use rand::thread_rng;
 
 #[cfg(test)] pub const NEIGHBOR_MINIMUM_CONTACT_INTERVAL : u64 = 0;
 #[cfg(not(test))] pub const NEIGHBOR_MINIMUM_CONTACT_INTERVAL : u64 = 600;      // don't reach out to a frontier neighbor more than once every 10 minutes
@@ -156,10 +156,9 @@ impl Neighbor {
     /// So, we can estimate the undirected degree as being a random value between the lower and
     /// upper bound.
     pub fn degree(&self) -> u64 {
-        let mut rng = thread_rng();
         let min = cmp::min(self.in_degree, self.out_degree);
         let max = cmp::max(self.in_degree, self.out_degree);
-        let res = rng.gen_range(min, max+1) as u64;
+        let res = util::with_rng(|rng| rng.gen_range(min, max+1)) as u64;
         if res == 0 {
             1
         }
@@ -699,8 +698,7 @@ impl NeighborWalk {
             return Ok(None);
         }
 
-        let mut rng = thread_rng();
-        slots.shuffle(&mut rng);
+        util::with_rng(|rng| slots.shuffle(rng));
         
         for slot in slots {
             let peer_opt = PeerDB::get_peer_at(conn, nk.network_id, slot)
@@ -995,12 +993,15 @@ impl NeighborWalk {
 
     /// Pick a random neighbor from the frontier, excluding an optional given neighbor 
     fn pick_random_neighbor(frontier: &HashMap<NeighborKey, Neighbor>, exclude: Option<&Neighbor>) -> Option<Neighbor> {
-        let mut rnd = thread_rng();
-
-        let sample = rnd.gen_range(0, frontier.len());
+        let sample = util::with_rng(|rng| rng.gen_range(0, frontier.len()));
         let mut count = 0;
 
-        for (nk, n) in frontier.iter() {
+        // visit in key order, so the choice depends only on the sample
+        let mut nks : Vec<&NeighborKey> = frontier.keys().collect();
+        nks.sort();
+
+        for nk in nks.into_iter() {
+            let n = frontier.get(nk).expect("BUG: frontier key with no neighbor");
             count += match exclude {
                 None => 1,
                 Some(ref e) => if (*e).addr == *nk { 0 } else { 1 }
@@ -1042,8 +1043,6 @@ impl NeighborWalk {
     pub fn step(&mut self, peerdb_conn: &DBConn) -> () {
         test_debug!("{:?}: execute neighbor step from {:?}", &self.local_peer, &self.cur_neighbor.addr);
 
-        let mut rnd = thread_rng();
-
         // step to a node in cur_neighbor's frontier, per MHRWDA
         let next_neighbor_opt = 
             if self.frontier.len() == 0 {
@@ -1059,7 +1058,7 @@ impl NeighborWalk {
             }
             else {
                 let next_neighbor = NeighborWalk::pick_random_neighbor(&self.frontier, None).expect("BUG: empty frontier size");     // won't panic since self.frontier.len() > 0
-                let walk_prob : f64 = rnd.gen();
+                let walk_prob : f64 = util::with_rng(|rng| rng.gen());
                 if walk_prob < fmin!(1.0, NeighborWalk::degree_ratio(peerdb_conn, &self.cur_neighbor, &next_neighbor)) {
                     match self.prev_neighbor {
                         Some(ref prev_neighbor) => {
@@ -1075,7 +1074,7 @@ impl NeighborWalk {
                                     // acceptance by probabilistically deciding to step to an alternative
                                     // instead of backtracking.
                                     let alt_next_neighbor = NeighborWalk::pick_random_neighbor(&self.frontier, Some(&prev_neighbor)).expect("BUG: empty frontier size");
-                                    let alt_prob : f64 = rnd.gen();
+                                    let alt_prob : f64 = util::with_rng(|rng| rng.gen());
 
                                     let cur_to_alt = NeighborWalk::degree_ratio(peerdb_conn, &self.cur_neighbor, &alt_next_neighbor);
                                     let prev_to_cur = NeighborWalk::degree_ratio(peerdb_conn, &prev_neighbor, &self.cur_neighbor);
@@ -1440,12 +1439,14 @@ impl PeerNetwork {
         }
 
         // pick a random inbound conversation
-        let mut idx = thread_rng().gen::<usize>() % self.peers.len();
+        let mut idx = util::with_rng(|rng| rng.gen::<usize>()) % self.peers.len();
+        let mut event_ids : Vec<usize> = self.peers.keys().map(|eid| *eid).collect();
+        event_ids.sort();
         
         test_debug!("{:?}: try inbound neighbors -- sample out of {}. idx = {}", &self.local_peer, self.peers.len(), idx);
         
         for _ in 0..self.walk_pingbacks.len()+1 {
-            let event_id = match self.peers.keys().skip(idx).next() {
+            let event_id = match event_ids.iter().skip(idx).next() {
                 Some(eid) => *eid,
                 None => {
                     idx = 0;
@@ -1484,11 +1485,13 @@ impl PeerNetwork {
             return Err(net_error::NoSuchNeighbor);
         }
 
-        let idx = thread_rng().gen::<usize>() % self.walk_pingbacks.len();
+        let idx = util::with_rng(|rng| rng.gen::<usize>()) % self.walk_pingbacks.len();
+        let mut addrs : Vec<&NeighborAddress> = self.walk_pingbacks.keys().collect();
+        addrs.sort();
 
         test_debug!("{:?}: try pingback candidates -- sample out of {}. idx = {}", &self.local_peer, self.walk_pingbacks.len(), idx);
 
-        let addr = match self.walk_pingbacks.keys().skip(idx).next() {
+        let addr = match addrs.into_iter().skip(idx).next() {
             Some(ref addr) => (*addr).clone(),
             None => {
                 return Err(net_error::NoSuchNeighbor);
@@ -2088,8 +2091,7 @@ impl PeerNetwork {
 
                         if walk_opt.is_some() && self.walk_count > NUM_INITIAL_WALKS && walk.walk_step_count >= walk.walk_min_duration {
                             // consider re-setting the walk state, now that we completed a walk.
-                            let mut rng = thread_rng();
-                            let sample : f64 = rng.gen();
+                            let sample : f64 = util::with_rng(|rng| rng.gen());
                             if walk.walk_step_count >= walk.walk_max_duration || sample < walk.walk_reset_prob {
                                 true
                             }
@@ -2152,6 +2154,7 @@ mod test {
     use net::test::*;
     use util::hash::*;
     use util::sleep_ms;
+    use rand::thread_rng;
 
     const TEST_IN_OUT_DEGREES : u64 = 0x1;
 
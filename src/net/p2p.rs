@@ -41,6 +41,9 @@ use net::download::BlockDownloader;
 
 use net::poll::NetworkState;
 use net::poll::NetworkPollState;
+use net::poll::NetworkTransport;
+use net::poll::NetworkSocket;
+use net::poll::TransportSocket;
 
 use net::db::LocalPeer;
 
@@ -50,8 +53,11 @@ use net::prune::*;
 
 use net::server::*;
 
+use net::websocket::RPCEvent;
+
 use net::relay::*;
 
+use util;
 use util::db::Error as db_error;
 use util::db::DBConn;
 
@@ -86,13 +92,17 @@ use chainstate::stacks::{MAX_BLOCK_LEN, MAX_TRANSACTION_LEN};
 use util::log;
 use util::get_epoch_time_secs;
 
+use monitoring;
+
 use rand::prelude::*;
-use rand::thread_rng;
 
 use mio;
 use mio::net as mio_net;
 
 use net::inv::*;
+use net::mempool::MemPoolSyncState;
+use net::compact::{ CompactBlockState, PartialBlock };
+use net::reputation::PeerOffense;
 use net::relay::*;
 
 /// inter-thread request to send a p2p message from another thread in this program.
@@ -104,7 +114,9 @@ pub enum NetworkRequest {
     AdvertizeMicroblocks(BlocksAvailableMap),       // announce to all wanting neighbors that we have these confirmed microblock streams
     Request(NeighborKey, StacksMessage, u64),       // target neighbor, message to send, ttl
     Relay(NeighborKey, StacksMessage),
-    Broadcast(Vec<RelayData>, StacksMessageType)
+    Broadcast(Vec<RelayData>, StacksMessageType),
+    FetchBlockTxs(Vec<PartialBlock>),               // ask compact block senders for the transactions we couldn't find
+    PublishEvents(Vec<RPCEvent>)                    // tell websocket subscribers about new chain and mempool data
 }
 
 /// Handle for other threads to use to issue p2p network requests.
@@ -186,7 +198,8 @@ impl NetworkHandle {
         self.send_request(req)
     }
 
-    /// Ban a peer
+    /// Penalize peers that sent us invalid blocks.  They'll be banned if this puts their
+    /// reputations over the ban threshold.
     pub fn ban_peers(&mut self, neighbor_keys: Vec<NeighborKey>) -> Result<(), net_error> {
         let req = NetworkRequest::Ban(neighbor_keys);
         self.send_request(req)
@@ -233,6 +246,20 @@ impl NetworkHandle {
         let req = NetworkRequest::Broadcast(relay_hints, msg);
         self.send_request(req)
     }
+
+    /// Ask the peers that sent us these compact blocks for the transactions we're missing.
+    /// Blocks that get rebuilt show up in a later NetworkResult's pushed blocks.
+    pub fn fetch_block_txs(&mut self, partial_blocks: Vec<PartialBlock>) -> Result<(), net_error> {
+        let req = NetworkRequest::FetchBlockTxs(partial_blocks);
+        self.send_request(req)
+    }
+
+    /// Hand events to the p2p network thread, so it can push them out to RPC websocket
+    /// subscribers.
+    pub fn publish_events(&mut self, events: Vec<RPCEvent>) -> Result<(), net_error> {
+        let req = NetworkRequest::PublishEvents(events);
+        self.send_request(req)
+    }
 }
 
 impl NetworkHandleServer {
@@ -270,10 +297,11 @@ pub struct PeerNetwork {
 
     // ongoing p2p conversations (either they reached out to us, or we to them)
     pub peers: PeerMap,
-    pub sockets: HashMap<usize, mio_net::TcpStream>,
+    pub sockets: HashMap<usize, NetworkSocket>,
     pub events: HashMap<NeighborKey, usize>,
-    pub connecting: HashMap<usize, (mio_net::TcpStream, bool, u64)>,   // (socket, outbound?, connection sent)
+    pub connecting: HashMap<usize, (NetworkSocket, bool, u64)>,   // (socket, outbound?, connection sent)
     pub bans: HashSet<usize>,
+    pub penalties: Vec<(usize, PeerOffense)>,     // misbehavior to count against neighbors' reputations
 
     // ongoing messages the network is sending via the p2p interface (not bound to a specific
     // conversation).
@@ -284,7 +312,7 @@ pub struct PeerNetwork {
     handles: VecDeque<NetworkHandleServer>,
 
     // network I/O
-    network: Option<NetworkState>,
+    network: Option<Box<dyn NetworkTransport>>,
     p2p_network_handle: usize,
     http_network_handle: usize,
 
@@ -312,6 +340,12 @@ pub struct PeerNetwork {
     // peer block download state
     pub block_downloader: Option<BlockDownloader>,
 
+    // mempool synchronization state
+    pub mempool_sync: MemPoolSyncState,
+
+    // compact block reconstruction state
+    pub compact_blocks: CompactBlockState,
+
     // do we need to do a prune at the end of the work state cycle?
     pub do_prune: bool,
 
@@ -341,6 +375,7 @@ impl PeerNetwork {
             events: HashMap::new(),
             connecting: HashMap::new(),
             bans: HashSet::new(),
+            penalties: vec![],
 
             relay_handles: HashMap::new(),
             relayer_stats: RelayerStats::new(),
@@ -365,6 +400,8 @@ impl PeerNetwork {
             
             inv_state: None,
             block_downloader: None,
+            mempool_sync: MemPoolSyncState::new(),
+            compact_blocks: CompactBlockState::new(),
 
             do_prune: false,
 
@@ -378,8 +415,12 @@ impl PeerNetwork {
 
     /// start serving.
     pub fn bind(&mut self, my_addr: &SocketAddr, http_addr: &SocketAddr) -> Result<(), net_error> {
-        let mut net = NetworkState::new(self.connection_opts.max_sockets)?;
+        let net = NetworkState::new(self.connection_opts.max_sockets)?;
+        self.bind_transport(Box::new(net), my_addr, http_addr)
+    }
 
+    /// start serving on the given transport.
+    pub fn bind_transport(&mut self, mut net: Box<dyn NetworkTransport>, my_addr: &SocketAddr, http_addr: &SocketAddr) -> Result<(), net_error> {
         let p2p_handle = net.bind(my_addr)?;
         let http_handle = net.bind(http_addr)?;
 
@@ -397,12 +438,12 @@ impl PeerNetwork {
     /// Run a closure with the network state
     pub fn with_network_state<F, R>(peer_network: &mut PeerNetwork, closure: F) -> Result<R, net_error>
     where
-        F: FnOnce(&mut PeerNetwork, &mut NetworkState) -> Result<R, net_error>
+        F: FnOnce(&mut PeerNetwork, &mut dyn NetworkTransport) -> Result<R, net_error>
     {
         let mut net = peer_network.network.take();
         let res = match net {
             Some(ref mut network_state) => {
-                closure(peer_network, network_state)
+                closure(peer_network, &mut **network_state)
             },
             None => {
                 return Err(net_error::NotConnected);
@@ -421,7 +462,7 @@ impl PeerNetwork {
 
     /// Saturate a socket with a reply handle
     /// Return (number of bytes sent, whether or not there's more to send)
-    fn do_saturate_p2p_socket(convo: &mut ConversationP2P, client_sock: &mut mio::net::TcpStream, handle: &mut ReplyHandleP2P) -> Result<(usize, bool), net_error> {
+    fn do_saturate_p2p_socket(convo: &mut ConversationP2P, client_sock: &mut NetworkSocket, handle: &mut ReplyHandleP2P) -> Result<(usize, bool), net_error> {
         let mut total_sent = 0;
         let mut flushed;
         
@@ -568,7 +609,7 @@ impl PeerNetwork {
     }
 
     /// Count how many connections to a given IP address we have 
-    pub fn count_ip_connections(ipaddr: &SocketAddr, sockets: &HashMap<usize, mio_net::TcpStream>) -> u64 {
+    pub fn count_ip_connections(ipaddr: &SocketAddr, sockets: &HashMap<usize, NetworkSocket>) -> u64 {
         let mut ret = 0;
         for (_, socket) in sockets.iter() {
             match socket.peer_addr() {
@@ -602,7 +643,7 @@ impl PeerNetwork {
                 return Err(net_error::NotConnected);
             },
             Some(ref mut network) => {
-                let sock = NetworkState::connect(&neighbor.addrbytes.to_socketaddr(neighbor.port))?;
+                let sock = network.connect(&neighbor.addrbytes.to_socketaddr(neighbor.port))?;
                 let hint_event_id = network.next_event_id()?;
                 let registered_event_id = network.register(self.p2p_network_handle, hint_event_id, &sock)?;
 
@@ -619,12 +660,16 @@ impl PeerNetwork {
     /// Up to MAX_BROADCAST_INBOUND_PEERS inbound connections will be used.
     /// The outbound will be sampled according to their AS distribution
     /// The inbound will be sampled according to how rarely they send duplicate messages
-    fn sample_broadcast_peers<R: RelayPayload>(&self, relay_hints: &Vec<RelayData>, payload: &R) -> Result<Vec<NeighborKey>, net_error> {
+    /// Only peers that advertise all of the `required_services` flags are considered.
+    fn sample_broadcast_peers<R: RelayPayload>(&self, relay_hints: &Vec<RelayData>, payload: &R, required_services: u16) -> Result<Vec<NeighborKey>, net_error> {
         // coalesce
         let mut outbound_neighbors = vec![];
         let mut inbound_neighbors = vec![];
 
         for (_, convo) in self.peers.iter() {
+            if (convo.peer_services & required_services) != required_services {
+                continue;
+            }
             let nk = convo.to_neighbor_key();
             if convo.is_outbound() {
                 outbound_neighbors.push(nk);
@@ -684,8 +729,8 @@ impl PeerNetwork {
                     test_debug!("Request to ban {:?}", neighbor_key);
                     match self.events.get(neighbor_key) {
                         Some(event_id) => {
-                            test_debug!("Will ban {:?} (event {})", neighbor_key, event_id);
-                            self.bans.insert(*event_id);
+                            test_debug!("Will penalize {:?} (event {})", neighbor_key, event_id);
+                            self.penalties.push((*event_id, PeerOffense::InvalidBlock));
                         },
                         None => {}
                     }
@@ -719,7 +764,7 @@ impl PeerNetwork {
                         // send to each neighbor that needs one
                         let mut all_neighbors = HashSet::new();
                         for (_, block) in data.blocks.iter() {
-                            let mut neighbors = self.sample_broadcast_peers(&relay_hints, block)?;
+                            let mut neighbors = self.sample_broadcast_peers(&relay_hints, block, 0)?;
                             for nk in neighbors.drain(..) {
                                 all_neighbors.insert(nk);
                             }
@@ -730,14 +775,24 @@ impl PeerNetwork {
                         // send to each neighbor that needs at least one
                         let mut all_neighbors = HashSet::new();
                         for mblock in data.microblocks.iter() {
-                            let mut neighbors = self.sample_broadcast_peers(&relay_hints, mblock)?;
+                            let mut neighbors = self.sample_broadcast_peers(&relay_hints, mblock, 0)?;
                             for nk in neighbors.drain(..) {
                                 all_neighbors.insert(nk);
                             }
                         }
                         Ok(all_neighbors.into_iter().collect())
                     },
-                    StacksMessageType::Transaction(ref data) => self.sample_broadcast_peers(&relay_hints, data),
+                    StacksMessageType::Transaction(ref data) => self.sample_broadcast_peers(&relay_hints, data, 0),
+                    StacksMessageType::CompactBlock(ref data) => {
+                        let flag = ServiceFlags::COMPACT_BLOCKS as u16;
+                        if (self.local_peer.services & flag) == 0 {
+                            // we don't relay compact blocks
+                            Ok(vec![])
+                        }
+                        else {
+                            self.sample_broadcast_peers(&relay_hints, data, flag)
+                        }
+                    },
                     _ => {
                         // not suitable for broadcast
                         return Err(net_error::InvalidMessage);
@@ -745,6 +800,20 @@ impl PeerNetwork {
                 }?;
                 self.broadcast_message(neighbor_keys, relay_hints, msg);
                 Ok(None)
+            },
+            NetworkRequest::FetchBlockTxs(partial_blocks) => {
+                for partial_block in partial_blocks.into_iter() {
+                    let _index_block_hash = partial_block.data.index_block_hash();
+                    if let Err(_e) = self.compact_block_fetch_begin(partial_block) {
+                        debug!("{:?}: failed to request transactions for compact block {}: {:?}", &self.local_peer, &_index_block_hash, &_e);
+                        self.hint_download_rescan();
+                    }
+                }
+                Ok(None)
+            },
+            NetworkRequest::PublishEvents(events) => {
+                self.http.publish_events(&events);
+                Ok(None)
             }
         }
     }
@@ -818,12 +887,16 @@ impl PeerNetwork {
         num_dispatched
     }
 
-    /// Process ban requests.  Update the blacklist in the peer database.  Return the vec of event IDs to disconnect from.
+    /// Process ban requests, including those from neighbors whose reputations crossed the ban
+    /// threshold.  Update the blacklist in the peer database.  Return the vec of event IDs to disconnect from.
     fn process_bans(&mut self) -> Result<Vec<usize>, net_error> {
         if cfg!(test) && self.connection_opts.disable_network_bans {
+             self.penalties.clear();
              return Ok(vec![]);
         }
 
+        self.process_penalties()?;
+
         let mut tx = self.peerdb.tx_begin()?;
         let mut disconnect = vec![];
         for event_id in self.bans.drain() {
@@ -918,10 +991,13 @@ impl PeerNetwork {
             return Err(net_error::AlreadyConnected(event_id));
         }
 
-        // blacklisted?
+        // blacklisted?  Peers we haven't met yet may still be banned by an operator rule.
         let blacklisted = match PeerDB::get_peer(&self.peerdb.conn(), neighbor_key.network_id, &neighbor_key.addrbytes, neighbor_key.port)? {
             Some(neighbor) => neighbor.is_blacklisted(),
-            None => false
+            None => match PeerDB::get_address_rule(&self.peerdb.conn(), neighbor_key.network_id, &neighbor_key.addrbytes, neighbor_key.port)? {
+                Some(rule) => rule.is_blacklisted(),
+                None => false
+            }
         };
 
         if blacklisted {
@@ -945,7 +1021,7 @@ impl PeerNetwork {
     /// connection events).  If this method fails for some reason, it'll de-register the socket
     /// from the poller.
     /// outbound is true if we are the peer that started the connection (otherwise it's false)
-    fn register_peer(&mut self, event_id: usize, socket: mio_net::TcpStream, outbound: bool) -> Result<(), net_error> {
+    fn register_peer(&mut self, event_id: usize, socket: NetworkSocket, outbound: bool) -> Result<(), net_error> {
         let client_addr = match socket.peer_addr() {
             Ok(addr) => addr,
             Err(e) => {
@@ -1021,7 +1097,7 @@ impl PeerNetwork {
     }
 
     /// Deregister a socket from our p2p network instance.
-    fn deregister_socket(&mut self, event_id: usize, socket: mio_net::TcpStream) -> () {
+    fn deregister_socket(&mut self, event_id: usize, socket: NetworkSocket) -> () {
         match self.network {
             Some(ref mut network) => {
                 let _ = network.deregister(event_id, &socket);
@@ -1178,7 +1254,7 @@ impl PeerNetwork {
     /// Process network traffic on a p2p conversation.
     /// Returns list of unhandled messages, and whether or not the convo is still alive.
     fn process_p2p_conversation(local_peer: &LocalPeer, peerdb: &mut PeerDB, burndb: &BurnDB, chainstate: &mut StacksChainState, chain_view: &BurnchainView, 
-                                event_id: usize, client_sock: &mut mio_net::TcpStream, convo: &mut ConversationP2P) -> Result<(Vec<StacksMessage>, bool), net_error> {
+                                event_id: usize, client_sock: &mut NetworkSocket, convo: &mut ConversationP2P) -> Result<(Vec<StacksMessage>, bool), net_error> {
         // get incoming bytes and update the state of this conversation.
         let mut convo_dead = false;
         let recv_res = convo.recv(client_sock);
@@ -1632,7 +1708,7 @@ impl PeerNetwork {
             }
         }
 
-        let _ = PeerNetwork::with_network_state(self, |ref mut network, ref mut network_state| {
+        let _ = PeerNetwork::with_network_state(self, |ref mut network, network_state| {
             for dead_event in broken_http_peers.drain(..) {
                 debug!("{:?}: De-register broken HTTP connection {}", &network.local_peer, dead_event);
                 network.http.deregister_http(network_state, dead_event);
@@ -1949,9 +2025,10 @@ impl PeerNetwork {
     }
     
     /// Handle unsolicited messages propagated up to us from our ongoing ConversationP2Ps.
-    /// Right now, this is just BlocksAvailables -- update our inv state for the peer that sent it.
+    /// Right now, this is BlocksAvailables -- update our inv state for the peer that sent it -- and
+    /// MemPoolSyncs, which we answer from our mempool.
     /// Return messages that we couldn't handle here, but key them by neighbor, not event.
-    fn handle_unsolicited_messages(&mut self, burndb: &BurnDB, mut unsolicited: HashMap<usize, Vec<StacksMessage>>) -> Result<HashMap<NeighborKey, Vec<StacksMessage>>, net_error> {
+    fn handle_unsolicited_messages(&mut self, burndb: &BurnDB, mempool: &MemPoolDB, mut unsolicited: HashMap<usize, Vec<StacksMessage>>) -> Result<HashMap<NeighborKey, Vec<StacksMessage>>, net_error> {
         let mut unhandled : HashMap<NeighborKey, Vec<StacksMessage>> = HashMap::new();
         for (event_id, messages) in unsolicited.drain() {
             let neighbor_key = match self.peers.get(&event_id) {
@@ -1975,6 +2052,12 @@ impl PeerNetwork {
                     StacksMessageType::MicroblocksAvailable(ref new_mblocks) => {
                         self.handle_unsolicited_MicroblocksAvailable(burndb, event_id, new_mblocks);
                     },
+                    StacksMessageType::MemPoolSync(ref sync_data) => {
+                        // send back the transactions this peer doesn't have
+                        if let Err(e) = self.handle_unsolicited_MemPoolSync(mempool, event_id, &message.preamble, sync_data) {
+                            debug!("{:?}: failed to answer MemPoolSync from {:?}: {:?}", &self.local_peer, &neighbor_key, &e);
+                        }
+                    },
                     StacksMessageType::Blocks(ref new_blocks) => {
                         // update inv state for this peer
                         self.handle_unsolicited_BlocksData(burndb, event_id, new_blocks);
@@ -2065,8 +2148,10 @@ impl PeerNetwork {
 
                     if self.walk_pingbacks.len() > MAX_NEIGHBORS_DATA_LEN as usize {
                         // drop one at random 
-                        let idx = thread_rng().gen::<usize>() % self.walk_pingbacks.len();
-                        let drop_addr = match self.walk_pingbacks.keys().skip(idx).next() {
+                        let idx = util::with_rng(|rng| rng.gen::<usize>()) % self.walk_pingbacks.len();
+                        let mut addrs : Vec<&NeighborAddress> = self.walk_pingbacks.keys().collect();
+                        addrs.sort();
+                        let drop_addr = match addrs.into_iter().skip(idx).next() {
                             Some(ref addr) => (*addr).clone(),
                             None => {
                                 continue;
@@ -2103,6 +2188,7 @@ impl PeerNetwork {
                         network_result: &mut NetworkResult,
                         burndb: &BurnDB, 
                         chainstate: &mut StacksChainState, 
+                        mempool: &MemPoolDB,
                         dns_client_opt: Option<&mut DNSClient>,
                         download_backpressure: bool,
                         mut poll_state: NetworkPollState) -> Result<(), net_error> {
@@ -2146,7 +2232,7 @@ impl PeerNetwork {
             debug!("{:?}: Failed connection on event {}", &self.local_peer, error_event);
             self.deregister_peer(error_event);
         }
-        let unhandled_messages = self.handle_unsolicited_messages(burndb, unsolicited_messages)?;
+        let unhandled_messages = self.handle_unsolicited_messages(burndb, mempool, unsolicited_messages)?;
         network_result.consume_unsolicited(unhandled_messages);
 
         // schedule now-authenticated inbound convos for pingback
@@ -2165,11 +2251,25 @@ impl PeerNetwork {
                 debug!("{:?}: Banned connection on event {}", &self.local_peer, dead);
                 self.deregister_peer(dead);
             }
+
+            // drop neighbors that an operator banned since we connected
+            let mut blacklisted_events = self.find_blacklisted_peers()?;
+            for dead in blacklisted_events.drain(..) {
+                debug!("{:?}: Blacklisted connection on event {}", &self.local_peer, dead);
+                self.deregister_peer(dead);
+            }
             self.prune_connections();
         }
         
         // In parallel, do a neighbor walk
         self.do_network_neighbor_walk()?;
+
+        // In parallel, fetch any mempool transactions we're missing from a neighbor
+        let mut synced_txs = self.do_mempool_sync(mempool);
+        network_result.synced_transactions.append(&mut synced_txs);
+
+        // finish rebuilding any compact blocks whose missing transactions have arrived
+        self.compact_block_fetch_try_finish(network_result);
         
         // remove timed-out requests from other threads 
         for (_, convo) in self.peers.iter_mut() {
@@ -2236,16 +2336,30 @@ impl PeerNetwork {
   
         let mut result = NetworkResult::new();
 
-        PeerNetwork::with_network_state(self, |ref mut network, ref mut network_state| {
-            let http_stacks_msgs = network.http.run(network_state, network.chain_view.clone(), &network.peers, burndb, &network.peerdb, chainstate, mempool, http_poll_state)?;
+        PeerNetwork::with_network_state(self, |ref mut network, network_state| {
+            let http_stacks_msgs = network.http.run(network_state, network.chain_view.clone(), &network.peers, burndb, &mut network.peerdb, chainstate, mempool, http_poll_state)?;
             result.consume_http_uploads(http_stacks_msgs);
             Ok(())
         })?;
         
-        self.dispatch_network(&mut result, burndb, chainstate, dns_client_opt, download_backpressure, p2p_poll_state)?;
+        self.dispatch_network(&mut result, burndb, chainstate, mempool, dns_client_opt, download_backpressure, p2p_poll_state)?;
 
+        self.update_metrics();
         Ok(result)
     }
+
+    /// Report peer and download statistics to the metrics registry
+    fn update_metrics(&self) -> () {
+        let num_outbound = PeerNetwork::count_outbound_conversations(&self.peers);
+        let num_inbound = (self.peers.len() as u64).saturating_sub(num_outbound);
+        monitoring::set_neighbor_counts(num_inbound, num_outbound);
+
+        let num_inflight = match self.block_downloader {
+            Some(ref downloader) => downloader.num_inflight_requests(),
+            None => 0
+        };
+        monitoring::set_downloader_inflight_requests(num_inflight);
+    }
 }
 
 #[cfg(test)]
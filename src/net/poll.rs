@@ -36,6 +36,8 @@ use std::io::Write;
 use std::io::Error as io_error;
 use std::io::ErrorKind;
 use std::time;
+use std::fmt;
+use std::any::Any;
 
 use util::log;
 use util::sleep_ms;
@@ -55,8 +57,55 @@ pub const NUM_NEIGHBORS : u32 = 32;
 
 const SERVER : Token = mio::Token(0);
 
+/// A connected, non-blocking stream socket, as handed out by a NetworkTransport.
+pub trait TransportSocket : Read + Write + fmt::Debug + Send {
+    /// Address of the remote end of this socket
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Used by a transport to recover its own concrete socket type
+    fn as_any(&self) -> &dyn Any;
+}
+
+pub type NetworkSocket = Box<dyn TransportSocket>;
+
+/// The interface between the peer network and whatever carries its bytes.  The peer network
+/// only ever talks to the network through this trait, so its sockets can be real TCP sockets
+/// (NetworkState) or in-memory pipes (net::sim::SimTransport in tests).
+pub trait NetworkTransport : Send {
+    /// Start listening on the given address.  Returns the server's event handle.
+    fn bind(&mut self, addr: &SocketAddr) -> Result<usize, net_error>;
+
+    /// Begin connecting to a remote address.  The socket must be registered with this
+    /// transport in order to find out when the connection is established.
+    fn connect(&mut self, addr: &SocketAddr) -> Result<NetworkSocket, net_error>;
+
+    /// Register a socket for readiness notifications on a server handle.  Returns the event ID
+    /// actually used, which may differ from the hint.
+    fn register(&mut self, server_event_id: usize, hint_event_id: usize, sock: &NetworkSocket) -> Result<usize, net_error>;
+
+    /// Deregister and shut down a socket
+    fn deregister(&mut self, event_id: usize, sock: &NetworkSocket) -> Result<(), net_error>;
+
+    /// Allocate the next free event ID
+    fn next_event_id(&mut self) -> Result<usize, net_error>;
+
+    /// Find out which sockets are ready and which connections were accepted, grouped by server
+    /// handle.
+    fn poll(&mut self, timeout: u64) -> Result<HashMap<usize, NetworkPollState>, net_error>;
+}
+
+impl TransportSocket for mio_net::TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        mio_net::TcpStream::peer_addr(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
 pub struct NetworkPollState {
-    pub new: HashMap<usize, mio_net::TcpStream>,
+    pub new: HashMap<usize, NetworkSocket>,
     pub ready: Vec<usize>
 }
 
@@ -347,7 +396,7 @@ impl NetworkState {
                         new_events.insert(next_event_id);
                         
                         test_debug!("New socket accepted from {:?} (event {}) on server {:?}: {:?}", &_client_addr, next_event_id, &server.server_socket, &client_sock);
-                        poll_state.new.insert(next_event_id, client_sock);
+                        poll_state.new.insert(next_event_id, Box::new(client_sock));
                     }
 
                     break;
@@ -380,6 +429,45 @@ impl NetworkState {
     }
 }
 
+impl NetworkState {
+    fn as_tcp_stream(sock: &NetworkSocket) -> Result<&mio_net::TcpStream, net_error> {
+        sock.as_any().downcast_ref::<mio_net::TcpStream>()
+            .ok_or_else(|| {
+                error!("Not a TCP socket: {:?}", sock);
+                net_error::RegisterError
+            })
+    }
+}
+
+impl NetworkTransport for NetworkState {
+    fn bind(&mut self, addr: &SocketAddr) -> Result<usize, net_error> {
+        NetworkState::bind(self, addr)
+    }
+
+    fn connect(&mut self, addr: &SocketAddr) -> Result<NetworkSocket, net_error> {
+        let sock = NetworkState::connect(addr)?;
+        Ok(Box::new(sock))
+    }
+
+    fn register(&mut self, server_event_id: usize, hint_event_id: usize, sock: &NetworkSocket) -> Result<usize, net_error> {
+        let tcp_sock = NetworkState::as_tcp_stream(sock)?;
+        NetworkState::register(self, server_event_id, hint_event_id, tcp_sock)
+    }
+
+    fn deregister(&mut self, event_id: usize, sock: &NetworkSocket) -> Result<(), net_error> {
+        let tcp_sock = NetworkState::as_tcp_stream(sock)?;
+        NetworkState::deregister(self, event_id, tcp_sock)
+    }
+
+    fn next_event_id(&mut self) -> Result<usize, net_error> {
+        NetworkState::next_event_id(self)
+    }
+
+    fn poll(&mut self, timeout: u64) -> Result<HashMap<usize, NetworkPollState>, net_error> {
+        NetworkState::poll(self, timeout)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
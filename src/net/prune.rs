@@ -36,6 +36,7 @@ use net::db::PeerDB;
 
 use net::neighbors::*;
 
+use util;
 use util::db::Error as db_error;
 use util::db::DBConn;
 
@@ -51,7 +52,6 @@ use util::log;
 use util::get_epoch_time_secs;
 
 use rand::prelude::*;
-use rand::thread_rng;
 
 impl PeerNetwork {
     /// Find out which organizations have which of our outbound neighbors.
@@ -138,8 +138,7 @@ impl PeerNetwork {
         }
 
         // flip a coin
-        let mut rng = thread_rng();
-        if rng.next_u32() % 2 == 0 {
+        if util::with_rng(|rng| rng.next_u32()) % 2 == 0 {
             return Ordering::Less;
         }
         else {
@@ -150,15 +149,20 @@ impl PeerNetwork {
 
     /// Sample an org based on its weight
     fn sample_org_by_neighbor_count(org_weights: &HashMap<u32, usize>) -> u32 {
-        let mut rng = thread_rng();
         let mut total = 0;
         for (_, count) in org_weights.iter() {
             total += count;
         }
 
-        let sample = rng.gen_range(0, total);
+        let sample = util::with_rng(|rng| rng.gen_range(0, total));
         let mut offset = 0;
-        for (org, count) in org_weights.iter() {
+
+        // visit in org order, so the choice depends only on the sample
+        let mut orgs : Vec<&u32> = org_weights.keys().collect();
+        orgs.sort();
+
+        for org in orgs.into_iter() {
+            let count = org_weights.get(org).expect("BUG: org with no weight");
             if *count == 0 {
                 continue;
             }
@@ -0,0 +1,378 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use net::HttpRequestType;
+
+/// Classes of RPC routes, each of which gets its own rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RPCRouteClass {
+    /// Cheap lookups -- node info, neighbors, blocks, microblocks, and so on.
+    Read,
+    /// Routes that evaluate Clarity code, read from the MARF, or scan many headers or
+    /// mempool rows.
+    Expensive,
+    /// Routes that submit data to the node.
+    Submit,
+}
+
+impl RPCRouteClass {
+    /// Which route class does this request belong to?
+    /// Returns None for requests that are never rate-limited.
+    pub fn from_request(req: &HttpRequestType) -> Option<RPCRouteClass> {
+        match *req {
+            HttpRequestType::OptionsPreflight(..) => None,
+            HttpRequestType::PostTransaction(..) |
+            HttpRequestType::PostPeerAdmin(..) => Some(RPCRouteClass::Submit),
+            HttpRequestType::GetHeaders(..) |
+            HttpRequestType::GetMempoolTransactions(..) |
+            HttpRequestType::GetAccount(..) |
+            HttpRequestType::GetMapEntry(..) |
+            HttpRequestType::GetMapEntries(..) |
+            HttpRequestType::GetBatchProof(..) |
+            HttpRequestType::GetFungibleTokenBalance(..) |
+            HttpRequestType::GetNonFungibleTokenOwner(..) |
+            HttpRequestType::GetDataVar(..) |
+            HttpRequestType::CallReadOnlyFunction(..) |
+            HttpRequestType::GetContractSrc(..) |
+            HttpRequestType::GetContractABI(..) => Some(RPCRouteClass::Expensive),
+            HttpRequestType::GetInfo(..) |
+            HttpRequestType::GetNeighbors(..) |
+            HttpRequestType::GetBlock(..) |
+            HttpRequestType::GetMicroblocksIndexed(..) |
+            HttpRequestType::GetMicroblocksConfirmed(..) |
+            HttpRequestType::GetMicroblocksUnconfirmed(..) |
+            HttpRequestType::GetBlockByHeight(..) |
+            HttpRequestType::GetMempoolAccount(..) |
+            HttpRequestType::GetPeerReputations(..) |
+            HttpRequestType::GetTransferCost(..) |
+            HttpRequestType::Subscribe(..) |
+            HttpRequestType::Unmatched(..) => Some(RPCRouteClass::Read)
+        }
+    }
+}
+
+/// Per-route-class rate limits, in requests per minute.  0 means unlimited.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RPCRateLimits {
+    pub read: u64,
+    pub expensive: u64,
+    pub submit: u64,
+}
+
+impl RPCRateLimits {
+    pub fn unlimited() -> RPCRateLimits {
+        RPCRateLimits {
+            read: 0,
+            expensive: 0,
+            submit: 0
+        }
+    }
+
+    pub fn limit_for(&self, class: RPCRouteClass) -> u64 {
+        match class {
+            RPCRouteClass::Read => self.read,
+            RPCRouteClass::Expensive => self.expensive,
+            RPCRouteClass::Submit => self.submit
+        }
+    }
+}
+
+/// Token bucket holding up to one minute's worth of requests, refilled continuously.
+/// Tokens are tracked in thousandths so that slow refill rates don't round down to nothing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenBucket {
+    per_minute: u64,
+    millitokens: u128,
+    last_refill_ms: u128,
+    last_used_ms: u128,
+}
+
+impl TokenBucket {
+    pub fn new(per_minute: u64, now_ms: u128) -> TokenBucket {
+        TokenBucket {
+            per_minute: per_minute,
+            millitokens: (per_minute as u128) * 1000,
+            last_refill_ms: now_ms,
+            last_used_ms: now_ms
+        }
+    }
+
+    fn capacity(&self) -> u128 {
+        (self.per_minute as u128) * 1000
+    }
+
+    fn refill(&mut self, now_ms: u128) -> () {
+        if now_ms <= self.last_refill_ms {
+            return;
+        }
+        let added = (now_ms - self.last_refill_ms) * (self.per_minute as u128) / 60;
+        if added > 0 {
+            self.millitokens = (self.millitokens + added).min(self.capacity());
+            self.last_refill_ms = now_ms;
+        }
+    }
+
+    /// Is the bucket full as of now_ms?  A full bucket carries no state worth keeping.
+    pub fn is_full(&mut self, now_ms: u128) -> bool {
+        self.refill(now_ms);
+        self.millitokens >= self.capacity()
+    }
+
+    /// Try to take a token.  On failure, returns the number of seconds until one will be
+    /// available (always at least 1).
+    pub fn try_take(&mut self, now_ms: u128) -> Result<(), u64> {
+        self.refill(now_ms);
+        self.last_used_ms = now_ms;
+        if self.millitokens >= 1000 {
+            self.millitokens -= 1000;
+            return Ok(());
+        }
+
+        let ms_needed = ((1000 - self.millitokens) * 60 + (self.per_minute as u128) - 1) / (self.per_minute as u128);
+        let secs_needed = (ms_needed + 999) / 1000;
+        Err((secs_needed as u64).max(1))
+    }
+}
+
+/// Most buckets of each kind to keep.  Past this, the ones that have gone unused the longest are
+/// dropped to make room.
+pub const RATE_LIMIT_MAX_BUCKETS : usize = 65536;
+
+/// How often to drop buckets that have refilled completely
+pub const RATE_LIMIT_PRUNE_INTERVAL_MS : u128 = 10_000;
+
+/// Token buckets for each (client IP, route class) and (API key, route class) pair.
+#[derive(Debug)]
+pub struct RPCRateLimiter {
+    ip_buckets: HashMap<(IpAddr, RPCRouteClass), TokenBucket>,
+    key_buckets: HashMap<(String, RPCRouteClass), TokenBucket>,
+    max_buckets: usize,
+    last_prune_ms: u128,
+}
+
+impl RPCRateLimiter {
+    pub fn new() -> RPCRateLimiter {
+        RPCRateLimiter::with_max_buckets(RATE_LIMIT_MAX_BUCKETS)
+    }
+
+    pub fn with_max_buckets(max_buckets: usize) -> RPCRateLimiter {
+        RPCRateLimiter {
+            ip_buckets: HashMap::new(),
+            key_buckets: HashMap::new(),
+            max_buckets: max_buckets,
+            last_prune_ms: 0
+        }
+    }
+
+    /// Make room for a new bucket: drop the full ones, and if that's not enough, the quarter of
+    /// them that have gone unused the longest.
+    fn evict<K: Hash + Eq>(buckets: &mut HashMap<K, TokenBucket>, max_buckets: usize, now_ms: u128) -> () {
+        buckets.retain(|_, bucket| !bucket.is_full(now_ms));
+        if buckets.len() < max_buckets {
+            return;
+        }
+
+        let mut last_used : Vec<u128> = buckets.values().map(|bucket| bucket.last_used_ms).collect();
+        last_used.sort();
+
+        let num_evict = (buckets.len() - (max_buckets * 3) / 4).max(1);
+        let cutoff = last_used[num_evict - 1];
+        buckets.retain(|_, bucket| bucket.last_used_ms > cutoff);
+    }
+
+    fn take<K: Hash + Eq>(buckets: &mut HashMap<K, TokenBucket>, max_buckets: usize, key: K, per_minute: u64, now_ms: u128) -> Result<(), u64> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+        if buckets.len() >= max_buckets && !buckets.contains_key(&key) {
+            RPCRateLimiter::evict(buckets, max_buckets, now_ms);
+        }
+        let bucket = buckets.entry(key).or_insert_with(|| TokenBucket::new(per_minute, now_ms));
+        if bucket.per_minute != per_minute {
+            // limits were reconfigured
+            *bucket = TokenBucket::new(per_minute, now_ms);
+        }
+        bucket.try_take(now_ms)
+    }
+
+    /// Charge a request of the given class to a client IP address.
+    /// Returns Err(retry-after seconds) if the client is over its limit.
+    pub fn check_ip(&mut self, addr: &IpAddr, class: RPCRouteClass, limits: &RPCRateLimits, now_ms: u128) -> Result<(), u64> {
+        RPCRateLimiter::take(&mut self.ip_buckets, self.max_buckets, (addr.clone(), class), limits.limit_for(class), now_ms)
+    }
+
+    /// Charge a request of the given class to an API key.
+    /// Returns Err(retry-after seconds) if the key is over its limit.
+    pub fn check_api_key(&mut self, api_key: &str, class: RPCRouteClass, limits: &RPCRateLimits, now_ms: u128) -> Result<(), u64> {
+        RPCRateLimiter::take(&mut self.key_buckets, self.max_buckets, (api_key.to_string(), class), limits.limit_for(class), now_ms)
+    }
+
+    /// Forget about clients whose buckets have refilled completely.  Only does anything every
+    /// RATE_LIMIT_PRUNE_INTERVAL_MS.
+    pub fn prune(&mut self, now_ms: u128) -> () {
+        if now_ms < self.last_prune_ms + RATE_LIMIT_PRUNE_INTERVAL_MS {
+            return;
+        }
+        self.last_prune_ms = now_ms;
+
+        self.ip_buckets.retain(|_, bucket| !bucket.is_full(now_ms));
+        self.key_buckets.retain(|_, bucket| !bucket.is_full(now_ms));
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.ip_buckets.len() + self.key_buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use net::*;
+    use chainstate::stacks::StacksAddress;
+    use util::hash::Hash160;
+    use vm::ContractName;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(60, 1000);
+
+        // starts full
+        for _ in 0..60 {
+            bucket.try_take(1000).unwrap();
+        }
+        assert_eq!(bucket.try_take(1000), Err(1));
+
+        // one token per second
+        assert_eq!(bucket.try_take(1500), Err(1));
+        bucket.try_take(2000).unwrap();
+        assert_eq!(bucket.try_take(2000), Err(1));
+
+        // never holds more than a minute's worth
+        assert!(bucket.is_full(1000000));
+        for _ in 0..60 {
+            bucket.try_take(1000000).unwrap();
+        }
+        assert!(bucket.try_take(1000000).is_err());
+
+        // slow buckets report how long to wait
+        let mut slow_bucket = TokenBucket::new(2, 0);
+        slow_bucket.try_take(0).unwrap();
+        slow_bucket.try_take(0).unwrap();
+        assert_eq!(slow_bucket.try_take(0), Err(30));
+        assert_eq!(slow_bucket.try_take(12000), Err(18));
+        slow_bucket.try_take(30000).unwrap();
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RPCRateLimiter::new();
+        let limits = RPCRateLimits {
+            read: 3,
+            expensive: 1,
+            submit: 0
+        };
+
+        let addr_1 : IpAddr = "127.0.0.1".parse().unwrap();
+        let addr_2 : IpAddr = "127.0.0.2".parse().unwrap();
+
+        // each route class is limited separately
+        for _ in 0..3 {
+            limiter.check_ip(&addr_1, RPCRouteClass::Read, &limits, 0).unwrap();
+        }
+        assert!(limiter.check_ip(&addr_1, RPCRouteClass::Read, &limits, 0).is_err());
+
+        limiter.check_ip(&addr_1, RPCRouteClass::Expensive, &limits, 0).unwrap();
+        assert_eq!(limiter.check_ip(&addr_1, RPCRouteClass::Expensive, &limits, 0), Err(60));
+
+        // unlimited classes never get a bucket
+        for _ in 0..100 {
+            limiter.check_ip(&addr_1, RPCRouteClass::Submit, &limits, 0).unwrap();
+        }
+        assert_eq!(limiter.num_buckets(), 2);
+
+        // each client is limited separately
+        limiter.check_ip(&addr_2, RPCRouteClass::Expensive, &limits, 0).unwrap();
+
+        // each key is limited separately, and independently of its client's IP
+        limiter.check_api_key("key-1", RPCRouteClass::Expensive, &limits, 0).unwrap();
+        assert!(limiter.check_api_key("key-1", RPCRouteClass::Expensive, &limits, 0).is_err());
+        limiter.check_api_key("key-2", RPCRouteClass::Expensive, &limits, 0).unwrap();
+        assert_eq!(limiter.num_buckets(), 5);
+
+        // buckets go away once they refill
+        limiter.prune(30000);
+        assert_eq!(limiter.num_buckets(), 5);
+        limiter.prune(60000);
+        assert_eq!(limiter.num_buckets(), 0);
+    }
+
+    #[test]
+    fn test_rate_limiter_max_buckets() {
+        let mut limiter = RPCRateLimiter::with_max_buckets(8);
+        let limits = RPCRateLimits {
+            read: 3,
+            expensive: 1,
+            submit: 0
+        };
+
+        let addr = |i: u8| -> IpAddr { format!("10.0.0.{}", i).parse().unwrap() };
+
+        // fill up, with clients that are all still waiting on their buckets
+        for i in 0..8 {
+            limiter.check_ip(&addr(i), RPCRouteClass::Expensive, &limits, (i as u128) * 1000).unwrap();
+        }
+        assert_eq!(limiter.num_buckets(), 8);
+
+        // a client we already track doesn't push anyone out
+        assert!(limiter.check_ip(&addr(7), RPCRouteClass::Expensive, &limits, 8000).is_err());
+        assert_eq!(limiter.num_buckets(), 8);
+
+        // a new client pushes out the ones that have been idle longest
+        limiter.check_ip(&addr(8), RPCRouteClass::Expensive, &limits, 9000).unwrap();
+        assert_eq!(limiter.num_buckets(), 7);
+
+        // the most recently used ones are still limited
+        assert!(limiter.check_ip(&addr(7), RPCRouteClass::Expensive, &limits, 9000).is_err());
+        assert!(limiter.check_ip(&addr(8), RPCRouteClass::Expensive, &limits, 9000).is_err());
+
+        // and the oldest ones start over
+        limiter.check_ip(&addr(0), RPCRouteClass::Expensive, &limits, 9000).unwrap();
+
+        // buckets that have refilled make room before anyone else is evicted
+        limiter.check_ip(&addr(9), RPCRouteClass::Expensive, &limits, 200000).unwrap();
+        assert_eq!(limiter.num_buckets(), 1);
+    }
+
+    #[test]
+    fn test_route_classes() {
+        let md = HttpRequestMetadata::new("127.0.0.1".to_string(), 20443);
+        assert_eq!(RPCRouteClass::from_request(&HttpRequestType::GetInfo(md.clone())), Some(RPCRouteClass::Read));
+        assert_eq!(RPCRouteClass::from_request(&HttpRequestType::GetContractABI(md.clone(), StacksAddress { version: 1, bytes: Hash160([0u8; 20]) }, ContractName::try_from("hello-world".to_string()).unwrap(), TipRequest::UseLatestAnchoredTip)),
+                   Some(RPCRouteClass::Expensive));
+        assert_eq!(RPCRouteClass::from_request(&HttpRequestType::GetHeaders(md.clone(), 2100, None)), Some(RPCRouteClass::Expensive));
+        assert_eq!(RPCRouteClass::from_request(&HttpRequestType::GetMempoolTransactions(md.clone(), None, None, 200)), Some(RPCRouteClass::Expensive));
+        assert_eq!(RPCRouteClass::from_request(&HttpRequestType::GetBlockByHeight(md.clone(), 1, None)), Some(RPCRouteClass::Read));
+        assert_eq!(RPCRouteClass::from_request(&HttpRequestType::OptionsPreflight(md.clone(), "/v2/info".to_string())), None);
+    }
+}
@@ -33,6 +33,10 @@ use net::poll::*;
 use net::rpc::*;
 use net::http::*;
 use net::p2p::*;
+use net::websocket::RPCEvent;
+use net::compact::PartialBlock;
+use net::compact::MemPoolShortTxidIndex;
+use net::reputation::PeerOffense;
 
 use chainstate::burn::db::burndb::BurnDB;
 use chainstate::stacks::db::{StacksChainState, StacksHeaderInfo};
@@ -47,12 +51,12 @@ use chainstate::burn::db::burndb::BurnDBConn;
 use burnchains::Burnchain;
 use burnchains::BurnchainView;
 
+use util;
 use util::hash::Sha512Trunc256Sum;
 use util::get_epoch_time_secs;
 
 use rand::prelude::*;
 use rand::Rng;
-use rand::thread_rng;
 
 pub type BlocksAvailableMap = HashMap<BurnchainHeaderHash, (u64, ConsensusHash)>;
 
@@ -113,6 +117,16 @@ impl RelayPayload for StacksBlock {
     }
 }
 
+impl RelayPayload for CompactBlockData {
+    fn get_digest(&self) -> Sha512Trunc256Sum {
+        let h = self.block_hash();
+        Sha512Trunc256Sum(h.0)
+    }
+    fn get_id(&self) -> String {
+        format!("CompactBlock({})", self.block_hash())
+    }
+}
+
 impl RelayPayload for StacksMicroblock {
     fn get_digest(&self) -> Sha512Trunc256Sum {
         let h = self.block_hash();
@@ -238,6 +252,16 @@ impl RelayerStats {
         }
     }
 
+    /// Has this neighbor recently sent us this message already?
+    pub fn has_relayed_message<R: RelayPayload>(&self, nk: &NeighborKey, msg: &R) -> bool {
+        let h = msg.get_digest();
+        let now = get_epoch_time_secs();
+        match self.recent_messages.get(nk) {
+            Some(relayed) => relayed.iter().any(|(ts, msg_hash)| ts + (MAX_RECENT_MESSAGE_AGE as u64) >= now && *msg_hash == h),
+            None => false
+        }
+    }
+
     /// Process a neighbor ban -- remove any state for this neighbor
     pub fn process_neighbor_ban(&mut self, nk: &NeighborKey) -> () {
         let addr = NeighborAddress::from_neighbor_key((*nk).clone(), Hash160([0u8; 20]));
@@ -371,11 +395,13 @@ impl RelayerStats {
     /// min(count, rankings.len())
     pub fn sample_neighbors(rankings: HashMap<NeighborKey, usize>, count: usize) -> Vec<NeighborKey> {
         let mut ret = vec![];
-        let mut rng = thread_rng();
 
         let mut norm = rankings.values().fold(0, |t, s| { t + s });
         let mut rankings_vec : Vec<(NeighborKey, usize)> = rankings.into_iter().collect();
 
+        // sorted, so the sample depends only on the RNG and not on hash map order
+        rankings_vec.sort();
+
         if norm <= 1 {
             // there is one or zero options
             if rankings_vec.len() > 0 {
@@ -393,7 +419,7 @@ impl RelayerStats {
                 break;
             }
 
-            let target : usize = rng.gen::<usize>() % norm;      // slightly biased, but it doesn't really matter
+            let target : usize = util::with_rng(|rng| rng.gen::<usize>()) % norm;      // slightly biased, but it doesn't really matter
             let mut w = 0;
 
             for i in 0..rankings_vec.len() {
@@ -793,23 +819,30 @@ impl Relayer {
     }
 
     /// Store all new transactions we received, and return the list of transactions that we need to
-    /// forward (as well as their relay hints).  Also, garbage-collect the mempool.
-    fn process_transactions(network_result: &mut NetworkResult, burndb: &BurnDB, chainstate: &StacksChainState, mempool: &mut MemPoolDB) -> Result<Vec<(Vec<RelayData>, StacksTransaction)>, net_error> {
+    /// forward (as well as their relay hints), and the list of transactions that are new to our
+    /// mempool.  Also, garbage-collect the mempool.
+    fn process_transactions(network_result: &mut NetworkResult, burndb: &BurnDB, chainstate: &StacksChainState, mempool: &mut MemPoolDB)
+                            -> Result<(Vec<(Vec<RelayData>, StacksTransaction)>, Vec<StacksTransaction>), net_error> {
         let (burn_header_hash, block_hash, chain_height) = match chainstate.get_stacks_chain_tip(burndb)? {
             Some(tip) => (tip.burn_header_hash, tip.anchored_block_hash, tip.height),
             None => {
                 debug!("No Stacks chain tip; dropping {} transaction(s)", network_result.pushed_transactions.len());
-                return Ok(vec![]);
+                return Ok((vec![], vec![]));
             }
         };
 
         let mut ret = vec![];
+        let mut admitted = vec![];
 
         // messages pushed via the p2p network
         for (_nk, tx_data) in network_result.pushed_transactions.iter() {
             for (relayers, tx) in tx_data.iter() {
+                let is_new = !mempool.has_tx(&tx.txid());
                 if Relayer::store_transaction(mempool, &burn_header_hash, &block_hash, tx.clone()) {
                     ret.push((relayers.clone(), tx.clone()));
+                    if is_new {
+                        admitted.push(tx.clone());
+                    }
                 }
             }
         }
@@ -818,6 +851,16 @@ impl Relayer {
         // have to forward them.
         for tx in network_result.uploaded_transactions.iter() {
             ret.push((vec![], tx.clone()));
+            admitted.push(tx.clone());
+        }
+
+        // fetched from a peer's mempool.  Store them, but don't forward them -- they were already
+        // relayed when they were first pushed to the network.
+        for tx in network_result.synced_transactions.iter() {
+            let is_new = !mempool.has_tx(&tx.txid());
+            if is_new && Relayer::store_transaction(mempool, &burn_header_hash, &block_hash, tx.clone()) {
+                admitted.push(tx.clone());
+            }
         }
 
         // garbage-collect 
@@ -830,24 +873,110 @@ impl Relayer {
             mempool_tx.commit()?;
         }
 
-        Ok(ret)
+        Ok((ret, admitted))
+    }
+
+    /// Rebuild pushed compact blocks from the mempool.  Blocks that we rebuild are added to the
+    /// network result's pushed blocks, as if the sender had pushed the whole block.  Returns
+    /// * list of compact blocks we rebuilt, as well as their relayers (so we can forward them)
+    /// * list of compact blocks we couldn't finish, so we can ask their senders for the missing
+    /// transactions
+    /// Compact blocks for blocks we already have are dropped.
+    pub fn reconstruct_compact_blocks(network_result: &mut NetworkResult, chainstate: &StacksChainState, mempool: &MemPoolDB)
+                                      -> Result<(Vec<(Vec<RelayData>, CompactBlockData)>, Vec<PartialBlock>), net_error> {
+        let mut rebuilt = vec![];
+        let mut incomplete = vec![];
+        let mut seen = HashSet::new();
+        let mut short_txid_index = None;
+
+        for (neighbor_key, compact_blocks) in network_result.pushed_compact_blocks.drain() {
+            for (relayers, compact_block) in compact_blocks.into_iter() {
+                let index_block_hash = compact_block.index_block_hash();
+                if seen.contains(&index_block_hash) || StacksChainState::has_block_indexed(&chainstate.blocks_path, &index_block_hash)? {
+                    test_debug!("Already have compact block {}", &index_block_hash);
+                    continue;
+                }
+                seen.insert(index_block_hash.clone());
+
+                let mut partial_block = PartialBlock::new(neighbor_key.clone(), relayers, compact_block);
+                if short_txid_index.is_none() {
+                    short_txid_index = Some(MemPoolShortTxidIndex::new(mempool)?);
+                }
+                if let Some(ref mut index) = short_txid_index {
+                    partial_block.fill_from_mempool(mempool, index)?;
+                }
+
+                match partial_block.try_assemble() {
+                    Ok(Some(block)) => {
+                        debug!("Rebuilt compact block {} from mempool", &index_block_hash);
+                        let blocks_data = BlocksData { blocks: vec![(partial_block.data.burn_header_hash.clone(), block)] };
+                        if let Some(blocks_msgs) = network_result.pushed_blocks.get_mut(&neighbor_key) {
+                            blocks_msgs.push(blocks_data);
+                        }
+                        else {
+                            network_result.pushed_blocks.insert(neighbor_key.clone(), vec![blocks_data]);
+                        }
+                        rebuilt.push((partial_block.relayers, partial_block.data));
+                    },
+                    Ok(None) => {
+                        debug!("Compact block {} is missing {} transaction(s)", &index_block_hash, partial_block.missing_indexes().len());
+                        incomplete.push(partial_block);
+                    },
+                    Err(e) => {
+                        // one of our mempool transactions matched the wrong short txid (or the
+                        // compact block is bad).  Ask the sender for all of the transactions.
+                        debug!("Failed to rebuild compact block {} from mempool: {:?}", &index_block_hash, &e);
+                        let partial_block = PartialBlock::new(neighbor_key.clone(), partial_block.relayers, partial_block.data);
+                        incomplete.push(partial_block);
+                    }
+                }
+            }
+        }
+
+        Ok((rebuilt, incomplete))
     }
 
     pub fn advertize_blocks(&mut self, available: BlocksAvailableMap) -> Result<(), net_error> {
         self.p2p.advertize_blocks(available)
     }
 
+    /// Push a block we just produced to our neighbors as a compact block.
+    pub fn broadcast_compact_block(&mut self, burn_header_hash: &BurnchainHeaderHash, block: &StacksBlock) -> Result<(), net_error> {
+        let seed = util::with_rng(|rng| rng.gen::<u64>());
+        let compact_block = CompactBlockData::from_block(burn_header_hash, block, seed);
+        self.p2p.broadcast_message(vec![], StacksMessageType::CompactBlock(compact_block))
+    }
+
+    /// Have the p2p thread push events out to RPC websocket subscribers
+    pub fn publish_events(&mut self, events: Vec<RPCEvent>) -> Result<(), net_error> {
+        self.p2p.publish_events(events)
+    }
+
     /// Given a network result, consume and store all data.
+    /// * Rebuild pushed compact blocks from the mempool, and ask for the transactions we're missing.
     /// * Add all blocks and microblocks to staging.
+    /// * Forward rebuilt compact blocks that we didn't already have
     /// * Forward BlocksAvailable messages to neighbors for newly-discovered anchored blocks
     /// * Forward MicroblocksAvailable messages to neighbors for newly-discovered confirmed microblock streams
     /// * Forward along unconfirmed microblocks that we didn't already have
     /// * Add all transactions to the mempool.
     /// * Forward transactions we didn't already have.
+    /// * Tell RPC websocket subscribers about new blocks, microblocks, and transactions.
     /// Mask errors from invalid data -- all errors due to invalid blocks and invalid data should be captured, and
     /// turned into peer bans.
     pub fn process_network_result(&mut self, _local_peer: &LocalPeer, network_result: &mut NetworkResult, burndb: &mut BurnDB, chainstate: &mut StacksChainState, mempool: &mut MemPoolDB)
                                   -> Result<Vec<(StacksHeaderInfo, Vec<StacksTransactionReceipt>)>, net_error> {
+        let mut events = vec![];
+
+        // rebuild what compact blocks we can, and ask for the rest of the transactions we need
+        let (rebuilt_compact_blocks, incomplete_compact_blocks) = Relayer::reconstruct_compact_blocks(network_result, chainstate, mempool)?;
+        if incomplete_compact_blocks.len() > 0 {
+            debug!("{:?}: Fetch transactions for {} compact block(s)", &_local_peer, incomplete_compact_blocks.len());
+            if let Err(e) = self.p2p.fetch_block_txs(incomplete_compact_blocks) {
+                warn!("Failed to fetch compact block transactions: {:?}", &e);
+            }
+        }
+
         let receipts = match Relayer::process_new_blocks(network_result, burndb, chainstate) {
             Ok((new_blocks, new_confirmed_microblocks, mut new_microblocks, bad_block_neighbors, receipts)) => {
                 // attempt to relay messages (note that this is all best-effort).
@@ -857,6 +986,18 @@ impl Relayer {
                     warn!("Failed to ban bad-block peers: {:?}", &e);
                 }
 
+                // have the p2p thread forward the compact blocks we rebuilt that were new to us
+                for (relayers, compact_block) in rebuilt_compact_blocks.into_iter() {
+                    if !new_blocks.contains(&compact_block.burn_header_hash) {
+                        continue;
+                    }
+                    test_debug!("{:?}: Send compact block {}", &_local_peer, &compact_block.index_block_hash());
+                    let msg = StacksMessageType::CompactBlock(compact_block);
+                    if let Err(e) = self.p2p.broadcast_message(relayers, msg) {
+                        warn!("Failed to broadcast compact block: {:?}", &e);
+                    }
+                }
+
                 // have the p2p thread tell our neighbors about newly-discovered blocks
                 let available = Relayer::load_blocks_available_data(burndb, new_blocks)?;
                 test_debug!("{:?}: Blocks available: {}", &_local_peer, available.len());
@@ -874,6 +1015,7 @@ impl Relayer {
                 // have the p2p thread forward all new unconfirmed microblocks
                 for (relayers, mblocks_msg) in new_microblocks.drain(..) {
                     test_debug!("{:?}: Send {} microblocks for {}", &_local_peer, mblocks_msg.microblocks.len(), &mblocks_msg.index_anchor_block);
+                    events.push(RPCEvent::from_microblocks(&mblocks_msg.index_anchor_block, &mblocks_msg.microblocks));
                     let msg = StacksMessageType::Microblocks(mblocks_msg);
                     if let Err(e) = self.p2p.broadcast_message(relayers, msg) {
                         warn!("Failed to broadcast microblock: {:?}", &e);
@@ -898,7 +1040,7 @@ impl Relayer {
 
         // store all transactions, and forward the novel ones to neighbors
         test_debug!("{:?}: Process {} transaction(s)", &_local_peer, network_result.pushed_transactions.len());
-        let mut new_txs = Relayer::process_transactions(network_result, burndb, chainstate, mempool)?;
+        let (mut new_txs, admitted_txs) = Relayer::process_transactions(network_result, burndb, chainstate, mempool)?;
 
         if new_txs.len() > 0 {
             debug!("{:?}: Send {} transactions to neighbors", &_local_peer, new_txs.len());
@@ -912,6 +1054,19 @@ impl Relayer {
             }
         }
 
+        // tell websocket subscribers
+        for (header_info, tx_receipts) in receipts.iter() {
+            events.append(&mut RPCEvent::from_block_receipts(header_info, tx_receipts));
+        }
+        for tx in admitted_txs.iter() {
+            events.append(&mut RPCEvent::from_mempool_tx(tx));
+        }
+        if events.len() > 0 {
+            if let Err(e) = self.p2p.publish_events(events) {
+                warn!("Failed to publish RPC events: {:?}", &e);
+            }
+        }
+
         Ok(receipts)
     }
 }
@@ -964,7 +1119,8 @@ impl PeerNetwork {
 
         let inbound_recipients =
             if inbound_recipients_unshuffled.len() > MAX_BROADCAST_INBOUND_RECEIVERS {
-                &mut inbound_recipients_unshuffled[..].shuffle(&mut thread_rng());
+                inbound_recipients_unshuffled.sort();
+                util::with_rng(|rng| inbound_recipients_unshuffled[..].shuffle(rng));
                 inbound_recipients_unshuffled[0..MAX_BROADCAST_INBOUND_RECEIVERS].to_vec()
             }
             else {
@@ -1098,6 +1254,7 @@ impl PeerNetwork {
 
     /// Update accounting information for relayed messages from a network result.
     /// This influences selecting next-hop neighbors to get data from us.
+    /// Neighbors that send us the same message more than once get penalized.
     pub fn update_relayer_stats(&mut self, network_result: &NetworkResult) -> () {
         // synchronize
         for (_, convo) in self.peers.iter_mut() {
@@ -1105,9 +1262,14 @@ impl PeerNetwork {
             self.relayer_stats.merge_relay_stats(stats);
         }
 
+        let mut duplicates = vec![];
+
         for (nk, blocks_data) in network_result.pushed_blocks.iter() {
             for block_msg in blocks_data.iter() {
                 for (_, block) in block_msg.blocks.iter() {
+                    if self.relayer_stats.has_relayed_message(nk, block) {
+                        duplicates.push((*nk).clone());
+                    }
                     self.relayer_stats.add_relayed_message((*nk).clone(), block);
                 }
             }
@@ -1116,6 +1278,9 @@ impl PeerNetwork {
         for (nk, microblocks_data) in network_result.pushed_microblocks.iter() {
             for (_, microblock_msg) in microblocks_data.iter() {
                 for mblock in microblock_msg.microblocks.iter() {
+                    if self.relayer_stats.has_relayed_message(nk, mblock) {
+                        duplicates.push((*nk).clone());
+                    }
                     self.relayer_stats.add_relayed_message((*nk).clone(), mblock);
                 }
             }
@@ -1123,9 +1288,19 @@ impl PeerNetwork {
 
         for (nk, txs) in network_result.pushed_transactions.iter() {
             for (_, tx) in txs.iter() {
+                if self.relayer_stats.has_relayed_message(nk, tx) {
+                    duplicates.push((*nk).clone());
+                }
                 self.relayer_stats.add_relayed_message((*nk).clone(), tx);
             }
         }
+
+        for nk in duplicates.into_iter() {
+            if let Some(event_id) = self.events.get(&nk).cloned() {
+                debug!("{:?}: Neighbor {:?} relayed a duplicate message", &self.local_peer, &nk);
+                self.penalize_peer(event_id, PeerOffense::RelayDuplicate);
+            }
+        }
     }
 }
 
@@ -1155,6 +1330,9 @@ mod test {
     use vm::costs::LimitedCostTracker;
     use vm::database::ClarityDatabase;
     use vm::clarity::ClarityConnection;
+    use chainstate::stacks::db::test::{instantiate_chainstate, chainstate_path};
+    use net::compact::test::make_block_with_txs;
+    use net::mempool::test::{make_mempool_txs, submit_mempool_txs};
 
     use util::sleep_ms;
 
@@ -1971,6 +2149,62 @@ mod test {
     }
 
 // TODO: process bans
+    #[test]
+    fn test_relay_reconstruct_compact_blocks() {
+        let chainstate = instantiate_chainstate(false, 0x80000000, "test_relay_reconstruct_compact_blocks");
+        let mut mempool = MemPoolDB::open(false, 0x80000000, &chainstate_path("test_relay_reconstruct_compact_blocks")).unwrap();
+
+        let mempool_txs = make_mempool_txs(5);
+        submit_mempool_txs(&mut mempool, &mempool_txs[0..4].to_vec());
+
+        let coinbase = make_codec_test_block(1).txs[0].clone();
+
+        // everything but the coinbase is in our mempool
+        let mut complete_txs = vec![coinbase.clone()];
+        complete_txs.append(&mut mempool_txs[0..3].to_vec());
+        let complete_block = make_block_with_txs(complete_txs);
+
+        // the last transaction is not in our mempool
+        let mut incomplete_txs = vec![coinbase.clone()];
+        incomplete_txs.append(&mut mempool_txs[3..5].to_vec());
+        let incomplete_block = make_block_with_txs(incomplete_txs);
+
+        let nk_1 = NeighborKey {
+            peer_version: 0x12345678,
+            network_id: 0x80000000,
+            addrbytes: PeerAddress([0x01; 16]),
+            port: 12345,
+        };
+        let nk_2 = NeighborKey {
+            port: 12346,
+            ..nk_1.clone()
+        };
+
+        let complete_compact = CompactBlockData::from_block(&BurnchainHeaderHash([0x11; 32]), &complete_block, 0x0102030405060708);
+        let incomplete_compact = CompactBlockData::from_block(&BurnchainHeaderHash([0x22; 32]), &incomplete_block, 0x1112131415161718);
+
+        // both neighbors push the complete block, so it only gets rebuilt once
+        let mut network_result = NetworkResult::new();
+        network_result.pushed_compact_blocks.insert(nk_1.clone(), vec![(vec![], complete_compact.clone()), (vec![], incomplete_compact.clone())]);
+        network_result.pushed_compact_blocks.insert(nk_2.clone(), vec![(vec![], complete_compact.clone())]);
+
+        let (rebuilt, incomplete) = Relayer::reconstruct_compact_blocks(&mut network_result, &chainstate, &mempool).unwrap();
+        assert_eq!(network_result.pushed_compact_blocks.len(), 0);
+
+        assert_eq!(rebuilt.len(), 1);
+        assert_eq!(rebuilt[0].1, complete_compact);
+
+        let num_pushed : usize = network_result.pushed_blocks.values().map(|blocks_msgs| blocks_msgs.len()).sum();
+        assert_eq!(num_pushed, 1);
+        let pushed = network_result.pushed_blocks.values().next().unwrap();
+        assert_eq!(pushed[0].blocks, vec![(BurnchainHeaderHash([0x11; 32]), complete_block.clone())]);
+
+        assert_eq!(incomplete.len(), 1);
+        assert_eq!(incomplete[0].neighbor, nk_1);
+        assert_eq!(incomplete[0].data, incomplete_compact);
+        assert_eq!(incomplete[0].missing_indexes(), vec![2]);
+    }
+
 // TODO: test sending invalid blocks-available and microblocks-available (should result in a ban)
 // TODO: test sending invalid transactions (should result in a ban)
 // TODO: test bandwidth limits (sending too much should result in a nack, and then a ban)
@@ -0,0 +1,637 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/// This module implements peer reputations.  Each offense a neighbor commits (sending an invalid
+/// block, botching a handshake, relaying us the same message twice, letting a request time out)
+/// adds to its misbehavior score in the `frontier` table.  Scores decay over time, and a neighbor
+/// whose score reaches the ban threshold gets banned.  Relaying duplicates and timing out only
+/// ever take a score halfway to the threshold, since honest peers on bad links do both.  Operators can also inspect scores and
+/// ban, unban, and allow-list peers by hand, via the RPC interface or the CLI.
+
+use std::cmp;
+use std::net::SocketAddr;
+
+use rusqlite::Transaction;
+
+use net::*;
+use net::Error as net_error;
+use net::db::{PeerDB, PeerReputation, PeerAdminRule};
+use net::p2p::PeerNetwork;
+
+use util::db::Error as db_error;
+use util::db::DBConn;
+use util::hash::{hex_bytes, to_hex};
+use util::secp256k1::Secp256k1PublicKey;
+use util::get_epoch_time_secs;
+use util::log;
+
+// score at which a neighbor gets banned
+pub const REPUTATION_BAN_THRESHOLD : u64 = 100;
+
+// number of seconds it takes for a neighbor's score to drop by one point
+pub const REPUTATION_DECAY_INTERVAL : u64 = 36;     // 100 points per hour
+
+pub const REPUTATION_PENALTY_INVALID_BLOCK : u64 = 100;
+pub const REPUTATION_PENALTY_BAD_HANDSHAKE : u64 = 25;
+pub const REPUTATION_PENALTY_RELAY_DUPLICATE : u64 = 2;
+pub const REPUTATION_PENALTY_TIMEOUT : u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerOffense {
+    InvalidBlock,
+    BadHandshake,
+    RelayDuplicate,
+    Timeout
+}
+
+impl PeerOffense {
+    pub fn penalty(&self) -> u64 {
+        match *self {
+            PeerOffense::InvalidBlock => REPUTATION_PENALTY_INVALID_BLOCK,
+            PeerOffense::BadHandshake => REPUTATION_PENALTY_BAD_HANDSHAKE,
+            PeerOffense::RelayDuplicate => REPUTATION_PENALTY_RELAY_DUPLICATE,
+            PeerOffense::Timeout => REPUTATION_PENALTY_TIMEOUT
+        }
+    }
+
+    /// Minor offenses are the ones that honest peers on slow or lossy links commit too
+    pub fn is_minor(&self) -> bool {
+        match *self {
+            PeerOffense::RelayDuplicate | PeerOffense::Timeout => true,
+            _ => false
+        }
+    }
+}
+
+/// How a peer is identified by an operator
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerSelector {
+    Address(PeerAddress, u16),
+    PublicKey(Secp256k1PublicKey)
+}
+
+impl PeerSelector {
+    /// Parse either an IP:port or a hex-encoded public key
+    pub fn parse(s: &str) -> Result<PeerSelector, net_error> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(PeerSelector::Address(PeerAddress::from_socketaddr(&addr), addr.port()));
+        }
+
+        let bytes = hex_bytes(s)
+            .map_err(|_e| net_error::DeserializeError(format!("Not an IP:port or a public key: {}", s)))?;
+
+        let public_key = Secp256k1PublicKey::from_slice(&bytes)
+            .map_err(|_e| net_error::DeserializeError(format!("Invalid public key: {}", s)))?;
+
+        Ok(PeerSelector::PublicKey(public_key))
+    }
+}
+
+/// What an operator can do to a peer.  Ban and allow-list durations are in seconds; None means
+/// forever.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerAdminAction {
+    Ban(Option<u64>),
+    Unban,
+    Allow(Option<u64>),
+    Disallow
+}
+
+impl PeerAdminAction {
+    /// Make an action out of its name, as it appears in the RPC path or on the command line
+    pub fn from_name(name: &str, duration: Option<u64>) -> Option<PeerAdminAction> {
+        match name {
+            "ban" => Some(PeerAdminAction::Ban(duration)),
+            "unban" => Some(PeerAdminAction::Unban),
+            "allow" => Some(PeerAdminAction::Allow(duration)),
+            "disallow" => Some(PeerAdminAction::Disallow),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PeerAdminAction::Ban(_) => "ban",
+            PeerAdminAction::Unban => "unban",
+            PeerAdminAction::Allow(_) => "allow",
+            PeerAdminAction::Disallow => "disallow"
+        }
+    }
+
+    pub fn duration(&self) -> Option<u64> {
+        match *self {
+            PeerAdminAction::Ban(ref duration) | PeerAdminAction::Allow(ref duration) => duration.clone(),
+            _ => None
+        }
+    }
+}
+
+/// Decay a score that was last updated at `score_updated`.
+/// Scores drop by one point every `decay_interval` seconds (0 means scores never decay).
+pub fn decay_reputation(score: u64, score_updated: u64, now: u64, decay_interval: u64) -> u64 {
+    if decay_interval == 0 || now <= score_updated {
+        return score;
+    }
+    score.saturating_sub((now - score_updated) / decay_interval)
+}
+
+/// Add an offense's penalty to a (decayed) score.  Minor offenses can only raise a score up to
+/// half the ban threshold, so a peer that is merely slow never gets banned for them alone.
+pub fn penalize_reputation(score: u64, offense: &PeerOffense, threshold: u64) -> u64 {
+    let penalized = score.saturating_add(offense.penalty());
+    if offense.is_minor() {
+        let cap = cmp::max(score, threshold / 2);
+        return cmp::min(penalized, cap);
+    }
+    penalized
+}
+
+/// Get all peer reputations in a network, with their scores decayed to the present.
+pub fn get_decayed_reputations(conn: &DBConn, network_id: u32, decay_interval: u64) -> Result<Vec<PeerReputation>, db_error> {
+    let now = get_epoch_time_secs();
+    let mut reputations = PeerDB::get_peer_reputations(conn, network_id)?;
+    for reputation in reputations.iter_mut() {
+        reputation.score = decay_reputation(reputation.score, reputation.score_updated, now, decay_interval);
+    }
+    reputations.sort_by(|r1, r2| r2.score.cmp(&r1.score));
+    Ok(reputations)
+}
+
+/// Carry out an operator's action on the peers matched by the selector, and remember it as a rule
+/// for the selector so it also applies to matching peers we haven't met yet.  Unban and disallow
+/// lift the ban or allow-listing that the rule for this same selector set.
+/// Returns the keys of the frontier peers that were changed.
+pub fn apply_peer_admin_action<'a>(tx: &mut Transaction<'a>, network_id: u32, selector: &PeerSelector, action: &PeerAdminAction) -> Result<Vec<NeighborKey>, db_error> {
+    let neighbors = match *selector {
+        PeerSelector::Address(ref addrbytes, ref port) => PeerDB::get_peer(tx, network_id, addrbytes, *port)?.into_iter().collect(),
+        PeerSelector::PublicKey(ref public_key) => PeerDB::find_peers_by_public_key(tx, network_id, public_key)?
+    };
+
+    let now = get_epoch_time_secs();
+    let mut changed = vec![];
+    for neighbor in neighbors.into_iter() {
+        let nk = neighbor.addr;
+        match *action {
+            PeerAdminAction::Ban(ref duration) => {
+                // a manual ban overrides the allow-list
+                let deadline = match duration {
+                    Some(secs) => now.saturating_add(*secs).min(i64::max_value() as u64),
+                    None => i64::max_value() as u64
+                };
+                PeerDB::set_whitelist_peer(tx, nk.network_id, &nk.addrbytes, nk.port, 0)?;
+                PeerDB::set_blacklist_peer(tx, nk.network_id, &nk.addrbytes, nk.port, deadline)?;
+            },
+            PeerAdminAction::Unban => {
+                PeerDB::set_blacklist_peer(tx, nk.network_id, &nk.addrbytes, nk.port, 0)?;
+                PeerDB::set_peer_reputation(tx, nk.network_id, &nk.addrbytes, nk.port, 0, now)?;
+            },
+            PeerAdminAction::Allow(ref duration) => {
+                let deadline = match duration {
+                    Some(secs) => now.saturating_add(*secs).min(i64::max_value() as u64) as i64,
+                    None => -1
+                };
+                PeerDB::set_blacklist_peer(tx, nk.network_id, &nk.addrbytes, nk.port, 0)?;
+                PeerDB::set_whitelist_peer(tx, nk.network_id, &nk.addrbytes, nk.port, deadline)?;
+            },
+            PeerAdminAction::Disallow => {
+                PeerDB::set_whitelist_peer(tx, nk.network_id, &nk.addrbytes, nk.port, 0)?;
+            }
+        }
+
+        debug!("Peer admin: {} {:?}", action.name(), &nk);
+        changed.push(nk);
+    }
+
+    let rule_opt = match *selector {
+        PeerSelector::Address(ref addrbytes, ref port) => PeerDB::get_address_rule(tx, network_id, addrbytes, *port)?,
+        PeerSelector::PublicKey(ref public_key) => PeerDB::get_public_key_rule(tx, network_id, public_key)?
+    };
+    let mut rule = rule_opt.unwrap_or(PeerAdminRule { whitelisted: None, blacklisted: None });
+    match *action {
+        PeerAdminAction::Ban(ref duration) => {
+            let deadline = match duration {
+                Some(secs) => now.saturating_add(*secs).min(i64::max_value() as u64) as i64,
+                None => i64::max_value()
+            };
+            rule.whitelisted = Some(0);
+            rule.blacklisted = Some(deadline);
+        },
+        PeerAdminAction::Unban => {
+            // a cleared allow-listing only came from the ban
+            rule.blacklisted = None;
+            if rule.whitelisted == Some(0) {
+                rule.whitelisted = None;
+            }
+        },
+        PeerAdminAction::Allow(ref duration) => {
+            let deadline = match duration {
+                Some(secs) => now.saturating_add(*secs).min(i64::max_value() as u64) as i64,
+                None => -1
+            };
+            rule.whitelisted = Some(deadline);
+            rule.blacklisted = Some(0);
+        },
+        PeerAdminAction::Disallow => {
+            // a cleared ban only came from the allow-listing
+            rule.whitelisted = None;
+            if rule.blacklisted == Some(0) {
+                rule.blacklisted = None;
+            }
+        }
+    }
+
+    match *selector {
+        PeerSelector::Address(ref addrbytes, ref port) => PeerDB::set_address_rule(tx, network_id, addrbytes, *port, &rule)?,
+        PeerSelector::PublicKey(ref public_key) => PeerDB::set_public_key_rule(tx, network_id, public_key, &rule)?
+    };
+
+    Ok(changed)
+}
+
+impl RPCPeerReputation {
+    pub fn from_reputation(reputation: &PeerReputation) -> RPCPeerReputation {
+        RPCPeerReputation {
+            network_id: reputation.addr.network_id,
+            peer_version: reputation.addr.peer_version,
+            addrbytes: reputation.addr.addrbytes.clone(),
+            port: reputation.addr.port,
+            public_key: to_hex(&reputation.public_key.to_bytes_compressed()),
+            score: reputation.score,
+            whitelisted: reputation.whitelisted,
+            blacklisted: reputation.blacklisted
+        }
+    }
+}
+
+impl PeerNetwork {
+    /// Record that a neighbor misbehaved.  Its score gets updated the next time we process bans.
+    pub fn penalize_peer(&mut self, event_id: usize, offense: PeerOffense) -> () {
+        test_debug!("{:?}: penalize event {} for {:?}", &self.local_peer, event_id, &offense);
+        self.penalties.push((event_id, offense));
+    }
+
+    /// Fold all pending penalties into our neighbors' scores, and schedule a ban for each
+    /// neighbor whose score reached the ban threshold.  Allow-listed neighbors are never penalized.
+    /// Neighbors that aren't in the frontier have nowhere to keep a score, so they only get banned
+    /// if a single offense is bad enough.
+    pub fn process_penalties(&mut self) -> Result<(), net_error> {
+        let mut penalties = vec![];
+        penalties.append(&mut self.penalties);
+        for (event_id, convo) in self.peers.iter_mut() {
+            for offense in convo.get_stats_mut().take_offenses().into_iter() {
+                penalties.push((*event_id, offense));
+            }
+        }
+
+        if penalties.len() == 0 {
+            return Ok(());
+        }
+
+        let threshold = self.connection_opts.reputation_ban_threshold;
+        let decay_interval = self.connection_opts.reputation_decay_interval;
+        let now = get_epoch_time_secs();
+
+        let mut tx = self.peerdb.tx_begin()?;
+        for (event_id, offense) in penalties.into_iter() {
+            let nk = match self.peers.get(&event_id) {
+                Some(convo) => convo.to_neighbor_key(),
+                None => {
+                    continue;
+                }
+            };
+
+            match PeerDB::get_peer_reputation(&tx, nk.network_id, &nk.addrbytes, nk.port)? {
+                Some(reputation) => {
+                    if reputation.whitelisted < 0 || (reputation.whitelisted as u64) > now {
+                        debug!("{:?}: Neighbor {:?} is whitelisted; will not penalize for {:?}", &self.local_peer, &nk, &offense);
+                        continue;
+                    }
+
+                    let score = penalize_reputation(decay_reputation(reputation.score, reputation.score_updated, now, decay_interval), &offense, threshold);
+                    debug!("{:?}: Neighbor {:?} penalized for {:?}; score is now {}", &self.local_peer, &nk, &offense, score);
+
+                    PeerDB::set_peer_reputation(&mut tx, nk.network_id, &nk.addrbytes, nk.port, score, now)?;
+                    if score >= threshold {
+                        info!("{:?}: Neighbor {:?} has score {} (threshold {}); will ban", &self.local_peer, &nk, score, threshold);
+                        self.bans.insert(event_id);
+                    }
+                },
+                None => {
+                    if offense.penalty() >= threshold {
+                        info!("{:?}: Neighbor {:?} not in peer DB, but will ban for {:?}", &self.local_peer, &nk, &offense);
+                        self.bans.insert(event_id);
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Find the neighbors we're connected to that have since been banned out-of-band (i.e. by an
+    /// operator).  Returns their event IDs.
+    pub fn find_blacklisted_peers(&self) -> Result<Vec<usize>, net_error> {
+        let mut ret = vec![];
+        for (event_id, convo) in self.peers.iter() {
+            let nk = convo.to_neighbor_key();
+            if let Some(neighbor) = PeerDB::get_peer(self.peerdb.conn(), nk.network_id, &nk.addrbytes, nk.port)? {
+                if neighbor.is_blacklisted() {
+                    ret.push(*event_id);
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use net::db::*;
+    use net::test::*;
+    use util::secp256k1::Secp256k1PrivateKey;
+    use util::strings::UrlString;
+    use std::convert::TryFrom;
+
+    fn make_test_neighbor(port: u16, public_key: &Secp256k1PublicKey) -> Neighbor {
+        Neighbor {
+            addr: NeighborKey {
+                peer_version: 0x12345678,
+                network_id: 0x9abcdef0,
+                addrbytes: PeerAddress::from_ipv4(127, 0, 0, 1),
+                port: port,
+            },
+            public_key: public_key.clone(),
+            expire_block: 23456,
+            last_contact_time: 1552509642,
+            whitelisted: 0,
+            blacklisted: 0,
+            asn: 34567,
+            org: 45678,
+            in_degree: 1,
+            out_degree: 1
+        }
+    }
+
+    #[test]
+    fn test_decay_reputation() {
+        assert_eq!(decay_reputation(100, 1000, 1000, 10), 100);
+        assert_eq!(decay_reputation(100, 1000, 1009, 10), 100);
+        assert_eq!(decay_reputation(100, 1000, 1010, 10), 99);
+        assert_eq!(decay_reputation(100, 1000, 2000, 10), 0);
+
+        // clock went backwards
+        assert_eq!(decay_reputation(100, 1000, 500, 10), 100);
+
+        // no decay
+        assert_eq!(decay_reputation(100, 1000, 2000, 0), 100);
+    }
+
+    #[test]
+    fn test_penalize_reputation() {
+        assert_eq!(penalize_reputation(0, &PeerOffense::InvalidBlock, 100), 100);
+        assert_eq!(penalize_reputation(90, &PeerOffense::BadHandshake, 100), 115);
+        assert_eq!(penalize_reputation(0, &PeerOffense::Timeout, 100), 2);
+
+        // minor offenses stop at half the threshold...
+        assert_eq!(penalize_reputation(49, &PeerOffense::Timeout, 100), 50);
+        assert_eq!(penalize_reputation(50, &PeerOffense::RelayDuplicate, 100), 50);
+
+        // ...but don't lower a score that's already past it
+        assert_eq!(penalize_reputation(75, &PeerOffense::Timeout, 100), 75);
+        assert_eq!(penalize_reputation(75, &PeerOffense::BadHandshake, 100), 100);
+    }
+
+    /// Step both peers until the first one has an authenticated conversation with the second.
+    /// Returns its event ID.
+    fn connect_test_peers(peer_1: &mut TestPeer, peer_2: &mut TestPeer) -> usize {
+        let nk = peer_2.to_neighbor().addr;
+        for _ in 0..1000 {
+            let _ = peer_1.step();
+            let _ = peer_2.step();
+
+            let event_id_opt = peer_1.network.peers.iter()
+                .find(|(_, convo)| convo.is_authenticated() && convo.peer_addrbytes == nk.addrbytes && convo.peer_port == nk.port)
+                .map(|(event_id, _)| *event_id);
+
+            if let Some(event_id) = event_id_opt {
+                return event_id;
+            }
+        }
+        panic!("Peer 1 never connected to peer 2");
+    }
+
+    #[test]
+    fn test_penalties_ban_peer() {
+        let mut peer_1_config = TestPeerConfig::new("test_penalties_ban_peer", 32090, 42090);
+        let mut peer_2_config = TestPeerConfig::new("test_penalties_ban_peer", 32091, 42091);
+
+        peer_1_config.add_neighbor(&peer_2_config.to_neighbor());
+        peer_2_config.add_neighbor(&peer_1_config.to_neighbor());
+
+        let mut peer_1 = TestPeer::new(peer_1_config);
+        let mut peer_2 = TestPeer::new(peer_2_config);
+
+        let event_id = connect_test_peers(&mut peer_1, &mut peer_2);
+        let nk = peer_2.to_neighbor().addr;
+        let threshold = peer_1.network.connection_opts.reputation_ban_threshold;
+
+        // no amount of timeouts and duplicates gets peer 2 banned
+        for _ in 0..(2 * threshold) {
+            peer_1.network.penalize_peer(event_id, PeerOffense::Timeout);
+            peer_1.network.penalize_peer(event_id, PeerOffense::RelayDuplicate);
+        }
+        peer_1.network.process_penalties().unwrap();
+
+        assert!(peer_1.network.bans.is_empty());
+        let reputation = PeerDB::get_peer_reputation(peer_1.network.peerdb.conn(), nk.network_id, &nk.addrbytes, nk.port).unwrap().unwrap();
+        assert!(reputation.score <= threshold / 2);
+
+        // but an invalid block on top of them does
+        peer_1.network.penalize_peer(event_id, PeerOffense::InvalidBlock);
+        peer_1.network.process_penalties().unwrap();
+        assert!(peer_1.network.bans.contains(&event_id));
+
+        // peer 1 blacklists and disconnects peer 2 once it processes its bans
+        let mut round = 0;
+        while peer_1.network.peers.contains_key(&event_id) {
+            let _ = peer_1.step();
+            let _ = peer_2.step();
+
+            round += 1;
+            assert!(round < 1000, "peer 1 never disconnected peer 2");
+        }
+
+        let neighbor = PeerDB::get_peer(peer_1.network.peerdb.conn(), nk.network_id, &nk.addrbytes, nk.port).unwrap().unwrap();
+        assert!(neighbor.is_blacklisted());
+    }
+
+    #[test]
+    fn test_find_blacklisted_peers() {
+        let mut peer_1_config = TestPeerConfig::new("test_find_blacklisted_peers", 32092, 42092);
+        let mut peer_2_config = TestPeerConfig::new("test_find_blacklisted_peers", 32093, 42093);
+
+        peer_1_config.add_neighbor(&peer_2_config.to_neighbor());
+        peer_2_config.add_neighbor(&peer_1_config.to_neighbor());
+
+        let mut peer_1 = TestPeer::new(peer_1_config);
+        let mut peer_2 = TestPeer::new(peer_2_config);
+
+        let event_id = connect_test_peers(&mut peer_1, &mut peer_2);
+        let nk = peer_2.to_neighbor().addr;
+        assert_eq!(peer_1.network.find_blacklisted_peers().unwrap(), Vec::<usize>::new());
+
+        // an operator bans peer 2 while it's connected
+        {
+            let mut tx = peer_1.network.peerdb.tx_begin().unwrap();
+            apply_peer_admin_action(&mut tx, nk.network_id, &PeerSelector::Address(nk.addrbytes.clone(), nk.port), &PeerAdminAction::Ban(None)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        assert_eq!(peer_1.network.find_blacklisted_peers().unwrap(), vec![event_id]);
+    }
+
+    #[test]
+    fn test_peer_selector_parse() {
+        let public_key = Secp256k1PublicKey::from_private(&Secp256k1PrivateKey::new());
+
+        assert_eq!(PeerSelector::parse("127.0.0.1:20444").unwrap(), PeerSelector::Address(PeerAddress::from_ipv4(127, 0, 0, 1), 20444));
+        assert_eq!(PeerSelector::parse(&to_hex(&public_key.to_bytes_compressed())).unwrap(), PeerSelector::PublicKey(public_key.clone()));
+
+        assert!(PeerSelector::parse("127.0.0.1").is_err());
+        assert!(PeerSelector::parse("00").is_err());
+        assert!(PeerSelector::parse("nope").is_err());
+    }
+
+    #[test]
+    fn test_peer_admin_actions() {
+        let public_key_1 = Secp256k1PublicKey::from_private(&Secp256k1PrivateKey::new());
+        let public_key_2 = Secp256k1PublicKey::from_private(&Secp256k1PrivateKey::new());
+
+        // two peers share a key
+        let neighbor_1 = make_test_neighbor(20001, &public_key_1);
+        let neighbor_2 = make_test_neighbor(20002, &public_key_1);
+        let neighbor_3 = make_test_neighbor(20003, &public_key_2);
+
+        let mut db = PeerDB::connect_memory(0x9abcdef0, 0, 23456, UrlString::try_from("http://foo.com").unwrap(), &vec![], &vec![neighbor_1.clone(), neighbor_2.clone(), neighbor_3.clone()]).unwrap();
+
+        {
+            let mut tx = db.tx_begin().unwrap();
+            PeerDB::set_peer_reputation(&mut tx, 0x9abcdef0, &neighbor_3.addr.addrbytes, neighbor_3.addr.port, 50, get_epoch_time_secs()).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let reputations = get_decayed_reputations(db.conn(), 0x9abcdef0, REPUTATION_DECAY_INTERVAL).unwrap();
+        assert_eq!(reputations.len(), 3);
+        assert_eq!(reputations[0].addr, neighbor_3.addr);
+        assert_eq!(reputations[0].score, 50);
+        assert_eq!(reputations[1].score, 0);
+        assert_eq!(reputations[2].score, 0);
+
+        // ban by public key hits both peers that use it
+        {
+            let mut tx = db.tx_begin().unwrap();
+            let changed = apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::PublicKey(public_key_1.clone()), &PeerAdminAction::Ban(None)).unwrap();
+            tx.commit().unwrap();
+            assert_eq!(changed.len(), 2);
+        }
+
+        assert!(PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_1.addr.addrbytes, neighbor_1.addr.port).unwrap().unwrap().is_blacklisted());
+        assert!(PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_2.addr.addrbytes, neighbor_2.addr.port).unwrap().unwrap().is_blacklisted());
+        assert!(!PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_3.addr.addrbytes, neighbor_3.addr.port).unwrap().unwrap().is_blacklisted());
+
+        // unban by address only hits that peer, and clears its score
+        {
+            let mut tx = db.tx_begin().unwrap();
+            let changed = apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::Address(neighbor_1.addr.addrbytes.clone(), neighbor_1.addr.port), &PeerAdminAction::Unban).unwrap();
+            assert_eq!(changed, vec![neighbor_1.addr.clone()]);
+
+            let changed = apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::Address(neighbor_3.addr.addrbytes.clone(), neighbor_3.addr.port), &PeerAdminAction::Unban).unwrap();
+            assert_eq!(changed, vec![neighbor_3.addr.clone()]);
+            tx.commit().unwrap();
+        }
+
+        assert!(!PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_1.addr.addrbytes, neighbor_1.addr.port).unwrap().unwrap().is_blacklisted());
+        assert!(PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_2.addr.addrbytes, neighbor_2.addr.port).unwrap().unwrap().is_blacklisted());
+        assert_eq!(PeerDB::get_peer_reputation(db.conn(), 0x9abcdef0, &neighbor_3.addr.addrbytes, neighbor_3.addr.port).unwrap().unwrap().score, 0);
+
+        // allow-listing lifts the ban, and banning again lifts the allow-listing
+        {
+            let mut tx = db.tx_begin().unwrap();
+            apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::Address(neighbor_2.addr.addrbytes.clone(), neighbor_2.addr.port), &PeerAdminAction::Allow(None)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let peer_2 = PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_2.addr.addrbytes, neighbor_2.addr.port).unwrap().unwrap();
+        assert!(peer_2.is_whitelisted());
+        assert!(!peer_2.is_blacklisted());
+
+        {
+            let mut tx = db.tx_begin().unwrap();
+            apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::Address(neighbor_2.addr.addrbytes.clone(), neighbor_2.addr.port), &PeerAdminAction::Ban(Some(3600))).unwrap();
+            tx.commit().unwrap();
+        }
+
+        let peer_2 = PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_2.addr.addrbytes, neighbor_2.addr.port).unwrap().unwrap();
+        assert!(!peer_2.is_whitelisted());
+        assert!(peer_2.is_blacklisted());
+        assert!((peer_2.blacklisted as u64) <= get_epoch_time_secs() + 3600);
+
+        // actions on unknown peers change no frontier rows, but are remembered for when the
+        // peers show up
+        let public_key_4 = Secp256k1PublicKey::from_private(&Secp256k1PrivateKey::new());
+        let mut neighbor_4 = make_test_neighbor(20004, &public_key_4);
+        neighbor_4.addr.addrbytes = PeerAddress::from_ipv4(1, 2, 3, 4);
+
+        let public_key_5 = Secp256k1PublicKey::from_private(&Secp256k1PrivateKey::new());
+        let neighbor_5 = make_test_neighbor(20005, &public_key_5);
+        {
+            let mut tx = db.tx_begin().unwrap();
+            let changed = apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::Address(neighbor_4.addr.addrbytes.clone(), neighbor_4.addr.port), &PeerAdminAction::Ban(None)).unwrap();
+            assert_eq!(changed.len(), 0);
+
+            let changed = apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::PublicKey(public_key_5.clone()), &PeerAdminAction::Allow(Some(3600))).unwrap();
+            assert_eq!(changed.len(), 0);
+            tx.commit().unwrap();
+        }
+
+        assert!(PeerDB::get_address_rule(db.conn(), 0x9abcdef0, &neighbor_4.addr.addrbytes, neighbor_4.addr.port).unwrap().unwrap().is_blacklisted());
+
+        {
+            let mut tx = db.tx_begin().unwrap();
+            assert!(PeerDB::try_insert_peer(&mut tx, &neighbor_4).unwrap());
+            assert!(PeerDB::try_insert_peer(&mut tx, &neighbor_5).unwrap());
+            tx.commit().unwrap();
+        }
+
+        assert!(PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_4.addr.addrbytes, neighbor_4.addr.port).unwrap().unwrap().is_blacklisted());
+        assert!(PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_5.addr.addrbytes, neighbor_5.addr.port).unwrap().unwrap().is_whitelisted());
+
+        // unbanning lifts the rule too
+        {
+            let mut tx = db.tx_begin().unwrap();
+            let changed = apply_peer_admin_action(&mut tx, 0x9abcdef0, &PeerSelector::Address(neighbor_4.addr.addrbytes.clone(), neighbor_4.addr.port), &PeerAdminAction::Unban).unwrap();
+            assert_eq!(changed, vec![neighbor_4.addr.clone()]);
+            tx.commit().unwrap();
+        }
+
+        assert!(!PeerDB::get_peer(db.conn(), 0x9abcdef0, &neighbor_4.addr.addrbytes, neighbor_4.addr.port).unwrap().unwrap().is_blacklisted());
+        assert!(PeerDB::get_address_rule(db.conn(), 0x9abcdef0, &neighbor_4.addr.addrbytes, neighbor_4.addr.port).unwrap().is_none());
+    }
+}
@@ -32,6 +32,7 @@ use net::StacksMessageCodec;
 use net::MAX_NEIGHBORS_DATA_LEN;
 use net::StacksHttpMessage;
 use net::HttpRequestType;
+use net::TipRequest;
 use net::HttpResponseType;
 use net::HttpRequestMetadata;
 use net::HttpResponseMetadata;
@@ -51,7 +52,17 @@ use net::db::PeerDB;
 use net::p2p::PeerNetwork;
 use net::{ RPCNeighbor, RPCNeighborsInfo };
 use net::{ MapEntryResponse, AccountEntryResponse, CallReadOnlyResponse, ContractSrcResponse };
+use net::RPCStacksHeaderInfo;
+use net::{ RPCMempoolTxInfo, RPCMempoolTxsData, RPCMempoolAccountData };
+use net::{ FungibleTokenBalanceResponse, NonFungibleTokenOwnerResponse, DataVarResponse };
+use net::{ MapEntriesItem, MapEntriesResponse };
+use net::MAX_MAP_KEYS_EXAMINED_PER_REQUEST;
+use net::BatchProofResponse;
 use net::p2p::PeerMap;
+use net::websocket::{ WebSocketSession, RPCEvent, websocket_accept_key };
+use net::ratelimit::{ RPCRateLimiter, RPCRouteClass };
+use net::reputation::{ PeerAdminAction, PeerSelector, get_decayed_reputations, decay_reputation, apply_peer_admin_action };
+use net::RPCPeerReputation;
 use core::mempool::*;
 
 use burnchains::Burnchain;
@@ -62,9 +73,11 @@ use chainstate::burn::db::burndb::BurnDB;
 use chainstate::burn::BlockHeaderHash;
 use chainstate::stacks::db::{
     StacksChainState,
+    StacksHeaderInfo,
     BlockStreamData,
     blocks::MINIMUM_TX_FEE_RATE_PER_BYTE};
 use chainstate::stacks::Error as chain_error;
+use chainstate::stacks::index::Error as MarfError;
 use chainstate::stacks::*;
 use burnchains::*;
 
@@ -73,8 +86,10 @@ use rusqlite::{DatabaseName, NO_PARAMS};
 use util::db::Error as db_error;
 use util::db::DBConn;
 use util::get_epoch_time_secs;
+use util::get_epoch_time_ms;
 use util::hash::to_hex;
 use util::hash::Hash160;
+use util::hash::Sha256Sum;
 
 use vm::{
     clarity::ClarityConnection,
@@ -89,6 +104,7 @@ use vm::{
     database::{ ClarityDatabase,
                 MarfedKV,
                 ClaritySerializable,
+                ClarityDeserializable,
                 marf::ContractCommitment },
 };
 
@@ -120,6 +136,9 @@ pub struct ConversationHttp {
     pending_request: Option<ReplyHandleHttp>,
     pending_response: Option<HttpResponseType>,
     pending_error_response: Option<HttpResponseType>,
+
+    // set once this connection has been upgraded to a websocket
+    websocket: Option<WebSocketSession>,
 }
 
 impl fmt::Display for ConversationHttp {
@@ -166,6 +185,39 @@ impl RPCPeerInfoData {
     }
 }
 
+impl RPCStacksHeaderInfo {
+    pub fn from_header_info(header_info: &StacksHeaderInfo) -> RPCStacksHeaderInfo {
+        let mut header_bytes = vec![];
+        header_info.anchored_header.consensus_serialize(&mut header_bytes)
+            .expect("BUG: failed to serialize block header to a Vec");
+
+        RPCStacksHeaderInfo {
+            header: to_hex(&header_bytes),
+            block_hash: header_info.anchored_header.block_hash().to_hex(),
+            index_block_hash: header_info.index_block_hash().to_hex(),
+            burn_header_hash: header_info.burn_header_hash.to_hex(),
+            burn_header_timestamp: header_info.burn_header_timestamp,
+            block_height: header_info.block_height,
+            index_root: header_info.index_root.to_hex()
+        }
+    }
+}
+
+impl RPCMempoolTxInfo {
+    pub fn from_metadata(metadata: &MemPoolTxMetadata) -> RPCMempoolTxInfo {
+        RPCMempoolTxInfo {
+            txid: metadata.txid.to_hex(),
+            fee_rate: metadata.fee_rate,
+            estimated_fee: metadata.estimated_fee,
+            origin_address: metadata.origin_address.to_string(),
+            origin_nonce: metadata.origin_nonce,
+            sponsor_address: metadata.sponsor_address.to_string(),
+            sponsor_nonce: metadata.sponsor_nonce,
+            accept_time: metadata.accept_time
+        }
+    }
+}
+
 impl RPCNeighborsInfo {
     /// Load neighbor address information from the peer network
     pub fn from_p2p(network_id: u32, peers: &PeerMap, chain_view: &BurnchainView, peerdb: &PeerDB) -> Result<RPCNeighborsInfo, net_error> {
@@ -215,6 +267,7 @@ impl ConversationHttp {
             pending_request: None,
             pending_response: None,
             pending_error_response: None,
+            websocket: None,
             keep_alive: true,
             total_request_count: 0,
             total_reply_count: 0,
@@ -321,6 +374,63 @@ impl ConversationHttp {
         response.send(http, fd)
     }
 
+    /// Handle a GET peer reputations.
+    /// The response will be synchronously written to the given fd (so use a fd that can buffer!)
+    fn handle_get_peer_reputations<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, network_id: u32, peerdb: &PeerDB, decay_interval: u64) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        match get_decayed_reputations(peerdb.conn(), network_id, decay_interval) {
+            Ok(reputations) => {
+                let data = reputations.iter().map(|r| RPCPeerReputation::from_reputation(r)).collect();
+                let response = HttpResponseType::PeerReputations(response_metadata, data);
+                response.send(http, fd)
+            },
+            Err(e) => {
+                warn!("Failed to load peer reputations {:?}: {:?}", req, &e);
+                let response = HttpResponseType::ServerError(response_metadata, "Failed to query peer reputations".to_string());
+                response.send(http, fd)
+            }
+        }
+    }
+
+    /// Handle a POST to ban, unban, allow-list, or un-allow-list a peer.  Replies with the
+    /// reputations of the frontier peers that were changed, which may be none if the action names
+    /// a peer we haven't met yet (it's remembered, and applied once we do).  Connected peers that
+    /// were just banned get disconnected the next time the p2p network prunes its connections.
+    /// The response will be synchronously written to the given fd (so use a fd that can buffer!)
+    fn handle_post_peer_admin<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, network_id: u32, peerdb: &mut PeerDB,
+                                        action: &PeerAdminAction, selector: &PeerSelector, decay_interval: u64) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let res = peerdb.tx_begin()
+            .and_then(|mut tx| {
+                let changed = apply_peer_admin_action(&mut tx, network_id, selector, action)?;
+                tx.commit().map_err(db_error::SqliteError)?;
+                Ok(changed)
+            });
+
+        let changed = match res {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!("Failed to {} peer {:?}: {:?}", action.name(), selector, &e);
+                let response = HttpResponseType::ServerError(response_metadata, format!("Failed to {} peer", action.name()));
+                return response.send(http, fd);
+            }
+        };
+
+        info!("RPC peer admin: {} {:?} ({} known peers)", action.name(), selector, changed.len());
+
+        let now = get_epoch_time_secs();
+        let mut data = vec![];
+        for nk in changed.iter() {
+            if let Some(mut reputation) = PeerDB::get_peer_reputation(peerdb.conn(), nk.network_id, &nk.addrbytes, nk.port)? {
+                reputation.score = decay_reputation(reputation.score, reputation.score_updated, now, decay_interval);
+                data.push(RPCPeerReputation::from_reputation(&reputation));
+            }
+        }
+
+        let response = HttpResponseType::PeerReputations(response_metadata, data);
+        response.send(http, fd)
+    }
+
     /// Handle a GET block.  Start streaming the reply.
     /// The response's preamble (but not the block data) will be synchronously written to the fd
     /// (so use a fd that can buffer!)
@@ -351,6 +461,63 @@ impl ConversationHttp {
         }
     }
     
+    /// Handle a GET headers.  Walks back up to `count` anchored block headers from the given tip
+    /// (identified by its index block hash), and replies with them in descending height order.
+    /// The response will be synchronously written to the given fd (so use a fd that can buffer!)
+    fn handle_getheaders<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, tip: &BlockHeaderHash, count: u64, chainstate: &StacksChainState) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let response = match chainstate.get_ancestor_headers(tip, count) {
+            Ok(Some(headers)) => {
+                let headers = headers.iter().map(RPCStacksHeaderInfo::from_header_info).collect();
+                HttpResponseType::Headers(response_metadata, headers)
+            },
+            Ok(None) | Err(chain_error::NoSuchBlockError) => {
+                HttpResponseType::NotFound(response_metadata, format!("No such chain tip {}", tip.to_hex()))
+            },
+            Err(e) => {
+                warn!("Failed to load headers {:?}: {:?}", req, &e);
+                HttpResponseType::ServerError(response_metadata, format!("Failed to load headers from {}", tip.to_hex()))
+            }
+        };
+        response.send(http, fd)
+    }
+
+    /// Handle a GET block header info by height, on the fork identified by the given tip's index
+    /// block hash.
+    /// The response will be synchronously written to the given fd (so use a fd that can buffer!)
+    fn handle_getblock_by_height<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, tip: &BlockHeaderHash, height: u64, chainstate: &StacksChainState) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let header_res = StacksChainState::get_stacks_block_header_info_by_index_block_hash(&chainstate.headers_db, tip)
+            .and_then(|tip_info_opt| match tip_info_opt {
+                Some(tip_info) => {
+                    if tip_info.block_height == height {
+                        Ok(Some(tip_info))
+                    }
+                    else if tip_info.block_height > height {
+                        chainstate.get_ancestor_header_info(tip, height)
+                    }
+                    else {
+                        Ok(None)
+                    }
+                },
+                None => Ok(None)
+            });
+
+        let response = match header_res {
+            Ok(Some(header_info)) => {
+                HttpResponseType::HeaderInfo(response_metadata, RPCStacksHeaderInfo::from_header_info(&header_info))
+            },
+            Ok(None) => {
+                HttpResponseType::NotFound(response_metadata, format!("No block at height {} from chain tip {}", height, tip.to_hex()))
+            },
+            Err(e) => {
+                warn!("Failed to load header {:?}: {:?}", req, &e);
+                HttpResponseType::ServerError(response_metadata, format!("Failed to load header at height {}", height))
+            }
+        };
+        response.send(http, fd)
+    }
+
     /// Handle a GET confirmed microblock stream, by _anchor block hash_.  Start streaming the reply.
     /// The response's preamble (but not the block data) will be synchronously written to the fd
     /// (so use a fd that can buffer!)
@@ -423,6 +590,86 @@ impl ConversationHttp {
         response.send(http, fd).map(|_| ())
     }
 
+    /// Handle a GET on the mempool's pending transactions.  Lists up to `count` transactions
+    /// (across all chain tips) that come after the `after` cursor -- an accept time, and optionally
+    /// the txid of the last transaction seen at that time -- optionally only those that the given
+    /// address originates or sponsors.
+    /// The response will be synchronously written to the given fd (so use a fd that can buffer!)
+    fn handle_get_mempool_transactions<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, mempool: &MemPoolDB,
+                                                 address: Option<&StacksAddress>, after: Option<&(u64, Option<Txid>)>, count: u64) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let after = after.map(|(accept_time, txid_opt)| (*accept_time, txid_opt.as_ref()));
+        let response = match MemPoolDB::get_tx_metadata_page(mempool.conn(), address, after, count) {
+            Ok(page) => {
+                // a short page means there is nothing left to fetch
+                let next_cursor = if (page.len() as u64) >= count {
+                    page.last().map(|md| format!("{}:{}", md.accept_time, md.txid.to_hex()))
+                }
+                else {
+                    None
+                };
+                let transactions = page.iter().map(RPCMempoolTxInfo::from_metadata).collect();
+                HttpResponseType::MempoolTransactions(response_metadata, RPCMempoolTxsData { transactions, next_cursor })
+            },
+            Err(e) => {
+                warn!("Failed to load mempool transactions {:?}: {:?}", req, &e);
+                HttpResponseType::ServerError(response_metadata, "Failed to query mempool transactions".to_string())
+            }
+        };
+        response.send(http, fd)
+    }
+
+    /// Handle a GET on an account's pending mempool nonces, given the current chain tip.  Pending
+    /// nonces below the account's nonce at the chain tip belong to already-mined transactions
+    /// that have not yet been garbage-collected, so they are ignored.
+    /// The response will be synchronously written to the given fd (so use a fd that can buffer!)
+    fn handle_get_mempool_account<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
+                                            chainstate: &mut StacksChainState, cur_burn: &BurnchainHeaderHash, cur_block: &BlockHeaderHash,
+                                            mempool: &MemPoolDB, address: &StacksAddress) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let mut pending_nonces = match MemPoolDB::get_pending_nonces(mempool.conn(), address) {
+            Ok(nonces) => nonces,
+            Err(e) => {
+                warn!("Failed to load mempool nonces {:?}: {:?}", req, &e);
+                let response = HttpResponseType::ServerError(response_metadata, format!("Failed to query mempool nonces for {}", address));
+                return response.send(http, fd);
+            }
+        };
+
+        let principal = PrincipalData::from(address.clone());
+        let nonce = chainstate.with_read_only_clarity_tx(cur_burn, cur_block, |clarity_tx| {
+            clarity_tx.with_clarity_db_readonly(|clarity_db| {
+                clarity_db.get_account_nonce(&principal)
+            })
+        });
+
+        pending_nonces.retain(|pending_nonce| *pending_nonce >= nonce);
+
+        // next usable nonce is the first one at or above the account nonce that no pending
+        // transaction already uses (pending_nonces is sorted and distinct)
+        let mut next_nonce = nonce;
+        for pending_nonce in pending_nonces.iter() {
+            if *pending_nonce == next_nonce {
+                next_nonce += 1;
+            }
+            else {
+                break;
+            }
+        }
+
+        let data = RPCMempoolAccountData {
+            address: address.to_string(),
+            nonce,
+            pending_count: pending_nonces.len() as u64,
+            min_pending_nonce: pending_nonces.first().cloned(),
+            max_pending_nonce: pending_nonces.last().cloned(),
+            next_nonce
+        };
+
+        let response = HttpResponseType::MempoolAccount(response_metadata, data);
+        response.send(http, fd)
+    }
+
     /// Handle a GET on an existing account, given the current chain tip.  Optionally supplies a
     /// MARF proof for each account detail loaded from the chain tip.
     fn handle_get_account_entry<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
@@ -494,6 +741,231 @@ impl ConversationHttp {
         response.send(http, fd).map(|_| ())
     }
 
+    /// Handle a GET on a page of a smart contract's data map entries, given the chain tip.  Entries
+    /// are listed in order by serialized key, starting after `after` if given.  Optionally
+    /// supplies a MARF proof for each value.  Only works if this node indexes data map keys.
+    fn handle_get_map_entries<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
+                                        chainstate: &mut StacksChainState, cur_burn: &BurnchainHeaderHash, cur_block: &BlockHeaderHash,
+                                        contract_addr: &StacksAddress, contract_name: &ContractName,
+                                        map_name: &ClarityName, after: Option<&Value>, count: u64, with_proof: bool) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+
+        let response = chainstate.with_read_only_clarity_tx(cur_burn, cur_block, |clarity_tx| {
+            clarity_tx.with_clarity_db_readonly(|clarity_db| {
+                if !clarity_db.has_map_key_index() {
+                    return HttpResponseType::NotFound(response_metadata, "This node does not index data map keys".into());
+                }
+
+                match clarity_db.list_data_map_entries(&contract_identifier, map_name, after, count as u32, MAX_MAP_KEYS_EXAMINED_PER_REQUEST, with_proof) {
+                    Ok((entries, cursor)) => {
+                        let next_cursor = cursor.map(|key_hex| format!("0x{}", key_hex));
+
+                        let entries = entries.into_iter()
+                            .map(|(key, value, proof)| MapEntriesItem {
+                                key: format!("0x{}", key.serialize()),
+                                data: format!("0x{}", value.serialize()),
+                                marf_proof: proof.map(|proof| format!("0x{}", proof.to_hex()))
+                            })
+                            .collect();
+
+                        HttpResponseType::GetMapEntries(response_metadata, MapEntriesResponse { entries, next_cursor })
+                    },
+                    Err(e) => {
+                        debug!("Failed to list entries of {}::{}: {:?}", &contract_identifier, map_name.as_str(), &e);
+                        HttpResponseType::NotFound(response_metadata, format!("No such data map {}::{}", &contract_identifier, map_name.as_str()))
+                    }
+                }
+            })
+        });
+
+        response.send(http, fd).map(|_| ())
+    }
+
+    /// Handle a POST for a batch of accounts and data map entries, given the chain tip.  The values
+    /// that exist are all covered by one batched MARF proof, which shares the trie nodes and shunt
+    /// proofs that the individual proofs would have in common.  Replies with a 404 if the tip's
+    /// trie went missing out from under us.
+    fn handle_get_batch_proof<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
+                                        chainstate: &mut StacksChainState, cur_burn: &BurnchainHeaderHash, cur_block: &BlockHeaderHash,
+                                        accounts: &[PrincipalData], map_entries: &[(StacksAddress, ContractName, ClarityName, Value)]) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+
+        let data : Result<BatchProofResponse, MarfError> = chainstate.with_read_only_clarity_tx(cur_burn, cur_block, |clarity_tx| {
+            clarity_tx.with_clarity_db_readonly(|clarity_db| {
+                let mut keys = vec![];
+                for account in accounts.iter() {
+                    keys.push(ClarityDatabase::make_key_for_account_balance(account));
+                    keys.push(ClarityDatabase::make_key_for_account_nonce(account));
+                }
+                for (contract_addr, contract_name, map_name, key) in map_entries.iter() {
+                    let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+                    keys.push(ClarityDatabase::make_key_for_data_map_entry(&contract_identifier, map_name, key));
+                }
+
+                let (values, marf_proof) = clarity_db.get_many_with_proof(&keys)?;
+
+                let proven_keys = keys.iter().zip(values.iter())
+                    .filter(|(_, value_opt)| value_opt.is_some())
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                let mut values = values.into_iter();
+                let mut account_entries = Vec::with_capacity(accounts.len());
+                for _ in 0..accounts.len() {
+                    let balance = values.next().expect("BUG: missing account balance")
+                        .map(|x| u128::deserialize(&x))
+                        .unwrap_or(0);
+                    let nonce = values.next().expect("BUG: missing account nonce")
+                        .map(|x| u64::deserialize(&x))
+                        .unwrap_or(0);
+
+                    let balance = format!("0x{}", to_hex(&balance.to_be_bytes()));
+                    account_entries.push(AccountEntryResponse { balance, nonce, balance_proof: None, nonce_proof: None });
+                }
+
+                let map_entry_responses = values
+                    .map(|value_opt| {
+                        let value = value_opt
+                            .map(|x| Value::try_deserialize_hex_untyped(&x).expect("ERROR: Failed to parse Clarity hex string"))
+                            .unwrap_or_else(|| Value::none());
+                        MapEntryResponse { data: format!("0x{}", value.serialize()), marf_proof: None }
+                    })
+                    .collect();
+
+                Ok(BatchProofResponse {
+                    accounts: account_entries,
+                    map_entries: map_entry_responses,
+                    proven_keys,
+                    proof: format!("0x{}", marf_proof.to_hex())
+                })
+            })
+        });
+
+        let response = match data {
+            Ok(data) => HttpResponseType::GetBatchProof(response_metadata, data),
+            Err(MarfError::NotFoundError) => {
+                let index_block_hash = StacksBlockHeader::make_index_block_hash(cur_burn, cur_block);
+                HttpResponseType::NotFound(response_metadata, format!("No state stored for block {}", index_block_hash.to_hex()))
+            },
+            Err(e) => {
+                warn!("Failed to load batch proof {:?}: {:?}", req, &e);
+                HttpResponseType::ServerError(response_metadata, "Failed to query batch proof".to_string())
+            }
+        };
+
+        response.send(http, fd).map(|_| ())
+    }
+
+    /// Handle a GET on a principal's fungible token balance, given the current chain tip.
+    /// Optionally supplies a MARF proof for the balance.  Replies with a 404 if the contract or
+    /// token does not exist.
+    fn handle_get_ft_balance<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
+                                       chainstate: &mut StacksChainState, cur_burn: &BurnchainHeaderHash, cur_block: &BlockHeaderHash,
+                                       contract_addr: &StacksAddress, contract_name: &ContractName,
+                                       token_name: &ClarityName, principal: &PrincipalData, with_proof: bool) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+
+        let data_opt = chainstate.with_read_only_clarity_tx(cur_burn, cur_block, |clarity_tx| {
+            clarity_tx.with_clarity_db_readonly(|clarity_db| {
+                // fails if there's no such contract or token
+                clarity_db.get_ft_balance_with_proof(&contract_identifier, token_name, principal)
+                    .ok()
+                    .map(|(balance, proof_opt)| {
+                        let marf_proof = if with_proof {
+                            proof_opt.map(|proof| format!("0x{}", proof.to_hex()))
+                        } else {
+                            None
+                        };
+
+                        let balance = format!("0x{}", to_hex(&balance.to_be_bytes()));
+                        FungibleTokenBalanceResponse { balance, marf_proof }
+                    })
+            })
+        });
+
+        let response = match data_opt {
+            Some(data) => HttpResponseType::GetFungibleTokenBalance(response_metadata, data),
+            None => HttpResponseType::NotFound(response_metadata, format!("No such fungible token {}::{}", &contract_identifier, token_name.as_str()))
+        };
+
+        response.send(http, fd).map(|_| ())
+    }
+
+    /// Handle a POST to look up the owner of a non-fungible token, given the current chain tip.
+    /// Optionally supplies a MARF proof for the owner, if the asset has been minted.  Replies with
+    /// a 404 if the token does not exist.
+    fn handle_get_nft_owner<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
+                                      chainstate: &mut StacksChainState, cur_burn: &BurnchainHeaderHash, cur_block: &BlockHeaderHash,
+                                      contract_addr: &StacksAddress, contract_name: &ContractName,
+                                      token_name: &ClarityName, asset: &Value, with_proof: bool) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+
+        let data_opt = chainstate.with_read_only_clarity_tx(cur_burn, cur_block, |clarity_tx| {
+            clarity_tx.with_clarity_db_readonly(|clarity_db| {
+                // fails if there's no such contract or token, or the asset can't be one of its tokens
+                clarity_db.get_nft_owner_with_proof(&contract_identifier, token_name, asset)
+                    .ok()
+                    .map(|owner_opt| {
+                        let (owner, proof_opt) = match owner_opt {
+                            Some((owner, proof)) => (Some(owner.to_string()), Some(proof)),
+                            None => (None, None)
+                        };
+                        let marf_proof = if with_proof {
+                            proof_opt.map(|proof| format!("0x{}", proof.to_hex()))
+                        } else {
+                            None
+                        };
+
+                        NonFungibleTokenOwnerResponse { owner, marf_proof }
+                    })
+            })
+        });
+
+        let response = match data_opt {
+            Some(data) => HttpResponseType::GetNonFungibleTokenOwner(response_metadata, data),
+            None => HttpResponseType::NotFound(response_metadata, format!("No such non-fungible token {}::{}", &contract_identifier, token_name.as_str()))
+        };
+
+        response.send(http, fd).map(|_| ())
+    }
+
+    /// Handle a GET on a smart contract's data variable, given the current chain tip.  Optionally
+    /// supplies a MARF proof for the value.  Replies with a 404 if the variable does not exist.
+    fn handle_get_data_var<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
+                                     chainstate: &mut StacksChainState, cur_burn: &BurnchainHeaderHash, cur_block: &BlockHeaderHash,
+                                     contract_addr: &StacksAddress, contract_name: &ContractName,
+                                     var_name: &ClarityName, with_proof: bool) -> Result<(), net_error> {
+        let response_metadata = HttpResponseMetadata::from(req);
+        let contract_identifier = QualifiedContractIdentifier::new(contract_addr.clone().into(), contract_name.clone());
+
+        let data_opt = chainstate.with_read_only_clarity_tx(cur_burn, cur_block, |clarity_tx| {
+            clarity_tx.with_clarity_db_readonly(|clarity_db| {
+                let key = ClarityDatabase::make_key_for_data_var(&contract_identifier, var_name);
+                clarity_db.get_with_proof::<Value>(&key)
+                    .map(|(value, proof)| {
+                        let marf_proof = if with_proof {
+                            Some(format!("0x{}", proof.to_hex()))
+                        } else {
+                            None
+                        };
+
+                        let data = format!("0x{}", value.serialize());
+                        DataVarResponse { data, marf_proof }
+                    })
+            })
+        });
+
+        let response = match data_opt {
+            Some(data) => HttpResponseType::GetDataVar(response_metadata, data),
+            None => HttpResponseType::NotFound(response_metadata, format!("No such data var {}::{}", &contract_identifier, var_name.as_str()))
+        };
+
+        response.send(http, fd).map(|_| ())
+    }
+
     /// Handle a POST to run a read-only function call with the given parameters on the given chain
     /// tip.  Returns the result of the function call.  Returns a CallReadOnlyResponse on success.
     fn handle_readonly_function_call<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType,
@@ -628,6 +1100,81 @@ impl ConversationHttp {
         }
     }
 
+    /// Resolve the chain tip a request should be served from, as an index block hash.  If the
+    /// caller gave one, use it.  Otherwise, fall back to the canonical Stacks chain tip.
+    fn handle_load_stacks_chain_tip_index_hash<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, tip_opt: Option<&BlockHeaderHash>, burndb: &BurnDB, chainstate: &StacksChainState) -> Result<Option<BlockHeaderHash>, net_error> {
+        match tip_opt {
+            Some(tip) => Ok(Some(*tip)),
+            None => {
+                let tip_opt = ConversationHttp::handle_load_stacks_chain_tip(http, fd, req, burndb, chainstate)?;
+                Ok(tip_opt.map(|(burn_block, block)| StacksBlockHeader::make_index_block_hash(&burn_block, &block)))
+            }
+        }
+    }
+
+    /// Resolve the block a request should read state from, as a (burn header hash, block hash)
+    /// pair.  The caller may name it by index block hash, or by height on the canonical fork;
+    /// otherwise, fall back to the canonical Stacks chain tip.  Replies with a 404 if the named
+    /// block is not known, or if its state is no longer stored (e.g. its trie was pruned).
+    fn handle_load_stacks_chain_tip_hashes<W: Write>(http: &mut StacksHttp, fd: &mut W, req: &HttpRequestType, tip_req: &TipRequest, burndb: &BurnDB, chainstate: &mut StacksChainState) -> Result<Option<(BurnchainHeaderHash, BlockHeaderHash)>, net_error> {
+        let header_info_res = match *tip_req {
+            TipRequest::UseLatestAnchoredTip => {
+                return ConversationHttp::handle_load_stacks_chain_tip(http, fd, req, burndb, chainstate);
+            },
+            TipRequest::SpecificTip(ref tip) => {
+                StacksChainState::get_stacks_block_header_info_by_index_block_hash(&chainstate.headers_db, tip)?
+                    .ok_or_else(|| format!("No such chain tip {}", tip.to_hex()))
+            },
+            TipRequest::AtHeight(height) => {
+                let tip = match ConversationHttp::handle_load_stacks_chain_tip(http, fd, req, burndb, chainstate)? {
+                    Some((burn_block, block)) => StacksBlockHeader::make_index_block_hash(&burn_block, &block),
+                    None => {
+                        return Ok(None);
+                    }
+                };
+                let header_info_opt = match StacksChainState::get_stacks_block_header_info_by_index_block_hash(&chainstate.headers_db, &tip)? {
+                    Some(tip_info) => {
+                        if tip_info.block_height == height {
+                            Some(tip_info)
+                        }
+                        else if tip_info.block_height > height {
+                            chainstate.get_ancestor_header_info(&tip, height)?
+                        }
+                        else {
+                            None
+                        }
+                    },
+                    None => None
+                };
+                header_info_opt.ok_or_else(|| format!("No block at height {} on the canonical fork", height))
+            }
+        };
+
+        let response_metadata = HttpResponseMetadata::from(req);
+        match header_info_res {
+            Ok(header_info) => {
+                let burn_block = header_info.burn_header_hash.clone();
+                let block = header_info.anchored_header.block_hash();
+                match chainstate.has_clarity_state(&burn_block, &block) {
+                    Ok(true) => Ok(Some((burn_block, block))),
+                    Ok(false) => {
+                        let response = HttpResponseType::NotFound(response_metadata, format!("No state stored for block {}", header_info.index_block_hash().to_hex()));
+                        response.send(http, fd).and_then(|_| Ok(None))
+                    },
+                    Err(e) => {
+                        warn!("Failed to check for the state of block {}: {:?}", header_info.index_block_hash().to_hex(), &e);
+                        let response = HttpResponseType::ServerError(response_metadata, format!("Failed to check for the state of block {}", header_info.index_block_hash().to_hex()));
+                        response.send(http, fd).and_then(|_| Ok(None))
+                    }
+                }
+            },
+            Err(msg) => {
+                let response = HttpResponseType::NotFound(response_metadata, msg);
+                response.send(http, fd).and_then(|_| Ok(None))
+            }
+        }
+    }
+
     /// Handle a transaction.  Directly submit it to the mempool so the client can see any
     /// rejection reasons up-front (different from how the peer network handles it).  Indicate
     /// whether or not the transaction was accepted (and thus needs to be forwarded) in the return
@@ -658,13 +1205,23 @@ impl ConversationHttp {
     /// those new streams into the `reply_streams` set.
     /// Returns a StacksMessageType option -- it's Some(...) if we need to forward a message to the
     /// peer network (like a transaction or a block or microblock)
-    pub fn handle_request(&mut self, req: HttpRequestType, chain_view: &BurnchainView, peers: &PeerMap, burndb: &BurnDB, peerdb: &PeerDB,
-                          chainstate: &mut StacksChainState, mempool: &mut MemPoolDB) -> Result<Option<StacksMessageType>, net_error> {
+    pub fn handle_request(&mut self, req: HttpRequestType, chain_view: &BurnchainView, peers: &PeerMap, burndb: &BurnDB, peerdb: &mut PeerDB,
+                          chainstate: &mut StacksChainState, mempool: &mut MemPoolDB, rate_limiter: &mut RPCRateLimiter) -> Result<Option<StacksMessageType>, net_error> {
 
         let mut reply = self.connection.make_relay_handle(self.conn_id)?;
         let keep_alive = req.metadata().keep_alive;
         let mut ret = None;
 
+        if let Some(response) = self.check_access(&req, rate_limiter, get_epoch_time_ms()) {
+            debug!("{:?}: deny request for {}: {:?}", &self, req.request_path(), &response);
+            response.send(&mut self.connection.protocol, &mut reply).map(|_| ())?;
+
+            // we stop parsing HTTP after an upgrade request, so hang up if we refuse it
+            let keep_alive = keep_alive && !self.connection.is_upgraded();
+            self.reply_streams.push_back((reply, None, keep_alive));
+            return Ok(None);
+        }
+
         let stream_opt = match req {
             HttpRequestType::GetInfo(ref _md) => {
                 ConversationHttp::handle_getinfo(&mut self.connection.protocol, &mut reply, &req, &self.burnchain, burndb, peerdb)?;
@@ -674,6 +1231,16 @@ impl ConversationHttp {
                 ConversationHttp::handle_getneighbors(&mut self.connection.protocol, &mut reply, &req, self.network_id, chain_view, peers, peerdb)?;
                 None
             },
+            HttpRequestType::GetPeerReputations(ref _md) => {
+                ConversationHttp::handle_get_peer_reputations(&mut self.connection.protocol, &mut reply, &req, self.network_id, peerdb,
+                                                              self.connection.options.reputation_decay_interval)?;
+                None
+            },
+            HttpRequestType::PostPeerAdmin(ref _md, ref action, ref selector) => {
+                ConversationHttp::handle_post_peer_admin(&mut self.connection.protocol, &mut reply, &req, self.network_id, peerdb, action, selector,
+                                                         self.connection.options.reputation_decay_interval)?;
+                None
+            },
             HttpRequestType::GetBlock(ref _md, ref index_block_hash) => {
                 ConversationHttp::handle_getblock(&mut self.connection.protocol, &mut reply, &req, index_block_hash, chainstate)?
             },
@@ -686,41 +1253,99 @@ impl ConversationHttp {
             HttpRequestType::GetMicroblocksUnconfirmed(ref _md, ref index_anchor_block_hash, ref min_seq) => {
                 ConversationHttp::handle_getmicroblocks_unconfirmed(&mut self.connection.protocol, &mut reply, &req, index_anchor_block_hash, *min_seq, chainstate)?
             },
-            HttpRequestType::GetAccount(ref _md, ref principal, ref with_proof) => {
+            HttpRequestType::GetHeaders(ref _md, ref count, ref tip_opt) => {
+                if let Some(tip) = ConversationHttp::handle_load_stacks_chain_tip_index_hash(&mut self.connection.protocol, &mut reply, &req, tip_opt.as_ref(), burndb, chainstate)? {
+                    ConversationHttp::handle_getheaders(&mut self.connection.protocol, &mut reply, &req, &tip, *count, chainstate)?;
+                }
+                None
+            },
+            HttpRequestType::GetBlockByHeight(ref _md, ref height, ref tip_opt) => {
+                if let Some(tip) = ConversationHttp::handle_load_stacks_chain_tip_index_hash(&mut self.connection.protocol, &mut reply, &req, tip_opt.as_ref(), burndb, chainstate)? {
+                    ConversationHttp::handle_getblock_by_height(&mut self.connection.protocol, &mut reply, &req, &tip, *height, chainstate)?;
+                }
+                None
+            },
+            HttpRequestType::GetMempoolTransactions(ref _md, ref address_opt, ref after_opt, ref count) => {
+                ConversationHttp::handle_get_mempool_transactions(&mut self.connection.protocol, &mut reply, &req, mempool, address_opt.as_ref(), after_opt.as_ref(), *count)?;
+                None
+            },
+            HttpRequestType::GetMempoolAccount(ref _md, ref address) => {
                 if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip(&mut self.connection.protocol, &mut reply, &req, burndb, chainstate)? {
+                    ConversationHttp::handle_get_mempool_account(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
+                                                                 mempool, address)?;
+                }
+                None
+            },
+            HttpRequestType::GetAccount(ref _md, ref principal, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
                     ConversationHttp::handle_get_account_entry(&mut self.connection.protocol, &mut reply, &req, chainstate,
                                                                &burn_block, &block, principal, *with_proof)?;
                 }
                 None
             },
-            HttpRequestType::GetMapEntry(ref _md, ref contract_addr, ref contract_name, ref map_name, ref key, ref with_proof) => {
-                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip(&mut self.connection.protocol, &mut reply, &req, burndb, chainstate)? {
+            HttpRequestType::GetMapEntry(ref _md, ref contract_addr, ref contract_name, ref map_name, ref key, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
                     ConversationHttp::handle_get_map_entry(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
                                                            contract_addr, contract_name, map_name, key, *with_proof)?;
                 }
                 None
             },
+            HttpRequestType::GetMapEntries(ref _md, ref contract_addr, ref contract_name, ref map_name, ref after, ref count, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
+                    ConversationHttp::handle_get_map_entries(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
+                                                             contract_addr, contract_name, map_name, after.as_ref(), *count, *with_proof)?;
+                }
+                None
+            },
+            HttpRequestType::GetBatchProof(ref _md, ref accounts, ref map_entries, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
+                    ConversationHttp::handle_get_batch_proof(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
+                                                             accounts, map_entries)?;
+                }
+                None
+            },
+            HttpRequestType::GetFungibleTokenBalance(ref _md, ref contract_addr, ref contract_name, ref token_name, ref principal, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
+                    ConversationHttp::handle_get_ft_balance(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
+                                                            contract_addr, contract_name, token_name, principal, *with_proof)?;
+                }
+                None
+            },
+            HttpRequestType::GetNonFungibleTokenOwner(ref _md, ref contract_addr, ref contract_name, ref token_name, ref asset, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
+                    ConversationHttp::handle_get_nft_owner(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
+                                                           contract_addr, contract_name, token_name, asset, *with_proof)?;
+                }
+                None
+            },
+            HttpRequestType::GetDataVar(ref _md, ref contract_addr, ref contract_name, ref var_name, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
+                    ConversationHttp::handle_get_data_var(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
+                                                          contract_addr, contract_name, var_name, *with_proof)?;
+                }
+                None
+            },
             HttpRequestType::GetTransferCost(ref _md) => {
                 ConversationHttp::handle_token_transfer_cost(&mut self.connection.protocol, &mut reply, &req)?;
                 None
             },
-            HttpRequestType::GetContractABI(ref _md, ref contract_addr, ref contract_name) => {
-                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip(&mut self.connection.protocol, &mut reply, &req, burndb, chainstate)? {
+            HttpRequestType::GetContractABI(ref _md, ref contract_addr, ref contract_name, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
                     ConversationHttp::handle_get_contract_abi(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
                                                               contract_addr, contract_name)?;
                 }
                 None
             },
-            HttpRequestType::CallReadOnlyFunction(ref _md, ref ctrct_addr, ref ctrct_name, ref as_sender, ref func_name, ref args) => {
-                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip(&mut self.connection.protocol, &mut reply, &req, burndb, chainstate)? {
+            HttpRequestType::CallReadOnlyFunction(ref _md, ref ctrct_addr, ref ctrct_name, ref as_sender, ref func_name, ref args, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
                     ConversationHttp::handle_readonly_function_call(
                         &mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
                         ctrct_addr, ctrct_name, func_name, as_sender, args, &self.connection.options)?;
                 }
                 None
             },
-            HttpRequestType::GetContractSrc(ref _md, ref contract_addr, ref contract_name, ref with_proof) => {
-                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip(&mut self.connection.protocol, &mut reply, &req, burndb, chainstate)? {
+            HttpRequestType::GetContractSrc(ref _md, ref contract_addr, ref contract_name, ref with_proof, ref tip_req) => {
+                if let Some((burn_block, block)) = ConversationHttp::handle_load_stacks_chain_tip_hashes(&mut self.connection.protocol, &mut reply, &req, tip_req, burndb, chainstate)? {
                     ConversationHttp::handle_get_contract_src(&mut self.connection.protocol, &mut reply, &req, chainstate, &burn_block, &block,
                                                               contract_addr, contract_name, *with_proof)?;
                 }
@@ -736,6 +1361,19 @@ impl ConversationHttp {
                 }
                 None
             },
+            HttpRequestType::Subscribe(ref _md, ref key) => {
+                let response_metadata = HttpResponseMetadata::from(&req);
+                let response = HttpResponseType::WebSocketUpgrade(response_metadata, websocket_accept_key(key));
+                response.send(&mut self.connection.protocol, &mut reply).map(|_| ())?;
+
+                // everything after the 101 response is websocket frames, including any frames the
+                // client sent right after its upgrade request
+                debug!("{:?}: upgrade to websocket", &self);
+                let mut session = WebSocketSession::new();
+                session.push_bytes(&self.connection.take_upgraded_bytes());
+                self.websocket = Some(session);
+                None
+            },
             HttpRequestType::OptionsPreflight(ref _md, ref _path) => {
                 let response_metadata = HttpResponseMetadata::from(&req);
                 let response = HttpResponseType::OptionsPreflight(response_metadata);
@@ -761,22 +1399,104 @@ impl ConversationHttp {
         Ok(ret)
     }
 
-    /// Make progress on outbound requests.
-    /// Return true if the connection should be kept alive after all messages are drained.
-    /// If we process a request with "Connection: close", then return false (indicating that the
-    /// connection should be severed once the conversation is drained)
-    fn send_outbound_responses(&mut self, chainstate: &mut StacksChainState) -> Result<(), net_error> {
-        // send out streamed responses in the order they were requested 
-        let mut drained_handle = false;
-        let mut drained_stream = false;
-        let mut broken = false;
-        let mut do_keep_alive = true;
-        
-        test_debug!("{:?}: {} HTTP replies pending", &self, self.reply_streams.len());
-        match self.reply_streams.front_mut() {
-            Some((ref mut reply, ref mut stream_opt, ref keep_alive)) => {
-                do_keep_alive = *keep_alive;
-
+    /// Is the given key one of the configured keys?  Keys are compared by their SHA256 digests,
+    /// and every byte of every digest is looked at, so how long this takes doesn't tell a client
+    /// how much of a valid key it has guessed.
+    fn is_api_key_in(keys: &[String], key: &str) -> bool {
+        let key_digest = Sha256Sum::from_data(key.as_bytes());
+        let mut found = 0u8;
+        for candidate in keys.iter() {
+            let candidate_digest = Sha256Sum::from_data(candidate.as_bytes());
+            let mut diff = 0u8;
+            for (a, b) in candidate_digest.as_bytes().iter().zip(key_digest.as_bytes().iter()) {
+                diff |= a ^ b;
+            }
+            found |= (diff == 0) as u8;
+        }
+        found != 0
+    }
+
+    /// Decide whether or not this client may make this request, given the configured API keys
+    /// and rate limits.  Returns the error response to send back if not.
+    fn check_access(&self, req: &HttpRequestType, rate_limiter: &mut RPCRateLimiter, now_ms: u128) -> Option<HttpResponseType> {
+        let route_class = match RPCRouteClass::from_request(req) {
+            Some(class) => class,
+            None => {
+                return None;
+            }
+        };
+
+        let options = &self.connection.options;
+        let response_metadata = HttpResponseMetadata::from(req);
+
+        // peer administration is only for holders of an admin key, or for localhost if there
+        // are no admin keys.  On these routes, an admin key stands in for a regular key.
+        let is_peer_admin = match req {
+            HttpRequestType::GetPeerReputations(..) | HttpRequestType::PostPeerAdmin(..) => true,
+            _ => false
+        };
+        let has_admin_keys = options.rpc_admin_api_keys.len() > 0;
+        if is_peer_admin {
+            let allowed = match req.metadata().api_key {
+                Some(ref key) if has_admin_keys => ConversationHttp::is_api_key_in(&options.rpc_admin_api_keys, key),
+                _ => !has_admin_keys && self.peer_addr.ip().is_loopback()
+            };
+            if !allowed {
+                return Some(HttpResponseType::Forbidden(response_metadata, "Peer administration is not available to this client".to_string()));
+            }
+        }
+
+        // keys are only checked if the node is configured with them
+        let api_key = match req.metadata().api_key {
+            Some(ref key) if is_peer_admin && has_admin_keys => Some(key.clone()),
+            Some(ref key) if options.rpc_api_keys.len() > 0 => {
+                if !ConversationHttp::is_api_key_in(&options.rpc_api_keys, key) {
+                    return Some(HttpResponseType::Unauthorized(response_metadata, "Invalid API key".to_string()));
+                }
+                Some(key.clone())
+            },
+            _ => {
+                if options.rpc_api_keys.len() > 0 {
+                    return Some(HttpResponseType::Unauthorized(response_metadata, "Missing API key".to_string()));
+                }
+                None
+            }
+        };
+
+        if let HttpRequestType::PostTransaction(..) = req {
+            if options.rpc_disable_post_transaction && api_key.is_none() {
+                return Some(HttpResponseType::Forbidden(response_metadata, "Transaction submission is disabled on this node".to_string()));
+            }
+        }
+
+        let res = match api_key {
+            Some(ref key) => rate_limiter.check_api_key(key, route_class, &options.rpc_api_key_rate_limits, now_ms),
+            None => rate_limiter.check_ip(&self.peer_addr.ip(), route_class, &options.rpc_ip_rate_limits, now_ms)
+        };
+
+        match res {
+            Ok(()) => None,
+            Err(retry_after) => Some(HttpResponseType::TooManyRequests(response_metadata, retry_after,
+                                                                       format!("Rate limit exceeded; retry in {} second(s)", retry_after)))
+        }
+    }
+
+    /// Make progress on outbound requests.
+    /// Return true if the connection should be kept alive after all messages are drained.
+    /// If we process a request with "Connection: close", then return false (indicating that the
+    /// connection should be severed once the conversation is drained)
+    fn send_outbound_responses(&mut self, chainstate: &mut StacksChainState) -> Result<(), net_error> {
+        // send out streamed responses in the order they were requested 
+        let mut drained_handle = false;
+        let mut drained_stream = false;
+        let mut broken = false;
+        let mut do_keep_alive = true;
+        
+        test_debug!("{:?}: {} HTTP replies pending", &self, self.reply_streams.len());
+        match self.reply_streams.front_mut() {
+            Some((ref mut reply, ref mut stream_opt, ref keep_alive)) => {
+                do_keep_alive = *keep_alive;
+
                 // if we're streaming, make some progress on the stream
                 match stream_opt {
                     Some((ref mut http_chunk_state, ref mut stream)) => {
@@ -939,6 +1659,49 @@ impl ConversationHttp {
         self.keep_alive
     }
 
+    /// Has this connection been upgraded to a websocket?
+    pub fn is_websocket(&self) -> bool {
+        self.websocket.is_some()
+    }
+
+    /// Is this a websocket that has been closed (or dropped for falling behind)?
+    pub fn is_websocket_closed(&self) -> bool {
+        match self.websocket {
+            Some(ref session) => session.is_closed(),
+            None => false
+        }
+    }
+
+    /// Does this websocket have un-sent frames?
+    pub fn has_websocket_data(&self) -> bool {
+        match self.websocket {
+            Some(ref session) => session.has_pending_data(),
+            None => false
+        }
+    }
+
+    /// Queue up whichever events this websocket's client subscribed to.
+    /// Returns the number of events queued.
+    pub fn publish_events(&mut self, events: &Vec<RPCEvent>) -> usize {
+        match self.websocket {
+            Some(ref mut session) => events.iter().filter(|event| session.publish(event)).count(),
+            None => 0
+        }
+    }
+
+    /// Ping an upgraded websocket's client if it has gone quiet.
+    /// Returns false if the client didn't answer an earlier ping in time.
+    pub fn check_websocket_liveness(&mut self, now: u64, idle_timeout: u64, ping_timeout: u64) -> bool {
+        let mut last_heard = self.last_request_timestamp;
+        if last_heard == 0 {
+            last_heard = self.connection_time;
+        }
+        match self.websocket {
+            Some(ref mut session) => session.check_liveness(last_heard, now, idle_timeout, ping_timeout),
+            None => true
+        }
+    }
+
     /// When was the last time we got an inbound request?
     pub fn get_last_request_time(&self) -> u64 {
         self.last_request_timestamp
@@ -956,14 +1719,20 @@ impl ConversationHttp {
 
     /// Make progress on in-flight requests and replies.
     /// Returns the list of transactions we'll need to forward to the peer network
-    pub fn chat(&mut self, chain_view: &BurnchainView, peers: &PeerMap, burndb: &BurnDB, peerdb: &PeerDB,
-                chainstate: &mut StacksChainState, mempool: &mut MemPoolDB) -> Result<Vec<StacksMessageType>, net_error> {
+    pub fn chat(&mut self, chain_view: &BurnchainView, peers: &PeerMap, burndb: &BurnDB, peerdb: &mut PeerDB,
+                chainstate: &mut StacksChainState, mempool: &mut MemPoolDB, rate_limiter: &mut RPCRateLimiter) -> Result<Vec<StacksMessageType>, net_error> {
 
         // if we have an in-flight error, then don't take any more requests.
         if self.pending_error_response.is_some() {
             return Ok(vec![]);
         }
 
+        // websockets only carry subscription commands
+        if let Some(ref mut session) = self.websocket {
+            session.process_frames();
+            return Ok(vec![]);
+        }
+
         // handle in-bound HTTP request(s)
         let num_inbound = self.connection.inbox_len();
         let mut ret = vec![];
@@ -982,7 +1751,7 @@ impl ConversationHttp {
                     // new request
                     self.total_request_count += 1;
                     self.last_request_timestamp = get_epoch_time_secs();
-                    let msg_opt = self.handle_request(req, chain_view, peers, burndb, peerdb, chainstate, mempool)?;
+                    let msg_opt = self.handle_request(req, chain_view, peers, burndb, peerdb, chainstate, mempool, rate_limiter)?;
                     if let Some(msg) = msg_opt {
                         ret.push(msg);
                     }
@@ -1013,6 +1782,14 @@ impl ConversationHttp {
 
     /// Load data into our HTTP connection
     pub fn recv<R: Read>(&mut self, r: &mut R) -> Result<usize, net_error> {
+        if let Some(ref mut session) = self.websocket {
+            let nrecv = session.recv(r)?;
+            if nrecv > 0 {
+                self.last_request_timestamp = get_epoch_time_secs();
+            }
+            return Ok(nrecv);
+        }
+
         let mut total_recv = 0;
         loop {
             let nrecv = match self.connection.recv_data(r) {
@@ -1057,6 +1834,17 @@ impl ConversationHttp {
                 break;
             }
         }
+
+        // websocket frames go out only once the 101 response has been fully sent
+        if self.reply_streams.len() == 0 && self.connection.outbox_len() == 0 {
+            if let Some(ref mut session) = self.websocket {
+                let sz = session.send(w)?;
+                total_sz += sz;
+                if sz > 0 {
+                    self.last_response_timestamp = get_epoch_time_secs();
+                }
+            }
+        }
         Ok(total_sz)
     }
 
@@ -1090,6 +1878,43 @@ impl ConversationHttp {
         HttpRequestType::GetMicroblocksUnconfirmed(HttpRequestMetadata::from_host(self.peer_host.clone()), anchored_index_block_hash, min_seq)
     }
 
+    /// Make a new get-headers request to this endpoint
+    pub fn new_getheaders(&self, count: u64, tip: Option<BlockHeaderHash>) -> HttpRequestType {
+        HttpRequestType::GetHeaders(HttpRequestMetadata::from_host(self.peer_host.clone()), count, tip)
+    }
+
+    /// Make a new get-block-header-by-height request to this endpoint
+    pub fn new_getblock_by_height(&self, height: u64, tip: Option<BlockHeaderHash>) -> HttpRequestType {
+        HttpRequestType::GetBlockByHeight(HttpRequestMetadata::from_host(self.peer_host.clone()), height, tip)
+    }
+
+    /// Make a new get-map-entries request to this endpoint
+    pub fn new_get_map_entries(&self, contract_addr: StacksAddress, contract_name: ContractName, map_name: ClarityName,
+                               after: Option<Value>, count: u64, with_proof: bool, tip_req: TipRequest) -> HttpRequestType {
+        HttpRequestType::GetMapEntries(HttpRequestMetadata::from_host(self.peer_host.clone()), contract_addr, contract_name, map_name, after, count, with_proof, tip_req)
+    }
+
+    /// Make a new batch proof request to this endpoint
+    pub fn new_get_batch_proof(&self, accounts: Vec<PrincipalData>, map_entries: Vec<(StacksAddress, ContractName, ClarityName, Value)>,
+                               tip_req: TipRequest) -> HttpRequestType {
+        HttpRequestType::GetBatchProof(HttpRequestMetadata::from_host(self.peer_host.clone()), accounts, map_entries, tip_req)
+    }
+
+    /// Make a new get-account request to this endpoint
+    pub fn new_getaccount(&self, principal: PrincipalData, with_proof: bool, tip_req: TipRequest) -> HttpRequestType {
+        HttpRequestType::GetAccount(HttpRequestMetadata::from_host(self.peer_host.clone()), principal, with_proof, tip_req)
+    }
+
+    /// Make a new get-mempool-transactions request to this endpoint
+    pub fn new_get_mempool_transactions(&self, address: Option<StacksAddress>, after: Option<(u64, Option<Txid>)>, count: u64) -> HttpRequestType {
+        HttpRequestType::GetMempoolTransactions(HttpRequestMetadata::from_host(self.peer_host.clone()), address, after, count)
+    }
+
+    /// Make a new get-mempool-account request to this endpoint
+    pub fn new_get_mempool_account(&self, address: StacksAddress) -> HttpRequestType {
+        HttpRequestType::GetMempoolAccount(HttpRequestMetadata::from_host(self.peer_host.clone()), address)
+    }
+
     /// Make a new post-transaction request
     pub fn new_post_transaction(&self, tx: StacksTransaction) -> HttpRequestType {
         HttpRequestType::PostTransaction(HttpRequestMetadata::from_host(self.peer_host.clone()), tx)
@@ -1113,7 +1938,13 @@ mod test {
     use chainstate::burn::BlockHeaderHash;
     use chainstate::stacks::test::*;
     use chainstate::stacks::db::StacksChainState;
+    use chainstate::stacks::db::StacksAccount;
     use chainstate::stacks::db::BlockStreamData;
+    use chainstate::stacks::index::marf::MARF;
+    use vm::types::TupleData;
+
+    use core::FIRST_BURNCHAIN_BLOCK_HASH;
+    use core::FIRST_STACKS_BLOCK_HASH;
     use chainstate::stacks::db::blocks::test::*;
     use chainstate::stacks::Error as chain_error;
     use chainstate::stacks::*;
@@ -1121,6 +1952,7 @@ mod test {
    
     use util::pipe::*;
     use util::get_epoch_time_secs;
+    use std::convert::TryFrom;
 
     fn convo_send_recv(sender: &mut ConversationHttp, sender_chainstate: &mut StacksChainState, receiver: &mut ConversationHttp, receiver_chainstate: &mut StacksChainState) -> () {
         let (mut pipe_read, mut pipe_write) = Pipe::new();
@@ -1181,6 +2013,7 @@ mod test {
                                                 1);
 
         let req = make_request(&mut peer_1, &mut convo_1, &mut peer_2, &mut convo_2);
+        let mut rate_limiter = RPCRateLimiter::new();
 
         convo_1.send_request(req.clone()).unwrap();
 
@@ -1192,7 +2025,7 @@ mod test {
         let mut peer_1_stacks_node = peer_1.stacks_node.take().unwrap();
         let mut peer_1_mempool = peer_1.mempool.take().unwrap();
 
-        convo_1.chat(&view_1, &PeerMap::new(), &mut peer_1_burndb, &peer_1.network.peerdb, &mut peer_1_stacks_node.chainstate, &mut peer_1_mempool).unwrap();
+        convo_1.chat(&view_1, &PeerMap::new(), &mut peer_1_burndb, &mut peer_1.network.peerdb, &mut peer_1_stacks_node.chainstate, &mut peer_1_mempool, &mut rate_limiter).unwrap();
 
         peer_1.burndb = Some(peer_1_burndb);
         peer_1.stacks_node = Some(peer_1_stacks_node);
@@ -1205,7 +2038,7 @@ mod test {
         let mut peer_2_stacks_node = peer_2.stacks_node.take().unwrap();
         let mut peer_2_mempool = peer_2.mempool.take().unwrap();
 
-        convo_2.chat(&view_2, &PeerMap::new(), &mut peer_2_burndb, &peer_2.network.peerdb, &mut peer_2_stacks_node.chainstate, &mut peer_2_mempool).unwrap();
+        convo_2.chat(&view_2, &PeerMap::new(), &mut peer_2_burndb, &mut peer_2.network.peerdb, &mut peer_2_stacks_node.chainstate, &mut peer_2_mempool, &mut rate_limiter).unwrap();
         
         peer_2.burndb = Some(peer_2_burndb);
         peer_2.stacks_node = Some(peer_2_stacks_node);
@@ -1222,7 +2055,7 @@ mod test {
         let mut peer_1_stacks_node = peer_1.stacks_node.take().unwrap();
         let mut peer_1_mempool = peer_1.mempool.take().unwrap();
 
-        convo_1.chat(&view_1, &PeerMap::new(), &mut peer_1_burndb, &peer_1.network.peerdb, &mut peer_1_stacks_node.chainstate, &mut peer_1_mempool).unwrap();
+        convo_1.chat(&view_1, &PeerMap::new(), &mut peer_1_burndb, &mut peer_1.network.peerdb, &mut peer_1_stacks_node.chainstate, &mut peer_1_mempool, &mut rate_limiter).unwrap();
         
         peer_1.burndb = Some(peer_1_burndb);
         peer_1.stacks_node = Some(peer_1_stacks_node);
@@ -1592,5 +2425,711 @@ mod test {
                     }
                 });
     }
-}
+    #[test]
+    fn test_rpc_getheaders() {
+        let server_genesis_cell = RefCell::new(None);
+
+        test_rpc("test_rpc_getheaders", 40090, 40091, 50090, 50091,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let genesis_header_info = StacksChainState::get_genesis_header_info(&peer_server.chainstate().headers_db).unwrap();
+                     let genesis_index_hash = genesis_header_info.index_block_hash();
+                     *server_genesis_cell.borrow_mut() = Some(genesis_header_info);
 
+                     // ask for more headers than exist
+                     convo_client.new_getheaders(5, Some(genesis_index_hash))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::Headers(response_md, headers) => {
+                           let genesis_header_info = (*server_genesis_cell.borrow()).clone().unwrap();
+                           assert_eq!(headers.len(), 1);
+                           assert_eq!(headers[0], RPCStacksHeaderInfo::from_header_info(&genesis_header_info));
+                           assert_eq!(headers[0].block_height, 0);
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_getblock_by_height() {
+        let server_genesis_cell = RefCell::new(None);
+
+        test_rpc("test_rpc_getblock_by_height", 40100, 40101, 50100, 50101,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let genesis_header_info = StacksChainState::get_genesis_header_info(&peer_server.chainstate().headers_db).unwrap();
+                     let genesis_index_hash = genesis_header_info.index_block_hash();
+                     *server_genesis_cell.borrow_mut() = Some(genesis_header_info);
+
+                     convo_client.new_getblock_by_height(0, Some(genesis_index_hash))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::HeaderInfo(response_md, header_info) => {
+                           let genesis_header_info = (*server_genesis_cell.borrow()).clone().unwrap();
+                           assert_eq!(*header_info, RPCStacksHeaderInfo::from_header_info(&genesis_header_info));
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_missing_getblock_by_height() {
+        test_rpc("test_rpc_missing_getblock_by_height", 40110, 40111, 50110, 50111,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let genesis_header_info = StacksChainState::get_genesis_header_info(&peer_server.chainstate().headers_db).unwrap();
+
+                     // no block at height 1 from the genesis tip
+                     convo_client.new_getblock_by_height(1, Some(genesis_header_info.index_block_hash()))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => true,
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_mempool_transactions() {
+        let server_tx_cell = RefCell::new(None);
+
+        test_rpc("test_rpc_get_mempool_transactions", 40120, 40121, 50120, 50121,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let tx = codec_all_transactions(&TransactionVersion::Testnet, 0x80000000, &TransactionAnchorMode::Any, &TransactionPostConditionMode::Allow)
+                         .pop()
+                         .unwrap();
+
+                     let mut tx_bytes = vec![];
+                     tx.consensus_serialize(&mut tx_bytes).unwrap();
+                     peer_server.mempool.as_mut().unwrap().submit_raw(&FIRST_BURNCHAIN_BLOCK_HASH, &FIRST_STACKS_BLOCK_HASH, tx_bytes).unwrap();
+
+                     let origin_address = tx.origin_address();
+                     *server_tx_cell.borrow_mut() = Some(tx);
+
+                     convo_client.new_get_mempool_transactions(Some(origin_address), None, 10)
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::MempoolTransactions(response_md, txs_data) => {
+                           let tx = (*server_tx_cell.borrow()).clone().unwrap();
+                           assert_eq!(txs_data.transactions.len(), 1);
+                           assert_eq!(txs_data.transactions[0].txid, tx.txid().to_hex());
+                           assert_eq!(txs_data.transactions[0].origin_address, tx.origin_address().to_string());
+                           assert_eq!(txs_data.transactions[0].origin_nonce, tx.get_origin_nonce());
+                           assert_eq!(txs_data.transactions[0].fee_rate, tx.get_fee_rate());
+
+                           // short page, so no more to fetch
+                           assert_eq!(txs_data.next_cursor, None);
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+    #[test]
+    fn test_rpc_get_ft_balance_at_tip() {
+        test_rpc("test_rpc_get_ft_balance_at_tip", 40140, 40141, 50140, 50141,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let genesis_header_info = StacksChainState::get_genesis_header_info(&peer_server.chainstate().headers_db).unwrap();
+                     let contract_addr = StacksAddress::from_string("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+
+                     // read the balance as of the genesis block, before the contract exists
+                     HttpRequestType::GetFungibleTokenBalance(HttpRequestMetadata::from_host(convo_client.peer_host.clone()), contract_addr.clone(),
+                                                              ContractName::try_from("hello-world".to_string()).unwrap(),
+                                                              ClarityName::try_from("tokens".to_string()).unwrap(),
+                                                              PrincipalData::from(contract_addr), false,
+                                                              TipRequest::SpecificTip(genesis_header_info.index_block_hash()))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => {
+                           assert!(msg.find("No such fungible token").is_some());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_nft_owner_no_contract() {
+        test_rpc("test_rpc_get_nft_owner_no_contract", 40270, 40271, 50270, 50271,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let contract_addr = StacksAddress::from_string("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+
+                     HttpRequestType::GetNonFungibleTokenOwner(HttpRequestMetadata::from_host(convo_client.peer_host.clone()), contract_addr,
+                                                               ContractName::try_from("hello-world".to_string()).unwrap(),
+                                                               ClarityName::try_from("names".to_string()).unwrap(),
+                                                               Value::UInt(1), true, TipRequest::UseLatestAnchoredTip)
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => {
+                           assert!(msg.find("No such non-fungible token").is_some());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_account_missing_tip() {
+        test_rpc("test_rpc_get_account_missing_tip", 40150, 40151, 50150, 50151,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let principal = PrincipalData::parse("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+
+                     // no such block
+                     convo_client.new_getaccount(principal, true, TipRequest::SpecificTip(BlockHeaderHash([0x11; 32])))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => {
+                           assert!(msg.find("No such chain tip").is_some());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    /// Have the given peer mine and process `num_tenures` Stacks blocks.  Returns the index block
+    /// hash of each one, in height order.
+    fn mine_rpc_tenures(peer: &mut TestPeer, num_tenures: usize) -> Vec<BlockHeaderHash> {
+        let mut index_hashes = vec![];
+        for _ in 0..num_tenures {
+            let (burn_ops, stacks_block, microblocks) = peer.make_default_tenure();
+            peer.next_burnchain_block(burn_ops);
+            peer.process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+            let sn = BurnDB::get_canonical_burn_chain_tip(peer.burndb.as_ref().unwrap().conn()).unwrap();
+            index_hashes.push(StacksBlockHeader::make_index_block_hash(&sn.burn_header_hash, &stacks_block.block_hash()));
+        }
+        index_hashes
+    }
+
+    /// Read an account straight out of the given block's Clarity state.
+    fn get_account_at_block(peer: &mut TestPeer, index_block_hash: &BlockHeaderHash, principal: &PrincipalData) -> StacksAccount {
+        let header_info = StacksChainState::get_stacks_block_header_info_by_index_block_hash(&peer.chainstate().headers_db, index_block_hash).unwrap().unwrap();
+        let block_hash = header_info.anchored_header.block_hash();
+        peer.chainstate().with_read_only_clarity_tx(&header_info.burn_header_hash, &block_hash, |clarity_tx| {
+            StacksChainState::get_account(clarity_tx, principal)
+        })
+    }
+
+    /// Ask the server peer for the miner's account at the given height, after mining two blocks.
+    /// Returns whether or not the response matched the account as of the block at that height.
+    fn test_rpc_get_account_at_height(test_name: &str, peer_1_p2p: u16, peer_1_http: u16, peer_2_p2p: u16, peer_2_http: u16, height: u64) -> () {
+        let expected_account_cell = RefCell::new(None);
+
+        test_rpc(test_name, peer_1_p2p, peer_1_http, peer_2_p2p, peer_2_http,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let index_hashes = mine_rpc_tenures(peer_server, 2);
+                     let principal = peer_server.miner.origin_address().unwrap().to_account_principal();
+
+                     // the miner's nonce moves every tenure, so the two heights are distinguishable
+                     let accounts : Vec<_> = index_hashes.iter().map(|h| get_account_at_block(peer_server, h, &principal)).collect();
+                     assert!(accounts[0].nonce != accounts[1].nonce);
+
+                     *expected_account_cell.borrow_mut() = Some(accounts[(height - 1) as usize].clone());
+                     convo_client.new_getaccount(principal, false, TipRequest::AtHeight(height))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::GetAccount(response_md, data) => {
+                           let account = (*expected_account_cell.borrow()).clone().unwrap();
+                           assert_eq!(data.nonce, account.nonce);
+                           assert_eq!(data.balance, format!("0x{}", to_hex(&account.stx_balance.to_be_bytes())));
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_account_at_current_height() {
+        test_rpc_get_account_at_height("test_rpc_get_account_at_current_height", 40160, 40161, 50160, 50161, 2);
+    }
+
+    #[test]
+    fn test_rpc_get_account_at_past_height() {
+        test_rpc_get_account_at_height("test_rpc_get_account_at_past_height", 40170, 40171, 50170, 50171, 1);
+    }
+
+    #[test]
+    fn test_rpc_get_account_above_tip_height() {
+        test_rpc("test_rpc_get_account_above_tip_height", 40180, 40181, 50180, 50181,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     mine_rpc_tenures(peer_server, 2);
+                     let principal = peer_server.miner.origin_address().unwrap().to_account_principal();
+
+                     // the canonical tip is at height 2
+                     convo_client.new_getaccount(principal, false, TipRequest::AtHeight(3))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => {
+                           assert!(msg.find("No block at height 3").is_some());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_account_dropped_trie() {
+        test_rpc("test_rpc_get_account_dropped_trie", 40190, 40191, 50190, 50191,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let index_hashes = mine_rpc_tenures(peer_server, 2);
+                     let principal = peer_server.miner.origin_address().unwrap().to_account_principal();
+
+                     // quarantine the tip's trie from a separate handle, the way marf-fsck does
+                     let mut marf = MARF::from_path(&peer_server.chainstate().clarity_state_index_path, None).unwrap();
+                     marf.borrow_storage_backend().quarantine_blocks(&[(index_hashes[1].clone(), "test".to_string())]).unwrap();
+
+                     // the block's header is still there, but its state is gone
+                     convo_client.new_getaccount(principal, true, TipRequest::AtHeight(2))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => {
+                           assert!(msg.find("No state stored for block").is_some());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_batch_proof() {
+        let expected_account_cell = RefCell::new(None);
+
+        test_rpc("test_rpc_get_batch_proof", 40230, 40231, 50230, 50231,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let index_hashes = mine_rpc_tenures(peer_server, 1);
+                     let miner = peer_server.miner.origin_address().unwrap().to_account_principal();
+                     *expected_account_cell.borrow_mut() = Some(get_account_at_block(peer_server, &index_hashes[0], &miner));
+
+                     // no one has ever used this account
+                     let nobody = PrincipalData::parse("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV").unwrap();
+                     convo_client.new_get_batch_proof(vec![miner, nobody], vec![], TipRequest::UseLatestAnchoredTip)
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     let (miner, nobody) = match http_request {
+                         HttpRequestType::GetBatchProof(_, accounts, ..) => (accounts[0].clone(), accounts[1].clone()),
+                         _ => panic!("Not a batch proof request")
+                     };
+                     match http_response {
+                        HttpResponseType::GetBatchProof(response_md, data) => {
+                           let account = (*expected_account_cell.borrow()).clone().unwrap();
+                           assert_eq!(data.accounts.len(), 2);
+                           assert_eq!(data.accounts[0].nonce, account.nonce);
+                           assert_eq!(data.accounts[0].balance, format!("0x{}", to_hex(&account.stx_balance.to_be_bytes())));
+                           assert_eq!(data.accounts[1].nonce, 0);
+                           assert_eq!(data.accounts[1].balance, format!("0x{}", to_hex(&0u128.to_be_bytes())));
+
+                           // only the keys that exist are proven
+                           assert!(data.proven_keys.contains(&ClarityDatabase::make_key_for_account_nonce(&miner)));
+                           assert!(!data.proven_keys.contains(&ClarityDatabase::make_key_for_account_nonce(&nobody)));
+                           assert!(!data.proven_keys.contains(&ClarityDatabase::make_key_for_account_balance(&nobody)));
+                           assert!(data.proof.len() > 2);
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    /// Have the given peer index data map keys, and mine two blocks: one that deploys a contract
+    /// with the map `kv` holding keys 1, 2 and 3, and one that deletes key 2 and adds key 4.
+    /// Returns the index block hash of each one.
+    fn mine_rpc_map_tenures(peer: &mut TestPeer) -> Vec<BlockHeaderHash> {
+        let contract =
+            "(define-map kv ((k int)) ((v int)))
+             (define-public (put (k int) (v int))
+               (begin (map-set kv (tuple (k k)) (tuple (v v))) (ok true)))
+             (define-public (del (k int))
+               (begin (map-delete kv (tuple (k k))) (ok true)))
+             (map-set kv (tuple (k 1)) (tuple (v 10)))
+             (map-set kv (tuple (k 2)) (tuple (v 20)))
+             (map-set kv (tuple (k 3)) (tuple (v 30)))";
+
+        peer.chainstate().enable_map_key_index().unwrap();
+        let contract_addr = peer.miner.origin_address().unwrap();
+
+        let payloads = vec![
+            vec![TransactionPayload::new_smart_contract(&"kv".to_string(), &contract.to_string()).unwrap()],
+            vec![TransactionPayload::new_contract_call(contract_addr.clone(), "kv", "del", vec![Value::Int(2)]).unwrap(),
+                 TransactionPayload::new_contract_call(contract_addr.clone(), "kv", "put", vec![Value::Int(4), Value::Int(40)]).unwrap()]
+        ];
+
+        let mut index_hashes = vec![];
+        for tenure_payloads in payloads.into_iter() {
+            let (burn_ops, stacks_block, microblocks) = peer.make_tenure_with_payloads(tenure_payloads);
+            peer.next_burnchain_block(burn_ops);
+            peer.process_stacks_epoch_at_tip(&stacks_block, &microblocks);
+
+            let sn = BurnDB::get_canonical_burn_chain_tip(peer.burndb.as_ref().unwrap().conn()).unwrap();
+            index_hashes.push(StacksBlockHeader::make_index_block_hash(&sn.burn_header_hash, &stacks_block.block_hash()));
+        }
+        index_hashes
+    }
+
+    fn make_rpc_map_key(k: i128) -> Value {
+        Value::from(TupleData::from_data(vec![("k".into(), Value::Int(k))]).unwrap())
+    }
+
+    /// Decode a page of `kv` entries into (k, v) pairs.
+    fn get_rpc_map_entries(data: &MapEntriesResponse) -> Vec<(i128, i128)> {
+        data.entries.iter()
+            .map(|entry| {
+                let k = match Value::try_deserialize_hex_untyped(&entry.key[2..]).unwrap() {
+                    Value::Tuple(data) => data.get("k").unwrap().clone(),
+                    _ => panic!("Not a tuple key")
+                };
+                let v = match Value::try_deserialize_hex_untyped(&entry.data[2..]).unwrap() {
+                    Value::Tuple(data) => data.get("v").unwrap().clone(),
+                    _ => panic!("Not a tuple value")
+                };
+                match (k, v) {
+                    (Value::Int(k), Value::Int(v)) => (k, v),
+                    _ => panic!("Not an int entry")
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rpc_get_map_entries_not_indexed() {
+        test_rpc("test_rpc_get_map_entries_not_indexed", 40240, 40241, 50240, 50241,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     mine_rpc_tenures(peer_server, 1);
+                     let contract_addr = peer_server.miner.origin_address().unwrap();
+
+                     // the server never turned on the key index
+                     convo_client.new_get_map_entries(contract_addr, ContractName::try_from("kv".to_string()).unwrap(), ClarityName::try_from("kv".to_string()).unwrap(),
+                                                      None, 10, false, TipRequest::UseLatestAnchoredTip)
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::NotFound(response_md, msg) => {
+                           assert!(msg.find("does not index data map keys").is_some());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_map_entries_at_tip() {
+        test_rpc("test_rpc_get_map_entries_at_tip", 40250, 40251, 50250, 50251,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let index_hashes = mine_rpc_map_tenures(peer_server);
+                     let contract_addr = peer_server.miner.origin_address().unwrap();
+
+                     // read as of the block that deployed the contract, before key 2 was deleted
+                     convo_client.new_get_map_entries(contract_addr, ContractName::try_from("kv".to_string()).unwrap(), ClarityName::try_from("kv".to_string()).unwrap(),
+                                                      None, 10, true, TipRequest::SpecificTip(index_hashes[0].clone()))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::GetMapEntries(response_md, data) => {
+                           assert_eq!(get_rpc_map_entries(data), vec![(1, 10), (2, 20), (3, 30)]);
+                           assert!(data.entries.iter().all(|entry| entry.marf_proof.is_some()));
+
+                           // key 4 was written by a later block, so it's skipped, and the index is exhausted
+                           assert_eq!(data.next_cursor, None);
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_get_map_entries_paging() {
+        test_rpc("test_rpc_get_map_entries_paging", 40260, 40261, 50260, 50261,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     mine_rpc_map_tenures(peer_server);
+                     let contract_addr = peer_server.miner.origin_address().unwrap();
+
+                     // resume after key 1; key 2 has since been deleted
+                     convo_client.new_get_map_entries(contract_addr, ContractName::try_from("kv".to_string()).unwrap(), ClarityName::try_from("kv".to_string()).unwrap(),
+                                                      Some(make_rpc_map_key(1)), 1, false, TipRequest::UseLatestAnchoredTip)
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     match http_response {
+                        HttpResponseType::GetMapEntries(response_md, data) => {
+                           assert_eq!(get_rpc_map_entries(data), vec![(3, 30)]);
+                           assert!(data.entries.iter().all(|entry| entry.marf_proof.is_none()));
+                           assert_eq!(data.next_cursor, Some(format!("0x{}", make_rpc_map_key(3).serialize())));
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_access_control() {
+        let peer_config = TestPeerConfig::new("test_rpc_access_control", 40130, 40131);
+        let tx = codec_all_transactions(&TransactionVersion::Testnet, 0x80000000, &TransactionAnchorMode::Any, &TransactionPostConditionMode::Allow)
+            .pop()
+            .unwrap();
+
+        let mut keyed_opts = peer_config.connection_opts.clone();
+        keyed_opts.rpc_api_keys = vec!["key-1".to_string()];
+        keyed_opts.rpc_api_key_rate_limits.expensive = 1;
+
+        let mut public_opts = peer_config.connection_opts.clone();
+        public_opts.rpc_disable_post_transaction = true;
+        public_opts.rpc_ip_rate_limits.read = 2;
+
+        let addr = "127.0.0.1:40131".parse::<SocketAddr>().unwrap();
+        let keyed_convo = ConversationHttp::new(peer_config.network_id, &peer_config.burnchain, addr.clone(), None, PeerHost::from_socketaddr(&addr), &keyed_opts, 0);
+        let public_convo = ConversationHttp::new(peer_config.network_id, &peer_config.burnchain, addr.clone(), None, PeerHost::from_socketaddr(&addr), &public_opts, 1);
+
+        let mut rate_limiter = RPCRateLimiter::new();
+
+        // keyed node: preflight requests are always allowed
+        let preflight = HttpRequestType::OptionsPreflight(HttpRequestMetadata::from_host(PeerHost::from_socketaddr(&addr)), "/v2/info".to_string());
+        assert!(keyed_convo.check_access(&preflight, &mut rate_limiter, 0).is_none());
+
+        // keyed node: missing and bad keys are rejected
+        let mut getinfo = keyed_convo.new_getinfo();
+        match keyed_convo.check_access(&getinfo, &mut rate_limiter, 0) {
+            Some(HttpResponseType::Unauthorized(_, msg)) => assert_eq!(msg, "Missing API key"),
+            x => panic!("Expected 401, got {:?}", &x)
+        }
+
+        getinfo.metadata_mut().api_key = Some("key-2".to_string());
+        match keyed_convo.check_access(&getinfo, &mut rate_limiter, 0) {
+            Some(HttpResponseType::Unauthorized(_, msg)) => assert_eq!(msg, "Invalid API key"),
+            x => panic!("Expected 401, got {:?}", &x)
+        }
+
+        // keyed node: good keys are charged against the key's budget
+        getinfo.metadata_mut().api_key = Some("key-1".to_string());
+        for _ in 0..10 {
+            assert!(keyed_convo.check_access(&getinfo, &mut rate_limiter, 0).is_none());
+        }
+
+        let mut get_abi = HttpRequestType::GetContractABI(HttpRequestMetadata::from_host(PeerHost::from_socketaddr(&addr)), tx.origin_address(), ContractName::try_from("hello-world".to_string()).unwrap(), TipRequest::UseLatestAnchoredTip);
+        get_abi.metadata_mut().api_key = Some("key-1".to_string());
+        assert!(keyed_convo.check_access(&get_abi, &mut rate_limiter, 0).is_none());
+        match keyed_convo.check_access(&get_abi, &mut rate_limiter, 0) {
+            Some(HttpResponseType::TooManyRequests(_, retry_after, _)) => assert_eq!(retry_after, 60),
+            x => panic!("Expected 429, got {:?}", &x)
+        }
+        assert!(keyed_convo.check_access(&get_abi, &mut rate_limiter, 60000).is_none());
+
+        // public node: transactions can't be posted
+        let mut post_tx = public_convo.new_post_transaction(tx.clone());
+        match public_convo.check_access(&post_tx, &mut rate_limiter, 0) {
+            Some(HttpResponseType::Forbidden(..)) => {},
+            x => panic!("Expected 403, got {:?}", &x)
+        }
+
+        // public node: keys are ignored, since none are configured
+        post_tx.metadata_mut().api_key = Some("key-1".to_string());
+        match public_convo.check_access(&post_tx, &mut rate_limiter, 0) {
+            Some(HttpResponseType::Forbidden(..)) => {},
+            x => panic!("Expected 403, got {:?}", &x)
+        }
+
+        // public node: reads are charged against the client IP
+        let getinfo = public_convo.new_getinfo();
+        assert!(public_convo.check_access(&getinfo, &mut rate_limiter, 0).is_none());
+        assert!(public_convo.check_access(&getinfo, &mut rate_limiter, 0).is_none());
+        match public_convo.check_access(&getinfo, &mut rate_limiter, 0) {
+            Some(HttpResponseType::TooManyRequests(_, retry_after, _)) => assert_eq!(retry_after, 30),
+            x => panic!("Expected 429, got {:?}", &x)
+        }
+        assert!(public_convo.check_access(&getinfo, &mut rate_limiter, 30000).is_none());
+    }
+
+    #[test]
+    fn test_rpc_api_key_match() {
+        let keys = vec!["key-1".to_string(), "key-2".to_string()];
+        assert!(ConversationHttp::is_api_key_in(&keys, "key-1"));
+        assert!(ConversationHttp::is_api_key_in(&keys, "key-2"));
+        assert!(!ConversationHttp::is_api_key_in(&keys, "key-3"));
+        assert!(!ConversationHttp::is_api_key_in(&keys, "key-"));
+        assert!(!ConversationHttp::is_api_key_in(&keys, "key-12"));
+        assert!(!ConversationHttp::is_api_key_in(&keys, ""));
+        assert!(!ConversationHttp::is_api_key_in(&vec![], "key-1"));
+    }
+
+    #[test]
+    fn test_rpc_peer_admin_access_control() {
+        let peer_config = TestPeerConfig::new("test_rpc_peer_admin_access_control", 40200, 40201);
+
+        let mut keyed_opts = peer_config.connection_opts.clone();
+        keyed_opts.rpc_api_keys = vec!["key-1".to_string()];
+        keyed_opts.rpc_admin_api_keys = vec!["admin-key-1".to_string()];
+
+        let public_opts = peer_config.connection_opts.clone();
+
+        let local_addr = "127.0.0.1:40201".parse::<SocketAddr>().unwrap();
+        let remote_addr = "1.2.3.4:40201".parse::<SocketAddr>().unwrap();
+
+        let keyed_local_convo = ConversationHttp::new(peer_config.network_id, &peer_config.burnchain, local_addr.clone(), None, PeerHost::from_socketaddr(&local_addr), &keyed_opts, 0);
+        let keyed_remote_convo = ConversationHttp::new(peer_config.network_id, &peer_config.burnchain, remote_addr.clone(), None, PeerHost::from_socketaddr(&local_addr), &keyed_opts, 1);
+        let public_local_convo = ConversationHttp::new(peer_config.network_id, &peer_config.burnchain, local_addr.clone(), None, PeerHost::from_socketaddr(&local_addr), &public_opts, 2);
+        let public_remote_convo = ConversationHttp::new(peer_config.network_id, &peer_config.burnchain, remote_addr.clone(), None, PeerHost::from_socketaddr(&local_addr), &public_opts, 3);
+
+        let mut rate_limiter = RPCRateLimiter::new();
+
+        let md = HttpRequestMetadata::from_host(PeerHost::from_socketaddr(&local_addr));
+        let reqs = vec![
+            HttpRequestType::GetPeerReputations(md.clone()),
+            HttpRequestType::PostPeerAdmin(md.clone(), PeerAdminAction::Ban(None), PeerSelector::Address(PeerAddress::from_socketaddr(&remote_addr), 20444))
+        ];
+
+        for req in reqs.into_iter() {
+            let mut admin_req = req.clone();
+            admin_req.metadata_mut().api_key = Some("admin-key-1".to_string());
+
+            let mut user_req = req.clone();
+            user_req.metadata_mut().api_key = Some("key-1".to_string());
+
+            // keyed node: only admin keys will do, even from localhost
+            for convo in [&keyed_local_convo, &keyed_remote_convo].iter() {
+                assert!(convo.check_access(&admin_req, &mut rate_limiter, 0).is_none());
+                for bad_req in [&req, &user_req].iter() {
+                    match convo.check_access(bad_req, &mut rate_limiter, 0) {
+                        Some(HttpResponseType::Forbidden(..)) => {},
+                        x => panic!("Expected 403, got {:?}", &x)
+                    }
+                }
+            }
+
+            // keyed node: admin keys are only good for peer administration
+            let mut getinfo = keyed_local_convo.new_getinfo();
+            getinfo.metadata_mut().api_key = Some("admin-key-1".to_string());
+            match keyed_local_convo.check_access(&getinfo, &mut rate_limiter, 0) {
+                Some(HttpResponseType::Unauthorized(_, msg)) => assert_eq!(msg, "Invalid API key"),
+                x => panic!("Expected 401, got {:?}", &x)
+            }
+
+            // public node: only localhost, with or without a key
+            assert!(public_local_convo.check_access(&req, &mut rate_limiter, 0).is_none());
+            assert!(public_local_convo.check_access(&admin_req, &mut rate_limiter, 0).is_none());
+            for any_req in [&req, &admin_req].iter() {
+                match public_remote_convo.check_access(any_req, &mut rate_limiter, 0) {
+                    Some(HttpResponseType::Forbidden(..)) => {},
+                    x => panic!("Expected 403, got {:?}", &x)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rpc_get_peer_reputations() {
+        test_rpc("test_rpc_get_peer_reputations", 40210, 40211, 50210, 50211,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     // the server has a grudge against the client
+                     let nk = peer_client.to_neighbor().addr;
+                     let mut tx = peer_server.network.peerdb.tx_begin().unwrap();
+                     PeerDB::set_peer_reputation(&mut tx, nk.network_id, &nk.addrbytes, nk.port, 50, get_epoch_time_secs()).unwrap();
+                     tx.commit().unwrap();
+
+                     HttpRequestType::GetPeerReputations(HttpRequestMetadata::from_host(peer_server.to_peer_host()))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     let nk = peer_client.to_neighbor().addr;
+                     match http_response {
+                        HttpResponseType::PeerReputations(response_md, reputations) => {
+                           assert_eq!(reputations.len(), 1);
+                           assert_eq!(reputations[0].addrbytes, nk.addrbytes);
+                           assert_eq!(reputations[0].port, nk.port);
+                           assert_eq!(reputations[0].score, 50);
+                           assert_eq!(reputations[0].blacklisted, 0);
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+
+    #[test]
+    fn test_rpc_post_peer_admin() {
+        test_rpc("test_rpc_post_peer_admin", 40220, 40221, 50220, 50221,
+                 |ref mut peer_client, ref mut convo_client, ref mut peer_server, ref mut convo_server| {
+                     let nk = peer_client.to_neighbor().addr;
+                     HttpRequestType::PostPeerAdmin(HttpRequestMetadata::from_host(peer_server.to_peer_host()), PeerAdminAction::Ban(Some(3600)), PeerSelector::Address(nk.addrbytes.clone(), nk.port))
+                 },
+                 |ref http_request, ref http_response, ref mut peer_client, ref mut peer_server| {
+                     let nk = peer_client.to_neighbor().addr;
+                     let neighbor = PeerDB::get_peer(peer_server.network.peerdb.conn(), nk.network_id, &nk.addrbytes, nk.port).unwrap().unwrap();
+                     assert!(neighbor.is_blacklisted());
+
+                     match http_response {
+                        HttpResponseType::PeerReputations(response_md, reputations) => {
+                           assert_eq!(reputations.len(), 1);
+                           assert_eq!(reputations[0].port, nk.port);
+                           assert_eq!(reputations[0].blacklisted, neighbor.blacklisted);
+                           assert!(reputations[0].blacklisted as u64 > get_epoch_time_secs());
+                           true
+                        },
+                        _ => {
+                           error!("Invalid response: {:?}", &http_response);
+                           false
+                        }
+                    }
+                });
+    }
+}
@@ -39,6 +39,8 @@ use net::poll::*;
 use net::rpc::*;
 use net::http::*;
 use net::p2p::PeerMap;
+use net::websocket::RPCEvent;
+use net::ratelimit::RPCRateLimiter;
 
 use chainstate::burn::db::burndb::BurnDB;
 use chainstate::stacks::db::StacksChainState;
@@ -48,6 +50,7 @@ use burnchains::BurnchainView;
 
 use mio::net as mio_net;
 
+use util::get_epoch_time_ms;
 use util::get_epoch_time_secs;
 
 use core::mempool::*;
@@ -58,10 +61,10 @@ pub struct HttpPeer {
 
     // ongoing http conversations (either they reached out to us, or we to them)
     pub peers: HashMap<usize, ConversationHttp>,
-    pub sockets: HashMap<usize, mio_net::TcpStream>,
+    pub sockets: HashMap<usize, NetworkSocket>,
 
     // outbound connections that are pending connection 
-    pub connecting: HashMap<usize, (mio_net::TcpStream, Option<UrlString>, Option<HttpRequestType>, u64)>,
+    pub connecting: HashMap<usize, (NetworkSocket, Option<UrlString>, Option<HttpRequestType>, u64)>,
 
     // server network handle
     pub http_server_handle: usize,
@@ -71,6 +74,9 @@ pub struct HttpPeer {
 
     // connection options
     pub connection_opts: ConnectionOptions,
+
+    // per-client and per-API-key request budgets
+    pub rate_limiter: RPCRateLimiter,
 }
 
 impl HttpPeer {
@@ -85,7 +91,8 @@ impl HttpPeer {
             http_server_handle: server_handle,
 
             burnchain: burnchain,
-            connection_opts: conn_opts
+            connection_opts: conn_opts,
+            rate_limiter: RPCRateLimiter::new(),
         }
     }
 
@@ -111,7 +118,7 @@ impl HttpPeer {
     }
     
     /// Get a mut ref to a conversation and its socket
-    pub fn get_conversation_and_socket(&mut self, event_id: usize) -> (Option<&mut ConversationHttp>, Option<&mut mio::net::TcpStream>) {
+    pub fn get_conversation_and_socket(&mut self, event_id: usize) -> (Option<&mut ConversationHttp>, Option<&mut NetworkSocket>) {
         (self.peers.get_mut(&event_id), self.sockets.get_mut(&event_id))
     }
 
@@ -119,12 +126,12 @@ impl HttpPeer {
     /// its origin.  Once connected, optionally send the given request.
     /// Idempotent -- will not re-connect if already connected and there is a free conversation channel open 
     /// (will return Error::AlreadyConnected with the event ID)
-    pub fn connect_http(&mut self, network_state: &mut NetworkState, data_url: UrlString, addr: SocketAddr, request: Option<HttpRequestType>) -> Result<usize, net_error> {
+    pub fn connect_http(&mut self, network_state: &mut dyn NetworkTransport, data_url: UrlString, addr: SocketAddr, request: Option<HttpRequestType>) -> Result<usize, net_error> {
         if let Some(event_id) = self.find_free_conversation(&data_url) {
             return Err(net_error::AlreadyConnected(event_id));
         }
 
-        let sock = NetworkState::connect(&addr)?;
+        let sock = network_state.connect(&addr)?;
         let hint_event_id = network_state.next_event_id()?;
         let next_event_id = network_state.register(self.http_server_handle, hint_event_id, &sock)?;
 
@@ -166,7 +173,7 @@ impl HttpPeer {
     /// Low-level method to register a socket/event pair on the p2p network interface.
     /// Call only once the socket is connected (called once the socket triggers ready).
     /// Will destroy the socket if we can't register for whatever reason.
-    fn register_http(&mut self, network_state: &mut NetworkState, chainstate: &mut StacksChainState, event_id: usize, mut socket: mio_net::TcpStream, outbound_url: Option<UrlString>, initial_request: Option<HttpRequestType>) -> Result<(), net_error> {
+    fn register_http(&mut self, network_state: &mut dyn NetworkTransport, chainstate: &mut StacksChainState, event_id: usize, mut socket: NetworkSocket, outbound_url: Option<UrlString>, initial_request: Option<HttpRequestType>) -> Result<(), net_error> {
         let client_addr = match socket.peer_addr() {
             Ok(addr) => addr,
             Err(e) => {
@@ -218,7 +225,7 @@ impl HttpPeer {
     }
     
     /// Deregister a socket/event pair
-    pub fn deregister_http(&mut self, network_state: &mut NetworkState, event_id: usize) -> () {
+    pub fn deregister_http(&mut self, network_state: &mut dyn NetworkTransport, event_id: usize) -> () {
         if self.peers.contains_key(&event_id) {
             // kill the conversation
             self.peers.remove(&event_id);
@@ -241,7 +248,7 @@ impl HttpPeer {
     }
     
     /// Remove slow/unresponsive peers
-    fn disconnect_unresponsive(&mut self, network_state: &mut NetworkState) -> () {
+    fn disconnect_unresponsive(&mut self, network_state: &mut dyn NetworkTransport) -> () {
         let now = get_epoch_time_secs();
         let mut to_remove = vec![];
         for (event_id, (socket, _, _, ts)) in self.connecting.iter() {
@@ -251,7 +258,16 @@ impl HttpPeer {
             }
         }
         
-        for (event_id, convo) in self.peers.iter() {
+        for (event_id, convo) in self.peers.iter_mut() {
+            if convo.is_websocket() {
+                // subscribers can go quiet, but they have to answer our pings
+                if !convo.check_websocket_liveness(now, self.connection_opts.idle_timeout, self.connection_opts.timeout) {
+                    debug!("Removing unresponsive websocket {:?}", convo);
+                    to_remove.push(*event_id);
+                }
+                continue;
+            }
+
             let mut last_request_time = convo.get_last_request_time();
             if last_request_time == 0 {
                 // never got a request
@@ -278,7 +294,7 @@ impl HttpPeer {
 
     /// Saturate a conversation's socket -- either sends the whole request, or fills the socket
     /// buffer.
-    pub fn saturate_http_socket(client_sock: &mut mio::net::TcpStream, convo: &mut ConversationHttp, chainstate: &mut StacksChainState) -> Result<(), net_error> {
+    pub fn saturate_http_socket(client_sock: &mut NetworkSocket, convo: &mut ConversationHttp, chainstate: &mut StacksChainState) -> Result<(), net_error> {
         // saturate the socket
         loop {
             let send_res = convo.send(client_sock, chainstate);
@@ -300,7 +316,7 @@ impl HttpPeer {
     
     /// Process new inbound HTTP connections we just accepted.
     /// Returns the event IDs of sockets we need to register
-    fn process_new_sockets(&mut self, network_state: &mut NetworkState, chainstate: &mut StacksChainState, poll_state: &mut NetworkPollState) -> Result<Vec<usize>, net_error> {
+    fn process_new_sockets(&mut self, network_state: &mut dyn NetworkTransport, chainstate: &mut StacksChainState, poll_state: &mut NetworkPollState) -> Result<Vec<usize>, net_error> {
         let mut registered = vec![];
 
         for (hint_event_id, client_sock) in poll_state.new.drain() {
@@ -333,9 +349,9 @@ impl HttpPeer {
     /// Returns whether or not the convo is still alive, as well as any message(s) that need to be
     /// forwarded to the peer network.
     fn process_http_conversation(chain_view: &BurnchainView, peers: &PeerMap,
-                                 burndb: &BurnDB, peerdb: &PeerDB,
-                                 chainstate: &mut StacksChainState, mempool: &mut MemPoolDB,
-                                 event_id: usize, client_sock: &mut mio_net::TcpStream,
+                                 burndb: &BurnDB, peerdb: &mut PeerDB,
+                                 chainstate: &mut StacksChainState, mempool: &mut MemPoolDB, rate_limiter: &mut RPCRateLimiter,
+                                 event_id: usize, client_sock: &mut NetworkSocket,
                                  convo: &mut ConversationHttp) -> Result<(bool, Vec<StacksMessageType>), net_error> {
         // get incoming bytes and update the state of this conversation.
         let mut convo_dead = false;
@@ -380,7 +396,7 @@ impl HttpPeer {
         // react to inbound messages -- do we need to send something out, or fulfill requests
         // to other threads?  Try to chat even if the recv() failed, since we'll want to at
         // least drain the conversation inbox.
-        let msgs = match convo.chat(chain_view, peers, burndb, peerdb, chainstate, mempool) {
+        let msgs = match convo.chat(chain_view, peers, burndb, peerdb, chainstate, mempool, rate_limiter) {
             Ok(msgs) => msgs,
             Err(e) => {
                 debug!("Failed to converse HTTP on event {} (socket {:?}): {:?}", event_id, &client_sock, &e);
@@ -410,7 +426,7 @@ impl HttpPeer {
     }
 
     /// Process newly-connected sockets
-    fn process_connecting_sockets(&mut self, network_state: &mut NetworkState, chainstate: &mut StacksChainState, poll_state: &mut NetworkPollState) -> () {
+    fn process_connecting_sockets(&mut self, network_state: &mut dyn NetworkTransport, chainstate: &mut StacksChainState, poll_state: &mut NetworkPollState) -> () {
         for event_id in poll_state.ready.iter() {
             if self.connecting.contains_key(event_id) {
                 let (socket, data_url, initial_request_opt, _) = self.connecting.remove(event_id).unwrap();
@@ -427,7 +443,7 @@ impl HttpPeer {
     /// Advance the state of all such conversations with remote peers.
     /// Return the list of events that correspond to failed conversations, as well as the list of
     /// peer network messages we'll need to forward
-    fn process_ready_sockets(&mut self, poll_state: &mut NetworkPollState, peers: &PeerMap, burndb: &BurnDB, peerdb: &PeerDB,
+    fn process_ready_sockets(&mut self, poll_state: &mut NetworkPollState, peers: &PeerMap, burndb: &BurnDB, peerdb: &mut PeerDB,
                              chainstate: &mut StacksChainState, mempool: &mut MemPoolDB) -> (Vec<StacksMessageType>, Vec<usize>) {
         let mut to_remove = vec![];
         let mut msgs = vec![];
@@ -450,7 +466,7 @@ impl HttpPeer {
                 Some(ref mut convo) => {
                     // activity on a http socket
                     test_debug!("Process HTTP data from {:?}", convo);
-                    match HttpPeer::process_http_conversation(&self.chain_view, peers, burndb, peerdb, chainstate, mempool,
+                    match HttpPeer::process_http_conversation(&self.chain_view, peers, burndb, peerdb, chainstate, mempool, &mut self.rate_limiter,
                                                               *event_id, client_sock, convo) {
                         Ok((alive, mut new_msgs)) => {
                             if !alive {
@@ -494,10 +510,44 @@ impl HttpPeer {
                 test_debug!("Close drained connection {:?}", convo);
                 close.push(*event_id);
             }
+            else if convo.is_websocket_closed() {
+                test_debug!("Close websocket connection {:?}", convo);
+                close.push(*event_id);
+            }
         }
 
         close
     }
+
+    /// Queue up events for websocket subscribers.
+    /// They get sent out on the next call to run().
+    pub fn publish_events(&mut self, events: &Vec<RPCEvent>) -> () {
+        for (_, convo) in self.peers.iter_mut() {
+            let num_queued = convo.publish_events(events);
+            if num_queued > 0 {
+                test_debug!("Queued {} event(s) for websocket {:?}", num_queued, convo);
+            }
+        }
+    }
+
+    /// Send queued websocket frames.  Subscribers don't send us requests, so their sockets
+    /// won't necessarily be readied by the poller when there's something to push to them.
+    /// Return the list of conversation event IDs whose sockets broke.
+    fn flush_websockets(&mut self, chainstate: &mut StacksChainState) -> Vec<usize> {
+        let mut close = vec![];
+        for (event_id, convo) in self.peers.iter_mut() {
+            if !convo.has_websocket_data() || convo.is_websocket_closed() {
+                continue;
+            }
+            if let Some(client_sock) = self.sockets.get_mut(event_id) {
+                if let Err(e) = convo.send(client_sock, chainstate) {
+                    debug!("Failed to send websocket data to event {}: {:?}", event_id, &e);
+                    close.push(*event_id);
+                }
+            }
+        }
+        close
+    }
     
     /// Update HTTP server state
     /// -- accept new connections
@@ -505,7 +555,7 @@ impl HttpPeer {
     /// -- receive data on ready sockets
     /// -- clear out timed-out requests
     /// Returns the list of messages to forward along to the peer network.
-    pub fn run(&mut self, network_state: &mut NetworkState, new_chain_view: BurnchainView, p2p_peers: &PeerMap, burndb: &BurnDB, peerdb: &PeerDB,
+    pub fn run(&mut self, network_state: &mut dyn NetworkTransport, new_chain_view: BurnchainView, p2p_peers: &PeerMap, burndb: &BurnDB, peerdb: &mut PeerDB,
                chainstate: &mut StacksChainState, mempool: &mut MemPoolDB, mut poll_state: NetworkPollState) -> Result<Vec<StacksMessageType>, net_error> {
 
         // update burnchain snapshot
@@ -525,7 +575,8 @@ impl HttpPeer {
         }
 
         // move conversations along
-        let close_events = self.flush_conversations(chainstate);
+        let mut close_events = self.flush_conversations(chainstate);
+        close_events.append(&mut self.flush_websockets(chainstate));
         for close_event in close_events {
             debug!("Close HTTP connection on event {}", close_event);
             self.deregister_http(network_state, close_event);
@@ -539,6 +590,9 @@ impl HttpPeer {
         // clear out slow or non-responsive peers
         self.disconnect_unresponsive(network_state);
 
+        // forget clients that haven't been making requests
+        self.rate_limiter.prune(get_epoch_time_ms());
+
         Ok(stacks_msgs)
     }
 }
@@ -552,6 +606,7 @@ mod test {
     use net::test::*;
     use net::http::*;
     use net::rpc::*;
+    use net::websocket::*;
     
     use burnchains::Burnchain;
     use burnchains::BurnchainView;
@@ -974,4 +1029,81 @@ mod test {
                             true
                         });
     }
+
+    fn decode_websocket_frames(bytes: &[u8]) -> Vec<WebSocketFrame> {
+        let mut frames = vec![];
+        let mut ptr = 0;
+        while ptr < bytes.len() {
+            match WebSocketFrame::decode(&bytes[ptr..], false).unwrap() {
+                Some((frame, consumed)) => {
+                    frames.push(frame);
+                    ptr += consumed;
+                },
+                None => break
+            }
+        }
+        frames
+    }
+
+    fn split_upgrade_response(http_response_bytes: &Vec<u8>) -> (String, Vec<WebSocketFrame>) {
+        let header_end = http_response_bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let preamble = String::from_utf8(http_response_bytes[0..header_end].to_vec()).unwrap();
+        let frames = decode_websocket_frames(&http_response_bytes[header_end..]);
+        (preamble, frames)
+    }
+
+    #[test]
+    fn test_http_websocket_upgrade_pipelined_frames() {
+        test_http_server("test_http_websocket_upgrade_pipelined_frames", 51090, 51091, ConnectionOptions::default(), 1, 0,
+                        |client_id, _| {
+                            let request = HttpRequestType::Subscribe(HttpRequestMetadata::from_host(PeerHost::from_host_port("127.0.0.1".to_string(), 51091)), "dGhlIHNhbXBsZSBub25jZQ==".to_string());
+                            let mut request_bytes = StacksHttp::serialize_request(&request).unwrap();
+
+                            // the client's first frames arrive in the same write as the upgrade request
+                            request_bytes.append(&mut WebSocketFrame::text(r#"{"action":"subscribe","topic":"blocks"}"#).encode(Some([1,2,3,4])));
+                            request_bytes.append(&mut WebSocketFrame::close(WEBSOCKET_CLOSE_NORMAL, "bye").encode(Some([5,6,7,8])));
+                            request_bytes
+                        },
+                        |client_id, http_response_bytes_res| {
+                            let http_response_bytes = http_response_bytes_res.unwrap();
+                            let (preamble, frames) = split_upgrade_response(&http_response_bytes);
+                            eprintln!("HTTP preamble\n{}\nframes: {:?}", &preamble, &frames);
+
+                            assert!(preamble.starts_with("HTTP/1.1 101"));
+                            assert_eq!(frames.len(), 2);
+
+                            assert_eq!(frames[0].opcode, WebSocketOpcode::Text);
+                            let reply = String::from_utf8(frames[0].payload.clone()).unwrap();
+                            assert!(reply.find("\"subscribed\"").is_some());
+                            assert!(reply.find("\"blocks\"").is_some());
+
+                            assert_eq!(frames[1].opcode, WebSocketOpcode::Close);
+                            true
+                        });
+    }
+
+    #[test]
+    fn test_http_websocket_idle_ping_timeout() {
+        let mut conn_opts = ConnectionOptions::default();
+        conn_opts.idle_timeout = 1;
+        conn_opts.timeout = 1;
+
+        test_http_server("test_http_websocket_idle_ping_timeout", 51092, 51093, conn_opts, 1, 0,
+                        |client_id, _| {
+                            // upgrade, then never say anything again
+                            let request = HttpRequestType::Subscribe(HttpRequestMetadata::from_host(PeerHost::from_host_port("127.0.0.1".to_string(), 51093)), "dGhlIHNhbXBsZSBub25jZQ==".to_string());
+                            StacksHttp::serialize_request(&request).unwrap()
+                        },
+                        |client_id, http_response_bytes_res| {
+                            // server pings the quiet client, and hangs up when no pong comes back
+                            let http_response_bytes = http_response_bytes_res.unwrap();
+                            let (preamble, frames) = split_upgrade_response(&http_response_bytes);
+                            eprintln!("HTTP preamble\n{}\nframes: {:?}", &preamble, &frames);
+
+                            assert!(preamble.starts_with("HTTP/1.1 101"));
+                            assert!(frames.len() >= 1);
+                            assert_eq!(frames[0].opcode, WebSocketOpcode::Ping);
+                            true
+                        });
+    }
 }
@@ -0,0 +1,1439 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Deterministic in-memory network for testing the peer network.
+//
+// A SimNetwork is a set of hosts joined by simulated TCP links.  Each host gets a SimTransport,
+// which a PeerNetwork uses in place of the mio-backed NetworkState.  Bytes written to a
+// SimSocket are cut into segments and delivered to the other end once the virtual clock passes
+// their delivery time.  Link latency, segment loss (modeled as retransmission delay), and
+// partitions are all drawn from a single seeded RNG, so a given seed and a given sequence of
+// socket operations always yields the same delivery schedule.
+//
+// The virtual clock only moves when the test calls advance().  While a SimNetwork exists, it also
+// drives the get_epoch_time_*() functions on the creating thread, so timeouts in the peer network
+// fire on simulated time.
+//
+// The SimHarness gives each peer its own RNG, seeded from the network's RNG, and installs it
+// (via util::set_seeded_rng()) while that peer runs.  With a fixed latency and no loss, the same
+// seed then yields the same run.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::io::ErrorKind;
+use std::fmt;
+use std::any::Any;
+use std::cmp;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::convert::TryFrom;
+
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use net::Error as net_error;
+use net::poll::NetworkPollState;
+use net::poll::NetworkSocket;
+use net::poll::NetworkTransport;
+use net::poll::TransportSocket;
+use net::dns::DNSClient;
+use net::dns::DNSResolver;
+use net::test::*;
+
+use util::get_epoch_time_ms;
+use util::set_virtual_time_ms;
+use util::set_seeded_rng;
+use util::log;
+
+use util::strings::UrlString;
+
+/// Default one-way latency range of a simulated link, in milliseconds
+pub const SIM_DEFAULT_MIN_LATENCY_MS : u64 = 10;
+pub const SIM_DEFAULT_MAX_LATENCY_MS : u64 = 50;
+
+/// How far the harness advances the virtual clock on each round
+pub const SIM_DEFAULT_TICK_MS : u64 = 100;
+
+/// How long a lost segment takes to be retransmitted
+pub const SIM_RETRANSMIT_TIMEOUT_MS : u64 = 200;
+
+/// A segment that is lost this many times in a row resets its connection
+pub const SIM_MAX_RETRANSMITS : u32 = 8;
+
+/// Largest segment a single write() will produce
+pub const SIM_MAX_SEGMENT_SIZE : usize = 1460;
+
+/// How many bytes may be in flight in one direction before writes would block
+pub const SIM_SOCKET_BUFFER_SIZE : usize = 65536;
+
+/// First ephemeral port handed out to outbound connections
+const SIM_EPHEMERAL_PORT_START : u16 = 49152;
+
+/// Host ID of a connection end that has not been accepted by anyone yet
+const SIM_NO_HOST : usize = usize::max_value();
+
+#[derive(Debug, Clone, PartialEq)]
+enum SimPayload {
+    Syn,
+    SynAck,
+    Refused,
+    Data(Vec<u8>),
+    Fin
+}
+
+#[derive(Debug)]
+struct SimSegment {
+    deliver_at: u128,
+    payload: SimPayload
+}
+
+#[derive(Debug)]
+struct SimEndpoint {
+    host: usize,
+    addr: SocketAddr,
+    event_id: Option<usize>,            // event ID this end is registered under on its host
+    recv_buf: VecDeque<u8>,             // delivered bytes, not yet read
+    inflight: VecDeque<SimSegment>,     // segments sent to this end, not yet delivered
+    inflight_bytes: usize,
+    last_deliver_at: u128,              // segments are delivered in order
+    connected: bool,
+    eof: bool,                          // the other end's FIN arrived
+    closed: bool,                       // this end was shut down
+    blocked: bool,                      // a write on this end would have blocked
+    ready: bool                         // something happened since the last poll
+}
+
+impl SimEndpoint {
+    fn new(host: usize, addr: SocketAddr) -> SimEndpoint {
+        SimEndpoint {
+            host: host,
+            addr: addr,
+            event_id: None,
+            recv_buf: VecDeque::new(),
+            inflight: VecDeque::new(),
+            inflight_bytes: 0,
+            last_deliver_at: 0,
+            connected: false,
+            eof: false,
+            closed: false,
+            blocked: false,
+            ready: false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SimConnection {
+    ends: [SimEndpoint; 2],     // ends[0] connected to ends[1]
+    refused: bool,
+    reset: bool
+}
+
+#[derive(Debug)]
+struct SimListener {
+    host: usize,
+    server_event_id: usize,
+    accepted: VecDeque<usize>   // connection IDs waiting to be handed to the host
+}
+
+struct SimNetworkState {
+    now_ms: u128,
+    rng: StdRng,
+    min_latency_ms: u64,
+    max_latency_ms: u64,
+    loss_rate: f64,
+    num_hosts: usize,
+    partitions: HashMap<usize, usize>,      // host ID to partition group (default 0)
+    listeners: BTreeMap<SocketAddr, SimListener>,
+    connections: BTreeMap<usize, SimConnection>,
+    next_conn_id: usize,
+    next_port: u16
+}
+
+impl SimNetworkState {
+    fn partition_of(&self, host: usize) -> usize {
+        *self.partitions.get(&host).unwrap_or(&0)
+    }
+
+    /// Can these two hosts currently talk to each other?
+    fn is_partitioned(&self, host_1: usize, host_2: usize) -> bool {
+        if host_1 == SIM_NO_HOST || host_2 == SIM_NO_HOST {
+            return false;
+        }
+        self.partition_of(host_1) != self.partition_of(host_2)
+    }
+
+    /// When will a segment sent now arrive, given that the previous segment on its link arrives
+    /// at `after`?  Returns None if the segment is lost so many times that the connection resets.
+    fn schedule(&mut self, after: u128) -> Option<u128> {
+        let latency = self.rng.gen_range(self.min_latency_ms, self.max_latency_ms + 1);
+        let mut deliver_at = self.now_ms + (latency as u128);
+        let mut retransmits = 0;
+        while self.loss_rate > 0.0 && self.rng.gen::<f64>() < self.loss_rate {
+            retransmits += 1;
+            if retransmits > SIM_MAX_RETRANSMITS {
+                return None;
+            }
+            deliver_at += SIM_RETRANSMIT_TIMEOUT_MS as u128;
+        }
+        Some(cmp::max(deliver_at, after))
+    }
+
+    /// Queue a segment for delivery to one end of a connection.
+    /// Returns false if the connection was reset instead.
+    fn send_segment(&mut self, conn_id: usize, to_side: usize, payload: SimPayload) -> bool {
+        let after = match self.connections.get(&conn_id) {
+            Some(conn) => conn.ends[to_side].last_deliver_at,
+            None => {
+                return false;
+            }
+        };
+
+        let deliver_at = match self.schedule(after) {
+            Some(t) => t,
+            None => {
+                test_debug!("Sim: connection {} reset after {} retransmits", conn_id, SIM_MAX_RETRANSMITS);
+                self.reset_connection(conn_id);
+                return false;
+            }
+        };
+
+        let conn = self.connections.get_mut(&conn_id).expect("BUG: connection disappeared");
+        let end = &mut conn.ends[to_side];
+        if let SimPayload::Data(ref bytes) = payload {
+            end.inflight_bytes += bytes.len();
+        }
+        end.last_deliver_at = deliver_at;
+        end.inflight.push_back(SimSegment {
+            deliver_at: deliver_at,
+            payload: payload
+        });
+        true
+    }
+
+    fn reset_connection(&mut self, conn_id: usize) -> () {
+        if let Some(conn) = self.connections.get_mut(&conn_id) {
+            conn.reset = true;
+            for end in conn.ends.iter_mut() {
+                end.inflight.clear();
+                end.inflight_bytes = 0;
+                end.ready = true;
+            }
+        }
+    }
+
+    /// Open a new connection from `host` at `local_addr` to `addr`.
+    fn open_connection(&mut self, host: usize, local_addr: SocketAddr, addr: &SocketAddr) -> usize {
+        let conn_id = self.next_conn_id;
+        self.next_conn_id += 1;
+
+        // resolve the destination now, so partitions apply to the handshake
+        let dest_host = match self.listeners.get(addr) {
+            Some(listener) => listener.host,
+            None => SIM_NO_HOST
+        };
+
+        self.connections.insert(conn_id, SimConnection {
+            ends: [SimEndpoint::new(host, local_addr), SimEndpoint::new(dest_host, addr.clone())],
+            refused: false,
+            reset: false
+        });
+
+        self.send_segment(conn_id, 1, SimPayload::Syn);
+        conn_id
+    }
+
+    fn next_ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = if self.next_port == u16::max_value() { SIM_EPHEMERAL_PORT_START } else { self.next_port + 1 };
+        port
+    }
+
+    /// A SYN arrived at the accepting end of a connection.
+    fn accept_connection(&mut self, conn_id: usize) -> () {
+        let (dest_addr, client_closed) = match self.connections.get(&conn_id) {
+            Some(conn) => (conn.ends[1].addr.clone(), conn.ends[0].closed),
+            None => {
+                return;
+            }
+        };
+
+        if client_closed {
+            // client gave up before we got here
+            return;
+        }
+
+        let accepted = match self.listeners.get_mut(&dest_addr) {
+            Some(listener) => {
+                listener.accepted.push_back(conn_id);
+                let conn = self.connections.get_mut(&conn_id).expect("BUG: connection disappeared");
+                conn.ends[1].host = listener.host;
+                conn.ends[1].connected = true;
+                true
+            },
+            None => false
+        };
+
+        if accepted {
+            self.send_segment(conn_id, 0, SimPayload::SynAck);
+        }
+        else {
+            self.send_segment(conn_id, 0, SimPayload::Refused);
+        }
+    }
+
+    /// Deliver every segment whose time has come and whose link is not partitioned.
+    fn deliver(&mut self) -> () {
+        let conn_ids : Vec<usize> = self.connections.keys().cloned().collect();
+        for conn_id in conn_ids.into_iter() {
+            for to_side in 0..2 {
+                loop {
+                    let payload = {
+                        let (from_host, to_host, due) = match self.connections.get(&conn_id) {
+                            Some(conn) => {
+                                let to_host =
+                                    if to_side == 1 && conn.ends[1].host == SIM_NO_HOST {
+                                        // not accepted yet -- route to whoever listens there now
+                                        self.listeners.get(&conn.ends[1].addr).map(|l| l.host).unwrap_or(SIM_NO_HOST)
+                                    }
+                                    else {
+                                        conn.ends[to_side].host
+                                    };
+
+                                let due = match conn.ends[to_side].inflight.front() {
+                                    Some(segment) => segment.deliver_at <= self.now_ms,
+                                    None => false
+                                };
+                                (conn.ends[1 - to_side].host, to_host, due)
+                            },
+                            None => {
+                                break;
+                            }
+                        };
+
+                        if !due || self.is_partitioned(from_host, to_host) {
+                            break;
+                        }
+
+                        let conn = self.connections.get_mut(&conn_id).expect("BUG: connection disappeared");
+                        conn.ends[to_side].inflight.pop_front().expect("BUG: no segment").payload
+                    };
+
+                    match payload {
+                        SimPayload::Syn => {
+                            self.accept_connection(conn_id);
+                        },
+                        other => {
+                            let conn = self.connections.get_mut(&conn_id).expect("BUG: connection disappeared");
+                            SimNetworkState::apply_segment(conn, to_side, other);
+                        }
+                    }
+                }
+            }
+        }
+
+        // forget connections that both sides are done with
+        let mut dead = vec![];
+        for (conn_id, conn) in self.connections.iter() {
+            if conn.ends[0].closed && (conn.ends[1].closed || !conn.ends[1].connected || conn.refused || conn.reset) {
+                dead.push(*conn_id);
+            }
+        }
+        for conn_id in dead.into_iter() {
+            self.connections.remove(&conn_id);
+            for (_, listener) in self.listeners.iter_mut() {
+                listener.accepted.retain(|id| *id != conn_id);
+            }
+        }
+    }
+
+    fn apply_segment(conn: &mut SimConnection, to_side: usize, payload: SimPayload) -> () {
+        let from_side = 1 - to_side;
+        match payload {
+            SimPayload::Syn => {
+                unreachable!("BUG: SYN is handled by accept_connection()");
+            },
+            SimPayload::SynAck => {
+                conn.ends[to_side].connected = true;
+                conn.ends[to_side].ready = true;
+            },
+            SimPayload::Refused => {
+                conn.refused = true;
+                conn.ends[to_side].ready = true;
+            },
+            SimPayload::Data(bytes) => {
+                conn.ends[to_side].inflight_bytes -= bytes.len();
+                if !conn.ends[to_side].closed {
+                    conn.ends[to_side].recv_buf.extend(bytes.iter());
+                    conn.ends[to_side].ready = true;
+                }
+
+                // sender may be able to write again
+                if conn.ends[from_side].blocked {
+                    conn.ends[from_side].blocked = false;
+                    conn.ends[from_side].ready = true;
+                }
+            },
+            SimPayload::Fin => {
+                conn.ends[to_side].eof = true;
+                conn.ends[to_side].ready = true;
+            }
+        }
+    }
+
+    fn shutdown(&mut self, conn_id: usize, side: usize) -> () {
+        let send_fin = match self.connections.get_mut(&conn_id) {
+            Some(conn) => {
+                if conn.ends[side].closed {
+                    return;
+                }
+                conn.ends[side].closed = true;
+                conn.ends[side].event_id = None;
+                conn.ends[side].recv_buf.clear();
+                conn.ends[side].connected && !conn.reset
+            },
+            None => {
+                return;
+            }
+        };
+
+        if send_fin {
+            self.send_segment(conn_id, 1 - side, SimPayload::Fin);
+        }
+    }
+
+    fn read(&mut self, conn_id: usize, side: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let conn = self.connections.get_mut(&conn_id).ok_or(io::Error::from(ErrorKind::NotConnected))?;
+        let end = &mut conn.ends[side];
+        if end.closed {
+            return Err(io::Error::from(ErrorKind::NotConnected));
+        }
+
+        if end.recv_buf.len() > 0 {
+            let num_read = cmp::min(buf.len(), end.recv_buf.len());
+            for (i, byte) in end.recv_buf.drain(0..num_read).enumerate() {
+                buf[i] = byte;
+            }
+            return Ok(num_read);
+        }
+
+        if conn.reset {
+            Err(io::Error::from(ErrorKind::ConnectionReset))
+        }
+        else if conn.refused {
+            Err(io::Error::from(ErrorKind::ConnectionRefused))
+        }
+        else if end.eof {
+            Ok(0)
+        }
+        else {
+            Err(io::Error::from(ErrorKind::WouldBlock))
+        }
+    }
+
+    fn write(&mut self, conn_id: usize, side: usize, buf: &[u8]) -> io::Result<usize> {
+        let other = 1 - side;
+        let num_written = {
+            let conn = self.connections.get_mut(&conn_id).ok_or(io::Error::from(ErrorKind::NotConnected))?;
+            if conn.ends[side].closed {
+                return Err(io::Error::from(ErrorKind::NotConnected));
+            }
+            if conn.reset {
+                return Err(io::Error::from(ErrorKind::ConnectionReset));
+            }
+            if conn.refused {
+                return Err(io::Error::from(ErrorKind::ConnectionRefused));
+            }
+            if !conn.ends[side].connected {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            if conn.ends[side].eof {
+                return Err(io::Error::from(ErrorKind::BrokenPipe));
+            }
+            if buf.len() == 0 {
+                return Ok(0);
+            }
+
+            let space = SIM_SOCKET_BUFFER_SIZE.saturating_sub(conn.ends[other].inflight_bytes);
+            if space == 0 {
+                conn.ends[side].blocked = true;
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            cmp::min(cmp::min(space, buf.len()), SIM_MAX_SEGMENT_SIZE)
+        };
+
+        if !self.send_segment(conn_id, other, SimPayload::Data(buf[0..num_written].to_vec())) {
+            return Err(io::Error::from(ErrorKind::ConnectionReset));
+        }
+        Ok(num_written)
+    }
+
+    fn peer_addr(&self, conn_id: usize, side: usize) -> io::Result<SocketAddr> {
+        let conn = self.connections.get(&conn_id).ok_or(io::Error::from(ErrorKind::NotConnected))?;
+        if conn.reset || conn.refused || !conn.ends[side].connected || conn.ends[side].closed {
+            return Err(io::Error::from(ErrorKind::NotConnected));
+        }
+        Ok(conn.ends[1 - side].addr.clone())
+    }
+}
+
+/// A simulated network.  Hand one SimTransport to each peer, and call advance() to move time
+/// forward.
+pub struct SimNetwork {
+    state: Arc<Mutex<SimNetworkState>>
+}
+
+impl SimNetwork {
+    /// Make a new simulated network.  The virtual clock starts at the current time, and takes
+    /// over this thread's get_epoch_time_*() until the SimNetwork is dropped.
+    pub fn new(seed: u64) -> SimNetwork {
+        // start on a whole second, so get_epoch_time_secs() ticks over at the same points in
+        // every run
+        let now_ms = (get_epoch_time_ms() / 1000) * 1000;
+        set_virtual_time_ms(Some(now_ms));
+
+        let state = SimNetworkState {
+            now_ms: now_ms,
+            rng: StdRng::seed_from_u64(seed),
+            min_latency_ms: SIM_DEFAULT_MIN_LATENCY_MS,
+            max_latency_ms: SIM_DEFAULT_MAX_LATENCY_MS,
+            loss_rate: 0.0,
+            num_hosts: 0,
+            partitions: HashMap::new(),
+            listeners: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            next_conn_id: 0,
+            next_port: SIM_EPHEMERAL_PORT_START
+        };
+        SimNetwork {
+            state: Arc::new(Mutex::new(state))
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<SimNetworkState> {
+        self.state.lock().expect("FATAL: simulated network lock poisoned")
+    }
+
+    /// Add a host to the network, and get the transport it uses to talk to the others.
+    pub fn transport(&self, event_capacity: usize) -> SimTransport {
+        let host = {
+            let mut state = self.lock();
+            state.num_hosts += 1;
+            state.num_hosts - 1
+        };
+        SimTransport {
+            host: host,
+            state: self.state.clone(),
+            event_capacity: event_capacity,
+            count: 1,
+            servers: vec![],
+            event_map: HashMap::new()
+        }
+    }
+
+    /// Set the range of one-way link latencies
+    pub fn set_latency(&self, min_latency_ms: u64, max_latency_ms: u64) -> () {
+        assert!(min_latency_ms <= max_latency_ms);
+        let mut state = self.lock();
+        state.min_latency_ms = min_latency_ms;
+        state.max_latency_ms = max_latency_ms;
+    }
+
+    /// Set the probability that a segment is lost (and must be retransmitted)
+    pub fn set_loss_rate(&self, loss_rate: f64) -> () {
+        assert!(loss_rate >= 0.0 && loss_rate <= 1.0);
+        self.lock().loss_rate = loss_rate;
+    }
+
+    /// Split the network.  Hosts in different groups cannot reach each other; hosts not named in
+    /// any group can only reach each other.  Traffic across the split is held until heal().
+    pub fn partition(&self, groups: &[Vec<usize>]) -> () {
+        let mut state = self.lock();
+        state.partitions.clear();
+        for (i, group) in groups.iter().enumerate() {
+            for host in group.iter() {
+                state.partitions.insert(*host, i + 1);
+            }
+        }
+    }
+
+    /// Undo any partition
+    pub fn heal(&self) -> () {
+        self.lock().partitions.clear();
+    }
+
+    /// Current virtual time
+    pub fn now_ms(&self) -> u128 {
+        self.lock().now_ms
+    }
+
+    /// Move the virtual clock forward and deliver whatever arrives in the meantime
+    pub fn advance(&self, ms: u64) -> () {
+        let mut state = self.lock();
+        state.now_ms += ms as u128;
+        state.deliver();
+        set_virtual_time_ms(Some(state.now_ms));
+    }
+
+    /// Shuffle a list using the network's RNG (e.g. to pick a peer stepping order)
+    pub fn shuffle<T>(&self, items: &mut [T]) -> () {
+        let mut state = self.lock();
+        items.shuffle(&mut state.rng);
+    }
+
+    /// Number of connections that are still open on at least one end
+    pub fn num_connections(&self) -> usize {
+        self.lock().connections.len()
+    }
+
+    /// Make a new RNG seeded from the network's RNG (e.g. for one simulated peer)
+    pub fn make_rng(&self) -> StdRng {
+        let mut state = self.lock();
+        let seed = state.rng.gen::<u64>();
+        StdRng::seed_from_u64(seed)
+    }
+}
+
+/// Run a closure with the given RNG installed as this thread's source of randomness (see
+/// util::with_rng()), and put it back afterwards.
+pub fn with_seeded_rng<F, R>(rng: &mut Option<StdRng>, f: F) -> R
+where
+    F: FnOnce() -> R
+{
+    let prev_rng = set_seeded_rng(rng.take());
+    let res = f();
+    *rng = set_seeded_rng(prev_rng);
+    res
+}
+
+impl Drop for SimNetwork {
+    fn drop(&mut self) {
+        set_virtual_time_ms(None);
+    }
+}
+
+/// One end of a simulated connection
+pub struct SimSocket {
+    state: Arc<Mutex<SimNetworkState>>,
+    conn_id: usize,
+    side: usize,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr
+}
+
+impl SimSocket {
+    fn lock(&self) -> io::Result<MutexGuard<SimNetworkState>> {
+        self.state.lock().map_err(|_e| io::Error::from(ErrorKind::Other))
+    }
+}
+
+impl fmt::Debug for SimSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SimSocket({} -> {}, conn {})", &self.local_addr, &self.remote_addr, self.conn_id)
+    }
+}
+
+impl Read for SimSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read(self.conn_id, self.side, buf)
+    }
+}
+
+impl Write for SimSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(self.conn_id, self.side, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportSocket for SimSocket {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.lock()?.peer_addr(self.conn_id, self.side)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        // closing the socket shuts it down, just like a TCP socket
+        if let Ok(mut state) = self.state.lock() {
+            state.shutdown(self.conn_id, self.side);
+        }
+    }
+}
+
+/// A host's view of the simulated network
+pub struct SimTransport {
+    host: usize,
+    state: Arc<Mutex<SimNetworkState>>,
+    event_capacity: usize,
+    count: usize,
+    servers: Vec<(SocketAddr, usize)>,      // bound address and its server event ID
+    event_map: HashMap<usize, usize>        // socket event ID to server event ID (servers map to 0)
+}
+
+impl SimTransport {
+    pub fn host_id(&self) -> usize {
+        self.host
+    }
+
+    fn lock(&self) -> MutexGuard<SimNetworkState> {
+        self.state.lock().expect("FATAL: simulated network lock poisoned")
+    }
+
+    fn make_next_event_id(&self, cur_count: usize, in_use: &HashSet<usize>) -> Result<usize, net_error> {
+        let mut ret = cur_count;
+        for _ in 0..self.event_capacity {
+            if self.event_map.contains_key(&ret) || in_use.contains(&ret) {
+                ret = (ret + 1) % self.event_capacity;
+            }
+            else {
+                return Ok(ret);
+            }
+        }
+        Err(net_error::TooManyPeers)
+    }
+
+    fn as_sim_socket(sock: &NetworkSocket) -> Result<&SimSocket, net_error> {
+        sock.as_any().downcast_ref::<SimSocket>()
+            .ok_or_else(|| {
+                error!("Not a simulated socket: {:?}", sock);
+                net_error::RegisterError
+            })
+    }
+
+    /// Address outbound connections come from
+    fn local_ip(&self) -> IpAddr {
+        match self.servers.first() {
+            Some((addr, _)) => addr.ip(),
+            None => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+        }
+    }
+}
+
+impl NetworkTransport for SimTransport {
+    fn bind(&mut self, addr: &SocketAddr) -> Result<usize, net_error> {
+        if self.lock().listeners.contains_key(addr) {
+            error!("Sim: address {:?} is already bound", addr);
+            return Err(net_error::BindError);
+        }
+
+        let server_event_id = self.next_event_id()?;
+        {
+            let mut state = self.lock();
+            state.listeners.insert(addr.clone(), SimListener {
+                host: self.host,
+                server_event_id: server_event_id,
+                accepted: VecDeque::new()
+            });
+        }
+
+        self.servers.push((addr.clone(), server_event_id));
+        self.event_map.insert(server_event_id, 0);
+        Ok(server_event_id)
+    }
+
+    fn connect(&mut self, addr: &SocketAddr) -> Result<NetworkSocket, net_error> {
+        let local_ip = self.local_ip();
+        let (conn_id, local_addr) = {
+            let mut state = self.lock();
+            let local_addr = SocketAddr::new(local_ip, state.next_ephemeral_port());
+            let conn_id = state.open_connection(self.host, local_addr.clone(), addr);
+            (conn_id, local_addr)
+        };
+
+        test_debug!("Sim: host {} connecting {:?} -> {:?} (conn {})", self.host, &local_addr, addr, conn_id);
+        Ok(Box::new(SimSocket {
+            state: self.state.clone(),
+            conn_id: conn_id,
+            side: 0,
+            local_addr: local_addr,
+            remote_addr: addr.clone()
+        }))
+    }
+
+    fn register(&mut self, server_event_id: usize, hint_event_id: usize, sock: &NetworkSocket) -> Result<usize, net_error> {
+        match self.event_map.get(&server_event_id) {
+            Some(0) => {},
+            _ => {
+                error!("Sim: event {} is not a server event", server_event_id);
+                return Err(net_error::RegisterError);
+            }
+        }
+
+        let sim_sock = SimTransport::as_sim_socket(sock)?;
+
+        let hint_event_id = hint_event_id % self.event_capacity;
+        let event_id =
+            if self.event_map.contains_key(&hint_event_id) {
+                self.next_event_id()?
+            }
+            else {
+                hint_event_id
+            };
+
+        {
+            let mut state = self.lock();
+            match state.connections.get_mut(&sim_sock.conn_id) {
+                Some(conn) => {
+                    conn.ends[sim_sock.side].event_id = Some(event_id);
+                },
+                None => {
+                    return Err(net_error::RegisterError);
+                }
+            }
+        }
+
+        self.event_map.insert(event_id, server_event_id);
+        test_debug!("Sim: host {} registered {:?} as event {} on server {}", self.host, sim_sock, event_id, server_event_id);
+        Ok(event_id)
+    }
+
+    fn deregister(&mut self, event_id: usize, sock: &NetworkSocket) -> Result<(), net_error> {
+        let sim_sock = SimTransport::as_sim_socket(sock)?;
+        self.event_map.remove(&event_id);
+        self.lock().shutdown(sim_sock.conn_id, sim_sock.side);
+
+        test_debug!("Sim: host {} deregistered event {}: {:?}", self.host, event_id, sim_sock);
+        Ok(())
+    }
+
+    fn next_event_id(&mut self) -> Result<usize, net_error> {
+        let ret = self.make_next_event_id(self.count, &HashSet::new())?;
+        self.count = (ret + 1) % self.event_capacity;
+        Ok(ret)
+    }
+
+    /// Never blocks -- the timeout is ignored, since time only passes in SimNetwork::advance()
+    fn poll(&mut self, _timeout: u64) -> Result<HashMap<usize, NetworkPollState>, net_error> {
+        let mut poll_states = HashMap::new();
+        for (_, server_event_id) in self.servers.iter() {
+            poll_states.insert(*server_event_id, NetworkPollState::new());
+        }
+
+        let mut accepted = vec![];
+        {
+            let mut state = self.lock();
+            state.deliver();
+
+            // new inbound connections
+            for (addr, listener) in state.listeners.iter_mut() {
+                if listener.host != self.host {
+                    continue;
+                }
+                for conn_id in listener.accepted.drain(..) {
+                    accepted.push((listener.server_event_id, conn_id, addr.clone()));
+                }
+            }
+
+            // readiness
+            for (_, conn) in state.connections.iter_mut() {
+                for end in conn.ends.iter_mut() {
+                    if end.host != self.host {
+                        continue;
+                    }
+                    if let Some(event_id) = end.event_id {
+                        if end.ready || end.recv_buf.len() > 0 {
+                            end.ready = false;
+                            match self.event_map.get(&event_id) {
+                                Some(server_event_id) => {
+                                    if let Some(poll_state) = poll_states.get_mut(server_event_id) {
+                                        poll_state.ready.push(event_id);
+                                    }
+                                },
+                                None => {
+                                    warn!("Sim: surreptitious readiness event {}", event_id);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // hand out accepted sockets (outside the lock, since dropping a socket takes it)
+        let mut new_events = HashSet::new();
+        for (server_event_id, conn_id, local_addr) in accepted.into_iter() {
+            let remote_addr = {
+                let state = self.lock();
+                match state.connections.get(&conn_id) {
+                    Some(conn) => conn.ends[0].addr.clone(),
+                    None => {
+                        continue;
+                    }
+                }
+            };
+
+            let sock = SimSocket {
+                state: self.state.clone(),
+                conn_id: conn_id,
+                side: 1,
+                local_addr: local_addr,
+                remote_addr: remote_addr
+            };
+
+            let event_id = match self.make_next_event_id(self.count, &new_events) {
+                Ok(eid) => eid,
+                Err(_e) => {
+                    info!("Sim: too many peers, closing {:?}", &sock);
+                    continue;
+                }
+            };
+            self.count = (event_id + 1) % self.event_capacity;
+            new_events.insert(event_id);
+
+            test_debug!("Sim: host {} accepted {:?} (event {}) on server {}", self.host, &sock, event_id, server_event_id);
+            if let Some(poll_state) = poll_states.get_mut(&server_event_id) {
+                poll_state.new.insert(event_id, Box::new(sock));
+            }
+        }
+
+        Ok(poll_states)
+    }
+}
+
+/// Drives a set of TestPeers over one simulated network.  Each round advances the virtual clock
+/// by one tick, then steps every peer once in an order drawn from the network's seed, and feeds
+/// each peer's network result through its relayer and chainstate.
+/// Each peer draws its randomness (e.g. its nonce, or which walk neighbor to pick) from its own
+/// RNG, which is seeded from the network's seed.
+pub struct SimHarness {
+    pub sim: SimNetwork,
+    pub peers: Vec<TestPeer>,
+    pub hosts: Vec<usize>,      // host ID of each peer on the simulated network
+    pub tick_ms: u64,
+    pub round: u64,
+    rngs: Vec<Option<StdRng>>,  // each peer's RNG, installed while it runs
+    dns_clients: Vec<DNSClient>,
+    dns_resolvers: Vec<DNSResolver>,    // never run -- simulated peers' data URLs are IP addresses
+}
+
+impl SimHarness {
+    /// Make peer configs that work on a simulated network: data URLs are IP addresses (so no DNS
+    /// is needed), and since every peer shares 127.0.0.1, per-host limits admit all of them.
+    pub fn make_peer_configs(test_name: &str, port_base: u16, num_peers: usize) -> Vec<TestPeerConfig> {
+        let mut peer_configs = vec![];
+        for i in 0..num_peers {
+            let mut peer_config = TestPeerConfig::new(test_name, port_base + ((2*i) as u16), port_base + ((2*i+1) as u16));
+            SimHarness::configure_peer(&mut peer_config, num_peers);
+            peer_configs.push(peer_config);
+        }
+        peer_configs
+    }
+
+    /// Adapt an existing peer config to a simulated network of num_peers peers
+    pub fn configure_peer(peer_config: &mut TestPeerConfig, num_peers: usize) -> () {
+        peer_config.data_url = UrlString::try_from(format!("http://127.0.0.1:{}", peer_config.http_port).as_str()).unwrap();
+
+        let max_per_host = cmp::max(peer_config.connection_opts.max_clients_per_host, 2 * (num_peers as u64));
+        peer_config.connection_opts.max_clients_per_host = max_per_host;
+        peer_config.connection_opts.max_neighbors_per_host = max_per_host;
+        peer_config.connection_opts.soft_max_clients_per_host = max_per_host;
+        peer_config.connection_opts.soft_max_neighbors_per_host = max_per_host;
+        peer_config.connection_opts.soft_max_neighbors_per_org = max_per_host;
+    }
+
+    pub fn new(sim: SimNetwork, peer_configs: Vec<TestPeerConfig>) -> SimHarness {
+        let mut peers = vec![];
+        let mut hosts = vec![];
+        let mut dns_clients = vec![];
+        let mut dns_resolvers = vec![];
+        let mut rngs = vec![];
+
+        for peer_config in peer_configs.into_iter() {
+            let transport = sim.transport(peer_config.connection_opts.max_sockets);
+            hosts.push(transport.host_id());
+
+            let mut rng = Some(sim.make_rng());
+            let peer = with_seeded_rng(&mut rng, || TestPeer::new_with_transport(peer_config, Some(Box::new(transport))));
+            peers.push(peer);
+            rngs.push(rng);
+
+            let (dns_resolver, dns_client) = DNSResolver::new(100);
+            dns_resolvers.push(dns_resolver);
+            dns_clients.push(dns_client);
+        }
+
+        SimHarness {
+            sim: sim,
+            peers: peers,
+            hosts: hosts,
+            tick_ms: SIM_DEFAULT_TICK_MS,
+            round: 0,
+            rngs: rngs,
+            dns_clients: dns_clients,
+            dns_resolvers: dns_resolvers
+        }
+    }
+
+    pub fn connect_initial(&mut self) -> () {
+        for (peer, rng) in self.peers.iter_mut().zip(self.rngs.iter_mut()) {
+            with_seeded_rng(rng, || peer.connect_initial().unwrap());
+        }
+    }
+
+    /// Run one round
+    pub fn step(&mut self) -> () {
+        self.sim.advance(self.tick_ms);
+
+        let mut order : Vec<usize> = (0..self.peers.len()).collect();
+        self.sim.shuffle(&mut order);
+
+        for i in order.into_iter() {
+            let peer = &mut self.peers[i];
+            let dns_client = &mut self.dns_clients[i];
+            let round = self.round;
+
+            with_seeded_rng(&mut self.rngs[i], || {
+                let mut result = match peer.step_dns(dns_client) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        test_debug!("Sim: peer {} failed to step in round {}: {:?}", i, round, &e);
+                        return;
+                    }
+                };
+
+                let lp = peer.network.local_peer.clone();
+                peer.with_db_state(|burndb, chainstate, relayer, mempool| {
+                    relayer.process_network_result(&lp, &mut result, burndb, chainstate, mempool)
+                }).unwrap();
+
+                peer.with_db_state(|burndb, chainstate, _, _| {
+                    chainstate.process_blocks(burndb, result.blocks.len() + 1).unwrap();
+                    Ok(())
+                }).unwrap();
+            });
+        }
+
+        self.round += 1;
+    }
+
+    /// Step until done() holds, and return how many rounds that took.
+    /// Panics if it takes more than max_rounds.
+    pub fn run_until<F>(&mut self, max_rounds: u64, mut done: F) -> u64
+    where
+        F: FnMut(&mut Vec<TestPeer>) -> bool
+    {
+        let start_round = self.round;
+        loop {
+            self.step();
+            if done(&mut self.peers) {
+                break;
+            }
+            assert!(self.round - start_round < max_rounds, "Simulation did not converge after {} rounds", max_rounds);
+        }
+        self.round - start_round
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use net::*;
+    use net::db::PeerDB;
+    use net::neighbors::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    /// Poll a transport, returning (accepted sockets, ready events) for one server
+    fn poll_server(transport: &mut SimTransport, server_event_id: usize) -> (HashMap<usize, NetworkSocket>, Vec<usize>) {
+        let mut poll_states = transport.poll(0).unwrap();
+        let poll_state = poll_states.remove(&server_event_id).unwrap();
+        (poll_state.new, poll_state.ready)
+    }
+
+    /// Connect host 1 to host 2 and return the (client, server) sockets and their events
+    fn make_connection(sim: &SimNetwork, transport_1: &mut SimTransport, server_1: usize, transport_2: &mut SimTransport, server_2: usize, port: u16) -> ((usize, NetworkSocket), (usize, NetworkSocket)) {
+        let client = transport_1.connect(&addr(port)).unwrap();
+        let hint = transport_1.next_event_id().unwrap();
+        let client_event = transport_1.register(server_1, hint, &client).unwrap();
+
+        let mut accepted = HashMap::new();
+        let mut connected = false;
+        for _ in 0..1000 {
+            sim.advance(10);
+            let (mut new_socks, _) = poll_server(transport_2, server_2);
+            for (event_id, sock) in new_socks.drain() {
+                accepted.insert(event_id, sock);
+            }
+            let (_, ready) = poll_server(transport_1, server_1);
+            if ready.contains(&client_event) {
+                connected = true;
+                break;
+            }
+        }
+        assert!(connected);
+        assert_eq!(accepted.len(), 1);
+
+        let (hint, server_sock) = accepted.drain().next().unwrap();
+        let server_event = transport_2.register(server_2, hint, &server_sock).unwrap();
+        ((client_event, client), (server_event, server_sock))
+    }
+
+    /// Write data and advance the clock until it all arrives.  Returns the virtual time it took.
+    fn transfer(sim: &SimNetwork, from: &mut NetworkSocket, to: &mut NetworkSocket, data: &[u8]) -> u128 {
+        let start = sim.now_ms();
+        let mut sent = 0;
+        let mut received = vec![];
+        let mut buf = [0u8; 4096];
+        while received.len() < data.len() {
+            while sent < data.len() {
+                match from.write(&data[sent..]) {
+                    Ok(n) => sent += n,
+                    Err(e) => {
+                        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                        break;
+                    }
+                }
+            }
+            sim.advance(1);
+            loop {
+                match to.read(&mut buf) {
+                    Ok(n) => {
+                        assert!(n > 0);
+                        received.extend_from_slice(&buf[0..n]);
+                    },
+                    Err(e) => {
+                        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                        break;
+                    }
+                }
+            }
+            assert!(sim.now_ms() - start < 1_000_000, "transfer never completed");
+        }
+        assert_eq!(received, data.to_vec());
+        sim.now_ms() - start
+    }
+
+    #[test]
+    fn test_sim_connect_send_recv() {
+        let sim = SimNetwork::new(0);
+        sim.set_latency(20, 20);
+
+        let mut transport_1 = sim.transport(100);
+        let mut transport_2 = sim.transport(100);
+        let server_1 = transport_1.bind(&addr(40000)).unwrap();
+        let server_2 = transport_2.bind(&addr(40001)).unwrap();
+
+        // can't bind twice
+        assert_eq!(transport_1.bind(&addr(40001)), Err(net_error::BindError));
+
+        let ((_, mut client), (server_event, mut server)) = make_connection(&sim, &mut transport_1, server_1, &mut transport_2, server_2, 40001);
+        assert_eq!(server.peer_addr().unwrap(), addr(SIM_EPHEMERAL_PORT_START));
+        assert_eq!(client.peer_addr().unwrap(), addr(40001));
+
+        // one-way latency is 20ms
+        let elapsed = transfer(&sim, &mut client, &mut server, &[1, 2, 3, 4]);
+        assert_eq!(elapsed, 20);
+
+        // data that exceeds the socket buffer still gets through, in order
+        let data : Vec<u8> = (0..(3 * SIM_SOCKET_BUFFER_SIZE)).map(|i| (i % 251) as u8).collect();
+        transfer(&sim, &mut server, &mut client, &data);
+
+        // closing one end yields EOF on the other, and a broken pipe on write
+        transport_2.deregister(server_event, &server).unwrap();
+        drop(server);
+        sim.advance(20);
+        let mut buf = [0u8; 16];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert_eq!(client.write(&[0u8]).unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_sim_connect_refused() {
+        let sim = SimNetwork::new(0);
+        let mut transport = sim.transport(100);
+        let server = transport.bind(&addr(40010)).unwrap();
+
+        let sock = transport.connect(&addr(40011)).unwrap();
+        let hint = transport.next_event_id().unwrap();
+        let event_id = transport.register(server, hint, &sock).unwrap();
+
+        // one trip for the SYN, one for the refusal
+        sim.advance(SIM_DEFAULT_MAX_LATENCY_MS);
+        sim.advance(SIM_DEFAULT_MAX_LATENCY_MS);
+        let (_, ready) = poll_server(&mut transport, server);
+        assert_eq!(ready, vec![event_id]);
+        assert!(sock.peer_addr().is_err());
+    }
+
+    #[test]
+    fn test_sim_partition() {
+        let sim = SimNetwork::new(0);
+        sim.set_latency(10, 10);
+
+        let mut transport_1 = sim.transport(100);
+        let mut transport_2 = sim.transport(100);
+        let server_1 = transport_1.bind(&addr(40020)).unwrap();
+        let server_2 = transport_2.bind(&addr(40021)).unwrap();
+
+        let ((_, mut client), (server_event, mut server)) = make_connection(&sim, &mut transport_1, server_1, &mut transport_2, server_2, 40021);
+
+        sim.partition(&[vec![transport_1.host_id()], vec![transport_2.host_id()]]);
+        client.write(&[1, 2, 3]).unwrap();
+
+        // nothing gets through while partitioned
+        sim.advance(10_000);
+        let (_, ready) = poll_server(&mut transport_2, server_2);
+        assert!(!ready.contains(&server_event));
+        let mut buf = [0u8; 16];
+        assert_eq!(server.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // new connections can't be made either
+        let sock = transport_1.connect(&addr(40021)).unwrap();
+        sim.advance(10_000);
+        let (new_socks, _) = poll_server(&mut transport_2, server_2);
+        assert_eq!(new_socks.len(), 0);
+        assert!(sock.peer_addr().is_err());
+
+        // everything arrives once healed
+        sim.heal();
+        sim.advance(1);
+        let (new_socks, ready) = poll_server(&mut transport_2, server_2);
+        assert_eq!(new_socks.len(), 1);
+        assert!(ready.contains(&server_event));
+        assert_eq!(server.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[0..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_sim_loss() {
+        let sim = SimNetwork::new(0);
+        sim.set_latency(10, 10);
+
+        let mut transport_1 = sim.transport(100);
+        let mut transport_2 = sim.transport(100);
+        let server_1 = transport_1.bind(&addr(40030)).unwrap();
+        let server_2 = transport_2.bind(&addr(40031)).unwrap();
+
+        let ((_, mut client), (_, mut server)) = make_connection(&sim, &mut transport_1, server_1, &mut transport_2, server_2, 40031);
+
+        // lost segments are retransmitted, so data still arrives intact, just later
+        sim.set_loss_rate(0.25);
+        let data : Vec<u8> = (0..(20 * SIM_MAX_SEGMENT_SIZE)).map(|i| (i % 251) as u8).collect();
+        let elapsed = transfer(&sim, &mut client, &mut server, &data);
+        assert!(elapsed >= 10 + (SIM_RETRANSMIT_TIMEOUT_MS as u128));
+
+        // a link that loses everything resets the connection
+        sim.set_loss_rate(1.0);
+        assert_eq!(client.write(&[1]).unwrap_err().kind(), ErrorKind::ConnectionReset);
+        let mut buf = [0u8; 16];
+        assert_eq!(server.read(&mut buf).unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_sim_deterministic() {
+        // the same seed and the same operations give the same delivery schedule
+        let run = |seed: u64| -> Vec<u128> {
+            let sim = SimNetwork::new(seed);
+            sim.set_latency(5, 500);
+            sim.set_loss_rate(0.1);
+
+            let mut transport_1 = sim.transport(100);
+            let mut transport_2 = sim.transport(100);
+            let server_1 = transport_1.bind(&addr(40040)).unwrap();
+            let server_2 = transport_2.bind(&addr(40041)).unwrap();
+
+            let start = sim.now_ms();
+            let ((_, mut client), (_, mut server)) = make_connection(&sim, &mut transport_1, server_1, &mut transport_2, server_2, 40041);
+            let mut times = vec![sim.now_ms() - start];
+            for i in 0..10 {
+                times.push(transfer(&sim, &mut client, &mut server, &vec![i as u8; 100 * (i + 1)]));
+            }
+            times
+        };
+
+        assert_eq!(run(1), run(1));
+        assert_eq!(run(2), run(2));
+        assert!(run(1) != run(2));
+    }
+
+    fn make_walk_configs(test_name: &str, port_base: u16, num_peers: usize, neighbor_count: usize) -> Vec<TestPeerConfig> {
+        let mut peer_configs = SimHarness::make_peer_configs(test_name, port_base, num_peers);
+        for conf in peer_configs.iter_mut() {
+            conf.connection_opts.num_neighbors = neighbor_count as u64;
+            conf.connection_opts.soft_num_neighbors = neighbor_count as u64;
+            conf.connection_opts.walk_interval = 0;
+            conf.connection_opts.disable_inv_sync = true;
+            conf.connection_opts.disable_block_download = true;
+        }
+
+        // ring topology
+        let neighbors : Vec<Neighbor> = peer_configs.iter().map(|conf| conf.to_neighbor()).collect();
+        for i in 0..num_peers {
+            peer_configs[i].add_neighbor(&neighbors[(i + 1) % num_peers]);
+            peer_configs[(i + 1) % num_peers].add_neighbor(&neighbors[i]);
+        }
+        peer_configs
+    }
+
+    fn frontier_full(peers: &Vec<TestPeer>) -> bool {
+        for peer in peers.iter() {
+            let frontier = PeerDB::get_all_peers(peer.network.peerdb.conn()).unwrap();
+            if frontier.len() < peers.len() - 1 {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check that each peer's frontier holds exactly the other peers, and that each peer is
+    /// talking to at least one of them.
+    fn assert_walk_converged(peers: &Vec<TestPeer>) -> () {
+        let ports : Vec<u16> = peers.iter().map(|p| p.config.server_port).collect();
+        for peer in peers.iter() {
+            let mut frontier_ports : Vec<u16> = PeerDB::get_all_peers(peer.network.peerdb.conn()).unwrap()
+                .iter()
+                .map(|n| n.addr.port)
+                .collect();
+            frontier_ports.sort();
+
+            let expected_ports : Vec<u16> = ports.iter().filter(|port| **port != peer.config.server_port).map(|port| *port).collect();
+            assert_eq!(frontier_ports, expected_ports, "peer {} has frontier {:?}", peer.config.server_port, &frontier_ports);
+
+            let num_authenticated = peer.network.peers.values().filter(|convo| convo.is_authenticated()).count();
+            assert!(num_authenticated > 0, "peer {} is not talking to anyone", peer.config.server_port);
+        }
+    }
+
+    /// One line per peer: its frontier, and who it has authenticated conversations with
+    fn walk_trace(peers: &Vec<TestPeer>) -> Vec<String> {
+        let mut trace = vec![];
+        for peer in peers.iter() {
+            let mut frontier_ports : Vec<u16> = PeerDB::get_all_peers(peer.network.peerdb.conn()).unwrap()
+                .iter()
+                .map(|n| n.addr.port)
+                .collect();
+            frontier_ports.sort();
+
+            let mut convo_ports : Vec<u16> = peer.network.peers.values()
+                .filter(|convo| convo.is_authenticated())
+                .map(|convo| convo.to_neighbor_key().port)
+                .collect();
+            convo_ports.sort();
+
+            trace.push(format!("{}: frontier={:?} convos={:?}", peer.config.server_port, &frontier_ports, &convo_ports));
+        }
+        trace
+    }
+
+    #[test]
+    fn test_sim_walk_ring_5() {
+        let peer_configs = make_walk_configs("test_sim_walk_ring_5", 41200, 5, 4);
+        let mut harness = SimHarness::new(SimNetwork::new(5), peer_configs);
+        harness.connect_initial();
+
+        harness.run_until(2_000, |peers| frontier_full(peers));
+        assert_walk_converged(&harness.peers);
+    }
+
+    #[test]
+    fn test_sim_walk_same_seed_same_trace() {
+        // with a fixed latency and no loss, the seed alone determines the run
+        let run = |seed: u64| -> Vec<Vec<String>> {
+            let peer_configs = make_walk_configs("test_sim_walk_same_seed_same_trace", 41300, 5, 4);
+            let mut harness = SimHarness::new(SimNetwork::new(seed), peer_configs);
+            harness.sim.set_latency(20, 20);
+            harness.connect_initial();
+
+            let mut traces = vec![];
+            for _ in 0..300 {
+                harness.step();
+                traces.push(walk_trace(&harness.peers));
+            }
+            traces
+        };
+
+        let traces_1 = run(3);
+        let traces_2 = run(3);
+
+        // the peers actually did something
+        assert!(traces_1.first() != traces_1.last());
+
+        assert_eq!(traces_1.len(), traces_2.len());
+        for (round, (trace_1, trace_2)) in traces_1.iter().zip(traces_2.iter()).enumerate() {
+            assert_eq!(trace_1, trace_2, "traces diverge in round {}", round);
+        }
+    }
+
+    #[test]
+    fn test_sim_walk_ring_24() {
+        let peer_configs = make_walk_configs("test_sim_walk_ring_24", 41000, 24, 4);
+        let mut harness = SimHarness::new(SimNetwork::new(24), peer_configs);
+        harness.sim.set_latency(10, 250);
+        harness.connect_initial();
+
+        let rounds = harness.run_until(10_000, |peers| frontier_full(peers));
+        test_debug!("Converged after {} rounds", rounds);
+
+        assert_walk_converged(&harness.peers);
+    }
+
+    #[test]
+    fn test_sim_walk_partition_heal() {
+        let num_peers = 12;
+        let peer_configs = make_walk_configs("test_sim_walk_partition_heal", 41100, num_peers, 4);
+        let mut harness = SimHarness::new(SimNetwork::new(12), peer_configs);
+        harness.connect_initial();
+
+        let left : Vec<usize> = harness.hosts[0..num_peers/2].to_vec();
+        let right : Vec<usize> = harness.hosts[num_peers/2..].to_vec();
+        let right_neighbors : HashSet<NeighborKey> = harness.peers[num_peers/2..].iter().map(|p| p.config.to_neighbor().addr).collect();
+
+        harness.sim.partition(&[left, right]);
+
+        // nothing new is learned across the split
+        for _ in 0..500 {
+            harness.step();
+            for peer in harness.peers[0..num_peers/2].iter() {
+                for nk in peer.network.events.keys() {
+                    if right_neighbors.contains(nk) {
+                        // only the initial ring links may cross, and they can't have handshaken
+                        if let Some(convo) = peer.network.get_convo(nk) {
+                            assert_eq!(convo.stats.last_handshake_time, 0);
+                        }
+                    }
+                }
+            }
+        }
+
+        harness.sim.heal();
+        harness.run_until(10_000, |peers| frontier_full(peers));
+        assert_walk_converged(&harness.peers);
+    }
+}
@@ -0,0 +1,1044 @@
+/*
+ copyright: (c) 2013-2020 by Blockstack PBC, a public benefit corporation.
+
+ This file is part of Blockstack.
+
+ Blockstack is free software. You may redistribute or modify
+ it under the terms of the GNU General Public License as published by
+ the Free Software Foundation, either version 3 of the License or
+ (at your option) any later version.
+
+ Blockstack is distributed in the hope that it will be useful,
+ but WITHOUT ANY WARRANTY, including without the implied warranty of
+ MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ GNU General Public License for more details.
+
+ You should have received a copy of the GNU General Public License
+ along with Blockstack. If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use std::io;
+use std::io::{Read, Write};
+use std::collections::HashSet;
+
+use net::Error as net_error;
+use net::StacksMessageCodec;
+
+use burnchains::Txid;
+
+use chainstate::burn::BlockHeaderHash;
+use chainstate::stacks::StacksTransaction;
+use chainstate::stacks::StacksMicroblock;
+use chainstate::stacks::db::StacksHeaderInfo;
+use chainstate::stacks::events::{
+    StacksTransactionReceipt,
+    StacksTransactionEvent
+};
+
+use vm::types::{
+    Value,
+    QualifiedContractIdentifier
+};
+use vm::database::ClaritySerializable;
+
+use util::hash::to_hex;
+
+use sha1::{Sha1, Digest};
+use base64;
+
+use serde_json;
+
+/// GUID appended to the client's Sec-WebSocket-Key when computing Sec-WebSocket-Accept (RFC 6455)
+pub const WEBSOCKET_GUID : &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message a subscriber may send us.  Clients only send subscription commands.
+pub const WEBSOCKET_MAX_MESSAGE_LEN : u64 = 65536;
+
+/// Maximum number of subscriptions a single websocket session may hold
+pub const WEBSOCKET_MAX_SUBSCRIPTIONS : usize = 1024;
+
+/// Maximum number of un-sent bytes we'll buffer for a subscriber before dropping it
+pub const WEBSOCKET_MAX_PENDING_BYTES : usize = 16 * 1024 * 1024;
+
+/// Close status codes we use (RFC 6455, section 7.4.1)
+pub const WEBSOCKET_CLOSE_NORMAL : u16 = 1000;
+pub const WEBSOCKET_CLOSE_PROTOCOL_ERROR : u16 = 1002;
+pub const WEBSOCKET_CLOSE_TOO_BIG : u16 = 1009;
+
+/// Compute the Sec-WebSocket-Accept header value for a client's Sec-WebSocket-Key
+pub fn websocket_accept_key(client_key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input(client_key.trim().as_bytes());
+    sha1.input(WEBSOCKET_GUID.as_bytes());
+    base64::encode(sha1.result().as_slice())
+}
+
+/// Is this a well-formed Sec-WebSocket-Key?  It must be the base64 encoding of 16 bytes.
+pub fn is_valid_websocket_key(client_key: &str) -> bool {
+    match base64::decode(client_key.trim()) {
+        Ok(bytes) => bytes.len() == 16,
+        Err(_) => false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebSocketOpcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xa
+}
+
+impl WebSocketOpcode {
+    pub fn from_u8(b: u8) -> Option<WebSocketOpcode> {
+        match b {
+            0x0 => Some(WebSocketOpcode::Continuation),
+            0x1 => Some(WebSocketOpcode::Text),
+            0x2 => Some(WebSocketOpcode::Binary),
+            0x8 => Some(WebSocketOpcode::Close),
+            0x9 => Some(WebSocketOpcode::Ping),
+            0xa => Some(WebSocketOpcode::Pong),
+            _ => None
+        }
+    }
+
+    pub fn is_control(&self) -> bool {
+        (*self as u8) & 0x8 != 0
+    }
+}
+
+/// A single RFC 6455 frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketFrame {
+    pub fin: bool,
+    pub opcode: WebSocketOpcode,
+    pub payload: Vec<u8>
+}
+
+impl WebSocketFrame {
+    pub fn new(opcode: WebSocketOpcode, payload: Vec<u8>) -> WebSocketFrame {
+        WebSocketFrame {
+            fin: true,
+            opcode: opcode,
+            payload: payload
+        }
+    }
+
+    pub fn text(text: &str) -> WebSocketFrame {
+        WebSocketFrame::new(WebSocketOpcode::Text, text.as_bytes().to_vec())
+    }
+
+    pub fn close(status: u16, reason: &str) -> WebSocketFrame {
+        let mut payload = vec![(status >> 8) as u8, (status & 0xff) as u8];
+        payload.extend_from_slice(reason.as_bytes());
+        WebSocketFrame::new(WebSocketOpcode::Close, payload)
+    }
+
+    /// Encode this frame.  Servers send unmasked frames; clients must give a mask.
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(self.payload.len() + 14);
+        let b0 = (if self.fin { 0x80 } else { 0x00 }) | (self.opcode as u8);
+        ret.push(b0);
+
+        let mask_bit = if mask.is_some() { 0x80 } else { 0x00 };
+        let len = self.payload.len() as u64;
+        if len < 126 {
+            ret.push(mask_bit | (len as u8));
+        }
+        else if len <= 0xffff {
+            ret.push(mask_bit | 126);
+            ret.push((len >> 8) as u8);
+            ret.push((len & 0xff) as u8);
+        }
+        else {
+            ret.push(mask_bit | 127);
+            for i in 0..8 {
+                ret.push(((len >> (8 * (7 - i))) & 0xff) as u8);
+            }
+        }
+
+        match mask {
+            Some(mask_key) => {
+                ret.extend_from_slice(&mask_key);
+                for (i, b) in self.payload.iter().enumerate() {
+                    ret.push(*b ^ mask_key[i % 4]);
+                }
+            },
+            None => {
+                ret.extend_from_slice(&self.payload);
+            }
+        }
+        ret
+    }
+
+    /// Try to decode a frame from the start of `buf`.
+    /// Returns Ok(None) if `buf` does not yet hold a complete frame, or Ok(Some((frame, len)))
+    /// where len is the number of bytes the frame occupied.
+    /// If `require_mask` is true, then unmasked frames are rejected (servers must do this).
+    pub fn decode(buf: &[u8], require_mask: bool) -> Result<Option<(WebSocketFrame, usize)>, net_error> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+        if buf[0] & 0x70 != 0 {
+            return Err(net_error::DeserializeError("Invalid websocket frame: reserved bits set".to_string()));
+        }
+        let opcode = WebSocketOpcode::from_u8(buf[0] & 0x0f)
+            .ok_or_else(|| net_error::DeserializeError(format!("Invalid websocket frame: unknown opcode {}", buf[0] & 0x0f)))?;
+
+        let masked = buf[1] & 0x80 != 0;
+        if require_mask && !masked {
+            return Err(net_error::DeserializeError("Invalid websocket frame: client frames must be masked".to_string()));
+        }
+
+        let mut ptr = 2;
+        let len = match buf[1] & 0x7f {
+            126 => {
+                if buf.len() < ptr + 2 {
+                    return Ok(None);
+                }
+                let len = ((buf[ptr] as u64) << 8) | (buf[ptr + 1] as u64);
+                ptr += 2;
+                len
+            },
+            127 => {
+                if buf.len() < ptr + 8 {
+                    return Ok(None);
+                }
+                let mut len = 0u64;
+                for i in 0..8 {
+                    len = (len << 8) | (buf[ptr + i] as u64);
+                }
+                ptr += 8;
+                len
+            },
+            l => l as u64
+        };
+
+        if opcode.is_control() && (!fin || len > 125) {
+            return Err(net_error::DeserializeError("Invalid websocket frame: bad control frame".to_string()));
+        }
+        if len > WEBSOCKET_MAX_MESSAGE_LEN {
+            return Err(net_error::OverflowError(format!("Websocket frame is too big ({} bytes)", len)));
+        }
+
+        let mask_key = if masked {
+            if buf.len() < ptr + 4 {
+                return Ok(None);
+            }
+            let mut mask_key = [0u8; 4];
+            mask_key.copy_from_slice(&buf[ptr..(ptr + 4)]);
+            ptr += 4;
+            Some(mask_key)
+        }
+        else {
+            None
+        };
+
+        let len = len as usize;
+        if buf.len() < ptr + len {
+            return Ok(None);
+        }
+
+        let mut payload = buf[ptr..(ptr + len)].to_vec();
+        if let Some(mask_key) = mask_key {
+            for i in 0..payload.len() {
+                payload[i] ^= mask_key[i % 4];
+            }
+        }
+
+        let frame = WebSocketFrame {
+            fin: fin,
+            opcode: opcode,
+            payload: payload
+        };
+        Ok(Some((frame, ptr + len)))
+    }
+}
+
+/// Things a websocket client can subscribe to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RPCSubscription {
+    Blocks,
+    Microblocks,
+    Mempool,
+    TransactionStatus(Txid),
+    PrintEvents(QualifiedContractIdentifier)
+}
+
+/// A subscribe/unsubscribe command sent by a websocket client, e.g.
+/// {"action": "subscribe", "topic": "tx_status", "txid": "..."}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RPCSubscriptionRequest {
+    pub action: String,
+    pub topic: String,
+    pub txid: Option<String>,
+    pub contract: Option<String>
+}
+
+impl RPCSubscription {
+    pub fn from_request(request: &RPCSubscriptionRequest) -> Result<RPCSubscription, String> {
+        match request.topic.as_str() {
+            "blocks" => Ok(RPCSubscription::Blocks),
+            "microblocks" => Ok(RPCSubscription::Microblocks),
+            "mempool" => Ok(RPCSubscription::Mempool),
+            "tx_status" => {
+                let txid_str = request.txid.as_ref()
+                    .ok_or_else(|| "Missing 'txid' for tx_status subscription".to_string())?;
+                let txid_hex = if txid_str.starts_with("0x") { &txid_str[2..] } else { &txid_str[..] };
+                let txid = Txid::from_hex(txid_hex)
+                    .map_err(|_e| "Failed to parse txid".to_string())?;
+                Ok(RPCSubscription::TransactionStatus(txid))
+            },
+            "print" => {
+                let contract_str = request.contract.as_ref()
+                    .ok_or_else(|| "Missing 'contract' for print subscription".to_string())?;
+                let contract_id = QualifiedContractIdentifier::parse(contract_str)
+                    .map_err(|_e| "Failed to parse contract identifier".to_string())?;
+                Ok(RPCSubscription::PrintEvents(contract_id))
+            },
+            _ => Err(format!("Unknown topic '{}'", &request.topic))
+        }
+    }
+
+    pub fn json_serialize(&self) -> serde_json::Value {
+        match self {
+            RPCSubscription::Blocks => json!({ "topic": "blocks" }),
+            RPCSubscription::Microblocks => json!({ "topic": "microblocks" }),
+            RPCSubscription::Mempool => json!({ "topic": "mempool" }),
+            RPCSubscription::TransactionStatus(txid) => json!({
+                "topic": "tx_status",
+                "txid": format!("0x{}", txid.to_hex())
+            }),
+            RPCSubscription::PrintEvents(contract_id) => json!({
+                "topic": "print",
+                "contract": format!("{}", contract_id)
+            })
+        }
+    }
+}
+
+/// An event that websocket subscribers can be told about.
+/// Each event carries its JSON payload, so it is only serialized once no matter how many
+/// subscribers receive it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RPCEvent {
+    NewBlock(serde_json::Value),
+    NewMicroblocks(serde_json::Value),
+    MempoolTransaction(serde_json::Value),
+    TransactionStatus(Txid, serde_json::Value),
+    PrintEvent(QualifiedContractIdentifier, serde_json::Value)
+}
+
+impl RPCEvent {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            RPCEvent::NewBlock(_) => "blocks",
+            RPCEvent::NewMicroblocks(_) => "microblocks",
+            RPCEvent::MempoolTransaction(_) => "mempool",
+            RPCEvent::TransactionStatus(..) => "tx_status",
+            RPCEvent::PrintEvent(..) => "print"
+        }
+    }
+
+    pub fn payload(&self) -> &serde_json::Value {
+        match self {
+            RPCEvent::NewBlock(ref payload) => payload,
+            RPCEvent::NewMicroblocks(ref payload) => payload,
+            RPCEvent::MempoolTransaction(ref payload) => payload,
+            RPCEvent::TransactionStatus(_, ref payload) => payload,
+            RPCEvent::PrintEvent(_, ref payload) => payload
+        }
+    }
+
+    /// Does this subscription want this event?
+    pub fn matches(&self, subscription: &RPCSubscription) -> bool {
+        match (self, subscription) {
+            (RPCEvent::NewBlock(_), RPCSubscription::Blocks) => true,
+            (RPCEvent::NewMicroblocks(_), RPCSubscription::Microblocks) => true,
+            (RPCEvent::MempoolTransaction(_), RPCSubscription::Mempool) => true,
+            (RPCEvent::TransactionStatus(ref txid, _), RPCSubscription::TransactionStatus(ref sub_txid)) => txid == sub_txid,
+            (RPCEvent::PrintEvent(ref contract_id, _), RPCSubscription::PrintEvents(ref sub_contract_id)) => contract_id == sub_contract_id,
+            (_, _) => false
+        }
+    }
+
+    pub fn json_serialize(&self) -> serde_json::Value {
+        json!({
+            "topic": self.topic(),
+            "payload": self.payload()
+        })
+    }
+
+    /// Make the events for a newly-processed anchored block: the block itself, the status of
+    /// each transaction it confirmed, and any `print` events its transactions emitted.
+    pub fn from_block_receipts(header_info: &StacksHeaderInfo, receipts: &Vec<StacksTransactionReceipt>) -> Vec<RPCEvent> {
+        let block_hash = format!("0x{}", header_info.anchored_header.block_hash().to_hex());
+        let index_block_hash = format!("0x{}", header_info.index_block_hash().to_hex());
+
+        let mut ret = vec![];
+        let mut txs_json = vec![];
+        let mut events_json = vec![];
+        let mut tx_events = vec![];
+
+        for (tx_index, receipt) in receipts.iter().enumerate() {
+            let txid = receipt.transaction.txid();
+            let (success, result) = match receipt.result {
+                Value::Response(ref response_data) => (response_data.committed, response_data.data.as_ref()),
+                ref other => (false, other)
+            };
+            let status = if success { "success" } else { "abort_by_response" };
+
+            txs_json.push(json!({
+                "txid": format!("0x{}", txid.to_hex()),
+                "tx_index": tx_index,
+                "success": success,
+                "raw_result": format!("0x{}", result.serialize())
+            }));
+
+            tx_events.push(RPCEvent::TransactionStatus(txid.clone(), json!({
+                "txid": format!("0x{}", txid.to_hex()),
+                "status": status,
+                "raw_result": format!("0x{}", result.serialize()),
+                "block_hash": &block_hash,
+                "index_block_hash": &index_block_hash,
+                "block_height": header_info.block_height
+            })));
+
+            for event in receipt.events.iter() {
+                let event_json = event.json_serialize(&txid);
+                if let StacksTransactionEvent::SmartContractEvent(ref event_data) = event {
+                    let (ref contract_id, ref event_name) = event_data.key;
+                    if event_name == "print" {
+                        tx_events.push(RPCEvent::PrintEvent(contract_id.clone(), event_json.clone()));
+                    }
+                }
+                events_json.push(event_json);
+            }
+        }
+
+        ret.push(RPCEvent::NewBlock(json!({
+            "block_hash": &block_hash,
+            "block_height": header_info.block_height,
+            "burn_block_hash": format!("0x{}", header_info.burn_header_hash.to_hex()),
+            "burn_block_time": header_info.burn_header_timestamp,
+            "index_block_hash": &index_block_hash,
+            "parent_block_hash": format!("0x{}", header_info.anchored_header.parent_block.to_hex()),
+            "transactions": txs_json,
+            "events": events_json
+        })));
+        ret.append(&mut tx_events);
+        ret
+    }
+
+    /// Make the event for a stream of new unconfirmed microblocks
+    pub fn from_microblocks(index_anchor_block: &BlockHeaderHash, microblocks: &Vec<StacksMicroblock>) -> RPCEvent {
+        let microblocks_json : Vec<serde_json::Value> = microblocks.iter().map(|mblock| {
+            let txids : Vec<String> = mblock.txs.iter().map(|tx| format!("0x{}", tx.txid().to_hex())).collect();
+            json!({
+                "block_hash": format!("0x{}", mblock.block_hash().to_hex()),
+                "sequence": mblock.header.sequence,
+                "txids": txids
+            })
+        }).collect();
+
+        RPCEvent::NewMicroblocks(json!({
+            "index_anchor_block": format!("0x{}", index_anchor_block.to_hex()),
+            "microblocks": microblocks_json
+        }))
+    }
+
+    /// Make the events for a transaction that was just admitted to the mempool
+    pub fn from_mempool_tx(tx: &StacksTransaction) -> Vec<RPCEvent> {
+        let txid = tx.txid();
+        let mut tx_bytes = vec![];
+        tx.consensus_serialize(&mut tx_bytes).expect("BUG: failed to serialize transaction");
+
+        vec![
+            RPCEvent::MempoolTransaction(json!({
+                "txid": format!("0x{}", txid.to_hex()),
+                "raw_tx": format!("0x{}", to_hex(&tx_bytes))
+            })),
+            RPCEvent::TransactionStatus(txid.clone(), json!({
+                "txid": format!("0x{}", txid.to_hex()),
+                "status": "pending"
+            }))
+        ]
+    }
+}
+
+/// State of a HTTP connection that has been upgraded to a websocket.
+/// Holds the bytes received but not yet decoded, the bytes queued but not yet sent, and what
+/// the client has subscribed to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketSession {
+    inbuf: Vec<u8>,
+    outbuf: Vec<u8>,
+    outptr: usize,
+    subscriptions: HashSet<RPCSubscription>,
+    fragments: Option<(WebSocketOpcode, Vec<u8>)>,
+    closing: bool,
+    broken: bool,
+    ping_sent_time: u64,        // when we pinged a quiet client (0 if we're not waiting on it)
+}
+
+impl WebSocketSession {
+    pub fn new() -> WebSocketSession {
+        WebSocketSession {
+            inbuf: vec![],
+            outbuf: vec![],
+            outptr: 0,
+            subscriptions: HashSet::new(),
+            fragments: None,
+            closing: false,
+            broken: false,
+            ping_sent_time: 0,
+        }
+    }
+
+    pub fn num_subscriptions(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    pub fn is_subscribed(&self, subscription: &RPCSubscription) -> bool {
+        self.subscriptions.contains(subscription)
+    }
+
+    /// Do we have bytes left to send?
+    pub fn has_pending_data(&self) -> bool {
+        self.outptr < self.outbuf.len()
+    }
+
+    /// Should this session be torn down?  True once we've sent a close frame, or if the
+    /// subscriber fell too far behind.
+    pub fn is_closed(&self) -> bool {
+        self.broken || (self.closing && !self.has_pending_data())
+    }
+
+    fn queue_frame(&mut self, frame: &WebSocketFrame) -> () {
+        if self.closing {
+            return;
+        }
+        if self.outptr > 0 && self.outptr == self.outbuf.len() {
+            self.outbuf.clear();
+            self.outptr = 0;
+        }
+        let bytes = frame.encode(None);
+        self.outbuf.extend_from_slice(&bytes);
+        if frame.opcode == WebSocketOpcode::Close {
+            self.closing = true;
+        }
+    }
+
+    fn queue_json(&mut self, value: &serde_json::Value) -> () {
+        let text = serde_json::to_string(value).expect("BUG: failed to serialize JSON");
+        self.queue_frame(&WebSocketFrame::text(&text));
+    }
+
+    fn queue_error(&mut self, message: &str) -> () {
+        self.queue_json(&json!({ "error": message }));
+    }
+
+    /// Queue an event for this subscriber if it asked for it.
+    /// Returns true if queued.
+    pub fn publish(&mut self, event: &RPCEvent) -> bool {
+        if self.closing || self.broken {
+            return false;
+        }
+        if !self.subscriptions.iter().any(|sub| event.matches(sub)) {
+            return false;
+        }
+        if self.outbuf.len() - self.outptr > WEBSOCKET_MAX_PENDING_BYTES {
+            warn!("Websocket subscriber has {} unsent bytes; dropping it", self.outbuf.len() - self.outptr);
+            self.broken = true;
+            return false;
+        }
+        self.queue_json(&event.json_serialize());
+        true
+    }
+
+    /// Handle a complete text message from the client
+    fn handle_command(&mut self, text: &str) -> () {
+        let request : RPCSubscriptionRequest = match serde_json::from_str(text) {
+            Ok(req) => req,
+            Err(_e) => {
+                self.queue_error("Failed to parse subscription request");
+                return;
+            }
+        };
+
+        let subscription = match RPCSubscription::from_request(&request) {
+            Ok(sub) => sub,
+            Err(msg) => {
+                self.queue_error(&msg);
+                return;
+            }
+        };
+
+        match request.action.as_str() {
+            "subscribe" => {
+                if !self.subscriptions.contains(&subscription) && self.subscriptions.len() >= WEBSOCKET_MAX_SUBSCRIPTIONS {
+                    self.queue_error("Too many subscriptions");
+                    return;
+                }
+                self.subscriptions.insert(subscription.clone());
+                self.queue_json(&json!({ "result": "subscribed", "subscription": subscription.json_serialize() }));
+            },
+            "unsubscribe" => {
+                self.subscriptions.remove(&subscription);
+                self.queue_json(&json!({ "result": "unsubscribed", "subscription": subscription.json_serialize() }));
+            },
+            _ => {
+                self.queue_error(&format!("Unknown action '{}'", &request.action));
+            }
+        }
+    }
+
+    /// Handle a complete (possibly reassembled) message from the client
+    fn handle_message(&mut self, opcode: WebSocketOpcode, payload: Vec<u8>) -> () {
+        match opcode {
+            WebSocketOpcode::Text => match String::from_utf8(payload) {
+                Ok(text) => self.handle_command(&text),
+                Err(_e) => self.queue_frame(&WebSocketFrame::close(WEBSOCKET_CLOSE_PROTOCOL_ERROR, "Invalid UTF-8"))
+            },
+            _ => {
+                self.queue_error("Binary messages are not supported");
+            }
+        }
+    }
+
+    /// Handle a single decoded frame
+    fn handle_frame(&mut self, frame: WebSocketFrame) -> () {
+        match frame.opcode {
+            WebSocketOpcode::Ping => {
+                self.queue_frame(&WebSocketFrame::new(WebSocketOpcode::Pong, frame.payload));
+            },
+            WebSocketOpcode::Pong => {},
+            WebSocketOpcode::Close => {
+                // echo the status code back, and hang up once it's sent
+                let status = if frame.payload.len() >= 2 {
+                    ((frame.payload[0] as u16) << 8) | (frame.payload[1] as u16)
+                }
+                else {
+                    WEBSOCKET_CLOSE_NORMAL
+                };
+                self.queue_frame(&WebSocketFrame::close(status, ""));
+            },
+            WebSocketOpcode::Continuation => {
+                let complete = match self.fragments {
+                    Some((_, ref mut buf)) => {
+                        buf.extend_from_slice(&frame.payload);
+                        if buf.len() as u64 > WEBSOCKET_MAX_MESSAGE_LEN {
+                            None
+                        }
+                        else {
+                            Some(frame.fin)
+                        }
+                    },
+                    None => {
+                        self.queue_frame(&WebSocketFrame::close(WEBSOCKET_CLOSE_PROTOCOL_ERROR, "Unexpected continuation frame"));
+                        return;
+                    }
+                };
+                match complete {
+                    None => {
+                        self.fragments = None;
+                        self.queue_frame(&WebSocketFrame::close(WEBSOCKET_CLOSE_TOO_BIG, "Message too big"));
+                    },
+                    Some(true) => {
+                        let (opcode, payload) = self.fragments.take().expect("BUG: no fragments");
+                        self.handle_message(opcode, payload);
+                    },
+                    Some(false) => {}
+                }
+            },
+            WebSocketOpcode::Text | WebSocketOpcode::Binary => {
+                if self.fragments.is_some() {
+                    self.queue_frame(&WebSocketFrame::close(WEBSOCKET_CLOSE_PROTOCOL_ERROR, "Expected continuation frame"));
+                    return;
+                }
+                if frame.fin {
+                    self.handle_message(frame.opcode, frame.payload);
+                }
+                else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+            }
+        }
+    }
+
+    /// Check on a client that may have gone away.  Once we haven't heard from it in
+    /// `idle_timeout` seconds, ping it; if it then stays quiet for another `ping_timeout`
+    /// seconds, it's gone.  `last_heard` is the last time we received any bytes from the client.
+    /// Returns false if the client should be disconnected.
+    pub fn check_liveness(&mut self, last_heard: u64, now: u64, idle_timeout: u64, ping_timeout: u64) -> bool {
+        if self.ping_sent_time > 0 {
+            if last_heard >= self.ping_sent_time {
+                // client is still there
+                self.ping_sent_time = 0;
+            }
+            else {
+                return self.ping_sent_time + ping_timeout >= now;
+            }
+        }
+
+        if last_heard + idle_timeout < now {
+            self.queue_frame(&WebSocketFrame::new(WebSocketOpcode::Ping, vec![]));
+            self.ping_sent_time = now;
+        }
+        true
+    }
+
+    /// Buffer bytes the client sent before this session started (i.e. right after its upgrade
+    /// request).  They get decoded along with everything else in process_frames().
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> () {
+        if !self.closing {
+            self.inbuf.extend_from_slice(bytes);
+        }
+    }
+
+    /// Decode and handle all complete frames we've received.
+    /// Malformed data gets the client a close frame.
+    pub fn process_frames(&mut self) -> () {
+        let mut consumed = 0;
+        while !self.closing && consumed < self.inbuf.len() {
+            match WebSocketFrame::decode(&self.inbuf[consumed..], true) {
+                Ok(Some((frame, len))) => {
+                    consumed += len;
+                    self.handle_frame(frame);
+                },
+                Ok(None) => {
+                    break;
+                },
+                Err(net_error::OverflowError(_)) => {
+                    self.queue_frame(&WebSocketFrame::close(WEBSOCKET_CLOSE_TOO_BIG, "Message too big"));
+                },
+                Err(e) => {
+                    debug!("Invalid websocket data: {:?}", &e);
+                    self.queue_frame(&WebSocketFrame::close(WEBSOCKET_CLOSE_PROTOCOL_ERROR, "Invalid frame"));
+                }
+            }
+        }
+
+        if self.closing {
+            self.inbuf.clear();
+        }
+        else {
+            self.inbuf = self.inbuf.split_off(consumed);
+        }
+    }
+
+    /// Read as many bytes as we can from the socket.
+    pub fn recv<R: Read>(&mut self, fd: &mut R) -> Result<usize, net_error> {
+        let mut total_read = 0;
+        loop {
+            let mut buf = [0u8; 4096];
+            let num_read = match fd.read(&mut buf) {
+                Ok(0) => {
+                    if total_read == 0 {
+                        return Err(net_error::PermanentlyDrained);
+                    }
+                    break;
+                },
+                Ok(count) => count,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock || (cfg!(windows) && e.kind() == io::ErrorKind::TimedOut) {
+                        break;
+                    }
+                    else if e.kind() == io::ErrorKind::BrokenPipe || e.kind() == io::ErrorKind::ConnectionReset {
+                        return Err(net_error::PermanentlyDrained);
+                    }
+                    else {
+                        return Err(net_error::RecvError(format!("Failed to read: {:?}", &e)));
+                    }
+                }
+            };
+
+            if !self.closing {
+                self.inbuf.extend_from_slice(&buf[0..num_read]);
+                if self.inbuf.len() as u64 > 2 * WEBSOCKET_MAX_MESSAGE_LEN {
+                    return Err(net_error::InboxOverflow);
+                }
+            }
+            total_read += num_read;
+        }
+        Ok(total_read)
+    }
+
+    /// Write as many queued bytes as we can to the socket.
+    pub fn send<W: Write>(&mut self, fd: &mut W) -> Result<usize, net_error> {
+        let mut total_sent = 0;
+        while self.has_pending_data() {
+            let num_written = match fd.write(&self.outbuf[self.outptr..]) {
+                Ok(0) => {
+                    return Err(net_error::PeerNotConnected);
+                },
+                Ok(count) => count,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock || (cfg!(windows) && e.kind() == io::ErrorKind::TimedOut) {
+                        break;
+                    }
+                    return Err(net_error::SendError(format!("Failed to send {} bytes", self.outbuf.len() - self.outptr)));
+                }
+            };
+            self.outptr += num_written;
+            total_sent += num_written;
+        }
+
+        if !self.has_pending_data() {
+            self.outbuf.clear();
+            self.outptr = 0;
+        }
+        Ok(total_sent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    use burnchains::Txid;
+    use vm::types::QualifiedContractIdentifier;
+
+    fn client_frame(text: &str) -> Vec<u8> {
+        WebSocketFrame::text(text).encode(Some([0x12, 0x34, 0x56, 0x78]))
+    }
+
+    fn server_frames(bytes: &[u8]) -> Vec<WebSocketFrame> {
+        let mut ret = vec![];
+        let mut ptr = 0;
+        while ptr < bytes.len() {
+            let (frame, len) = WebSocketFrame::decode(&bytes[ptr..], false).unwrap().unwrap();
+            ret.push(frame);
+            ptr += len;
+        }
+        ret
+    }
+
+    fn server_json(bytes: &[u8]) -> Vec<serde_json::Value> {
+        server_frames(bytes).into_iter()
+            .map(|frame| {
+                assert_eq!(frame.opcode, WebSocketOpcode::Text);
+                serde_json::from_slice(&frame.payload).unwrap()
+            })
+            .collect()
+    }
+
+    fn drain(session: &mut WebSocketSession) -> Vec<u8> {
+        let mut out = vec![];
+        session.send(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_websocket_accept_key() {
+        // example from RFC 6455, section 1.3
+        assert_eq!(websocket_accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert!(is_valid_websocket_key("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!is_valid_websocket_key("dGhlIHNhbXBsZQ=="));
+        assert!(!is_valid_websocket_key("not base64!"));
+    }
+
+    #[test]
+    fn test_websocket_frame_codec() {
+        for len in [0usize, 1, 125, 126, 127, 65535, 65536].iter() {
+            let payload : Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            let frame = WebSocketFrame::new(WebSocketOpcode::Binary, payload);
+
+            for mask in [None, Some([0xde, 0xad, 0xbe, 0xef])].iter() {
+                let bytes = frame.encode(*mask);
+                let (decoded, used) = WebSocketFrame::decode(&bytes, false).unwrap().unwrap();
+                assert_eq!(decoded, frame);
+                assert_eq!(used, bytes.len());
+
+                // every strict prefix is incomplete
+                for i in [0, 1, bytes.len() / 2, bytes.len() - 1].iter() {
+                    if *i < bytes.len() {
+                        assert!(WebSocketFrame::decode(&bytes[0..*i], false).unwrap().is_none());
+                    }
+                }
+
+                // servers reject unmasked client frames
+                assert_eq!(WebSocketFrame::decode(&bytes, true).is_ok(), mask.is_some());
+            }
+        }
+
+        // RFC 6455, section 5.7: a single-frame masked text message containing "Hello"
+        let hello = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (frame, used) = WebSocketFrame::decode(&hello, true).unwrap().unwrap();
+        assert_eq!(frame, WebSocketFrame::text("Hello"));
+        assert_eq!(used, hello.len());
+
+        // reserved bits, unknown opcodes, and oversized or fragmented control frames are rejected
+        assert!(WebSocketFrame::decode(&[0xc1, 0x00], false).is_err());
+        assert!(WebSocketFrame::decode(&[0x83, 0x00], false).is_err());
+        assert!(WebSocketFrame::decode(&[0x89, 0x7e, 0x00, 0x7e], false).is_err());
+        assert!(WebSocketFrame::decode(&[0x09, 0x00], false).is_err());
+
+        // too-big frames are rejected as soon as the length is known
+        let too_big = WebSocketFrame::new(WebSocketOpcode::Text, vec![0u8; (WEBSOCKET_MAX_MESSAGE_LEN + 1) as usize]).encode(None);
+        match WebSocketFrame::decode(&too_big[0..10], false) {
+            Err(net_error::OverflowError(_)) => {},
+            x => panic!("Expected overflow, got {:?}", &x)
+        }
+    }
+
+    #[test]
+    fn test_websocket_session_subscriptions() {
+        let txid = Txid([0x11; 32]);
+        let other_txid = Txid([0x22; 32]);
+        let contract_id = QualifiedContractIdentifier::parse("ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV.hello-world").unwrap();
+
+        let mut session = WebSocketSession::new();
+
+        let mut input = vec![];
+        input.append(&mut client_frame(r#"{"action":"subscribe","topic":"blocks"}"#));
+        input.append(&mut client_frame(&format!(r#"{{"action":"subscribe","topic":"tx_status","txid":"0x{}"}}"#, txid.to_hex())));
+        input.append(&mut client_frame(r#"{"action":"subscribe","topic":"print","contract":"ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV.hello-world"}"#));
+        input.append(&mut client_frame(r#"{"action":"subscribe","topic":"nope"}"#));
+        input.append(&mut client_frame(r#"{"action":"subscribe","topic":"tx_status"}"#));
+        input.append(&mut client_frame("not json"));
+
+        // feed it in two pieces, so a frame straddles the boundary
+        let split = input.len() / 2 + 3;
+        session.recv(&mut Cursor::new(input[0..split].to_vec())).unwrap();
+        session.process_frames();
+        session.recv(&mut Cursor::new(input[split..].to_vec())).unwrap();
+        session.process_frames();
+
+        assert_eq!(session.num_subscriptions(), 3);
+        assert!(session.is_subscribed(&RPCSubscription::Blocks));
+        assert!(session.is_subscribed(&RPCSubscription::TransactionStatus(txid.clone())));
+        assert!(session.is_subscribed(&RPCSubscription::PrintEvents(contract_id.clone())));
+
+        let replies = server_json(&drain(&mut session));
+        assert_eq!(replies.len(), 6);
+        assert_eq!(replies[0]["result"], "subscribed");
+        assert_eq!(replies[0]["subscription"]["topic"], "blocks");
+        assert_eq!(replies[1]["subscription"]["txid"], format!("0x{}", txid.to_hex()));
+        assert_eq!(replies[2]["subscription"]["contract"], "ST1A14RBKJ289E3DP89QAZE2RRHDPWP5RHMYFRCHV.hello-world");
+        assert_eq!(replies[3]["error"], "Unknown topic 'nope'");
+        assert_eq!(replies[4]["error"], "Missing 'txid' for tx_status subscription");
+        assert_eq!(replies[5]["error"], "Failed to parse subscription request");
+
+        // only matching events are delivered
+        let events = vec![
+            RPCEvent::NewBlock(json!({"block_height": 1})),
+            RPCEvent::NewMicroblocks(json!({})),
+            RPCEvent::MempoolTransaction(json!({})),
+            RPCEvent::TransactionStatus(txid.clone(), json!({"status": "pending"})),
+            RPCEvent::TransactionStatus(other_txid.clone(), json!({"status": "pending"})),
+            RPCEvent::PrintEvent(contract_id.clone(), json!({"type": "contract_event"})),
+            RPCEvent::PrintEvent(QualifiedContractIdentifier::transient(), json!({"type": "contract_event"})),
+        ];
+        let delivered : Vec<bool> = events.iter().map(|ev| session.publish(ev)).collect();
+        assert_eq!(delivered, vec![true, false, false, true, false, true, false]);
+
+        let pushed = server_json(&drain(&mut session));
+        assert_eq!(pushed.len(), 3);
+        assert_eq!(pushed[0], json!({"topic": "blocks", "payload": {"block_height": 1}}));
+        assert_eq!(pushed[1], json!({"topic": "tx_status", "payload": {"status": "pending"}}));
+        assert_eq!(pushed[2], json!({"topic": "print", "payload": {"type": "contract_event"}}));
+
+        // unsubscribe
+        session.recv(&mut Cursor::new(client_frame(r#"{"action":"unsubscribe","topic":"blocks"}"#))).unwrap();
+        session.process_frames();
+        assert!(!session.is_subscribed(&RPCSubscription::Blocks));
+        assert!(!session.publish(&events[0]));
+        let replies = server_json(&drain(&mut session));
+        assert_eq!(replies[0]["result"], "unsubscribed");
+    }
+
+    #[test]
+    fn test_websocket_session_control_frames() {
+        let mut session = WebSocketSession::new();
+
+        // fragmented text message, with a ping in the middle
+        let mut input = vec![];
+        input.append(&mut WebSocketFrame { fin: false, opcode: WebSocketOpcode::Text, payload: r#"{"action":"subscribe","#.as_bytes().to_vec() }.encode(Some([1, 2, 3, 4])));
+        input.append(&mut WebSocketFrame::new(WebSocketOpcode::Ping, vec![9, 8, 7]).encode(Some([5, 6, 7, 8])));
+        input.append(&mut WebSocketFrame::new(WebSocketOpcode::Continuation, r#""topic":"mempool"}"#.as_bytes().to_vec()).encode(Some([1, 2, 3, 4])));
+        session.recv(&mut Cursor::new(input)).unwrap();
+        session.process_frames();
+
+        assert!(session.is_subscribed(&RPCSubscription::Mempool));
+        let frames = server_frames(&drain(&mut session));
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], WebSocketFrame::new(WebSocketOpcode::Pong, vec![9, 8, 7]));
+        assert_eq!(frames[1].opcode, WebSocketOpcode::Text);
+
+        // close is echoed, and then the session is done
+        session.recv(&mut Cursor::new(WebSocketFrame::close(WEBSOCKET_CLOSE_NORMAL, "bye").encode(Some([1, 2, 3, 4])))).unwrap();
+        session.process_frames();
+        assert!(!session.is_closed());
+        assert!(!session.publish(&RPCEvent::MempoolTransaction(json!({}))));
+
+        let frames = server_frames(&drain(&mut session));
+        assert_eq!(frames, vec![WebSocketFrame::close(WEBSOCKET_CLOSE_NORMAL, "")]);
+        assert!(session.is_closed());
+
+        // unmasked client frames get a protocol error
+        let mut session = WebSocketSession::new();
+        session.recv(&mut Cursor::new(WebSocketFrame::text("{}").encode(None))).unwrap();
+        session.process_frames();
+        let frames = server_frames(&drain(&mut session));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].opcode, WebSocketOpcode::Close);
+        assert_eq!(&frames[0].payload[0..2], &[0x03, 0xea]);
+        assert!(session.is_closed());
+
+        // EOF on an empty read means the client hung up
+        let mut session = WebSocketSession::new();
+        match session.recv(&mut Cursor::new(vec![])) {
+            Err(net_error::PermanentlyDrained) => {},
+            x => panic!("Expected PermanentlyDrained, got {:?}", &x)
+        }
+    }
+
+    #[test]
+    fn test_websocket_session_liveness() {
+        let mut session = WebSocketSession::new();
+
+        // a chatty client isn't pinged
+        assert!(session.check_liveness(100, 110, 15, 30));
+        assert!(!session.has_pending_data());
+
+        // a quiet client is pinged once
+        assert!(session.check_liveness(100, 116, 15, 30));
+        assert_eq!(server_frames(&drain(&mut session)), vec![WebSocketFrame::new(WebSocketOpcode::Ping, vec![])]);
+        assert!(session.check_liveness(100, 130, 15, 30));
+        assert!(!session.has_pending_data());
+
+        // an answer resets the clock
+        assert!(session.check_liveness(120, 130, 15, 30));
+        assert!(session.check_liveness(120, 135, 15, 30));
+        assert!(!session.has_pending_data());
+
+        // no answer means the client is gone
+        assert!(session.check_liveness(120, 136, 15, 30));
+        assert_eq!(server_frames(&drain(&mut session)), vec![WebSocketFrame::new(WebSocketOpcode::Ping, vec![])]);
+        assert!(session.check_liveness(120, 166, 15, 30));
+        assert!(!session.check_liveness(120, 167, 15, 30));
+    }
+
+    #[test]
+    fn test_websocket_session_push_bytes() {
+        let mut session = WebSocketSession::new();
+
+        // frames pipelined after the upgrade request are handled along with later ones
+        let mut pipelined = client_frame(r#"{"action":"subscribe","topic":"blocks"}"#);
+        let later = pipelined.split_off(5);
+        session.push_bytes(&pipelined);
+        session.recv(&mut Cursor::new(later)).unwrap();
+        session.process_frames();
+
+        assert!(session.is_subscribed(&RPCSubscription::Blocks));
+        let replies = server_json(&drain(&mut session));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["result"], "subscribed");
+    }
+}
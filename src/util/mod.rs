@@ -34,8 +34,76 @@ use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::fmt;
 use std::error;
+use std::cell::Cell;
+
+use rand::RngCore;
+use rand::thread_rng;
+
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use rand::rngs::StdRng;
+
+#[cfg(test)]
+thread_local! {
+    // virtual clock used by the network simulator (net::sim), in milliseconds since the epoch.
+    // When set, it replaces the system clock for this thread.
+    static VIRTUAL_TIME_MS: Cell<Option<u128>> = Cell::new(None);
+
+    // seeded RNG used by the network simulator (net::sim) so that each simulated peer draws
+    // its randomness from the simulation seed.  When set, it replaces thread_rng() for this thread.
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+}
+
+/// Set (or clear) this thread's virtual clock
+#[cfg(test)]
+pub fn set_virtual_time_ms(now_ms: Option<u128>) -> () {
+    VIRTUAL_TIME_MS.with(|t| t.set(now_ms));
+}
+
+#[cfg(test)]
+fn get_virtual_time_ms() -> Option<u128> {
+    VIRTUAL_TIME_MS.with(|t| t.get())
+}
+
+#[cfg(not(test))]
+fn get_virtual_time_ms() -> Option<u128> {
+    None
+}
+
+/// Install (or clear) this thread's seeded RNG, returning the one it replaces
+#[cfg(test)]
+pub fn set_seeded_rng(rng: Option<StdRng>) -> Option<StdRng> {
+    SEEDED_RNG.with(|r| r.replace(rng))
+}
+
+/// Run a closure with this thread's source of randomness.
+/// This is thread_rng(), unless a test has installed a seeded RNG with set_seeded_rng().
+#[cfg(test)]
+pub fn with_rng<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut dyn RngCore) -> R
+{
+    SEEDED_RNG.with(|r| {
+        match *r.borrow_mut() {
+            Some(ref mut rng) => f(rng),
+            None => f(&mut thread_rng())
+        }
+    })
+}
+
+#[cfg(not(test))]
+pub fn with_rng<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut dyn RngCore) -> R
+{
+    f(&mut thread_rng())
+}
 
 pub fn get_epoch_time_secs() -> u64 {
+    if let Some(now_ms) = get_virtual_time_ms() {
+        return (now_ms / 1000) as u64;
+    }
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
@@ -43,6 +111,9 @@ pub fn get_epoch_time_secs() -> u64 {
 }
 
 pub fn get_epoch_time_ms() -> u128 {
+    if let Some(now_ms) = get_virtual_time_ms() {
+        return now_ms;
+    }
     let start = SystemTime::now();
     let since_the_epoch = start.duration_since(UNIX_EPOCH)
         .expect("Time went backwards");
@@ -27,6 +27,7 @@ use secp256k1::RecoverableSignature as LibSecp256k1RecoverableSignature;
 use secp256k1::Signature as LibSecp256k1Signature;
 use secp256k1::RecoveryId as LibSecp256k1RecoveryID;
 use secp256k1::Error as LibSecp256k1Error;
+use secp256k1::ecdh::SharedSecret as LibSecp256k1SharedSecret;
 
 use burnchains::PublicKey;
 use burnchains::PrivateKey;
@@ -338,6 +339,17 @@ impl Secp256k1PrivateKey {
         }
         to_hex(&bytes)
     }
+
+    /// ECDH shared secret between this key and a public key.  Both parties to the exchange get
+    /// the same 32 bytes (libsecp256k1 hashes the shared point for us).
+    pub fn shared_secret(&self, public_key: &Secp256k1PublicKey) -> [u8; 32] {
+        _secp256k1.with(|ctx| {
+            let secret = LibSecp256k1SharedSecret::new(&ctx, &public_key.key, &self.key);
+            let mut ret = [0u8; 32];
+            ret.copy_from_slice(&secret[..]);
+            ret
+        })
+    }
 }
 
 impl PrivateKey for Secp256k1PrivateKey {
@@ -581,6 +593,21 @@ mod tests {
         }
     }
     
+    #[test]
+    fn test_shared_secret() {
+        let privk_1 = Secp256k1PrivateKey::new();
+        let privk_2 = Secp256k1PrivateKey::new();
+        let privk_3 = Secp256k1PrivateKey::new();
+
+        let pubk_1 = Secp256k1PublicKey::from_private(&privk_1);
+        let pubk_2 = Secp256k1PublicKey::from_private(&privk_2);
+        let pubk_3 = Secp256k1PublicKey::from_private(&privk_3);
+
+        assert_eq!(privk_1.shared_secret(&pubk_2), privk_2.shared_secret(&pubk_1));
+        assert!(privk_1.shared_secret(&pubk_2) != privk_1.shared_secret(&pubk_3));
+        assert!(privk_1.shared_secret(&pubk_2) != privk_3.shared_secret(&pubk_2));
+    }
+
     #[test]
     #[ignore]
     fn test_verify_benchmark_roundtrip() {
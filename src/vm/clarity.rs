@@ -195,6 +195,14 @@ impl ClarityInstance {
         }
     }
 
+    /// Is the state as of the given block still in the datastore?
+    pub fn has_block_state(&mut self, at_block: &BlockHeaderHash) -> Result<bool, Error> {
+        self.datastore.as_mut()
+            .expect("FAIL: use of has_block_state while a block is neither committed nor rolled back.")
+            .has_block(at_block)
+            .map_err(Error::from)
+    }
+
     pub fn eval_read_only(&mut self, at_block: &BlockHeaderHash, header_db: &dyn HeadersDB,
                           contract: &QualifiedContractIdentifier, program: &str) -> Result<Value, Error> {
         self.datastore.as_mut().unwrap()
@@ -207,6 +215,20 @@ impl ClarityInstance {
             .map_err(Error::from)
     }
 
+    pub fn drop_blocks(&mut self, bhhs: &[BlockHeaderHash]) -> Result<(), Error> {
+        self.datastore.as_mut()
+            .expect("FAIL: use of drop_blocks while a block is neither committed nor rolled back.")
+            .drop_blocks(bhhs)
+            .map_err(Error::from)
+    }
+
+    pub fn enable_map_key_index(&mut self) -> Result<(), Error> {
+        self.datastore.as_mut()
+            .expect("FAIL: use of enable_map_key_index while a block is neither committed nor rolled back.")
+            .enable_map_key_index()
+            .map_err(Error::from)
+    }
+
     pub fn destroy(mut self) -> MarfedKV {
         let datastore = self.datastore.take()
             .expect("FAIL: attempt to recover database connection from clarity instance which is still open");
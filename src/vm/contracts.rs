@@ -1,8 +1,11 @@
 use std::convert::TryInto;
+use std::collections::HashMap;
 use vm::{Value, apply, eval_all};
-use vm::representations::{SymbolicExpression};
-use vm::errors::{InterpreterResult as Result};
-use vm::callables::CallableType;
+use vm::representations::{SymbolicExpression, ClarityName};
+use vm::errors::{CheckErrors, InterpreterResult as Result};
+use vm::callables::{CallableType, DefinedFunction, DefineType};
+use vm::functions::define::DefineFunctionsParsed;
+use vm::types::parse_name_type_pairs;
 use vm::contexts::{Environment, LocalContext, ContractContext, GlobalContext};
 use vm::ast::ContractAST;
 use vm::types::QualifiedContractIdentifier;
@@ -23,4 +26,28 @@ impl Contract {
         Ok(Contract { contract_context: contract_context })
     }
 
+    /// Rebuild the functions a contract defines from its AST, without evaluating any of it.
+    /// Used to check a stored contract against its source.
+    pub fn functions_from_ast(contract: &ContractAST) -> Result<HashMap<ClarityName, DefinedFunction>> {
+        let context_name = contract.contract_identifier.to_string();
+        let mut functions = HashMap::new();
+        for exp in contract.expressions.iter() {
+            let (signature, body, define_type) = match DefineFunctionsParsed::try_parse(exp)? {
+                Some(DefineFunctionsParsed::PrivateFunction { signature, body }) => (signature, body, DefineType::Private),
+                Some(DefineFunctionsParsed::ReadOnlyFunction { signature, body }) => (signature, body, DefineType::ReadOnly),
+                Some(DefineFunctionsParsed::PublicFunction { signature, body }) => (signature, body, DefineType::Public),
+                _ => continue
+            };
+
+            let (function_symbol, arg_symbols) = signature.split_first()
+                .ok_or(CheckErrors::DefineFunctionBadSignature)?;
+            let function_name = function_symbol.match_atom()
+                .ok_or(CheckErrors::ExpectedName)?;
+            let arguments = parse_name_type_pairs(arg_symbols, &mut ())?;
+
+            functions.insert(function_name.clone(),
+                             DefinedFunction::new(arguments, body.clone(), define_type, function_name, &context_name));
+        }
+        Ok(functions)
+    }
 }
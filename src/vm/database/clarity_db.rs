@@ -1,4 +1,5 @@
 use std::collections::{VecDeque, HashMap};
+use std::cmp;
 use std::convert::TryFrom;
 use rusqlite::OptionalExtension;
 
@@ -6,7 +7,8 @@ use vm::contracts::Contract;
 use vm::errors::{Error, InterpreterError, RuntimeErrorType, CheckErrors, InterpreterResult as Result, IncomparableError};
 use vm::types::{Value, OptionalData, TypeSignature, TupleTypeSignature, PrincipalData, StandardPrincipalData, QualifiedContractIdentifier, NONE};
 
-use chainstate::stacks::index::proofs::TrieMerkleProof;
+use chainstate::stacks::index::proofs::{TrieMerkleProof, TrieMerkleBatchProof};
+use chainstate::stacks::index::Error as MarfError;
 use chainstate::stacks::db::{StacksHeaderInfo, MinerPaymentSchedule};
 use chainstate::burn::{VRFSeed, BlockHeaderHash};
 use burnchains::BurnchainHeaderHash;
@@ -188,6 +190,10 @@ impl <'a> ClarityDatabase <'a> {
         self.store.get_with_proof(key)
     }
 
+    pub fn get_many_with_proof (&mut self, keys: &[String]) -> std::result::Result<(Vec<Option<String>>, TrieMerkleBatchProof), MarfError> {
+        self.store.get_many_with_proof(keys)
+    }
+
     pub fn make_key_for_trip(contract_identifier: &QualifiedContractIdentifier, data: StoreType, var_name: &str) -> String {
         format!("vm::{}::{}::{}", contract_identifier, data as u8, var_name)
     }
@@ -366,10 +372,14 @@ impl <'a> ClarityDatabase <'a> {
         return Ok(Value::Bool(true))
     }
 
+    pub fn make_key_for_data_var(contract_identifier: &QualifiedContractIdentifier, variable_name: &str) -> String {
+        ClarityDatabase::make_key_for_trip(contract_identifier, StoreType::Variable, variable_name)
+    }
+
     pub fn lookup_variable(&mut self, contract_identifier: &QualifiedContractIdentifier, variable_name: &str) -> Result<Value>  {
         let variable_descriptor = self.load_variable(contract_identifier, variable_name)?;
 
-        let key = ClarityDatabase::make_key_for_trip(contract_identifier, StoreType::Variable, variable_name);
+        let key = ClarityDatabase::make_key_for_data_var(contract_identifier, variable_name);
 
         let result = self.get_value(&key, &variable_descriptor.value_type);
 
@@ -404,6 +414,97 @@ impl <'a> ClarityDatabase <'a> {
         ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::DataMap, map_name, key_value.serialize())
     }
 
+    /// Split a data map entry's key (see make_key_for_data_map_entry) back into the contract
+    ///   identifier, the map name, and the serialized key value.  Returns None for any other key.
+    pub fn parse_data_map_entry_key(key: &str) -> Option<(&str, &str, &str)> {
+        if !key.starts_with("vm::") {
+            return None;
+        }
+        let parts: Vec<&str> = key[4..].splitn(4, "::").collect();
+        if parts.len() != 4 || parts[1] != format!("{}", StoreType::DataMap as u8) {
+            return None;
+        }
+        Some((parts[0], parts[2], parts[3]))
+    }
+
+    pub fn has_map_key_index(&mut self) -> bool {
+        self.store.has_map_key_index()
+    }
+
+    /// List up to limit of a data map's entries as of the current block, in order by serialized
+    ///   key, starting after the given key.  Each value comes with its MARF proof if with_proof is set.
+    /// Examines at most max_examined indexed keys.  The key index holds every key ever written to the
+    ///   map on any fork, along with the blocks that wrote it.  Keys that no ancestor of the current
+    ///   block wrote are skipped without touching the MARF, and keys deleted since are skipped once
+    ///   the MARF says so, but both count as examined.  So a page can come back short (even empty)
+    ///   while more entries remain.
+    /// Returns the entries, and the serialized key to resume after, or None if the index has no
+    ///   more keys.
+    /// Only works if the backing store records data map keys (see has_map_key_index()), and only
+    ///   lists entries written since it started recording.
+    pub fn list_data_map_entries(&mut self, contract_identifier: &QualifiedContractIdentifier, map_name: &str,
+                                 after: Option<&Value>, limit: u32, max_examined: u32, with_proof: bool)
+                                 -> Result<(Vec<(Value, Value, Option<TrieMerkleProof>)>, Option<String>)> {
+        let map_descriptor = self.load_map(contract_identifier, map_name)?;
+        let contract_id = contract_identifier.to_string();
+
+        let tip_height = self.get_current_block_height();
+        // block at each height on the current fork, looked up as needed
+        let mut ancestors: HashMap<u32, Option<BlockHeaderHash>> = HashMap::new();
+
+        let mut entries = vec![];
+        let mut cursor = after.map(|key_value| key_value.serialize());
+        let mut num_examined = 0;
+        while (entries.len() as u32) < limit && num_examined < max_examined {
+            // each key yields at most one entry, so this never overshoots limit
+            let batch = cmp::min(limit - (entries.len() as u32), max_examined - num_examined);
+            let key_hexes = self.store.get_map_keys(&contract_id, map_name, cursor.as_ref().map(|x| x.as_str()), batch);
+            let exhausted = (key_hexes.len() as u32) < batch;
+
+            for (key_hex, writers) in key_hexes.into_iter() {
+                num_examined += 1;
+
+                let mut on_fork = false;
+                for (writer, height) in writers.into_iter() {
+                    if height > tip_height {
+                        continue;
+                    }
+                    let store = &mut self.store;
+                    let ancestor = ancestors.entry(height)
+                        .or_insert_with(|| store.get_block_header_hash(height));
+                    if ancestor.as_ref() == Some(&writer) {
+                        on_fork = true;
+                        break;
+                    }
+                }
+                if !on_fork {
+                    cursor = Some(key_hex);
+                    continue;
+                }
+
+                let key = ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::DataMap, map_name, key_hex.clone());
+                let value_opt = if with_proof {
+                    self.get_with_proof::<Value>(&key).map(|(value, proof)| (value, Some(proof)))
+                } else {
+                    self.get::<Value>(&key).map(|value| (value, None))
+                };
+
+                // keys deleted since they were written have no value here
+                if let Some((Value::Optional(OptionalData { data: Some(value) }), proof)) = value_opt {
+                    let key_value = Value::deserialize(&key_hex, &map_descriptor.key_type);
+                    entries.push((key_value, *value, proof));
+                }
+                cursor = Some(key_hex);
+            }
+
+            if exhausted {
+                return Ok((entries, None));
+            }
+        }
+
+        Ok((entries, cursor))
+    }
+
     pub fn fetch_entry(&mut self, contract_identifier: &QualifiedContractIdentifier, map_name: &str, key_value: &Value) -> Result<Value> {
         let map_descriptor = self.load_map(contract_identifier, map_name)?;
         if !map_descriptor.key_type.admits(key_value) {
@@ -537,10 +638,18 @@ impl <'a> ClarityDatabase <'a> {
         }
     }
 
+    pub fn make_key_for_ft_balance(contract_identifier: &QualifiedContractIdentifier, token_name: &str, principal: &PrincipalData) -> String {
+        ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::FungibleToken, token_name, principal.serialize())
+    }
+
+    pub fn make_key_for_nft_owner(contract_identifier: &QualifiedContractIdentifier, asset_name: &str, asset: &Value) -> String {
+        ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::NonFungibleToken, asset_name, asset.serialize())
+    }
+
     pub fn get_ft_balance(&mut self, contract_identifier: &QualifiedContractIdentifier, token_name: &str, principal: &PrincipalData) -> Result<u128> {
         self.load_ft(contract_identifier, token_name)?;
 
-        let key =  ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::FungibleToken, token_name, principal.serialize());
+        let key = ClarityDatabase::make_key_for_ft_balance(contract_identifier, token_name, principal);
 
         let result = self.get(&key);
         match result {
@@ -549,6 +658,19 @@ impl <'a> ClarityDatabase <'a> {
         }
     }
 
+    /// Like get_ft_balance(), but also returns the MARF proof of the balance.  There is no proof
+    ///   if the principal never held the token, since its balance was never stored.
+    pub fn get_ft_balance_with_proof(&mut self, contract_identifier: &QualifiedContractIdentifier, token_name: &str, principal: &PrincipalData) -> Result<(u128, Option<TrieMerkleProof>)> {
+        self.load_ft(contract_identifier, token_name)?;
+
+        let key = ClarityDatabase::make_key_for_ft_balance(contract_identifier, token_name, principal);
+
+        match self.get_with_proof(&key) {
+            None => Ok((0, None)),
+            Some((balance, proof)) => Ok((balance, Some(proof)))
+        }
+    }
+
     pub fn set_ft_balance(&mut self, contract_identifier: &QualifiedContractIdentifier, token_name: &str, principal: &PrincipalData, balance: u128) -> Result<()> {
         let key =  ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::FungibleToken, token_name, principal.serialize());
         self.put(&key, &balance);
@@ -562,12 +684,25 @@ impl <'a> ClarityDatabase <'a> {
             return Err(CheckErrors::TypeValueError(descriptor.key_type, (*asset).clone()).into())
         }
 
-        let key = ClarityDatabase::make_key_for_quad(contract_identifier, StoreType::NonFungibleToken, asset_name, asset.serialize());
+        let key = ClarityDatabase::make_key_for_nft_owner(contract_identifier, asset_name, asset);
 
         let result = self.get(&key);
         result.ok_or(RuntimeErrorType::NoSuchToken.into())
     }
 
+    /// Like get_nft_owner(), but also returns the MARF proof of the owner.  Returns None if the
+    ///   asset has not been minted.
+    pub fn get_nft_owner_with_proof(&mut self, contract_identifier: &QualifiedContractIdentifier, asset_name: &str, asset: &Value) -> Result<Option<(PrincipalData, TrieMerkleProof)>> {
+        let descriptor = self.load_nft(contract_identifier, asset_name)?;
+        if !descriptor.key_type.admits(asset) {
+            return Err(CheckErrors::TypeValueError(descriptor.key_type, (*asset).clone()).into())
+        }
+
+        let key = ClarityDatabase::make_key_for_nft_owner(contract_identifier, asset_name, asset);
+
+        Ok(self.get_with_proof(&key))
+    }
+
     pub fn get_nft_key_type(&mut self, contract_identifier: &QualifiedContractIdentifier, asset_name: &str) -> Result<TypeSignature> {
         let descriptor = self.load_nft(contract_identifier, asset_name)?;
         Ok(descriptor.key_type)
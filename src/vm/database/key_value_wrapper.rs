@@ -1,7 +1,8 @@
 use super::{MarfedKV, ClarityBackingStore, ClarityDeserializable};
 use vm::Value;
 use vm::errors::{ InterpreterResult as Result };
-use chainstate::{ burn::BlockHeaderHash, stacks::index::proofs::TrieMerkleProof };
+use chainstate::{ burn::BlockHeaderHash, stacks::index::proofs::{TrieMerkleProof, TrieMerkleBatchProof} };
+use chainstate::stacks::index::Error as MarfError;
 use std::collections::{HashMap};
 use util::hash::{Sha512Trunc256Sum};
 use vm::types::{QualifiedContractIdentifier, TypeSignature};
@@ -244,6 +245,12 @@ impl <'a> RollbackWrapper <'a> {
             .map(|(value, proof)| (T::deserialize(&value), proof))
     }
 
+    /// like get_with_proof(), but for several keys at once.  The single batched proof covers each
+    ///  value that was found, in order.  Values are left serialized, since the keys may hold different types.
+    pub fn get_many_with_proof(&mut self, keys: &[String]) -> std::result::Result<(Vec<Option<String>>, TrieMerkleBatchProof), MarfError> {
+        self.store.get_many_with_proof(keys)
+    }
+
     pub fn get<T>(&mut self, key: &str) -> Option<T> where T: ClarityDeserializable<T> {
         self.stack.last()
             .expect("ERROR: Clarity VM attempted GET on non-nested context.");
@@ -272,6 +279,15 @@ impl <'a> RollbackWrapper <'a> {
         self.store.get_current_block_height()
     }
 
+    pub fn has_map_key_index(&mut self) -> bool {
+        self.store.get_side_store().has_map_key_index()
+    }
+
+    /// this function will only return keys _already_ materialized in the underlying store.
+    pub fn get_map_keys(&mut self, contract_id: &str, map_name: &str, after: Option<&str>, limit: u32) -> Vec<(String, Vec<(BlockHeaderHash, u32)>)> {
+        self.store.get_side_store().get_map_keys(contract_id, map_name, after, limit)
+    }
+
     pub fn get_block_header_hash(&mut self, block_height: u32) -> Option<BlockHeaderHash> {
         self.store.get_block_at_height(block_height)
     }
@@ -7,8 +7,8 @@ use vm::database::{SqliteConnection, ClarityDatabase, HeadersDB, NULL_HEADER_DB,
 use vm::analysis::{AnalysisDatabase};
 use chainstate::stacks::index::marf::MARF;
 use chainstate::stacks::index::{MARFValue, Error as MarfError, TrieHash};
-use chainstate::stacks::index::storage::{TrieFileStorage};
-use chainstate::stacks::index::proofs::{TrieMerkleProof};
+use chainstate::stacks::index::storage::{TrieFileStorage, TrieHashCalculationMode};
+use chainstate::stacks::index::proofs::{TrieMerkleProof, TrieMerkleBatchProof};
 use chainstate::burn::{VRFSeed, BlockHeaderHash};
 use burnchains::BurnchainHeaderHash;
 use std::convert::TryInto;
@@ -42,6 +42,10 @@ pub trait ClarityBackingStore {
     /// fetch K-V out of the committed datastore
     fn get(&mut self, key: &str) -> Option<String>;
     fn get_with_proof(&mut self, key: &str) -> Option<(String, TrieMerkleProof)>;
+    /// look up several keys at once, with a single batched proof for the ones that exist (in order).
+    /// Unlike the other getters, this one is only used to serve proofs to clients, so a MARF
+    /// failure is returned to the caller instead of panicking.
+    fn get_many_with_proof(&mut self, keys: &[String]) -> std::result::Result<(Vec<Option<String>>, TrieMerkleBatchProof), MarfError>;
     fn has_entry(&mut self, key: &str) -> bool {
         self.get(key).is_some()
     }
@@ -140,9 +144,12 @@ impl MarfedKV {
             .to_string();
 
         let side_store = SqliteConnection::initialize(&data_path)?;
-        let marf = MARF::from_path(&marf_path, miner_tip)
+        let mut marf = MARF::from_path(&marf_path, miner_tip)
             .map_err(|err| InterpreterError::MarfFailure(IncomparableError{ err }))?;
 
+        // blocks write many keys, so hash each block's trie once, in parallel, when it's sealed
+        marf.set_hash_calculation_mode(TrieHashCalculationMode::Deferred);
+
         let chain_tip = match miner_tip {
             Some(ref miner_tip) => *miner_tip.clone(),
             None => TrieFileStorage::block_sentinel()
@@ -221,6 +228,7 @@ impl MarfedKV {
     pub fn commit_to(&mut self, final_bhh: &BlockHeaderHash) {
         debug!("commit_to({})", final_bhh); 
         self.side_store.commit_metadata_to(&self.chain_tip, final_bhh);
+        self.side_store.commit_map_keys_to(&self.chain_tip, final_bhh);
         self.side_store.commit(&self.chain_tip);
         self.marf.commit_to(final_bhh)
             .expect("ERROR: Failed to commit MARF block");
@@ -245,9 +253,48 @@ impl MarfedKV {
         &mut self.marf
     }
 
+    /// Is there a trie for the given block?  There isn't if it was never committed, or if it
+    ///   was pruned or quarantined.
+    pub fn has_block(&mut self, bhh: &BlockHeaderHash) -> Result<bool> {
+        let has_block = self.marf.borrow_storage_backend().has_block(bhh)
+            .map_err(|err| InterpreterError::MarfFailure(IncomparableError{ err }))?;
+        Ok(has_block)
+    }
+
+    /// Forget the given blocks' tries and contract metadata.  Used to roll back the chainstate
+    ///   past a burnchain reorg -- descendants of a dropped block must be dropped too.
+    pub fn drop_blocks(&mut self, bhhs: &[BlockHeaderHash]) -> Result<()> {
+        self.marf.drop_blocks(bhhs)
+            .map_err(|err| InterpreterError::MarfFailure(IncomparableError{ err }))?;
+        for bhh in bhhs.iter() {
+            self.side_store.drop_metadata(bhh);
+            self.side_store.drop_map_keys(bhh);
+        }
+        self.chain_tip = TrieFileStorage::block_sentinel();
+        Ok(())
+    }
+
+    /// Start recording data map keys in the side store, so map entries can be listed.
+    pub fn enable_map_key_index(&mut self) -> Result<()> {
+        self.side_store.enable_map_key_index()
+    }
+
+    /// If data map keys are being recorded and this is a data map entry's key, record it
+    ///   as written in the open block.
+    fn record_map_key(&mut self, key: &str) {
+        if !self.side_store.has_map_key_index() {
+            return;
+        }
+        if let Some((contract_id, map_name, key_hex)) = ClarityDatabase::parse_data_map_entry_key(key) {
+            let height = self.get_open_chain_tip_height();
+            self.side_store.insert_map_key(&self.chain_tip, height, contract_id, map_name, key_hex);
+        }
+    }
+
     pub fn put(&mut self, key: &str, value: &str) {
         let marf_value = MARFValue::from_value(value);
         self.side_store.put(&marf_value.to_hex(), value);
+        self.record_map_key(key);
 
         self.marf.insert(key, marf_value)
             .expect("ERROR: Unexpected MARF Failure")
@@ -318,6 +365,19 @@ impl ClarityBackingStore for MarfedKV {
             })
     }
 
+    fn get_many_with_proof(&mut self, keys: &[String]) -> std::result::Result<(Vec<Option<String>>, TrieMerkleBatchProof), MarfError> {
+        let (marf_values, proof) = self.marf.get_many_with_proof(&self.chain_tip, keys)?;
+        let values = marf_values.into_iter()
+            .map(|marf_value_opt| marf_value_opt.map(|marf_value| {
+                let side_key = marf_value.to_hex();
+                self.side_store.get(&side_key)
+                    .expect(&format!("ERROR: MARF contained value_hash not found in side storage: {}",
+                                     side_key))
+            }))
+            .collect();
+        Ok((values, proof))
+    }
+
     fn get(&mut self, key: &str) -> Option<String> {
         self.marf.get(&self.chain_tip, key)
             .or_else(|e| {
@@ -341,6 +401,7 @@ impl ClarityBackingStore for MarfedKV {
         for (key, value) in items.drain(..) {
             let marf_value = MARFValue::from_value(&value);
             self.side_store.put(&marf_value.to_hex(), &value);
+            self.record_map_key(&key);
             keys.push(key);
             values.push(marf_value);
         }
@@ -385,6 +446,13 @@ impl ClarityBackingStore for MemoryBackingStore {
             })
     }
 
+    fn get_many_with_proof(&mut self, keys: &[String]) -> std::result::Result<(Vec<Option<String>>, TrieMerkleBatchProof), MarfError> {
+        let values = keys.iter()
+            .map(|key| self.side_store.get(key))
+            .collect();
+        Ok((values, TrieMerkleBatchProof { entries: vec![], proofs: vec![] }))
+    }
+
     fn get_side_store(&mut self) -> &mut SqliteConnection {
         &mut self.side_store
     }
@@ -11,9 +11,18 @@ use vm::errors::{Error, InterpreterError, RuntimeErrorType, InterpreterResult as
 const SQL_FAIL_MESSAGE: &str = "PANIC: SQL Failure in Smart Contract VM.";
 
 pub struct SqliteConnection {
-    conn: Connection
+    conn: Connection,
+    // whether or not data map keys are being recorded in data_map_keys
+    map_key_index: bool
 }
 
+// (contract, map, serialized key) for every data map entry written, by the block (and height) that
+//   wrote it.  readers skip keys none of whose writers are ancestors of the tip being read, but a key
+//   may also have been deleted since, so readers must still check the MARF to see if the entry exists.
+const SQL_DATA_MAP_KEYS_TABLE: &str = "CREATE TABLE IF NOT EXISTS data_map_keys
+                      (blockhash TEXT NOT NULL, height INTEGER NOT NULL, contract TEXT NOT NULL, map_name TEXT NOT NULL, key TEXT NOT NULL,
+                       UNIQUE (contract, map_name, key, blockhash))";
+
 fn sqlite_put(conn: &Connection, key: &str, value: &str) {
     let params: [&dyn ToSql; 2] = [&key, &value];
     conn.execute("REPLACE INTO data_table (key, value) VALUES (?, ?)",
@@ -59,6 +68,11 @@ impl SqliteConnection {
             .expect(SQL_FAIL_MESSAGE);
     }
 
+    pub fn drop_metadata(&mut self, bhh: &BlockHeaderHash) {
+        self.conn.execute("DELETE FROM metadata_table WHERE blockhash = ?", &[bhh])
+            .expect(SQL_FAIL_MESSAGE);
+    }
+
     pub fn get_metadata(&mut self, bhh: &BlockHeaderHash, contract_hash: &str, key: &str) -> Option<String> {
         let key = format!("clr-meta::{}::{}", contract_hash, key);
         let params: [&dyn ToSql; 2] = [&bhh, &key];
@@ -75,6 +89,79 @@ impl SqliteConnection {
         sqlite_has_entry(&self.conn, key)
     }
 
+    /// Start recording the keys of every data map entry written, so a map's entries can be
+    ///   listed.  Only entries written from now on are recorded.
+    pub fn enable_map_key_index(&mut self) -> Result<()> {
+        self.conn.execute(SQL_DATA_MAP_KEYS_TABLE, NO_PARAMS)
+            .map_err(|x| InterpreterError::SqliteError(IncomparableError{ err: x }))?;
+        self.map_key_index = true;
+        Ok(())
+    }
+
+    pub fn has_map_key_index(&self) -> bool {
+        self.map_key_index
+    }
+
+    pub fn insert_map_key(&mut self, bhh: &BlockHeaderHash, height: u32, contract_id: &str, map_name: &str, key_hex: &str) {
+        let params: [&dyn ToSql; 5] = [&bhh, &height, &contract_id, &map_name, &key_hex];
+        self.conn.execute("INSERT OR IGNORE INTO data_map_keys (blockhash, height, contract, map_name, key) VALUES (?, ?, ?, ?, ?)", &params)
+            .expect(SQL_FAIL_MESSAGE);
+    }
+
+    pub fn commit_map_keys_to(&mut self, from: &BlockHeaderHash, to: &BlockHeaderHash) {
+        if !self.map_key_index {
+            return;
+        }
+        let params = [to, from];
+        self.conn.execute(
+            "UPDATE data_map_keys SET blockhash = ? WHERE blockhash = ?",
+            &params)
+            .expect(SQL_FAIL_MESSAGE);
+    }
+
+    pub fn drop_map_keys(&mut self, bhh: &BlockHeaderHash) {
+        if !self.map_key_index {
+            return;
+        }
+        self.conn.execute("DELETE FROM data_map_keys WHERE blockhash = ?", &[bhh])
+            .expect(SQL_FAIL_MESSAGE);
+    }
+
+    /// Get up to limit serialized keys ever written to the given map, on any fork, in order,
+    ///   starting after the given serialized key.  Each key comes with the (block, height) of
+    ///   every block that wrote it.
+    pub fn get_map_keys(&mut self, contract_id: &str, map_name: &str, after: Option<&str>, limit: u32) -> Vec<(String, Vec<(BlockHeaderHash, u32)>)> {
+        let after = after.unwrap_or("");
+        let limit = limit as i64;
+        let params: [&dyn ToSql; 4] = [&contract_id, &map_name, &after, &limit];
+        let mut stmt = self.conn.prepare(
+            "SELECT key, blockhash, height FROM data_map_keys WHERE contract = ?1 AND map_name = ?2 AND key IN \
+               (SELECT DISTINCT key FROM data_map_keys WHERE contract = ?1 AND map_name = ?2 AND key > ?3 ORDER BY key LIMIT ?4) \
+             ORDER BY key")
+            .expect(SQL_FAIL_MESSAGE);
+        let rows = stmt.query_map(&params, |row| {
+            let key: String = row.get(0);
+            let bhh: BlockHeaderHash = row.get(1);
+            let height: i64 = row.get(2);
+            (key, bhh, height as u32)
+        })
+            .expect(SQL_FAIL_MESSAGE);
+
+        let mut keys: Vec<(String, Vec<(BlockHeaderHash, u32)>)> = vec![];
+        for row in rows {
+            let (key, bhh, height) = row.expect(SQL_FAIL_MESSAGE);
+            match keys.last_mut() {
+                Some((ref last_key, ref mut writers)) if *last_key == key => {
+                    writers.push((bhh, height));
+                    continue;
+                },
+                _ => {}
+            }
+            keys.push((key, vec![(bhh, height)]));
+        }
+        keys
+    }
+
     /// begin, commit, rollback a save point identified by key
     ///    this is used to clean up any data from aborted blocks
     ///     (NOT aborted transactions that is handled by the clarity vm directly).
@@ -144,7 +231,14 @@ impl SqliteConnection {
         conn.busy_handler(Some(tx_busy_handler))
             .map_err(|x| InterpreterError::SqliteError(IncomparableError{ err: x }))?;
 
-        Ok(SqliteConnection { conn })
+        let map_key_index = {
+            let num_tables: i64 = conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'data_map_keys'",
+                                                 NO_PARAMS, |row| row.get(0))
+                .map_err(|x| InterpreterError::SqliteError(IncomparableError{ err: x }))?;
+            num_tables > 0
+        };
+
+        Ok(SqliteConnection { conn, map_key_index })
     }
 
     #[cfg(test)]
@@ -4,7 +4,7 @@ use vm::types::{Value};
 use vm::contexts::{OwnedEnvironment};
 use vm::representations::SymbolicExpression;
 use vm::database::{MarfedKV, ClarityDatabase, NULL_HEADER_DB};
-use vm::types::{QualifiedContractIdentifier, PrincipalData};
+use vm::types::{QualifiedContractIdentifier, PrincipalData, TupleData};
 
 use vm::tests::{symbols_from_values, execute, is_err_code, is_committed};
 
@@ -125,6 +125,109 @@ fn test_at_block_missing_defines() {
 
 }
 
+#[test]
+fn test_list_data_map_entries_across_forks() {
+    let p1 = execute(p1_str);
+    let contract_identifier = QualifiedContractIdentifier::local("kv").unwrap();
+    let contract =
+        "(define-map kv ((k int)) ((v int)))
+         (define-public (put (k int) (v int))
+           (begin (map-set kv (tuple (k k)) (tuple (v v))) (ok true)))
+         (define-public (del (k int))
+           (begin (map-delete kv (tuple (k k))) (ok true)))
+         (map-set kv (tuple (k 1)) (tuple (v 10)))
+         (map-set kv (tuple (k 2)) (tuple (v 20)))
+         (map-set kv (tuple (k 3)) (tuple (v 30)))";
+
+    let block = |x: u8| BlockHeaderHash::from_bytes(&[x; 32]).unwrap();
+    let key = |k: i128| Value::from(TupleData::from_data(vec![("k".into(), Value::Int(k))]).unwrap());
+
+    let mut marf_kv = MarfedKV::temporary();
+    marf_kv.enable_map_key_index().unwrap();
+
+    marf_kv.begin(&TrieFileStorage::block_sentinel(), &block(0));
+    marf_kv.as_clarity_db(&NULL_HEADER_DB).initialize();
+    marf_kv.test_commit();
+
+    marf_kv.begin(&block(0), &block(1));
+    {
+        let mut owned_env = OwnedEnvironment::new(marf_kv.as_clarity_db(&NULL_HEADER_DB));
+        owned_env.initialize_contract(contract_identifier.clone(), contract).unwrap();
+    }
+    marf_kv.test_commit();
+
+    // fork a deletes 2 and adds 4; fork b adds 5
+    marf_kv.begin(&block(1), &block(2));
+    {
+        let mut owned_env = OwnedEnvironment::new(marf_kv.as_clarity_db(&NULL_HEADER_DB));
+        owned_env.execute_transaction(p1.clone(), contract_identifier.clone(), "del", &symbols_from_values(vec![Value::Int(2)])).unwrap();
+        owned_env.execute_transaction(p1.clone(), contract_identifier.clone(), "put", &symbols_from_values(vec![Value::Int(4), Value::Int(40)])).unwrap();
+    }
+    marf_kv.test_commit();
+
+    marf_kv.begin(&block(1), &block(3));
+    {
+        let mut owned_env = OwnedEnvironment::new(marf_kv.as_clarity_db(&NULL_HEADER_DB));
+        owned_env.execute_transaction(p1.clone(), contract_identifier.clone(), "put", &symbols_from_values(vec![Value::Int(5), Value::Int(50)])).unwrap();
+    }
+    marf_kv.test_commit();
+
+    let mut list_entries = |parent: u8, after: Option<i128>, limit: u32, max_examined: u32, with_proof: bool| -> (Vec<(i128, i128)>, Option<String>) {
+        marf_kv.begin(&block(parent), &block(parent + 0x10));
+        let (entries, cursor) = {
+            let mut db = marf_kv.as_clarity_db(&NULL_HEADER_DB);
+            db.begin();
+            assert!(db.has_map_key_index());
+            let after = after.map(|k| key(k));
+            let result = db.list_data_map_entries(&contract_identifier, "kv", after.as_ref(), limit, max_examined, with_proof).unwrap();
+            db.roll_back();
+            result
+        };
+        marf_kv.rollback();
+
+        let entries = entries.into_iter()
+            .map(|(k, v, proof)| {
+                assert_eq!(proof.is_some(), with_proof);
+                let k = match k { Value::Tuple(data) => data.get("k").unwrap().clone(), _ => panic!("Not a tuple key") };
+                let v = match v { Value::Tuple(data) => data.get("v").unwrap().clone(), _ => panic!("Not a tuple value") };
+                match (k, v) {
+                    (Value::Int(k), Value::Int(v)) => (k, v),
+                    _ => panic!("Not an int entry")
+                }
+            })
+            .collect();
+        (entries, cursor)
+    };
+
+    let mut entries = |parent: u8, after: Option<i128>, limit: u32| -> Vec<(i128, i128)> {
+        list_entries(parent, after, limit, 100, true).0
+    };
+    let cursor = |k: i128| Some(key(k).serialize());
+
+    assert_eq!(entries(1, None, 10), vec![(1, 10), (2, 20), (3, 30)]);
+    assert_eq!(entries(2, None, 10), vec![(1, 10), (3, 30), (4, 40)]);
+    assert_eq!(entries(3, None, 10), vec![(1, 10), (2, 20), (3, 30), (5, 50)]);
+
+    // paging skips entries deleted or written on other forks
+    assert_eq!(entries(2, None, 2), vec![(1, 10), (3, 30)]);
+    assert_eq!(entries(2, Some(3), 2), vec![(4, 40)]);
+    assert_eq!(entries(3, Some(2), 1), vec![(3, 30)]);
+    assert_eq!(entries(3, Some(5), 10), Vec::<(i128, i128)>::new());
+
+    // proofs are only built when asked for
+    assert_eq!(list_entries(2, None, 10, 100, false), (vec![(1, 10), (3, 30), (4, 40)], None));
+
+    // the cursor resumes after the last key examined, and is None once the index runs out
+    assert_eq!(list_entries(2, None, 2, 100, true).1, cursor(3));
+    assert_eq!(list_entries(3, Some(3), 10, 100, true), (vec![(5, 50)], None));
+
+    // skipped keys count against the examined-key budget: on fork a, keys 2 and 5 have no value
+    assert_eq!(list_entries(2, Some(1), 10, 1, true), (vec![], cursor(2)));
+    assert_eq!(list_entries(2, Some(1), 10, 2, true), (vec![(3, 30)], cursor(3)));
+    assert_eq!(list_entries(2, Some(4), 10, 1, true), (vec![], cursor(5)));
+    assert_eq!(list_entries(2, Some(5), 10, 1, true), (vec![], None));
+}
+
 // execute:
 // f -> a -> z
 //    \--> b
@@ -20,6 +20,7 @@ use stacks::burnchains::bitcoin::indexer::{BitcoinIndexer, BitcoinIndexerRuntime
 use stacks::burnchains::bitcoin::spv::SpvClient; 
 use stacks::burnchains::PublicKey;
 use stacks::chainstate::burn::db::burndb::BurnDB;
+use stacks::chainstate::burn::BlockSnapshot;
 use stacks::chainstate::burn::operations::{
     LeaderBlockCommitOp,
     LeaderKeyRegisterOp,
@@ -42,6 +43,7 @@ pub struct BitcoinRegtestController {
     indexer_config: BitcoinIndexerConfig,
     db: Option<BurnDB>,
     chain_tip: Option<BurnchainTip>,
+    rewound_snapshots: Vec<BlockSnapshot>,
 }
 
 const DUST_UTXO_LIMIT: u64 = 5500;
@@ -84,6 +86,7 @@ impl BitcoinRegtestController {
             indexer_config,
             db: None,
             chain_tip: None,
+            rewound_snapshots: vec![],
         }
     }
 
@@ -111,6 +114,7 @@ impl BitcoinRegtestController {
             indexer_config,
             db: None,
             chain_tip: None,
+            rewound_snapshots: vec![],
         }        
     }
 
@@ -137,7 +141,7 @@ impl BitcoinRegtestController {
         let (mut burnchain, mut burnchain_indexer) = self.setup_indexer_runtime();
 
         let (block_snapshot, state_transition) = loop {
-            match burnchain.sync_with_indexer(&mut burnchain_indexer) {
+            match burnchain.sync_with_indexer_and_rewind(&mut burnchain_indexer, &mut self.rewound_snapshots) {
                 Ok(x) => {
                     break x;
                 }
@@ -564,6 +568,10 @@ impl BurnchainController for BitcoinRegtestController {
         self.receive_blocks()
     }
 
+    fn take_rewound_snapshots(&mut self) -> Vec<BlockSnapshot> {
+        std::mem::replace(&mut self.rewound_snapshots, vec![])
+    }
+
     fn sync(&mut self) -> BurnchainTip {        
         if self.config.burnchain.mode == "helium" {
             // Helium: this node is responsible for mining new burnchain blocks
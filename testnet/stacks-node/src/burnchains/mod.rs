@@ -1,8 +1,10 @@
 pub mod mocknet_controller;
 pub mod bitcoin_regtest_controller;
+pub mod spv_follower_controller;
 
 pub use self::mocknet_controller::{MocknetController};
 pub use self::bitcoin_regtest_controller::{BitcoinRegtestController};
+pub use self::spv_follower_controller::{SpvFollowerController};
 
 use super::operations::BurnchainOpSigner;
 
@@ -21,6 +23,12 @@ pub trait BurnchainController {
     fn burndb_mut(&mut self) -> &mut BurnDB;
     fn get_chain_tip(&mut self) -> BurnchainTip;
 
+    /// Get (and forget) the snapshots that were removed from the burn DB because of burnchain
+    /// reorgs since the last call.  The Stacks blocks selected in them must be rolled back.
+    fn take_rewound_snapshots(&mut self) -> Vec<BlockSnapshot> {
+        vec![]
+    }
+
     #[cfg(test)]
     fn bootstrap_chain(&mut self, blocks_count: u64);
 }
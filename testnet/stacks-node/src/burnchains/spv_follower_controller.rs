@@ -0,0 +1,178 @@
+use std::time::Instant;
+
+use super::{BurnchainController, BurnchainTip};
+use super::super::operations::BurnchainOpSigner;
+use super::super::Config;
+
+use stacks::burnchains::Burnchain;
+use stacks::burnchains::BurnchainStateTransition;
+use stacks::burnchains::Error as burnchain_error;
+use stacks::burnchains::bitcoin::BitcoinNetworkType;
+use stacks::burnchains::bitcoin::indexer::{BitcoinIndexer, BitcoinIndexerRuntime, BitcoinIndexerConfig};
+use stacks::burnchains::bitcoin::spv::SpvClient;
+use stacks::chainstate::burn::db::burndb::BurnDB;
+use stacks::chainstate::burn::BlockSnapshot;
+use stacks::chainstate::burn::operations::BlockstackOperationType;
+use stacks::util::sleep_ms;
+
+/// SpvFollowerController tracks the burnchain using only the Bitcoin peer-to-peer protocol:
+/// headers are fetched and validated by the SPV client, and blocks are downloaded from the
+/// configured peer.  It never talks to bitcoind's JSON-RPC interface, so it needs neither RPC
+/// credentials nor a wallet -- and it cannot submit burnchain operations.
+pub struct SpvFollowerController {
+    config: Config,
+    indexer_config: BitcoinIndexerConfig,
+    db: Option<BurnDB>,
+    chain_tip: Option<BurnchainTip>,
+    rewound_snapshots: Vec<BlockSnapshot>,
+}
+
+impl SpvFollowerController {
+
+    pub fn generic(config: Config) -> Box<dyn BurnchainController> {
+        Box::new(Self::new(config))
+    }
+
+    pub fn new(config: Config) -> Self {
+        std::fs::create_dir_all(&config.node.get_burnchain_path())
+            .expect("Unable to create workdir");
+
+        if let Err(err) = SpvClient::new(&config.burnchain.spv_headers_path, 0, None, BitcoinNetworkType::Regtest, true, false) {
+            error!("Unable to init block headers: {}", err);
+            panic!()
+        }
+
+        let indexer_config = {
+            let burnchain_config = config.burnchain.clone();
+            BitcoinIndexerConfig {
+                peer_host: burnchain_config.peer_host,
+                peer_port: burnchain_config.peer_port,
+                rpc_port: burnchain_config.rpc_port,
+                rpc_ssl: burnchain_config.rpc_ssl,
+                // never used -- this controller does not speak JSON-RPC
+                username: None,
+                password: None,
+                timeout: burnchain_config.timeout,
+                spv_headers_path: burnchain_config.spv_headers_path,
+                first_block: burnchain_config.first_block,
+                magic_bytes: burnchain_config.magic_bytes
+            }
+        };
+
+        Self {
+            config,
+            indexer_config,
+            db: None,
+            chain_tip: None,
+            rewound_snapshots: vec![],
+        }
+    }
+
+    fn make_burnchain(&self) -> Burnchain {
+        let network = "regtest".to_string();
+        let working_dir = self.config.get_burn_db_path();
+        match Burnchain::new(&working_dir, &self.config.burnchain.chain, &network) {
+            Ok(burnchain) => burnchain,
+            Err(e) => {
+                error!("Failed to instantiate burnchain: {}", e);
+                panic!()
+            }
+        }
+    }
+
+    fn make_indexer(&self) -> BitcoinIndexer {
+        BitcoinIndexer {
+            config: self.indexer_config.clone(),
+            runtime: BitcoinIndexerRuntime::new(BitcoinNetworkType::Regtest)
+        }
+    }
+
+    fn receive_blocks(&mut self) -> BurnchainTip {
+        let mut burnchain = self.make_burnchain();
+        let mut indexer = self.make_indexer();
+
+        let (block_snapshot, state_transition) = loop {
+            match burnchain.sync_with_indexer_and_rewind(&mut indexer, &mut self.rewound_snapshots) {
+                Ok(x) => {
+                    break x;
+                }
+                Err(burnchain_error::TrySyncAgain) => {
+                    // try again immediately
+                    continue;
+                }
+                Err(e) => {
+                    // the peer may be unreachable or may have fed us a broken fork --
+                    // delay and try again
+                    error!("Unable to sync with burnchain peer: {}", e);
+                    sleep_ms(5000);
+                    continue;
+                }
+            }
+        };
+
+        // re-open the burn DB so we see the snapshots (and any rewinds) we just wrote
+        let db = burnchain.open_db(true)
+            .expect("FATAL: failed to open the burn DB after sync");
+        self.db = Some(db);
+
+        let burnchain_tip = match (state_transition, &self.chain_tip) {
+            (None, Some(chain_tip)) if chain_tip.block_snapshot == block_snapshot => chain_tip.clone(),
+            (state_transition_opt, _) => BurnchainTip {
+                block_snapshot,
+                state_transition: state_transition_opt.unwrap_or_else(BurnchainStateTransition::noop),
+                received_at: Instant::now()
+            }
+        };
+
+        self.chain_tip = Some(burnchain_tip.clone());
+        burnchain_tip
+    }
+}
+
+impl BurnchainController for SpvFollowerController {
+
+    fn burndb_ref(&self) -> &BurnDB {
+        self.db.as_ref().expect("BUG: did not instantiate the burn DB")
+    }
+
+    fn burndb_mut(&mut self) -> &mut BurnDB {
+        self.db.as_mut().expect("BUG: did not instantiate the burn DB")
+    }
+
+    fn get_chain_tip(&mut self) -> BurnchainTip {
+        match &self.chain_tip {
+            Some(chain_tip) => chain_tip.clone(),
+            None => {
+                unreachable!();
+            }
+        }
+    }
+
+    fn start(&mut self) -> BurnchainTip {
+        self.receive_blocks()
+    }
+
+    fn sync(&mut self) -> BurnchainTip {
+        // wait for the peer to announce a new block (or for a reorg)
+        let current_tip = self.get_chain_tip().block_snapshot;
+        loop {
+            let burnchain_tip = self.receive_blocks();
+            if burnchain_tip.block_snapshot.block_height > current_tip.block_height || self.rewound_snapshots.len() > 0 {
+                break burnchain_tip;
+            }
+            sleep_ms(5000);
+        }
+    }
+
+    fn take_rewound_snapshots(&mut self) -> Vec<BlockSnapshot> {
+        std::mem::replace(&mut self.rewound_snapshots, vec![])
+    }
+
+    fn submit_operation(&mut self, _operation: BlockstackOperationType, _op_signer: &mut BurnchainOpSigner) -> bool {
+        warn!("Follower node cannot submit burnchain operations");
+        false
+    }
+
+    #[cfg(test)]
+    fn bootstrap_chain(&mut self, _num_blocks: u64) {}
+}
@@ -9,11 +9,17 @@ use stacks::burnchains::{
     MagicBytes, BLOCKSTACK_MAGIC_MAINNET};
 use stacks::burnchains::bitcoin::indexer::FIRST_BLOCK_MAINNET;
 use stacks::net::connection::ConnectionOptions;
+use stacks::net::ratelimit::RPCRateLimits;
 use stacks::net::{Neighbor, NeighborKey, PeerAddress};
 use stacks::util::secp256k1::Secp256k1PublicKey;
 use stacks::util::hash::{to_hex, hex_bytes};
 use stacks::vm::types::{PrincipalData, QualifiedContractIdentifier, AssetIdentifier} ;
 use stacks::vm::costs::ExecutionCost;
+use stacks::chainstate::burn::BlockHeaderHash;
+use stacks::chainstate::stacks::index::cache::MARF_NODE_CACHE_DEFAULT_CAPACITY;
+use stacks::chainstate::stacks::miner::{
+    BlockBuilderSettings, TransactionSelector, FeeRateSelector, FifoSelector, AllowDenySelector,
+    MAX_MEMPOOL_CANDIDATES};
 
 use super::node::TESTNET_CHAIN_ID;
 use super::neon_node::TESTNET_PEER_VERSION;
@@ -28,6 +34,7 @@ pub struct ConfigFile {
     pub events_observer: Option<Vec<EventObserverConfigFile>>,
     pub connection_options: Option<ConnectionOptionsFile>,
     pub block_limit: Option<BlockLimitFile>,
+    pub miner: Option<MinerConfigFile>,
 }
 
 impl ConfigFile {
@@ -148,6 +155,7 @@ pub struct Config {
     pub events_observers: Vec<EventObserverConfig>,
     pub connection_options: ConnectionOptions,
     pub block_limit: ExecutionCost,
+    pub miner: MinerConfig,
 }
 
 lazy_static! {
@@ -218,7 +226,16 @@ impl Config {
                         None => default_node_config.local_peer_seed
                     },
                     miner: node.miner.unwrap_or(default_node_config.miner),
+                    prometheus_bind: node.prometheus_bind,
+                    index_map_keys: node.index_map_keys.unwrap_or(default_node_config.index_map_keys),
+                    marf_node_cache_capacity: node.marf_node_cache_capacity.unwrap_or(default_node_config.marf_node_cache_capacity),
+                    snapshot_path: node.snapshot_path,
+                    snapshot_tip: node.snapshot_tip.map(|tip| BlockHeaderHash::from_hex(&tip)
+                                                        .expect("snapshot_tip should be a hex encoded index block hash")),
                 };
+                if node_config.snapshot_path.is_some() != node_config.snapshot_tip.is_some() {
+                    panic!("snapshot_path and snapshot_tip must be set together");
+                }
                 node_config.set_bootstrap_node(node.bootstrap_node);
                 node_config
             },
@@ -317,8 +334,20 @@ impl Config {
                 opts.read_only_call_limit_read_length.map(|x| { read_only_call_limit.read_length = x; });
                 opts.read_only_call_limit_read_count.map(|x| { read_only_call_limit.read_count = x; });
                 opts.read_only_call_limit_runtime.map(|x| { read_only_call_limit.runtime = x; });
+                let rpc_ip_rate_limits = RPCRateLimits {
+                    read: opts.rpc_ip_rate_limit_read.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_ip_rate_limits.read.clone()),
+                    expensive: opts.rpc_ip_rate_limit_expensive.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_ip_rate_limits.expensive.clone()),
+                    submit: opts.rpc_ip_rate_limit_submit.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_ip_rate_limits.submit.clone()),
+                };
+                let rpc_api_key_rate_limits = RPCRateLimits {
+                    read: opts.rpc_api_key_rate_limit_read.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_api_key_rate_limits.read.clone()),
+                    expensive: opts.rpc_api_key_rate_limit_expensive.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_api_key_rate_limits.expensive.clone()),
+                    submit: opts.rpc_api_key_rate_limit_submit.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_api_key_rate_limits.submit.clone()),
+                };
                 ConnectionOptions {
                     read_only_call_limit,
+                    rpc_ip_rate_limits,
+                    rpc_api_key_rate_limits,
                     inbox_maxlen: opts.inbox_maxlen.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.inbox_maxlen.clone()),
                     outbox_maxlen: opts.outbox_maxlen.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.outbox_maxlen.clone()),
                     timeout: opts.timeout.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.timeout.clone()),
@@ -338,6 +367,16 @@ impl Config {
                     dns_timeout: opts.dns_timeout.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.dns_timeout.clone()),
                     max_inflight_blocks: opts.max_inflight_blocks.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.max_inflight_blocks.clone()),
                     maximum_call_argument_size: opts.maximum_call_argument_size.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.maximum_call_argument_size.clone()),
+                    mempool_sync_interval: opts.mempool_sync_interval.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.mempool_sync_interval.clone()),
+                    mempool_sync_max_bytes: opts.mempool_sync_max_bytes.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.mempool_sync_max_bytes.clone()),
+                    max_mempool_sync_bandwidth: opts.max_mempool_sync_bandwidth.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.max_mempool_sync_bandwidth.clone()),
+                    max_block_txs_read_bandwidth: opts.max_block_txs_read_bandwidth.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.max_block_txs_read_bandwidth.clone()),
+                    rpc_api_keys: opts.rpc_api_keys.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_api_keys.clone()),
+                    rpc_admin_api_keys: opts.rpc_admin_api_keys.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_admin_api_keys.clone()),
+                    rpc_disable_post_transaction: opts.rpc_disable_post_transaction.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.rpc_disable_post_transaction.clone()),
+                    disable_p2p_encryption: opts.disable_p2p_encryption.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.disable_p2p_encryption.clone()),
+                    reputation_ban_threshold: opts.reputation_ban_threshold.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.reputation_ban_threshold.clone()),
+                    reputation_decay_interval: opts.reputation_decay_interval.unwrap_or_else(|| HELIUM_DEFAULT_CONNECTION_OPTIONS.reputation_decay_interval.clone()),
                     ..ConnectionOptions::default() 
                 }
             },
@@ -357,13 +396,48 @@ impl Config {
             None => HELIUM_BLOCK_LIMIT.clone()
         };
 
+        let default_miner_config = MinerConfig::default();
+        let miner = match config_file.miner {
+            Some(miner) => {
+                let parse_contracts = |contracts: Option<Vec<String>>| -> Vec<QualifiedContractIdentifier> {
+                    contracts.unwrap_or_default().iter()
+                        .map(|c| QualifiedContractIdentifier::parse(c).expect("Contracts in miner allow/deny lists should be contract identifiers"))
+                        .collect()
+                };
+                let parse_principals = |principals: Option<Vec<String>>| -> Vec<PrincipalData> {
+                    principals.unwrap_or_default().iter()
+                        .map(|p| PrincipalData::parse(p).expect("Principals in miner allow/deny lists should be principals"))
+                        .collect()
+                };
+
+                let strategy = miner.strategy.unwrap_or(default_miner_config.strategy);
+                let supported_strategies = vec!["fee_rate", "fifo"];
+                if !supported_strategies.contains(&strategy.as_str()) {
+                    panic!("Setting miner.strategy not supported (should be: {})", supported_strategies.join(", "))
+                }
+
+                MinerConfig {
+                    strategy,
+                    allow_contracts: parse_contracts(miner.allow_contracts),
+                    deny_contracts: parse_contracts(miner.deny_contracts),
+                    allow_principals: parse_principals(miner.allow_principals),
+                    deny_principals: parse_principals(miner.deny_principals),
+                    max_txs_per_sender: miner.max_txs_per_sender.unwrap_or(default_miner_config.max_txs_per_sender),
+                    tenure_time_budget_ms: miner.tenure_time_budget_ms,
+                    max_mempool_candidates: miner.max_mempool_candidates.unwrap_or(default_miner_config.max_mempool_candidates),
+                }
+            },
+            None => default_miner_config
+        };
+
         Config {
             node,
             burnchain,
             initial_balances,
             events_observers,
             connection_options,
-            block_limit
+            block_limit,
+            miner
         }
     }
 
@@ -417,6 +491,7 @@ impl std::default::Default for Config {
             events_observers: vec![],
             connection_options,
             block_limit,
+            miner: MinerConfig::default(),
         }
     }
 }
@@ -504,6 +579,14 @@ pub struct NodeConfig {
     pub local_peer_seed: Vec<u8>,
     pub bootstrap_node: Option<Neighbor>,
     pub miner: bool,
+    pub prometheus_bind: Option<String>,
+    pub index_map_keys: bool,
+    /// How many decoded trie nodes each open MARF keeps in RAM (0 disables the cache)
+    pub marf_node_cache_capacity: usize,
+    /// Snapshot to import the chain state and burn DB from, if the node has neither yet
+    pub snapshot_path: Option<String>,
+    /// Index block hash of the Stacks block the snapshot must be of
+    pub snapshot_tip: Option<BlockHeaderHash>,
 }
 
 impl NodeConfig {
@@ -535,6 +618,11 @@ impl NodeConfig {
             bootstrap_node: None,
             local_peer_seed: local_peer_seed.to_vec(),
             miner: false,
+            prometheus_bind: None,
+            index_map_keys: false,
+            marf_node_cache_capacity: MARF_NODE_CACHE_DEFAULT_CAPACITY,
+            snapshot_path: None,
+            snapshot_tip: None,
         }
     }
 
@@ -605,6 +693,85 @@ pub struct ConnectionOptionsFile {
     pub read_only_call_limit_read_count: Option<u64>,
     pub read_only_call_limit_runtime: Option<u64>,
     pub maximum_call_argument_size: Option<u32>,
+    pub mempool_sync_interval: Option<u64>,
+    pub mempool_sync_max_bytes: Option<u64>,
+    pub max_mempool_sync_bandwidth: Option<u64>,
+    pub max_block_txs_read_bandwidth: Option<u64>,
+    pub disable_p2p_encryption: Option<bool>,
+    pub reputation_ban_threshold: Option<u64>,
+    pub reputation_decay_interval: Option<u64>,
+    pub rpc_api_keys: Option<Vec<String>>,
+    pub rpc_admin_api_keys: Option<Vec<String>>,
+    pub rpc_disable_post_transaction: Option<bool>,
+    pub rpc_ip_rate_limit_read: Option<u64>,
+    pub rpc_ip_rate_limit_expensive: Option<u64>,
+    pub rpc_ip_rate_limit_submit: Option<u64>,
+    pub rpc_api_key_rate_limit_read: Option<u64>,
+    pub rpc_api_key_rate_limit_expensive: Option<u64>,
+    pub rpc_api_key_rate_limit_submit: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct MinerConfig {
+    pub strategy: String,
+    pub allow_contracts: Vec<QualifiedContractIdentifier>,
+    pub deny_contracts: Vec<QualifiedContractIdentifier>,
+    pub allow_principals: Vec<PrincipalData>,
+    pub deny_principals: Vec<PrincipalData>,
+    pub max_txs_per_sender: u64,
+    pub tenure_time_budget_ms: Option<u64>,
+    pub max_mempool_candidates: u64,
+}
+
+impl MinerConfig {
+
+    fn default() -> MinerConfig {
+        MinerConfig {
+            strategy: "fee_rate".to_string(),
+            allow_contracts: vec![],
+            deny_contracts: vec![],
+            allow_principals: vec![],
+            deny_principals: vec![],
+            max_txs_per_sender: 1,
+            tenure_time_budget_ms: None,
+            max_mempool_candidates: MAX_MEMPOOL_CANDIDATES,
+        }
+    }
+
+    /// Settings for assembling a block in one tenure
+    pub fn make_block_builder_settings(&self) -> BlockBuilderSettings {
+        let ordering: Box<dyn TransactionSelector> = match self.strategy.as_str() {
+            "fifo" => Box::new(FifoSelector {}),
+            _ => Box::new(FeeRateSelector {})
+        };
+
+        let selector: Box<dyn TransactionSelector> = Box::new(AllowDenySelector {
+            inner: ordering,
+            allowed_contracts: self.allow_contracts.iter().cloned().collect(),
+            denied_contracts: self.deny_contracts.iter().cloned().collect(),
+            allowed_principals: self.allow_principals.iter().cloned().collect(),
+            denied_principals: self.deny_principals.iter().cloned().collect(),
+        });
+
+        BlockBuilderSettings {
+            selector,
+            max_txs_per_sender: self.max_txs_per_sender,
+            max_assembly_time_ms: self.tenure_time_budget_ms,
+            max_mempool_candidates: self.max_mempool_candidates,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct MinerConfigFile {
+    pub strategy: Option<String>,
+    pub allow_contracts: Option<Vec<String>>,
+    pub deny_contracts: Option<Vec<String>>,
+    pub allow_principals: Option<Vec<String>>,
+    pub deny_principals: Option<Vec<String>>,
+    pub max_txs_per_sender: Option<u64>,
+    pub tenure_time_budget_ms: Option<u64>,
+    pub max_mempool_candidates: Option<u64>,
 }
 
 #[derive(Clone, Default, Deserialize)]
@@ -629,6 +796,11 @@ pub struct NodeConfigFile {
     pub bootstrap_node: Option<String>,
     pub local_peer_seed: Option<String>,
     pub miner: Option<bool>,
+    pub prometheus_bind: Option<String>,
+    pub index_map_keys: Option<bool>,
+    pub marf_node_cache_capacity: Option<usize>,
+    pub snapshot_path: Option<String>,
+    pub snapshot_tip: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Default)]
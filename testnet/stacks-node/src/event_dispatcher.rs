@@ -1,6 +1,6 @@
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread::sleep;
 
 use rand::{Rng, thread_rng};
@@ -9,6 +9,7 @@ use serde_json::json;
 
 use stacks::burnchains::Txid;
 use stacks::chainstate::stacks::events::{StacksTransactionEvent, STXEventType, FTEventType, NFTEventType};
+use stacks::monitoring;
 use stacks::net::StacksMessageCodec;
 use stacks::vm::types::{Value, QualifiedContractIdentifier, AssetIdentifier};
 use stacks::vm::analysis::{contract_interface_builder::build_contract_interface};
@@ -177,13 +178,24 @@ impl EventDispatcher {
         }
 
 
+        // observers are sent to one at a time, and each send blocks until the observer accepts
+        // the payload -- track how far behind the chain tip the observers are falling.
+        let dispatch_start = Instant::now();
+        let mut num_pending = dispatch_matrix.len() as u64;
+        monitoring::set_event_dispatcher_pending(num_pending);
+
         for (observer_id, filtered_events_ids) in dispatch_matrix.iter().enumerate() {
             let mut filtered_events: Vec<&(Txid, &StacksTransactionEvent)> = vec![];
             for event_id in filtered_events_ids {
                 filtered_events.push(&events[*event_id]);
             }
             self.registered_observers[observer_id].send(filtered_events, chain_tip);
+
+            num_pending -= 1;
+            monitoring::set_event_dispatcher_pending(num_pending);
         }
+
+        monitoring::set_event_dispatcher_lag_ms(dispatch_start.elapsed().as_millis() as u64);
     }
 
     fn update_dispatch_matrix_if_observer_subscribed(&self, asset_identifier: &AssetIdentifier, event_index: usize, dispatch_matrix: &mut Vec<HashSet<usize>>) {
@@ -17,11 +17,12 @@ pub mod event_dispatcher;
 pub mod operations;
 pub mod burnchains;
 pub mod neon_node;
+pub mod monitoring;
 
 pub use self::keychain::{Keychain};
 pub use self::node::{Node, ChainTip};
 pub use self::neon_node::{InitializedNeonNode, NeonGenesisNode};
-pub use self::burnchains::{MocknetController, BitcoinRegtestController, BurnchainTip, BurnchainController};
+pub use self::burnchains::{MocknetController, BitcoinRegtestController, SpvFollowerController, BurnchainTip, BurnchainController};
 pub use self::tenure::{Tenure};
 pub use self::config::{Config, ConfigFile};
 pub use self::event_dispatcher::{EventDispatcher};
@@ -30,6 +31,8 @@ pub use self::run_loop::{neon, helium};
 use pico_args::Arguments;
 use std::env;
 
+use stacks::chainstate::stacks::index::cache::set_node_cache_capacity;
+
 fn main() {
 
     let mut args = Arguments::from_env();
@@ -68,6 +71,9 @@ fn main() {
 
     let conf = Config::from_config_file(config_file);
 
+    // applies to every MARF this process opens from here on
+    set_node_cache_capacity(conf.node.marf_node_cache_capacity);
+
     let num_round: u64 = 0; // Infinite number of rounds
 
     if conf.burnchain.mode == "helium" || conf.burnchain.mode == "mocknet" {
@@ -0,0 +1,62 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use stacks::monitoring;
+
+/// How long a scrape may take to send its request or read our response.  Connections are served
+/// one at a time, so a stalled client must not hold up the listener.
+pub const METRICS_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bind to the given address and serve the node's metrics registry at `GET /metrics`,
+/// in the Prometheus text exposition format.
+pub fn start_serving_prometheus_metrics(bind_address: &str) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(bind_address)?;
+    info!("Serving Prometheus metrics on {}", bind_address);
+
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_metrics_request(stream) {
+                        debug!("Failed to serve metrics request: {:?}", &e);
+                    }
+                },
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {:?}", &e);
+                }
+            }
+        }
+    });
+    Ok(handle)
+}
+
+fn handle_metrics_request(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(METRICS_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(METRICS_IO_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(&stream);
+        reader.read_line(&mut request_line)?;
+
+        // drain the request headers
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", monitoring::render_prometheus()),
+        _ => ("404 Not Found", "".to_string())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body);
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
@@ -1,5 +1,7 @@
 use super::{Keychain, Config, BurnchainController, BurnchainTip, EventDispatcher};
-use crate::config::HELIUM_BLOCK_LIMIT;
+use crate::config::{HELIUM_BLOCK_LIMIT, MinerConfig};
+use crate::tenure::log_skipped_transactions;
+use crate::node::set_local_peer_services;
 
 use std::convert::TryFrom;
 use std::{thread, thread::JoinHandle};
@@ -23,8 +25,10 @@ use stacks::chainstate::stacks::{Error as ChainstateError};
 use stacks::chainstate::stacks::StacksPublicKey;
 
 use stacks::core::mempool::MemPoolDB;
+use stacks::monitoring;
 use stacks::net::{ p2p::PeerNetwork, Error as NetError, db::{ PeerDB, LocalPeer }, relay::Relayer };
 use stacks::net::dns::DNSResolver;
+use stacks::net::websocket::RPCEvent;
 use stacks::util::vrf::VRFPublicKey;
 use stacks::util::get_epoch_time_secs;
 use stacks::util::strings::UrlString;
@@ -57,6 +61,7 @@ enum RelayerDirective {
     ProcessTenure(BurnchainHeaderHash, BurnchainHeaderHash, BlockHeaderHash),
     RunTenure(RegisteredKey, BlockSnapshot),
     RegisterKey(BlockSnapshot),
+    RollbackChainstate(Vec<BurnchainHeaderHash>),
     TryProcessAttachable
 }
 
@@ -308,6 +313,7 @@ fn spawn_miner_relayer(mut relayer: Relayer, local_peer: LocalPeer,
     // mined_on_burn_header_hash
     let mut last_mined_block: Option<(BurnchainHeaderHash, StacksBlock, BurnchainHeaderHash)> = None;
     let burn_fee_cap = config.burnchain.burn_fee_cap;
+    let miner_config = config.miner.clone();
     let mut bitcoin_controller = BitcoinRegtestController::new_dummy(config);
 
     let blocks_path = chainstate.blocks_path.clone();
@@ -335,6 +341,9 @@ fn spawn_miner_relayer(mut relayer: Relayer, local_peer: LocalPeer,
                     for (headers_and_receipts_opt, _poison_microblock_opt) in block_receipts.into_iter() {
                         // TODO: pass the poison microblock transaction off to the miner!
                         if let Some((header_info, receipts)) = headers_and_receipts_opt {
+                            if let Err(e) = relayer.publish_events(RPCEvent::from_block_receipts(&header_info, &receipts)) {
+                                warn!("Failed to publish RPC events for new block: {:?}", &e);
+                            }
                             dispatcher_announce(&blocks_path, &mut event_dispatcher, header_info, receipts);
                             num_processed += 1;
                         }
@@ -362,8 +371,9 @@ fn spawn_miner_relayer(mut relayer: Relayer, local_peer: LocalPeer,
                             info!("Won sortition! stacks_header={}, burn_header={}",
                                   block_header_hash,
                                   mined_burn_hh);
+                            monitoring::increment_sortition_wins_counter();
 
-                            let (stacks_header, _) = 
+                            let (stacks_header, receipts) = 
                                 match inner_process_tenure(&mined_block, &burn_header_hash, &parent_burn_hh,
                                                            vec![], // no microblocks for now...
                                                            &mut burndb, &mut chainstate, &mut event_dispatcher) {
@@ -374,6 +384,16 @@ fn spawn_miner_relayer(mut relayer: Relayer, local_peer: LocalPeer,
                                     }
                                 };
 
+                            if let Err(e) = relayer.publish_events(RPCEvent::from_block_receipts(&stacks_header, &receipts)) {
+                                warn!("Failed to publish RPC events for mined block: {:?}", &e);
+                            }
+
+                            // push the block to our neighbors as a compact block, so they can rebuild
+                            // it from their mempools instead of downloading it
+                            if let Err(e) = relayer.broadcast_compact_block(&stacks_header.burn_header_hash, &mined_block) {
+                                warn!("Failed to push compact block for mined block: {:?}", &e);
+                            }
+
                             let blocks_available = Relayer::load_blocks_available_data(&burndb, vec![stacks_header.burn_header_hash])
                                 .expect("Failed to obtain block information for a block we mined.");
                             if let Err(e) = relayer.advertize_blocks(blocks_available) {
@@ -388,10 +408,22 @@ fn spawn_miner_relayer(mut relayer: Relayer, local_peer: LocalPeer,
                 RelayerDirective::RunTenure(registered_key, last_burn_block) => {
                     last_mined_block = InitializedNeonNode::relayer_run_tenure(
                         registered_key, &mut chainstate, &burndb, last_burn_block,
-                        &mut keychain, &mut mem_pool, burn_fee_cap, &mut bitcoin_controller);
+                        &mut keychain, &mut mem_pool, burn_fee_cap, &miner_config, &mut bitcoin_controller);
                 },
                 RelayerDirective::RegisterKey(ref last_burn_block) => {
                     rotate_vrf_and_register(&mut keychain, last_burn_block, &mut bitcoin_controller)
+                },
+                RelayerDirective::RollbackChainstate(ref burn_header_hashes) => {
+                    // the burn DB was rewound past a burnchain reorg -- forget the Stacks blocks
+                    // selected in the removed burnchain blocks, and re-open the burn DB so we
+                    // don't serve anything from the old fork's tries.
+                    let rolled_back = chainstate.rollback_blocks(burn_header_hashes)
+                        .expect("BUG: failure rolling back chainstate");
+                    info!("Rolled back {} Stacks block(s) due to burnchain reorg", rolled_back.len());
+
+                    burndb = BurnDB::open(&burn_db_path, true)
+                        .expect("BUG: failed to re-open the burn DB");
+                    last_mined_block = None;
                 }
             }
         }
@@ -464,7 +496,7 @@ impl InitializedNeonNode {
             my_private_key
         };
 
-        let peerdb = PeerDB::connect(
+        let mut peerdb = PeerDB::connect(
             &config.get_peer_db_path(), 
             true, 
             TESTNET_CHAIN_ID, 
@@ -477,6 +509,8 @@ impl InitializedNeonNode {
             &vec![], 
             Some(&initial_neighbors)).unwrap();
 
+        set_local_peer_services(&mut peerdb, &config.connection_options);
+
         let local_peer = match PeerDB::get_local_peer(peerdb.conn()) {
             Ok(local_peer) => local_peer,
             _ => panic!("Unable to retrieve local peer")
@@ -550,6 +584,26 @@ impl InitializedNeonNode {
         }
     }
 
+    /// Tell the relayer to roll back the Stacks blocks selected in the given (rewound) burnchain
+    /// blocks, and forget the last burn block if it was one of them.
+    /// returns _false_ if the relayer hung up the channel.
+    pub fn relayer_rollback_chainstate(&mut self, rewound: &Vec<BlockSnapshot>) -> bool {
+        if rewound.len() == 0 {
+            return true;
+        }
+
+        if let Some(ref last_burn_block) = self.last_burn_block {
+            if rewound.iter().any(|sn| sn.burn_header_hash == last_burn_block.burn_header_hash) {
+                self.last_burn_block = None;
+            }
+        }
+
+        let burn_header_hashes = rewound.iter().map(|sn| sn.burn_header_hash.clone()).collect();
+        self.relay_channel
+            .send(RelayerDirective::RollbackChainstate(burn_header_hashes))
+            .is_ok()
+    }
+
     /// Notify the relayer of a sortition, telling it to process the block
     ///  and advertize it if it was mined by the node.
     /// returns _false_ if the relayer hung up the channel.
@@ -582,6 +636,7 @@ impl InitializedNeonNode {
                           keychain: &mut Keychain,
                           mem_pool: &mut MemPoolDB,
                           burn_fee_cap: u64,
+                          miner_config: &MinerConfig,
                           bitcoin_controller: &mut BitcoinRegtestController) -> Option<(BurnchainHeaderHash, StacksBlock, BurnchainHeaderHash)> {
         // Generates a proof out of the sortition hash provided in the params.
         let vrf_proof = keychain.generate_proof(
@@ -656,10 +711,14 @@ impl InitializedNeonNode {
         
         let coinbase_tx = inner_generate_coinbase_tx(keychain, coinbase_nonce);
 
-        let anchored_block = match StacksBlockBuilder::build_anchored_block(
+        let settings = miner_config.make_block_builder_settings();
+        let anchored_block = match StacksBlockBuilder::build_anchored_block_with_settings(
             chain_state, mem_pool, &stacks_parent_header, parent_block_total_burn,
-            vrf_proof.clone(), mblock_pubkey_hash, &coinbase_tx, HELIUM_BLOCK_LIMIT.clone()) {
-            Ok(block) => block,
+            vrf_proof.clone(), mblock_pubkey_hash, &coinbase_tx, HELIUM_BLOCK_LIMIT.clone(), &settings) {
+            Ok((block, skipped)) => {
+                log_skipped_transactions(&skipped);
+                block
+            },
             Err(e) => {
                 error!("Failure mining anchored block: {}", e);
                 return None
@@ -756,7 +815,7 @@ impl NeonGenesisNode {
         let initial_balances = config.initial_balances.iter().map(|e| (e.address.clone(), e.amount)).collect();
 
         // do the initial open!
-        let _chain_state = match StacksChainState::open_and_exec(
+        let mut chain_state = match StacksChainState::open_and_exec(
             false, 
             TESTNET_CHAIN_ID, 
             &config.get_chainstate_path(), 
@@ -767,6 +826,11 @@ impl NeonGenesisNode {
             Err(err) => panic!("Error while opening chain state at path {}: {:?}", config.get_chainstate_path(), err)
         };
 
+        if config.node.index_map_keys {
+            chain_state.enable_map_key_index()
+                .expect("Failed to enable the data map key index");
+        }
+
         let mut event_dispatcher = EventDispatcher::new();
         for observer in config.events_observers.iter() {
             event_dispatcher.register_observer(observer);
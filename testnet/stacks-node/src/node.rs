@@ -16,7 +16,8 @@ use stacks::chainstate::burn::operations::{
     BlockstackOperationType,
 };
 use stacks::core::mempool::MemPoolDB;
-use stacks::net::{ p2p::PeerNetwork, Error as NetError, db::PeerDB, PeerAddress};
+use stacks::net::{ p2p::PeerNetwork, Error as NetError, db::PeerDB, PeerAddress, ServiceFlags };
+use stacks::net::connection::ConnectionOptions;
 use stacks::util::vrf::VRFPublicKey;
 use stacks::util::get_epoch_time_secs;
 use stacks::util::strings::UrlString;
@@ -108,6 +109,17 @@ fn spawn_peer(mut this: PeerNetwork, p2p_sock: &SocketAddr, rpc_sock: &SocketAdd
     Ok(server_thread)
 }
 
+/// Advertise compact block relay and mempool sync, and encrypted p2p channels unless the operator
+/// turned them off
+pub fn set_local_peer_services(peerdb: &mut PeerDB, connection_options: &ConnectionOptions) {
+    let mut services = (ServiceFlags::RELAY as u16) | (ServiceFlags::COMPACT_BLOCKS as u16) | (ServiceFlags::MEMPOOL_SYNC as u16);
+    if !connection_options.disable_p2p_encryption {
+        services |= ServiceFlags::ENCRYPTION as u16;
+    }
+    let mut tx = peerdb.tx_begin().unwrap();
+    PeerDB::set_local_services(&mut tx, services).unwrap();
+    tx.commit().unwrap();
+}
 
 impl Node {
 
@@ -123,10 +135,14 @@ impl Node {
             false, TESTNET_CHAIN_ID, &config.get_chainstate_path(),
             Some(initial_balances), boot_block_exec, config.block_limit.clone());
 
-        let chain_state = match chain_state_result {
+        let mut chain_state = match chain_state_result {
             Ok(res) => res,
             Err(err) => panic!("Error while opening chain state at path {}: {:?}", config.get_chainstate_path(), err)
         };
+        if config.node.index_map_keys {
+            chain_state.enable_map_key_index()
+                .expect("Failed to enable the data map key index");
+        }
         let mut event_dispatcher = EventDispatcher::new();
 
         for observer in &config.events_observers {
@@ -247,7 +263,7 @@ impl Node {
             my_private_key
         };
 
-        let peerdb = PeerDB::connect(
+        let mut peerdb = PeerDB::connect(
             &self.config.get_peer_db_path(), 
             true, 
             TESTNET_CHAIN_ID, 
@@ -260,6 +276,8 @@ impl Node {
             &vec![], 
             Some(&initial_neighbors)).unwrap();
 
+        set_local_peer_services(&mut peerdb, &self.config.connection_options);
+
         let local_peer = match PeerDB::get_local_peer(peerdb.conn()) {
             Ok(local_peer) => local_peer,
             _ => panic!("Unable to retrieve local peer")
@@ -1,4 +1,7 @@
 use crate::{Config, Node, BurnchainController, MocknetController, BitcoinRegtestController, ChainTip};
+use crate::monitoring::start_serving_prometheus_metrics;
+
+use stacks::monitoring;
 
 use stacks::chainstate::stacks::db::ClarityTx;
 
@@ -39,6 +42,11 @@ impl RunLoop {
     /// the nodes, taking turns on tenures.  
     pub fn start(&mut self, expected_num_rounds: u64) {
 
+        if let Some(ref prometheus_bind) = self.config.node.prometheus_bind {
+            start_serving_prometheus_metrics(prometheus_bind)
+                .expect("Failed to bind Prometheus metrics endpoint");
+        }
+
         // Initialize and start the burnchain.
         let mut burnchain: Box<dyn BurnchainController> = match &self.config.burnchain.mode[..] {
             "helium" => {
@@ -157,6 +165,7 @@ impl RunLoop {
             }
 
             burnchain_tip = burnchain.sync();
+            monitoring::set_burn_block_height(burnchain_tip.block_snapshot.block_height);
             self.callbacks.invoke_new_burn_chain_state(round_index, &burnchain_tip, &chain_tip);
     
             leader_tenure = None;
@@ -190,6 +199,7 @@ impl RunLoop {
             
             // If won sortition, initialize and configure the next tenure
             if won_sortition {
+                monitoring::increment_sortition_wins_counter();
                 leader_tenure = self.node.initiate_new_tenure();
             } 
             
@@ -1,12 +1,17 @@
 use std::process;
 use crate::{Config, NeonGenesisNode, BurnchainController, 
-            BitcoinRegtestController, Keychain};
+            BitcoinRegtestController, SpvFollowerController, Keychain};
+use crate::monitoring::start_serving_prometheus_metrics;
+use stacks::monitoring;
 use stacks::chainstate::burn::db::burndb::BurnDB;
+use stacks::chainstate::stacks::db::StacksChainState;
 use stacks::burnchains::bitcoin::address::BitcoinAddress;
 use stacks::burnchains::Address;
 use stacks::burnchains::bitcoin::{BitcoinNetworkType, 
                                   address::{BitcoinAddressType}};
 
+use crate::node::TESTNET_CHAIN_ID;
+
 use super::RunLoopCallbacks;
 
 /// Coordinating a node running in neon mode.
@@ -33,10 +38,25 @@ impl RunLoop {
     /// the nodes, taking turns on tenures.  
     pub fn start(&mut self, _expected_num_rounds: u64) {
 
-        // Initialize and start the burnchain.
-        let mut burnchain = BitcoinRegtestController::new(self.config.clone());
+        if let Some(ref prometheus_bind) = self.config.node.prometheus_bind {
+            start_serving_prometheus_metrics(prometheus_bind)
+                .expect("Failed to bind Prometheus metrics endpoint");
+        }
 
-        let is_miner = if self.config.node.miner {
+        // A new node can start from a snapshot of a trusted Stacks block instead of syncing the
+        // whole chain.  This must happen before the burnchain creates its own burn DB.
+        if let (Some(ref snapshot_path), Some(ref snapshot_tip)) = (&self.config.node.snapshot_path, &self.config.node.snapshot_tip) {
+            let burn_db_path = self.config.get_burn_db_file_path();
+            StacksChainState::open_from_snapshot(false, TESTNET_CHAIN_ID, &self.config.get_chainstate_path(),
+                                                 burn_db_path.trim_end_matches('/'), snapshot_path, snapshot_tip)
+                .expect("Failed to import the chain state snapshot");
+        }
+
+        // Initialize and start the burnchain.
+        // Only miners need bitcoind's RPC interface (for UTXOs and to submit operations) --
+        // followers track the burnchain over the Bitcoin peer network alone.
+        let (mut burnchain, is_miner): (Box<dyn BurnchainController>, bool) = if self.config.node.miner {
+            let burnchain = BitcoinRegtestController::new(self.config.clone());
             let mut keychain = Keychain::default(self.config.node.seed.clone());
             let btc_addr = BitcoinAddress::from_bytes(
                 BitcoinNetworkType::Regtest,
@@ -49,14 +69,14 @@ impl RunLoop {
                 &keychain.generate_op_signer().get_public_key(), 1);
             if utxos.is_none() {
                 error!("Miner node: UTXOs not found. Switching to Follower node. Restart node when you get some UTXOs.");
-                false
+                (SpvFollowerController::generic(self.config.clone()), false)
             } else {
                 info!("Miner node: starting up, UTXOs found.");
-                true
+                (Box::new(burnchain), true)
             }
         } else {
             info!("Follower node: starting up");
-            false
+            (SpvFollowerController::generic(self.config.clone()), false)
         };
 
         let mut burnchain_tip = burnchain.start();
@@ -78,6 +98,23 @@ impl RunLoop {
         info!("Begin run loop");
         loop {
             burnchain_tip = burnchain.sync();
+            monitoring::set_burn_block_height(burnchain_tip.block_snapshot.block_height);
+
+            // if the burnchain reorged, the burn DB was rewound -- roll back the chainstate too,
+            // and re-process the new fork from the highest common ancestor.
+            let rewound = burnchain.take_rewound_snapshots();
+            if rewound.len() > 0 {
+                let rewind_height = rewound.iter().map(|sn| sn.block_height).min().expect("BUG: no rewound snapshots") - 1;
+                warn!("Burnchain reorg: rewound {} burn block(s) to height {}", rewound.len(), rewind_height);
+                if !node.relayer_rollback_chainstate(&rewound) {
+                    // relayer hung up, exit.
+                    error!("Block relayer and miner hung up, exiting.");
+                    process::exit(1);
+                }
+                if rewind_height < block_height {
+                    block_height = rewind_height;
+                }
+            }
 
             let next_height = burnchain_tip.block_snapshot.block_height;
             if next_height <= block_height {
@@ -3,11 +3,13 @@ use super::node::{TESTNET_CHAIN_ID, ChainTip};
 
 use std::time::{Instant, Duration};
 use std::thread;
+use std::collections::BTreeMap;
 
 use stacks::burnchains::PublicKey;
 use stacks::chainstate::stacks::db::{StacksChainState};
 use stacks::chainstate::stacks::{StacksPrivateKey, StacksBlock, 
                                  StacksPublicKey, StacksTransaction, StacksMicroblock, StacksBlockBuilder};
+use stacks::chainstate::stacks::miner::{TransactionSkip, TransactionSkipReason};
 use stacks::chainstate::burn::VRFSeed;
 use stacks::core::mempool::MemPoolDB;
 use stacks::util::vrf::VRFProof;
@@ -81,10 +83,12 @@ impl <'a> Tenure {
             &self.config.get_chainstate_path(),
             self.config.block_limit.clone()).unwrap();
 
-        let anchored_block = StacksBlockBuilder::build_anchored_block(
+        let settings = self.config.miner.make_block_builder_settings();
+        let (anchored_block, skipped) = StacksBlockBuilder::build_anchored_block_with_settings(
             &mut chain_state, &mut self.mem_pool, &self.parent_block.metadata,
             self.parent_block_total_burn, self.vrf_proof.clone(), self.microblock_pubkeyhash.clone(),
-            &self.coinbase_tx, self.config.block_limit.clone()).unwrap();
+            &self.coinbase_tx, self.config.block_limit.clone(), &settings).unwrap();
+        log_skipped_transactions(&skipped);
 
         info!("Finish tenure: {}", anchored_block.block_hash());
 
@@ -97,3 +101,25 @@ impl <'a> Tenure {
         Some(artifact)
     }
 }
+
+/// Report how many mempool transactions didn't make it into the block we just assembled, and why,
+/// in one line.  Each skipped transaction is only logged at debug level.
+/// Skips are only logged; they are not sent to event observers.
+pub fn log_skipped_transactions(skipped: &[TransactionSkip]) {
+    if skipped.len() == 0 {
+        return;
+    }
+
+    let mut counts : BTreeMap<String, usize> = BTreeMap::new();
+    for skip in skipped.iter() {
+        debug!("Miner skipped transaction {}: {}", &skip.txid, &skip.reason);
+        let reason = match skip.reason {
+            TransactionSkipReason::RuntimeError(_) => "runtime error".to_string(),
+            ref reason => reason.to_string()
+        };
+        *counts.entry(reason).or_insert(0) += 1;
+    }
+
+    let summary : Vec<String> = counts.iter().map(|(reason, count)| format!("{} {}", count, reason)).collect();
+    info!("Miner skipped {} mempool transaction(s): {}", skipped.len(), summary.join(", "));
+}
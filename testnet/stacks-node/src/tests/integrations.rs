@@ -10,7 +10,9 @@ use stacks::chainstate::stacks::{
     db::StacksChainState, StacksPrivateKey, StacksTransaction, StacksAddress };
 use stacks::chainstate::burn::VRFSeed;
 use stacks::burnchains::Address;
-use stacks::net::{AccountEntryResponse, ContractSrcResponse, CallReadOnlyRequestBody};
+use stacks::net::{AccountEntryResponse, ContractSrcResponse, CallReadOnlyRequestBody,
+                  FungibleTokenBalanceResponse, NonFungibleTokenOwnerResponse, DataVarResponse,
+                  RPCMempoolTxsData, RPCMempoolAccountData};
 use stacks::net::StacksMessageCodec;
 use stacks::vm::clarity::ClarityConnection;
 
@@ -30,6 +32,9 @@ const GET_INFO_CONTRACT: &'static str = "
            (vrf-seed (buff 32))
            (burn-block-time uint)
            (stacks-miner principal)))
+        (define-data-var info-var uint u7)
+        (define-fungible-token info-ft)
+        (define-non-fungible-token info-nft uint)
         (define-private (test-1) (get-block-info? time u1))
         (define-private (test-2) (get-block-info? time block-height))
         (define-private (test-3) (get-block-info? time u100000))
@@ -343,6 +348,45 @@ fn integration_test_get_info() {
 
                 assert_eq!(result_data, expected_data);
 
+                // let's get a data var
+                let path = format!("{}/v2/data_var/{}/{}/{}",
+                                   &http_origin, &contract_addr, "get-info", "info-var");
+                eprintln!("Test: GET {}", path);
+                let res = client.get(&path).send().unwrap().json::<DataVarResponse>().unwrap();
+                let result_data = Value::try_deserialize_hex_untyped(&res.data[2..]).unwrap();
+                assert_eq!(result_data, Value::UInt(7));
+                assert!(res.marf_proof.is_some());
+
+                let path = format!("{}/v2/data_var/{}/{}/{}?proof=0",
+                                   &http_origin, &contract_addr, "get-info", "info-var");
+                eprintln!("Test: GET {}", path);
+                let res = client.get(&path).send().unwrap().json::<DataVarResponse>().unwrap();
+                assert!(res.marf_proof.is_none());
+
+                // a missing one?
+                let path = format!("{}/v2/data_var/{}/{}/{}",
+                                   &http_origin, &contract_addr, "get-info", "not-there");
+                eprintln!("Test: GET {}", path);
+                assert_eq!(client.get(&path).send().unwrap().status(), 404);
+
+                // nothing has been minted, so balances are 0 and tokens are unowned
+                let path = format!("{}/v2/ft_balance/{}/{}/{}/{}",
+                                   &http_origin, &contract_addr, "get-info", "info-ft", &sender_addr);
+                eprintln!("Test: GET {}", path);
+                let res = client.get(&path).send().unwrap().json::<FungibleTokenBalanceResponse>().unwrap();
+                assert_eq!(u128::from_str_radix(&res.balance[2..], 16).unwrap(), 0);
+                assert!(res.marf_proof.is_some());
+
+                let path = format!("{}/v2/nft_owner/{}/{}/{}?proof=0",
+                                   &http_origin, &contract_addr, "get-info", "info-nft");
+                eprintln!("Test: POST {}", path);
+                let res = client.post(&path)
+                    .json(&Value::UInt(1).serialize())
+                    .send()
+                    .unwrap().json::<NonFungibleTokenOwnerResponse>().unwrap();
+                assert!(res.owner.is_none());
+                assert!(res.marf_proof.is_none());
+
                 // account with a nonce entry + a balance entry
                 let path = format!("{}/v2/accounts/{}",
                                    &http_origin, &sender_addr);
@@ -353,6 +397,25 @@ fn integration_test_get_info() {
                 assert!(res.nonce_proof.is_some());
                 assert!(res.balance_proof.is_some());
 
+                // its transactions have all been mined, so no nonces are pending
+                let path = format!("{}/v2/mempool/accounts/{}",
+                                   &http_origin, &sender_addr);
+                eprintln!("Test: GET {}", path);
+                let res = client.get(&path).send().unwrap().json::<RPCMempoolAccountData>().unwrap();
+                assert_eq!(res.nonce, 3);
+                assert_eq!(res.pending_count, 0);
+                assert_eq!(res.min_pending_nonce, None);
+                assert_eq!(res.next_nonce, 3);
+
+                let path = format!("{}/v2/mempool/transactions?count=10&principal={}",
+                                   &http_origin, &sender_addr);
+                eprintln!("Test: GET {}", path);
+                let res = client.get(&path).send().unwrap().json::<RPCMempoolTxsData>().unwrap();
+                assert!(res.transactions.len() <= 10);
+                for tx_info in res.transactions.iter() {
+                    assert!(tx_info.origin_address == sender_addr.to_string() || tx_info.sponsor_address == sender_addr.to_string());
+                }
+
                 // account with a nonce entry but not a balance entry
                 let path = format!("{}/v2/accounts/{}",
                                    &http_origin, &contract_addr);
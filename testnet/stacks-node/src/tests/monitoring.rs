@@ -0,0 +1,75 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use stacks::monitoring;
+
+use crate::monitoring::{start_serving_prometheus_metrics, METRICS_IO_TIMEOUT};
+
+/// Send a request to the metrics endpoint and read back the whole response
+fn scrape(bind_address: &str, request: &str) -> String {
+    let mut stream = TcpStream::connect(bind_address).unwrap();
+    stream.set_read_timeout(Some(METRICS_IO_TIMEOUT * 2)).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn prometheus_metrics_scrape() {
+    let bind_address = "127.0.0.1:20450";
+    start_serving_prometheus_metrics(bind_address).unwrap();
+
+    monitoring::set_burn_block_height(456);
+
+    let response = scrape(bind_address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let (preamble, body) = {
+        let mut parts = response.splitn(2, "\r\n\r\n");
+        (parts.next().unwrap().to_string(), parts.next().unwrap().to_string())
+    };
+
+    let preamble_lines : Vec<&str> = preamble.lines().collect();
+    assert_eq!(preamble_lines[0], "HTTP/1.1 200 OK");
+    assert!(preamble_lines.contains(&"Content-Type: text/plain; version=0.0.4"));
+    assert!(preamble_lines.contains(&format!("Content-Length: {}", body.len()).as_str()));
+
+    // every metric has a HELP line, a TYPE line, and its value
+    let lines : Vec<&str> = body.lines().collect();
+    assert!(lines.len() > 0);
+    assert_eq!(lines.len() % 3, 0);
+    for metric_lines in lines.chunks(3) {
+        assert!(metric_lines[0].starts_with("# HELP "));
+        assert!(metric_lines[1].starts_with("# TYPE "));
+        assert!(metric_lines[1].ends_with(" gauge") || metric_lines[1].ends_with(" counter"));
+        let value = metric_lines[2].split_whitespace().nth(1).unwrap();
+        assert!(value.parse::<u64>().is_ok());
+    }
+    assert!(lines.contains(&"stacks_node_burn_block_height 456"));
+
+    // anything else is a 404
+    let response = scrape(bind_address, "GET /v2/info HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn prometheus_metrics_stalled_scrape() {
+    let bind_address = "127.0.0.1:20451";
+    start_serving_prometheus_metrics(bind_address).unwrap();
+
+    // a client that connects but never sends its request holds the listener for at most
+    // METRICS_IO_TIMEOUT, and is then disconnected
+    let mut stalled = TcpStream::connect(bind_address).unwrap();
+    stalled.set_read_timeout(Some(METRICS_IO_TIMEOUT * 2)).unwrap();
+
+    let start = Instant::now();
+    let response = scrape(bind_address, "GET /metrics HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(start.elapsed() < METRICS_IO_TIMEOUT * 2);
+
+    let mut buf = vec![];
+    let start = Instant::now();
+    assert_eq!(stalled.read_to_end(&mut buf).unwrap_or(0), 0);
+    assert!(start.elapsed() < METRICS_IO_TIMEOUT + Duration::from_secs(1));
+}
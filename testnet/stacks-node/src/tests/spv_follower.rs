@@ -0,0 +1,228 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::{BurnchainController, SpvFollowerController};
+use crate::operations::BurnchainOpSigner;
+
+use stacks::burnchains::{BurnchainHeaderHash, Txid};
+use stacks::burnchains::bitcoin::BitcoinNetworkType;
+use stacks::burnchains::bitcoin::indexer::network_id_to_bytes;
+use stacks::chainstate::burn::ConsensusHash;
+use stacks::chainstate::burn::db::burndb::BurnDB;
+use stacks::chainstate::burn::operations::{BlockstackOperationType, LeaderKeyRegisterOp};
+use stacks::deps::bitcoin::blockdata::block::{Block, BlockHeader, LoneBlockHeader};
+use stacks::deps::bitcoin::blockdata::constants::genesis_block;
+use stacks::deps::bitcoin::blockdata::script::Script;
+use stacks::deps::bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, OutPoint};
+use stacks::deps::bitcoin::network::address::Address;
+use stacks::deps::bitcoin::network::constants::{Network, PROTOCOL_VERSION};
+use stacks::deps::bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable, VarInt};
+use stacks::deps::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use stacks::deps::bitcoin::network::message_blockdata::{InvType, Inventory};
+use stacks::deps::bitcoin::network::message_network::VersionMessage;
+use stacks::deps::bitcoin::network::serialize::{BitcoinHash, RawDecoder, RawEncoder};
+use stacks::deps::bitcoin::util::hash::bitcoin_merkle_root;
+use stacks::util::secp256k1::Secp256k1PrivateKey;
+use stacks::util::vrf::VRFPublicKey;
+
+/// A stand-in for a Bitcoin peer: it speaks just enough of the Bitcoin P2P protocol
+/// (version/verack, ping, getheaders, getdata) to serve a regtest chain of empty blocks.
+/// Clients can't reach any JSON-RPC endpoint through it.
+struct StandInBitcoinPeer {
+    blocks: Arc<Mutex<Vec<Block>>>,
+    port: u16,
+}
+
+impl StandInBitcoinPeer {
+    fn spawn(num_blocks: u64) -> StandInBitcoinPeer {
+        let blocks = Arc::new(Mutex::new(vec![genesis_block(Network::Regtest)]));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let peer = StandInBitcoinPeer { blocks: blocks.clone(), port };
+        peer.mine_blocks(num_blocks);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let sock = match stream {
+                    Ok(sock) => sock,
+                    Err(_) => continue
+                };
+                let blocks = blocks.clone();
+                thread::spawn(move || {
+                    // connection ends when the client hangs up
+                    let _ = serve_connection(sock, blocks);
+                });
+            }
+        });
+
+        peer
+    }
+
+    fn mine_blocks(&self, num_blocks: u64) {
+        let mut blocks = self.blocks.lock().unwrap();
+        for _ in 0..num_blocks {
+            let height = blocks.len() as u32;
+            let parent_header = blocks[blocks.len() - 1].header;
+
+            // one coinbase-like transaction, so each block's merkle root is unique
+            let tx = Transaction {
+                version: 1,
+                lock_time: height,
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value: 0,
+                    script_pubkey: Script::new(),
+                }],
+            };
+
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash: parent_header.bitcoin_hash(),
+                merkle_root: bitcoin_merkle_root(vec![tx.txid()]),
+                time: parent_header.time + 600,
+                bits: parent_header.bits,
+                nonce: 0,
+            };
+
+            blocks.push(Block { header, txdata: vec![tx] });
+        }
+    }
+
+    fn block_hash(&self, height: u64) -> BurnchainHeaderHash {
+        let blocks = self.blocks.lock().unwrap();
+        BurnchainHeaderHash::from_bitcoin_hash(&blocks[height as usize].bitcoin_hash())
+    }
+}
+
+fn send_message(sock: &mut TcpStream, payload: NetworkMessage) -> Result<(), ()> {
+    let message = RawNetworkMessage {
+        magic: network_id_to_bytes(BitcoinNetworkType::Regtest),
+        payload
+    };
+    message.consensus_encode(&mut RawEncoder::new(&mut *sock)).map_err(|_| ())?;
+    sock.flush().map_err(|_| ())
+}
+
+fn serve_connection(mut sock: TcpStream, blocks: Arc<Mutex<Vec<Block>>>) -> Result<(), ()> {
+    loop {
+        let message: RawNetworkMessage = {
+            let mut decoder = RawDecoder::new(&mut sock);
+            ConsensusDecodable::consensus_decode(&mut decoder).map_err(|_| ())?
+        };
+
+        match message.payload {
+            NetworkMessage::Version(version) => {
+                let tip_height = blocks.lock().unwrap().len() - 1;
+                let local_addr = sock.local_addr().map_err(|_| ())?;
+                let reply = VersionMessage {
+                    version: PROTOCOL_VERSION,
+                    services: 0,
+                    timestamp: version.timestamp,
+                    receiver: version.sender.clone(),
+                    sender: Address::new(&local_addr, 0),
+                    nonce: version.nonce.wrapping_add(1),
+                    user_agent: "/stand-in-peer:0.1/".to_string(),
+                    start_height: tip_height as i32,
+                    relay: false
+                };
+                send_message(&mut sock, NetworkMessage::Version(reply))?;
+                send_message(&mut sock, NetworkMessage::Verack)?;
+            },
+            NetworkMessage::Ping(nonce) => {
+                send_message(&mut sock, NetworkMessage::Pong(nonce))?;
+            },
+            NetworkMessage::GetHeaders(getheaders) => {
+                let headers: Vec<LoneBlockHeader> = {
+                    let blocks = blocks.lock().unwrap();
+                    // reply with the headers after the first locator hash we know about
+                    let start = getheaders.locator_hashes.iter()
+                        .filter_map(|hash| blocks.iter().position(|blk| blk.bitcoin_hash() == *hash))
+                        .next()
+                        .unwrap_or(0);
+
+                    blocks.iter()
+                        .skip(start + 1)
+                        .take(2000)
+                        .map(|blk| LoneBlockHeader { header: blk.header, tx_count: VarInt(0) })
+                        .collect()
+                };
+                send_message(&mut sock, NetworkMessage::Headers(headers))?;
+            },
+            NetworkMessage::GetData(invs) => {
+                for inv in invs.iter() {
+                    let block_opt = {
+                        let blocks = blocks.lock().unwrap();
+                        blocks.iter().find(|blk| blk.bitcoin_hash() == inv.hash).cloned()
+                    };
+                    match block_opt {
+                        Some(block) => send_message(&mut sock, NetworkMessage::Block(block))?,
+                        None => send_message(&mut sock, NetworkMessage::NotFound(vec![Inventory { inv_type: InvType::Block, hash: inv.hash }]))?
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn spv_follower_syncs_without_rpc() {
+    let peer = StandInBitcoinPeer::spawn(10);
+
+    let mut conf = super::new_test_conf();
+    conf.node.miner = false;
+    conf.burnchain.mode = "neon".to_string();
+    conf.burnchain.peer_host = "127.0.0.1".to_string();
+    conf.burnchain.peer_port = peer.port;
+    // nothing listens here, and there are no credentials -- any RPC attempt would fail
+    conf.burnchain.rpc_port = 1;
+    conf.burnchain.username = None;
+    conf.burnchain.password = None;
+
+    let mut controller = SpvFollowerController::new(conf.clone());
+
+    // initial sync fetches all headers and blocks from the stand-in peer
+    let tip = controller.start();
+    assert_eq!(tip.block_snapshot.block_height, 10);
+    assert_eq!(tip.block_snapshot.burn_header_hash, peer.block_hash(10));
+    assert_eq!(tip.block_snapshot.sortition, false);
+
+    for height in 1..11 {
+        let ic = controller.burndb_ref().index_conn();
+        let sn = BurnDB::get_ancestor_snapshot(&ic, height, &tip.block_snapshot.burn_header_hash)
+            .unwrap()
+            .expect("missing snapshot");
+        assert_eq!(sn.burn_header_hash, peer.block_hash(height));
+    }
+
+    // new blocks are picked up on the next sync
+    peer.mine_blocks(5);
+    let tip = controller.sync();
+    assert_eq!(tip.block_snapshot.block_height, 15);
+    assert_eq!(tip.block_snapshot.burn_header_hash, peer.block_hash(15));
+    assert_eq!(controller.get_chain_tip().block_snapshot, tip.block_snapshot);
+    assert_eq!(controller.take_rewound_snapshots().len(), 0);
+
+    // followers never submit burnchain operations
+    let sk = Secp256k1PrivateKey::new();
+    let mut op_signer = BurnchainOpSigner::new(sk, false);
+    let op = BlockstackOperationType::LeaderKeyRegister(LeaderKeyRegisterOp {
+        public_key: VRFPublicKey::from_hex(&"99fe9d43bbb0d36a23e4102cef59accfa983a342ae1e5acedc1b8dcb06b17cd4".to_string()).unwrap(),
+        memo: vec![],
+        address: super::to_addr(&sk),
+        consensus_hash: ConsensusHash([0u8; 20]),
+        vtxindex: 0,
+        txid: Txid([0u8; 32]),
+        block_height: 0,
+        burn_header_hash: BurnchainHeaderHash([0u8; 32]),
+    });
+    assert!(!controller.submit_operation(op, &mut op_signer));
+}